@@ -0,0 +1,67 @@
+//! Minimal CN flow against a running MS.
+//!
+//! CINDIR=/home/ms/cin cargo run --example cn_session -- 5 file://cc/11000.wav
+
+use anyhow::{Result, Context};
+use rcn::{
+    vn_codec::{negotiate, CallProfile},
+    vn_proto::{Filename, MCodeType, MediaType, Play, RegisterRef, RequestChannel, RequestChannelAckRef},
+    vn_session::CnSession,
+};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let cn_id: u32 = args.next().unwrap_or_else(||"5".into()).parse().with_context(||"invalid cn_id")?;
+    let prompt = args.next().unwrap_or_else(||"file://cc/11000.wav".into());
+
+    // bind $CINDIR/mscn{cn_id} and say hello to $CINDIR/msvn
+    let mut session = CnSession::bind_env(cn_id).await?;
+    session.enable_latency();
+    session.handshake().await?;
+
+    // MS registers its capabilities
+    let register = session.accept_register().await?;
+    let reg = RegisterRef::parse_from(&register[..])?;
+    println!("{reg:#?}");
+
+    // open a channel with a codec the MS supports
+    let mut req = RequestChannel {
+        life_seconds: 300,
+        as_call_id: format!("example-{cn_id}"),
+        is_caller: true,
+        ..Default::default()
+    };
+    let profile = CallProfile {
+        media_type: MediaType::AudioOnly,
+        audio: vec!["PCMA".into(), "PCMU".into()],
+        video: vec![],
+        ptime: 20,
+    };
+    negotiate(&reg.media_info, &profile)?.apply_to(&mut req);
+
+    let fsm_id = session.base_fsm_id() + 1;
+    session.request_channel(fsm_id, &req).await?;
+    {
+        let packet = session.expect_packet(MCodeType::REQUESTCHANNEL_ACK).await?;
+        println!("{:#?}", RequestChannelAckRef::parse_from(packet.payload())?);
+    }
+
+    // play one prompt
+    session.play(fsm_id, &Play {
+        play_times: 1,
+        files: vec![Filename { format: 100, filename: prompt }],
+        ..Default::default()
+    }).await?;
+    session.expect_packet(MCodeType::PLAY_ACK).await?;
+
+    session.send_request(MCodeType::RELEASECHANNEL, fsm_id, &[]).await?;
+
+    if let Some(latency) = session.latency() {
+        for line in latency.summary() {
+            println!("{line}");
+        }
+    }
+
+    Ok(())
+}
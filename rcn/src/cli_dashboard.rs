@@ -0,0 +1,254 @@
+//! live dashboard of `rcn cli --tui`, replaces scrolling logs
+
+use std::{collections::{BTreeMap, VecDeque}, io, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use anyhow::Result;
+use crossterm::{
+    event::{self, Event, KeyCode},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Direction, Layout},
+    style::{Color, Style},
+    widgets::{Block, Borders, List, ListItem, Paragraph, Row, Table},
+    Terminal,
+};
+
+use crate::{utils::rtt::RttStats, vn_channels::ChannelReport, vn_proto::{Header, MCode, MCodeType, PacketRef}, vn_seq::LossStats};
+
+const MAX_ERRORS: usize = 20;
+
+const MAX_RECENT: usize = 8;
+
+/// heartbeat older than this is shown as unhealthy
+const HEARTBEAT_STALE: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct CodeStat {
+    count: u64,
+    last_count: u64,
+    rate: f64,
+}
+
+pub struct DashboardStats {
+    title: String,
+    started: Instant,
+    registered: Option<Instant>,
+    last_heartbeat: Option<Instant>,
+    /// of HEARTBEATs we sent
+    rtt: RttStats,
+    /// from sn of received packets
+    loss: LossStats,
+    codes: BTreeMap<u16, CodeStat>,
+    /// compact lines of last packets
+    recent: VecDeque<String>,
+    errors: VecDeque<String>,
+    /// of the channel registry, see vn_channels
+    channels: ChannelReport,
+    last_tick: Instant,
+    stop: bool,
+}
+
+pub type SharedStats = Arc<Mutex<DashboardStats>>;
+
+impl DashboardStats {
+    pub fn new(title: String) -> SharedStats {
+        let now = Instant::now();
+        Arc::new(Mutex::new(Self {
+            title,
+            started: now,
+            registered: None,
+            last_heartbeat: None,
+            rtt: RttStats::default(),
+            loss: LossStats::default(),
+            codes: Default::default(),
+            recent: Default::default(),
+            errors: Default::default(),
+            channels: Default::default(),
+            last_tick: now,
+            stop: false,
+        }))
+    }
+
+    pub fn on_registered(&mut self) {
+        self.registered = Some(Instant::now());
+    }
+
+    pub fn on_packet(&mut self, packet: &PacketRef<'_>) {
+        self.on_code(packet.code(), || packet.format_compact());
+    }
+
+    /// packet received as header and payload, e.g. by MsPool
+    pub fn on_header(&mut self, header: &Header, payload: &[u8]) {
+        self.on_code(header.code, || {
+            let mut data = Vec::new();
+            header.write_to2(&mut data, payload);
+            PacketRef::parse_from(&data).map(|x| x.format_compact()).unwrap_or_else(|_e| format!("{header:?}"))
+        });
+    }
+
+    fn on_code(&mut self, code: u16, line: impl FnOnce() -> String) {
+        self.codes.entry(code).or_default().count += 1;
+        if code == MCodeType::HEARTBEAT.code() || code == MCodeType::THEARTBEAT.code() {
+            // would push everything else out of recent
+            self.last_heartbeat = Some(Instant::now());
+            return
+        }
+        if self.recent.len() >= MAX_RECENT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(line());
+    }
+
+    pub fn on_channels(&mut self, report: ChannelReport) {
+        self.channels = report;
+    }
+
+    pub fn on_link(&mut self, rtt: RttStats, loss: LossStats) {
+        self.rtt = rtt;
+        self.loss = loss;
+    }
+
+    pub fn on_error(&mut self, error: String) {
+        if self.errors.len() >= MAX_ERRORS {
+            self.errors.pop_front();
+        }
+        self.errors.push_back(error);
+    }
+
+    /// make run_dashboard return
+    pub fn request_stop(&mut self) {
+        self.stop = true;
+    }
+
+    fn tick(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_tick).as_secs_f64();
+        if elapsed <= 0.0 {
+            return
+        }
+        for stat in self.codes.values_mut() {
+            stat.rate = (stat.count - stat.last_count) as f64 / elapsed;
+            stat.last_count = stat.count;
+        }
+        self.last_tick = now;
+    }
+}
+
+/// draw until 'q' or Esc pressed or stop requested,
+/// blocking, run it on a blocking thread
+pub fn run_dashboard(stats: SharedStats, refresh: Duration) -> Result<()> {
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+    let r = draw_loop(&mut terminal, &stats, refresh);
+
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    r
+}
+
+fn draw_loop<B: ratatui::backend::Backend>(terminal: &mut Terminal<B>, stats: &SharedStats, refresh: Duration) -> Result<()> {
+    let mut next_tick = Instant::now() + refresh;
+    loop {
+        {
+            let mut stats = stats.lock().unwrap_or_else(|e| e.into_inner());
+            if stats.stop {
+                return Ok(())
+            }
+            let now = Instant::now();
+            if now >= next_tick {
+                stats.tick(now);
+                next_tick = now + refresh;
+            }
+            terminal.draw(|f| draw(f, &stats, now))?;
+        }
+
+        if event::poll(refresh.min(Duration::from_millis(200)))? {
+            if let Event::Key(key) = event::read()? {
+                if matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                    return Ok(())
+                }
+            }
+        }
+    }
+}
+
+fn draw<B: ratatui::backend::Backend>(f: &mut ratatui::Frame<'_, B>, stats: &DashboardStats, now: Instant) {
+    let chunks = Layout::default()
+    .direction(Direction::Vertical)
+    .constraints([
+        Constraint::Length(6),
+        Constraint::Min(6),
+        Constraint::Length(MAX_RECENT as u16 + 2),
+        Constraint::Length(MAX_ERRORS as u16 / 2 + 2),
+    ])
+    .split(f.size());
+    let middle = Layout::default()
+    .direction(Direction::Horizontal)
+    .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+    .split(chunks[1]);
+
+    let registered = match stats.registered {
+        Some(t) => format!("registered {}s ago", now.duration_since(t).as_secs()),
+        None => "not registered".to_string(),
+    };
+    let (heartbeat, heartbeat_color) = match stats.last_heartbeat {
+        Some(t) if now.duration_since(t) < HEARTBEAT_STALE => (format!("heartbeat ok, {}s ago", now.duration_since(t).as_secs()), Color::Green),
+        Some(t) => (format!("heartbeat stale, {}s ago", now.duration_since(t).as_secs()), Color::Red),
+        None => ("no heartbeat".to_string(), Color::Yellow),
+    };
+    let header = Paragraph::new(vec![
+        format!("{}  up {}s  {registered}", stats.title, now.duration_since(stats.started).as_secs()).into(),
+        ratatui::text::Line::styled(heartbeat, Style::default().fg(heartbeat_color)),
+        match stats.rtt.count {
+            0 => "no rtt".into(),
+            _ => stats.rtt.to_string().into(),
+        },
+        ratatui::text::Line::styled(
+            format!("sn {}", stats.loss),
+            Style::default().fg(if stats.loss.has_loss() { Color::Red } else { Color::Green }),
+        ),
+    ])
+    .block(Block::default().borders(Borders::ALL).title("rcn cli (q to quit)"));
+    f.render_widget(header, chunks[0]);
+
+    let rows: Vec<_> = stats.codes.iter()
+    .map(|(code, stat)| Row::new(vec![
+        format!("{:?}", MCode::new(*code)),
+        stat.count.to_string(),
+        format!("{:.1}", stat.rate),
+    ]))
+    .collect();
+    let widths = [Constraint::Percentage(50), Constraint::Percentage(25), Constraint::Percentage(25)];
+    let table = Table::new(rows)
+    .header(Row::new(vec!["code", "count", "rate/s"]).style(Style::default().fg(Color::Cyan)))
+    .widths(&widths)
+    .block(Block::default().borders(Borders::ALL).title("packets"));
+    f.render_widget(table, middle[0]);
+
+    let (requested, answered, refused) = stats.channels.active_states();
+    let channels: Vec<_> = std::iter::once(format!("active: requested [{requested}] answered [{answered}] refused [{refused}]"))
+    .chain(stats.channels.summary())
+    .map(ListItem::new)
+    .collect();
+    let channels = List::new(channels)
+    .block(Block::default().borders(Borders::ALL).title("channels"));
+    f.render_widget(channels, middle[1]);
+
+    let recent: Vec<_> = stats.recent.iter().rev().map(|x| ListItem::new(x.as_str())).collect();
+    let recent = List::new(recent)
+    .block(Block::default().borders(Borders::ALL).title("recent packets"));
+    f.render_widget(recent, chunks[2]);
+
+    let errors: Vec<_> = stats.errors.iter().rev()
+    .map(|x| ListItem::new(x.as_str()).style(Style::default().fg(Color::Red)))
+    .collect();
+    let errors = List::new(errors)
+    .block(Block::default().borders(Borders::ALL).title("recent errors"));
+    f.render_widget(errors, chunks[3]);
+}
@@ -0,0 +1,176 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+pub mod utils;
+pub mod vn_proto;
+pub mod vn_charset;
+pub mod vn_redact;
+
+#[cfg(feature = "auth")]
+pub mod vn_auth;
+
+#[cfg(feature = "compress")]
+pub mod vn_compress;
+
+#[cfg(feature = "std")]
+pub mod vn_codec;
+
+#[cfg(feature = "std")]
+pub mod vn_fragment;
+
+#[cfg(feature = "std")]
+pub mod vn_media;
+
+#[cfg(feature = "std")]
+pub mod vn_speech;
+
+#[cfg(feature = "std")]
+pub mod vn_rtp;
+
+#[cfg(feature = "std")]
+pub mod vn_pcap;
+
+#[cfg(feature = "std")]
+pub mod vn_fields;
+
+#[cfg(feature = "std")]
+pub mod vn_key;
+
+#[cfg(feature = "std")]
+pub mod vn_explain;
+
+#[cfg(feature = "std")]
+pub mod vn_minimize;
+
+#[cfg(feature = "std")]
+pub mod vn_diffms;
+
+#[cfg(feature = "std")]
+pub mod vn_seq;
+
+#[cfg(feature = "std")]
+pub mod vn_anomaly;
+
+#[cfg(any(feature = "runtime", feature = "smol"))]
+pub mod vn_session;
+
+#[cfg(feature = "std")]
+pub mod vn_socket_name;
+
+#[cfg(any(feature = "runtime", feature = "smol"))]
+pub mod vn_conn_stats;
+
+#[cfg(any(feature = "runtime", feature = "smol"))]
+pub mod vn_capture;
+
+#[cfg(any(feature = "runtime", feature = "smol"))]
+pub mod vn_report;
+
+#[cfg(any(feature = "runtime", feature = "smol"))]
+pub mod vn_acl;
+
+#[cfg(any(feature = "runtime", feature = "smol"))]
+pub mod vn_epoch;
+
+#[cfg(any(feature = "runtime", feature = "smol"))]
+pub mod vn_fsm_id;
+
+#[cfg(any(feature = "runtime", feature = "smol"))]
+pub mod vn_cn_state;
+
+#[cfg(any(feature = "runtime", feature = "smol"))]
+pub mod vn_handshake;
+
+#[cfg(any(feature = "runtime", feature = "smol"))]
+pub mod vn_dedup;
+
+#[cfg(feature = "runtime")]
+pub mod vn_unix_socket;
+
+#[cfg(feature = "runtime")]
+pub mod vn_session_handle;
+
+#[cfg(feature = "runtime")]
+pub mod vn_scenario;
+
+#[cfg(feature = "runtime")]
+pub mod vn_play_queue;
+
+#[cfg(feature = "runtime")]
+pub mod vn_digit_map;
+
+#[cfg(feature = "runtime")]
+pub mod vn_audio;
+
+#[cfg(feature = "runtime")]
+pub mod vn_ms_sim;
+
+#[cfg(feature = "runtime")]
+pub mod vn_ports;
+
+#[cfg(feature = "runtime")]
+pub mod vn_echo;
+
+#[cfg(feature = "runtime")]
+pub mod vn_impair;
+
+#[cfg(feature = "runtime")]
+pub mod vn_testkit;
+
+#[cfg(feature = "protobuf")]
+pub mod vn_pb;
+
+#[cfg(feature = "grpc")]
+pub mod vn_grpc;
+
+#[cfg(feature = "events")]
+pub mod vn_events;
+
+#[cfg(feature = "runtime")]
+pub mod vn_chaos;
+
+#[cfg(feature = "runtime")]
+pub mod vn_proxy;
+
+#[cfg(feature = "runtime")]
+pub mod vn_shadow;
+
+#[cfg(feature = "runtime")]
+pub mod vn_channels;
+
+#[cfg(feature = "runtime")]
+pub mod vn_cdr;
+
+#[cfg(feature = "runtime")]
+pub mod vn_storage;
+
+#[cfg(feature = "runtime")]
+pub mod vn_pool;
+
+#[cfg(feature = "runtime")]
+pub mod vn_inspect;
+
+#[cfg(feature = "runtime")]
+pub mod vn_inject;
+
+#[cfg(feature = "runtime")]
+pub mod vn_marker;
+
+#[cfg(feature = "runtime")]
+pub mod vn_tail;
+
+#[cfg(feature = "runtime")]
+pub mod vn_probe;
+
+#[cfg(feature = "runtime")]
+pub mod vn_audit;
+
+#[cfg(feature = "runtime")]
+pub mod vn_canary;
+
+#[cfg(feature = "sip")]
+pub mod vn_sip;
+
+#[cfg(feature = "tls")]
+pub mod vn_tls;
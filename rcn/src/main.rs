@@ -1,142 +1,220 @@
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, CommandFactory};
+use rcn::{utils, vn_acl, vn_anomaly, vn_audit, vn_auth, vn_canary, vn_capture, vn_cdr, vn_channels, vn_chaos, vn_charset, vn_codec, vn_conn_stats, vn_diffms, vn_digit_map, vn_epoch, vn_explain, vn_fsm_id, vn_handshake, vn_impair, vn_inject, vn_inspect, vn_key, vn_marker, vn_media, vn_minimize, vn_ms_sim, vn_pool, vn_ports, vn_probe, vn_proto, vn_proxy, vn_redact, vn_report, vn_scenario, vn_seq, vn_session, vn_shadow, vn_socket_name, vn_speech, vn_storage, vn_tail};
 
+#[cfg(feature = "sip")]
+use rcn::vn_sip;
 
-pub mod utils;
-pub mod vn_proto;
-pub mod vn_unix_socket;
+#[cfg(feature = "grpc")]
+use rcn::vn_grpc;
+
+#[cfg(feature = "events")]
+use rcn::vn_events;
+
+
+pub mod subcmd_cli;
 pub mod subcmd_decvn;
+pub mod subcmd_fuzz_send;
+pub mod subcmd_completions;
+pub mod subcmd_codes;
+pub mod subcmd_scenario;
+pub mod subcmd_ms_sim;
+pub mod subcmd_proxy;
+pub mod subcmd_replay;
+pub mod subcmd_capture;
+pub mod subcmd_gen_fixtures;
+pub mod subcmd_bench_loop;
+pub mod subcmd_doctor;
+pub mod subcmd_ctl;
+pub mod subcmd_report;
+pub mod subcmd_inspect;
+pub mod subcmd_probe;
+pub mod subcmd_audit;
+pub mod subcmd_diffms;
+pub mod self_test;
+
+#[cfg(feature = "sip")]
+pub mod subcmd_b2bua;
+
+#[cfg(feature = "tui")]
+pub mod cli_dashboard;
 
 fn main() -> Result<()> {
-    utils::log::init_log();
     let args = CmdArgs::parse();
-    match &args.cmd {
-        SubCmd::Decvn(sub) => subcmd_decvn::run(&sub),
+    if is_tui(&args) {
+        // logs would garble the dashboard
+        utils::log::init_log2(env!("CARGO_PKG_NAME"), std::io::sink);
+    } else {
+        utils::log::init_log();
+    }
+
+    if let Some(level) = &args.log_level {
+        utils::log::set_log_level(level)?;
+    }
+    if let Some(path) = &args.ctl_socket {
+        subcmd_ctl::serve(path)?;
+    }
+
+    vn_redact::set_redact(&args.redact);
+    vn_socket_name::set_socket_naming(vn_socket_name::SocketNaming::new(&args.cn_socket_template, &args.ms_socket_template)?);
+
+    let seed = args.seed.unwrap_or_else(utils::rng::seed_from_time);
+    tracing::info!("seed [{seed}]");
+    let rng = utils::rng::SimRng::new(seed);
+
+    if args.self_test {
+        self_test::run()?;
+    }
+    let Some(cmd) = &args.cmd else {
+        if args.self_test {
+            return Ok(())
+        }
+        CmdArgs::command().error(clap::error::ErrorKind::MissingSubcommand, "a subcommand is required without --self-test").exit()
+    };
+
+    match cmd {
+        SubCmd::Decvn(sub) => subcmd_decvn::run(sub),
         SubCmd::Cli(sub) => {
             tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()?
-            .block_on(rcn::run(sub))
+            .block_on(subcmd_cli::run(sub, &rng))
+        },
+        SubCmd::FuzzSend(sub) => {
+            tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?
+            .block_on(subcmd_fuzz_send::run(sub, &rng))
+        },
+        SubCmd::Completions(sub) => subcmd_completions::run(sub, &mut CmdArgs::command()),
+        SubCmd::Codes(sub) => subcmd_codes::run(sub),
+        SubCmd::Scenario(sub) => {
+            tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?
+            .block_on(subcmd_scenario::run(sub, &rng))
+        },
+        SubCmd::ScenarioFromCapture(sub) => subcmd_scenario::from_capture(sub),
+        SubCmd::MsSim(sub) => {
+            tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?
+            .block_on(subcmd_ms_sim::run(sub, &rng))
+        },
+        SubCmd::Proxy(sub) => {
+            tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?
+            .block_on(subcmd_proxy::run(sub, &rng))
+        },
+        SubCmd::CaptureConvert(sub) => subcmd_capture::convert(sub),
+        SubCmd::GenFixtures(sub) => subcmd_gen_fixtures::run(sub),
+        SubCmd::Replay(sub) => {
+            tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?
+            .block_on(subcmd_replay::run(sub))
+        },
+        SubCmd::BenchLoop(sub) => {
+            // one thread, as a CN session is driven
+            tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(subcmd_bench_loop::run(sub))
+        },
+        SubCmd::Doctor(sub) => {
+            tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?
+            .block_on(subcmd_doctor::run(sub))
+        },
+        SubCmd::Ctl(sub) => subcmd_ctl::run(sub),
+        SubCmd::Report(sub) => subcmd_report::run(sub),
+        SubCmd::Inspect(sub) => subcmd_inspect::run(sub),
+        SubCmd::Audit(sub) => subcmd_audit::run(sub),
+        SubCmd::Probe(sub) => {
+            tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?
+            .block_on(subcmd_probe::run(sub))
+        },
+        SubCmd::Diffms(sub) => {
+            tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?
+            .block_on(subcmd_diffms::run(sub))
+        },
+        #[cfg(feature = "sip")]
+        SubCmd::B2bua(sub) => {
+            tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?
+            .block_on(subcmd_b2bua::run(sub, &rng))
         },
     }
 }
 
-#[derive(Parser, Debug)]
-#[clap(name = "rcn", author, about, version)]
-struct CmdArgs {
-    #[clap(subcommand)]
-    cmd: SubCmd,
+fn is_tui(_args: &CmdArgs) -> bool {
+    #[cfg(feature = "tui")]
+    if let Some(SubCmd::Cli(sub)) = &_args.cmd {
+        return sub.tui
+    }
+    false
 }
 
 #[derive(Parser, Debug)]
-enum SubCmd {
-    Decvn(subcmd_decvn::CmdArgs),
-    Cli(rcn::CmdArgs),
-}
-
-
-
-// async fn async_main() -> Result<()> {
-//     tracing::debug!("hello");
-//     rcn::run().await?;
-//     Ok(())
-// }
-
-mod rcn {
-    use std::{path::Path, fmt::Write};
-
-    use anyhow::{Result, Context, bail};
-    use clap::Parser;
-    use tokio::net::UnixDatagram;
-    use tracing::debug;
-
-    use crate::vn_proto::{Header, MCodeType, PacketRef, RegisterRef};
-
-    #[derive(Parser, Debug)]
-    #[clap(name = "cli", author, about, version)]
-    pub struct CmdArgs {
-
-    }
-    
-    pub async fn run(_args: &CmdArgs) -> Result<()> {
-
-        let cindir = std::env::var(CINDIR).with_context(||"can't get env [{CINDIR}]")?;
-        let cindir_path: &Path = cindir.as_ref();
-
-        let cn_id = 5_u32;
-        
-        let mut cn_socket_path = cindir_path.join("mscn");
-        write!(cn_socket_path.as_mut_os_string(), "{cn_id}")?;
-        tokio::fs::remove_file(&cn_socket_path).await.with_context(||format!("failed to remove unix socket path [{cn_socket_path:?}]"))?;
-        let socket = UnixDatagram::bind(&cn_socket_path)
-        .with_context(||format!("can't bind unix socket path [{cn_socket_path:?}]"))?;
-
-        let ms_socket_path = cindir_path.join("msvn");
-        let mut send_buf = vec![0_u8; 1700];
-        let mut recv_buf = vec![0_u8; 1700];
-
-        {
-            let header = Header {
-                code: MCodeType::CNISUP.code(),
-                fsm_id: cn_id * 1000000,
-                ..Default::default()
-            };
-            let len = header.write_to(&mut send_buf[..]);
-            socket.send_to(&send_buf[..len], &ms_socket_path).await.with_context(||"sendto failed")?;
-            debug!("header={header:?}");
-            debug!("sent to [{ms_socket_path:?}], bytes [{len}]");
-    
-            let (recv_len, from) = socket.recv_from(&mut recv_buf).await.with_context(||"recvfrom failed")?;
-            debug!("recv from [{from:?}], bytes [{recv_len}]");
-            let packet = PacketRef::parse_from(&recv_buf[..recv_len]).with_context(||"parse packet failed")?;
-            debug!("  {packet:?}");
-
-            if packet.code() != MCodeType::CNISUP_ACK.code() {
-                bail!("expect CNISUP_ACK but [{:?}]",packet.code())
-            }
-        }
+#[clap(name = "rcn", author, about, version)]
+struct CmdArgs {
+    #[clap(long = "seed", global = true, long_help = "seed of all random behavior, printed at startup when omitted")]
+    seed: Option<u64>,
 
-        {
-            let (recv_len, from) = socket.recv_from(&mut recv_buf).await.with_context(||"recvfrom failed")?;
-            debug!("recv from [{from:?}], bytes [{recv_len}]");
-            let packet = PacketRef::parse_from(&recv_buf[..recv_len]).with_context(||"parse packet failed")?;
-            debug!("  {packet:?}");
+    #[clap(long = "redact", global = true, value_delimiter = ',', long_help = "fields hidden in logs, reports and captures: as_call_id, agora_info, rtmp_key")]
+    redact: Vec<vn_redact::RedactField>,
 
-            if packet.code() != MCodeType::REGISTER.code() {
-                bail!("expect CNISUP_ACK but [{:?}]", packet.code())
-            }
+    #[clap(long = "log-level", global = true, long_help = "log filter instead of RUST_LOG, e.g. rcn=debug,vn_proto=trace, bare module names are of rcn")]
+    log_level: Option<String>,
 
-            let reg = RegisterRef::parse_from(packet.payload()).with_context(||"parse register packet failed")?;
-            debug!("  {reg:?}");
+    #[clap(long = "ctl-socket", global = true, long_help = "unix socket path taking rcn ctl commands, e.g. rcn ctl log-level to change levels without restart")]
+    ctl_socket: Option<std::path::PathBuf>,
 
+    #[clap(long = "cn-socket-template", global = true, default_value = "mscn{id}", long_help = "path of a CN socket relative to CINDIR, {id} is the cn id, e.g. cn/cn_{id}.sock")]
+    cn_socket_template: String,
 
-            let header = Header {
-                code: MCodeType::REGISTER_ACK.code(),
-                fsm_id: cn_id * 1000000,
-                ..Default::default()
-            };
-            let len = header.write_to2(&mut send_buf[..], &[0][..]);
-            socket.send_to(&send_buf[..len], &ms_socket_path).await.with_context(||"sendto failed")?;
-            debug!("header={header:?}");
-            debug!("sent to [{ms_socket_path:?}], bytes [{len}]");
+    #[clap(long = "ms-socket-template", global = true, default_value = "msvn", long_help = "path of the MS socket relative to CINDIR, e.g. ms/ms.sock")]
+    ms_socket_template: String,
 
-        }
+    #[clap(long = "self-test", global = true, long_help = "round-trip one message of every type through its builder and parser and print a report, exits non-zero on a mismatch, then runs the subcommand if any")]
+    self_test: bool,
 
-        loop {
-            let (recv_len, from) = socket.recv_from(&mut recv_buf).await.with_context(||"recvfrom failed")?;
-            debug!("recv from [{from:?}], bytes [{recv_len}]");
-            let packet = PacketRef::parse_from(&recv_buf[..recv_len]).with_context(||"parse packet failed")?;
-            debug!("  {packet:?}");
-        }
-        
-        // Ok(())
-    }
-
-    const CINDIR: &str = "CINDIR";
+    #[clap(subcommand)]
+    cmd: Option<SubCmd>,
 }
 
-
-
-
-
+// parsed once, size doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Parser, Debug)]
+enum SubCmd {
+    Decvn(subcmd_decvn::CmdArgs),
+    Cli(subcmd_cli::CmdArgs),
+    FuzzSend(subcmd_fuzz_send::CmdArgs),
+    Completions(subcmd_completions::CmdArgs),
+    Codes(subcmd_codes::CmdArgs),
+    Scenario(subcmd_scenario::CmdArgs),
+    ScenarioFromCapture(subcmd_scenario::FromCaptureArgs),
+    MsSim(subcmd_ms_sim::CmdArgs),
+    Proxy(subcmd_proxy::CmdArgs),
+    Replay(subcmd_replay::CmdArgs),
+    CaptureConvert(subcmd_capture::ConvertArgs),
+    GenFixtures(subcmd_gen_fixtures::CmdArgs),
+    BenchLoop(subcmd_bench_loop::CmdArgs),
+    Doctor(subcmd_doctor::CmdArgs),
+    Ctl(subcmd_ctl::CmdArgs),
+    Report(subcmd_report::CmdArgs),
+    Inspect(subcmd_inspect::CmdArgs),
+    Audit(subcmd_audit::CmdArgs),
+    Probe(subcmd_probe::CmdArgs),
+    Diffms(subcmd_diffms::CmdArgs),
+    #[cfg(feature = "sip")]
+    B2bua(subcmd_b2bua::CmdArgs),
+}
@@ -0,0 +1,231 @@
+//! `--self-test`, every fixture of gen-fixtures built, parsed with
+//! parse_message, built again from the parsed values and compared byte by
+//! byte, a one-command check of a new rcn build in the lab.
+
+use std::{fmt, net::IpAddr};
+
+use anyhow::{Result, Context, bail};
+use bytes::BufMut;
+
+use crate::{
+    subcmd_gen_fixtures::{fixtures, Fixture},
+    vn_proto::{
+        parse_message, Capability, CodecDesc, CodecDescRef, Filename, Message, OpenRtpConnect, PacketRef, Play, PlayAck,
+        Register, RequestChannel, RequestChannelAck, ResFromTag, RtpInfo, StrRef, TagType, HEADER_LENGTH,
+    },
+};
+
+pub fn run() -> Result<()> {
+    let checks = check_all();
+    for check in checks.iter() {
+        println!("{check}");
+    }
+    let failed = checks.iter().filter(|x| x.result.is_err()).count();
+    println!("self-test of rcn {}: [{}] ok, [{failed}] failed", env!("CARGO_PKG_VERSION"), checks.len() - failed);
+    if failed > 0 {
+        bail!("[{failed}] of [{}] messages failed self-test", checks.len())
+    }
+    Ok(())
+}
+
+/// outcome of one fixture
+#[derive(Debug)]
+pub struct Check {
+    pub name: String,
+    pub len: usize,
+    /// payload parsed by its layout, copied as is otherwise
+    pub decoded: bool,
+    pub result: Result<()>,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = if self.result.is_ok() { "ok" } else { "FAIL" };
+        let kind = if self.decoded { "decoded" } else { "header only" };
+        write!(f, "[{status:^4}] {:<32} {:>4} bytes, {kind}", self.name, self.len)?;
+        if let Err(e) = &self.result {
+            write!(f, "\n       -> {e:#}")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn check_all() -> Vec<Check> {
+    fixtures().iter().map(check).collect()
+}
+
+fn check(fixture: &Fixture) -> Check {
+    let mut decoded = false;
+    let result = round_trip(&fixture.data, &mut decoded);
+    Check { name: fixture.name.clone(), len: fixture.data.len(), decoded, result }
+}
+
+fn round_trip(data: &[u8], decoded: &mut bool) -> Result<()> {
+    let msg = parse_message(data).with_context(||"parse failed")?;
+    let packet = PacketRef::parse_from(data)?;
+    *decoded = !matches!(msg, Message::Unknown { .. });
+    let payload = rebuild(&msg).with_context(||format!("rebuild {msg:?} failed"))?;
+
+    let mut rebuilt = Vec::with_capacity(HEADER_LENGTH + payload.len());
+    packet.to_header().write_to2(&mut rebuilt, &payload[..]);
+    // cn path after the packet is not part of the message
+    let origin = &data[..data.len() - packet.cn_path_data().len()];
+    if let Some(pos) = origin.iter().zip(rebuilt.iter()).position(|(a, b)| a != b) {
+        bail!("byte [{pos}] built [0x{:02x}] but rebuilt [0x{:02x}] from {msg:?}", origin[pos], rebuilt[pos])
+    }
+    if origin.len() != rebuilt.len() {
+        bail!("built [{}] bytes but rebuilt [{}] from {msg:?}", origin.len(), rebuilt.len())
+    }
+    Ok(())
+}
+
+/// payload written by the builder of msg from its parsed values
+fn rebuild(msg: &Message<'_>) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    match msg {
+        Message::Heartbeat | Message::ReleaseChannel => {},
+        Message::Register(r) => {
+            let mut tags = Vec::new();
+            let mut capability = None;
+            for tag in r.tags() {
+                let tag = tag?;
+                if capability.is_none() && tag.tag_type() == Some(TagType::CAPABILITY) {
+                    capability = Some(Capability::parse_from(tag.payload())?);
+                    continue
+                }
+                // tags without a builder as they came
+                tags.put_u8(tag.tag_code());
+                tags.put_u16(tag.payload().len() as u16);
+                tags.put_slice(tag.payload());
+            }
+            if !r.media_info_remains.is_empty() {
+                bail!("[{}] bytes left in MEDIAINFO", r.media_info_remains.len())
+            }
+            Register {
+                ip: r.ip,
+                support_t38: r.media_info.support_t38,
+                audio_codecs: codecs(&r.media_info.audio_codecs),
+                video_codecs: codecs(&r.media_info.video_codecs),
+                fax_codecs: codecs(&r.media_info.fax_codecs),
+                capability,
+                // VERSION and the like go in tags as they came
+                ..Default::default()
+            }.write_to(&mut buf);
+            buf.extend_from_slice(&tags);
+        },
+        Message::RequestChannel(r) => {
+            RequestChannel {
+                ice_type: r.part1().ice_type_code(),
+                life_seconds: r.part1().life_seconds(),
+                media_type: r.part1().media_type_code(),
+                as_call_id: owned(r.as_call_id()),
+                agora_info: r.agora_info().map(owned),
+                is_nbup: r.part2().is_nbup(),
+                ptime: r.part2().ptime(),
+                is_caller: r.part2().is_caller(),
+                codec: r.part2().codec_code(),
+                amr_mode: r.part2().amr_mode(),
+                webrtc: r.webrtc_strs().map(owned).collect(),
+            }.write_to(&mut buf);
+        },
+        Message::RequestChannelAck(r) => {
+            let p = r.part1();
+            RequestChannelAck {
+                result: p.result(),
+                audio_port: p.audio_port(),
+                video_port: p.video_port(),
+                fax_port: p.fax_port(),
+                media_type: p.media_type(),
+                webrtc: r.webrtc_strs().map(owned).collect(),
+            }.write_to(&mut buf);
+        },
+        Message::OpenRtpConnect(r) => {
+            let mut rtpinfos = Vec::new();
+            for info in r.rtpinfo_iter() {
+                let info = info?;
+                let IpAddr::V4(ip) = info.part1().ip() else { bail!("rtpinfo of ipv6") };
+                rtpinfos.push(RtpInfo {
+                    ip,
+                    port: info.part1().port(),
+                    media_type: info.part1().media_type(),
+                    internal_pltyp: info.part1().internal_pltyp(),
+                    nego_pltyp: info.part1().nego_pltyp(),
+                    attribute: owned(info.attribute()),
+                    tele_event: info.part2().tele_event(),
+                    direction: info.part2().direction(),
+                    webrtc: info.webrtc_strs().map(owned).collect(),
+                });
+            }
+            OpenRtpConnect { rtpinfos }.write_to(&mut buf);
+        },
+        Message::ResFromTag(r) => {
+            ResFromTag { value: String::from_utf8_lossy(r.value()).into_owned() }.write_to(&mut buf);
+        },
+        Message::Play(r) => {
+            let p = r.part1();
+            let mut files = Vec::new();
+            for file in r.files() {
+                let file = file?;
+                files.push(Filename { format: file.format(), filename: owned(file.filename().clone()) });
+            }
+            Play {
+                interval: p.interval(),
+                play_times: p.play_times(),
+                max_duration: p.max_duration(),
+                key_mask: p.key_mask(),
+                record: p.record(),
+                speech_barge: p.speech_barge(),
+                erase_dtmf: p.erase_dtmf(),
+                files,
+            }.write_to(&mut buf);
+        },
+        Message::PlayAck(r) => {
+            PlayAck { result: r.part1().result(), play_duration: r.part1().play_duration() }.write_to(&mut buf);
+        },
+        Message::Cancel(r) => buf.put_u16(r.op_code()),
+        Message::OpenRtpConnectAck(r) => buf.put_u8(r.value()),
+        Message::CloseRtpConnect(r) => buf.put_u8(r.value()),
+        Message::CloseRtpConnectAck(r) => buf.put_u8(r.value()),
+        Message::Unknown { payload, .. } => buf.extend_from_slice(payload),
+    }
+    Ok(buf)
+}
+
+/// as the builder got it, strings of fixtures are utf8
+fn owned(s: StrRef<'_>) -> String {
+    String::from_utf8_lossy(s.data()).into_owned()
+}
+
+fn codecs(v: &[CodecDescRef<'_>]) -> Vec<CodecDesc> {
+    v.iter().map(|x| CodecDesc {
+        index: x.index(),
+        payload_type: x.payload_type(),
+        mapstr: String::from_utf8_lossy(x.map_str_data()).into_owned(),
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{subcmd_gen_fixtures::fixtures, vn_proto::MCodeType};
+
+    use super::{check, check_all};
+
+    #[test]
+    fn test_self_test() {
+        let checks = check_all();
+        for x in checks.iter() {
+            assert!(x.result.is_ok(), "{x}");
+        }
+        assert!(checks.iter().any(|x| x.name == "PLAY" && x.decoded));
+        assert!(checks.iter().any(|x| x.name == "REGISTER_unknown_tag" && x.decoded));
+
+        // a builder and parser disagreeing is reported
+        let mut fixture = fixtures().into_iter().find(|x| x.name == "PLAY_ACK").unwrap();
+        assert_eq!(fixture.data[2..4], MCodeType::PLAY_ACK.code().to_be_bytes());
+        fixture.data.push(0xee);
+        fixture.data[1] += 1;
+        let failed = check(&fixture);
+        assert!(failed.result.is_err(), "{failed}");
+        assert!(failed.to_string().starts_with("[FAIL] PLAY_ACK"), "{failed}");
+    }
+}
@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Parser;
+
+use crate::{vn_audit::{audit, render_audit, AuditLimits}, vn_capture::read_capture};
+
+pub fn run(args: &CmdArgs) -> Result<()> {
+    let records = read_capture(&args.input)?;
+    let limits = AuditLimits {
+        packet: args.packet_limit,
+        string: args.string_limit,
+        tags: args.tag_limit,
+        near_pct: args.near_pct,
+    };
+    let report = audit(&records, &limits);
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", render_audit(&report));
+    }
+    Ok(())
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "audit", author, about = "sizes of packets, string fields and tag lists per code across a capture, flagging those near protocol limits", version)]
+pub struct CmdArgs {
+    #[clap(long_help = "jsonl or vnrec capture, e.g. of rcn cli --capture")]
+    input: PathBuf,
+
+    #[clap(long = "json", long_help = "print the report as json")]
+    json: bool,
+
+    #[clap(long = "packet-limit", long_help = "bytes of a whole packet, the MS receive buffer", default_value = "1700")]
+    packet_limit: usize,
+
+    #[clap(long = "string-limit", long_help = "bytes of a string field", default_value = "255")]
+    string_limit: usize,
+
+    #[clap(long = "tag-limit", long_help = "tags of a packet", default_value = "255")]
+    tag_limit: usize,
+
+    #[clap(long = "near-pct", long_help = "percent of a limit flagged as near it", default_value = "80")]
+    near_pct: usize,
+}
@@ -0,0 +1,86 @@
+use std::net::Ipv4Addr;
+
+use anyhow::{Result, Context};
+use clap::Parser;
+use tokio::net::UdpSocket;
+use tracing::{info, Instrument};
+
+use crate::{
+    utils::{log::tenant_span, rng::SimRng},
+    vn_cdr::CdrWriter,
+    vn_fsm_id::{FsmIdAllocator, FsmIdCheck, FsmIdSpace, FsmIdStrategy},
+    vn_session::CnSession,
+    vn_sip::{SipBridge, SipBridgeConfig},
+};
+
+pub async fn run(args: &CmdArgs, rng: &SimRng) -> Result<()> {
+    run_bridge(args, rng).instrument(tenant_span(args.tenant.as_deref())).await
+}
+
+async fn run_bridge(args: &CmdArgs, rng: &SimRng) -> Result<()> {
+    let mut session = CnSession::bind_env(args.cn_id).await?;
+    session.set_tenant(args.tenant.clone());
+    let space = args.fsm_id_space.unwrap_or_else(|| FsmIdSpace::of_cn(args.cn_id));
+    let mut ids = FsmIdAllocator::with_strategy(space, args.fsm_id_strategy.clone())?;
+    ids.set_rng(rng.fork("b2bua.fsm_ids"));
+    session.set_fsm_ids(ids, args.fsm_id_check);
+    session.handshake().await?;
+    session.accept_register().await?;
+
+    let udp = UdpSocket::bind(&args.listen).await.with_context(||format!("bind SIP [{}] failed", args.listen))?;
+    let mut bridge = SipBridge::new(udp, session, SipBridgeConfig {
+        media_ip: args.media_ip,
+        codecs: args.codec.clone(),
+    });
+    if let Some(path) = &args.cdr {
+        bridge.set_cdr(Some(CdrWriter::create(path)?));
+    }
+    info!("SIP listening on [{}]", bridge.local_addr()?);
+
+    let r = tokio::select! {
+        r = bridge.run() => r,
+        _r = tokio::signal::ctrl_c() => Ok(()),
+    };
+    info!("[{}] calls still up", bridge.num_calls());
+    for line in bridge.channels().summary() {
+        info!("{line}");
+    }
+    if let Some(path) = &args.channels_json {
+        crate::subcmd_cli::write_channels_json(path, bridge.channels())?;
+    }
+    r
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "b2bua", author, about = "answer SIP calls over udp and bridge each onto a VN channel", version)]
+pub struct CmdArgs {
+    #[clap(long = "listen", long_help = "udp address for SIP", default_value = "127.0.0.1:5060")]
+    listen: String,
+
+    #[clap(long = "media-ip", long_help = "address of MS rtp put into answer SDP", default_value = "127.0.0.1")]
+    media_ip: Ipv4Addr,
+
+    #[clap(long = "codec", value_delimiter = ',', long_help = "payload types accepted from offers in order of preference", default_value = "8,0")]
+    codec: Vec<u8>,
+
+    #[clap(long = "channels-json", long_help = "write open and ended channels into this json file on exit, rcn ctl export-channels turns it into csv")]
+    channels_json: Option<std::path::PathBuf>,
+
+    #[clap(long = "tenant", long_help = "label of the team this bridge runs for, carried into logs, --channels-json and --cdr")]
+    tenant: Option<String>,
+
+    #[clap(long = "cdr", long_help = "append a call detail record of each call's channel as it ends, csv if it ends with .csv else jsonl")]
+    cdr: Option<std::path::PathBuf>,
+
+    #[clap(long = "cn-id", default_value = "5")]
+    cn_id: u32,
+
+    #[clap(long = "fsm-id-space", long_help = "fsm_ids of this CN as first-last, e.g. 7000000-7499999, first one for link level packets, default cn_id * 1000000 on")]
+    fsm_id_space: Option<FsmIdSpace>,
+
+    #[clap(long = "fsm-id-strategy", long_help = "how fsm_ids of calls are picked from --fsm-id-space: increment[:step], random or list:<id>,<id>..", default_value = "increment")]
+    fsm_id_strategy: FsmIdStrategy,
+
+    #[clap(long = "fsm-id-check", value_enum, default_value = "off", long_help = "drop (discard) or only log (warn) packets whose fsm_id is outside --fsm-id-space")]
+    fsm_id_check: FsmIdCheck,
+}
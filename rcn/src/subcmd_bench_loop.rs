@@ -0,0 +1,223 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use anyhow::{Result, bail};
+use clap::Parser;
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::{
+    utils::datagram::LoopDatagram,
+    vn_fsm_id::FsmIdSpace,
+    vn_proto::{Header, MCodeType, PacketRef, PlayAck, PlayAckRef},
+    vn_session::CnSession,
+};
+
+/// system allocator counting allocations of the whole process
+struct CountingAlloc;
+
+static ALLOCS: AtomicU64 = AtomicU64::new(0);
+static ALLOC_BYTES: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        ALLOC_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        ALLOC_BYTES.fetch_add(new_size as u64, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// (allocations, bytes) so far
+fn alloc_counts() -> (u64, u64) {
+    (ALLOCS.load(Ordering::Relaxed), ALLOC_BYTES.load(Ordering::Relaxed))
+}
+
+/// packets between pacing checks
+const PACE_BATCH: u64 = 1000;
+
+pub async fn run(args: &CmdArgs) -> Result<()> {
+    if args.packets == 0 {
+        bail!("no packets to run")
+    }
+    if args.channels == 0 {
+        bail!("at least one channel")
+    }
+    let report = match &args.log {
+        // events go to a sink, only their cost is measured
+        Some(filter) => {
+            let subscriber = tracing_subscriber::fmt()
+            .with_env_filter(filter.as_str())
+            .with_writer(std::io::sink)
+            .finish();
+            let _guard = tracing::subscriber::set_default(subscriber);
+            bench(args.packets, args.pps, args.channels).await?
+        },
+        None => bench(args.packets, args.pps, args.channels).await?,
+    };
+    for line in report.table() {
+        info!("{line}");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+pub struct BenchReport {
+    pub packets: u64,
+    pub elapsed: Duration,
+    /// 0 if unpaced
+    pub target_pps: u64,
+    /// worst time behind the paced schedule
+    pub max_lag: Duration,
+    pub allocs: u64,
+    pub alloc_bytes: u64,
+    /// of fields dispatched, keeps the work from being optimized out
+    pub checksum: u64,
+}
+
+impl BenchReport {
+    pub fn pps(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            x if x > 0.0 => self.packets as f64 / x,
+            _ => 0.0,
+        }
+    }
+
+    pub fn table(&self) -> Vec<String> {
+        let per_packet = |x: u64| x as f64 / self.packets.max(1) as f64;
+        let target = match self.target_pps {
+            0 => "unpaced".to_string(),
+            pps => format!("{pps} pps, {}", if self.pps() >= pps as f64 * 0.99 { "held" } else { "missed" }),
+        };
+        let rows = [
+            ("packets", self.packets.to_string()),
+            ("elapsed", format!("{:?}", self.elapsed)),
+            ("throughput", format!("{:.0} pps", self.pps())),
+            ("per packet", format!("{:.0} ns", self.elapsed.as_nanos() as f64 / self.packets.max(1) as f64)),
+            ("target", target),
+            ("max lag", format!("{:?}", self.max_lag)),
+            ("allocs", self.allocs.to_string()),
+            ("allocs/packet", format!("{:.2}", per_packet(self.allocs))),
+            ("alloc bytes/packet", format!("{:.1}", per_packet(self.alloc_bytes))),
+        ];
+        let width = rows.iter().map(|x| x.0.len()).max().unwrap_or(0);
+        let mut lines = vec![format!("{:<width$}  value", "metric"), format!("{:-<width$}  {:-<12}", "", "")];
+        lines.extend(rows.iter().map(|(name, value)| format!("{name:<width$}  {value}")));
+        lines
+    }
+}
+
+/// PLAY_ACKs of channels fsm_ids taking turns, a full round of sn each
+/// so that looping over them keeps every fsm_id in order
+fn play_acks(cn_id: u32, channels: u32) -> Vec<Vec<u8>> {
+    let base = FsmIdSpace::of_cn(cn_id).base;
+    let mut payload = Vec::new();
+    PlayAck { result: 0, play_duration: 4820 }.write_to(&mut payload);
+    (0..(u16::MAX as usize + 1) * channels as usize)
+    .map(|i| {
+        let header = Header {
+            code: MCodeType::PLAY_ACK.code(),
+            fsm_id: base + 1 + (i % channels as usize) as u32,
+            key: 0,
+            sn: ((i / channels as usize) as u16).wrapping_add(1),
+        };
+        let mut data = Vec::new();
+        header.write_to2(&mut data, &payload[..]);
+        data
+    })
+    .collect()
+}
+
+/// what a CN does with a packet beyond decoding its header
+fn dispatch(packet: &PacketRef<'_>) -> Result<u64> {
+    match MCodeType::try_from(packet.code()) {
+        Ok(MCodeType::PLAY_ACK) => {
+            let ack = PlayAckRef::parse_from(packet.payload())?;
+            Ok(ack.part1().result() as u64 + ack.part1().play_duration() as u64)
+        },
+        _ => Ok(0),
+    }
+}
+
+/// recv and dispatch PLAY_ACKs from memory, paced at pps unless 0
+pub async fn bench(packets: u64, pps: u64, channels: u32) -> Result<BenchReport> {
+    let cn_id = 5;
+    let ms_path = PathBuf::from("/tmp/cin/msvn");
+    let socket = LoopDatagram::new(play_acks(cn_id, channels), ms_path.clone());
+    let mut session = CnSession::with_socket(socket, ms_path, cn_id);
+    let mut report = BenchReport { packets, target_pps: pps, ..Default::default() };
+
+    // per fsm_id state is set up outside the measurement
+    for _ in 0..channels {
+        report.checksum += dispatch(&session.recv_packet().await?)?;
+    }
+
+    let (allocs, alloc_bytes) = alloc_counts();
+    let start = Instant::now();
+    for n in 0..packets {
+        if pps > 0 && n % PACE_BATCH == 0 {
+            let due = start + Duration::from_secs_f64(n as f64 / pps as f64);
+            report.max_lag = report.max_lag.max(Instant::now().saturating_duration_since(due));
+            tokio::time::sleep_until(due).await;
+        }
+        report.checksum += dispatch(&session.recv_packet().await?)?;
+    }
+    report.elapsed = start.elapsed();
+
+    let (allocs2, alloc_bytes2) = alloc_counts();
+    report.allocs = allocs2 - allocs;
+    report.alloc_bytes = alloc_bytes2 - alloc_bytes;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{bench, play_acks};
+
+    #[tokio::test]
+    async fn test_bench_loop() {
+        assert_eq!(play_acks(5, 2).len(), 2 * 65536);
+
+        let report = bench(3000, 0, 2).await.unwrap();
+        assert_eq!(report.packets, 3000);
+        assert_eq!(report.checksum, 3002 * 4820);
+        assert!(report.pps() > 0.0);
+        assert_eq!(report.table().len(), 11);
+
+        let report = bench(2000, 100000, 1).await.unwrap();
+        assert!(report.elapsed >= std::time::Duration::from_millis(10));
+        assert!(report.table()[6].contains("100000 pps"));
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "bench-loop", author, about = "measure decode and dispatch throughput of PLAY_ACKs over an in-memory transport", version)]
+pub struct CmdArgs {
+    #[clap(long = "packets", long_help = "packets to measure", default_value = "1000000")]
+    packets: u64,
+
+    #[clap(long = "pps", long_help = "pace at this many packets per second, 0 runs as fast as possible", default_value = "100000")]
+    pps: u64,
+
+    #[clap(long = "channels", long_help = "fsm_ids the PLAY_ACKs are spread over", default_value = "4")]
+    channels: u32,
+
+    #[clap(long = "log", long_help = "log filter while measuring, e.g. rcn=debug, events are formatted then discarded")]
+    log: Option<String>,
+}
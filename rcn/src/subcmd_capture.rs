@@ -0,0 +1,117 @@
+use std::{fs::File, io::BufWriter, path::{Path, PathBuf}};
+
+use anyhow::{Result, Context};
+use clap::{Parser, ValueEnum};
+use tracing::info;
+
+use crate::{
+    subcmd_decvn::{parse_hexdump_packets, to_hexdump},
+    vn_capture::{read_capture, to_hex, write_capture, CaptureDir, CaptureFormat, CaptureRecord},
+    vn_proto::{Direction, MCodeType, PacketRef},
+};
+
+pub fn convert(args: &ConvertArgs) -> Result<()> {
+    let from = args.from.unwrap_or_else(|| Format::from_path(&args.input));
+    let to = args.to.unwrap_or_else(|| Format::from_path(&args.output));
+
+    let records = match from {
+        Format::Hexdump => {
+            let text = std::fs::read_to_string(&args.input).with_context(||format!("read hexdump failed [{:?}]", args.input))?;
+            records_from_hexdump(&text)?
+        },
+        Format::Jsonl | Format::Vnrec => read_capture(&args.input)?,
+    };
+
+    match to {
+        Format::Hexdump => {
+            let text = records_to_hexdump(&records)?;
+            std::fs::write(&args.output, text).with_context(||format!("write hexdump failed [{:?}]", args.output))?;
+        },
+        Format::Jsonl | Format::Vnrec => {
+            let format = if to == Format::Vnrec { CaptureFormat::Vnrec } else { CaptureFormat::Jsonl };
+            let file = File::create(&args.output).with_context(||format!("create capture failed [{:?}]", args.output))?;
+            write_capture(BufWriter::new(file), format, &records)?;
+        },
+    }
+    info!("converted [{}] records {from:?} [{:?}] -> {to:?} [{:?}]", records.len(), args.input, args.output);
+    Ok(())
+}
+
+/// legacy hexdumps carry no timing, direction is guessed from code
+fn records_from_hexdump(text: &str) -> Result<Vec<CaptureRecord>> {
+    parse_hexdump_packets(text)?.iter().enumerate()
+    .map(|(n, data)| {
+        let packet = PacketRef::parse_from(&data[..]).with_context(||format!("invalid packet #{n} in hexdump"))?;
+        let dir = match MCodeType::try_from(packet.code()).map(|x| x.direction()) {
+            Ok(Direction::MsToCn) => CaptureDir::MsToCn,
+            _ => CaptureDir::CnToMs,
+        };
+        Ok(CaptureRecord { ts_us: 0, dir, socket: None, hex: to_hex(&data[..]) })
+    })
+    .collect()
+}
+
+/// packets separated by an empty line, timing and direction are lost
+fn records_to_hexdump(records: &[CaptureRecord]) -> Result<String> {
+    let mut text = String::new();
+    for (n, record) in records.iter().enumerate() {
+        if n > 0 {
+            text.push('\n');
+        }
+        text.push_str(&to_hexdump(&record.data()?));
+    }
+    Ok(text)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Hexdump,
+    Jsonl,
+    Vnrec,
+}
+
+impl Format {
+    /// by extension, `.txt` and `.hex` are hexdump
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("txt" | "hex") => Self::Hexdump,
+            _ => match CaptureFormat::from_path(path) {
+                CaptureFormat::Jsonl => Self::Jsonl,
+                CaptureFormat::Vnrec => Self::Vnrec,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{records_from_hexdump, records_to_hexdump};
+
+    #[test]
+    fn test_hexdump_records() {
+        let text = format!(
+            "{}\n{}",
+            include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test_vn_packet/PLAY.txt")),
+            include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test_vn_packet/PLAY_ACK.txt")),
+        );
+        let records = records_from_hexdump(&text).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].dir, crate::vn_capture::CaptureDir::CnToMs);
+        assert_eq!(records[1].dir, crate::vn_capture::CaptureDir::MsToCn);
+        assert_eq!(records_to_hexdump(&records).unwrap(), text);
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "capture-convert", author, about = "convert captures between vnrec, jsonl and legacy hexdump text", version)]
+pub struct ConvertArgs {
+    input: PathBuf,
+
+    output: PathBuf,
+
+    #[clap(long = "from", value_enum, long_help = "input format, guessed from extension by default (.vnrec, .txt/.hex, else jsonl)")]
+    from: Option<Format>,
+
+    #[clap(long = "to", value_enum, long_help = "output format, guessed from extension by default")]
+    to: Option<Format>,
+}
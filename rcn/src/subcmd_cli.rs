@@ -0,0 +1,627 @@
+use std::{collections::VecDeque, path::{Path, PathBuf}, time::{Duration, Instant}};
+
+use anyhow::{Result, Context, bail};
+use clap::{Parser, ValueEnum};
+use rcn::{utils::datagram::Datagram, vn_capture::CaptureWriter, vn_compress::Compression, vn_tls::{TlsDatagram, TlsOptions}};
+use tracing::{debug, info, warn, Instrument};
+
+use crate::{utils::{clock::{SharedClock, Ticker}, log::tenant_span, rate_limit::{RateConfig, RateLimiter}, rng::SimRng}, vn_acl::AclMode, vn_anomaly::{AnomalyConfig, AnomalyDetector}, vn_canary::{run_canary, CanaryConfig, Slo}, vn_cdr::CdrWriter, vn_codec::CallProfile, vn_channels::ChannelRegistry, vn_conn_stats::{self, LinkStats}, vn_epoch::EpochMode, vn_fsm_id::{FsmIdAllocator, FsmIdCheck, FsmIdSpace, FsmIdStrategy}, vn_handshake::{StrayPolicy, StrayRule}, vn_inject::{self, Injector}, vn_marker, vn_pool::{MsPool, PoolEvent, SelectPolicy}, vn_proto::{IceType, LengthPolicy, MCodeType, MediaType, RegisterRef, RequestChannel}, vn_session::CnSession, vn_storage::{self, ChunkConfig}, vn_tail};
+
+#[derive(Parser, Debug)]
+#[clap(name = "cli", author, about, version)]
+pub struct CmdArgs {
+    #[clap(long = "ms", long_help = "msvn path of a MS, repeat to register with a pool of MS instead of $CINDIR/msvn")]
+    ms: Vec<PathBuf>,
+
+    #[clap(long = "policy", value_enum, default_value = "round-robin", long_help = "which MS of pool gets a new channel")]
+    policy: SelectPolicy,
+
+    #[clap(long = "weights", value_delimiter = ',', long_help = "weight of each --ms in order, for weighted policy, e.g. 3,1")]
+    weights: Vec<u32>,
+
+    #[clap(long = "loadgen", long_help = "originate this many REQUESTCHANNELs through the pool")]
+    loadgen: Option<u32>,
+
+    #[clap(long = "loadgen-interval-ms", long_help = "gap between originated channels", default_value = "100")]
+    loadgen_interval_ms: u64,
+
+    #[clap(long = "media-type", value_enum, default_value = "audio-only", long_help = "media type of REQUESTCHANNELs of --loadgen and --canary-interval-ms")]
+    media_type: MediaType,
+
+    #[clap(long = "ice-type", value_enum, default_value = "simple", long_help = "ice type of REQUESTCHANNELs of --loadgen and --canary-interval-ms")]
+    ice_type: IceType,
+
+    #[clap(long = "codecs", value_delimiter = ',', default_value = "PCMA,PCMU", long_help = "audio codecs in preference order, negotiated against the REGISTER of each MS, e.g. PCMA,AMR/8000")]
+    codecs: Vec<String>,
+
+    #[clap(long = "video-codecs", value_delimiter = ',', default_value = "H264", long_help = "video codecs in preference order of --media-type audio-video and video-only")]
+    video_codecs: Vec<String>,
+
+    #[clap(long = "ptime", default_value = "20", long_help = "ptime of REQUESTCHANNELs of --loadgen and --canary-interval-ms")]
+    ptime: u8,
+
+    #[clap(long = "rate", long_help = "send at most this many packets per second in total, as rate[:burst], e.g. 200:50, burst defaults to rate")]
+    rate: Option<RateConfig>,
+
+    #[clap(long = "channel-rate", long_help = "send at most this many packets per second on each fsm_id, as rate[:burst]")]
+    channel_rate: Option<RateConfig>,
+
+    #[clap(long = "hold-ms", long_help = "release originated channels after this long, keep them if not set")]
+    hold_ms: Option<u64>,
+
+    #[clap(long = "transport", value_enum, default_value = "unix")]
+    transport: Transport,
+
+    #[clap(long = "peer", long_help = "host:port of MS, for tls transport")]
+    peer: Option<String>,
+
+    #[clap(long = "ca", long_help = "pem file of trusted CA certificates, for tls transport")]
+    ca: Option<PathBuf>,
+
+    #[clap(long = "server-name", long_help = "name checked against MS certificate, default to host of peer")]
+    server_name: Option<String>,
+
+    #[clap(long = "insecure", long_help = "skip MS certificate validation")]
+    insecure: bool,
+
+    #[clap(long = "compress", long_help = "offer zlib compression of payloads larger than this many bytes")]
+    compress: Option<usize>,
+
+    #[clap(long = "fragment-mtu", long_help = "offer splitting packets larger than this many bytes")]
+    fragment_mtu: Option<usize>,
+
+    #[clap(long = "recv-buf", long_help = "initial recv buffer bytes", default_value = "1700")]
+    recv_buf: usize,
+
+    #[clap(long = "recv-buf-max", long_help = "recv buffer grows up to this many bytes on truncated datagram", default_value = "65536")]
+    recv_buf_max: usize,
+
+    #[clap(long = "length-policy", value_enum, default_value = "truncate", long_help = "parsing of packets whose header length disagrees with datagram size")]
+    length_policy: LengthPolicy,
+
+    #[clap(long = "hdr-out", long_help = "dump request to ACK latency histograms (HdrHistogram logs) into this dir on exit")]
+    hdr_out: Option<PathBuf>,
+
+    #[clap(long = "capture", long_help = "record sent and received packets into this file, binary if it ends with .vnrec else jsonl, s3://bucket/prefix/name streams chunks to a bucket of AWS_ENDPOINT_URL")]
+    capture: Option<PathBuf>,
+
+    #[clap(long = "capture-chunk-bytes", long_help = "cut --capture into numbered files or objects of about this many bytes, 8MiB for s3:// when omitted")]
+    capture_chunk_bytes: Option<usize>,
+
+    #[clap(long = "capture-chunk-secs", long_help = "also cut a chunk of --capture this old, so a quiet link still streams", default_value = "300")]
+    capture_chunk_secs: u64,
+
+    #[clap(long = "channels-json", long_help = "write open and ended channels of --ms pool into this json file on exit, rcn ctl export-channels turns it into csv")]
+    channels_json: Option<PathBuf>,
+
+    #[clap(long = "tenant", long_help = "label of the team this CN runs for, carried into logs, ctl stats, --channels-json and --cdr so shared lab runs can be told apart")]
+    tenant: Option<String>,
+
+    #[clap(long = "cdr", long_help = "append a call detail record of each channel of --ms pool or --canary-interval-ms as it ends, csv if it ends with .csv else jsonl")]
+    cdr: Option<PathBuf>,
+
+    #[cfg(feature = "events")]
+    #[clap(long = "events", long_help = "publish channels of --ms pool or --canary-interval-ms as they are requested, answered and ended, with their CDR, to nats://HOST:PORT/SUBJECT or kafka://HOST:PORT/TOPIC")]
+    events: Option<crate::vn_events::EventTarget>,
+
+    #[cfg(feature = "events")]
+    #[clap(long = "events-source", long_help = "lab or host name carried in each event of --events")]
+    events_source: Option<String>,
+
+    #[clap(long = "peer-acl", value_enum, default_value = "off", long_help = "drop (enforce) or only log (warn) datagrams from other than the MS and --allow-peer paths")]
+    peer_acl: AclMode,
+
+    #[clap(long = "allow-peer", long_help = "path besides the MS datagrams are taken from with --peer-acl, <unnamed> for unbound senders")]
+    allow_peer: Vec<PathBuf>,
+
+    #[clap(long = "epoch", value_enum, default_value = "off", long_help = "offer MS epochs at CNISUP and drop (discard) or only log (warn) packets of a previous MS incarnation, off for MS builds without epochs")]
+    epoch: EpochMode,
+
+    #[clap(long = "handshake-stray", long_help = "what a packet of another code does while waiting for CNISUP_ACK or REGISTER, as [cnisup-ack:|register:]code=ignore|queue|error, e.g. register:*=queue, first match wins, HEARTBEAT is ignored and others fail the handshake unless given")]
+    handshake_stray: Vec<StrayRule>,
+
+    #[clap(long = "fsm-id-space", long_help = "fsm_ids of this CN as first-last, e.g. 7000000-7499999, first one for link level packets, default cn_id * 1000000 on")]
+    fsm_id_space: Option<FsmIdSpace>,
+
+    #[clap(long = "fsm-id-strategy", long_help = "how channel fsm_ids are picked from --fsm-id-space: increment[:step], random or list:<id>,<id>..", default_value = "increment")]
+    fsm_id_strategy: FsmIdStrategy,
+
+    #[clap(long = "fsm-id-check", value_enum, default_value = "off", long_help = "drop (discard) or only log (warn) packets whose fsm_id is outside --fsm-id-space")]
+    fsm_id_check: FsmIdCheck,
+
+    #[clap(long = "heartbeat-ms", long_help = "send HEARTBEAT this often and track round trip times, and clock offset of MS if its answers carry time")]
+    heartbeat_ms: Option<u64>,
+
+    #[clap(long = "anomaly-window-ms", long_help = "count received packets per code in windows this long and warn when a rate or ACK failure ratio shifts by --anomaly-factor from the windows before, e.g. 10000")]
+    anomaly_window_ms: Option<u64>,
+
+    #[clap(long = "anomaly-factor", long_help = "shift either way taken as an anomaly", default_value = "10")]
+    anomaly_factor: f64,
+
+    #[clap(long = "canary-interval-ms", long_help = "probe MS with HEARTBEAT and REQUESTCHANNEL this often instead of only listening")]
+    canary_interval_ms: Option<u64>,
+
+    #[clap(long = "state-file", long_help = "keep sn counters and the fsm_id cursor in this file and go on from them after a restart, so no fsm_id MS still holds is reused")]
+    state_file: Option<PathBuf>,
+
+    #[clap(long = "canary-window", long_help = "rounds of probes per check of --slo", default_value = "10")]
+    canary_window: u32,
+
+    #[clap(long = "slo", long_help = "latency budget, exit non-zero when a window breaches it, e.g. REQUESTCHANNEL_ACK.p99<50ms or heartbeat.rtt<10ms")]
+    slo: Vec<Slo>,
+
+    #[cfg(feature = "grpc")]
+    #[clap(long = "grpc", long_help = "stream decoded packets to gRPC subscribers at this address, e.g. 127.0.0.1:50051")]
+    grpc: Option<std::net::SocketAddr>,
+
+    #[cfg(feature = "tui")]
+    #[clap(long = "tui", long_help = "show live dashboard instead of logs")]
+    pub tui: bool,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Transport {
+    Unix,
+    Tls,
+}
+
+/// channel fsm_ids by --fsm-id-space and --fsm-id-strategy
+fn fsm_ids(args: &CmdArgs, cn_id: u32, rng: &SimRng) -> Result<FsmIdAllocator> {
+    let space = args.fsm_id_space.unwrap_or_else(|| FsmIdSpace::of_cn(cn_id));
+    let mut ids = FsmIdAllocator::with_strategy(space, args.fsm_id_strategy.clone())?;
+    ids.set_rng(rng.fork("cli.fsm_ids"));
+    Ok(ids)
+}
+
+/// originated by --loadgen and --canary-interval-ms
+fn request_channel(args: &CmdArgs) -> RequestChannel {
+    RequestChannel { media_type: args.media_type as u8, ice_type: args.ice_type as u8, ptime: args.ptime, webrtc: vec!["".into()], ..Default::default() }
+}
+
+/// codecs to negotiate, media types negotiation doesn't know are requested as given
+fn call_profile(args: &CmdArgs) -> Option<CallProfile> {
+    matches!(args.media_type, MediaType::AudioOnly | MediaType::AudioVideo | MediaType::VideoOnly).then(|| CallProfile {
+        media_type: args.media_type,
+        audio: args.codecs.clone(),
+        video: args.video_codecs.clone(),
+        ptime: args.ptime,
+    })
+}
+
+/// of --rate and --channel-rate
+fn rate_limiter(args: &CmdArgs) -> RateLimiter {
+    RateLimiter::new(args.rate, args.channel_rate)
+}
+
+#[cfg(feature = "events")]
+fn event_publisher(args: &CmdArgs) -> Option<crate::vn_events::EventPublisher> {
+    let target = args.events.clone()?;
+    Some(crate::vn_events::EventPublisher::spawn(target, args.events_source.clone()))
+}
+
+pub async fn run(args: &CmdArgs, rng: &SimRng) -> Result<()> {
+    run_cn(args, rng).instrument(tenant_span(args.tenant.as_deref())).await
+}
+
+async fn run_cn(args: &CmdArgs, rng: &SimRng) -> Result<()> {
+    let cn_id = 5_u32;
+    let ids = fsm_ids(args, cn_id, rng)?;
+    info!("fsm_ids [{}] by [{}], check [{:?}]", ids.space(), ids.strategy(), args.fsm_id_check);
+
+    if !args.ms.is_empty() {
+        if args.state_file.is_some() {
+            bail!("--state-file is of a single MS, not of --ms")
+        }
+        return run_pool(args, cn_id, ids).await
+    }
+
+    match args.transport {
+        Transport::Unix => {
+            let mut session = CnSession::bind_env(cn_id).await?;
+            session.set_rate_limiter(rate_limiter(args));
+            session.set_fsm_ids(ids, args.fsm_id_check);
+            session.set_tenant(args.tenant.clone());
+            run_session(session, args).await
+        },
+        Transport::Tls => {
+            let opts = TlsOptions {
+                peer: args.peer.clone().ok_or_else(||anyhow::anyhow!("--peer is required for tls transport"))?,
+                ca: args.ca.clone(),
+                server_name: args.server_name.clone(),
+                insecure: args.insecure,
+            };
+            let socket = TlsDatagram::connect(&opts).await?;
+            let mut session = CnSession::with_socket(socket, PathBuf::new(), cn_id);
+            session.set_rate_limiter(rate_limiter(args));
+            session.set_fsm_ids(ids, args.fsm_id_check);
+            session.set_tenant(args.tenant.clone());
+            run_session(session, args).await
+        },
+    }
+}
+
+/// register with every --ms and log what they send, see vn_pool.
+/// With --loadgen also originate channels spread by --policy.
+async fn run_pool(args: &CmdArgs, cn_id: u32, mut ids: FsmIdAllocator) -> Result<()> {
+    let mut pool = MsPool::bind(&args.ms, cn_id).await?;
+    pool.set_fsm_ids(ids.space(), args.fsm_id_check);
+    pool.set_policy(args.policy);
+    pool.set_peer_acl(args.peer_acl);
+    pool.set_epoch_mode(args.epoch);
+    pool.set_stray_policy(StrayPolicy::new(args.handshake_stray.clone()));
+    pool.set_tenant(args.tenant.clone());
+    pool.set_rate_limiter(rate_limiter(args));
+    pool.set_profile(call_profile(args));
+    if let Some(path) = &args.cdr {
+        pool.set_cdr(Some(CdrWriter::create(path)?));
+    }
+    #[cfg(feature = "events")]
+    pool.set_events(event_publisher(args));
+    if !args.weights.is_empty() {
+        pool.set_weights(&args.weights)?;
+    }
+    pool.register_all(Duration::from_secs(5)).await?;
+
+    let total = args.loadgen.unwrap_or(0);
+    let hold = args.hold_ms.map(Duration::from_millis);
+    let req = request_channel(args);
+
+    let mut anomaly = anomaly_detector(args);
+    let clock = pool.clock().clone();
+
+    // --tui shows the loop below instead of logs, channels refreshed once a second
+    #[cfg(feature = "tui")]
+    let dashboard = args.tui.then(|| crate::cli_dashboard::DashboardStats::new(format!("cn [{cn_id}] ms {:?}", args.ms)));
+    #[cfg(feature = "tui")]
+    let mut ui = dashboard.clone().map(|stats| {
+        stats.lock().unwrap_or_else(|e| e.into_inner()).on_registered();
+        tokio::task::spawn_blocking(move || crate::cli_dashboard::run_dashboard(stats, Duration::from_secs(1)))
+    });
+    #[cfg(not(feature = "tui"))]
+    let mut ui: Option<tokio::task::JoinHandle<Result<()>>> = None;
+
+    let work = async {
+        #[cfg(feature = "tui")]
+        let mut next_report = clock.now();
+        let mut originated = 0_u32;
+        let mut injector = Injector::default();
+        let mut holding: VecDeque<(Instant, u32)> = VecDeque::new();
+        let mut ticker = Ticker::new(clock.clone(), Duration::from_millis(args.loadgen_interval_ms.max(1)));
+        let mut heartbeat = heartbeat_ticker(clock.clone(), args.heartbeat_ms);
+        loop {
+            tokio::select! {
+                _r = heartbeat.tick(), if args.heartbeat_ms.is_some() => pool.send_heartbeats().await?,
+                _r = ticker.tick(), if originated < total => {
+                    originated += 1;
+                    let fsm_id = ids.allocate()?;
+                    let peer = pool.request_channel(fsm_id, &req).await?;
+                    debug!("originated [{fsm_id}] on ms [{peer}]");
+                    if let Some(hold) = hold {
+                        holding.push_back((clock.now() + hold, fsm_id));
+                    }
+                },
+                r = pool.recv(Duration::from_millis(100)) => match r? {
+                    Some(PoolEvent::Packet { peer, header, payload }) => {
+                        debug!("ms [{peer}]: {header:?}, payload [{}]", payload.len());
+                        if let Some(detector) = &mut anomaly {
+                            detector.on_packet(header.code, &payload);
+                        }
+                        #[cfg(feature = "tui")]
+                        if let Some(stats) = &dashboard {
+                            stats.lock().unwrap_or_else(|e| e.into_inner()).on_header(&header, &payload);
+                        }
+                    },
+                    Some(ev @ PoolEvent::PeerDown { .. }) => {
+                        warn!("{ev:?}");
+                        #[cfg(feature = "tui")]
+                        if let Some(stats) = &dashboard {
+                            stats.lock().unwrap_or_else(|e| e.into_inner()).on_error(format!("{ev:?}"));
+                        }
+                    },
+                    Some(PoolEvent::Reregistered { peer, diff, lost }) => {
+                        let line = format!("ms [{peer}] re-registered, lost [{}] channels, [{diff}]", lost.len());
+                        warn!("{line}");
+                        #[cfg(feature = "tui")]
+                        if let Some(stats) = &dashboard {
+                            stats.lock().unwrap_or_else(|e| e.into_inner()).on_error(line);
+                        }
+                    },
+                    None => {},
+                },
+            }
+            #[cfg(feature = "tui")]
+            if let Some(stats) = &dashboard {
+                if clock.now() >= next_report {
+                    next_report = clock.now() + Duration::from_secs(1);
+                    stats.lock().unwrap_or_else(|e| e.into_inner()).on_channels(pool.channels().report());
+                }
+            }
+            for (header, payload) in injector.due(clock.now(), ids.space()) {
+                if let Err(e) = pool.send_raw(header, &payload).await {
+                    warn!("inject failed [{e:#}]");
+                }
+            }
+            for marker in vn_marker::take_pending() {
+                if let Err(e) = pool.send_marker(&marker).await {
+                    warn!("marker [{}] failed [{e:#}]", marker.id);
+                }
+            }
+            vn_conn_stats::publish(pool.link_stats());
+            check_anomalies(&mut anomaly);
+
+            while holding.front().is_some_and(|x| x.0 <= clock.now()) {
+                let Some((_deadline, fsm_id)) = holding.pop_front() else { break };
+                if pool.owner(fsm_id).is_some() {
+                    pool.send_request(MCodeType::RELEASECHANNEL, fsm_id, &[]).await?;
+                    pool.release(fsm_id);
+                    ids.release(fsm_id);
+                }
+            }
+        }
+    };
+
+    let mut ui_done = false;
+    let r = tokio::select! {
+        r = work => r,
+        _r = tokio::signal::ctrl_c() => Ok(()),
+        r = wait_dashboard(&mut ui) => {
+            ui_done = true;
+            r
+        },
+    };
+    #[cfg(feature = "tui")]
+    if let Some(stats) = &dashboard {
+        stats.lock().unwrap_or_else(|e| e.into_inner()).request_stop();
+    }
+    if let Some(ui) = ui.filter(|_x| !ui_done) {
+        ui.await??;
+    }
+
+    for line in pool.summary().into_iter().chain(pool.channels().summary()) {
+        info!("{line}");
+    }
+    if let Some(path) = &args.channels_json {
+        write_channels_json(path, pool.channels())?;
+    }
+    r
+}
+
+/// result of the --tui dashboard once it quits, never without one
+async fn wait_dashboard(ui: &mut Option<tokio::task::JoinHandle<Result<()>>>) -> Result<()> {
+    match ui {
+        Some(ui) => ui.await?,
+        None => std::future::pending().await,
+    }
+}
+
+pub(crate) fn write_channels_json(path: &Path, channels: &ChannelRegistry) -> Result<()> {
+    let json = serde_json::to_string_pretty(&channels.report())?;
+    std::fs::write(path, json).with_context(||format!("write channels failed [{path:?}]"))?;
+    info!("wrote channels to [{path:?}]");
+    Ok(())
+}
+
+/// chunked into storage for s3:// or --capture-chunk-bytes, one file otherwise
+fn open_capture(args: &CmdArgs) -> Result<Option<CaptureWriter>> {
+    let Some(path) = &args.capture else { return Ok(None) };
+    let url = path.to_string_lossy();
+    if !vn_storage::is_remote(&url) && args.capture_chunk_bytes.is_none() {
+        return CaptureWriter::create(path).map(Some)
+    }
+    let (storage, name) = vn_storage::open_storage(&url)?;
+    let config = ChunkConfig {
+        bytes: args.capture_chunk_bytes.unwrap_or(ChunkConfig::default().bytes),
+        max_age: Duration::from_secs(args.capture_chunk_secs.max(1)),
+    };
+    CaptureWriter::create_chunked(storage, &name, config).map(Some)
+}
+
+async fn run_session<S: Datagram>(mut session: CnSession<S>, args: &CmdArgs) -> Result<()> {
+    session.set_compression(args.compress.map(|threshold| Compression { threshold, ..Default::default() }));
+    session.set_fragment_mtu(args.fragment_mtu);
+    session.set_recv_buf(args.recv_buf, args.recv_buf_max);
+    session.set_length_policy(args.length_policy);
+    session.set_peer_acl(args.peer_acl, &args.allow_peer);
+    session.set_epoch_mode(args.epoch);
+    session.set_stray_policy(StrayPolicy::new(args.handshake_stray.clone()));
+    if let Some(path) = &args.state_file {
+        session.set_state_file(path)?;
+    }
+    if args.hdr_out.is_some() {
+        session.enable_latency();
+    }
+    let mut capture = open_capture(args)?;
+    #[cfg(feature = "grpc")]
+    if let Some(addr) = args.grpc {
+        let hub = crate::vn_grpc::PacketHub::new(crate::vn_grpc::DEFAULT_BACKLOG);
+        let capture = capture.get_or_insert_with(|| CaptureWriter::new(Box::new(std::io::sink())));
+        capture.set_tap(Some(hub.tap()));
+        tokio::spawn(async move {
+            if let Err(e) = crate::vn_grpc::serve(addr, hub).await {
+                warn!("{e:?}");
+            }
+        });
+    }
+    if let Some(tap) = vn_tail::tap() {
+        capture.get_or_insert_with(|| CaptureWriter::new(Box::new(std::io::sink()))).add_tap(tap);
+    }
+    if capture.is_some() {
+        session.set_capture(capture);
+    }
+
+    #[cfg(feature = "tui")]
+    if args.tui {
+        return run_session_tui(session, args.heartbeat_ms).await
+    }
+
+    session.handshake().await?;
+
+    {
+        let register = session.accept_register().await?;
+        let reg = RegisterRef::parse_from(&register[..])?;
+        debug!("  {reg:?}");
+    }
+
+    let r = match args.canary_interval_ms {
+        Some(ms) => {
+            let config = CanaryConfig {
+                interval: Duration::from_millis(ms.max(1)),
+                window: args.canary_window,
+                slos: args.slo.clone(),
+                cdr: args.cdr.clone(),
+                #[cfg(feature = "events")]
+                events: event_publisher(args),
+                request: request_channel(args),
+                profile: call_profile(args),
+                ..Default::default()
+            };
+            tokio::select! {
+                r = run_canary(&mut session, &config) => r.and_then(|breaches| match breaches.len() {
+                    0 => Ok(()),
+                    n => bail!("[{n}] latency budgets breached"),
+                }),
+                _r = tokio::signal::ctrl_c() => Ok(()),
+            }
+        },
+        None => tokio::select! {
+            r = recv_loop(&mut session, args.heartbeat_ms, anomaly_detector(args)) => r,
+            _r = tokio::signal::ctrl_c() => Ok(()),
+        },
+    };
+
+    if let Err(e) = session.save_state() {
+        warn!("{e:#}");
+    }
+    if session.rtt().count > 0 {
+        info!("heartbeat {}", session.rtt());
+    }
+    info!("sn {}", session.loss());
+    if let Some(acl) = session.peer_acl() {
+        info!("{}", acl.stats());
+    }
+    if session.epoch().current().is_some() {
+        info!("{}", session.epoch().stats());
+    }
+
+    if let (Some(dir), Some(latency)) = (&args.hdr_out, session.latency()) {
+        for line in latency.summary() {
+            info!("latency {line}");
+        }
+        let num = latency.write_hdr_dir(dir)?;
+        info!("wrote [{num}] histograms to [{dir:?}]");
+    }
+
+    r
+}
+
+/// ticks every ms, never ticks if None
+fn heartbeat_ticker(clock: SharedClock, ms: Option<u64>) -> Ticker {
+    let period = ms.map(|x| Duration::from_millis(x.max(1))).unwrap_or(Duration::from_secs(3600));
+    Ticker::new(clock, period)
+}
+
+/// by --anomaly-window-ms, None without
+fn anomaly_detector(args: &CmdArgs) -> Option<AnomalyDetector> {
+    let window = Duration::from_millis(args.anomaly_window_ms?.max(1));
+    Some(AnomalyDetector::new(AnomalyConfig { window, factor: args.anomaly_factor, ..Default::default() }, Instant::now()))
+}
+
+/// log anomalies of a window just ended
+fn check_anomalies(detector: &mut Option<AnomalyDetector>) {
+    let Some(detector) = detector else { return };
+    for anomaly in detector.tick(Instant::now()) {
+        match anomaly.active {
+            true => warn!("anomaly {anomaly}"),
+            false => info!("anomaly over, {anomaly}"),
+        }
+    }
+}
+
+async fn recv_loop<S: Datagram>(session: &mut CnSession<S>, heartbeat_ms: Option<u64>, mut anomaly: Option<AnomalyDetector>) -> Result<()> {
+    let mut heartbeat = heartbeat_ticker(session.clock().clone(), heartbeat_ms);
+    let mut injector = Injector::default();
+    loop {
+        tokio::select! {
+            _r = heartbeat.tick(), if heartbeat_ms.is_some() => {
+                session.send_heartbeat().await?;
+            },
+            _r = injector.ready() => {},
+            _r = vn_marker::submitted() => {},
+            r = session.recv_packet() => {
+                let packet = r?;
+                if let Some(detector) = &mut anomaly {
+                    detector.on_packet(packet.code(), packet.payload());
+                }
+                if packet.code() == MCodeType::REGISTER.code() {
+                    let payload = packet.payload().to_vec();
+                    let diff = session.reregister(&payload).await?;
+                    info!("re-registered, [{diff}]");
+                }
+            },
+        }
+        // between packets, a safe point for ones of rcn ctl inject and marker
+        let now = session.clock().now();
+        vn_inject::send_due(&mut injector, session, now).await?;
+        vn_marker::send_pending(session).await?;
+        vn_conn_stats::publish(vec![LinkStats::of_session(session, true, session.clock().now())]);
+        check_anomalies(&mut anomaly);
+    }
+}
+
+#[cfg(feature = "tui")]
+async fn run_session_tui<S: Datagram>(mut session: CnSession<S>, heartbeat_ms: Option<u64>) -> Result<()> {
+    use crate::cli_dashboard::{run_dashboard, DashboardStats};
+
+    let stats = DashboardStats::new(format!("cn [{}] ms [{:?}]", session.cn_id(), session.ms_path()));
+
+    let mut ui = {
+        let stats = stats.clone();
+        tokio::task::spawn_blocking(move || run_dashboard(stats, std::time::Duration::from_secs(1)))
+    };
+
+    let work = async {
+        session.handshake().await?;
+        session.accept_register().await?;
+        let mut channels = ChannelRegistry::default();
+        if let Some(info) = session.register_info() {
+            channels.on_register(&session.ms_path().display().to_string(), info);
+        }
+        {
+            let mut stats = stats.lock().unwrap_or_else(|e| e.into_inner());
+            stats.on_registered();
+            stats.on_channels(channels.report());
+        }
+
+        let mut heartbeat = heartbeat_ticker(session.clock().clone(), heartbeat_ms);
+        loop {
+            tokio::select! {
+                _r = heartbeat.tick(), if heartbeat_ms.is_some() => {
+                    if let Err(e) = session.send_heartbeat().await {
+                        stats.lock().unwrap_or_else(|e| e.into_inner()).on_error(format!("{e:#}"));
+                    }
+                },
+                r = session.recv_packet() => {
+                    let mut stats = stats.lock().unwrap_or_else(|e| e.into_inner());
+                    match r {
+                        Ok(packet) => {
+                            stats.on_packet(&packet);
+                            channels.on_packet(packet.fsm_id(), packet.code(), packet.payload());
+                            stats.on_channels(channels.report());
+                        },
+                        Err(e) => stats.on_error(format!("{e:#}")),
+                    }
+                    stats.on_link(*session.rtt(), *session.loss());
+                },
+            }
+        }
+    };
+
+    tokio::select! {
+        r = &mut ui => r?,
+        r = work => {
+            stats.lock().unwrap_or_else(|e| e.into_inner()).request_stop();
+            ui.await??;
+            r
+        },
+    }
+}
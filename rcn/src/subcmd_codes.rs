@@ -0,0 +1,30 @@
+use anyhow::Result;
+use clap::Parser;
+
+use crate::vn_proto::MCODE_TABLE;
+
+pub fn run(args: &CmdArgs) -> Result<()> {
+    match &args.cmd {
+        CodesCmd::List => list(),
+    }
+}
+
+fn list() -> Result<()> {
+    for info in MCODE_TABLE.iter() {
+        println!("0x{:04X}  {:<22} {:<7} {}", info.code.code(), format!("{:?}", info.code), info.direction, info.description);
+    }
+    Ok(())
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "codes", author, about = "known message codes", version)]
+pub struct CmdArgs {
+    #[clap(subcommand)]
+    cmd: CodesCmd,
+}
+
+#[derive(Parser, Debug)]
+enum CodesCmd {
+    /// list codes with direction and description
+    List,
+}
@@ -0,0 +1,16 @@
+use anyhow::Result;
+use clap::{Parser, Command};
+use clap_complete::Shell;
+
+pub fn run(args: &CmdArgs, cmd: &mut Command) -> Result<()> {
+    let name = cmd.get_name().to_string();
+    clap_complete::generate(args.shell, cmd, name, &mut std::io::stdout());
+    Ok(())
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "completions", author, about = "print shell completion script", version)]
+pub struct CmdArgs {
+    #[clap(value_enum)]
+    shell: Shell,
+}
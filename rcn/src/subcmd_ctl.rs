@@ -0,0 +1,497 @@
+use std::{
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, Context, bail};
+use clap::{Parser, ValueEnum};
+use tracing::{info, warn};
+
+use crate::{
+    utils::{self, ctl::request},
+    vn_capture::CaptureDir,
+    vn_channels::ChannelReport,
+    vn_conn_stats::{self, LinkStats},
+    vn_impair::{self, ImpairProfile},
+    vn_inject::{self, Injection},
+    vn_marker::{self, Marker},
+    vn_ms_sim::{self, NO_PORTS_RESULT},
+    vn_proto::MCode,
+    vn_tail::{self, TailEvent, TailFilter, TAIL_TTL},
+};
+
+pub fn run(args: &CmdArgs) -> Result<()> {
+    match &args.cmd {
+        CtlCmd::ExportChannels(sub) => export_channels(sub),
+        CtlCmd::LogLevel(sub) => {
+            let cmd = match &sub.directives {
+                Some(directives) => format!("log-level {directives}"),
+                None => "log-level".to_string(),
+            };
+            let reply = request(&sub.socket, &cmd, Duration::from_millis(sub.timeout_ms))?;
+            println!("{reply}");
+            Ok(())
+        },
+        CtlCmd::Inject(sub) => {
+            let path = std::fs::canonicalize(&sub.file).with_context(||format!("no such file [{:?}]", sub.file))?;
+            // checked here too, the running rcn only says why it refused
+            let num = Injection::load(&path, sub.fsm_id)?.len();
+            let target = sub.fsm_id.map(|x| x.to_string()).unwrap_or_else(|| "-".into());
+            let reply = request(&sub.socket, &format!("inject {target} {}", path.display()), Duration::from_millis(sub.timeout_ms))?;
+            println!("{reply}, [{num}] packets");
+            Ok(())
+        },
+        CtlCmd::Tail(sub) => tail(sub),
+        CtlCmd::Marker(sub) => {
+            // id made here so it can be quoted to whoever reads the MS logs
+            let marker = Marker::new(sub.id.as_deref(), sub.fsm_id)?;
+            let target = sub.fsm_id.map(|x| x.to_string()).unwrap_or_else(|| "-".into());
+            let reply = request(&sub.socket, &format!("marker {} {target}", marker.id), Duration::from_millis(sub.timeout_ms))?;
+            println!("{reply}");
+            Ok(())
+        },
+        CtlCmd::Drain(sub) => {
+            let reply = request(&sub.socket, &format!("drain {}", sub.busy_result), Duration::from_millis(sub.timeout_ms))?;
+            println!("{reply}");
+            Ok(())
+        },
+        CtlCmd::Impair(sub) => {
+            let profile = ImpairProfile {
+                loss_pct: sub.loss_pct,
+                jitter_ms: sub.jitter_ms,
+                reorder_pct: sub.reorder_pct,
+                bitrate_kbps: sub.bitrate_kbps,
+            };
+            profile.validate()?;
+            let target = sub.fsm_id.map(|x| x.to_string()).unwrap_or_else(|| "*".into());
+            let cmd = format!("impair {target} {}", serde_json::to_string(&profile)?);
+            println!("{}", request(&sub.socket, &cmd, Duration::from_millis(sub.timeout_ms))?);
+            Ok(())
+        },
+        CtlCmd::Stats(sub) => {
+            let reply = request(&sub.socket, "stats", Duration::from_millis(sub.timeout_ms))?;
+            let mut links: Vec<LinkStats> = serde_json::from_str(&reply).with_context(||"invalid stats reply")?;
+            if let Some(tenant) = &sub.tenant {
+                links.retain(|x| x.tenant.as_ref() == Some(tenant));
+            }
+            match sub.json {
+                true => println!("{}", serde_json::to_string_pretty(&links)?),
+                false => print!("{}", vn_conn_stats::render_table(&links)),
+            }
+            Ok(())
+        },
+    }
+}
+
+/// answer commands of `rcn ctl` sent to path, on a thread of its own
+/// so that it works whichever runtime the subcommand uses
+pub fn serve(path: &Path) -> Result<()> {
+    let _r = std::fs::remove_file(path);
+    let socket = UnixDatagram::bind(path).with_context(||format!("can't bind ctl socket [{path:?}]"))?;
+    info!("ctl socket [{path:?}]");
+    vn_tail::enable();
+    std::thread::Builder::new().name("ctl".into()).spawn(move || {
+        let mut buf = vec![0_u8; 4096];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("ctl recv failed [{e}]");
+                    break
+                },
+            };
+            let reply = match handle(&String::from_utf8_lossy(&buf[..len]), from.as_pathname()) {
+                Ok(x) => format!("ok {x}"),
+                Err(e) => format!("error {e:#}"),
+            };
+            if let Some(from) = from.as_pathname() {
+                let _r = socket.send_to(reply.trim_end().as_bytes(), from);
+            }
+        }
+    })?;
+    Ok(())
+}
+
+/// from is the socket of the sender, where tail events go
+fn handle(cmd: &str, from: Option<&Path>) -> Result<String> {
+    let (name, arg) = cmd.trim().split_once(' ').unwrap_or((cmd.trim(), ""));
+    match (name, arg.trim()) {
+        ("log-level", "") => Ok(utils::log::log_level()),
+        ("log-level", directives) => {
+            utils::log::set_log_level(directives)?;
+            info!("log level changed to [{}]", utils::log::log_level());
+            Ok(utils::log::log_level())
+        },
+        ("stats", "") => Ok(serde_json::to_string(&vn_conn_stats::published())?),
+        ("inject", arg) if !arg.is_empty() => {
+            // target fsm_id or '-', then the path which may have spaces
+            let (target, path) = arg.split_once(' ').with_context(||"expect inject <fsm_id|-> <path>")?;
+            let target = match target {
+                "-" => None,
+                x => Some(x.parse::<u32>().with_context(||format!("invalid fsm_id [{x}]"))?),
+            };
+            let num = vn_inject::submit(Injection::load(Path::new(path.trim()), target)?);
+            Ok(format!("queued [{num}] packets, sent at the next safe point"))
+        },
+        ("marker", arg) if !arg.is_empty() => {
+            // id, then fsm_id or '-' for the link level one
+            let (id, target) = arg.split_once(' ').unwrap_or((arg, "-"));
+            let fsm_id = match target.trim() {
+                "-" => None,
+                x => Some(x.parse::<u32>().with_context(||format!("invalid fsm_id [{x}]"))?),
+            };
+            let marker = Marker::new(Some(id), fsm_id)?;
+            let reply = format!("marker [{}] queued, sent at the next safe point", marker.id);
+            vn_marker::submit(marker);
+            Ok(reply)
+        },
+        ("impair", arg) if !arg.is_empty() => {
+            // fsm_id or '*' for every channel, then the profile, none if omitted
+            let (target, profile) = arg.split_once(' ').unwrap_or((arg, ""));
+            let fsm_id = match target {
+                "*" => None,
+                x => Some(x.parse::<u32>().with_context(||format!("invalid fsm_id [{x}]"))?),
+            };
+            let profile: ImpairProfile = match profile.trim() {
+                "" => ImpairProfile::default(),
+                x => serde_json::from_str(x).with_context(||format!("invalid impair profile [{x}]"))?,
+            };
+            vn_impair::set_profile(fsm_id, profile)?;
+            info!("impairment of [{target}] set {profile:?}");
+            Ok(format!("impairment of [{target}] set"))
+        },
+        ("drain", arg) => {
+            let busy = match arg {
+                "" => NO_PORTS_RESULT,
+                x => x.parse::<u8>().with_context(||format!("invalid busy result [{x}]"))?,
+            };
+            let num = vn_ms_sim::drain_running(busy);
+            if num == 0 {
+                bail!("no ms-sim running here")
+            }
+            info!("draining [{num}] ms-sim, REQUESTCHANNEL answered [{busy}]");
+            Ok(format!("draining [{num}] ms-sim, exits once its channels are released"))
+        },
+        ("tail", arg) => {
+            let filter: TailFilter = match arg {
+                "" => TailFilter::default(),
+                x => serde_json::from_str(x).with_context(||format!("invalid tail filter [{x}]"))?,
+            };
+            let from = from.with_context(||"tail from an unbound socket")?;
+            vn_tail::subscribe(from, filter, Instant::now())?;
+            Ok(format!("tailing, renew within [{}s]", TAIL_TTL.as_secs()))
+        },
+        _ => bail!("unknown command [{cmd}]"),
+    }
+}
+
+/// events of a running rcn until count or ctrl-c, renewing the tail as it goes
+fn tail(args: &TailArgs) -> Result<()> {
+    let filter = TailFilter {
+        codes: args.codes.clone(),
+        fsm_ids: args.fsm_ids.clone(),
+        dir: args.dir.map(|x| match x {
+            TailDir::CnToMs => CaptureDir::CnToMs,
+            TailDir::MsToCn => CaptureDir::MsToCn,
+        }),
+    };
+    let cmd = format!("tail {}", serde_json::to_string(&filter)?);
+    let renew = TAIL_TTL / 3;
+    let timeout = Duration::from_millis(args.timeout_ms);
+
+    let local = std::env::temp_dir().join(format!("rcn_ctl_tail_{}", std::process::id()));
+    let _r = std::fs::remove_file(&local);
+    let socket = UnixDatagram::bind(&local).with_context(||format!("can't bind [{local:?}]"))?;
+    let r = (|| {
+        let mut buf = vec![0_u8; 65536];
+        let mut num = 0;
+        let mut renewed = Instant::now();
+        // first reply must come in timeout, later ones only renew
+        let mut replied = false;
+        socket.send_to(cmd.as_bytes(), &args.socket).with_context(||format!("send to ctl socket failed [{:?}]", args.socket))?;
+        loop {
+            if renewed.elapsed() >= renew {
+                socket.send_to(cmd.as_bytes(), &args.socket).with_context(||format!("ctl socket gone [{:?}]", args.socket))?;
+                renewed = Instant::now();
+            }
+            let wait = match replied {
+                true => renew.saturating_sub(renewed.elapsed()),
+                false => timeout,
+            };
+            socket.set_read_timeout(Some(wait.max(Duration::from_millis(1))))?;
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                    if !replied {
+                        bail!("no reply from [{:?}] in [{timeout:?}]", args.socket)
+                    }
+                    continue
+                },
+                Err(e) => return Err(e.into()),
+            };
+            let text = String::from_utf8_lossy(&buf[..len]);
+            if let Some(e) = text.strip_prefix("error ") {
+                bail!("{e}")
+            }
+            if text.starts_with("ok") {
+                replied = true;
+                continue
+            }
+            let event: TailEvent = match serde_json::from_str(&text) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("invalid tail event [{e}]");
+                    continue
+                },
+            };
+            match args.json {
+                true => println!("{text}"),
+                false => println!("{event}"),
+            }
+            num += 1;
+            if args.count.is_some_and(|x| num >= x) {
+                return Ok(())
+            }
+        }
+    })();
+    let _r = std::fs::remove_file(&local);
+    r
+}
+
+/// channels json of `--channels-json` as csv or json again
+fn export_channels(args: &ExportChannelsArgs) -> Result<()> {
+    let mut report = read_channels(&args.file)?;
+    if let Some(tenant) = &args.tenant {
+        report = report.for_tenant(tenant);
+    }
+    let out = match args.csv {
+        true => report.to_csv(),
+        false => serde_json::to_string_pretty(&report)? + "\n",
+    };
+    match &args.output {
+        Some(path) => std::fs::write(path, out).with_context(||format!("write channels failed [{path:?}]"))?,
+        None => print!("{out}"),
+    }
+    Ok(())
+}
+
+pub fn read_channels(path: &Path) -> Result<ChannelReport> {
+    let text = std::fs::read_to_string(path).with_context(||format!("read channels failed [{path:?}]"))?;
+    serde_json::from_str(&text).with_context(||format!("invalid channels json [{path:?}]"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::{subcmd_cli::write_channels_json, utils, vn_channels::{ChannelRegistry, EndReason}};
+
+    use super::{read_channels, request, serve};
+
+    #[test]
+    fn test_export_channels() {
+        let mut registry = ChannelRegistry::default();
+        registry.on_requested(7, 0, Instant::now());
+        registry.on_requested(8, 0, Instant::now());
+        registry.end(7, EndReason::Released, Instant::now());
+
+        let path = std::env::temp_dir().join(format!("rcn_channels_{}.json", std::process::id()));
+        write_channels_json(&path, &registry).unwrap();
+        let report = read_channels(&path).unwrap();
+        let csv = report.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("active,8,"));
+        // lifetime of active ones moves on, ended ones read back the same
+        assert_eq!(Some(lines[2]), registry.report().to_csv().lines().nth(2));
+        let _r = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_ctl_log_level() {
+        let _guard = tracing::subscriber::set_default(utils::log::log_subscriber(env!("CARGO_PKG_NAME"), std::io::sink));
+        let path = std::env::temp_dir().join(format!("rcn_ctl_test_{}", std::process::id()));
+        serve(&path).unwrap();
+
+        let timeout = Duration::from_secs(2);
+        assert_eq!(request(&path, "log-level rcn=info,vn_proto=debug", timeout).unwrap(), "rcn=info,rcn::vn_proto=debug");
+        assert_eq!(request(&path, "log-level", timeout).unwrap(), "rcn=info,rcn::vn_proto=debug");
+        assert!(request(&path, "log-level rcn=loud", timeout).is_err());
+        assert!(request(&path, "reboot", timeout).unwrap_err().to_string().contains("unknown command"));
+        assert!(request(&path, "stats", timeout).unwrap().starts_with('['));
+        assert!(request(&path, "inject - /no/such.jsonl", timeout).unwrap_err().to_string().contains("read capture failed"));
+        assert!(request(&path, r#"tail {"codes":["PLAYS"]}"#, timeout).unwrap_err().to_string().contains("invalid tail filter"));
+        assert!(request(&path, r#"impair 7000021 {"loss_pct":200}"#, timeout).unwrap_err().to_string().contains("loss_pct"));
+        assert_eq!(request(&path, r#"impair 7000021 {"jitter_ms":30}"#, timeout).unwrap(), "impairment of [7000021] set");
+        assert_eq!(crate::vn_impair::profile_of(7000021).jitter_ms, 30);
+        assert!(request(&path, "drain 3", timeout).unwrap_err().to_string().contains("no ms-sim running"));
+        assert!(request(&path, r#"tail {"codes":["PLAY"],"dir":"cn_to_ms"}"#, timeout).unwrap().starts_with("tailing"));
+        let _r = std::fs::remove_file(&path);
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "ctl", author, about = "control a running rcn or work with state it wrote", version)]
+pub struct CmdArgs {
+    #[clap(subcommand)]
+    cmd: CtlCmd,
+}
+
+#[derive(Parser, Debug)]
+enum CtlCmd {
+    /// one row per active and ended channel of a --channels-json file
+    ExportChannels(ExportChannelsArgs),
+    /// show or change log level of a running rcn started with --ctl-socket
+    LogLevel(LogLevelArgs),
+    /// per MS traffic, retransmits, heartbeat timeouts and recv buffers of a running rcn cli
+    Stats(StatsArgs),
+    /// send cn_to_ms packets of a capture into a running rcn cli at its next safe point
+    Inject(InjectArgs),
+    /// decoded packets of a running rcn cli, proxy or ms-sim as they go
+    Tail(TailArgs),
+    /// send a marker with an id also logged by rcn, to line MS logs up with captures
+    Marker(MarkerArgs),
+    /// lose, jitter, reorder or cap rtp of channels of a running rcn ms-sim
+    Impair(ImpairArgs),
+    /// refuse new channels of a running rcn ms-sim and exit once the open ones are released
+    Drain(DrainArgs),
+}
+
+#[derive(Parser, Debug)]
+pub struct LogLevelArgs {
+    /// new filter, e.g. rcn=debug,vn_proto=trace, shows the current one if omitted
+    directives: Option<String>,
+
+    #[clap(long = "socket", long_help = "--ctl-socket of the running rcn")]
+    socket: PathBuf,
+
+    #[clap(long = "timeout-ms", default_value = "2000")]
+    timeout_ms: u64,
+}
+
+#[derive(Parser, Debug)]
+pub struct StatsArgs {
+    #[clap(long = "socket", long_help = "--ctl-socket of the running rcn")]
+    socket: PathBuf,
+
+    #[clap(long = "json", long_help = "print json instead of a table")]
+    json: bool,
+
+    #[clap(long = "tenant", long_help = "only links of this tenant")]
+    tenant: Option<String>,
+
+    #[clap(long = "timeout-ms", default_value = "2000")]
+    timeout_ms: u64,
+}
+
+#[derive(Parser, Debug)]
+pub struct InjectArgs {
+    /// jsonl or vnrec capture, e.g. cut from a customer's with rcn capture-convert
+    file: PathBuf,
+
+    #[clap(long = "fsm-id", long_help = "send channel packets on this live channel, their own fsm_ids are kept when omitted and must be of the running CN")]
+    fsm_id: Option<u32>,
+
+    #[clap(long = "socket", long_help = "--ctl-socket of the running rcn")]
+    socket: PathBuf,
+
+    #[clap(long = "timeout-ms", default_value = "2000")]
+    timeout_ms: u64,
+}
+
+#[derive(Parser, Debug)]
+pub struct TailArgs {
+    #[clap(long = "socket", long_help = "--ctl-socket of the running rcn")]
+    socket: PathBuf,
+
+    #[clap(long = "codes", value_delimiter = ',', long_help = "only these codes by name or number, e.g. PLAY,0x1d")]
+    codes: Vec<MCode>,
+
+    #[clap(long = "fsm-ids", value_delimiter = ',', long_help = "only packets of these fsm_ids")]
+    fsm_ids: Vec<u32>,
+
+    #[clap(long = "dir", value_enum, long_help = "only packets going this way")]
+    dir: Option<TailDir>,
+
+    #[clap(long = "json", long_help = "print one json object per packet instead of a line")]
+    json: bool,
+
+    #[clap(long = "count", long_help = "exit after this many packets")]
+    count: Option<u64>,
+
+    #[clap(long = "timeout-ms", default_value = "2000", long_help = "wait for the running rcn to take the tail")]
+    timeout_ms: u64,
+}
+
+#[derive(Parser, Debug)]
+pub struct MarkerArgs {
+    #[clap(long = "socket", long_help = "--ctl-socket of the running rcn cli")]
+    socket: PathBuf,
+
+    #[clap(long = "id", long_help = "correlation id, printable ascii without spaces, one of unix ms and a count if omitted")]
+    id: Option<String>,
+
+    #[clap(long = "fsm-id", long_help = "channel to send it on, the link level fsm_id if omitted")]
+    fsm_id: Option<u32>,
+
+    #[clap(long = "timeout-ms", default_value = "2000")]
+    timeout_ms: u64,
+}
+
+#[derive(Parser, Debug)]
+pub struct DrainArgs {
+    #[clap(long = "socket", long_help = "--ctl-socket of the running rcn")]
+    socket: PathBuf,
+
+    #[clap(long = "busy-result", default_value = "1", long_help = "result of REQUESTCHANNEL_ACK refusing a channel while draining")]
+    busy_result: u8,
+
+    #[clap(long = "timeout-ms", default_value = "2000")]
+    timeout_ms: u64,
+}
+
+#[derive(Parser, Debug)]
+pub struct ImpairArgs {
+    #[clap(long = "socket", long_help = "--ctl-socket of the running rcn")]
+    socket: PathBuf,
+
+    #[clap(long = "fsm-id", long_help = "only this channel, every channel without a profile of its own when omitted")]
+    fsm_id: Option<u32>,
+
+    #[clap(long = "loss-pct", default_value = "0", long_help = "percent of rtp packets lost")]
+    loss_pct: f64,
+
+    #[clap(long = "jitter-ms", default_value = "0", long_help = "extra random delay of a packet up to this")]
+    jitter_ms: u64,
+
+    #[clap(long = "reorder-pct", default_value = "0", long_help = "percent of packets held back so the next one overtakes")]
+    reorder_pct: f64,
+
+    #[clap(long = "bitrate-kbps", default_value = "0", long_help = "cap of the rtp bitrate, packets queued too long are lost, 0 for none")]
+    bitrate_kbps: u32,
+
+    #[clap(long = "timeout-ms", default_value = "2000")]
+    timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum TailDir {
+    CnToMs,
+    MsToCn,
+}
+
+#[derive(Parser, Debug)]
+pub struct ExportChannelsArgs {
+    /// json written by cli or b2bua --channels-json
+    file: PathBuf,
+
+    #[clap(long = "csv", long_help = "write csv instead of json")]
+    csv: bool,
+
+    #[clap(long = "tenant", long_help = "only channels of this tenant")]
+    tenant: Option<String>,
+
+    #[clap(long = "output", long_help = "write into this file instead of stdout")]
+    output: Option<PathBuf>,
+}
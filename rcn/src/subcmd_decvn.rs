@@ -2,11 +2,37 @@ use bytes::{BufMut, BytesMut};
 use clap::Parser;
 use anyhow::{Result, Context};
 use tracing::{debug, info, warn};
-use std::io::{self, Read};
+use std::{collections::HashMap, fmt::Write as _, io::{self, Read}, path::{Path, PathBuf}};
+
+use crate::vn_auth::{split_trailer, AuthStatus, HmacSha256Auth, PacketAuth};
+use crate::vn_capture::{read_capture, CaptureDir, CaptureRecord};
+use crate::vn_charset::{set_charset, Charset};
+use crate::vn_explain::explain;
+use crate::vn_key::KeyMap;
+use crate::vn_media::{AssetProblem, MediaCatalog};
+use crate::vn_minimize::{error_class, minimize};
+use crate::vn_proto::{CodeName, LengthPolicy, Message, PacketRef, MCodeType, PlayRef};
+use crate::vn_seq::{SeqEvent, SeqTracker};
+
+pub fn run(args: &CmdArgs) -> Result<()> {
+    set_charset(args.charset);
+    let auth = args.hmac_key.as_ref().map(|x|HmacSha256Auth::new(x.as_bytes()));
+    if args.split && args.output.is_none() {
+        anyhow::bail!("--split needs -o")
+    }
+    let mut output = args.output.as_ref().map(|x| PacketOutput::new(x, args.split)).transpose()?;
 
-use crate::vn_proto::{PacketRef, MCodeType, RegisterRef, RequestChannelRef, OpenRtpConnectRef, RequestChannelAckRef, OpenRtpConnectAck, ResFromTagRef, PlayRef, CancelRef, CloseRtpConnect, CloseRtpConnectAck, PlayAckRef};
+    if args.minimize.is_some() && args.capture.is_some() {
+        anyhow::bail!("--minimize takes a stdin hexdump, not --capture")
+    }
+
+    if let Some(path) = &args.capture {
+        let records = read_capture(path)?;
+        let filter = KeyFilter { map: args.key_map.clone().unwrap_or_default(), group: args.key_group.clone() };
+        decode_capture(&records, auth.as_ref().map(|x| x as &dyn PacketAuth), args.length_policy, &filter, args.compact, output.as_mut())?;
+        return finish_output(output)
+    }
 
-pub fn run(_args: &CmdArgs) -> Result<()> {
     info!("enter text and press ctrl+D when completed");
     
     
@@ -18,7 +44,15 @@ pub fn run(_args: &CmdArgs) -> Result<()> {
         reader.read_to_end(&mut read_buf).with_context(||"read stdin failed")?;
     }
     let text = std::str::from_utf8(&read_buf[..]).with_context(||"invalid input text")?;
-    decode_text(text)?;
+    let files_root = if args.check_files { Some(args.root.as_path()) } else { None };
+    if args.explain {
+        return explain_text(text)
+    }
+    if let Some(out) = &args.minimize {
+        return minimize_text(text, auth.as_ref().map(|x| x as &dyn PacketAuth), args.length_policy, out)
+    }
+    decode_text_with(text, auth.as_ref().map(|x| x as &dyn PacketAuth), files_root, args.length_policy, output.as_mut())?;
+    finish_output(output)?;
 
     // let mut lines = Vec::new();
     // {
@@ -39,103 +73,425 @@ pub fn run(_args: &CmdArgs) -> Result<()> {
     Ok(())
 }
 
-fn decode_text(text: &str) -> Result<()> {
-    decode_lines(text.lines())
+#[cfg(test)]
+pub(crate) fn decode_text(text: &str) -> Result<()> {
+    decode_text_with(text, None, None, LengthPolicy::default(), None)
 }
 
-fn decode_lines<'a, I>(lines: I) -> Result<()> 
+fn decode_text_with(text: &str, auth: Option<&dyn PacketAuth>, files_root: Option<&Path>, policy: LengthPolicy, output: Option<&mut PacketOutput>) -> Result<()> {
+    decode_lines(text.lines(), auth, files_root, policy, output)
+}
+
+fn decode_lines<'a, I>(lines: I, auth: Option<&dyn PacketAuth>, files_root: Option<&Path>, policy: LengthPolicy, output: Option<&mut PacketOutput>) -> Result<()> 
 where
     I: Iterator<Item = &'a str>
 {
-    let mut bin_buf = BytesMut::new();
-    {
-        for line in lines {
-            // debug!("line=[{line:?}]");
-            let line = line.trim();
-            if !line.is_empty() {
-                parse_line(&line, &mut bin_buf)?;
-            }
-        }
-        // debug!("--------");
-    }
-    
+    let bin_buf = parse_hexdump_lines(lines)?;
 
     let data = &bin_buf[..];
     debug!("parsed length [{}]", bin_buf.len());
     debug!("parsed content {data:02x?}");
 
     
-    let packet = PacketRef::parse_from(&bin_buf[..]).with_context(||"invalid packet")?;
+    let (data, status) = split_trailer(auth, &bin_buf[..]);
+    match status {
+        AuthStatus::None => {},
+        AuthStatus::Verified => info!("auth trailer verified"),
+        _ => warn!("auth trailer failed [{status:?}]"),
+    }
+
+    let packet = PacketRef::parse_with(data, policy).with_context(||"invalid packet")?;
+    warn_length_mismatch(&packet);
     print_packet(&packet)?;
+    if let Some(output) = output {
+        output.add(0, "-", data, Ok(&packet));
+    }
+
+    if let Some(root) = files_root {
+        if packet.code() == MCodeType::PLAY.code() {
+            let r = PlayRef::parse_from(packet.payload()).with_context(||"invalid Play packet")?;
+            check_play_files(&r, root)?;
+        }
+    }
     Ok(())
 }
 
-fn print_packet(packet: &PacketRef<'_>) -> Result<()> {
-    info!("{packet:?}");
+/// every field with offset, raw bytes and description
+fn explain_text(text: &str) -> Result<()> {
+    let bin_buf = parse_hexdump_text(text)?;
+    let annotations = hexdump_annotations(text)?;
+    let mut sections = annotations.iter().filter_map(|x| x.section().map(|name| (x.offset, name))).peekable();
+    println!("{:>5}  {:<27} {:<15} {:<24} description", "off", "raw", "field", "value");
+    for field in explain(&bin_buf[..]) {
+        // sections of the hexdump above their first field
+        while let Some((_offset, name)) = sections.next_if(|x| x.0 <= field.offset) {
+            println!("[{name}]");
+        }
+        println!("{field}");
+    }
+    Ok(())
+}
 
-    let r = MCodeType::try_from(packet.code()).ok();
-    if let Some(code_type) = r {
-        match code_type {
-            MCodeType::REGISTER => {
-                let r = RegisterRef::parse_from(packet.payload()).with_context(||"invalid Register packet")?;
-                info!("{r:#?}");
-            }
-            MCodeType::REQUESTCHANNEL => {
-                let r = RequestChannelRef::parse_from(packet.payload()).with_context(||"invalid RequestChannel packet")?;
-                info!("{r:#?}");
-            }
-            MCodeType::REQUESTCHANNEL_ACK => {
-                let r = RequestChannelAckRef::parse_from(packet.payload()).with_context(||"invalid RequestChannelAck packet")?;
-                info!("{r:#?}");
-            }
-            MCodeType::OPENRTPCONNECT => {
-                let r = OpenRtpConnectRef::parse_from(packet.payload()).with_context(||"invalid OpenRtpConnect packet")?;
-                info!("{r:#?}");
-            }
-            MCodeType::OPENRTPCONNECT_ACK => {
-                let r = OpenRtpConnectAck::parse_from(packet.payload()).with_context(||"invalid OpenRtpConnectAck packet")?;
-                info!("{r:#?}");
-            }
-            MCodeType::RESFROMTAG => {
-                let r = ResFromTagRef::parse_from(packet.payload()).with_context(||"invalid ResFromTag packet")?;
-                info!("{r:#?}");
+/// error of decoding data as decode_lines does, None if it decodes
+fn decode_error(data: &[u8], policy: LengthPolicy) -> Option<anyhow::Error> {
+    PacketRef::parse_with(data, policy)
+    .and_then(|packet| Message::from_packet(&packet).map(|_x| ()))
+    .err()
+}
+
+/// shrink a packet failing to decode to the smallest failing the same way,
+/// written to out as a hexdump for the regression corpus
+fn minimize_text(text: &str, auth: Option<&dyn PacketAuth>, policy: LengthPolicy, out: &Path) -> Result<()> {
+    let bin_buf = parse_hexdump_text(text)?;
+    let (data, _status) = split_trailer(auth, &bin_buf[..]);
+    let Some(e) = decode_error(data, policy) else {
+        anyhow::bail!("packet decodes fine, nothing to minimize")
+    };
+    let class = error_class(&e);
+    info!("error class [{class}]");
+
+    let (minimized, stats) = minimize(data, |x| decode_error(x, policy).is_some_and(|e| error_class(&e) == class));
+    std::fs::write(out, to_hexdump(&minimized)).with_context(||format!("write minimized failed [{out:?}]"))?;
+    info!("minimized [{}] -> [{}] bytes in [{}] tries, wrote [{out:?}]", stats.original_len, stats.minimized_len, stats.tries);
+    print!("{}", to_hexdump(&minimized));
+    Ok(())
+}
+
+/// only packets whose key maps to group, all if None
+struct KeyFilter {
+    map: KeyMap,
+    group: Option<String>,
+}
+
+/// decode every record of capture with its time, gap to previous one and sender,
+/// then count packets per key group. compact prints one line per record
+fn decode_capture(records: &[CaptureRecord], auth: Option<&dyn PacketAuth>, policy: LengthPolicy, filter: &KeyFilter, compact: bool, mut output: Option<&mut PacketOutput>) -> Result<()> {
+    let mut last = None;
+    let mut keys = Vec::new();
+    let mut seqs: HashMap<CaptureDir, SeqTracker<u32>> = HashMap::new();
+    for (n, record) in records.iter().enumerate() {
+        let data = record.data().with_context(||format!("invalid capture record #{n}"))?;
+        let (data, status) = split_trailer(auth, &data[..]);
+        let packet = PacketRef::parse_with(data, policy);
+        if let (Ok(packet), Some(group)) = (&packet, &filter.group) {
+            if filter.map.label(packet.key()) != *group {
+                continue
             }
-            MCodeType::PLAY => {
-                let r = PlayRef::parse_from(packet.payload()).with_context(||"invalid Play packet")?;
-                info!("{r:#?}");
+        }
+
+        let ts = record.ts();
+        let gap = last.map(|x| ts.saturating_sub(x)).unwrap_or_default();
+        last = Some(ts);
+        if let Some(output) = output.as_deref_mut() {
+            let origin = format!("{:.3} ms {:?}", ts.as_secs_f64() * 1000.0, record.dir);
+            output.add(n, &origin, data, packet.as_ref().map_err(|e| e as &dyn std::fmt::Display));
+        }
+        if let Ok(packet) = &packet {
+            let seq = seqs.entry(record.dir).or_default();
+            if packet.code() == MCodeType::REGISTER.code() {
+                // sender restarted
+                seq.clear();
             }
-            MCodeType::PLAY_ACK => {
-                let r = PlayAckRef::parse_from(packet.payload()).with_context(||"invalid PlayAck packet")?;
-                info!("{r:#?}");
+            match seq.on_recv(packet.fsm_id(), packet.sn()) {
+                SeqEvent::InOrder => {},
+                ev => warn!("#{n} {:?} sn [{}] of fsm_id [{}] is {ev:?}", record.dir, packet.sn(), packet.fsm_id()),
             }
-            MCodeType::CANCEL => {
-                let r = CancelRef::parse_from(packet.payload()).with_context(||"invalid Cancel packet")?;
-                info!("{r:#?}");
+        }
+        if compact {
+            match &packet {
+                Ok(packet) => {
+                    keys.push(packet.key());
+                    info!("#{n} +{:.3} ms {:?} {packet}", gap.as_secs_f64() * 1000.0, record.dir);
+                },
+                Err(e) => warn!("#{n} invalid packet [{e}]"),
             }
-            MCodeType::CLOSERTPCONNECT => {
-                let r = CloseRtpConnect::parse_from(packet.payload()).with_context(||"invalid CloseRtpConnect packet")?;
-                info!("{r:#?}");
+            continue
+        }
+        info!(
+            "#{n} [{:.3} ms] +{:.3} ms {:?} from [{}]",
+            ts.as_secs_f64() * 1000.0, gap.as_secs_f64() * 1000.0, record.dir,
+            record.socket.as_deref().unwrap_or("-"),
+        );
+
+        if !matches!(status, AuthStatus::None | AuthStatus::Verified) {
+            warn!("auth trailer failed [{status:?}]");
+        }
+        match packet {
+            Ok(packet) => {
+                keys.push(packet.key());
+                warn_length_mismatch(&packet);
+                if let Err(e) = print_packet(&packet) {
+                    warn!("decode record #{n} failed [{e:?}]");
+                }
+            },
+            Err(e) => warn!("invalid packet of record #{n} [{e:?}]"),
+        }
+    }
+
+    for (group, num) in filter.map.count_by_label(keys) {
+        info!("key group [{group}]: packets [{num}]");
+    }
+    for dir in [CaptureDir::CnToMs, CaptureDir::MsToCn] {
+        if let Some(seq) = seqs.get(&dir) {
+            info!("{dir:?} sn {}", seq.stats());
+        }
+    }
+    Ok(())
+}
+
+/// decoded packets written under a directory, each into its own file with
+/// an index of them if split, all into one file otherwise
+struct PacketOutput {
+    dir: PathBuf,
+    split: bool,
+    /// one line per packet
+    index: String,
+    combined: String,
+    packets: usize,
+}
+
+impl PacketOutput {
+    fn new(dir: &Path, split: bool) -> Result<Self> {
+        std::fs::create_dir_all(dir).with_context(||format!("create dir [{dir:?}] failed"))?;
+        Ok(Self { dir: dir.to_path_buf(), split, index: String::new(), combined: String::new(), packets: 0 })
+    }
+
+    /// n is the record index, origin its time and direction or "-"
+    fn add(&mut self, n: usize, origin: &str, data: &[u8], packet: Result<&PacketRef<'_>, &dyn std::fmt::Display>) {
+        let (name, text) = match packet {
+            Ok(packet) => {
+                let name = format!("{n:05}_{}_{}.txt", CodeName(packet.code()), packet.fsm_id());
+                let _r = writeln!(self.index, "{n}	{name}	{origin}	{}	fsm_id {}	sn {}	{} bytes", CodeName(packet.code()), packet.fsm_id(), packet.sn(), data.len());
+                (name, packet_text(packet))
+            },
+            Err(e) => {
+                let name = format!("{n:05}_invalid.txt");
+                let _r = writeln!(self.index, "{n}	{name}	{origin}	invalid	{e}");
+                (name, format!("invalid packet [{e}]\n"))
+            },
+        };
+        let text = format!("#{n} {origin}\n{text}\n{}", to_hexdump(data));
+        if self.split {
+            let path = self.dir.join(&name);
+            if let Err(e) = std::fs::write(&path, text) {
+                warn!("write [{path:?}] failed [{e}]");
             }
-            MCodeType::CLOSERTPCONNECT_ACK => {
-                let r = CloseRtpConnectAck::parse_from(packet.payload()).with_context(||"invalid CloseRtpConnectAck packet")?;
-                info!("{r:#?}");
+        } else {
+            self.combined.push_str(&text);
+            self.combined.push('\n');
+        }
+        self.packets += 1;
+    }
+
+    /// writes the index, or the combined file
+    fn finish(self) -> Result<PathBuf> {
+        let (path, text) = match self.split {
+            true => (self.dir.join("index.txt"), self.index),
+            false => (self.dir.join("decoded.txt"), self.combined),
+        };
+        std::fs::write(&path, text).with_context(||format!("write [{path:?}] failed"))?;
+        Ok(path)
+    }
+}
+
+fn finish_output(output: Option<PacketOutput>) -> Result<()> {
+    if let Some(output) = output {
+        let packets = output.packets;
+        let path = output.finish()?;
+        info!("decoded packets [{packets}] written, see [{path:?}]");
+    }
+    Ok(())
+}
+
+/// header and pretty printed payload as print_packet logs them
+fn packet_text(packet: &PacketRef<'_>) -> String {
+    let mut text = format!("{packet:?}\n");
+    match Message::from_packet(packet) {
+        Ok(Message::Unknown { .. } | Message::Heartbeat | Message::ReleaseChannel) => {},
+        Ok(msg) => { let _r = writeln!(text, "{msg:#?}"); },
+        Err(e) => { let _r = writeln!(text, "decode payload failed [{e:?}]"); },
+    }
+    text
+}
+
+fn warn_length_mismatch(packet: &PacketRef<'_>) {
+    if let Some(m) = packet.length_mismatch() {
+        warn!("header length says [{}] bytes but datagram has [{}]", m.declared, m.actual);
+    }
+}
+
+/// warn about files of PLAY missing under root or with unexpected format
+fn check_play_files(play: &PlayRef<'_>, root: &Path) -> Result<usize> {
+    let mut catalog = MediaCatalog::new(root);
+    let mut bad = 0;
+    for r in play.files() {
+        let file = r?;
+        let problems = catalog.check(&file);
+        for problem in problems.iter() {
+            match problem {
+                AssetProblem::NotLocal => debug!("skip non local file {:?}", file.filename()),
+                _ => {
+                    warn!("file {:?}: {problem:?}", file.filename());
+                    bad += 1;
+                },
             }
-            MCodeType::RELEASECHANNEL => {
-                // no payload
+        }
+
+        if problems.is_empty() {
+            if let Some(name) = file.filename().decode() {
+                match catalog.duration(&name) {
+                    Ok(Some(d)) => info!("file {name:?} duration {d:?}"),
+                    Ok(None) => {},
+                    Err(e) => warn!("file {name:?} probe failed [{e:?}]"),
+                }
             }
-            
-            _ => {
+        }
+    }
+
+    if bad == 0 {
+        info!("all files of Play checked ok");
+    }
+    Ok(bad)
+}
+
+pub(crate) fn parse_hexdump_text(text: &str) -> Result<BytesMut> {
+    parse_hexdump_lines(text.lines())
+}
+
+fn parse_hexdump_lines<'a, I>(lines: I) -> Result<BytesMut> 
+where
+    I: Iterator<Item = &'a str>
+{
+    let mut bin_buf = BytesMut::new();
+    for line in lines {
+        // debug!("line=[{line:?}]");
+        let line = line.trim();
+        if !line.is_empty() {
+            parse_line(line, &mut bin_buf)?;
+        }
+    }
+    Ok(bin_buf)
+}
+
+/// packets of a multi packet hexdump, a line at offset 0 starts a new packet
+pub(crate) fn parse_hexdump_packets(text: &str) -> Result<Vec<BytesMut>> {
+    let mut packets: Vec<BytesMut> = Vec::new();
+    let mut buf = BytesMut::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue
+        }
+        let Some(offset) = parse_line(line, &mut buf)? else { continue };
+        if offset == 0 && packets.last().is_none_or(|x| !x.is_empty()) {
+            packets.push(BytesMut::new());
+        }
+        if let Some(last) = packets.last_mut() {
+            last.extend_from_slice(&buf.split());
+        }
+    }
+    Ok(packets)
+}
+
+/// same layout as the fixtures under assets/test_vn_packet
+pub(crate) fn to_hexdump(data: &[u8]) -> String {
+    to_hexdump_annotated(data, &[])
+}
+
+/// to_hexdump with each annotation before the byte at its offset, a row
+/// of 16 bytes is split where one falls inside it
+pub(crate) fn to_hexdump_annotated(data: &[u8], annotations: &[Annotation]) -> String {
+    let mut text = String::new();
+    let mut annotations = annotations.iter().peekable();
+    let mut offset = 0;
+    while offset < data.len() {
+        while let Some(x) = annotations.next_if(|x| x.offset <= offset) {
+            text.push_str(&x.line);
+            text.push('\n');
+        }
+        let row_end = (offset - offset % 16 + 16).min(data.len());
+        let end = annotations.peek().map_or(row_end, |x| x.offset.min(row_end));
+        write_row(&mut text, offset, &data[offset..end]);
+        offset = end;
+    }
+    for x in annotations {
+        text.push_str(&x.line);
+        text.push('\n');
+    }
+    text
+}
+
+fn write_row(text: &mut String, offset: usize, chunk: &[u8]) {
+    let mut hex = String::new();
+    for (i, b) in chunk.iter().enumerate() {
+        if i == 8 {
+            hex.push(' ');
+        }
+        hex.push_str(&format!("{b:02x} "));
+    }
+    let ascii: String = chunk.iter()
+    .map(|b| if b.is_ascii_graphic() { *b as char } else { '.' })
+    .collect();
+    let _r = writeln!(text, "{offset}\t{hex:<49}\t{ascii}");
+}
+
+/// `# text` comment or `[name]` section line of a hexdump, documenting the
+/// bytes after it. Decoding skips them, gen-fixtures keeps them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Annotation {
+    /// bytes before it
+    pub offset: usize,
+    pub line: String,
+}
+
+impl Annotation {
+    pub fn section(&self) -> Option<&str> {
+        section_name(&self.line)
+    }
+}
+
+fn section_name(line: &str) -> Option<&str> {
+    line.strip_prefix('[')?.strip_suffix(']').filter(|x| !x.is_empty())
+}
+
+/// comment and section lines of a hexdump with their byte offsets
+pub(crate) fn hexdump_annotations(text: &str) -> Result<Vec<Annotation>> {
+    let mut annotations = Vec::new();
+    let mut buf = BytesMut::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if !line.is_empty() && parse_line(line, &mut buf)?.is_none() {
+            annotations.push(Annotation { offset: buf.len(), line: line.to_string() });
+        }
+    }
+    Ok(annotations)
+}
+
+fn print_packet(packet: &PacketRef<'_>) -> Result<()> {
+    info!("{packet:?}");
+
+    match Message::from_packet(packet)? {
+        Message::Unknown { code, .. } => {
+            if MCodeType::try_from(code).is_ok() {
                 warn!("Not imple code");
+            } else {
+                warn!("unknown code");
             }
-        }
-    } else {
-        warn!("unknown code");
+        },
+        // no payload
+        Message::Heartbeat | Message::ReleaseChannel => {},
+        msg => info!("{msg:#?}"),
     }
 
     Ok(())
 }
 
-fn parse_line<B: BufMut>(line: &str, buf: &mut B) -> Result<u64> {
+/// bytes of a hexdump line into buf and its offset, None if a comment or
+/// section line
+fn parse_line<B: BufMut>(line: &str, buf: &mut B) -> Result<Option<u64>> {
+    if line.starts_with('#') || section_name(line).is_some() {
+        return Ok(None)
+    }
     let mut parts = line.split_whitespace();
     let offset = parts.next().with_context(||"no offset part")?;
     let offset: u64 = offset.parse().with_context(||format!("invalid offset [{offset}]"))?;
@@ -160,14 +516,35 @@ fn parse_line<B: BufMut>(line: &str, buf: &mut B) -> Result<u64> {
         }
     }
 
-    Ok(offset)
+    Ok(Some(offset))
 }
 
 #[cfg(test)]
 mod test {
     use bytes::BytesMut;
 
-    use super::{parse_line, decode_text};
+    use crate::vn_proto::{PacketRef, PlayRef};
+
+    use super::{
+        check_play_files, decode_error, hexdump_annotations, minimize_text, parse_hexdump_packets, parse_hexdump_text, parse_line,
+        decode_text, to_hexdump, to_hexdump_annotated, PacketOutput,
+    };
+
+    #[test]
+    fn test_check_play_files() {
+        let data = parse_hexdump_text(include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test_vn_packet/PLAY.txt"))).unwrap();
+        let packet = PacketRef::parse_from(&data[..]).unwrap();
+        let play = PlayRef::parse_from(packet.payload()).unwrap();
+
+        let root = std::env::temp_dir().join(format!("rcn_check_files_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("cc")).unwrap();
+        assert_eq!(check_play_files(&play, &root).unwrap(), 1);
+
+        std::fs::write(root.join("cc/11000.wav"), b"").unwrap();
+        assert_eq!(check_play_files(&play, &root).unwrap(), 0);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 
     #[test]
     fn poc() {
@@ -200,26 +577,95 @@ mod test {
 
     }
 
+    #[test]
+    fn test_packet_output() {
+        let dir = std::env::temp_dir().join(format!("rcn_decvn_out_{}", std::process::id()));
+        let play = parse_hexdump_text(include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test_vn_packet/PLAY.txt"))).unwrap();
+        let packet = PacketRef::parse_from(&play[..]).unwrap();
+
+        let mut output = PacketOutput::new(&dir, true).unwrap();
+        output.add(3, "-", &play[..], Ok(&packet));
+        output.add(4, "-", b"xx", Err(&"too short"));
+        let name = format!("00003_PLAY_{}.txt", packet.fsm_id());
+        let index = output.finish().unwrap();
+        let index = std::fs::read_to_string(index).unwrap();
+        assert_eq!(index.lines().count(), 2);
+        assert!(index.starts_with(&format!("3\t{name}\t-\tPLAY\t")), "{index}");
+        let text = std::fs::read_to_string(dir.join(&name)).unwrap();
+        assert!(text.starts_with("#3 -\n"));
+        // hexdump at the end parses back
+        let hexdump = &text[text.find("\n0\t").unwrap()..];
+        assert_eq!(parse_hexdump_text(hexdump).unwrap(), play);
+        assert!(dir.join("00004_invalid.txt").exists());
+
+        let mut output = PacketOutput::new(&dir, false).unwrap();
+        output.add(0, "-", &play[..], Ok(&packet));
+        output.add(1, "-", &play[..], Ok(&packet));
+        let text = std::fs::read_to_string(output.finish().unwrap()).unwrap();
+        assert!(text.matches("Play").count() >= 2, "{text}");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_hexdump_roundtrip() {
+        let fixture = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test_vn_packet/CANCEL.txt"));
+        let data = parse_hexdump_text(fixture).unwrap();
+        assert_eq!(to_hexdump(&data[..]), fixture);
+
+        let two = format!("{fixture}\n{}", include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test_vn_packet/PLAY_ACK.txt")));
+        let packets = parse_hexdump_packets(&two).unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0], data);
+        assert_eq!(packets[1].len(), 17);
+
+        // comments and sections are skipped and written back where they were
+        let annotated = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test_vn_packet/REGISTER_ACK.txt"));
+        let data = parse_hexdump_text(annotated).unwrap();
+        assert_eq!(data.len(), 39);
+        let annotations = hexdump_annotations(annotated).unwrap();
+        let sections: Vec<_> = annotations.iter().filter_map(|x| x.section().map(|name| (x.offset, name))).collect();
+        assert_eq!(sections, [(0, "header"), (12, "payload"), (13, "capability"), (20, "cn_path")]);
+        assert_eq!(to_hexdump_annotated(&data, &annotations), annotated);
+        assert!(to_hexdump(&data).lines().all(|x| !x.starts_with(['#', '['])));
+    }
+
+    #[test]
+    fn test_minimize_text() {
+        let mut data = parse_hexdump_text(include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test_vn_packet/PLAY.txt"))).unwrap();
+        // Play fixed part cut short
+        data.truncate(12 + 10);
+        data[..2].copy_from_slice(&20_u16.to_be_bytes());
+        let out = std::env::temp_dir().join(format!("rcn_minimize_{}.txt", std::process::id()));
+        minimize_text(&to_hexdump(&data), None, Default::default(), &out).unwrap();
+        let minimized = parse_hexdump_text(&std::fs::read_to_string(&out).unwrap()).unwrap();
+        assert_eq!(minimized.len(), 12, "{minimized:02x?}");
+        assert!(decode_error(&minimized, Default::default()).unwrap().to_string().contains("Play"));
+
+        assert!(minimize_text(include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test_vn_packet/PLAY.txt")), None, Default::default(), &out).is_err());
+        let _r = std::fs::remove_file(&out);
+    }
+
     #[test]
     fn test_parse_line() {
         let mut buf = BytesMut::new();
 
         let offset = parse_line("0\t00 35 00 01 00 2d c6 c2  00 00 80 00 00 00 3c 01 \t.5...-........<.", &mut buf).unwrap();
-        assert_eq!(offset, 0);
+        assert_eq!(offset, Some(0));
         assert_eq!(&buf.split()[..], &[
             0x00, 0x35, 0x00, 0x01, 0x00, 0x2d, 0xc6, 0xc2,  
             0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x3c, 0x01
         ][..]);
 
         let offset = parse_line("23\t00 35 00 01 00 2d c6 c2  00 00 80 00 00 00 3c 01 \t.5...-........<.", &mut buf).unwrap();
-        assert_eq!(offset, 23);
+        assert_eq!(offset, Some(23));
         assert_eq!(&buf.split()[..], &[
             0x00, 0x35, 0x00, 0x01, 0x00, 0x2d, 0xc6, 0xc2,  
             0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x3c, 0x01
         ][..]);
 
         let offset = parse_line("12\t00 35       \t.5", &mut buf).unwrap();
-        assert_eq!(offset, 12);
+        assert_eq!(offset, Some(12));
         assert_eq!(&buf.split()[..], &[
             0x00, 0x35
         ][..]);
@@ -231,5 +677,43 @@ mod test {
 #[derive(Parser, Debug)]
 #[clap(name = "decvn", author, about, version)]
 pub struct CmdArgs {
+    #[clap(long = "hmac-key", long_help = "verify and strip HMAC-SHA256 trailer with this key")]
+    hmac_key: Option<String>,
+
+    #[clap(long = "charset", value_enum, default_value = "utf8", long_help = "fallback charset of strings which are not utf-8")]
+    charset: Charset,
+
+    #[clap(long = "check-files", long_help = "check files of PLAY exist under --root")]
+    check_files: bool,
+
+    #[clap(long = "root", long_help = "prompt root for --check-files, file://xxx resolves to root/xxx", default_value = ".")]
+    root: PathBuf,
+
+    #[clap(long = "length-policy", value_enum, default_value = "truncate", long_help = "parsing of packets whose header length disagrees with datagram size")]
+    length_policy: LengthPolicy,
+
+    #[clap(long = "key-map", long_help = "group packets by Header.key, e.g. mask=0xff00,shift=8,1=board-a")]
+    key_map: Option<KeyMap>,
+
+    #[clap(long = "key-group", long_help = "with --capture, only decode packets of this key group")]
+    key_group: Option<String>,
+
+    #[clap(long = "capture", long_help = "decode all packets of a jsonl or vnrec capture with their timing instead of stdin hexdump")]
+    capture: Option<PathBuf>,
+
+    #[clap(long = "compact", long_help = "with --capture, one line per packet instead of pretty printed payloads")]
+    compact: bool,
+
+    #[clap(short = 'o', long = "output", long_help = "also write decoded packets with their hexdump under this dir, into decoded.txt or one file each with --split")]
+    output: Option<PathBuf>,
+
+    #[clap(long = "split", long_help = "with -o, write each packet to <index>_<code>_<fsm_id>.txt and list them in index.txt, e.g. for attaching to bug reports")]
+    split: bool,
+
+    #[clap(long = "explain", long_help = "print every field of stdin hexdump with its offset, raw bytes and description")]
+    explain: bool,
+
+    #[clap(long = "minimize", long_help = "stdin hexdump failing to decode is shrunk to the smallest packet failing with the same error and written here as a hexdump, e.g. for assets of regression tests")]
+    minimize: Option<PathBuf>,
 }
 
@@ -0,0 +1,136 @@
+use std::{path::{Path, PathBuf}, str::FromStr, time::Duration};
+
+use anyhow::{Result, Context, bail};
+use clap::Parser;
+use tracing::info;
+
+use crate::{
+    subcmd_decvn::parse_hexdump_packets,
+    vn_capture::read_capture,
+    vn_diffms::{diff, render_diff, DiffStatus, MsCatalog},
+    vn_proto::{MCodeType, PacketRef, RegisterRef},
+    vn_session::{cn_socket_path, CnSession},
+};
+
+pub async fn run(args: &CmdArgs) -> Result<()> {
+    let timeout = Duration::from_millis(args.timeout_ms);
+    let a = load(&args.a, args.cn_id, timeout).await.with_context(||format!("REGISTER of [{}] failed", args.a))?;
+    let b = load(&args.b, args.cn_id, timeout).await.with_context(||format!("REGISTER of [{}] failed", args.b))?;
+
+    let rows = diff(&a, &b);
+    print!("{}", render_diff(&args.a.to_string(), &args.b.to_string(), &rows));
+    let differing = rows.iter().filter(|x| x.status != DiffStatus::Same).count();
+    if args.check && differing > 0 {
+        bail!("[{differing}] items differ")
+    }
+    Ok(())
+}
+
+/// where a REGISTER comes from
+#[derive(Debug, Clone)]
+pub enum Source {
+    /// capture or hexdump, first REGISTER in it
+    File(PathBuf),
+    /// MS running in this CINDIR, registered with as a CN
+    Live(PathBuf),
+}
+
+impl FromStr for Source {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix("live:") {
+            Some("") => bail!("no CINDIR after live:"),
+            Some(cindir) => Ok(Self::Live(cindir.into())),
+            None => Ok(Self::File(s.into())),
+        }
+    }
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Live(cindir) => write!(f, "live:{}", cindir.display()),
+        }
+    }
+}
+
+async fn load(source: &Source, cn_id: u32, timeout: Duration) -> Result<MsCatalog> {
+    let payload = match source {
+        Source::File(path) => register_in_file(path)?,
+        Source::Live(cindir) => register_of_live(cindir, cn_id, timeout).await?,
+    };
+    let reg = RegisterRef::parse_from(&payload).with_context(||"invalid Register packet")?;
+    Ok(MsCatalog::from_register(&reg))
+}
+
+/// payload of the first REGISTER of a jsonl or vnrec capture, or of a hexdump
+fn register_in_file(path: &Path) -> Result<Vec<u8>> {
+    let packets = match read_capture(path) {
+        Ok(records) => records.iter().filter_map(|x| x.data().ok()).collect::<Vec<_>>(),
+        Err(e) => {
+            let text = std::fs::read_to_string(path).with_context(||format!("read failed [{path:?}]"))?;
+            let packets = parse_hexdump_packets(&text).with_context(||format!("neither a capture [{e:#}] nor a hexdump"))?;
+            packets.iter().map(|x| x.to_vec()).collect()
+        },
+    };
+    packets.iter()
+    .filter_map(|x| PacketRef::parse_from(x).ok())
+    .find(|x| x.code() == MCodeType::REGISTER.code())
+    .map(|x| x.payload().to_vec())
+    .with_context(||format!("no REGISTER in [{path:?}]"))
+}
+
+async fn register_of_live(cindir: &Path, cn_id: u32, timeout: Duration) -> Result<Vec<u8>> {
+    let mut session = CnSession::bind(cindir, cn_id).await?;
+    let r = tokio::time::timeout(timeout, async {
+        session.handshake().await?;
+        session.accept_register().await
+    }).await;
+    // don't leave a stale socket behind
+    if let Ok(path) = cn_socket_path(cindir, cn_id) {
+        let _r = std::fs::remove_file(path);
+    }
+    let payload = r.with_context(||format!("no REGISTER in [{timeout:?}]"))??;
+    info!("registered with MS in [{cindir:?}]");
+    Ok(payload)
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "diffms", author, about = "compare codec catalogs and capabilities two MS advertise in REGISTER", version)]
+pub struct CmdArgs {
+    #[clap(long_help = "REGISTER of the old MS: a jsonl or vnrec capture, a hexdump, or live:<CINDIR> of a running MS")]
+    a: Source,
+
+    #[clap(long_help = "REGISTER of the new MS, as a")]
+    b: Source,
+
+    #[clap(long = "cn-id", long_help = "CN a live MS is registered with as, must not be running", default_value = "5")]
+    cn_id: u32,
+
+    #[clap(long = "timeout-ms", long_help = "wait this long for REGISTER of a live MS", default_value = "3000")]
+    timeout_ms: u64,
+
+    #[clap(long = "check", long_help = "fail if anything differs, e.g. for sign-off scripts")]
+    check: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{register_in_file, Source};
+
+    #[test]
+    fn test_register_in_file() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/test_vn_packet");
+        let payload = register_in_file(&dir.join("REGISTER.txt")).unwrap();
+        assert_eq!(crate::vn_proto::RegisterRef::parse_from(&payload).unwrap().media_info.audio_codecs.len(), 2);
+        assert!(register_in_file(&dir.join("PLAY.txt")).is_err());
+
+        assert!(matches!("live:/tmp/cin".parse::<Source>().unwrap(), Source::Live(x) if x == Path::new("/tmp/cin")));
+        assert!("live:".parse::<Source>().is_err());
+        assert_eq!("ms.vnrec".parse::<Source>().unwrap().to_string(), "ms.vnrec");
+    }
+}
@@ -0,0 +1,364 @@
+use std::{
+    fmt,
+    io::ErrorKind,
+    os::unix::{fs::{FileTypeExt, PermissionsExt}, net::UnixDatagram},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, bail};
+use clap::Parser;
+
+use crate::{
+    vn_proto::MCodeType,
+    vn_session::{cn_socket_path, ms_socket_path, CnSession, CINDIR},
+    vn_socket_name::socket_naming,
+};
+
+/// sun_path of sockaddr_un is 108 bytes, nul included
+const MAX_SOCKET_PATH: usize = 107;
+/// largest VN packet, length field + 2
+const MAX_PACKET: usize = u16::MAX as usize + 2;
+/// 2020-01-01, a wall clock before this was never set
+const MIN_UNIX_SECS: u64 = 1577836800;
+/// wall clock moving this much apart from monotonic while we watch is a step
+const MAX_CLOCK_STEP: Duration = Duration::from_millis(50);
+
+pub async fn run(args: &CmdArgs) -> Result<()> {
+    let checks = diagnose(args.cindir.clone(), args.cn_id, Duration::from_millis(args.timeout_ms)).await;
+    for check in checks.iter() {
+        println!("{check}");
+    }
+    let failed = checks.iter().filter(|x| x.status == Status::Fail).count();
+    if failed > 0 {
+        bail!("[{failed}] checks failed")
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Ok,
+    Warn,
+    Fail,
+    /// not run since an earlier check failed
+    Skip,
+}
+
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    /// what to do about it, empty if nothing
+    pub hint: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self { name, status, detail: detail.into(), hint: hint.into() }
+    }
+
+    fn ok(name: &'static str, detail: impl Into<String>) -> Self {
+        Self::new(name, Status::Ok, detail, "")
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self.status {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "FAIL",
+            Status::Skip => "skip",
+        };
+        write!(f, "[{status:^4}] {:<10} {}", self.name, self.detail)?;
+        if !self.hint.is_empty() {
+            write!(f, "\n       -> {}", self.hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// what is at a socket path
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Probe {
+    Missing,
+    NotSocket,
+    /// someone is bound to it
+    Live,
+    /// socket file nobody is bound to
+    Stale,
+    Denied(u32),
+    Error(String),
+}
+
+fn probe(path: &Path) -> Probe {
+    let meta = match std::fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Probe::Missing,
+        Err(e) => return Probe::Error(e.to_string()),
+    };
+    if !meta.file_type().is_socket() {
+        return Probe::NotSocket
+    }
+    let r = UnixDatagram::unbound().and_then(|socket| socket.connect(path));
+    match r {
+        Ok(()) => Probe::Live,
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => Probe::Stale,
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => Probe::Denied(meta.permissions().mode() & 0o777),
+        Err(e) => Probe::Error(e.to_string()),
+    }
+}
+
+/// all checks in order, later ones are skipped when what they need failed
+pub async fn diagnose(cindir: Option<PathBuf>, cn_id: u32, timeout: Duration) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let cindir = check_cindir(cindir, &mut checks);
+
+    let mut ms_live = false;
+    let mut cn_free = false;
+    match &cindir {
+        Some(cindir) => {
+            let cn_path = socket_naming().cn_path(cindir, cn_id);
+            checks.push(check_path_len(&cn_path));
+            let check = check_ms_socket(&ms_socket_path(cindir));
+            ms_live = check.status == Status::Ok;
+            checks.push(check);
+            checks.push(check_stale(cindir));
+            let check = check_cn_socket(&cn_path, cn_id);
+            cn_free = check.status == Status::Ok;
+            checks.push(check);
+        },
+        None => {
+            for name in ["path len", "ms socket", "stale", "cn socket"] {
+                checks.push(Check::new(name, Status::Skip, "no CINDIR", ""));
+            }
+        },
+    }
+
+    checks.push(check_datagram_size());
+    checks.push(check_clock().await);
+
+    let peer = match (&cindir, ms_live, cn_free) {
+        (Some(cindir), true, true) => check_peer(cindir, cn_id, timeout).await,
+        _ => Check::new("peer", Status::Skip, "MS socket or cn socket not usable", ""),
+    };
+    checks.push(peer);
+    checks
+}
+
+fn check_cindir(cindir: Option<PathBuf>, checks: &mut Vec<Check>) -> Option<PathBuf> {
+    const NAME: &str = "cindir";
+    let cindir = match cindir.or_else(|| std::env::var_os(CINDIR).map(PathBuf::from)) {
+        Some(x) => x,
+        None => {
+            checks.push(Check::new(NAME, Status::Fail, format!("env [{CINDIR}] not set"), format!("export {CINDIR}=<dir the MS binds msvn in>, or pass --cindir")));
+            return None
+        },
+    };
+
+    let check = match std::fs::metadata(&cindir) {
+        Ok(meta) if meta.is_dir() => {
+            // writable is what binding our socket needs
+            let probe_file = cindir.join(format!(".rcn_doctor_{}", std::process::id()));
+            match std::fs::write(&probe_file, b"") {
+                Ok(()) => {
+                    let _r = std::fs::remove_file(&probe_file);
+                    Check::ok(NAME, format!("[{}]", cindir.display()))
+                },
+                Err(e) => Check::new(
+                    NAME, Status::Fail, format!("[{}] not writable, [{e}], mode [{:o}]", cindir.display(), meta.permissions().mode() & 0o777),
+                    "run as the user of the MS or make the dir group writable (chmod g+w)",
+                ),
+            }
+        },
+        Ok(_meta) => Check::new(NAME, Status::Fail, format!("[{}] is not a directory", cindir.display()), format!("point {CINDIR} at the dir holding msvn")),
+        Err(e) => Check::new(NAME, Status::Fail, format!("[{}] {e}", cindir.display()), format!("create it (mkdir -p) or fix {CINDIR}")),
+    };
+    let ok = check.status == Status::Ok;
+    checks.push(check);
+    ok.then_some(cindir)
+}
+
+fn check_path_len(cn_path: &Path) -> Check {
+    const NAME: &str = "path len";
+    let len = cn_path.as_os_str().len();
+    if len > MAX_SOCKET_PATH {
+        return Check::new(NAME, Status::Fail, format!("[{}] is [{len}] bytes, max [{MAX_SOCKET_PATH}]", cn_path.display()), format!("use a shorter {CINDIR}"))
+    }
+    Check::ok(NAME, format!("[{len}] of [{MAX_SOCKET_PATH}] bytes"))
+}
+
+fn check_ms_socket(ms_path: &Path) -> Check {
+    const NAME: &str = "ms socket";
+    let path = ms_path.display();
+    match probe(ms_path) {
+        Probe::Live => Check::ok(NAME, format!("[{path}] is bound")),
+        Probe::Missing => Check::new(NAME, Status::Fail, format!("[{path}] missing"), format!("start the MS with the same {CINDIR}")),
+        Probe::NotSocket => Check::new(NAME, Status::Fail, format!("[{path}] is not a socket"), "remove it, the MS binds it at start"),
+        Probe::Stale => Check::new(NAME, Status::Fail, format!("[{path}] is stale, nobody bound"), "MS is down or crashed, restart it"),
+        Probe::Denied(mode) => Check::new(NAME, Status::Fail, format!("[{path}] permission denied, mode [{mode:o}]"), "run as the user of the MS or make the socket group writable"),
+        Probe::Error(e) => Check::new(NAME, Status::Fail, format!("[{path}] {e}"), ""),
+    }
+}
+
+/// sockets of CNs or MS left behind by dead processes
+fn check_stale(cindir: &Path) -> Check {
+    const NAME: &str = "stale";
+    let naming = socket_naming();
+    let mut stale = Vec::new();
+    for dir in naming.dirs(cindir) {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(x) => x,
+            Err(e) => return Check::new(NAME, Status::Warn, format!("can't list [{}], {e}", dir.display()), ""),
+        };
+        stale.extend(entries
+        .filter_map(|x| x.ok())
+        .map(|x| x.path())
+        .filter(|x| x.strip_prefix(cindir).is_ok_and(|x| naming.is_socket(x)))
+        .filter(|x| probe(x) == Probe::Stale)
+        .map(|x| x.strip_prefix(cindir).unwrap_or(&x).to_string_lossy().into_owned()));
+    }
+    stale.sort();
+
+    if stale.is_empty() {
+        return Check::ok(NAME, "no stale sockets")
+    }
+    Check::new(NAME, Status::Warn, format!("[{}]", stale.join(", ")), "left by dead processes, safe to remove, binding removes them as well")
+}
+
+/// the socket we probe the MS from must not be some running CN's
+fn check_cn_socket(cn_path: &Path, cn_id: u32) -> Check {
+    const NAME: &str = "cn socket";
+    match probe(cn_path) {
+        Probe::Missing | Probe::Stale => Check::ok(NAME, format!("cn [{cn_id}] free")),
+        Probe::Live => Check::new(NAME, Status::Warn, format!("[{}] is bound by a running CN", cn_path.display()), "pass --cn-id of an unused CN to probe the MS"),
+        other => Check::new(NAME, Status::Fail, format!("[{}] {other:?}", cn_path.display()), "remove it"),
+    }
+}
+
+/// largest of some sizes a unix datagram goes through
+fn check_datagram_size() -> Check {
+    const NAME: &str = "datagram";
+    let (a, b) = match UnixDatagram::pair() {
+        Ok(x) => x,
+        Err(e) => return Check::new(NAME, Status::Fail, format!("socketpair failed, {e}"), ""),
+    };
+    let mut buf = vec![0_u8; MAX_PACKET];
+    let mut largest = 0;
+    for size in [1700, 9000, 32768, MAX_PACKET] {
+        if a.send(&buf[..size]).is_err() || b.recv(&mut buf).ok() != Some(size) {
+            break
+        }
+        largest = size;
+    }
+    match largest {
+        MAX_PACKET => Check::ok(NAME, format!("[{MAX_PACKET}] bytes go through")),
+        0 => Check::new(NAME, Status::Fail, "a 1700 bytes datagram fails", "check net.core.wmem_default and net.unix.max_dgram_qlen"),
+        n => Check::new(NAME, Status::Warn, format!("only [{n}] of [{MAX_PACKET}] bytes go through"), "raise net.core.wmem_default, large REGISTERs may fail"),
+    }
+}
+
+async fn check_clock() -> Check {
+    const NAME: &str = "clock";
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0);
+    if secs < MIN_UNIX_SECS {
+        return Check::new(NAME, Status::Fail, format!("wall clock at unix [{secs}]"), "set the system time (ntp), HEARTBEAT clock offsets are meaningless")
+    }
+
+    let (wall, mono) = (SystemTime::now(), Instant::now());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mono = mono.elapsed();
+    let wall = SystemTime::now().duration_since(wall).unwrap_or_default();
+    let step = wall.abs_diff(mono);
+    if step > MAX_CLOCK_STEP {
+        return Check::new(NAME, Status::Warn, format!("wall clock moved [{wall:?}] in [{mono:?}]"), "something steps the clock, prefer ntp slewing")
+    }
+    Check::ok(NAME, format!("unix [{secs}]"))
+}
+
+/// HEARTBEAT from cn_id and wait for the answer
+async fn check_peer(cindir: &Path, cn_id: u32, timeout: Duration) -> Check {
+    const NAME: &str = "peer";
+    let r = async {
+        let mut session = CnSession::bind(cindir, cn_id).await?;
+        let started = Instant::now();
+        session.send_heartbeat().await?;
+        loop {
+            let packet = session.recv_packet().await?;
+            if packet.code() == MCodeType::HEARTBEAT.code() {
+                return anyhow::Ok(started.elapsed())
+            }
+        }
+    };
+    let r = tokio::time::timeout(timeout, r).await;
+    // don't leave a stale socket behind
+    if let Ok(path) = cn_socket_path(cindir, cn_id) {
+        let _r = std::fs::remove_file(path);
+    }
+    match r {
+        Ok(Ok(rtt)) => Check::ok(NAME, format!("HEARTBEAT answered in [{rtt:?}]")),
+        Ok(Err(e)) => Check::new(NAME, Status::Fail, format!("{e:#}"), ""),
+        Err(_e) => Check::new(NAME, Status::Fail, format!("no HEARTBEAT answer in [{timeout:?}]"), "MS is bound but not answering, is it hung or a different CINDIR?"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        vn_ms_sim::{MsSim, MsSimConfig},
+        vn_session::ms_socket_path,
+    };
+
+    use super::{diagnose, Status};
+
+    #[tokio::test]
+    async fn test_doctor() {
+        let cindir = std::env::temp_dir().join(format!("rcn_doctor_{}", std::process::id()));
+        std::fs::create_dir_all(&cindir).unwrap();
+        let status = |checks: &[super::Check], name: &str| checks.iter().find(|x| x.name == name).unwrap().status;
+
+        // nothing running yet
+        let checks = diagnose(Some(cindir.clone()), 5, Duration::from_millis(100)).await;
+        assert_eq!(status(&checks, "cindir"), Status::Ok);
+        assert_eq!(status(&checks, "ms socket"), Status::Fail);
+        assert_eq!(status(&checks, "datagram"), Status::Ok);
+        assert_eq!(status(&checks, "peer"), Status::Skip);
+
+        // dropped socket leaves its file behind
+        drop(std::os::unix::net::UnixDatagram::bind(cindir.join("mscn9")).unwrap());
+        let mut sim = MsSim::bind(&cindir, MsSimConfig::default()).await.unwrap();
+        let sim_task = tokio::spawn(async move { sim.run().await });
+
+        let checks = diagnose(Some(cindir.clone()), 5, Duration::from_millis(1000)).await;
+        assert_eq!(status(&checks, "ms socket"), Status::Ok);
+        let stale = checks.iter().find(|x| x.name == "stale").unwrap();
+        assert_eq!((stale.status, stale.detail.as_str()), (Status::Warn, "[mscn9]"));
+        assert_eq!(status(&checks, "peer"), Status::Ok, "{checks:?}");
+        assert!(!cindir.join("mscn5").exists());
+
+        sim_task.abort();
+        let _r = std::fs::remove_file(ms_socket_path(&cindir));
+        let checks = diagnose(Some(cindir.join("nope")), 5, Duration::from_millis(100)).await;
+        assert_eq!(status(&checks, "cindir"), Status::Fail);
+        assert_eq!(status(&checks, "ms socket"), Status::Skip);
+
+        let _r = std::fs::remove_dir_all(&cindir);
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "doctor", author, about = "check CINDIR, sockets, MS reachability, datagram limits and clock", version)]
+pub struct CmdArgs {
+    #[clap(long = "cindir", long_help = "dir of msvn and mscn sockets, default is env CINDIR")]
+    cindir: Option<PathBuf>,
+
+    #[clap(long = "cn-id", long_help = "CN the MS is probed as, must not be running", default_value = "5")]
+    cn_id: u32,
+
+    #[clap(long = "timeout-ms", long_help = "wait this long for the HEARTBEAT answer", default_value = "2000")]
+    timeout_ms: u64,
+}
@@ -0,0 +1,341 @@
+use std::{path::Path, time::Duration, collections::BTreeSet};
+
+use anyhow::{Result, Context};
+use bytes::Buf;
+use clap::Parser;
+use tokio::{net::UnixDatagram, time::Instant};
+use tracing::{debug, info, warn};
+
+use crate::{
+    subcmd_decvn::parse_hexdump_text,
+    utils::rng::SimRng,
+    vn_fsm_id::FsmIdSpace,
+    vn_proto::{Header, MCode, MCodeType, PacketRef, TagRef, HEADER_LENGTH},
+    vn_session::{bind_socket, cindir_from_env, cn_socket_path, ms_socket_path},
+};
+
+pub async fn run(args: &CmdArgs, rng: &SimRng) -> Result<()> {
+    let text = tokio::fs::read_to_string(&args.input).await
+    .with_context(||format!("failed to read input [{}]", args.input))?;
+
+    let origin = parse_hexdump_text(&text)?;
+    let packet = PacketRef::parse_from(&origin[..]).with_context(||"input is not a valid packet")?;
+    info!("origin {packet:?}");
+
+    let mut mutations = gen_mutations(&origin[..]);
+    mutations.extend(gen_random_mutations(&origin[..], args.random, &rng.fork("fuzz")));
+    info!("generated [{}] mutations", mutations.len());
+
+    if args.dry_run {
+        for (index, mutation) in mutations.iter().enumerate() {
+            info!("[{index}] {:?}, bytes [{}]", mutation.kind, mutation.data.len());
+        }
+        return Ok(())
+    }
+
+    let cindir = cindir_from_env()?;
+    let socket = bind_socket(&cn_socket_path(&cindir, args.cn_id)?).await?;
+    let ms_socket_path = ms_socket_path(&cindir);
+    let timeout = Duration::from_millis(args.timeout_ms);
+    let probe = Header {
+        code: args.probe_code.code(),
+        fsm_id: FsmIdSpace::of_cn(args.cn_id).base,
+        ..Default::default()
+    };
+
+    let mut recv_buf = vec![0_u8; 1700];
+    let mut outcomes = Vec::with_capacity(mutations.len());
+
+    for (index, mutation) in mutations.iter().enumerate() {
+        let outcome = send_and_probe(&socket, &ms_socket_path, mutation, &probe, timeout, &mut recv_buf).await;
+        match &outcome {
+            Outcome::Alive => info!("[{index}] {:?} => {outcome:?}", mutation.kind),
+            _ => warn!("[{index}] {:?} => {outcome:?}", mutation.kind),
+        }
+
+        let is_crash = matches!(outcome, Outcome::Crash);
+        outcomes.push((index, outcome));
+        if is_crash {
+            warn!("peer looks dead, stop sending");
+            break;
+        }
+    }
+
+    let num_alive = outcomes.iter().filter(|x|matches!(x.1, Outcome::Alive)).count();
+    info!("sent [{}/{}] mutations, alive [{num_alive}]", outcomes.len(), mutations.len());
+    for (index, outcome) in outcomes.iter() {
+        if !matches!(outcome, Outcome::Alive) {
+            info!("  [{index}] {:?} => {outcome:?}", mutations[*index].kind);
+        }
+    }
+
+    Ok(())
+}
+
+async fn send_and_probe(
+    socket: &UnixDatagram,
+    ms_path: &Path,
+    mutation: &Mutation,
+    probe: &Header,
+    timeout: Duration,
+    recv_buf: &mut [u8],
+) -> Outcome {
+    if let Err(e) = socket.send_to(&mutation.data[..], ms_path).await {
+        warn!("send mutation failed [{e}]");
+        return Outcome::Crash
+    }
+
+    let mut probe_buf = [0_u8; HEADER_LENGTH];
+    let len = probe.write_to(&mut probe_buf[..]);
+    if let Err(e) = socket.send_to(&probe_buf[..len], ms_path).await {
+        warn!("send probe failed [{e}]");
+        return Outcome::Crash
+    }
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let r = tokio::time::timeout_at(deadline, socket.recv_from(recv_buf)).await;
+        match r {
+            Ok(Ok((recv_len, _from))) => {
+                match PacketRef::parse_from(&recv_buf[..recv_len]) {
+                    Ok(packet) => {
+                        debug!("  recv {packet:?}");
+                        if packet.code() == probe.code {
+                            return Outcome::Alive
+                        }
+                    },
+                    Err(e) => debug!("  recv invalid packet [{e:?}]"),
+                }
+            },
+            Ok(Err(e)) => {
+                warn!("recv failed [{e}]");
+                return Outcome::Crash
+            },
+            Err(_elapsed) => return Outcome::Hang,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Outcome {
+    /// peer answered the probe
+    Alive,
+
+    /// no probe response within timeout
+    Hang,
+
+    /// socket level failure, peer is probably gone
+    Crash,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MutationKind {
+    /// cut the datagram at a field boundary
+    Truncate { at: usize },
+
+    /// overwrite header length field
+    HeaderLength { value: u16 },
+
+    /// replace string terminator at pos with a printable char
+    MissingNul { pos: usize },
+
+    /// overwrite length field of the tag at offset
+    TagLength { offset: usize, value: u16 },
+
+    /// xor one byte at pos with mask
+    FlipByte { pos: usize, mask: u8 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Mutation {
+    pub kind: MutationKind,
+    pub data: Vec<u8>,
+}
+
+pub fn gen_mutations(origin: &[u8]) -> Vec<Mutation> {
+    let mut mutations = Vec::new();
+    if origin.len() < HEADER_LENGTH {
+        return mutations
+    }
+
+    let declared = (&origin[0..]).get_u16() as usize;
+    let payload_end = (declared + 2).min(origin.len());
+    let tags = tag_offsets(origin, payload_end);
+
+    // truncations
+    {
+        let mut boundaries: BTreeSet<usize> = [0, 2, 4, 8, 10, HEADER_LENGTH, payload_end].into_iter().collect();
+        for (pos, v) in origin.iter().enumerate().skip(HEADER_LENGTH) {
+            if *v == 0 {
+                boundaries.insert(pos);
+                boundaries.insert(pos + 1);
+            }
+        }
+
+        for (offset, len) in tags.iter() {
+            boundaries.insert(*offset + 1);
+            boundaries.insert(*offset + 3);
+            boundaries.insert(*offset + 3 + *len);
+        }
+
+        for at in boundaries.into_iter().filter(|x| *x < origin.len()) {
+            mutations.push(Mutation {
+                kind: MutationKind::Truncate { at },
+                data: origin[..at].to_vec(),
+            });
+        }
+    }
+
+    // oversized and undersized header length
+    {
+        let values: BTreeSet<u16> = [
+            0,
+            (HEADER_LENGTH - 2 - 1) as u16,
+            (declared + 1) as u16,
+            origin.len() as u16,
+            u16::MAX,
+        ].into_iter().filter(|x| *x as usize != declared).collect();
+
+        for value in values {
+            let mut data = origin.to_vec();
+            data[0..2].copy_from_slice(&value.to_be_bytes());
+            mutations.push(Mutation {
+                kind: MutationKind::HeaderLength { value },
+                data,
+            });
+        }
+    }
+
+    // missing string terminators
+    for pos in HEADER_LENGTH+1..origin.len() {
+        if origin[pos] == 0 && is_printable(origin[pos-1]) {
+            let mut data = origin.to_vec();
+            data[pos] = b'A';
+            mutations.push(Mutation {
+                kind: MutationKind::MissingNul { pos },
+                data,
+            });
+        }
+    }
+
+    // bad tag lengths
+    for (offset, len) in tags.iter() {
+        let values: BTreeSet<u16> = [0, (*len + 1) as u16, u16::MAX].into_iter().collect();
+        for value in values {
+            let mut data = origin.to_vec();
+            data[offset+1..offset+3].copy_from_slice(&value.to_be_bytes());
+            mutations.push(Mutation {
+                kind: MutationKind::TagLength { offset: *offset, value },
+                data,
+            });
+        }
+    }
+
+    mutations
+}
+
+/// flip one random byte of the payload in each mutation
+pub fn gen_random_mutations(origin: &[u8], num: usize, rng: &SimRng) -> Vec<Mutation> {
+    if origin.len() <= HEADER_LENGTH {
+        return Vec::new()
+    }
+
+    (0..num).map(|_| {
+        let pos = rng.range(HEADER_LENGTH as u64, origin.len() as u64) as usize;
+        let mask = rng.range(1, 256) as u8;
+        let mut data = origin.to_vec();
+        data[pos] ^= mask;
+        Mutation {
+            kind: MutationKind::FlipByte { pos, mask },
+            data,
+        }
+    }).collect()
+}
+
+/// returns (offset, payload length) of each tag in the packet
+fn tag_offsets(origin: &[u8], payload_end: usize) -> Vec<(usize, usize)> {
+    let mut offsets = Vec::new();
+
+    let code = (&origin[2..]).get_u16();
+    let start = match MCodeType::try_from(code) {
+        Ok(MCodeType::REGISTER) => 4,
+        Ok(MCodeType::OPENRTPCONNECT) => 1,
+        Ok(MCodeType::PLAY) => 16,
+        Ok(MCodeType::PLAY_ACK) => 5,
+        _ => {
+            debug!("no tags for code {:?}", MCode::new(code));
+            return offsets
+        },
+    };
+
+    let mut pos = HEADER_LENGTH + start;
+    while pos < payload_end {
+        match TagRef::parse_from(&origin[pos..payload_end]) {
+            Ok(tag) => {
+                let len = tag.payload().len();
+                offsets.push((pos, len));
+                pos += 3 + len;
+            },
+            Err(_e) => break,
+        }
+    }
+    offsets
+}
+
+fn is_printable(v: u8) -> bool {
+    (0x20..0x7f).contains(&v)
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "fuzz-send", author, about, version)]
+pub struct CmdArgs {
+    #[clap(short = 'i', long = "input", long_help = "hexdump file of a valid packet, same format as decvn")]
+    input: String,
+
+    #[clap(long = "cn-id", default_value = "5")]
+    cn_id: u32,
+
+    #[clap(long = "timeout", default_value = "1000", long_help = "milliseconds waiting for probe response")]
+    timeout_ms: u64,
+
+    #[clap(long = "probe-code", value_enum, default_value = "heartbeat", long_help = "code sent after each mutation, peer is alive if it answers with the same code")]
+    probe_code: MCodeType,
+
+    #[clap(long = "dry-run", long_help = "print mutations without sending")]
+    dry_run: bool,
+
+    #[clap(long = "random", long_help = "add this many random byte flips, reproducible with --seed", default_value = "0")]
+    random: usize,
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{subcmd_decvn::parse_hexdump_text, utils::rng::SimRng};
+
+    use super::{gen_mutations, gen_random_mutations, MutationKind};
+
+    #[test]
+    fn test_gen_mutations() {
+        let origin = parse_hexdump_text(include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test_vn_packet/PLAY.txt"))).unwrap();
+        let mutations = gen_mutations(&origin[..]);
+
+        assert!(mutations.iter().all(|x| x.data[..] != origin[..]));
+
+        assert!(mutations.iter().any(|x| x.kind == MutationKind::Truncate { at: 2 }));
+        assert!(mutations.iter().any(|x| x.kind == MutationKind::HeaderLength { value: u16::MAX }));
+        assert!(mutations.iter().any(|x| matches!(x.kind, MutationKind::MissingNul{..})));
+
+        // PLAY has one FILENAME tag right after the 16 bytes fixed part
+        assert!(mutations.iter().any(|x| x.kind == MutationKind::TagLength { offset: 28, value: 0 }));
+        assert!(mutations.iter().any(|x| x.kind == MutationKind::TagLength { offset: 28, value: u16::MAX }));
+    }
+
+    #[test]
+    fn test_random_mutations_reproducible() {
+        let origin = parse_hexdump_text(include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/test_vn_packet/PLAY.txt"))).unwrap();
+        let kinds = |seed| gen_random_mutations(&origin[..], 16, &SimRng::new(seed)).into_iter().map(|x| x.kind).collect::<Vec<_>>();
+        assert_eq!(kinds(7), kinds(7));
+        assert_ne!(kinds(7), kinds(8));
+        assert!(kinds(7).iter().all(|x| matches!(x, MutationKind::FlipByte { pos, mask } if *pos >= 12 && *mask != 0)));
+    }
+}
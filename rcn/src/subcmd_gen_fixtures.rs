@@ -0,0 +1,265 @@
+//! deterministic hexdump fixtures of every message type, built with the
+//! vn_proto builders, see assets/test_vn_packet.
+
+use std::{net::Ipv4Addr, path::{Path, PathBuf}};
+
+use anyhow::{Result, Context, bail};
+use clap::Parser;
+use tracing::info;
+
+use crate::{
+    subcmd_decvn::{hexdump_annotations, to_hexdump_annotated},
+    vn_proto::{
+        Capability, CodecDesc, Direction, Filename, Header, MCodeType, MediaType, OpenRtpConnect, Play, PlayAck, Register,
+        RequestChannel, RequestChannelAck, ResFromTag, RtpInfo, MCODE_TABLE,
+    },
+};
+
+/// cn 3, as the original captures
+const CN_PATH: &str = "/home/ms/cin/mscn3";
+const BASE_FSM_ID: u32 = 3000000;
+const FSM_ID: u32 = 3000002;
+
+/// longest string of the edge case fixtures
+const MAX_STR_LEN: usize = u8::MAX as usize;
+
+pub fn run(args: &CmdArgs) -> Result<()> {
+    let fixtures = fixtures();
+    if args.check {
+        let stale = check_dir(&args.out, &fixtures);
+        if !stale.is_empty() {
+            bail!("fixtures out of date under [{:?}]: {stale:?}", args.out)
+        }
+        info!("all [{}] fixtures up to date", fixtures.len());
+        return Ok(())
+    }
+
+    std::fs::create_dir_all(&args.out).with_context(||format!("create dir failed [{:?}]", args.out))?;
+    for fixture in fixtures.iter() {
+        let path = fixture.path(&args.out);
+        std::fs::write(&path, fixture.hexdump_in(&path)?).with_context(||format!("write fixture failed [{path:?}]"))?;
+    }
+    info!("wrote [{}] fixtures to [{:?}]", fixtures.len(), args.out);
+    Ok(())
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "gen-fixtures", author, about = "write hexdump fixtures of every message type", version)]
+pub struct CmdArgs {
+    #[clap(long = "out", default_value = "assets/test_vn_packet", long_help = "directory of <name>.txt fixtures")]
+    out: PathBuf,
+
+    #[clap(long = "check", long_help = "compare with files under --out instead of writing, fail if any differs")]
+    check: bool,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Fixture {
+    pub name: String,
+    /// whole datagram, cn path included if sent by CN
+    pub data: Vec<u8>,
+}
+
+impl Fixture {
+    fn new(name: impl Into<String>, from: Direction, header: Header, payload: &[u8]) -> Self {
+        let mut data = Vec::new();
+        header.write_to2(&mut data, payload);
+        // CN appends its path after the packet
+        if from == Direction::CnToMs {
+            data.extend_from_slice(CN_PATH.as_bytes());
+            data.push(0);
+        }
+        Self { name: name.into(), data }
+    }
+
+    pub fn path(&self, dir: &Path) -> PathBuf {
+        dir.join(format!("{}.txt", self.name))
+    }
+
+    /// hexdump with the comments and sections of the one at path kept
+    pub fn hexdump_in(&self, path: &Path) -> Result<String> {
+        let annotations = match std::fs::read_to_string(path) {
+            Ok(text) => hexdump_annotations(&text).with_context(||format!("invalid fixture [{path:?}]"))?,
+            Err(_e) => Vec::new(),
+        };
+        Ok(to_hexdump_annotated(&self.data, &annotations))
+    }
+}
+
+/// names of fixtures missing or differing under dir
+fn check_dir(dir: &Path, fixtures: &[Fixture]) -> Vec<String> {
+    let mut stale = Vec::new();
+    for fixture in fixtures.iter() {
+        let path = fixture.path(dir);
+        let r = std::fs::read_to_string(&path);
+        if !r.is_ok_and(|x| fixture.hexdump_in(&path).is_ok_and(|hexdump| x == hexdump)) {
+            stale.push(fixture.name.clone());
+        }
+    }
+    stale
+}
+
+fn header(code: MCodeType, fsm_id: u32, sn: u16) -> Header {
+    Header { code: code.code(), fsm_id, key: 0, sn }
+}
+
+fn payload_of(write: impl FnOnce(&mut Vec<u8>) -> usize) -> Vec<u8> {
+    let mut payload = Vec::new();
+    write(&mut payload);
+    payload
+}
+
+/// every fixture in a fixed order, same output on every run
+pub(crate) fn fixtures() -> Vec<Fixture> {
+    let cn = |name: &str, code: MCodeType, sn: u16, payload: &[u8]| Fixture::new(name, Direction::CnToMs, header(code, FSM_ID, sn), payload);
+    let ms = |name: &str, code: MCodeType, sn: u16, payload: &[u8]| Fixture::new(name, Direction::MsToCn, header(code, FSM_ID, sn), payload);
+    let long_str = |prefix: &str| format!("{prefix}{}", "x".repeat(MAX_STR_LEN - prefix.len()));
+
+    let register = Register {
+        ip: Ipv4Addr::new(192, 168, 9, 246),
+        audio_codecs: vec![
+            CodecDesc { index: 0, payload_type: 0, mapstr: "PCMU/8000".into() },
+            CodecDesc { index: 8, payload_type: 8, mapstr: "PCMA/8000".into() },
+        ],
+        video_codecs: vec![CodecDesc { index: 100, payload_type: 96, mapstr: "H264/90000".into() }],
+        capability: Some(Capability { flags: Capability::ZLIB | Capability::FRAGMENT }),
+        ..Default::default()
+    };
+
+    let request_channel = RequestChannel {
+        life_seconds: 60,
+        media_type: 1,
+        ptime: 20,
+        is_caller: true,
+        codec: 0xff,
+        webrtc: ["null_crypto", "", "", "encode:0", "decode:0"].iter().map(|x| String::from(*x)).collect(),
+        ..Default::default()
+    };
+
+    let mut request_channel_ack = RequestChannelAck {
+        audio_port: 16000,
+        media_type: 1,
+        webrtc: [
+            "null_crypto", "ice-ufrag:", "ice-pwd:", "fingerprint:SHA-256",
+            "assrc:452867621#cname:i1PIEtGQsYqf6uy1", "vssrc:452867621#cname:i1PIEtGQsYqf6uy1",
+        ].iter().map(|x| String::from(*x)).collect(),
+        ..Default::default()
+    };
+    // padding of the original capture
+    request_channel_ack.webrtc.extend(std::iter::repeat_n(String::new(), 39));
+
+    let open_rtp = OpenRtpConnect {
+        rtpinfos: vec![RtpInfo {
+            media_type: 0,
+            internal_pltyp: 0,
+            tele_event: 100,
+            webrtc: [
+                "null_crypto", "null_ice_frag", "null_ice_pwd", "null_fingerprint", "dtls_roll:client", "ice:0", "", "",
+                "H264fmtp:packetization-mode=1;profile-level-id=42C01E;sprop-parameter-sets=Z0LAHtoHgUSAeEAhUA==,aM48gA==",
+                "videoext:0|0|0|0",
+            ].iter().map(|x| String::from(*x)).collect(),
+            ..RtpInfo::plain(Ipv4Addr::new(192, 168, 9, 246), 53335, 8)
+        }],
+    };
+
+    let play = Play {
+        play_times: 2,
+        files: vec![Filename { format: 100, filename: "file://cc/11000.wav".into() }],
+        ..Default::default()
+    };
+
+    let mut fixtures = vec![
+        Fixture::new("HEARTBEAT", Direction::CnToMs, header(MCodeType::HEARTBEAT, BASE_FSM_ID, 0), &[]),
+        Fixture::new("REGISTER", Direction::MsToCn, header(MCodeType::REGISTER, BASE_FSM_ID, 0), &payload_of(|b| register.write_to(b))),
+        Fixture::new("REGISTER_ACK", Direction::CnToMs, header(MCodeType::REGISTER_ACK, BASE_FSM_ID, 0), &payload_of(|b| {
+            b.push(0);
+            1 + Capability { flags: Capability::ZLIB }.write_tag_to(b)
+        })),
+        cn("REQUESTCHANNEL", MCodeType::REQUESTCHANNEL, 0x8000, &payload_of(|b| request_channel.write_to(b))),
+        ms("REQUESTCHANNEL_ACK", MCodeType::REQUESTCHANNEL_ACK, 0x8000, &payload_of(|b| request_channel_ack.write_to(b))),
+        cn("OPENRTPCONNECT", MCodeType::OPENRTPCONNECT, 0x8001, &payload_of(|b| open_rtp.write_to(b))),
+        ms("OPENRTPCONNECT_ACK", MCodeType::OPENRTPCONNECT_ACK, 0x8001, &[0]),
+        cn("RESFROMTAG", MCodeType::RESFROMTAG, 0x8002, &payload_of(|b| ResFromTag { value: "0003-032-0-2d7d37719432d620".into() }.write_to(b))),
+        cn("PLAY", MCodeType::PLAY, 0x8003, &payload_of(|b| play.write_to(b))),
+        ms("PLAY_ACK", MCodeType::PLAY_ACK, 0x8003, &payload_of(|b| PlayAck { result: 2, play_duration: 4820 }.write_to(b))),
+        cn("CANCEL", MCodeType::CANCEL, 0x8005, &MCodeType::PLAY.code().to_be_bytes()),
+        cn("CLOSERTPCONNECT", MCodeType::CLOSERTPCONNECT, 0x8006, &[0]),
+        ms("CLOSERTPCONNECT_ACK", MCodeType::CLOSERTPCONNECT_ACK, 0x8006, &[0]),
+        cn("RELEASECHANNEL", MCodeType::RELEASECHANNEL, 0x8007, &[]),
+    ];
+
+    // edge cases
+    let zero_codecs = Register { audio_codecs: Vec::new(), video_codecs: Vec::new(), capability: None, ..register.clone() };
+    let max_mapstr = Register {
+        audio_codecs: vec![CodecDesc { index: 0, payload_type: 0, mapstr: long_str("PCMU/8000;") }],
+        ..zero_codecs.clone()
+    };
+    let mut unknown_tag = payload_of(|b| register.write_to(b));
+    unknown_tag.extend_from_slice(&[0x7f, 0x00, 0x04, 0xde, 0xad, 0xbe, 0xef]);
+    fixtures.extend([
+        Fixture::new("REGISTER_zero_codecs", Direction::MsToCn, header(MCodeType::REGISTER, BASE_FSM_ID, 0), &payload_of(|b| zero_codecs.write_to(b))),
+        Fixture::new("REGISTER_max_mapstr", Direction::MsToCn, header(MCodeType::REGISTER, BASE_FSM_ID, 0), &payload_of(|b| max_mapstr.write_to(b))),
+        Fixture::new("REGISTER_unknown_tag", Direction::MsToCn, header(MCodeType::REGISTER, BASE_FSM_ID, 0), &unknown_tag),
+        cn("REQUESTCHANNEL_max_call_id", MCodeType::REQUESTCHANNEL, 0x8000, &payload_of(|b| RequestChannel {
+            as_call_id: long_str("call-"),
+            // agora_info is only parsed with an agora media type
+            media_type: MediaType::Agora as u8,
+            agora_info: Some(long_str("agora-")),
+            ..request_channel.clone()
+        }.write_to(b))),
+        ms("REQUESTCHANNEL_ACK_no_webrtc", MCodeType::REQUESTCHANNEL_ACK, 0x8000, &payload_of(|b| RequestChannelAck {
+            webrtc: Vec::new(),
+            ..request_channel_ack.clone()
+        }.write_to(b))),
+        ms("REQUESTCHANNEL_ACK_error", MCodeType::REQUESTCHANNEL_ACK, 0x8000, &payload_of(|b| RequestChannelAck {
+            result: 4,
+            ..Default::default()
+        }.write_to(b))),
+        cn("PLAY_zero_files", MCodeType::PLAY, 0x8003, &payload_of(|b| Play { files: Vec::new(), ..play.clone() }.write_to(b))),
+        cn("PLAY_max_filename", MCodeType::PLAY, 0x8003, &payload_of(|b| Play {
+            files: vec![Filename { format: 100, filename: long_str("file://cc/") }],
+            ..play.clone()
+        }.write_to(b))),
+        cn("RESFROMTAG_empty", MCodeType::RESFROMTAG, 0x8002, &payload_of(|b| ResFromTag::default().write_to(b))),
+        cn("OPENRTPCONNECT_zero_rtpinfos", MCodeType::OPENRTPCONNECT, 0x8001, &payload_of(|b| OpenRtpConnect::default().write_to(b))),
+    ]);
+
+    // remaining codes have no payload layout yet, header only
+    for info in MCODE_TABLE.iter() {
+        let name = format!("{:?}", info.code);
+        if fixtures.iter().any(|x| x.name == name) {
+            continue
+        }
+        let from = if info.direction == Direction::MsToCn { Direction::MsToCn } else { Direction::CnToMs };
+        fixtures.push(Fixture::new(name, from, header(info.code, FSM_ID, 0x8000), &[]));
+    }
+
+    fixtures
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::{subcmd_decvn::{decode_text, parse_hexdump_text, to_hexdump}, vn_proto::{MCODE_TABLE, PacketRef}};
+
+    use super::{check_dir, fixtures};
+
+    #[test]
+    fn test_fixtures_golden() {
+        let fixtures = fixtures();
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/test_vn_packet");
+        assert_eq!(check_dir(&dir, &fixtures), Vec::<String>::new(), "run `rcn gen-fixtures`");
+
+        for info in MCODE_TABLE.iter() {
+            assert!(fixtures.iter().any(|x| x.name == format!("{:?}", info.code)), "no fixture of {:?}", info.code);
+        }
+
+        for fixture in fixtures.iter() {
+            let data = parse_hexdump_text(&to_hexdump(&fixture.data)).unwrap();
+            assert_eq!(&data[..], &fixture.data[..]);
+            PacketRef::parse_from(&data).unwrap();
+            decode_text(&to_hexdump(&fixture.data)).unwrap_or_else(|e| panic!("decode {} failed: {e:?}", fixture.name));
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::{io::{self, BufRead, Write}, path::PathBuf};
+
+use anyhow::{Result, Context};
+use clap::Parser;
+
+use crate::{vn_capture::read_capture, vn_inspect::Journal};
+
+const HELP: &str = "n/enter next, p prev, t next transition, b prev transition, g <step> goto, q quit";
+
+pub fn run(args: &CmdArgs) -> Result<()> {
+    let records = read_capture(&args.input)?;
+    let journal = Journal::replay(&records);
+    if journal.is_empty() {
+        println!("no records in [{}]", args.input.display());
+        return Ok(())
+    }
+    let last = journal.len() - 1;
+
+    if args.transitions {
+        let mut n = if journal.frames()[0].transitions.is_empty() { journal.next_transition(0) } else { Some(0) };
+        while let Some(step) = n {
+            print!("{}", journal.render(step));
+            n = journal.next_transition(step);
+        }
+        return Ok(())
+    }
+
+    let mut n = args.step.min(last);
+    println!("{HELP}");
+    print!("{}", journal.render(n));
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        io::stdout().flush().with_context(||"flush stdout failed")?;
+        let Some(line) = lines.next() else { break };
+        let line = line.with_context(||"read stdin failed")?;
+        let mut words = line.split_whitespace();
+        let next = match words.next().unwrap_or("n") {
+            "n" => (n < last).then_some(n + 1),
+            "p" => n.checked_sub(1),
+            "t" => journal.next_transition(n),
+            "b" => journal.prev_transition(n),
+            "g" => words.next().and_then(|x| x.parse::<usize>().ok()).filter(|x| *x <= last),
+            "q" => break,
+            _ => {
+                println!("{HELP}");
+                continue
+            },
+        };
+        match next {
+            Some(step) => {
+                n = step;
+                print!("{}", journal.render(n));
+            },
+            None => println!("no such step, at #{n}/{last}"),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "inspect", author, about = "step forward and back through channel transitions of a capture, showing the channel registry at each step", version)]
+pub struct CmdArgs {
+    #[clap(long_help = "jsonl or vnrec capture, e.g. of rcn cli --capture")]
+    input: PathBuf,
+
+    #[clap(long = "step", long_help = "step to start at", default_value = "0")]
+    step: usize,
+
+    #[clap(long = "transitions", long_help = "print every step with a transition and exit instead of asking")]
+    transitions: bool,
+}
@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+use anyhow::{Result, Context, bail};
+use clap::Parser;
+use tracing::info;
+
+use crate::{
+    utils::{rate_limit::{RateConfig, RateLimiter}, rng::SimRng},
+    vn_acl::{AclMode, PeerAcl},
+    vn_digit_map::DigitMap,
+    vn_epoch::MAX_EPOCH,
+    vn_key::KeyMap,
+    vn_ms_sim::{MsSim, MsSimConfig},
+    vn_ports::{PortPoolConfig, PortRanges},
+    vn_proto::CodecDesc,
+    vn_session::cindir_from_env,
+    vn_speech::{StubAsr, StubTts},
+    vn_tail,
+};
+
+pub async fn run(args: &CmdArgs, rng: &SimRng) -> Result<()> {
+    let mut config = MsSimConfig {
+        ports: PortPoolConfig { ranges: args.ports.clone(), random: args.random_ports, check_bind: args.check_bind },
+        key_map: args.key_map.clone().unwrap_or_default(),
+        heartbeat_clock_ms: args.heartbeat_clock_ms,
+        epoch: args.epoch,
+        pcap_dir: args.pcap_dir.clone(),
+        echo_delay: args.echo_ms.map(Duration::from_millis),
+        dedup_window: (args.dedup_window > 0).then_some(args.dedup_window),
+        digit_map: args.digit_map.clone(),
+        digit_timeout: Duration::from_millis(args.digit_timeout_ms),
+        ..Default::default()
+    };
+    if let Some(epoch) = args.epoch {
+        if !(1..=MAX_EPOCH).contains(&epoch) {
+            bail!("--epoch [{epoch}] out of 1..={MAX_EPOCH}")
+        }
+    }
+    if let Some(ip) = args.ip {
+        config.register.ip = ip;
+    }
+    if !args.audio_codec.is_empty() {
+        config.register.audio_codecs = args.audio_codec.iter()
+        .map(|x| parse_codec(x))
+        .collect::<Result<_>>()?;
+    }
+
+    let cindir = cindir_from_env()?;
+    let mut sim = MsSim::bind(&cindir, config).await?;
+    sim.set_rng(rng);
+    sim.set_tap(vn_tail::tap());
+    sim.set_rate_limiter(RateLimiter::new(args.rate, args.channel_rate));
+    if args.peer_acl != AclMode::Off {
+        if args.allow_peer.is_empty() {
+            bail!("--peer-acl needs --allow-peer")
+        }
+        sim.set_peer_acl(Some(PeerAcl::new(args.peer_acl, args.allow_peer.clone())));
+    }
+    if args.speech_stub {
+        sim.set_tts(Some(Box::new(StubTts { out_dir: args.tts_dir.clone(), ..Default::default() })));
+        sim.set_asr(Some(Box::new(StubAsr::new(args.asr_result.clone()))));
+        info!("stub speech backends, asr results {:?}", args.asr_result);
+    }
+
+    #[cfg(feature = "script")]
+    if let Some(path) = &args.hooks {
+        sim.set_hooks(Some(Box::new(crate::vn_ms_sim::ScriptHooks::load(path)?)));
+        info!("loaded hooks [{path:?}]");
+    }
+
+    info!("ms sim listening at [{cindir:?}]");
+    let r = tokio::select! {
+        r = sim.run() => r,
+        _r = tokio::signal::ctrl_c() => Ok(()),
+    };
+    for (group, num) in sim.channels_by_key() {
+        info!("key group [{group}]: active channels [{num}]");
+    }
+    info!("{}", sim.port_stats());
+    if let Some(acl) = sim.peer_acl() {
+        info!("{}", acl.stats());
+    }
+    if let Some(stats) = sim.dedup_stats() {
+        info!("{stats}");
+    }
+    r
+}
+
+/// "index:payload_type:mapstr", e.g. "8:8:PCMA/8000"
+fn parse_codec(s: &str) -> Result<CodecDesc> {
+    let mut parts = s.splitn(3, ':');
+    let (Some(index), Some(payload_type), Some(mapstr)) = (parts.next(), parts.next(), parts.next()) else {
+        bail!("invalid codec [{s}], expect index:payload_type:mapstr")
+    };
+    Ok(CodecDesc {
+        index: index.parse().with_context(||format!("invalid codec index [{s}]"))?,
+        payload_type: payload_type.parse().with_context(||format!("invalid codec payload type [{s}]"))?,
+        mapstr: mapstr.to_string(),
+    })
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "ms-sim", author, about = "emulate MS answering a CN at $CINDIR/msvn", version)]
+pub struct CmdArgs {
+    #[clap(long = "ip", long_help = "ip announced in REGISTER")]
+    ip: Option<std::net::Ipv4Addr>,
+
+    #[clap(long = "audio-codec", long_help = "audio codec announced in REGISTER as index:payload_type:mapstr, e.g. 8:8:PCMA/8000")]
+    audio_codec: Vec<String>,
+
+    #[clap(long = "ports", long_help = "rtp port ranges of channels, 4 ports each, e.g. 20000-20999,30000-30999", default_value = "20000-29999")]
+    ports: PortRanges,
+
+    #[clap(long = "random-ports", long_help = "allocate free ports at random instead of lowest first")]
+    random_ports: bool,
+
+    #[clap(long = "check-bind", long_help = "bind ports before handing them out and skip ones held by other processes, as a real rtp engine needs them")]
+    check_bind: bool,
+
+    #[clap(long = "key-map", long_help = "group channels by Header.key, e.g. mask=0xff00,shift=8,1=board-a")]
+    key_map: Option<KeyMap>,
+
+    #[clap(long = "heartbeat-clock-ms", allow_hyphen_values = true, long_help = "answer HEARTBEAT with our unix time shifted by this many ms, e.g. -250, for clock offset estimation of CN")]
+    heartbeat_clock_ms: Option<i64>,
+
+    #[clap(long = "epoch", long_help = "stamp this epoch (1..=7) in Header.key when CN offers it at CNISUP, bump it on each restart to test stale packet detection of CN")]
+    epoch: Option<u16>,
+
+    #[clap(long = "peer-acl", value_enum, default_value = "off", long_help = "drop (enforce) or only log (warn) datagrams from other than --allow-peer paths")]
+    peer_acl: AclMode,
+
+    #[clap(long = "allow-peer", long_help = "path of a CN datagrams are taken from with --peer-acl, e.g. $CINDIR/mscn5, <unnamed> for unbound senders")]
+    allow_peer: Vec<std::path::PathBuf>,
+
+    #[clap(long = "speech-stub", long_help = "answer PLAY of tts:<text> with a tone and asr:<grammar> with RESFROMTAG")]
+    speech_stub: bool,
+
+    #[clap(long = "asr-result", long_help = "canned text of stub asr, repeat to cycle through several, nomatch if none")]
+    asr_result: Vec<String>,
+
+    #[clap(long = "tts-dir", long_help = "save tones of stub tts here as <fsm_id>.wav")]
+    tts_dir: Option<std::path::PathBuf>,
+
+    #[clap(long = "pcap-dir", long_help = "write rtp/rtcp of each channel's synthesized audio (tts:, tone:, dtmf: plays) as <fsm_id>.pcap here, to be opened in Wireshark")]
+    pcap_dir: Option<std::path::PathBuf>,
+
+    #[clap(long = "echo-ms", long_help = "send rtp coming to each channel's audio port back to its sender after this delay, 0 echoes at once")]
+    echo_ms: Option<u64>,
+
+    #[clap(long = "dedup-window", long_help = "drop requests whose code, fsm_id and sn are among the last this many of the same CN, so that retransmissions don't play or reserve ports twice, 0 takes all", default_value = "64")]
+    dedup_window: usize,
+
+    #[clap(long = "digit-map", long_help = "take DTMFRCV of CN as keys pressed and report them back as one DTMFRCV when they complete this map, e.g. [2-9]xxxxxx|0T|#")]
+    digit_map: Option<DigitMap>,
+
+    #[clap(long = "digit-timeout-ms", long_help = "inter-digit timer, T of --digit-map", default_value = "4000")]
+    digit_timeout_ms: u64,
+
+    #[clap(long = "rate", long_help = "send at most this many packets per second in total, as rate[:burst], e.g. 200:50, burst defaults to rate")]
+    rate: Option<RateConfig>,
+
+    #[clap(long = "channel-rate", long_help = "send at most this many packets per second on each fsm_id, as rate[:burst]")]
+    channel_rate: Option<RateConfig>,
+
+    #[cfg(feature = "script")]
+    #[clap(long = "hooks", long_help = "rhai script with on_request_channel/on_play/on_dtmf functions")]
+    hooks: Option<std::path::PathBuf>,
+}
@@ -0,0 +1,63 @@
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::{Result, Context};
+use clap::Parser;
+use tracing::info;
+
+use crate::{
+    vn_probe::{default_codes, probe, render_matrix},
+    vn_proto::MCode,
+    vn_session::{cindir_from_env, cn_socket_path, CnSession},
+};
+
+pub async fn run(args: &CmdArgs) -> Result<()> {
+    let codes = match args.codes.is_empty() {
+        true => default_codes(),
+        false => args.codes.iter()
+        .map(|x| x.as_type().with_context(||format!("unknown code [{x}]")))
+        .collect::<Result<Vec<_>>>()?,
+    };
+    let cindir = match &args.cindir {
+        Some(cindir) => cindir.clone(),
+        None => cindir_from_env()?,
+    };
+
+    let mut session = CnSession::bind(&cindir, args.cn_id).await?;
+    let r = async {
+        session.handshake().await?;
+        session.accept_register().await?;
+        info!("registered, probing [{}] codes", codes.len());
+        probe(&mut session, &codes, Duration::from_millis(args.timeout_ms)).await
+    }.await;
+    // don't leave a stale socket behind
+    if let Ok(path) = cn_socket_path(&cindir, args.cn_id) {
+        let _r = std::fs::remove_file(path);
+    }
+    let rows = r?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&rows)?);
+    } else {
+        print!("{}", render_matrix(&rows));
+    }
+    Ok(())
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "probe", author, about = "register and send each request with a minimal payload to find which codes an MS answers and with what result", version)]
+pub struct CmdArgs {
+    #[clap(long = "cindir", long_help = "dir of msvn and mscn sockets, default is env CINDIR")]
+    cindir: Option<PathBuf>,
+
+    #[clap(long = "cn-id", long_help = "CN the MS is probed as, must not be running", default_value = "5")]
+    cn_id: u32,
+
+    #[clap(long = "codes", value_delimiter = ',', long_help = "codes to probe by name or number, e.g. PLAY,0x1d, default every request CN may send")]
+    codes: Vec<MCode>,
+
+    #[clap(long = "timeout-ms", long_help = "wait this long for the answer of each code", default_value = "1000")]
+    timeout_ms: u64,
+
+    #[clap(long = "json", long_help = "print rows as json instead of a table")]
+    json: bool,
+}
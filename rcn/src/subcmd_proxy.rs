@@ -0,0 +1,92 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Parser;
+use tracing::info;
+
+use crate::{
+    utils::rng::SimRng,
+    vn_chaos::{Chaos, ChaosProfile},
+    vn_proxy::{Proxy, ProxyConfig},
+    vn_shadow::ShadowConfig,
+    vn_tail,
+};
+
+pub async fn run(args: &CmdArgs, rng: &SimRng) -> Result<()> {
+    let mut profile = match &args.chaos {
+        Some(name) => ChaosProfile::load(name)?,
+        None => ChaosProfile::default(),
+    };
+    if let Some(p) = args.dup_ack {
+        profile.dup_ack = p;
+    }
+    if let Some(n) = args.reorder_window {
+        profile.reorder_window = n;
+    }
+    if let Some(ms) = args.reorder_window_ms {
+        profile.reorder_window_ms = ms;
+    }
+    info!("chaos profile {profile:?}");
+
+    let mut proxy = Proxy::new(ProxyConfig {
+        cn_dir: args.cn_dir.clone(),
+        ms_dir: args.ms_dir.clone(),
+        chaos: Chaos::new(profile, rng),
+        shadow: args.shadow_ms_dir.clone().map(|ms_dir| ShadowConfig { ms_dir, ignore: args.shadow_ignore.clone() }),
+    });
+
+    #[cfg(feature = "grpc")]
+    let _server = match args.grpc {
+        Some(addr) => {
+            let hub = crate::vn_grpc::PacketHub::new(crate::vn_grpc::DEFAULT_BACKLOG);
+            proxy.set_tap(Some(hub.tap()));
+            Some(tokio::spawn(crate::vn_grpc::serve(addr, hub)))
+        },
+        None => None,
+    };
+    if let Some(tap) = vn_tail::tap() {
+        proxy.add_tap(tap);
+    }
+
+    let r = tokio::select! {
+        r = proxy.run() => r,
+        _r = tokio::signal::ctrl_c() => Ok(()),
+    };
+
+    for line in proxy.stats().summary() {
+        info!("{line}");
+    }
+    r
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "proxy", author, about = "forward VN packets between CNs and a MS with optional impairments", version)]
+pub struct CmdArgs {
+    #[clap(long = "cn-dir", long_help = "CINDIR of the CNs, proxy binds msvn here")]
+    cn_dir: PathBuf,
+
+    #[clap(long = "ms-dir", long_help = "CINDIR of the MS, proxy binds one mscn{id} per CN here")]
+    ms_dir: PathBuf,
+
+    #[clap(long = "chaos", long_help = "impairment profile, a preset (lossy-lan, congested, ms-restart-every-5m) or a yaml file")]
+    chaos: Option<String>,
+
+    #[clap(long = "dup-ack", long_help = "probability of delivering an ACK twice, overrides chaos profile")]
+    dup_ack: Option<f64>,
+
+    #[clap(long = "reorder-window", long_help = "shuffle up to this many packets before releasing them, overrides chaos profile")]
+    reorder_window: Option<usize>,
+
+    #[clap(long = "reorder-window-ms", long_help = "release a partly filled reorder window after this many milliseconds")]
+    reorder_window_ms: Option<u64>,
+
+    #[clap(long = "shadow-ms-dir", long_help = "CINDIR of a second MS getting every CN packet too, its ACKs are compared field by field to those of the MS and divergences reported, never forwarded")]
+    shadow_ms_dir: Option<PathBuf>,
+
+    #[clap(long = "shadow-ignore", value_delimiter = ',', long_help = "fields not compared with --shadow-ms-dir, e.g. audio_port,video_port")]
+    shadow_ignore: Vec<String>,
+
+    #[cfg(feature = "grpc")]
+    #[clap(long = "grpc", long_help = "stream decoded packets to gRPC subscribers at this address, e.g. 127.0.0.1:50051")]
+    grpc: Option<std::net::SocketAddr>,
+}
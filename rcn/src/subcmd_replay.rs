@@ -0,0 +1,204 @@
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::{Result, Context, bail};
+use clap::Parser;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::{
+    utils::datagram::Datagram,
+    vn_capture::{read_capture, CaptureDir, CaptureRecord},
+    vn_proto::PacketRef,
+    vn_session::CnSession,
+};
+
+pub async fn run(args: &CmdArgs) -> Result<()> {
+    if args.speed.is_nan() || args.speed <= 0.0 {
+        bail!("invalid speed [{}]", args.speed)
+    }
+    let records = read_capture(&args.file)?;
+    let mut session = CnSession::bind_env(args.cn_id).await?;
+    let report = replay(&mut session, &records, args.speed, Duration::from_millis(args.grace_ms)).await?;
+    for line in report.summary() {
+        info!("{line}");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    pub sent: usize,
+    /// sent as recorded since re-encoding would change them
+    pub verbatim: usize,
+    /// ms_to_cn packets in capture
+    pub expected: usize,
+    pub received: usize,
+    /// worst lateness of a send against its scaled capture time
+    pub max_late: Duration,
+}
+
+impl ReplayReport {
+    pub fn summary(&self) -> Vec<String> {
+        vec![
+            format!("sent [{}] ([{}] verbatim), received [{}] of [{}] in capture", self.sent, self.verbatim, self.received, self.expected),
+            format!("max send lateness [{:?}]", self.max_late),
+        ]
+    }
+}
+
+/// send cn_to_ms records at their capture offsets divided by speed,
+/// receiving in between, then keep receiving for grace after the last record.
+/// Records that don't parse or don't re-encode to the same bytes (length
+/// mismatch, trailing bytes) are sent verbatim
+pub async fn replay<S: Datagram>(session: &mut CnSession<S>, records: &[CaptureRecord], speed: f64, grace: Duration) -> Result<ReplayReport> {
+    let mut report = ReplayReport {
+        expected: records.iter().filter(|x| x.dir == CaptureDir::MsToCn).count(),
+        ..Default::default()
+    };
+
+    let start = Instant::now();
+    let scaled = |ts: Duration| start + ts.div_f64(speed);
+
+    for (n, record) in records.iter().enumerate() {
+        if record.dir != CaptureDir::CnToMs {
+            continue
+        }
+        let data = record.data().with_context(||format!("invalid capture record #{n}"))?;
+
+        let due = scaled(record.ts());
+        recv_until(session, due, &mut report).await?;
+
+        report.max_late = report.max_late.max(Instant::now().saturating_duration_since(due));
+        match PacketRef::parse_from(&data[..]) {
+            Ok(packet) if packet.length_mismatch().is_none() => {
+                session.send_packet(&packet.to_header(), packet.payload()).await?;
+            },
+            r => {
+                debug!("record #{n} sent verbatim, {}", r.err().map(|e| e.to_string()).unwrap_or_else(|| "length mismatch".into()));
+                session.send_verbatim(&data).await?;
+                report.verbatim += 1;
+            },
+        }
+        report.sent += 1;
+    }
+
+    let last = records.last().map(|x| scaled(x.ts())).unwrap_or(start);
+    recv_until(session, last + grace, &mut report).await?;
+
+    if report.received != report.expected {
+        warn!("received [{}] but capture has [{}]", report.received, report.expected);
+    }
+    Ok(report)
+}
+
+async fn recv_until<S: Datagram>(session: &mut CnSession<S>, deadline: Instant, report: &mut ReplayReport) -> Result<()> {
+    loop {
+        tokio::select! {
+            _r = tokio::time::sleep_until(deadline) => return Ok(()),
+            r = session.recv_packet() => {
+                r?;
+                report.received += 1;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        vn_capture::{read_capture, to_hex, CaptureDir, CaptureRecord, CaptureWriter},
+        vn_ms_sim::{MsSim, MsSimConfig},
+        vn_proto::{Header, MCodeType},
+        vn_session::{ms_socket_path, CnSession},
+    };
+
+    use super::replay;
+
+    fn record(ts_ms: u64, dir: CaptureDir, code: MCodeType, payload: &[u8]) -> CaptureRecord {
+        let mut data = Vec::new();
+        Header { code: code.code(), fsm_id: 5000000, ..Default::default() }.write_to2(&mut data, payload);
+        CaptureRecord { ts_us: ts_ms * 1000, dir, socket: None, hex: to_hex(&data) }
+    }
+
+    #[tokio::test]
+    async fn test_replay_timing() {
+        let cindir = std::env::temp_dir().join(format!("rcn_replay_{}", std::process::id()));
+        std::fs::create_dir_all(&cindir).unwrap();
+        let mut sim = MsSim::bind(&cindir, MsSimConfig::default()).await.unwrap();
+        let sim_task = tokio::spawn(async move { sim.run().await });
+
+        let records = vec![
+            record(0, CaptureDir::CnToMs, MCodeType::CNISUP, &[]),
+            record(1, CaptureDir::MsToCn, MCodeType::CNISUP_ACK, &[]),
+            record(2, CaptureDir::MsToCn, MCodeType::REGISTER, &[]),
+            record(80, CaptureDir::CnToMs, MCodeType::HEARTBEAT, &[]),
+            record(81, CaptureDir::MsToCn, MCodeType::HEARTBEAT, &[]),
+        ];
+
+        let mut session = CnSession::bind(&cindir, 5).await.unwrap();
+        let started = std::time::Instant::now();
+        let report = replay(&mut session, &records, 2.0, Duration::from_millis(50)).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(40 + 50));
+        assert_eq!(report.sent, 2);
+        assert_eq!(report.expected, 3);
+        assert_eq!(report.received, 3);
+
+        sim_task.abort();
+        let _r = std::fs::remove_dir_all(&cindir);
+    }
+
+    #[tokio::test]
+    async fn test_replay_verbatim() {
+        let cindir = std::env::temp_dir().join(format!("rcn_replay_raw_{}", std::process::id()));
+        std::fs::create_dir_all(&cindir).unwrap();
+        let ms = tokio::net::UnixDatagram::bind(ms_socket_path(&cindir)).unwrap();
+
+        let raw = |ts_ms: u64, data: Vec<u8>| CaptureRecord { ts_us: ts_ms * 1000, dir: CaptureDir::CnToMs, socket: None, hex: to_hex(&data) };
+        let mut trailing = Vec::new();
+        Header { code: 0x7e01, fsm_id: 5000001, key: 0x1234, sn: 9 }.write_to2(&mut trailing, &[0xfe, 0x00, 0x01, 0x55][..]);
+        trailing.extend_from_slice(b"/tmp/cin/mscn5");
+        let records = vec![
+            // unknown code and tag, re-encoded to the same bytes
+            record(0, CaptureDir::CnToMs, MCodeType::REGISTER, &[0, 0, 0, 0, 0x01, 0x00, 0x00, 0xee, 0x00, 0x02, 0xaa, 0xbb]),
+            raw(1, trailing),
+            raw(2, vec![0xff; 5]),
+        ];
+
+        for ext in ["jsonl", "vnrec"] {
+            let path = cindir.join(format!("replay.{ext}"));
+            let mut session = CnSession::bind(&cindir, 5).await.unwrap();
+            session.set_capture(Some(CaptureWriter::create(&path).unwrap()));
+            let report = replay(&mut session, &records, 1.0, Duration::from_millis(1)).await.unwrap();
+            assert_eq!((report.sent, report.verbatim), (3, 2));
+            drop(session);
+
+            let mut buf = vec![0_u8; 2048];
+            let captured = read_capture(&path).unwrap();
+            for (record, captured) in records.iter().zip(captured.iter()) {
+                let len = ms.recv(&mut buf).await.unwrap();
+                assert_eq!(buf[..len], record.data().unwrap()[..]);
+                assert_eq!(captured.hex, record.hex);
+            }
+            assert_eq!(captured.len(), records.len());
+        }
+
+        let _r = std::fs::remove_dir_all(&cindir);
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "replay", author, about = "replay CN packets of a capture with their original timing", version)]
+pub struct CmdArgs {
+    file: PathBuf,
+
+    #[clap(long = "cn-id", default_value = "5")]
+    cn_id: u32,
+
+    #[clap(long = "speed", long_help = "time scale, 2 replays twice as fast", default_value = "1.0")]
+    speed: f64,
+
+    #[clap(long = "grace-ms", long_help = "keep receiving this long after the last record", default_value = "1000")]
+    grace_ms: u64,
+}
@@ -0,0 +1,28 @@
+use std::path::PathBuf;
+
+use anyhow::{Result, Context};
+use clap::Parser;
+use tracing::info;
+
+use crate::{vn_capture::read_capture, vn_report::render_html};
+
+pub fn run(args: &CmdArgs) -> Result<()> {
+    let records = read_capture(&args.input)?;
+    let title = args.title.clone().unwrap_or_else(|| args.input.display().to_string());
+    let html = render_html(&title, &records)?;
+    std::fs::write(&args.output, html).with_context(||format!("write report failed [{:?}]", args.output))?;
+    info!("report of [{}] records written [{:?}]", records.len(), args.output);
+    Ok(())
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "report", author, about = "html report of a capture with per channel timeline, packet details and stats, no tools needed to open it", version)]
+pub struct CmdArgs {
+    input: PathBuf,
+
+    #[clap(short = 'o', long = "output", long_help = "html file to write", default_value = "report.html")]
+    output: PathBuf,
+
+    #[clap(long = "title", long_help = "page title, the capture path by default")]
+    title: Option<String>,
+}
@@ -0,0 +1,169 @@
+use std::path::PathBuf;
+
+use anyhow::{Result, Context, bail};
+use clap::Parser;
+use tracing::{info, Instrument};
+
+use crate::{
+    utils::{clock::default_clock, log::tenant_span, rng::SimRng},
+    vn_capture::read_capture,
+    vn_proto::{IceType, MediaType},
+    vn_scenario::{run_scenario, run_sweep, scenario_from_capture, Scenario, SweepOptions},
+    vn_session::{cindir_from_env, CnSession},
+};
+
+pub async fn run(args: &CmdArgs, rng: &SimRng) -> Result<()> {
+    match &args.cmd {
+        ScenarioCmd::Run(sub) => run_file(sub).await,
+        ScenarioCmd::Sweep(sub) => sweep(sub, rng).await,
+    }
+}
+
+async fn run_file(args: &RunArgs) -> Result<()> {
+    let mut scenario = Scenario::load(&args.file)?;
+    if args.tenant.is_some() {
+        scenario.tenant = args.tenant.clone();
+    }
+    let span = tenant_span(scenario.tenant.as_deref());
+    async {
+        info!("loaded scenario [{}], steps [{}]", scenario.name, scenario.steps.len());
+
+        let mut session = CnSession::bind_env(args.cn_id).await?;
+        session.set_tenant(scenario.tenant.clone());
+        let timings = run_scenario(&mut session, &scenario).await?;
+        for timing in timings.iter() {
+            info!("{timing}");
+        }
+
+        info!("scenario [{}] passed", scenario.name);
+        Ok(())
+    }.instrument(span).await
+}
+
+async fn sweep(args: &SweepArgs, rng: &SimRng) -> Result<()> {
+    let mut scenario = Scenario::load(&args.file)?;
+    if !args.codec.is_empty() {
+        scenario.matrix.codec = args.codec.clone();
+    }
+    if !args.media_type.is_empty() {
+        scenario.matrix.media_type = args.media_type.iter().map(|x| *x as u8).collect();
+    }
+    if !args.ice_type.is_empty() {
+        scenario.matrix.ice_type = args.ice_type.iter().map(|x| *x as u8).collect();
+    }
+    if args.tenant.is_some() {
+        scenario.tenant = args.tenant.clone();
+    }
+
+    let opts = SweepOptions {
+        cindir: cindir_from_env()?,
+        base_cn_id: args.cn_id,
+        repeat: args.repeat,
+        parallel: args.parallel,
+        start_jitter_ms: args.start_jitter_ms,
+        rng: rng.clone(),
+        clock: default_clock(),
+    };
+    info!("sweep scenario [{}], combinations [{}], repeat [{}]", scenario.name, scenario.matrix.combinations().len(), opts.repeat);
+
+    let report = run_sweep(&scenario, &opts).await;
+    for line in report.summary() {
+        println!("{line}");
+    }
+
+    if let Some(path) = &args.report {
+        let json = serde_json::to_string_pretty(&report)?;
+        std::fs::write(path, json).with_context(||format!("write report failed [{path:?}]"))?;
+    }
+
+    let failed = report.failed();
+    if failed > 0 {
+        bail!("scenario [{}] failed runs [{failed}/{}]", scenario.name, report.runs.len())
+    }
+    info!("scenario [{}] passed runs [{}]", scenario.name, report.runs.len());
+    Ok(())
+}
+
+pub fn from_capture(args: &FromCaptureArgs) -> Result<()> {
+    let records = read_capture(&args.capture)?;
+    let name = args.capture.file_stem().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+    let scenario = scenario_from_capture(&name, &records)?;
+    let yaml = scenario.to_yaml()?;
+
+    match &args.output {
+        Some(path) => {
+            std::fs::write(path, yaml).with_context(||format!("write scenario failed [{path:?}]"))?;
+            info!("wrote scenario [{path:?}], steps [{}]", scenario.steps.len());
+        },
+        None => print!("{yaml}"),
+    }
+    Ok(())
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "scenario", author, about = "run yaml call flows against MS", version)]
+pub struct CmdArgs {
+    #[clap(subcommand)]
+    cmd: ScenarioCmd,
+}
+
+#[derive(Parser, Debug)]
+enum ScenarioCmd {
+    /// run one scenario file, fails on first unmet expect
+    Run(RunArgs),
+
+    /// run scenario over codec × media_type × ice_type matrix in parallel
+    Sweep(SweepArgs),
+}
+
+#[derive(Parser, Debug)]
+pub struct RunArgs {
+    file: PathBuf,
+
+    #[clap(long = "cn-id", default_value = "5")]
+    cn_id: u32,
+
+    #[clap(long = "tenant", long_help = "label logs of the run with this team, instead of tenant of the file")]
+    tenant: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct SweepArgs {
+    file: PathBuf,
+
+    #[clap(long = "cn-id", long_help = "cn_id of first run, run n uses cn-id + n", default_value = "5")]
+    cn_id: u32,
+
+    #[clap(long = "repeat", long_help = "runs of each matrix combination", default_value = "1")]
+    repeat: usize,
+
+    #[clap(long = "parallel", long_help = "max runs at the same time", default_value = "8")]
+    parallel: usize,
+
+    #[clap(long = "codec", value_delimiter = ',', long_help = "override matrix codecs, e.g. 0,8")]
+    codec: Vec<u8>,
+
+    #[clap(long = "media-type", value_enum, value_delimiter = ',', long_help = "override matrix media types, e.g. audio-only,audio-video")]
+    media_type: Vec<MediaType>,
+
+    #[clap(long = "ice-type", value_enum, value_delimiter = ',', long_help = "override matrix ice types, e.g. simple,webrtc")]
+    ice_type: Vec<IceType>,
+
+    #[clap(long = "start-jitter-ms", long_help = "delay start of each run at random up to this, reproducible with --seed", default_value = "0")]
+    start_jitter_ms: u64,
+
+    #[clap(long = "report", long_help = "write json report of all runs")]
+    report: Option<PathBuf>,
+
+    #[clap(long = "tenant", long_help = "label logs of the runs and the report with this team, instead of tenant of the file")]
+    tenant: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "scenario-from-capture", author, about = "convert a capture (jsonl or vnrec) of rcn cli --capture into a scenario", version)]
+pub struct FromCaptureArgs {
+    capture: PathBuf,
+
+    #[clap(short = 'o', long = "output", long_help = "scenario yaml file, default to stdout")]
+    output: Option<PathBuf>,
+}
@@ -53,6 +53,12 @@ pub struct ActorBuilder<E: ActorHandler> {
     op_rx: mpsc::Receiver<Op<E>>,
 }
 
+impl<E: ActorHandler> Default for ActorBuilder<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<E: ActorHandler> ActorBuilder<E> {
     pub fn new() -> Self {
         let (op_tx, op_rx) = mpsc::channel(128);
@@ -318,14 +324,14 @@ where
     match op {
         Op::Shutdown => {
             info!("got shutdown");
-            return Ok(Action::Finished)
+            Ok(Action::Finished)
         },
         Op::Invoke(mut envelope) => {
             let _r = envelope.handle(entity).await;
-            return Ok(Action::None)
+            Ok(Action::None)
         }
         Op::Msg(msg) => {
-            return entity.handle_msg(msg).await
+            entity.handle_msg(msg).await
         },
     }
 }
@@ -2,6 +2,7 @@
 mod tokio_rt;
 pub use tokio_rt::*;
 
+#[allow(clippy::module_inception)]
 mod async_rt;
 // pub use async_rt::*;
 
@@ -0,0 +1,192 @@
+use std::{future::Future, sync::Arc, task::Poll, time::{Duration, Instant}};
+
+/// time source for timers, so tests can drive time manually
+#[async_trait::async_trait]
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> Instant;
+
+    async fn sleep_until(&self, deadline: Instant);
+
+    async fn sleep(&self, duration: Duration) {
+        let deadline = self.now() + duration;
+        self.sleep_until(deadline).await
+    }
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+/// tokio clock when runtime feature is on, async-io clock otherwise
+pub fn default_clock() -> SharedClock {
+    #[cfg(feature = "runtime")]
+    return Arc::new(TokioClock);
+
+    #[cfg(not(feature = "runtime"))]
+    return Arc::new(AsyncIoClock);
+}
+
+/// output of fut, None if deadline of clock passes first
+pub async fn timeout_at<F: Future>(clock: &dyn Clock, deadline: Instant, fut: F) -> Option<F::Output> {
+    let mut fut = std::pin::pin!(fut);
+    let mut sleep = clock.sleep_until(deadline);
+    std::future::poll_fn(|cx| {
+        if let Poll::Ready(output) = fut.as_mut().poll(cx) {
+            return Poll::Ready(Some(output))
+        }
+        sleep.as_mut().poll(cx).map(|()| None)
+    }).await
+}
+
+/// fires at once, then every period after the last tick, late ticks are not made up
+pub struct Ticker {
+    clock: SharedClock,
+    period: Duration,
+    next: Instant,
+}
+
+impl Ticker {
+    pub fn new(clock: SharedClock, period: Duration) -> Self {
+        let next = clock.now();
+        Self { clock, period, next }
+    }
+
+    /// cancel safe, a tick not waited to the end is still due
+    pub async fn tick(&mut self) -> Instant {
+        self.clock.sleep_until(self.next).await;
+        let now = self.clock.now();
+        self.next = now + self.period;
+        now
+    }
+}
+
+#[cfg(feature = "runtime")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioClock;
+
+#[cfg(feature = "runtime")]
+#[async_trait::async_trait]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        tokio::time::sleep_until(deadline.into()).await
+    }
+}
+
+#[cfg(feature = "smol")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AsyncIoClock;
+
+#[cfg(feature = "smol")]
+#[async_trait::async_trait]
+impl Clock for AsyncIoClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        async_io::Timer::at(deadline).await;
+    }
+}
+
+/// clock that only moves on advance()
+#[cfg(feature = "runtime")]
+pub struct MockClock {
+    base: Instant,
+    elapsed_tx: tokio::sync::watch::Sender<Duration>,
+}
+
+#[cfg(feature = "runtime")]
+impl MockClock {
+    pub fn new() -> Arc<Self> {
+        let (elapsed_tx, _rx) = tokio::sync::watch::channel(Duration::ZERO);
+        Arc::new(Self {
+            base: Instant::now(),
+            elapsed_tx,
+        })
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed_tx.send_modify(|elapsed| *elapsed += duration);
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed_tx.borrow()
+    }
+
+    /// run fut, advancing step each time it waits, so timers fire at once
+    /// however long they are. Time also flies while fut waits for anything
+    /// else, e.g. a socket, keep such waits out of it or give them ample time.
+    pub async fn drive<F: Future>(&self, step: Duration, fut: F) -> F::Output {
+        let mut fut = std::pin::pin!(fut);
+        std::future::poll_fn(|cx| {
+            let r = fut.as_mut().poll(cx);
+            if r.is_pending() {
+                self.advance(step);
+                cx.waker().wake_by_ref();
+            }
+            r
+        }).await
+    }
+}
+
+#[cfg(feature = "runtime")]
+#[async_trait::async_trait]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.base + self.elapsed()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        let mut rx = self.elapsed_tx.subscribe();
+        let base = self.base;
+        let _r = rx.wait_for(|elapsed| base + *elapsed >= deadline).await;
+    }
+}
+
+#[cfg(all(test, feature = "runtime"))]
+mod test {
+    use std::time::Duration;
+
+    use super::{Clock, MockClock, Ticker};
+
+    #[tokio::test]
+    async fn test_mock_clock() {
+        let clock = MockClock::new();
+        let start = clock.now();
+
+        let task = {
+            let clock = clock.clone();
+            tokio::spawn(async move {
+                clock.sleep(Duration::from_secs(3600)).await;
+                clock.now()
+            })
+        };
+
+        tokio::task::yield_now().await;
+        assert!(!task.is_finished());
+
+        clock.advance(Duration::from_secs(1800));
+        tokio::task::yield_now().await;
+        assert!(!task.is_finished());
+
+        clock.advance(Duration::from_secs(1800));
+        let end = task.await.unwrap();
+        assert_eq!(end - start, Duration::from_secs(3600));
+    }
+
+    #[tokio::test]
+    async fn test_ticker() {
+        let clock = MockClock::new();
+        let mut ticker = Ticker::new(clock.clone(), Duration::from_secs(10));
+        let start = ticker.tick().await;
+
+        let task = tokio::spawn(async move { ticker.tick().await });
+        tokio::task::yield_now().await;
+        assert!(!task.is_finished());
+
+        clock.advance(Duration::from_secs(25));
+        assert_eq!(task.await.unwrap() - start, Duration::from_secs(25));
+    }
+}
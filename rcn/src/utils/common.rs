@@ -1,11 +1,75 @@
-use std::{marker::PhantomData, fmt};
+use core::{fmt, marker::PhantomData, str::FromStr};
 
+use anyhow::{Result, Context};
 
-pub struct EnumNum<TN, TE>(TN, PhantomData<TE>);
+/// number type of an [`EnumNum`]
+pub trait EnumRepr: Copy + PartialEq + fmt::Display + fmt::LowerHex + TryFrom<u64> {
+    /// hex digits of the widest value
+    const HEX_WIDTH: usize;
 
-impl<TN: Copy, TE: TryFrom<TN>> EnumNum<TN, TE> {
+    /// every value, to find one by variant name
+    fn values() -> impl Iterator<Item = Self>;
+
+    fn from_str_radix(s: &str, radix: u32) -> Result<Self>;
+}
+
+macro_rules! impl_enum_repr {
+    ($($t:ty),*) => {$(
+        impl EnumRepr for $t {
+            const HEX_WIDTH: usize = 2 * core::mem::size_of::<$t>();
+
+            fn values() -> impl Iterator<Item = Self> {
+                <$t>::MIN..=<$t>::MAX
+            }
+
+            fn from_str_radix(s: &str, radix: u32) -> Result<Self> {
+                <$t>::from_str_radix(s, radix).map_err(|e| anyhow::anyhow!("{e}"))
+            }
+        }
+    )*};
+}
+
+impl_enum_repr!(u8, u16);
+
+/// enums numbered by an [`EnumNum`], keeps them apart from its numbers
+/// for PartialEq
+pub trait IsEnum {}
+
+/// how the number of an [`EnumNum`] is written
+pub trait Radix {
+    fn fmt_num<TN: EnumRepr>(num: TN, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+}
+
+/// e.g. 8
+pub struct Dec;
+
+/// e.g. 0x0008 for u16
+pub struct Hex;
+
+impl Radix for Dec {
+    fn fmt_num<TN: EnumRepr>(num: TN, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{num}")
+    }
+}
+
+impl Radix for Hex {
+    fn fmt_num<TN: EnumRepr>(num: TN, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{num:0w$x}", w = TN::HEX_WIDTH)
+    }
+}
+
+/// number on the wire and the enum it may be, unknown numbers kept.
+///
+/// Debug is `PLAY(0x0003)` or `Unknown(0x7777)`, Display the variant name
+/// or the number alone, both with the number in radix R. Parsed from a
+/// variant name (case insensitive) or a number, decimal or 0x hex.
+pub struct EnumNum<TN, TE, R = Dec>(TN, PhantomData<(TE, R)>);
+
+pub type EnumHexU16<TE> = EnumNum<u16, TE, Hex>;
+
+impl<TN: Copy, TE: TryFrom<TN>, R> EnumNum<TN, TE, R> {
     pub fn new(num: TN) -> Self {
-        Self(num, Default::default())
+        Self(num, PhantomData)
     }
 
     pub fn as_num(&self) -> TN {
@@ -17,72 +81,152 @@ impl<TN: Copy, TE: TryFrom<TN>> EnumNum<TN, TE> {
     }
 }
 
-impl<TN: Copy + fmt::Debug, TE: TryFrom<TN> + fmt::Debug> fmt::Debug for EnumNum<TN, TE> {
+impl<TN: Copy, TE, R> Clone for EnumNum<TN, TE, R> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<TN: Copy, TE, R> Copy for EnumNum<TN, TE, R> {}
+
+impl<TN: EnumRepr, TE: TryFrom<TN> + fmt::Debug, R: Radix> fmt::Debug for EnumNum<TN, TE, R> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        
-        let anum = self.as_num();
-        let atype = self.as_type();
+        match self.as_type() {
+            Some(v) => write!(f, "{v:?}(")?,
+            None => f.write_str("Unknown(")?,
+        }
+        R::fmt_num(self.0, f)?;
+        f.write_str(")")
+    }
+}
 
-        match atype {
-            Some(v) => write!(f, "{v:?}({anum:?})")?,
-            None => write!(f, "Unknown({anum:?})")?,
+impl<TN: EnumRepr, TE: TryFrom<TN> + fmt::Debug, R: Radix> fmt::Display for EnumNum<TN, TE, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.as_type() {
+            Some(v) => write!(f, "{v:?}"),
+            None => R::fmt_num(self.0, f),
         }
+    }
+}
 
-        // match atype {
-        //     Some(v) => f.write_fmt(format_args!("{v:?}({anum:?})"))?,
-        //     None => f.write_fmt(format_args!("Unknown({anum:?})"))?,
-        // }
-        
-        Ok(())
+/// same number, whatever the radix
+impl<TN: PartialEq, TE, R> PartialEq for EnumNum<TN, TE, R> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
     }
 }
 
-pub struct EnumHexU16<TE>(u16, PhantomData<TE>);
+impl<TN: Eq, TE, R> Eq for EnumNum<TN, TE, R> {}
+
+macro_rules! impl_eq_num {
+    ($($t:ty),*) => {$(
+        impl<TE, R> PartialEq<$t> for EnumNum<$t, TE, R> {
+            fn eq(&self, other: &$t) -> bool {
+                self.0 == *other
+            }
+        }
+    )*};
+}
+
+impl_eq_num!(u8, u16);
 
-impl<TE: TryFrom<u16>> EnumHexU16<TE> {
-    pub fn new(num: u16) -> Self {
-        Self(num, Default::default())
+/// variant of the number, never equal if unknown
+impl<TN: Copy, TE: TryFrom<TN> + PartialEq + IsEnum, R> PartialEq<TE> for EnumNum<TN, TE, R> {
+    fn eq(&self, other: &TE) -> bool {
+        self.as_type().is_some_and(|x| x == *other)
     }
+}
 
-    pub fn as_num(&self) -> u16 {
-        self.0
+impl<TN: EnumRepr, TE: TryFrom<TN> + fmt::Debug, R> FromStr for EnumNum<TN, TE, R> {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            return Ok(Self::new(TN::from_str_radix(hex, 16).with_context(||alloc::format!("invalid number [{s}]"))?))
+        }
+        if s.starts_with(|x: char| x.is_ascii_digit()) {
+            return Ok(Self::new(TN::from_str_radix(s, 10).with_context(||alloc::format!("invalid number [{s}]"))?))
+        }
+        TN::values()
+        .find(|x| TE::try_from(*x).is_ok_and(|v| alloc::format!("{v:?}").eq_ignore_ascii_case(s)))
+        .map(Self::new)
+        .with_context(||alloc::format!("unknown name [{s}]"))
     }
+}
 
-    pub fn as_type(&self) -> Option<TE> {
-        TE::try_from(self.0).ok()
+/// as Display, e.g. "PLAY" or "0x7777"
+#[cfg(any(feature = "runtime", feature = "smol"))]
+impl<TN: EnumRepr, TE: TryFrom<TN> + fmt::Debug, R: Radix> serde::Serialize for EnumNum<TN, TE, R> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
     }
 }
 
-impl<TE: TryFrom<u16> + fmt::Debug> fmt::Debug for EnumHexU16<TE> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        
-        let anum = self.as_num();
-        let atype = self.as_type();
+/// a name or number as a string, or a plain number
+#[cfg(any(feature = "runtime", feature = "smol"))]
+impl<'de, TN: EnumRepr, TE: TryFrom<TN> + fmt::Debug, R> serde::Deserialize<'de> for EnumNum<TN, TE, R> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> core::result::Result<Self, D::Error> {
+        struct Visitor<TN, TE, R>(PhantomData<(TN, TE, R)>);
+
+        impl<TN: EnumRepr, TE: TryFrom<TN> + fmt::Debug, R> serde::de::Visitor<'_> for Visitor<TN, TE, R> {
+            type Value = EnumNum<TN, TE, R>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a variant name or number")
+            }
 
-        match atype {
-            Some(v) => write!(f, "{v:?}(0x{anum:04x?})")?,
-            None => write!(f, "Unknown({anum:?})")?,
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> core::result::Result<Self::Value, E> {
+                v.parse().map_err(|e| E::custom(alloc::format!("{e:#}")))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> core::result::Result<Self::Value, E> {
+                TN::try_from(v).map(EnumNum::new).map_err(|_e| E::custom(alloc::format!("out of range [{v}]")))
+            }
         }
-        
-        Ok(())
+
+        deserializer.deserialize_any(Visitor(PhantomData))
     }
 }
 
-// // #[derive(Debug)]
-// struct Foo(pub i32);
-
-// impl fmt::Debug for Foo {
-//     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-//         let value = self.0;
-//         match value {
-//             100 => write!(f, "OneHundred({value})")?,
-//             _ => write!(f, "Unknown({value})")?,
-//         }
-//         Ok(())
-//     }
-// }
-
-// #[test]
-// fn test_foo() {
-//     println!("{:02X?}", Foo(100));
-// }
+#[cfg(test)]
+mod test {
+    use crate::vn_proto::{MCode, MCodeType, MediaCode, MediaType};
+
+    use super::{EnumNum, Hex};
+
+    #[test]
+    fn test_enum_num() {
+        let play = MCode::new(3);
+        assert_eq!((format!("{play:?}"), play.to_string()), ("PLAY(0x0003)".into(), "PLAY".into()));
+        let odd = MCode::new(0x7777);
+        assert_eq!((format!("{odd:?}"), odd.to_string()), ("Unknown(0x7777)".into(), "0x7777".into()));
+        let rtmp = MediaCode::new(8);
+        assert_eq!((format!("{rtmp:?}"), rtmp.to_string()), ("Rtmp(8)".into(), "Rtmp".into()));
+        let hex = EnumNum::<u8, MediaType, Hex>::new(200);
+        assert_eq!(format!("{hex:?}"), "Unknown(0xc8)");
+
+        assert!(play == 3_u16 && play == MCodeType::PLAY && play != MCodeType::PLAY_ACK);
+        assert!(odd == 0x7777_u16 && odd != MCodeType::PLAY);
+
+        for s in ["PLAY", "play", " 0x3", "3", "0X0003"] {
+            assert_eq!(s.parse::<MCode>().unwrap(), play, "{s}");
+        }
+        assert_eq!("0x7777".parse::<MCode>().unwrap(), odd);
+        assert_eq!("rtmp".parse::<MediaCode>().unwrap(), MediaType::Rtmp);
+        assert!("PLAYS".parse::<MCode>().is_err());
+        assert!("0x10000".parse::<MCode>().is_err());
+        assert!("256".parse::<MediaCode>().is_err());
+
+
+        // serde impls come with the features pulling in serde_json
+        #[cfg(any(feature = "runtime", feature = "smol"))]
+        {
+            let json = serde_json::to_string(&[play, odd]).unwrap();
+            assert_eq!(json, r#"["PLAY","0x7777"]"#);
+            let back: Vec<MCode> = serde_json::from_str(r#"["PLAY","0x7777",4]"#).unwrap();
+            assert_eq!(back, [play, odd, MCode::new(4)]);
+            assert!(serde_json::from_str::<MCode>("70000").is_err());
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! client side of the ctl socket of a running rcn, see `rcn ctl`
+
+use std::{
+    os::unix::net::UnixDatagram,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use anyhow::{Result, Context, bail};
+
+/// local sockets of requests at once, e.g. scenarios in parallel
+static NEXT_LOCAL: AtomicU64 = AtomicU64::new(0);
+
+/// send cmd to a ctl socket and wait for its reply
+pub fn request(path: &Path, cmd: &str, timeout: Duration) -> Result<String> {
+    let n = NEXT_LOCAL.fetch_add(1, Ordering::Relaxed);
+    let local = std::env::temp_dir().join(format!("rcn_ctl_{}_{n}", std::process::id()));
+    let _r = std::fs::remove_file(&local);
+    let socket = UnixDatagram::bind(&local).with_context(||format!("can't bind [{local:?}]"))?;
+    let r = (|| {
+        socket.set_read_timeout(Some(timeout))?;
+        socket.send_to(cmd.as_bytes(), path).with_context(||format!("send to ctl socket failed [{path:?}]"))?;
+        let mut buf = vec![0_u8; 65536];
+        let len = socket.recv(&mut buf).with_context(||format!("no reply from [{path:?}] in [{timeout:?}]"))?;
+        let reply = String::from_utf8_lossy(&buf[..len]).into_owned();
+        match reply.strip_prefix("error ") {
+            Some(e) => bail!("{e}"),
+            None => Ok(reply.strip_prefix("ok").unwrap_or(&reply).trim().to_string()),
+        }
+    })();
+    let _r = std::fs::remove_file(&local);
+    r
+}
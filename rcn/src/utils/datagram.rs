@@ -0,0 +1,83 @@
+use std::{io, path::{Path, PathBuf}, sync::atomic::{AtomicUsize, Ordering}};
+
+/// unix datagram socket of whichever runtime is in use
+#[async_trait::async_trait]
+pub trait Datagram: Send + Sync + 'static {
+    async fn send_to(&self, buf: &[u8], target: &Path) -> io::Result<usize>;
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Option<PathBuf>)>;
+}
+
+#[cfg(feature = "runtime")]
+#[async_trait::async_trait]
+impl Datagram for tokio::net::UnixDatagram {
+    async fn send_to(&self, buf: &[u8], target: &Path) -> io::Result<usize> {
+        tokio::net::UnixDatagram::send_to(self, buf, target).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Option<PathBuf>)> {
+        let (len, from) = tokio::net::UnixDatagram::recv_from(self, buf).await?;
+        Ok((len, from.as_pathname().map(|x|x.to_path_buf())))
+    }
+}
+
+/// for smol and other async-io based executors
+#[cfg(feature = "smol")]
+pub type AsyncIoDatagram = async_io::Async<std::os::unix::net::UnixDatagram>;
+
+#[cfg(feature = "smol")]
+#[async_trait::async_trait]
+impl Datagram for AsyncIoDatagram {
+    async fn send_to(&self, buf: &[u8], target: &Path) -> io::Result<usize> {
+        self.write_with(|x| x.send_to(buf, target)).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Option<PathBuf>)> {
+        let (len, from) = self.read_with(|x| x.recv_from(buf)).await?;
+        Ok((len, from.as_pathname().map(|x|x.to_path_buf())))
+    }
+}
+
+/// in-memory socket for benchmarks, recv_from hands out inbound round robin
+/// as if sent from `from`, sends are counted and dropped
+pub struct LoopDatagram {
+    inbound: Vec<Vec<u8>>,
+    from: PathBuf,
+    next: AtomicUsize,
+    sent: AtomicUsize,
+}
+
+impl LoopDatagram {
+    pub fn new(inbound: Vec<Vec<u8>>, from: PathBuf) -> Self {
+        Self { inbound, from, next: AtomicUsize::new(0), sent: AtomicUsize::new(0) }
+    }
+
+    /// datagrams handed out so far
+    pub fn received(&self) -> usize {
+        self.next.load(Ordering::Relaxed)
+    }
+
+    pub fn sent(&self) -> usize {
+        self.sent.load(Ordering::Relaxed)
+    }
+}
+
+#[async_trait::async_trait]
+impl Datagram for LoopDatagram {
+    async fn send_to(&self, buf: &[u8], _target: &Path) -> io::Result<usize> {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        Ok(buf.len())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Option<PathBuf>)> {
+        if self.inbound.is_empty() {
+            return std::future::pending().await
+        }
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        let data = &self.inbound[n % self.inbound.len()];
+        // cut like a real socket would
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok((len, Some(self.from.clone())))
+    }
+}
@@ -0,0 +1,120 @@
+use std::{collections::{BTreeMap, HashMap}, path::Path, time::{Duration, Instant, SystemTime}};
+
+use anyhow::{Result, Context, anyhow};
+use hdrhistogram::{Histogram, serialization::{V2DeflateSerializer, interval_log::{IntervalLogWriterBuilder, Tag}}};
+
+use crate::vn_proto::{MCode, MCodeType};
+
+/// 1us .. 1h with 3 significant digits
+const MAX_LATENCY_US: u64 = 3_600_000_000;
+
+/// request -> ACK latencies per request code, in microseconds
+pub struct LatencyRecorder {
+    pending: HashMap<(u32, u16), Instant>,
+    hists: BTreeMap<u16, Histogram<u64>>,
+    started: SystemTime,
+}
+
+impl Default for LatencyRecorder {
+    fn default() -> Self {
+        Self {
+            pending: Default::default(),
+            hists: Default::default(),
+            started: SystemTime::now(),
+        }
+    }
+}
+
+impl LatencyRecorder {
+    /// remember request sent, only codes having an ACK are tracked
+    pub fn on_request(&mut self, fsm_id: u32, code: u16, now: Instant) {
+        let Ok(code) = MCodeType::try_from(code) else { return };
+        if let Some(ack) = code.ack() {
+            self.pending.insert((fsm_id, ack.code()), now);
+        }
+    }
+
+    /// match ACK with pending request and record latency
+    pub fn on_response(&mut self, fsm_id: u32, code: u16, now: Instant) -> Option<Duration> {
+        let sent = self.pending.remove(&(fsm_id, code))?;
+        let request = MCodeType::try_from(code).ok()?.request()?;
+        let latency = now.saturating_duration_since(sent);
+
+        let hist = self.hists.entry(request.code())
+        .or_insert_with(|| Histogram::new_with_bounds(1, MAX_LATENCY_US, 3).expect("valid histogram bounds"));
+        hist.saturating_record((latency.as_micros() as u64).max(1));
+        Some(latency)
+    }
+
+    pub fn histogram(&self, request: MCodeType) -> Option<&Histogram<u64>> {
+        self.hists.get(&request.code())
+    }
+
+    /// one line per code: count, p50, p99, max in ms
+    pub fn summary(&self) -> Vec<String> {
+        self.hists.iter()
+        .map(|(code, h)| format!(
+            "{:?}: count {}, p50 {:.3}ms, p99 {:.3}ms, max {:.3}ms",
+            MCode::new(*code),
+            h.len(),
+            h.value_at_quantile(0.5) as f64 / 1000.0,
+            h.value_at_quantile(0.99) as f64 / 1000.0,
+            h.max() as f64 / 1000.0,
+        ))
+        .collect()
+    }
+
+    /// write dir/<CODE>.hlog per request code, HdrHistogram interval log
+    /// with values in milliseconds
+    pub fn write_hdr_dir(&self, dir: &Path) -> Result<usize> {
+        std::fs::create_dir_all(dir).with_context(||format!("create dir failed [{dir:?}]"))?;
+
+        let elapsed = SystemTime::now().duration_since(self.started).unwrap_or_default();
+        for (code, hist) in self.hists.iter() {
+            let name = match MCodeType::try_from(*code) {
+                Ok(v) => format!("{v:?}"),
+                Err(_) => format!("0x{code:04X}"),
+            };
+            let path = dir.join(format!("{name}.hlog"));
+            let mut file = std::fs::File::create(&path).with_context(||format!("create file failed [{path:?}]"))?;
+            let mut serializer = V2DeflateSerializer::new();
+            let mut writer = IntervalLogWriterBuilder::new()
+            .add_comment(&format!("rcn {name} request to ACK latency, us"))
+            .with_start_time(self.started)
+            .with_max_value_divisor(1000.0)
+            .begin_log_with(&mut file, &mut serializer)?;
+            writer.write_histogram(hist, Duration::ZERO, elapsed, Tag::new(&name))
+            .map_err(|e| anyhow!("write histogram failed [{path:?}], [{e:?}]"))?;
+        }
+        Ok(self.hists.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::vn_proto::MCodeType;
+
+    use super::LatencyRecorder;
+
+    #[test]
+    fn test_request_ack_latency() {
+        let mut recorder = LatencyRecorder::default();
+        let t0 = Instant::now();
+        recorder.on_request(5000001, MCodeType::PLAY.code(), t0);
+        assert!(recorder.on_response(5000002, MCodeType::PLAY_ACK.code(), t0).is_none());
+
+        let latency = recorder.on_response(5000001, MCodeType::PLAY_ACK.code(), t0 + Duration::from_millis(20)).unwrap();
+        assert_eq!(latency, Duration::from_millis(20));
+        assert!(recorder.on_response(5000001, MCodeType::PLAY_ACK.code(), t0).is_none());
+
+        let h = recorder.histogram(MCodeType::PLAY).unwrap();
+        assert_eq!(h.len(), 1);
+
+        let dir = std::env::temp_dir().join(format!("rcn_hdr_{}", std::process::id()));
+        assert_eq!(recorder.write_hdr_dir(&dir).unwrap(), 1);
+        assert!(dir.join("PLAY.hlog").is_file());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
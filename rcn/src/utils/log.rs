@@ -1,14 +1,33 @@
 
+use std::sync::Mutex;
+
+use anyhow::{Result, Context};
 use time::{UtcOffset, macros::format_description};
 
-use tracing::level_filters::LevelFilter;
-use tracing_subscriber::{EnvFilter, fmt::{time::OffsetTime, MakeWriter}};
+use tracing::{level_filters::LevelFilter, Subscriber};
+use tracing_subscriber::{EnvFilter, fmt::{time::OffsetTime, MakeWriter}, util::SubscriberInitExt};
+
+type Reload = Box<dyn Fn(EnvFilter) -> Result<()> + Send + Sync>;
+
+/// swaps the filter of the subscriber built by log_subscriber
+static RELOAD: Mutex<Option<Reload>> = Mutex::new(None);
+/// directives last set by set_log_level, empty if still the startup ones
+static LEVEL: Mutex<String> = Mutex::new(String::new());
 
-pub(crate) fn init_log() {
-    init_log2(env!("CARGO_PKG_NAME"), ||std::io::stdout())
+pub fn init_log() {
+    init_log2(env!("CARGO_PKG_NAME"), std::io::stdout)
 }
 
-pub(crate) fn init_log2<W2>(name: &str, w: W2) 
+pub fn init_log2<W2>(name: &str, w: W2) 
+where
+    W2: for<'writer> MakeWriter<'writer> + 'static + Send + Sync,
+{
+    log_subscriber(name, w).init();
+}
+
+/// subscriber of init_log2 without installing it, e.g. for set_default in tests,
+/// the last one built is what set_log_level changes
+pub fn log_subscriber<W2>(name: &str, w: W2) -> impl Subscriber + Send + Sync + 'static
 where
     W2: for<'writer> MakeWriter<'writer> + 'static + Send + Sync,
 {
@@ -16,7 +35,8 @@ where
     // https://time-rs.github.io/book/api/format-description.html
     let fmts = format_description!("[hour]:[minute]:[second].[subsecond digits:3]");
 
-    let offset = UtcOffset::current_local_offset().expect("should get local offset!");
+    // local offset is refused once other threads run (e.g. under cargo test), UTC then
+    let offset = UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC);
     let timer = OffsetTime::new(offset, fmts);
     
     let filter = if cfg!(debug_assertions) {
@@ -33,12 +53,70 @@ where
         .from_env_lossy()
     };
         
-    tracing_subscriber::fmt()
+    let builder = tracing_subscriber::fmt()
     .with_max_level(tracing::metadata::LevelFilter::DEBUG)
     .with_env_filter(filter)
     // .with_env_filter("rtun=debug,rserver=debug")
     .with_writer(w)
     .with_timer(timer)
     .with_target(false)
-    .init();
+    .with_filter_reloading();
+
+    let handle = builder.reload_handle();
+    *RELOAD.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(move |filter| handle.reload(filter).with_context(||"reload log filter failed")));
+    builder.finish()
+}
+
+/// span labelling events of a tenant's work, none without a tenant
+pub fn tenant_span(tenant: Option<&str>) -> tracing::Span {
+    match tenant {
+        Some(tenant) => tracing::info_span!("", tenant),
+        None => tracing::Span::none(),
+    }
+}
+
+/// replace log filter at runtime, e.g. `rcn=debug,vn_proto=trace`,
+/// bare module names are taken as modules of this crate
+pub fn set_log_level(directives: &str) -> Result<()> {
+    let expanded = expand_directives(directives);
+    let filter = EnvFilter::builder().parse(&expanded).with_context(||format!("invalid log level [{directives}]"))?;
+    {
+        let reload = RELOAD.lock().unwrap_or_else(|e| e.into_inner());
+        let reload = reload.as_ref().with_context(||"log not initialized")?;
+        reload(filter)?;
+    }
+    *LEVEL.lock().unwrap_or_else(|e| e.into_inner()) = expanded;
+    Ok(())
+}
+
+/// directives of last set_log_level, empty if never called
+pub fn log_level() -> String {
+    LEVEL.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// `vn_proto=trace` -> `rcn::vn_proto=trace`, events are targeted by module path
+pub fn expand_directives(directives: &str) -> String {
+    directives.split(',')
+    .map(|x| x.trim())
+    .filter(|x| !x.is_empty())
+    .map(|x| {
+        let local = ["vn_", "subcmd_", "utils", "cli_"].iter().any(|prefix| x.starts_with(prefix));
+        match local {
+            true => format!("{}::{x}", env!("CARGO_PKG_NAME")),
+            false => x.to_string(),
+        }
+    })
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+#[cfg(test)]
+mod test {
+    use super::expand_directives;
+
+    #[test]
+    fn test_expand_directives() {
+        assert_eq!(expand_directives("rcn=debug, vn_proto=trace,,utils::rtt=info"), "rcn=debug,rcn::vn_proto=trace,rcn::utils::rtt=info");
+        assert_eq!(expand_directives("warn"), "warn");
+    }
 }
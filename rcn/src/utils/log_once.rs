@@ -0,0 +1,15 @@
+//! logging of things that tend to repeat, e.g. one bad peer flooding.
+
+/// warn if `$n`, the count including this one, is 1 and log at debug
+/// otherwise: the first one tells, the rest quietly
+macro_rules! warn_first {
+    ($n:expr, $($arg:tt)+) => {
+        if $n == 1 {
+            tracing::warn!($($arg)+)
+        } else {
+            tracing::debug!($($arg)+)
+        }
+    };
+}
+
+pub(crate) use warn_first;
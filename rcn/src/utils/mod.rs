@@ -1,5 +1,38 @@
 
-pub mod log;
 pub mod common;
+
+#[cfg(feature = "runtime")]
+pub mod log;
+
+#[cfg(feature = "runtime")]
 pub mod actor;
+
+#[cfg(feature = "runtime")]
+pub mod ctl;
+
+#[cfg(feature = "runtime")]
 pub mod async_rt;
+
+#[cfg(any(feature = "runtime", feature = "smol"))]
+pub mod rate_limit;
+
+#[cfg(any(feature = "runtime", feature = "smol"))]
+pub mod clock;
+
+#[cfg(any(feature = "runtime", feature = "smol"))]
+pub mod datagram;
+
+#[cfg(any(feature = "runtime", feature = "smol"))]
+pub mod recv_buf;
+
+#[cfg(any(feature = "runtime", feature = "smol"))]
+pub mod latency;
+
+#[cfg(any(feature = "runtime", feature = "smol"))]
+pub mod rtt;
+
+#[cfg(any(feature = "runtime", feature = "smol"))]
+pub mod log_once;
+
+#[cfg(feature = "std")]
+pub mod rng;
@@ -0,0 +1,258 @@
+use std::{collections::{BTreeSet, HashMap}, str::FromStr, sync::{Mutex, MutexGuard}, time::{Duration, Instant}};
+
+use anyhow::{Result, Context, bail};
+
+use super::clock::{SharedClock, default_clock};
+
+/// slower rates wait longer than MAX_WAIT for a token
+pub const MIN_RATE: f64 = 0.001;
+
+/// longest wait try_acquire returns
+const MAX_WAIT: Duration = Duration::from_secs(3600);
+
+/// rate in tokens per second, burst in tokens
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateConfig {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+impl FromStr for RateConfig {
+    type Err = anyhow::Error;
+
+    /// "rate" or "rate:burst", burst defaults to rate
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, ':');
+        let rate = parts.next().with_context(||"empty rate")?.trim();
+        let rate: f64 = rate.parse().with_context(||format!("invalid rate [{rate}]"))?;
+        if !rate.is_finite() || rate < MIN_RATE {
+            bail!("rate must be a number from {MIN_RATE} on but [{rate}]")
+        }
+
+        let burst = match parts.next() {
+            Some(v) => v.trim().parse().with_context(||format!("invalid burst [{v}]"))?,
+            None => (rate.ceil() as u32).max(1),
+        };
+        if burst == 0 {
+            bail!("burst must be positive")
+        }
+
+        Ok(Self { rate, burst })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    config: RateConfig,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(config: RateConfig, now: Instant) -> Self {
+        Self {
+            config,
+            tokens: config.burst as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.config.rate).min(self.config.burst as f64);
+        self.last = now;
+    }
+
+    /// take one token, or return how long to wait before it's available
+    pub fn try_acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - self.tokens) / self.config.rate;
+            Err(Duration::try_from_secs_f64(wait).map_or(MAX_WAIT, |x| x.min(MAX_WAIT)))
+        }
+    }
+
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.config.burst as f64
+    }
+}
+
+/// global bucket plus one bucket per channel, shared by reference so
+/// a caller can wait for it outside an actor
+pub struct RateLimiter {
+    per_channel: Option<RateConfig>,
+    buckets: Mutex<Buckets>,
+    clock: SharedClock,
+}
+
+#[derive(Default)]
+struct Buckets {
+    global: Option<TokenBucket>,
+    /// bucket and last use of each channel
+    channels: HashMap<u32, (TokenBucket, Instant)>,
+    /// channels by last use, least recent first
+    idle: BTreeSet<(Instant, u32)>,
+}
+
+impl Buckets {
+    fn touch(&mut self, channel: u32, now: Instant) {
+        if let Some((_bucket, used)) = self.channels.get_mut(&channel) {
+            self.idle.remove(&(*used, channel));
+            *used = now;
+            self.idle.insert((now, channel));
+        }
+    }
+
+    /// make room for one more channel, forgetting the ones idle longest
+    fn evict(&mut self) {
+        while self.channels.len() >= MAX_CHANNELS {
+            let Some((_used, channel)) = self.idle.pop_first() else { break };
+            self.channels.remove(&channel);
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(global: Option<RateConfig>, per_channel: Option<RateConfig>) -> Self {
+        Self::with_clock(global, per_channel, default_clock())
+    }
+
+    pub fn with_clock(global: Option<RateConfig>, per_channel: Option<RateConfig>, clock: SharedClock) -> Self {
+        let now = clock.now();
+        Self {
+            per_channel,
+            buckets: Mutex::new(Buckets { global: global.map(|x|TokenBucket::new(x, now)), ..Default::default() }),
+            clock,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.per_channel.is_some() || self.lock().global.is_some()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Buckets> {
+        self.buckets.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// wait until both the global and the channel bucket allow one packet
+    pub async fn acquire(&self, channel: u32) {
+        loop {
+            let now = self.clock.now();
+            match self.try_acquire(channel, now) {
+                Ok(()) => return,
+                Err(wait) => self.clock.sleep_until(now + wait).await,
+            }
+        }
+    }
+
+    pub fn try_acquire(&self, channel: u32, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.lock();
+        let buckets = &mut *buckets;
+        if let Some(config) = self.per_channel {
+            if buckets.channels.contains_key(&channel) {
+                buckets.touch(channel, now);
+            } else {
+                buckets.evict();
+                buckets.channels.insert(channel, (TokenBucket::new(config, now), now));
+                buckets.idle.insert((now, channel));
+            }
+        }
+
+        // check both before consuming so a rejected packet doesn't burn the other bucket
+        if let Some((bucket, _used)) = buckets.channels.get_mut(&channel) {
+            bucket.refill(now);
+            if bucket.tokens < 1.0 {
+                return bucket.try_acquire(now);
+            }
+        }
+
+        if let Some(global) = &mut buckets.global {
+            global.try_acquire(now)?;
+        }
+
+        if let Some((bucket, _used)) = buckets.channels.get_mut(&channel) {
+            bucket.tokens -= 1.0;
+        }
+
+        Ok(())
+    }
+}
+
+/// channel buckets kept, the one idle longest goes first
+const MAX_CHANNELS: usize = 4096;
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{RateConfig, RateLimiter, TokenBucket, MAX_CHANNELS};
+
+    #[test]
+    fn test_token_bucket() {
+        let config: RateConfig = "10:2".parse().unwrap();
+        assert_eq!(config, RateConfig { rate: 10.0, burst: 2 });
+
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(config, now);
+        assert!(bucket.try_acquire(now).is_ok());
+        assert!(bucket.try_acquire(now).is_ok());
+
+        let wait = bucket.try_acquire(now).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(100));
+
+        assert!(bucket.try_acquire(now + Duration::from_millis(100)).is_ok());
+
+        for s in ["0", "-1", "1e-300", "inf", "NaN", "1:0", "x"] {
+            assert!(s.parse::<RateConfig>().is_err(), "{s}");
+        }
+        let mut bucket = TokenBucket::new(RateConfig { rate: 1e-300, burst: 1 }, now);
+        assert!(bucket.try_acquire(now).is_ok());
+        assert_eq!(bucket.try_acquire(now).unwrap_err(), super::MAX_WAIT);
+    }
+
+    #[test]
+    fn test_limiter_per_channel() {
+        let now = Instant::now();
+        let limiter = RateLimiter::new(
+            Some("100:3".parse().unwrap()),
+            Some("1:1".parse().unwrap()),
+        );
+
+        assert!(limiter.try_acquire(1, now).is_ok());
+        assert!(limiter.try_acquire(1, now).is_err());
+        assert!(limiter.try_acquire(2, now).is_ok());
+        assert!(limiter.try_acquire(3, now).is_ok());
+
+        // global burst exhausted
+        assert!(limiter.try_acquire(4, now).is_err());
+    }
+
+    #[test]
+    fn test_limiter_evicts_idle() {
+        let now = Instant::now();
+        let at = |ms: u64| now + Duration::from_millis(ms);
+        let limiter = RateLimiter::new(None, Some("1:1".parse().unwrap()));
+        assert!(limiter.try_acquire(0, at(0)).is_ok());
+        assert!(limiter.try_acquire(1, at(0)).is_ok());
+        // 0 used again, 1 is idle longest now
+        assert!(limiter.try_acquire(0, at(1)).is_err());
+        for n in 2..=MAX_CHANNELS as u32 {
+            assert!(limiter.try_acquire(n, at(2)).is_ok());
+        }
+        assert_eq!(limiter.lock().channels.len(), MAX_CHANNELS);
+
+        // a kept channel still owes its token, an evicted one starts over
+        assert!(limiter.try_acquire(0, at(3)).is_err());
+        assert!(limiter.try_acquire(1, at(3)).is_ok());
+    }
+}
@@ -0,0 +1,112 @@
+use tracing::error;
+
+pub const DEFAULT_RECV_BUF: usize = 1700;
+
+/// max unix datagram payload we ever want to hold
+pub const DEFAULT_RECV_BUF_MAX: usize = 65536;
+
+/// datagram receive buffer which grows when a datagram doesn't fit.
+///
+/// recv_from doesn't report MSG_TRUNC through the Datagram trait, so one
+/// spare byte is received into beyond capacity: a datagram of exactly
+/// capacity fits, one reaching the spare byte was truncated.
+#[derive(Debug)]
+pub struct RecvBuf {
+    /// capacity and the spare byte
+    buf: Vec<u8>,
+    max: usize,
+    truncated: u64,
+}
+
+impl Default for RecvBuf {
+    fn default() -> Self {
+        Self::new(DEFAULT_RECV_BUF, DEFAULT_RECV_BUF_MAX)
+    }
+}
+
+impl RecvBuf {
+    pub fn new(size: usize, max: usize) -> Self {
+        let size = size.max(1);
+        Self {
+            buf: vec![0; size + 1],
+            max: max.max(size),
+            truncated: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.len() - 1
+    }
+
+    /// capacity it may grow to
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// number of truncated datagrams so far
+    pub fn truncated(&self) -> u64 {
+        self.truncated
+    }
+
+    /// capacity and the spare byte, to receive into
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buf[..]
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..]
+    }
+
+    /// check received length, on truncation log it,
+    /// double the buffer (up to max) and return true
+    pub fn check_truncated(&mut self, recv_len: usize) -> bool {
+        let old = self.capacity();
+        if recv_len <= old {
+            return false
+        }
+
+        self.truncated += 1;
+        if old < self.max {
+            let new = old.saturating_mul(2).min(self.max);
+            self.buf.resize(new + 1, 0);
+            error!("datagram truncated at [{old}] bytes, recv buffer grown to [{new}], total truncated [{}]", self.truncated);
+        } else {
+            error!("datagram truncated at [{old}] bytes, recv buffer already at max, total truncated [{}]", self.truncated);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RecvBuf;
+
+    #[test]
+    fn test_grow_to_max() {
+        let mut buf = RecvBuf::new(1700, 5000);
+        assert_eq!(buf.as_mut_slice().len(), 1701);
+        assert!(!buf.check_truncated(1699));
+        // exact fit is a whole datagram
+        assert!(!buf.check_truncated(1700));
+        assert!(buf.check_truncated(1701));
+        assert_eq!(buf.capacity(), 3400);
+        assert!(buf.check_truncated(3401));
+        assert_eq!(buf.capacity(), 5000);
+        assert!(!buf.check_truncated(5000));
+        assert!(buf.check_truncated(5001));
+        assert_eq!(buf.capacity(), 5000);
+        assert_eq!(buf.truncated(), 3);
+    }
+
+    #[test]
+    fn test_exact_fit_datagram() {
+        let (a, b) = std::os::unix::net::UnixDatagram::pair().unwrap();
+        let mut buf = RecvBuf::new(1700, 1700);
+        for len in [1700, 1701] {
+            a.send(&vec![7_u8; len]).unwrap();
+            let recv_len = b.recv(buf.as_mut_slice()).unwrap();
+            assert_eq!(buf.check_truncated(recv_len), len > 1700, "{len}");
+        }
+        assert_eq!(buf.truncated(), 1);
+    }
+}
@@ -0,0 +1,95 @@
+use std::{sync::{Arc, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+
+/// seed from wall clock, for runs without --seed
+pub fn seed_from_time() -> u64 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_nanos() as u64).unwrap_or(0);
+    splitmix64(&mut (nanos ^ std::process::id() as u64))
+}
+
+/// deterministic random source, splitmix64.
+///
+/// Each subsystem takes its own stream with [`SimRng::fork`], so adding draws
+/// in one place doesn't change what another place gets from the same seed.
+/// Clones share the stream.
+#[derive(Debug, Clone)]
+pub struct SimRng {
+    seed: u64,
+    state: Arc<Mutex<u64>>,
+}
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            state: Arc::new(Mutex::new(seed)),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// independent stream derived from seed and name
+    pub fn fork(&self, name: &str) -> Self {
+        // fnv-1a of name mixed into seed
+        let mut hash = 0xcbf29ce484222325_u64;
+        for b in name.as_bytes() {
+            hash ^= *b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        let mut state = self.seed ^ hash;
+        Self::new(splitmix64(&mut state))
+    }
+
+    pub fn next_u64(&self) -> u64 {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        splitmix64(&mut state)
+    }
+
+    /// uniform in [low, high), low if range is empty
+    pub fn range(&self, low: u64, high: u64) -> u64 {
+        if high <= low {
+            return low
+        }
+        low + self.next_u64() % (high - low)
+    }
+
+    /// true with probability p
+    pub fn chance(&self, p: f64) -> bool {
+        let unit = (self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64;
+        unit < p
+    }
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use super::SimRng;
+
+    #[test]
+    fn test_rng_deterministic() {
+        let draws = |rng: &SimRng| (0..8).map(|_| rng.range(10, 20)).collect::<Vec<_>>();
+
+        let a = SimRng::new(42);
+        let b = SimRng::new(42);
+        assert_eq!(draws(&a), draws(&b));
+        assert!(draws(&a).iter().all(|x| (10..20).contains(x)));
+
+        // forks don't depend on draws of the parent
+        let ports = SimRng::new(42).fork("ports");
+        let parent = SimRng::new(42);
+        let _r = parent.next_u64();
+        assert_eq!(draws(&ports), draws(&parent.fork("ports")));
+        assert_ne!(draws(&SimRng::new(42).fork("ports")), draws(&SimRng::new(42).fork("fuzz")));
+
+        assert!(!SimRng::new(1).chance(0.0));
+        assert!(SimRng::new(1).chance(1.0));
+    }
+}
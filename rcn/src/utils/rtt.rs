@@ -0,0 +1,142 @@
+use std::{fmt, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+/// weight of a new sample in ewma, as srtt of TCP
+const EWMA_ALPHA: f64 = 0.125;
+
+/// payload of HEARTBEAT carrying sender's unix time in microseconds
+pub const HEARTBEAT_TIME_LEN: usize = 8;
+
+/// unix time of a HEARTBEAT payload, None if it carries none
+pub fn heartbeat_time(payload: &[u8]) -> Option<SystemTime> {
+    let us = u64::from_be_bytes(payload.get(..HEARTBEAT_TIME_LEN)?.try_into().ok()?);
+    Some(UNIX_EPOCH + Duration::from_micros(us))
+}
+
+/// HEARTBEAT payload carrying time
+pub fn heartbeat_time_payload(time: SystemTime) -> [u8; HEARTBEAT_TIME_LEN] {
+    let us = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+    us.to_be_bytes()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RttStats {
+    pub count: u64,
+    pub last: Option<Duration>,
+    pub ewma: Option<Duration>,
+    pub min: Option<Duration>,
+    pub max: Option<Duration>,
+    /// MS clock minus CN clock in microseconds, from the answer of lowest rtt,
+    /// None if MS heartbeats carry no time
+    pub clock_offset_us: Option<i64>,
+    /// heartbeats still unanswered when the next one was sent
+    pub timeouts: u64,
+    pub last_answer: Option<Instant>,
+}
+
+impl fmt::Display for RttStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |x: Option<Duration>| x.map(|x| format!("{:.3}ms", x.as_secs_f64() * 1000.0)).unwrap_or_else(|| "-".into());
+        write!(f, "rtt count [{}] last [{}] ewma [{}] min [{}] max [{}]", self.count, ms(self.last), ms(self.ewma), ms(self.min), ms(self.max))?;
+        if let Some(offset) = self.clock_offset_us {
+            write!(f, " clock offset [{:.3}ms]", offset as f64 / 1000.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// HEARTBEAT -> HEARTBEAT round trips of one MS.
+/// Only the last heartbeat sent is waited for, an answer without
+/// heartbeat pending is a heartbeat of MS and ignored
+#[derive(Debug, Default)]
+pub struct RttTracker {
+    pending: Option<(Instant, SystemTime)>,
+    stats: RttStats,
+}
+
+impl RttTracker {
+    pub fn on_sent(&mut self, now: Instant, wall: SystemTime) {
+        if self.pending.is_some() {
+            self.stats.timeouts += 1;
+        }
+        self.pending = Some((now, wall));
+    }
+
+    /// record rtt of answer, offset of MS clock estimated if payload has time
+    pub fn on_answer(&mut self, now: Instant, payload: &[u8]) -> Option<Duration> {
+        let (sent, sent_wall) = self.pending.take()?;
+        let rtt = now.saturating_duration_since(sent);
+        let s = &mut self.stats;
+        s.count += 1;
+        s.last = Some(rtt);
+        s.last_answer = Some(now);
+        s.ewma = Some(match s.ewma {
+            Some(ewma) => ewma.mul_f64(1.0 - EWMA_ALPHA) + rtt.mul_f64(EWMA_ALPHA),
+            None => rtt,
+        });
+        let is_min = s.min.is_none_or(|x| rtt <= x);
+        s.min = s.min.min(Some(rtt)).or(Some(rtt));
+        s.max = s.max.max(Some(rtt));
+
+        // MS stamped its answer half way through the round trip, as NTP.
+        // The least delayed sample is the least skewed by queuing
+        if let (true, Some(ms_time)) = (is_min || s.clock_offset_us.is_none(), heartbeat_time(payload)) {
+            let cn_time = sent_wall + rtt / 2;
+            let offset = match ms_time.duration_since(cn_time) {
+                Ok(d) => d.as_micros() as i64,
+                Err(e) => -(e.duration().as_micros() as i64),
+            };
+            s.clock_offset_us = Some(offset);
+        }
+        Some(rtt)
+    }
+
+    pub fn stats(&self) -> &RttStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant, SystemTime};
+
+    use super::{heartbeat_time, heartbeat_time_payload, RttTracker};
+
+    #[test]
+    fn test_rtt_and_clock_offset() {
+        let mut rtt = RttTracker::default();
+        let t0 = Instant::now();
+        // whole microseconds, as carried by heartbeats
+        let wall = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert!(rtt.on_answer(t0, &[]).is_none());
+
+        rtt.on_sent(t0, wall);
+        assert_eq!(rtt.on_answer(t0 + Duration::from_millis(8), &[]), Some(Duration::from_millis(8)));
+        assert_eq!(rtt.stats().clock_offset_us, None);
+
+        // MS clock 100ms ahead, answered 1ms after our send
+        rtt.on_sent(t0, wall);
+        let ms_time = wall + Duration::from_millis(101);
+        rtt.on_answer(t0 + Duration::from_millis(2), &heartbeat_time_payload(ms_time)).unwrap();
+        assert_eq!(rtt.stats().clock_offset_us, Some(100_000));
+
+        // slower sample doesn't replace the offset
+        rtt.on_sent(t0, wall);
+        rtt.on_answer(t0 + Duration::from_millis(16), &heartbeat_time_payload(wall)).unwrap();
+
+        let s = rtt.stats();
+        assert_eq!((s.count, s.min, s.max), (3, Some(Duration::from_millis(2)), Some(Duration::from_millis(16))));
+        assert_eq!(s.last, Some(Duration::from_millis(16)));
+        assert_eq!(s.clock_offset_us, Some(100_000));
+        assert_eq!(s.last_answer, Some(t0 + Duration::from_millis(16)));
+        // 8 -> 7.25 -> 8.34375
+        assert_eq!(s.ewma.unwrap().as_micros(), 8343);
+        assert!(s.to_string().contains("clock offset [100.000ms]"));
+
+        rtt.on_sent(t0, wall);
+        rtt.on_sent(t0, wall);
+        assert_eq!(rtt.stats().timeouts, 1);
+
+        assert!(heartbeat_time(&[1, 2]).is_none());
+        assert_eq!(heartbeat_time(&heartbeat_time_payload(SystemTime::UNIX_EPOCH + Duration::from_micros(7))), Some(SystemTime::UNIX_EPOCH + Duration::from_micros(7)));
+    }
+}
@@ -0,0 +1,132 @@
+//! allow-list of peers a socket takes datagrams from.
+//!
+//! Unix datagrams carry the sender's bound path, anything not on the list
+//! is dropped, or only counted and logged in [`AclMode::Warn`]. Senders
+//! without a bound path (unbound sockets) show up as [`UNNAMED`] and can be
+//! allowed by that name.
+
+use std::{collections::BTreeMap, fmt, path::{Path, PathBuf}};
+
+use tracing::{debug, warn};
+
+/// name of senders without a bound path
+pub const UNNAMED: &str = "<unnamed>";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum AclMode {
+    /// accept datagrams from anyone
+    #[default]
+    Off,
+    /// drop datagrams from peers not allowed
+    Enforce,
+    /// accept all but log and count peers not allowed
+    Warn,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AclStats {
+    pub accepted: u64,
+    /// dropped, or let through in warn mode
+    pub rejected: u64,
+    /// rejected count per sender
+    pub sources: BTreeMap<String, u64>,
+}
+
+impl fmt::Display for AclStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peer acl accepted [{}] rejected [{}]", self.accepted, self.rejected)?;
+        for (source, num) in self.sources.iter() {
+            write!(f, ", [{source}]: [{num}]")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PeerAcl {
+    mode: AclMode,
+    allowed: Vec<PathBuf>,
+    stats: AclStats,
+}
+
+impl PeerAcl {
+    pub fn new(mode: AclMode, allowed: Vec<PathBuf>) -> Self {
+        Self { mode, allowed, stats: AclStats::default() }
+    }
+
+    pub fn mode(&self) -> AclMode {
+        self.mode
+    }
+
+    pub fn allow(&mut self, path: PathBuf) {
+        if !self.allowed.contains(&path) {
+            self.allowed.push(path);
+        }
+    }
+
+    fn is_allowed(&self, from: Option<&Path>) -> bool {
+        match from {
+            Some(from) => self.allowed.iter().any(|x| x == from),
+            None => self.allowed.iter().any(|x| x.as_os_str() == UNNAMED),
+        }
+    }
+
+    /// true if a datagram from this sender is to be processed
+    pub fn check(&mut self, from: Option<&Path>) -> bool {
+        if self.mode == AclMode::Off || self.is_allowed(from) {
+            self.stats.accepted += 1;
+            return true
+        }
+
+        let source = from.map(|x| x.display().to_string()).unwrap_or_else(|| UNNAMED.into());
+        self.stats.rejected += 1;
+        let num = self.stats.sources.entry(source.clone()).or_default();
+        *num += 1;
+        let drop = self.mode == AclMode::Enforce;
+        // once per sender, then quietly
+        if *num == 1 {
+            warn!("datagram from unexpected peer [{source}], {}", if drop { "dropped" } else { "accepted (warn only)" });
+        } else {
+            debug!("datagram #{num} from unexpected peer [{source}]");
+        }
+        !drop
+    }
+
+    pub fn stats(&self) -> &AclStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{AclMode, PeerAcl, UNNAMED};
+
+    #[test]
+    fn test_peer_acl() {
+        let ms = Path::new("/cin/msvn");
+        let rogue = Path::new("/tmp/tool");
+
+        let mut acl = PeerAcl::new(AclMode::Enforce, vec![ms.into()]);
+        assert!(acl.check(Some(ms)));
+        assert!(!acl.check(Some(rogue)));
+        assert!(!acl.check(Some(rogue)));
+        assert!(!acl.check(None));
+        acl.allow(UNNAMED.into());
+        assert!(acl.check(None));
+        let s = acl.stats();
+        assert_eq!((s.accepted, s.rejected), (2, 3));
+        assert_eq!(s.sources.get("/tmp/tool"), Some(&2));
+        assert_eq!(s.to_string(), "peer acl accepted [2] rejected [3], [/tmp/tool]: [2], [<unnamed>]: [1]");
+
+        let mut acl = PeerAcl::new(AclMode::Warn, vec![ms.into()]);
+        assert!(acl.check(Some(rogue)));
+        assert_eq!(acl.stats().rejected, 1);
+
+        let mut acl = PeerAcl::default();
+        assert!(acl.check(Some(rogue)));
+        assert_eq!(acl.stats().rejected, 0);
+    }
+}
@@ -0,0 +1,227 @@
+//! sudden shifts in packet rates and ACK failures during long runs.
+//!
+//! Packets are counted per code in fixed windows. Once a few windows have
+//! made a baseline, a window whose rate or ACK failure ratio of a code moved
+//! by more than a factor from it is an [`Anomaly`], e.g. PLAY_ACK failing
+//! 10x as often as before. An anomaly is reported when it starts and when
+//! it is over, the baseline holds still in between so a lasting shift isn't
+//! learned as normal.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::vn_proto::{CodeName, MCodeType};
+
+/// weight of the latest window in the baseline
+const BASELINE_WEIGHT: f64 = 0.3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnomalyConfig {
+    pub window: Duration,
+    /// shift by this much or more either way is an anomaly
+    pub factor: f64,
+    /// windows making the baseline before any is judged
+    pub warmup: usize,
+    /// packets of a code in a window, now or at baseline, to judge its rate
+    pub min_count: u64,
+    /// failure ratio below this counts as this, so a first failure or two
+    /// after none isn't infinitely more
+    pub min_ratio: f64,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        Self { window: Duration::from_secs(10), factor: 10.0, warmup: 3, min_count: 20, min_ratio: 0.01 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum AnomalyKind {
+    /// packets per second
+    Rate,
+    /// ACKs with non zero result of all ACKs
+    Failures,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomaly {
+    pub code: u16,
+    pub kind: AnomalyKind,
+    pub baseline: f64,
+    pub value: f64,
+    /// false once back within the factor
+    pub active: bool,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self.kind {
+            AnomalyKind::Rate => "rate",
+            AnomalyKind::Failures => "failure ratio",
+        };
+        match self.active {
+            true => write!(
+                f, "[{}] {what} [{:.3}] -> [{:.3}], [{:.1}x]",
+                CodeName(self.code), self.baseline, self.value, self.value / self.baseline,
+            ),
+            false => write!(f, "[{}] {what} back to [{:.3}], baseline [{:.3}]", CodeName(self.code), self.value, self.baseline),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct WindowCount {
+    packets: u64,
+    failures: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Baseline {
+    rate: f64,
+    count: f64,
+    ratio: f64,
+}
+
+#[derive(Debug)]
+pub struct AnomalyDetector {
+    config: AnomalyConfig,
+    started: Instant,
+    counts: BTreeMap<u16, WindowCount>,
+    baselines: BTreeMap<u16, Baseline>,
+    windows: usize,
+    active: HashSet<(u16, AnomalyKind)>,
+}
+
+impl AnomalyDetector {
+    pub fn new(config: AnomalyConfig, now: Instant) -> Self {
+        Self { config, started: now, counts: BTreeMap::new(), baselines: BTreeMap::new(), windows: 0, active: HashSet::new() }
+    }
+
+    /// packet received, first byte of an ACK is its result
+    pub fn on_packet(&mut self, code: u16, payload: &[u8]) {
+        let count = self.counts.entry(code).or_default();
+        count.packets += 1;
+        let is_ack = MCodeType::try_from(code).is_ok_and(|x| x.request().is_some());
+        if is_ack && payload.first().is_some_and(|x| *x != 0) {
+            count.failures += 1;
+        }
+    }
+
+    /// anomalies started or over with the window ending by now, none
+    /// while it runs
+    pub fn tick(&mut self, now: Instant) -> Vec<Anomaly> {
+        let elapsed = now.saturating_duration_since(self.started);
+        if elapsed < self.config.window {
+            return Vec::new()
+        }
+        self.started = now;
+        let counts = std::mem::take(&mut self.counts);
+        self.windows += 1;
+        let judged = self.windows > self.config.warmup;
+
+        let codes: BTreeSet<u16> = counts.keys().chain(self.baselines.keys()).copied().collect();
+        let mut found = Vec::new();
+        for code in codes {
+            let count = counts.get(&code).copied().unwrap_or_default();
+            let rate = count.packets as f64 / elapsed.as_secs_f64();
+            let ratio = match count.packets {
+                0 => 0.0,
+                n => count.failures as f64 / n as f64,
+            };
+            let Some(baseline) = self.baselines.get(&code).copied() else {
+                self.baselines.insert(code, Baseline { rate, count: count.packets as f64, ratio });
+                continue
+            };
+
+            let min_count = self.config.min_count as f64;
+            let rate_shift = judged && (count.packets as f64).max(baseline.count) >= min_count
+                && shifted(baseline.rate, rate, self.config.factor);
+            let failure_shift = judged && count.packets >= self.config.min_count
+                && shifted(baseline.ratio.max(self.config.min_ratio), ratio.max(self.config.min_ratio), self.config.factor);
+            for (kind, shift, base, value) in [
+                (AnomalyKind::Rate, rate_shift, baseline.rate, rate),
+                (AnomalyKind::Failures, failure_shift, baseline.ratio.max(self.config.min_ratio), ratio),
+            ] {
+                let was = self.active.contains(&(code, kind));
+                if shift && !was {
+                    self.active.insert((code, kind));
+                    found.push(Anomaly { code, kind, baseline: base, value, active: true });
+                } else if !shift && was {
+                    self.active.remove(&(code, kind));
+                    found.push(Anomaly { code, kind, baseline: base, value, active: false });
+                }
+            }
+
+            if !self.active.iter().any(|x| x.0 == code) {
+                let w = BASELINE_WEIGHT;
+                self.baselines.insert(code, Baseline {
+                    rate: baseline.rate * (1.0 - w) + rate * w,
+                    count: baseline.count * (1.0 - w) + count.packets as f64 * w,
+                    ratio: baseline.ratio * (1.0 - w) + ratio * w,
+                });
+            }
+        }
+        found
+    }
+
+    /// anomalies going on
+    pub fn num_active(&self) -> usize {
+        self.active.len()
+    }
+}
+
+/// value off base by factor or more, either way
+fn shifted(base: f64, value: f64, factor: f64) -> bool {
+    match (base > 0.0, value > 0.0) {
+        (true, true) => value >= base * factor || value * factor <= base,
+        // from something to nothing or from nothing to something
+        (true, false) | (false, true) => true,
+        (false, false) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::vn_proto::MCodeType;
+
+    use super::{AnomalyConfig, AnomalyDetector, AnomalyKind};
+
+    #[test]
+    fn test_anomaly() {
+        let t0 = Instant::now();
+        let window = Duration::from_secs(10);
+        let mut detector = AnomalyDetector::new(AnomalyConfig { window, ..Default::default() }, t0);
+        let ack = MCodeType::PLAY_ACK.code();
+        let heartbeat = MCodeType::HEARTBEAT.code();
+        // failures of every window in 100 PLAY_ACKs, heartbeats per window
+        let windows = [(1, 10), (0, 10), (1, 10), (1, 10), (40, 10), (45, 10), (1, 10), (1, 0)];
+        let mut found = Vec::new();
+        for (n, (failures, heartbeats)) in windows.into_iter().enumerate() {
+            for i in 0..100 {
+                detector.on_packet(ack, &[if i < failures { 3 } else { 0 }]);
+            }
+            for _ in 0..heartbeats {
+                detector.on_packet(heartbeat, &[]);
+            }
+            assert!(detector.tick(t0 + window * n as u32 + window / 2).is_empty());
+            found.push(detector.tick(t0 + window * (n as u32 + 1)));
+        }
+
+        // warmup, then a jump reported once and once when over
+        assert!(found[..4].iter().all(|x| x.is_empty()), "{found:?}");
+        assert_eq!(found[4].len(), 1);
+        assert_eq!((found[4][0].code, found[4][0].kind, found[4][0].active), (ack, AnomalyKind::Failures, true));
+        assert!(found[4][0].to_string().starts_with("[PLAY_ACK] failure ratio [0.010] -> [0.400]"), "{}", found[4][0]);
+        assert!(found[5].is_empty());
+        assert_eq!(found[6].len(), 1);
+        assert!(!found[6][0].active);
+        // 10 heartbeats a window are too few to judge their rate
+        assert!(found[7].is_empty(), "{:?}", found[7]);
+        assert_eq!(detector.num_active(), 0);
+    }
+}
@@ -0,0 +1,271 @@
+//! post-call analysis of recorded audio: energy, silence, clipping and tones,
+//! to verify a prompt was actually heard rather than only acked.
+//!
+//! Audio is read from 16bit pcm wav or from G.711 rtp of a pcap (see vn_pcap).
+
+use std::{fmt, path::Path, time::Duration};
+
+use anyhow::{Result, Context, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    vn_media::{dtmf_digit, dtmf_grid, read_wav},
+    vn_pcap::read_udp,
+    vn_rtp::{depacketize, CLOCK_RATE},
+};
+
+/// frames quieter than this are silence
+pub const SILENCE_DBFS: f64 = -50.0;
+/// samples at or above this magnitude are clipped
+const CLIP_LEVEL: i32 = 32000;
+const FRAME: Duration = Duration::from_millis(20);
+const TONE_BLOCK: Duration = Duration::from_millis(100);
+/// share of frame energy in its dtmf row and column frequencies
+const DTMF_MIN_RATIO: f64 = 0.6;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AudioStats {
+    pub duration: Duration,
+    /// of the whole, -inf for digital silence
+    pub rms_dbfs: f64,
+    pub peak_dbfs: f64,
+    /// of 20ms frames below SILENCE_DBFS
+    pub silence_ratio: f64,
+    /// of samples
+    pub clipping_ratio: f64,
+}
+
+impl fmt::Display for AudioStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "duration [{:?}] rms [{:.1} dBFS] peak [{:.1} dBFS] silence [{:.0}%] clipping [{:.2}%]",
+            self.duration, self.rms_dbfs, self.peak_dbfs, self.silence_ratio * 100.0, self.clipping_ratio * 100.0,
+        )
+    }
+}
+
+fn dbfs(rms: f64) -> f64 {
+    20.0 * (rms / 32768.0).log10()
+}
+
+fn rms(samples: &[i16]) -> f64 {
+    if samples.is_empty() {
+        return 0.0
+    }
+    let sum: f64 = samples.iter().map(|x| *x as f64 * *x as f64).sum();
+    (sum / samples.len() as f64).sqrt()
+}
+
+pub fn analyze(samples: &[i16], sample_rate: u32) -> AudioStats {
+    if samples.is_empty() || sample_rate == 0 {
+        return AudioStats { rms_dbfs: f64::NEG_INFINITY, peak_dbfs: f64::NEG_INFINITY, ..Default::default() }
+    }
+    let frame_len = ((sample_rate as u128 * FRAME.as_millis() / 1000) as usize).max(1);
+    let frames = samples.chunks(frame_len).count();
+    let silent = samples.chunks(frame_len).filter(|x| dbfs(rms(x)) < SILENCE_DBFS).count();
+    let peak = samples.iter().map(|x| (*x as i32).abs()).max().unwrap_or(0);
+    let clipped = samples.iter().filter(|x| (**x as i32).abs() >= CLIP_LEVEL).count();
+    AudioStats {
+        duration: Duration::from_secs_f64(samples.len() as f64 / sample_rate as f64),
+        rms_dbfs: dbfs(rms(samples)),
+        peak_dbfs: dbfs(peak as f64),
+        silence_ratio: silent as f64 / frames as f64,
+        clipping_ratio: clipped as f64 / samples.len() as f64,
+    }
+}
+
+/// energy of block at freq, as sum of squares, by goertzel
+fn goertzel(block: &[i16], sample_rate: u32, freq: f64) -> f64 {
+    let coeff = 2.0 * (std::f64::consts::TAU * freq / sample_rate as f64).cos();
+    let (mut s1, mut s2) = (0.0, 0.0);
+    for x in block {
+        let s0 = *x as f64 + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s0;
+    }
+    2.0 * (s1 * s1 + s2 * s2 - coeff * s1 * s2) / block.len().max(1) as f64
+}
+
+/// share of the energy at freq, 1.0 for a pure tone, over blocks of
+/// TONE_BLOCK so that silence around the tone doesn't count
+pub fn tone_ratio(samples: &[i16], sample_rate: u32, freq: f64) -> f64 {
+    let energy: f64 = samples.iter().map(|x| *x as f64 * *x as f64).sum();
+    if energy == 0.0 || sample_rate == 0 {
+        return 0.0
+    }
+    let block_len = ((sample_rate as u128 * TONE_BLOCK.as_millis() / 1000) as usize).max(1);
+    let tone_energy: f64 = samples.chunks(block_len).map(|block| goertzel(block, sample_rate, freq)).sum();
+    (tone_energy / energy).min(1.0)
+}
+
+/// in-band dtmf digits, each held for at least two 20ms frames
+pub fn dtmf_digits(samples: &[i16], sample_rate: u32) -> String {
+    let frame_len = ((sample_rate as u128 * FRAME.as_millis() / 1000) as usize).max(1);
+    let (rows, cols) = dtmf_grid();
+    let strongest = |block: &[i16], freqs: &[f64; 4]| {
+        freqs.iter().map(|x| goertzel(block, sample_rate, *x)).enumerate()
+        .fold((0, 0.0), |max, (n, x)| if x > max.1 { (n, x) } else { max })
+    };
+
+    let mut digits = String::new();
+    // (candidate, frames held), None while no digit
+    let mut held: Option<(char, u32)> = None;
+    for frame in samples.chunks_exact(frame_len) {
+        let energy: f64 = frame.iter().map(|x| *x as f64 * *x as f64).sum();
+        let (row, row_energy) = strongest(frame, rows);
+        let (col, col_energy) = strongest(frame, cols);
+        let digit = (dbfs(rms(frame)) >= SILENCE_DBFS && row_energy + col_energy >= DTMF_MIN_RATIO * energy)
+        .then(|| dtmf_digit(row, col));
+        held = match (digit, held) {
+            (Some(digit), Some((last, n))) if digit == last => {
+                if n + 1 == 2 {
+                    digits.push(digit);
+                }
+                Some((digit, n + 1))
+            },
+            (Some(digit), _) => Some((digit, 1)),
+            (None, _) => None,
+        };
+    }
+    digits
+}
+
+/// (sample_rate, samples) of a .wav, or of the G.711 rtp in a .pcap
+pub fn load_audio(path: &Path) -> Result<(u32, Vec<i16>)> {
+    match path.extension().and_then(|x| x.to_str()) {
+        Some("wav") => read_wav(path),
+        Some("pcap") => {
+            let data = std::fs::read(path).with_context(||format!("read pcap failed [{path:?}]"))?;
+            let records = read_udp(&data).with_context(||format!("invalid pcap [{path:?}]"))?;
+            Ok((CLOCK_RATE, depacketize(records.iter().map(|x| &x.payload[..]))))
+        },
+        _ => bail!("unknown audio file [{path:?}], expect .wav or .pcap"),
+    }
+}
+
+/// what recorded audio should look like, unset limits aren't checked
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioExpect {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_rms_dbfs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_silence_ratio: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_clipping_ratio: Option<f64>,
+    /// a tone of this frequency is to be present
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tone_hz: Option<f64>,
+    /// share of energy the tone has at least, 0.5 if None
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_tone_ratio: Option<f64>,
+    /// in-band dtmf digits heard, in order
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dtmf: Option<String>,
+}
+
+impl AudioExpect {
+    /// unmet expectations, empty if all met
+    pub fn check(&self, samples: &[i16], sample_rate: u32) -> Vec<String> {
+        let stats = analyze(samples, sample_rate);
+        let mut problems = Vec::new();
+        if let Some(ms) = self.min_duration_ms {
+            if stats.duration < Duration::from_millis(ms) {
+                problems.push(format!("duration [{:?}] < [{ms}ms]", stats.duration));
+            }
+        }
+        if let Some(min) = self.min_rms_dbfs {
+            if stats.rms_dbfs.is_nan() || stats.rms_dbfs < min {
+                problems.push(format!("rms [{:.1} dBFS] < [{min} dBFS]", stats.rms_dbfs));
+            }
+        }
+        if let Some(max) = self.max_silence_ratio {
+            if stats.silence_ratio > max {
+                problems.push(format!("silence ratio [{:.2}] > [{max}]", stats.silence_ratio));
+            }
+        }
+        if let Some(max) = self.max_clipping_ratio {
+            if stats.clipping_ratio > max {
+                problems.push(format!("clipping ratio [{:.4}] > [{max}]", stats.clipping_ratio));
+            }
+        }
+        if let Some(freq) = self.tone_hz {
+            let min = self.min_tone_ratio.unwrap_or(0.5);
+            let ratio = tone_ratio(samples, sample_rate, freq);
+            if ratio < min {
+                problems.push(format!("tone [{freq}Hz] ratio [{ratio:.2}] < [{min}]"));
+            }
+        }
+        if let Some(expected) = &self.dtmf {
+            let digits = dtmf_digits(samples, sample_rate);
+            if !digits.eq_ignore_ascii_case(expected) {
+                problems.push(format!("dtmf [{digits}] != [{expected}]"));
+            }
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{vn_media::ToneSpec, vn_rtp::{depacketize, RtpSender, PT_PCMU}};
+
+    use super::{analyze, dtmf_digits, tone_ratio, AudioExpect};
+
+    fn tone(freq: f64, amplitude: f64, num: usize) -> Vec<i16> {
+        let step = std::f64::consts::TAU * freq / 8000.0;
+        (0..num).map(|n| ((n as f64 * step).sin() * amplitude) as i16).collect()
+    }
+
+    #[test]
+    fn test_analyze_audio() {
+        // 300ms of tone then 100ms of silence, through PCMU and back
+        let mut samples = tone(440.0, 8000.0, 2400);
+        samples.extend([0; 800]);
+        let packets = RtpSender::new(PT_PCMU, 1).packetize(&samples);
+        let samples = depacketize(packets.iter().map(|x| &x[..]));
+
+        let stats = analyze(&samples, 8000);
+        assert_eq!(stats.duration, Duration::from_millis(400));
+        assert!((stats.silence_ratio - 0.25).abs() < 0.01, "{stats}");
+        assert!((stats.peak_dbfs - -12.3).abs() < 0.5, "{stats}");
+        assert_eq!(stats.clipping_ratio, 0.0);
+        assert!(tone_ratio(&samples, 8000, 440.0) > 0.9);
+        assert!(tone_ratio(&samples, 8000, 1000.0) < 0.1);
+
+        let expect = AudioExpect { min_duration_ms: Some(300), max_silence_ratio: Some(0.3), tone_hz: Some(440.0), ..Default::default() };
+        assert!(expect.check(&samples, 8000).is_empty());
+        let expect = AudioExpect { min_rms_dbfs: Some(-10.0), tone_hz: Some(1000.0), max_silence_ratio: Some(0.1), ..Default::default() };
+        assert_eq!(expect.check(&samples, 8000).len(), 3);
+
+        let silence = analyze(&[0; 160], 8000);
+        assert_eq!(silence.silence_ratio, 1.0);
+        assert_eq!(silence.rms_dbfs, f64::NEG_INFINITY);
+        let clipped = analyze(&tone(440.0, 40000.0, 800), 8000);
+        assert!(clipped.clipping_ratio > 0.1);
+        assert!(analyze(&[], 8000).to_string().contains("silence [0%]"));
+    }
+
+    #[test]
+    fn test_dtmf_digits() {
+        let spec = ToneSpec::dtmf("123A456B789C*0#D");
+        let packets = RtpSender::new(PT_PCMU, 1).packetize(&spec.synthesize(8000));
+        let samples = depacketize(packets.iter().map(|x| &x[..]));
+        assert_eq!(dtmf_digits(&samples, 8000), "123A456B789C*0#D");
+
+        // repeated digits need the gap, too short ones are dropped
+        let spec = ToneSpec { tone: Duration::from_millis(60), gap: Duration::from_millis(40), ..ToneSpec::dtmf("1155") };
+        assert_eq!(dtmf_digits(&spec.synthesize(8000), 8000), "1155");
+        let spec = ToneSpec { tone: Duration::from_millis(20), ..ToneSpec::dtmf("9") };
+        assert_eq!(dtmf_digits(&spec.synthesize(8000), 8000), "");
+        assert_eq!(dtmf_digits(&tone(440.0, 8000.0, 8000), 8000), "");
+
+        let expect = AudioExpect { dtmf: Some("115".into()), ..Default::default() };
+        let spec = ToneSpec { tone: Duration::from_millis(60), gap: Duration::from_millis(40), ..ToneSpec::dtmf("1155") };
+        assert_eq!(expect.check(&spec.synthesize(8000), 8000), ["dtmf [1155] != [115]"]);
+    }
+}
@@ -0,0 +1,254 @@
+//! sizes of packets, strings and tag lists per code across a capture.
+//!
+//! For sizing MS buffers and deciding what needs fragmentation: each code
+//! gets a distribution of its packet size, of the strings it carries
+//! (filenames of PLAY, webrtc lines of REQUESTCHANNEL, codec names of
+//! REGISTER, RESFROMTAG text) and of its tag count. A maximum at or above
+//! [`AuditLimits::near_pct`] of its limit is a [`Finding`].
+
+use std::{collections::BTreeMap, fmt::Write as _};
+
+use serde::Serialize;
+
+use crate::{
+    utils::recv_buf::DEFAULT_RECV_BUF,
+    vn_capture::CaptureRecord,
+    vn_proto::{CodeName, MCodeType, PacketRef, PlayRef, RegisterRef, RequestChannelRef, ResFromTagRef, TagIter},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditLimits {
+    /// bytes of a whole packet, header included
+    pub packet: usize,
+    /// bytes of a string field without its NUL
+    pub string: usize,
+    /// tags of a packet, counts are one byte where sent
+    pub tags: usize,
+    /// percent of a limit flagged as near it
+    pub near_pct: usize,
+}
+
+impl Default for AuditLimits {
+    fn default() -> Self {
+        Self { packet: DEFAULT_RECV_BUF, string: 255, tags: u8::MAX as usize, near_pct: 80 }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Distribution {
+    pub count: usize,
+    pub min: usize,
+    pub p50: usize,
+    pub p95: usize,
+    pub max: usize,
+}
+
+impl Distribution {
+    fn of(mut values: Vec<usize>) -> Self {
+        values.sort_unstable();
+        let at = |q: f64| values[((values.len() - 1) as f64 * q).round() as usize];
+        match values.is_empty() {
+            true => Self::default(),
+            false => Self { count: values.len(), min: values[0], p50: at(0.5), p95: at(0.95), max: values[values.len() - 1] },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CodeAudit {
+    pub code: String,
+    pub size: Distribution,
+    /// by field name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub strings: BTreeMap<String, Distribution>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Distribution>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Finding {
+    pub code: String,
+    /// size, a string field name or tags
+    pub what: String,
+    pub max: usize,
+    pub limit: usize,
+    /// packets at or over the limit
+    pub over: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct AuditReport {
+    pub packets: usize,
+    /// records that don't parse
+    pub invalid: usize,
+    pub codes: Vec<CodeAudit>,
+    pub findings: Vec<Finding>,
+}
+
+#[derive(Default)]
+struct Samples {
+    sizes: Vec<usize>,
+    strings: BTreeMap<&'static str, Vec<usize>>,
+    tags: Vec<usize>,
+}
+
+/// strings of a payload by field name, lengths in bytes
+fn strings_of(code: MCodeType, payload: &[u8]) -> Vec<(&'static str, usize)> {
+    match code {
+        MCodeType::PLAY => PlayRef::parse_from(payload).map(|x| {
+            x.files().filter_map(|x| x.ok()).map(|x| ("filename", x.filename().data().len())).collect()
+        }).unwrap_or_default(),
+        MCodeType::REQUESTCHANNEL => RequestChannelRef::parse_from(payload).map(|x| {
+            x.webrtc_strs().map(|x| ("webrtc", x.data().len())).collect()
+        }).unwrap_or_default(),
+        MCodeType::REGISTER => RegisterRef::parse_from(payload).map(|x| {
+            let info = &x.media_info;
+            info.audio_codecs.iter().chain(info.video_codecs.iter()).chain(info.fax_codecs.iter())
+            .map(|x| ("mapstr", x.map_str_data().len()))
+            .collect()
+        }).unwrap_or_default(),
+        MCodeType::RESFROMTAG => ResFromTagRef::parse_from(payload).map(|x| vec![("value", x.value().len())]).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// tags of codes carrying a tag list
+fn tags_of(code: MCodeType, payload: &[u8]) -> Option<usize> {
+    match code {
+        MCodeType::CNISUP => Some(TagIter::new(payload).count()),
+        MCodeType::PLAY => PlayRef::parse_from(payload).ok().map(|_| TagIter::new(&payload[16..]).count()),
+        // MEDIAINFO and the tags after it
+        MCodeType::REGISTER => RegisterRef::parse_from(payload).ok().map(|x| 1 + x.tags.count()),
+        MCodeType::OPENRTPCONNECT => payload.first().map(|x| *x as usize),
+        _ => None,
+    }
+}
+
+pub fn audit(records: &[CaptureRecord], limits: &AuditLimits) -> AuditReport {
+    let mut report = AuditReport::default();
+    let mut samples: BTreeMap<u16, Samples> = BTreeMap::new();
+    for record in records {
+        let Some(data) = record.data().ok() else {
+            report.invalid += 1;
+            continue
+        };
+        let Ok(packet) = PacketRef::parse_from(&data[..]) else {
+            report.invalid += 1;
+            continue
+        };
+        report.packets += 1;
+        let entry = samples.entry(packet.code()).or_default();
+        entry.sizes.push(data.len());
+        let Ok(code) = MCodeType::try_from(packet.code()) else { continue };
+        for (name, len) in strings_of(code, packet.payload()) {
+            entry.strings.entry(name).or_default().push(len);
+        }
+        if let Some(num) = tags_of(code, packet.payload()) {
+            entry.tags.push(num);
+        }
+    }
+
+    for (code, samples) in samples {
+        let name = CodeName(code).to_string();
+        let mut check = |what: &str, values: &[usize], limit: usize| {
+            let max = values.iter().copied().max().unwrap_or(0);
+            if max * 100 >= limit * limits.near_pct {
+                let over = values.iter().filter(|x| **x >= limit).count();
+                report.findings.push(Finding { code: name.clone(), what: what.to_string(), max, limit, over });
+            }
+        };
+        check("size", &samples.sizes, limits.packet);
+        for (field, values) in samples.strings.iter() {
+            check(field, values, limits.string);
+        }
+        check("tags", &samples.tags, limits.tags);
+
+        report.codes.push(CodeAudit {
+            code: name,
+            size: Distribution::of(samples.sizes),
+            strings: samples.strings.into_iter().map(|(k, v)| (k.to_string(), Distribution::of(v))).collect(),
+            tags: (!samples.tags.is_empty()).then(|| Distribution::of(samples.tags)),
+        });
+    }
+    report
+}
+
+/// one line per code and measure, then findings
+pub fn render_audit(report: &AuditReport) -> String {
+    let mut table = vec![["code", "measure", "count", "min", "p50", "p95", "max"].map(String::from).to_vec()];
+    for code in report.codes.iter() {
+        let measures = std::iter::once(("size".to_string(), &code.size))
+        .chain(code.strings.iter().map(|(k, v)| (k.clone(), v)))
+        .chain(code.tags.iter().map(|x| ("tags".to_string(), x)));
+        for (measure, d) in measures {
+            table.push(vec![
+                code.code.clone(), measure, d.count.to_string(), d.min.to_string(),
+                d.p50.to_string(), d.p95.to_string(), d.max.to_string(),
+            ]);
+        }
+    }
+
+    let widths: Vec<usize> = (0..table[0].len()).map(|i| table.iter().map(|x| x[i].len()).max().unwrap_or(0)).collect();
+    let mut out = String::new();
+    for row in table.iter() {
+        let cells: Vec<String> = row.iter().zip(widths.iter()).map(|(cell, w)| format!("{cell:<w$}")).collect();
+        out.push_str(cells.join("  ").trim_end());
+        out.push('\n');
+    }
+    let _r = writeln!(out, "packets [{}] invalid [{}] findings [{}]", report.packets, report.invalid, report.findings.len());
+    for x in report.findings.iter() {
+        let _r = writeln!(out, "near limit [{}] {} max [{}] of [{}], at or over [{}]", x.code, x.what, x.max, x.limit, x.over);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        vn_capture::{CaptureDir, CaptureRecord},
+        vn_proto::{Filename, Header, MCodeType, Play},
+    };
+
+    use super::{audit, render_audit, AuditLimits};
+
+    fn record(code: MCodeType, payload: &[u8]) -> CaptureRecord {
+        let header = Header { code: code.code(), fsm_id: 5000001, sn: 1, key: 0 };
+        let mut data = Vec::new();
+        header.write_to2(&mut data, payload);
+        CaptureRecord::new(Duration::ZERO, CaptureDir::CnToMs, None, &data)
+    }
+
+    #[test]
+    fn test_audit() {
+        let mut records = Vec::new();
+        for len in [10, 20, 230] {
+            let file = Filename { format: 0, filename: format!("file://cc/{}", "a".repeat(len)) };
+            let mut payload = Vec::new();
+            Play { files: vec![file.clone(), file], ..Default::default() }.write_to(&mut payload);
+            records.push(record(MCodeType::PLAY, &payload));
+        }
+        records.push(record(MCodeType::HEARTBEAT, &[]));
+        records.push(CaptureRecord { hex: "0102".into(), ..record(MCodeType::HEARTBEAT, &[]) });
+
+        let report = audit(&records, &AuditLimits::default());
+        assert_eq!((report.packets, report.invalid), (4, 1));
+        let play = report.codes.iter().find(|x| x.code == "PLAY").unwrap();
+        let filename = &play.strings["filename"];
+        assert_eq!((filename.count, filename.min, filename.max), (6, 20, 240));
+        assert_eq!(play.tags.as_ref().map(|x| x.max), Some(2));
+        assert_eq!(report.codes.iter().find(|x| x.code == "HEARTBEAT").unwrap().size.max, 12);
+
+        // 240 of 255 is near, not over
+        assert_eq!(report.findings.len(), 1);
+        assert_eq!((report.findings[0].what.as_str(), report.findings[0].over), ("filename", 0));
+        let report = audit(&records, &AuditLimits { packet: 300, string: 240, ..Default::default() });
+        let over: Vec<(&str, usize)> = report.findings.iter().map(|x| (x.what.as_str(), x.over)).collect();
+        assert_eq!(over, [("size", 1), ("filename", 2)]);
+
+        let text = render_audit(&report);
+        assert!(text.contains("near limit [PLAY] filename max [240] of [240], at or over [2]"), "{text}");
+        assert!(text.lines().any(|x| x.starts_with("PLAY       filename  6")), "{text}");
+    }
+}
@@ -0,0 +1,109 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use alloc::vec::Vec;
+
+/// sign/verify hook for a trailer appended after the whole datagram
+pub trait PacketAuth: Send + Sync {
+    fn trailer_len(&self) -> usize;
+
+    /// append trailer for data to buf
+    fn sign(&self, data: &[u8], buf: &mut Vec<u8>);
+
+    fn verify(&self, data: &[u8], trailer: &[u8]) -> bool;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthStatus {
+    /// no auth configured
+    None,
+    Verified,
+    Mismatch,
+    /// datagram shorter than trailer
+    Missing,
+}
+
+/// split datagram into (body, trailer status)
+pub fn split_trailer<'a>(auth: Option<&dyn PacketAuth>, datagram: &'a [u8]) -> (&'a [u8], AuthStatus) {
+    let auth = match auth {
+        Some(v) => v,
+        None => return (datagram, AuthStatus::None),
+    };
+
+    let trailer_len = auth.trailer_len();
+    if datagram.len() < trailer_len {
+        return (datagram, AuthStatus::Missing)
+    }
+
+    let (body, trailer) = datagram.split_at(datagram.len() - trailer_len);
+    if auth.verify(body, trailer) {
+        (body, AuthStatus::Verified)
+    } else {
+        (body, AuthStatus::Mismatch)
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub struct HmacSha256Auth {
+    key: Vec<u8>,
+}
+
+impl HmacSha256Auth {
+    pub const TRAILER_LEN: usize = 32;
+
+    pub fn new(key: &[u8]) -> Self {
+        Self { key: key.to_vec() }
+    }
+
+    fn mac(&self, data: &[u8]) -> HmacSha256 {
+        // hmac accepts keys of any length
+        let mut mac = HmacSha256::new_from_slice(&self.key[..]).unwrap_or_else(|_|unreachable!());
+        mac.update(data);
+        mac
+    }
+}
+
+impl PacketAuth for HmacSha256Auth {
+    fn trailer_len(&self) -> usize {
+        Self::TRAILER_LEN
+    }
+
+    fn sign(&self, data: &[u8], buf: &mut Vec<u8>) {
+        let tag = self.mac(data).finalize().into_bytes();
+        buf.extend_from_slice(&tag[..]);
+    }
+
+    fn verify(&self, data: &[u8], trailer: &[u8]) -> bool {
+        self.mac(data).verify_slice(trailer).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split_trailer, AuthStatus, HmacSha256Auth, PacketAuth};
+
+    #[test]
+    fn test_hmac_trailer() {
+        let auth = HmacSha256Auth::new(b"secret");
+        let body = b"\x00\x0a\xff\xff\x00\x4c\x4b\x40\x00\x00\x00\x01";
+
+        let mut datagram = body.to_vec();
+        auth.sign(body, &mut datagram);
+        assert_eq!(datagram.len(), body.len() + HmacSha256Auth::TRAILER_LEN);
+
+        let (r, status) = split_trailer(Some(&auth), &datagram[..]);
+        assert_eq!(status, AuthStatus::Verified);
+        assert_eq!(r, body);
+
+        datagram[3] ^= 1;
+        let (_r, status) = split_trailer(Some(&auth), &datagram[..]);
+        assert_eq!(status, AuthStatus::Mismatch);
+
+        let (_r, status) = split_trailer(Some(&HmacSha256Auth::new(b"other")), &datagram[..]);
+        assert_eq!(status, AuthStatus::Mismatch);
+
+        let (_r, status) = split_trailer(Some(&auth), &body[..]);
+        assert_eq!(status, AuthStatus::Missing);
+    }
+}
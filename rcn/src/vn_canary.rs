@@ -0,0 +1,345 @@
+//! periodic probes of a MS checked against latency budgets.
+//!
+//! Every interval the canary sends HEARTBEAT and a REQUESTCHANNEL /
+//! RELEASECHANNEL pair. Latencies of the last `window` rounds are checked
+//! against each [`Slo`], a breach is logged as an alert line with
+//! `slo`, `observed_ms`, `limit_ms` and `samples` fields.
+
+use std::{collections::HashMap, fmt, path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::{Result, Context, bail};
+use hdrhistogram::Histogram;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+use crate::{
+    utils::datagram::Datagram,
+    vn_cdr::CdrWriter,
+    vn_channels::{ChannelRegistry, EndReason},
+    vn_codec::{negotiate_register, CallProfile},
+    vn_proto::{MCodeType, MediaType, RequestChannel},
+    vn_session::CnSession,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SloStat {
+    /// e.g. 0.99 of p99
+    Quantile(f64),
+    Max,
+}
+
+/// latency budget of a request, e.g. `REQUESTCHANNEL_ACK.p99<50ms` or `heartbeat.rtt<10ms`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Slo {
+    /// HEARTBEAT or a request having an ACK
+    pub request: MCodeType,
+    pub stat: SloStat,
+    pub limit: Duration,
+}
+
+impl Slo {
+    /// None if no samples
+    pub fn observed(&self, hist: &Histogram<u64>) -> Option<Duration> {
+        if hist.is_empty() {
+            return None
+        }
+        let us = match self.stat {
+            SloStat::Quantile(q) => hist.value_at_quantile(q),
+            SloStat::Max => hist.max(),
+        };
+        Some(Duration::from_micros(us))
+    }
+}
+
+impl FromStr for Slo {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (left, limit) = s.split_once('<').with_context(||format!("invalid slo [{s}], expect <code>.<stat><<limit>"))?;
+        let (code, stat) = left.rsplit_once('.').with_context(||format!("invalid slo [{s}], no stat"))?;
+
+        let request = if code.eq_ignore_ascii_case("heartbeat") {
+            MCodeType::HEARTBEAT
+        } else {
+            let code = MCodeType::from_name(code).with_context(||format!("unknown code [{code}] of slo [{s}]"))?;
+            match (code.request(), code.ack()) {
+                (Some(request), _) => request,
+                (None, Some(_ack)) => code,
+                _ => bail!("no ACK of [{code:?}] of slo [{s}]"),
+            }
+        };
+
+        let stat = match stat {
+            "max" | "rtt" => SloStat::Max,
+            _ => {
+                let digits = stat.strip_prefix('p').with_context(||format!("unknown stat [{stat}], expect pNN, max or rtt"))?;
+                let n: u32 = digits.parse().with_context(||format!("invalid quantile [{stat}]"))?;
+                // p99 -> 0.99, p999 -> 0.999
+                let q = n as f64 / 10_u32.pow(digits.len() as u32) as f64;
+                if !(q > 0.0 && q < 1.0) {
+                    bail!("invalid quantile [{stat}]")
+                }
+                SloStat::Quantile(q)
+            },
+        };
+
+        Ok(Self { request, stat, limit: parse_duration(limit.trim())? })
+    }
+}
+
+impl fmt::Display for Slo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.request.ack() {
+            Some(ack) => format!("{ack:?}"),
+            None => "heartbeat".into(),
+        };
+        match self.stat {
+            SloStat::Quantile(q) => write!(f, "{name}.p{}<{:?}", (q * 1000.0).round() / 10.0, self.limit),
+            SloStat::Max => write!(f, "{name}.max<{:?}", self.limit),
+        }
+    }
+}
+
+/// "50ms", "500us", "1s"
+pub(crate) fn parse_duration(s: &str) -> Result<Duration> {
+    let (num, unit) = s.find(|c: char| !c.is_ascii_digit() && c != '.')
+    .map(|pos| s.split_at(pos))
+    .with_context(||format!("no unit of [{s}], expect us, ms or s"))?;
+    let num: f64 = num.parse().with_context(||format!("invalid duration [{s}]"))?;
+    let secs = match unit {
+        "us" => num / 1e6,
+        "ms" => num / 1e3,
+        "s" => num,
+        _ => bail!("unknown unit of [{s}], expect us, ms or s"),
+    };
+    Ok(Duration::from_secs_f64(secs))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breach {
+    pub slo: Slo,
+    pub observed: Duration,
+    pub samples: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct CanaryConfig {
+    pub interval: Duration,
+    /// a probe not answered within this counts as taking this long
+    pub timeout: Duration,
+    /// rounds of probes per check
+    pub window: u32,
+    pub slos: Vec<Slo>,
+    /// stop after this many rounds, run forever if None
+    pub rounds: Option<u64>,
+    /// CDR of each probe channel appended here, see vn_cdr
+    pub cdr: Option<PathBuf>,
+    /// probe channels published here, see vn_events
+    #[cfg(feature = "events")]
+    pub events: Option<crate::vn_events::EventPublisher>,
+    /// REQUESTCHANNEL of each probe
+    pub request: RequestChannel,
+    /// codecs of the probe negotiated against the accepted REGISTER, None sends request as is
+    pub profile: Option<CallProfile>,
+}
+
+impl Default for CanaryConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(1),
+            window: 10,
+            slos: Vec::new(),
+            rounds: None,
+            cdr: None,
+            #[cfg(feature = "events")]
+            events: None,
+            request: RequestChannel { media_type: MediaType::AudioOnly as u8, webrtc: vec!["".into()], ..Default::default() },
+            profile: None,
+        }
+    }
+}
+
+/// latencies of current window by request code
+#[derive(Default)]
+struct Window {
+    hists: HashMap<u16, Histogram<u64>>,
+}
+
+impl Window {
+    fn record(&mut self, request: MCodeType, latency: Duration) {
+        let hist = self.hists.entry(request.code())
+        .or_insert_with(|| Histogram::new_with_bounds(1, 3_600_000_000, 3).expect("valid histogram bounds"));
+        hist.saturating_record((latency.as_micros() as u64).max(1));
+    }
+
+    fn check(&self, slos: &[Slo]) -> Vec<Breach> {
+        slos.iter().filter_map(|slo| {
+            let hist = self.hists.get(&slo.request.code())?;
+            let observed = slo.observed(hist)?;
+            (observed >= slo.limit).then(|| Breach { slo: slo.clone(), observed, samples: hist.len() })
+        })
+        .collect()
+    }
+}
+
+/// probe registered session until a window breaches an SLO or rounds are done,
+/// returns breaches of that window
+pub async fn run_canary<S: Datagram>(session: &mut CnSession<S>, config: &CanaryConfig) -> Result<Vec<Breach>> {
+    let mut ticker = tokio::time::interval(config.interval);
+    let mut window = Window::default();
+    let mut channels = ChannelRegistry::default();
+    channels.set_tenant(session.tenant().map(String::from));
+    if let Some(path) = &config.cdr {
+        channels.set_cdr(Some(CdrWriter::create(path)?));
+    }
+    #[cfg(feature = "events")]
+    channels.set_events(config.events.clone());
+    if let Some(info) = session.register_info() {
+        channels.on_register(&session.ms_path().display().to_string(), info);
+    }
+    let mut round = 0_u64;
+    loop {
+        ticker.tick().await;
+        round += 1;
+
+        let base = session.base_fsm_id();
+        let (rtt, _answer) = probe(session, MCodeType::HEARTBEAT, base, &[], MCodeType::HEARTBEAT, config.timeout).await?;
+        window.record(MCodeType::HEARTBEAT, rtt);
+
+        // by the allocator, so a state file keeps a restart off fsm_ids the MS still holds
+        let fsm_id = session.allocate_fsm_id()?;
+        let mut req = config.request.clone();
+        if let Some(profile) = &config.profile {
+            let register = session.register().with_context(||"canary session not registered")?;
+            negotiate_register(register, profile)?.apply_to(&mut req);
+        }
+        let mut payload = Vec::new();
+        req.write_to(&mut payload);
+        channels.on_requested(fsm_id, 0, Instant::now().into_std());
+        channels.on_packet(fsm_id, MCodeType::REQUESTCHANNEL.code(), &payload);
+        let (latency, answer) = probe(session, MCodeType::REQUESTCHANNEL, fsm_id, &payload, MCodeType::REQUESTCHANNEL_ACK, config.timeout).await?;
+        window.record(MCodeType::REQUESTCHANNEL, latency);
+        if let Some(answer) = answer {
+            channels.on_packet(fsm_id, MCodeType::REQUESTCHANNEL_ACK.code(), &answer);
+        }
+        session.send_request(MCodeType::RELEASECHANNEL, fsm_id, &[]).await?;
+        session.release_fsm_id(fsm_id);
+        channels.end(fsm_id, EndReason::Released, Instant::now().into_std());
+        debug!("canary round [{round}], heartbeat [{rtt:?}], channel [{latency:?}]");
+
+        if round.is_multiple_of(config.window.max(1) as u64) {
+            let breaches = window.check(&config.slos);
+            for b in breaches.iter() {
+                warn!(
+                    slo = %b.slo,
+                    observed_ms = b.observed.as_secs_f64() * 1000.0,
+                    limit_ms = b.slo.limit.as_secs_f64() * 1000.0,
+                    samples = b.samples,
+                    "alert: latency budget breached",
+                );
+            }
+            if !breaches.is_empty() {
+                return Ok(breaches)
+            }
+            window = Window::default();
+        }
+
+        if config.rounds.is_some_and(|x| round >= x) {
+            return Ok(Vec::new())
+        }
+    }
+}
+
+/// send request and wait for answer of fsm_id, timeout and no payload
+/// if not answered
+async fn probe<S: Datagram>(
+    session: &mut CnSession<S>,
+    code: MCodeType,
+    fsm_id: u32,
+    payload: &[u8],
+    answer: MCodeType,
+    timeout: Duration,
+) -> Result<(Duration, Option<Vec<u8>>)> {
+    let start = Instant::now();
+    session.send_request(code, fsm_id, payload).await?;
+    let r = tokio::time::timeout(timeout, async {
+        loop {
+            let packet = session.recv_packet().await?;
+            if packet.code() == answer.code() && packet.fsm_id() == fsm_id {
+                return anyhow::Ok(packet.payload().to_vec())
+            }
+        }
+    }).await;
+    match r {
+        Ok(r) => r.map(|payload| (start.elapsed(), Some(payload))),
+        Err(_e) => {
+            warn!("canary {code:?} of fsm_id [{fsm_id}] not answered in [{timeout:?}]");
+            Ok((timeout, None))
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        vn_ms_sim::{MsSim, MsSimConfig},
+        vn_proto::MCodeType,
+        vn_session::CnSession,
+    };
+
+    use super::{run_canary, CanaryConfig, Slo, SloStat};
+
+    #[tokio::test]
+    async fn test_canary_slo() {
+        let slo: Slo = "REQUESTCHANNEL_ACK.p99<50ms".parse().unwrap();
+        assert_eq!(slo, Slo { request: MCodeType::REQUESTCHANNEL, stat: SloStat::Quantile(0.99), limit: Duration::from_millis(50) });
+        let slo: Slo = "heartbeat.rtt<500us".parse().unwrap();
+        assert_eq!((slo.request, slo.stat, slo.limit), (MCodeType::HEARTBEAT, SloStat::Max, Duration::from_micros(500)));
+        assert_eq!("PLAY.p999<1s".parse::<Slo>().unwrap().stat, SloStat::Quantile(0.999));
+        assert_eq!("PLAY.p99<1s".parse::<Slo>().unwrap().to_string(), "PLAY_ACK.p99<1s");
+        assert_eq!(slo.to_string(), "heartbeat.max<500µs");
+        assert!("RELEASECHANNEL.p99<1s".parse::<Slo>().is_err());
+        assert!("heartbeat.p99<10".parse::<Slo>().is_err());
+
+        let dir = std::env::temp_dir().join(format!("rcn_canary_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut sim = MsSim::bind(&dir, MsSimConfig::default()).await.unwrap();
+        let task = tokio::spawn(async move { sim.run().await });
+
+        let state_path = dir.join("cn5.state");
+        let mut session = CnSession::bind(&dir, 5).await.unwrap();
+        session.set_state_file(&state_path).unwrap();
+        session.handshake().await.unwrap();
+        session.accept_register().await.unwrap();
+
+        let mut config = CanaryConfig {
+            interval: Duration::from_millis(5),
+            window: 2,
+            slos: vec!["heartbeat.rtt<1s".parse().unwrap()],
+            rounds: Some(4),
+            ..Default::default()
+        };
+        assert!(run_canary(&mut session, &config).await.unwrap().is_empty());
+
+        config.slos.push("REQUESTCHANNEL_ACK.p50<1us".parse().unwrap());
+        let breaches = run_canary(&mut session, &config).await.unwrap();
+        assert_eq!(breaches.len(), 1);
+        assert_eq!(breaches[0].slo.request, MCodeType::REQUESTCHANNEL);
+        assert_eq!(breaches[0].samples, 2);
+
+        // a restarted canary goes on after the 6 channels of rounds before
+        let heartbeats = session.state().sns[&5000000];
+        session.save_state().unwrap();
+        drop(session);
+        let mut session = CnSession::bind(&dir, 5).await.unwrap();
+        session.set_state_file(&state_path).unwrap();
+        assert_eq!(session.allocate_fsm_id().unwrap(), 5000007);
+        assert_eq!(session.next_sn(5000000), heartbeats + crate::vn_cn_state::SN_SKIP + 1);
+
+        task.abort();
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,382 @@
+//! packets seen on a VN link, one json object per line
+//!
+//! ```text
+//! {"ts_us":0,"dir":"cn_to_ms","socket":"/tmp/cin/mscn1","hex":"00140003000f4240..."}
+//! {"ts_us":1830,"dir":"ms_to_cn","socket":"/tmp/cin/msvn","hex":"00140004000f4240..."}
+//! ```
+//!
+//! `ts_us` counts from the start of capture on a monotonic clock,
+//! `socket` is the path of the sending socket when known.
+//! Packets are recorded after reassembly and decompression.
+//!
+//! Long captures use the binary `.vnrec` container instead, all integers big endian:
+//!
+//! ```text
+//! file:   magic "VNRC" | version u16 | reserved u16 | record*
+//! record: ts_us u64 | dir u8 | socket_len u16 | length u32 | socket | packet
+//! ```
+//!
+//! [`CaptureWriter::create`] picks the format by extension,
+//! [`read_capture`] detects it by magic.
+//! [`CaptureWriter::create_chunked`] streams chunks to a directory or bucket instead.
+//! A [`RecordTap`] gets each record as written, e.g. to stream them live.
+
+use std::{fmt::Write as _, fs::File, io::{BufWriter, Write}, path::Path, sync::Arc, time::{Duration, Instant}};
+
+use anyhow::{Result, Context, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::vn_redact::mask_packet;
+
+#[cfg(feature = "runtime")]
+use crate::vn_storage::{ChunkConfig, ChunkWriter, Storage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureDir {
+    CnToMs,
+    MsToCn,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaptureRecord {
+    pub ts_us: u64,
+    pub dir: CaptureDir,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket: Option<String>,
+    /// whole packet, header included
+    pub hex: String,
+}
+
+impl CaptureRecord {
+    /// record of data with redacted fields masked, see vn_redact
+    pub fn new(ts: Duration, dir: CaptureDir, socket: Option<String>, data: &[u8]) -> Self {
+        let mut data = data.to_vec();
+        mask_packet(&mut data);
+        Self { ts_us: ts.as_micros() as u64, dir, socket, hex: to_hex(&data) }
+    }
+
+    pub fn data(&self) -> Result<Vec<u8>> {
+        parse_hex(&self.hex)
+    }
+
+    pub fn ts(&self) -> Duration {
+        Duration::from_micros(self.ts_us)
+    }
+
+    /// copy with redacted fields masked, see vn_redact
+    pub fn redacted(&self) -> Result<Self> {
+        let mut data = self.data()?;
+        if mask_packet(&mut data) == 0 {
+            return Ok(self.clone())
+        }
+        Ok(Self { hex: to_hex(&data), ..self.clone() })
+    }
+}
+
+pub const VNREC_MAGIC: &[u8; 4] = b"VNRC";
+pub const VNREC_VERSION: u16 = 1;
+const VNREC_HEADER_LEN: usize = 8;
+const VNREC_RECORD_HEADER_LEN: usize = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureFormat {
+    Jsonl,
+    Vnrec,
+}
+
+impl CaptureFormat {
+    /// `.vnrec` is binary, anything else jsonl
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("vnrec") => Self::Vnrec,
+            _ => Self::Jsonl,
+        }
+    }
+}
+
+/// gets a copy of every record written
+pub type RecordTap = Arc<dyn Fn(&CaptureRecord) + Send + Sync>;
+
+/// next called after first, e.g. a gRPC hub and a ctl tail
+pub fn join_taps(first: Option<RecordTap>, next: RecordTap) -> RecordTap {
+    match first {
+        Some(first) => Arc::new(move |record: &CaptureRecord| {
+            first(record);
+            next(record);
+        }),
+        None => next,
+    }
+}
+
+pub struct CaptureWriter {
+    out: Box<dyn Write + Send>,
+    format: CaptureFormat,
+    started: Instant,
+    /// sending socket of cn_to_ms and ms_to_cn
+    origins: [Option<String>; 2],
+    tap: Option<RecordTap>,
+}
+
+impl CaptureWriter {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self {
+            out,
+            format: CaptureFormat::Jsonl,
+            started: Instant::now(),
+            origins: Default::default(),
+            tap: None,
+        }
+    }
+
+    /// binary writer, file header is written here
+    pub fn new_vnrec(mut out: Box<dyn Write + Send>) -> Result<Self> {
+        write_vnrec_header(&mut out)?;
+        Ok(Self {
+            format: CaptureFormat::Vnrec,
+            ..Self::new(out)
+        })
+    }
+
+    /// socket path recorded with packets of dir
+    pub fn set_origin(&mut self, dir: CaptureDir, path: &Path) {
+        self.origins[dir as usize] = Some(path.to_string_lossy().into_owned());
+    }
+
+    pub fn set_tap(&mut self, tap: Option<RecordTap>) {
+        self.tap = tap;
+    }
+
+    /// tap called after those set before
+    pub fn add_tap(&mut self, tap: RecordTap) {
+        self.tap = Some(join_taps(self.tap.take(), tap));
+    }
+
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(||format!("create capture failed [{path:?}]"))?;
+        let out = Box::new(BufWriter::new(file));
+        match CaptureFormat::from_path(path) {
+            CaptureFormat::Jsonl => Ok(Self::new(out)),
+            CaptureFormat::Vnrec => Self::new_vnrec(out),
+        }
+    }
+
+    /// chunks of about config.bytes put into storage as they fill, format
+    /// by extension of name, see vn_storage
+    #[cfg(feature = "runtime")]
+    pub fn create_chunked(storage: Box<dyn Storage>, name: &str, config: ChunkConfig) -> Result<Self> {
+        let mut out = ChunkWriter::new(storage, name, config);
+        match CaptureFormat::from_path(Path::new(name)) {
+            CaptureFormat::Jsonl => Ok(Self::new(Box::new(out))),
+            CaptureFormat::Vnrec => {
+                let mut header = Vec::new();
+                write_vnrec_header(&mut header)?;
+                out.set_header(&header);
+                Ok(Self { format: CaptureFormat::Vnrec, ..Self::new(Box::new(out)) })
+            },
+        }
+    }
+
+    /// redacted fields are masked, see vn_redact
+    pub fn write(&mut self, dir: CaptureDir, data: &[u8]) -> Result<()> {
+        let record = CaptureRecord::new(self.started.elapsed(), dir, self.origins[dir as usize].clone(), data);
+        self.write_record(&record)
+    }
+
+    pub fn write_record(&mut self, record: &CaptureRecord) -> Result<()> {
+        match self.format {
+            CaptureFormat::Jsonl => {
+                serde_json::to_writer(&mut self.out, record)?;
+                self.out.write_all(b"\n")?;
+            },
+            CaptureFormat::Vnrec => write_vnrec_record(&mut self.out, record)?,
+        }
+        self.out.flush()?;
+        if let Some(tap) = &self.tap {
+            tap(record);
+        }
+        Ok(())
+    }
+}
+
+fn write_vnrec_header<W: Write>(out: &mut W) -> Result<()> {
+    out.write_all(VNREC_MAGIC)?;
+    out.write_all(&VNREC_VERSION.to_be_bytes())?;
+    out.write_all(&0_u16.to_be_bytes())?;
+    Ok(())
+}
+
+fn write_vnrec_record<W: Write>(out: &mut W, record: &CaptureRecord) -> Result<()> {
+    let socket = record.socket.as_deref().unwrap_or("").as_bytes();
+    let data = record.data()?;
+    let socket_len = u16::try_from(socket.len()).with_context(||"too long socket path")?;
+    let len = u32::try_from(data.len()).with_context(||"too large packet")?;
+
+    out.write_all(&record.ts_us.to_be_bytes())?;
+    out.write_all(&[record.dir as u8])?;
+    out.write_all(&socket_len.to_be_bytes())?;
+    out.write_all(&len.to_be_bytes())?;
+    out.write_all(socket)?;
+    out.write_all(&data)?;
+    Ok(())
+}
+
+/// all records to out in format
+pub fn write_capture<W: Write>(mut out: W, format: CaptureFormat, records: &[CaptureRecord]) -> Result<()> {
+    if format == CaptureFormat::Vnrec {
+        write_vnrec_header(&mut out)?;
+    }
+    for record in records {
+        let record = &record.redacted()?;
+        match format {
+            CaptureFormat::Jsonl => {
+                serde_json::to_writer(&mut out, record)?;
+                out.write_all(b"\n")?;
+            },
+            CaptureFormat::Vnrec => write_vnrec_record(&mut out, record)?,
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+pub fn is_vnrec(data: &[u8]) -> bool {
+    data.starts_with(VNREC_MAGIC)
+}
+
+pub fn parse_vnrec(data: &[u8]) -> Result<Vec<CaptureRecord>> {
+    if data.len() < VNREC_HEADER_LEN || !is_vnrec(data) {
+        bail!("not a vnrec capture")
+    }
+    let version = u16::from_be_bytes([data[4], data[5]]);
+    if version != VNREC_VERSION {
+        bail!("unsupported vnrec version [{version}]")
+    }
+
+    let mut records = Vec::new();
+    let mut buf = &data[VNREC_HEADER_LEN..];
+    while !buf.is_empty() {
+        let n = records.len();
+        if buf.len() < VNREC_RECORD_HEADER_LEN {
+            bail!("truncated vnrec record #{n} header, remains [{}]", buf.len())
+        }
+        let ts_us = u64::from_be_bytes(buf[0..8].try_into()?);
+        let dir = match buf[8] {
+            0 => CaptureDir::CnToMs,
+            1 => CaptureDir::MsToCn,
+            x => bail!("invalid direction [{x}] of vnrec record #{n}"),
+        };
+        let socket_len = u16::from_be_bytes([buf[9], buf[10]]) as usize;
+        let len = u32::from_be_bytes(buf[11..15].try_into()?) as usize;
+        buf = &buf[VNREC_RECORD_HEADER_LEN..];
+
+        if buf.len() < socket_len + len {
+            bail!("truncated vnrec record #{n}, expect [{}] but [{}]", socket_len + len, buf.len())
+        }
+        let socket = match socket_len {
+            0 => None,
+            _ => Some(String::from_utf8_lossy(&buf[..socket_len]).into_owned()),
+        };
+        records.push(CaptureRecord {
+            ts_us,
+            dir,
+            socket,
+            hex: to_hex(&buf[socket_len..socket_len+len]),
+        });
+        buf = &buf[socket_len+len..];
+    }
+    Ok(records)
+}
+
+pub fn parse_capture(text: &str) -> Result<Vec<CaptureRecord>> {
+    text.lines()
+    .enumerate()
+    .filter(|(_n, line)| !line.trim().is_empty())
+    .map(|(n, line)| serde_json::from_str(line).with_context(||format!("invalid capture line [{}]", n+1)))
+    .collect()
+}
+
+/// jsonl or vnrec, detected by content
+pub fn read_capture(path: &Path) -> Result<Vec<CaptureRecord>> {
+    let data = std::fs::read(path).with_context(||format!("read capture failed [{path:?}]"))?;
+    parse_capture_bytes(&data).with_context(||format!("load capture failed [{path:?}]"))
+}
+
+/// jsonl or vnrec, detected by content
+pub fn parse_capture_bytes(data: &[u8]) -> Result<Vec<CaptureRecord>> {
+    if is_vnrec(data) {
+        parse_vnrec(data)
+    } else {
+        std::str::from_utf8(data).map_err(anyhow::Error::from).and_then(parse_capture)
+    }
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() * 2);
+    for b in data {
+        let _r = write!(s, "{b:02x}");
+    }
+    s
+}
+
+/// whitespace between digits is ignored, e.g. "00 14"
+pub fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    let digits: String = hex.chars().filter(|x| !x.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        bail!("odd hex digits [{hex}]")
+    }
+    (0..digits.len()).step_by(2)
+    .map(|i| u8::from_str_radix(&digits[i..i+2], 16).with_context(||format!("invalid hex [{hex}]")))
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::{parse_capture, parse_vnrec, CaptureDir, CaptureWriter};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_capture_roundtrip() {
+        let out = Shared::default();
+        let mut writer = CaptureWriter::new(Box::new(out.clone()));
+        writer.set_origin(CaptureDir::CnToMs, std::path::Path::new("/tmp/mscn1"));
+        writer.write(CaptureDir::CnToMs, &[0x00, 0x14, 0xab]).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        writer.write(CaptureDir::MsToCn, &[]).unwrap();
+
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let records = parse_capture(&text).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].dir, CaptureDir::CnToMs);
+        assert_eq!(records[0].data().unwrap(), vec![0x00, 0x14, 0xab]);
+        assert_eq!(records[0].socket.as_deref(), Some("/tmp/mscn1"));
+        assert_eq!(records[1].socket, None);
+        assert!(records[1].ts() >= records[0].ts() + std::time::Duration::from_millis(2));
+        assert!(parse_capture("{\"ts_us\": 1}").is_err());
+
+        let bin = Shared::default();
+        let mut writer = CaptureWriter::new_vnrec(Box::new(bin.clone())).unwrap();
+        for record in records.iter() {
+            writer.write_record(record).unwrap();
+        }
+        let data = bin.0.lock().unwrap().clone();
+        assert_eq!(parse_vnrec(&data).unwrap(), records);
+        assert!(parse_vnrec(&data[..data.len()-1]).is_err());
+        assert!(parse_vnrec(text.as_bytes()).is_err());
+    }
+}
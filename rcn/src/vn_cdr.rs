@@ -0,0 +1,218 @@
+//! call detail records, one per channel as it ends.
+//!
+//! A [`CdrWriter`] set on a ChannelRegistry (see vn_channels) gets every
+//! ended channel and writes it as a json line, or as a csv row if the path
+//! ends with .csv. Lines are flushed one by one so a tail sees them at once.
+
+use std::{collections::BTreeMap, fmt, fs::File, io::{BufWriter, Write}, path::Path};
+
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{utils::log_once::warn_first, vn_channels::{csv_field, EndedChannel}};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CdrFormat {
+    Jsonl,
+    Csv,
+}
+
+impl CdrFormat {
+    /// csv if the extension is .csv, jsonl otherwise
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|x| x.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => Self::Csv,
+            _ => Self::Jsonl,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cdr {
+    pub fsm_id: u32,
+    /// unix ms
+    pub started_ms: u64,
+    pub ended_ms: u64,
+    pub lifetime_ms: u64,
+    /// REQUESTCHANNEL to its ACK, None if never answered
+    pub setup_ms: Option<u64>,
+    pub media_type: Option<u8>,
+    pub codec: Option<u8>,
+    pub audio_port: Option<u16>,
+    pub video_port: Option<u16>,
+    pub play_ms: u64,
+    pub record_ms: u64,
+    /// last result of each ACK by code name
+    pub results: BTreeMap<String, u8>,
+    /// Display of EndReason
+    pub reason: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+impl From<&EndedChannel> for Cdr {
+    fn from(x: &EndedChannel) -> Self {
+        Self {
+            fsm_id: x.fsm_id,
+            started_ms: x.detail.started_ms,
+            ended_ms: x.ended_ms,
+            lifetime_ms: x.lifetime_ms,
+            setup_ms: x.detail.setup_ms,
+            media_type: x.detail.media_type,
+            codec: x.detail.codec,
+            audio_port: x.detail.audio_port,
+            video_port: x.detail.video_port,
+            play_ms: x.detail.play_ms,
+            record_ms: x.detail.record_ms,
+            results: x.detail.results.clone(),
+            reason: x.reason.to_string(),
+            tenant: x.detail.tenant.clone(),
+        }
+    }
+}
+
+pub const CSV_HEADER: &str = "fsm_id,started_ms,ended_ms,lifetime_ms,setup_ms,media_type,codec,audio_port,video_port,play_ms,record_ms,results,reason,tenant";
+
+impl Cdr {
+    /// one csv row, results as code=result separated by ';', empty cells for unknown
+    pub fn to_csv_row(&self) -> String {
+        let cell = |x: Option<String>| x.unwrap_or_default();
+        let results: Vec<String> = self.results.iter().map(|(code, result)| format!("{code}={result}")).collect();
+        format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            self.fsm_id, self.started_ms, self.ended_ms, self.lifetime_ms,
+            cell(self.setup_ms.map(|x| x.to_string())), cell(self.media_type.map(|x| x.to_string())),
+            cell(self.codec.map(|x| x.to_string())), cell(self.audio_port.map(|x| x.to_string())),
+            cell(self.video_port.map(|x| x.to_string())), self.play_ms, self.record_ms,
+            csv_field(&results.join(";")), csv_field(&self.reason), csv_field(self.tenant.as_deref().unwrap_or_default()),
+        )
+    }
+}
+
+pub struct CdrWriter {
+    out: Box<dyn Write + Send>,
+    format: CdrFormat,
+    /// csv header written or found in the file
+    has_header: bool,
+    written: u64,
+    failed: u64,
+}
+
+impl fmt::Debug for CdrWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CdrWriter")
+        .field("format", &self.format)
+        .field("written", &self.written)
+        .field("failed", &self.failed)
+        .finish()
+    }
+}
+
+impl CdrWriter {
+    /// csv header is written with the first record
+    pub fn new(out: Box<dyn Write + Send>, format: CdrFormat) -> Self {
+        Self { out, format, has_header: false, written: 0, failed: 0 }
+    }
+
+    /// appends to an existing file, csv header only if it was empty
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::options().create(true).append(true).open(path).with_context(||format!("open cdr failed [{path:?}]"))?;
+        let empty = file.metadata().map(|x| x.len() == 0).unwrap_or(true);
+        let mut writer = Self::new(Box::new(BufWriter::new(file)), CdrFormat::from_path(path));
+        writer.has_header = !empty;
+        Ok(writer)
+    }
+
+    /// a failed write is logged and counted, never returned as the
+    /// channel ended anyway
+    pub fn write(&mut self, channel: &EndedChannel) {
+        match self.try_write(&Cdr::from(channel)) {
+            Ok(()) => self.written += 1,
+            Err(e) => {
+                self.failed += 1;
+                warn_first!(self.failed, "write cdr of [{}] failed [{e:#}]", channel.fsm_id);
+            },
+        }
+    }
+
+    fn try_write(&mut self, cdr: &Cdr) -> Result<()> {
+        match self.format {
+            CdrFormat::Jsonl => {
+                serde_json::to_writer(&mut self.out, cdr)?;
+                self.out.write_all(b"\n")?;
+            },
+            CdrFormat::Csv => {
+                if !self.has_header {
+                    writeln!(self.out, "{CSV_HEADER}")?;
+                    self.has_header = true;
+                }
+                writeln!(self.out, "{}", cdr.to_csv_row())?;
+            },
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::{
+        vn_channels::{ChannelRegistry, EndReason},
+        vn_proto::{MCodeType, PlayAck, RequestChannel, RequestChannelAck},
+    };
+
+    use super::{Cdr, CdrFormat, CdrWriter, CSV_HEADER};
+
+    #[test]
+    fn test_cdr() {
+        let dir = std::env::temp_dir().join(format!("rcn_cdr_{}", std::process::id()));
+        let _r = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (json_path, csv_path) = (dir.join("cdr.jsonl"), dir.join("cdr.csv"));
+        assert_eq!(CdrFormat::from_path(&csv_path), CdrFormat::Csv);
+
+        let mut registry = ChannelRegistry::default();
+        registry.set_cdr(Some(CdrWriter::create(&json_path).unwrap()));
+        let t0 = Instant::now();
+        let (mut req, mut ack, mut play_ack) = (Vec::new(), Vec::new(), Vec::new());
+        RequestChannel { media_type: 1, codec: 8, webrtc: vec!["".into()], ..Default::default() }.write_to(&mut req);
+        RequestChannelAck { audio_port: 20000, ..Default::default() }.write_to(&mut ack);
+        PlayAck { result: 2, play_duration: 0 }.write_to(&mut play_ack);
+
+        registry.on_requested(7, 0, t0);
+        registry.on_packet_at(7, MCodeType::REQUESTCHANNEL.code(), &req, t0);
+        registry.on_packet_at(7, MCodeType::REQUESTCHANNEL_ACK.code(), &ack, t0 + Duration::from_millis(15));
+        registry.on_packet_at(7, MCodeType::PLAY.code(), &[], t0 + Duration::from_millis(100));
+        registry.on_packet_at(7, MCodeType::PLAY_ACK.code(), &play_ack, t0 + Duration::from_millis(2100));
+        registry.end(7, EndReason::Released, t0 + Duration::from_secs(3));
+
+        let text = std::fs::read_to_string(&json_path).unwrap();
+        let cdr: Cdr = serde_json::from_str(text.trim_end()).unwrap();
+        assert_eq!((cdr.fsm_id, cdr.lifetime_ms, cdr.setup_ms, cdr.play_ms, cdr.record_ms), (7, 3000, Some(15), 2000, 0));
+        assert_eq!((cdr.media_type, cdr.codec, cdr.audio_port), (Some(1), Some(8), Some(20000)));
+        assert_eq!(cdr.results.get("REQUESTCHANNEL_ACK"), Some(&0));
+        assert_eq!(cdr.results.get("PLAY_ACK"), Some(&2));
+        assert_eq!(cdr.reason, "released");
+
+        // header once across reopening
+        for _ in 0..2 {
+            let mut writer = CdrWriter::create(&csv_path).unwrap();
+            writer.write(&registry.ended()[0]);
+            assert_eq!(writer.written(), 1);
+        }
+        let csv = std::fs::read_to_string(&csv_path).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], CSV_HEADER);
+        assert!(lines[1].starts_with("7,"), "{csv}");
+        assert!(lines[1].ends_with(",3000,15,1,8,20000,0,2000,0,PLAY_ACK=2;REQUESTCHANNEL_ACK=0,released,"), "{csv}");
+        assert_eq!(lines[1].split(',').count(), CSV_HEADER.split(',').count());
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,620 @@
+//! registry of CN side channels and why each one ended.
+//!
+//! Besides the end reason each channel keeps when it started and ended
+//! (unix ms), codes seen, codec asked for and what REQUESTCHANNEL_ACK
+//! answered. [`ChannelReport`] is what `--channels-json` writes and
+//! `rcn ctl export-channels` reads back, [`ChannelReport::to_csv`] has
+//! one row per channel for spreadsheets. With a [`CdrWriter`] set each
+//! channel is also written out as it ends, see vn_cdr.
+//! A tenant set on the registry labels each channel requested after, so
+//! channels of teams sharing one rcn can be told apart afterwards, see
+//! [`ChannelReport::for_tenant`].
+//! With the events feature an EventPublisher set on the registry gets each
+//! channel as it is requested, answered and ended, see vn_events.
+//! Audio RTPINFO of OPENRTPCONNECT disagreeing with codec or ptime of
+//! REQUESTCHANNEL is warned about and kept as a [`MediaMismatch`], a usual
+//! suspect of one-way audio.
+//! What each MS announced in its last REGISTER (version, capacity, RTP
+//! port range, codecs) is kept by MS path, see [`RegisterInfo`].
+
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, fmt::{self, Write as _}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+#[cfg(feature = "events")]
+use crate::vn_events::{ChannelEvent, EventPublisher};
+use crate::{
+    vn_cdr::CdrWriter,
+    vn_proto::{CodeName, MCodeType, OpenRtpConnectRef, RequestChannelAckRef, RequestChannelRef, RtpInfoRef, RtpMediaType},
+    vn_session::RegisterInfo,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "reason", content = "result")]
+pub enum EndReason {
+    /// RELEASECHANNEL sent by CN
+    Released,
+    /// life_seconds of REQUESTCHANNEL passed
+    Expired,
+    /// call cancelled before channel was up
+    Cancelled,
+    /// MS answered with non zero result
+    MsError(u8),
+    /// link to MS lost
+    TransportLost,
+    /// MS registered again, its channels are gone
+    MsRestarted,
+}
+
+impl fmt::Display for EndReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Released => f.write_str("released"),
+            Self::Expired => f.write_str("expired"),
+            Self::Cancelled => f.write_str("cancelled"),
+            Self::MsError(result) => write!(f, "ms-error({result})"),
+            Self::TransportLost => f.write_str("transport-lost"),
+            Self::MsRestarted => f.write_str("ms-restarted"),
+        }
+    }
+}
+
+/// what was seen of a channel on the wire
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelDetail {
+    /// unix ms of REQUESTCHANNEL
+    #[serde(default)]
+    pub started_ms: u64,
+    /// codes sent and received for its fsm_id
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub codes: BTreeSet<u16>,
+    /// codec of REQUESTCHANNEL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video_port: Option<u16>,
+    /// result of REQUESTCHANNEL_ACK
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ack_result: Option<u8>,
+    /// media_type of REQUESTCHANNEL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<u8>,
+    /// ptime of REQUESTCHANNEL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ptime: Option<u8>,
+    /// RTPINFO disagreeing with REQUESTCHANNEL, each kind once
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mismatches: Vec<MediaMismatch>,
+    /// REQUESTCHANNEL to its ACK
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub setup_ms: Option<u64>,
+    /// PLAY and RECORD to their ACKs, summed
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub play_ms: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub record_ms: u64,
+    /// last result of each ACK by code name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub results: BTreeMap<String, u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+fn is_zero(x: &u64) -> bool {
+    *x == 0
+}
+
+/// codec or ptime asked for by REQUESTCHANNEL and what audio RTPINFO says
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MediaMismatch {
+    /// "codec" against internal_pltyp, or "ptime" against its attribute
+    pub field: String,
+    pub requested: u8,
+    pub rtpinfo: u8,
+}
+
+impl fmt::Display for MediaMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} requested [{}] rtpinfo [{}]", self.field, self.requested, self.rtpinfo)
+    }
+}
+
+/// mismatches of an audio RTPINFO against codec and ptime requested,
+/// ptime only if the attribute carries one
+pub fn media_mismatches(codec: Option<u8>, ptime: Option<u8>, info: &RtpInfoRef<'_>) -> Vec<MediaMismatch> {
+    let mut mismatches = Vec::new();
+    if info.part1().media_type() != RtpMediaType::Audio as u8 {
+        return mismatches
+    }
+    let pltyp = info.part1().internal_pltyp();
+    if let Some(codec) = codec.filter(|x| *x != pltyp) {
+        mismatches.push(MediaMismatch { field: "codec".into(), requested: codec, rtpinfo: pltyp });
+    }
+    let attr_ptime = std::str::from_utf8(info.attribute().data()).ok().and_then(attribute_ptime);
+    if let (Some(requested), Some(rtpinfo)) = (ptime, attr_ptime) {
+        if requested != rtpinfo {
+            mismatches.push(MediaMismatch { field: "ptime".into(), requested, rtpinfo });
+        }
+    }
+    mismatches
+}
+
+/// ptime of an sdp like attribute, e.g. "a=ptime:20" or "ptime=20;maxptime=40"
+fn attribute_ptime(attribute: &str) -> Option<u8> {
+    attribute.split([';', ',', ' ', '\r', '\n'])
+    .map(|x| x.trim_start_matches("a="))
+    .find_map(|x| x.strip_prefix("ptime:").or_else(|| x.strip_prefix("ptime=")))
+    .and_then(|x| x.trim().parse().ok())
+}
+
+impl ChannelDetail {
+    fn on_packet(&mut self, code: u16, payload: &[u8]) {
+        self.codes.insert(code);
+        let code = MCodeType::try_from(code);
+        if let (Ok(ack), Some(result)) = (code, payload.first()) {
+            // every ACK starts with its result
+            if ack.request().is_some() {
+                self.results.insert(format!("{ack:?}"), *result);
+            }
+        }
+        match code {
+            Ok(MCodeType::REQUESTCHANNEL) => {
+                if let Ok(req) = RequestChannelRef::parse_from(payload) {
+                    self.codec = Some(req.part2().codec_code());
+                    self.media_type = Some(req.part1().media_type_code());
+                    self.ptime = Some(req.part2().ptime());
+                }
+            },
+            Ok(MCodeType::OPENRTPCONNECT) => {
+                let Ok(open) = OpenRtpConnectRef::parse_from(payload) else { return };
+                for info in open.rtpinfo_iter().flatten() {
+                    for mismatch in media_mismatches(self.codec, self.ptime, &info) {
+                        if !self.mismatches.contains(&mismatch) {
+                            self.mismatches.push(mismatch);
+                        }
+                    }
+                }
+            },
+            Ok(MCodeType::REQUESTCHANNEL_ACK) => {
+                if let Ok(ack) = RequestChannelAckRef::parse_from(payload) {
+                    let part1 = ack.part1();
+                    self.ack_result = Some(part1.result());
+                    self.audio_port = Some(part1.audio_port());
+                    self.video_port = Some(part1.video_port());
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EndedChannel {
+    pub fsm_id: u32,
+    #[serde(flatten)]
+    pub reason: EndReason,
+    pub lifetime_ms: u64,
+    /// unix ms
+    #[serde(default)]
+    pub ended_ms: u64,
+    #[serde(flatten)]
+    pub detail: ChannelDetail,
+}
+
+/// channel still open when the report was made
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActiveChannel {
+    pub fsm_id: u32,
+    /// so far
+    pub lifetime_ms: u64,
+    #[serde(flatten)]
+    pub detail: ChannelDetail,
+}
+
+#[derive(Debug, Clone)]
+struct OpenChannel {
+    started: Instant,
+    /// None if no life_seconds
+    expires: Option<Instant>,
+    detail: ChannelDetail,
+    /// requests waiting for their ACK, by code
+    sent: HashMap<u16, Instant>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChannelReport {
+    pub open: usize,
+    /// count per reason, keyed by Display of EndReason
+    pub ended: BTreeMap<String, u64>,
+    pub channels: Vec<EndedChannel>,
+    /// by fsm_id
+    #[serde(default)]
+    pub active: Vec<ActiveChannel>,
+    /// last REGISTER of each MS by its path
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ms: BTreeMap<String, RegisterInfo>,
+}
+
+const CSV_HEADER: &str = "state,fsm_id,started_ms,ended_ms,lifetime_ms,reason,codes,codec,audio_port,video_port,ack_result,tenant";
+
+impl ChannelReport {
+    /// header then one row per active and ended channel,
+    /// codes by name separated by ';', empty cells for unknown
+    pub fn to_csv(&self) -> String {
+        let mut out = String::new();
+        out.push_str(CSV_HEADER);
+        out.push('\n');
+        let rows = self.active.iter().map(|x| ("active", x.fsm_id, &x.detail, None, x.lifetime_ms, String::new()))
+        .chain(self.channels.iter().map(|x| ("ended", x.fsm_id, &x.detail, Some(x.ended_ms), x.lifetime_ms, x.reason.to_string())));
+        for (state, fsm_id, detail, ended_ms, lifetime_ms, reason) in rows {
+            let codes: Vec<String> = detail.codes.iter().map(|x| CodeName(*x).to_string()).collect();
+            let cell = |x: Option<String>| x.unwrap_or_default();
+            let _r = writeln!(
+                out, "{state},{fsm_id},{},{},{lifetime_ms},{},{},{},{},{},{},{}",
+                detail.started_ms, cell(ended_ms.map(|x| x.to_string())), csv_field(&reason), csv_field(&codes.join(";")),
+                cell(detail.codec.map(|x| x.to_string())), cell(detail.audio_port.map(|x| x.to_string())),
+                cell(detail.video_port.map(|x| x.to_string())), cell(detail.ack_result.map(|x| x.to_string())),
+                csv_field(detail.tenant.as_deref().unwrap_or_default()),
+            );
+        }
+        out
+    }
+
+    /// fsm_id and detail of active and ended channels with media mismatches
+    pub fn mismatched(&self) -> impl Iterator<Item = (u32, &ChannelDetail)> {
+        self.active.iter().map(|x| (x.fsm_id, &x.detail))
+        .chain(self.channels.iter().map(|x| (x.fsm_id, &x.detail)))
+        .filter(|x| !x.1.mismatches.is_empty())
+    }
+
+    /// open count, one line per reason, channels with media mismatches if any,
+    /// then one line per MS announcing version, capacity or ports
+    pub fn summary(&self) -> Vec<String> {
+        let mismatched = self.mismatched().count();
+        std::iter::once(format!("channels open [{}]", self.open))
+        .chain(self.ended.iter().map(|(reason, num)| format!("channels ended [{reason}]: [{num}]")))
+        .chain((mismatched > 0).then(|| format!("channels with media mismatch [{mismatched}]")))
+        .chain(self.ms.iter().filter_map(|(ms, info)| ms_line(ms, info)))
+        .collect()
+    }
+
+    /// active channels still waiting for REQUESTCHANNEL_ACK, answered and refused
+    pub fn active_states(&self) -> (usize, usize, usize) {
+        let mut states = (0, 0, 0);
+        for channel in self.active.iter() {
+            match channel.detail.ack_result {
+                None => states.0 += 1,
+                Some(0) => states.1 += 1,
+                Some(_) => states.2 += 1,
+            }
+        }
+        states
+    }
+
+    /// only channels of tenant, counts made again
+    pub fn for_tenant(&self, tenant: &str) -> ChannelReport {
+        let of = |x: &ChannelDetail| x.tenant.as_deref() == Some(tenant);
+        let channels: Vec<EndedChannel> = self.channels.iter().filter(|x| of(&x.detail)).cloned().collect();
+        let active: Vec<ActiveChannel> = self.active.iter().filter(|x| of(&x.detail)).cloned().collect();
+        let mut ended = BTreeMap::new();
+        for channel in channels.iter() {
+            *ended.entry(channel.reason.to_string()).or_default() += 1;
+        }
+        ChannelReport { open: active.len(), ended, channels, active, ms: self.ms.clone() }
+    }
+}
+
+fn ms_line(ms: &str, info: &RegisterInfo) -> Option<String> {
+    let mut parts = Vec::new();
+    if let Some(v) = &info.version {
+        parts.push(format!("version [{v}]"));
+    }
+    if let Some(v) = info.capacity {
+        parts.push(format!("capacity [{v}]"));
+    }
+    if let Some(v) = info.port_range {
+        parts.push(format!("ports [{v}]"));
+    }
+    (!parts.is_empty()).then(|| format!("ms [{ms}] {}", parts.join(" ")))
+}
+
+/// quoted if it has ',', '"' or a line break
+pub(crate) fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+pub(crate) fn unix_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_millis() as u64).unwrap_or(0)
+}
+
+#[derive(Debug, Default)]
+pub struct ChannelRegistry {
+    open: HashMap<u32, OpenChannel>,
+    ended: Vec<EndedChannel>,
+    cdr: Option<CdrWriter>,
+    tenant: Option<String>,
+    #[cfg(feature = "events")]
+    events: Option<EventPublisher>,
+    ms: BTreeMap<String, RegisterInfo>,
+}
+
+impl ChannelRegistry {
+    /// REQUESTCHANNEL sent, life_seconds 0 never expires
+    pub fn on_requested(&mut self, fsm_id: u32, life_seconds: u16, now: Instant) {
+        let expires = (life_seconds > 0).then(|| now + Duration::from_secs(life_seconds as u64));
+        let detail = ChannelDetail { started_ms: unix_ms(), tenant: self.tenant.clone(), ..Default::default() };
+        #[cfg(feature = "events")]
+        if let Some(events) = &self.events {
+            events.publish(ChannelEvent::Requested { fsm_id, at_ms: detail.started_ms, tenant: detail.tenant.clone() });
+        }
+        self.open.insert(fsm_id, OpenChannel { started: now, expires, detail, sent: HashMap::new() });
+    }
+
+    /// REGISTER accepted from ms, replaces the one before
+    pub fn on_register(&mut self, ms: &str, info: RegisterInfo) {
+        self.ms.insert(ms.to_string(), info);
+    }
+
+    /// label of channels requested from now on
+    pub fn set_tenant(&mut self, tenant: Option<String>) {
+        self.tenant = tenant;
+    }
+
+    /// write a CDR of each channel as it ends
+    pub fn set_cdr(&mut self, cdr: Option<CdrWriter>) {
+        self.cdr = cdr;
+    }
+
+    /// publish each channel as it is requested, answered and ended
+    #[cfg(feature = "events")]
+    pub fn set_events(&mut self, events: Option<EventPublisher>) {
+        self.events = events;
+    }
+
+    /// packet sent or received for fsm_id, ignored unless its channel is open
+    pub fn on_packet(&mut self, fsm_id: u32, code: u16, payload: &[u8]) {
+        self.on_packet_at(fsm_id, code, payload, Instant::now());
+    }
+
+    /// on_packet of a packet seen at now
+    pub fn on_packet_at(&mut self, fsm_id: u32, code: u16, payload: &[u8], now: Instant) {
+        let Some(channel) = self.open.get_mut(&fsm_id) else { return };
+        let known = channel.detail.mismatches.len();
+        channel.detail.on_packet(code, payload);
+        for m in channel.detail.mismatches[known..].iter() {
+            warn!(fsm_id, field = %m.field, requested = m.requested, rtpinfo = m.rtpinfo, "media mismatch, RTPINFO disagrees with REQUESTCHANNEL");
+        }
+        let Ok(code) = MCodeType::try_from(code) else { return };
+        if code.ack().is_some() {
+            channel.sent.insert(code.code(), now);
+        }
+        let Some(request) = code.request() else { return };
+        let Some(sent) = channel.sent.remove(&request.code()) else { return };
+        let ms = now.saturating_duration_since(sent).as_millis() as u64;
+        match request {
+            MCodeType::REQUESTCHANNEL => {
+                channel.detail.setup_ms = Some(ms);
+                #[cfg(feature = "events")]
+                if let Some(events) = &self.events {
+                    events.publish(ChannelEvent::Answered { fsm_id, at_ms: unix_ms(), setup_ms: ms, result: channel.detail.ack_result });
+                }
+            },
+            MCodeType::PLAY => channel.detail.play_ms += ms,
+            MCodeType::RECORD => channel.detail.record_ms += ms,
+            _ => {},
+        }
+    }
+
+    pub fn is_open(&self, fsm_id: u32) -> bool {
+        self.open.contains_key(&fsm_id)
+    }
+
+    pub fn num_open(&self) -> usize {
+        self.open.len()
+    }
+
+    /// ends an open channel, false if it isn't open
+    pub fn end(&mut self, fsm_id: u32, reason: EndReason, now: Instant) -> bool {
+        let Some(channel) = self.open.remove(&fsm_id) else { return false };
+        let lifetime = now.saturating_duration_since(channel.started);
+        match &channel.detail.tenant {
+            Some(tenant) => info!("channel [{fsm_id}] of [{tenant}] ended [{reason}] after [{lifetime:?}]"),
+            None => info!("channel [{fsm_id}] ended [{reason}] after [{lifetime:?}]"),
+        }
+        self.ended.push(EndedChannel {
+            fsm_id,
+            reason,
+            lifetime_ms: lifetime.as_millis() as u64,
+            ended_ms: unix_ms(),
+            detail: channel.detail,
+        });
+        if let (Some(cdr), Some(ended)) = (&mut self.cdr, self.ended.last()) {
+            cdr.write(ended);
+        }
+        #[cfg(feature = "events")]
+        if let (Some(events), Some(ended)) = (&self.events, self.ended.last()) {
+            events.publish(ChannelEvent::Ended(ended.into()));
+        }
+        true
+    }
+
+    /// end channels past their life_seconds, returns their fsm_ids
+    pub fn expire(&mut self, now: Instant) -> Vec<u32> {
+        let mut expired: Vec<u32> = self.open.iter()
+        .filter(|x| x.1.expires.is_some_and(|t| t <= now))
+        .map(|x| *x.0)
+        .collect();
+        expired.sort_unstable();
+        for fsm_id in expired.iter() {
+            self.end(*fsm_id, EndReason::Expired, now);
+        }
+        expired
+    }
+
+    pub fn ended(&self) -> &[EndedChannel] {
+        &self.ended
+    }
+
+    pub fn count(&self, reason: EndReason) -> u64 {
+        self.ended.iter().filter(|x| x.reason == reason).count() as u64
+    }
+
+    pub fn report(&self) -> ChannelReport {
+        let mut ended = BTreeMap::new();
+        for channel in self.ended.iter() {
+            *ended.entry(channel.reason.to_string()).or_default() += 1;
+        }
+        let now = Instant::now();
+        let mut active: Vec<ActiveChannel> = self.open.iter()
+        .map(|(fsm_id, x)| ActiveChannel {
+            fsm_id: *fsm_id,
+            lifetime_ms: now.saturating_duration_since(x.started).as_millis() as u64,
+            detail: x.detail.clone(),
+        })
+        .collect();
+        active.sort_by_key(|x| x.fsm_id);
+        ChannelReport { open: self.open.len(), ended, channels: self.ended.clone(), active, ms: self.ms.clone() }
+    }
+
+    /// see ChannelReport::summary
+    pub fn summary(&self) -> Vec<String> {
+        self.report().summary()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::vn_proto::{MCodeType, OpenRtpConnect, RequestChannel, RequestChannelAck, RtpInfo};
+
+    use super::{attribute_ptime, ChannelRegistry, ChannelReport, EndReason, MediaMismatch};
+
+    #[test]
+    fn test_channel_end_reasons() {
+        let mut registry = ChannelRegistry::default();
+        let t0 = Instant::now();
+        for fsm_id in 1..=5 {
+            registry.on_requested(fsm_id, if fsm_id == 2 { 10 } else { 0 }, t0);
+        }
+
+        assert!(registry.end(1, EndReason::Released, t0 + Duration::from_secs(1)));
+        assert!(!registry.end(1, EndReason::Released, t0));
+        assert_eq!(registry.expire(t0 + Duration::from_secs(9)), Vec::<u32>::new());
+        assert_eq!(registry.expire(t0 + Duration::from_secs(10)), vec![2]);
+        registry.end(3, EndReason::MsError(4), t0);
+        registry.end(4, EndReason::TransportLost, t0);
+
+        assert_eq!(registry.num_open(), 1);
+        assert_eq!(registry.count(EndReason::Expired), 1);
+        assert_eq!(registry.ended()[0].lifetime_ms, 1000);
+
+        let report = registry.report();
+        assert_eq!(report.ended.get("ms-error(4)"), Some(&1));
+        let json = serde_json::to_value(&report).unwrap();
+        let mut ended = json["channels"][2].clone();
+        assert!(ended["started_ms"].as_u64().unwrap() > 0);
+        for key in ["started_ms", "ended_ms"] {
+            ended.as_object_mut().unwrap().remove(key);
+        }
+        assert_eq!(ended, serde_json::json!({"fsm_id": 3, "reason": "ms-error", "result": 4, "lifetime_ms": 0}));
+        assert_eq!(json["channels"][0]["reason"], "released");
+        assert_eq!(registry.summary().len(), 5);
+    }
+
+    #[test]
+    fn test_channel_csv() {
+        let mut registry = ChannelRegistry::default();
+        let t0 = Instant::now();
+        let mut req = Vec::new();
+        RequestChannel { codec: 8, webrtc: vec!["".into()], ..Default::default() }.write_to(&mut req);
+        let mut ack = Vec::new();
+        RequestChannelAck { audio_port: 20000, video_port: 20002, ..Default::default() }.write_to(&mut ack);
+
+        for fsm_id in [7, 8] {
+            registry.on_requested(fsm_id, 0, t0);
+            registry.on_packet(fsm_id, MCodeType::REQUESTCHANNEL.code(), &req);
+            registry.on_packet(fsm_id, MCodeType::REQUESTCHANNEL_ACK.code(), &ack);
+        }
+        registry.on_packet(7, MCodeType::PLAY.code(), &[]);
+        registry.end(7, EndReason::MsError(2), t0);
+        // ended, not recorded
+        registry.on_packet(7, MCodeType::PLAY_ACK.code(), &[]);
+
+        let report = registry.report();
+        assert_eq!(report.active.len(), 1);
+        assert_eq!(report.channels[0].detail.codes.len(), 3);
+        registry.on_requested(10, 0, t0);
+        assert_eq!(registry.report().active_states(), (1, 1, 0));
+        registry.end(10, EndReason::Released, t0);
+
+        // json of --channels-json reads back the same
+        let json = serde_json::to_string(&report).unwrap();
+        let back: ChannelReport = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.to_csv(), report.to_csv());
+
+        let csv = report.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "state,fsm_id,started_ms,ended_ms,lifetime_ms,reason,codes,codec,audio_port,video_port,ack_result,tenant");
+        assert!(lines[1].starts_with("active,8,"));
+        assert!(lines[1].ends_with(",,0,,REQUESTCHANNEL;REQUESTCHANNEL_ACK,8,20000,20002,0,"), "{}", lines[1]);
+        assert!(lines[2].starts_with("ended,7,"));
+        assert!(lines[2].ends_with(",0,ms-error(2),REQUESTCHANNEL;REQUESTCHANNEL_ACK;PLAY,8,20000,20002,0,"), "{}", lines[2]);
+
+        registry.set_tenant(Some("team-a".into()));
+        registry.on_requested(9, 0, t0);
+        registry.end(9, EndReason::Released, t0);
+        let report = registry.report().for_tenant("team-a");
+        assert_eq!((report.open, report.channels.len(), report.active.len()), (0, 1, 0));
+        assert_eq!(report.ended.get("released"), Some(&1));
+        assert!(report.to_csv().lines().nth(1).unwrap().ends_with(",team-a"));
+        assert_eq!(super::csv_field("a,\"b\""), "\"a,\"\"b\"\"\"");
+    }
+
+    #[test]
+    fn test_media_mismatch() {
+        let mut registry = ChannelRegistry::default();
+        let t0 = Instant::now();
+        let mut req = Vec::new();
+        RequestChannel { codec: 8, ptime: 20, webrtc: vec!["".into()], ..Default::default() }.write_to(&mut req);
+        let open = |pltyp: u8, attribute: &str| {
+            let mut info = RtpInfo::plain([10, 0, 0, 2].into(), 4000, pltyp);
+            info.attribute = attribute.into();
+            let mut payload = Vec::new();
+            OpenRtpConnect { rtpinfos: vec![info] }.write_to(&mut payload);
+            payload
+        };
+
+        for fsm_id in [1, 2] {
+            registry.on_requested(fsm_id, 0, t0);
+            registry.on_packet(fsm_id, MCodeType::REQUESTCHANNEL.code(), &req);
+        }
+        registry.on_packet(1, MCodeType::OPENRTPCONNECT.code(), &open(8, "a=ptime:20"));
+        for _ in 0..2 {
+            registry.on_packet(2, MCodeType::OPENRTPCONNECT.code(), &open(0, "ptime=30;maxptime=40"));
+        }
+
+        let report = registry.report();
+        let mismatched: Vec<_> = report.mismatched().collect();
+        assert_eq!(mismatched.len(), 1);
+        assert_eq!(mismatched[0].0, 2);
+        assert_eq!(mismatched[0].1.mismatches, [
+            MediaMismatch { field: "codec".into(), requested: 8, rtpinfo: 0 },
+            MediaMismatch { field: "ptime".into(), requested: 20, rtpinfo: 30 },
+        ]);
+        assert_eq!(mismatched[0].1.mismatches[0].to_string(), "codec requested [8] rtpinfo [0]");
+        assert!(registry.summary().contains(&"channels with media mismatch [1]".to_string()));
+
+        assert_eq!(attribute_ptime("a=rtpmap:8 PCMA/8000 a=ptime:40"), Some(40));
+        assert_eq!(attribute_ptime("maxptime:40"), None);
+        assert_eq!(attribute_ptime(""), None);
+    }
+}
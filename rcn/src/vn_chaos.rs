@@ -0,0 +1,226 @@
+//! packet impairment rules applied by the proxy, see vn_proxy.
+//!
+//! A profile is either a named preset (`lossy-lan`, `congested`,
+//! `ms-restart-every-5m`) or a yaml file with the fields of [`ChaosProfile`].
+
+use std::{path::Path, time::Duration};
+
+use anyhow::{Result, Context, bail};
+use serde::Deserialize;
+
+use crate::{utils::rng::SimRng, vn_proto::MCodeType};
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ChaosProfile {
+    /// probability of dropping a packet
+    pub drop: f64,
+    /// fixed delay of every packet
+    pub delay_ms: u64,
+    /// extra random delay in [0, jitter_ms)
+    pub jitter_ms: u64,
+    /// probability of holding a packet back by reorder_ms so later ones overtake it
+    pub reorder: f64,
+    pub reorder_ms: u64,
+    /// probability of flipping one payload byte
+    pub corrupt: f64,
+    /// MS looks restarted: both directions blackholed for outage_secs
+    /// at the end of every restart_every_secs
+    pub restart_every_secs: u64,
+    pub outage_secs: u64,
+    /// probability of delivering an ACK twice
+    pub dup_ack: f64,
+    /// hold up to this many packets and release them shuffled,
+    /// link control packets (CNISUP, REGISTER, HEARTBEAT) flush the window instead
+    pub reorder_window: usize,
+    /// release a partly filled window after this long
+    pub reorder_window_ms: u64,
+}
+
+pub const PRESETS: &[&str] = &["lossy-lan", "congested", "ms-restart-every-5m"];
+
+impl ChaosProfile {
+    pub fn preset(name: &str) -> Option<Self> {
+        let profile = match name {
+            "lossy-lan" => Self {
+                drop: 0.01,
+                delay_ms: 1,
+                jitter_ms: 5,
+                reorder: 0.005,
+                reorder_ms: 10,
+                ..Default::default()
+            },
+            "congested" => Self {
+                drop: 0.05,
+                delay_ms: 80,
+                jitter_ms: 120,
+                reorder: 0.02,
+                reorder_ms: 150,
+                corrupt: 0.001,
+                ..Default::default()
+            },
+            "ms-restart-every-5m" => Self {
+                restart_every_secs: 300,
+                outage_secs: 10,
+                ..Default::default()
+            },
+            _ => return None,
+        };
+        Some(profile)
+    }
+
+    /// preset name or path of yaml file
+    pub fn load(name_or_path: &str) -> Result<Self> {
+        if let Some(profile) = Self::preset(name_or_path) {
+            return Ok(profile)
+        }
+
+        let path = Path::new(name_or_path);
+        if !path.exists() {
+            bail!("unknown chaos preset [{name_or_path}], expect one of {PRESETS:?} or a yaml file")
+        }
+        let text = std::fs::read_to_string(path).with_context(||format!("read chaos profile failed [{path:?}]"))?;
+        serde_yaml::from_str(&text).with_context(||format!("invalid chaos profile [{path:?}]"))
+    }
+
+    pub fn is_none(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// what to do with one packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Drop,
+    Deliver {
+        delay: Duration,
+        /// xor byte at pos with mask
+        corrupt: Option<(usize, u8)>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Chaos {
+    profile: ChaosProfile,
+    rng: SimRng,
+}
+
+impl Chaos {
+    pub fn new(profile: ChaosProfile, rng: &SimRng) -> Self {
+        Self {
+            profile,
+            rng: rng.fork("chaos"),
+        }
+    }
+
+    pub fn profile(&self) -> &ChaosProfile {
+        &self.profile
+    }
+
+    /// whether MS is in a simulated restart at elapsed since start
+    pub fn in_outage(&self, elapsed: Duration) -> bool {
+        let every = self.profile.restart_every_secs;
+        if every == 0 || self.profile.outage_secs == 0 {
+            return false
+        }
+        let pos = elapsed.as_secs() % every;
+        pos >= every.saturating_sub(self.profile.outage_secs)
+    }
+
+    /// packet of len bytes, protected bytes at start (header) are never corrupted
+    pub fn decide(&self, len: usize, protected: usize, elapsed: Duration) -> Verdict {
+        let p = &self.profile;
+        if self.in_outage(elapsed) || (p.drop > 0.0 && self.rng.chance(p.drop)) {
+            return Verdict::Drop
+        }
+
+        let mut delay_ms = p.delay_ms + self.rng.range(0, p.jitter_ms);
+        if p.reorder > 0.0 && self.rng.chance(p.reorder) {
+            delay_ms += p.reorder_ms;
+        }
+
+        let corrupt = if p.corrupt > 0.0 && len > protected && self.rng.chance(p.corrupt) {
+            let pos = self.rng.range(protected as u64, len as u64) as usize;
+            Some((pos, self.rng.range(1, 256) as u8))
+        } else {
+            None
+        };
+
+        Verdict::Deliver {
+            delay: Duration::from_millis(delay_ms),
+            corrupt,
+        }
+    }
+
+    /// whether to deliver a packet of code once more, only ACKs are duplicated
+    pub fn duplicate(&self, code: u16) -> bool {
+        let is_ack = MCodeType::try_from(code).ok().and_then(|x| x.request()).is_some();
+        is_ack && self.profile.dup_ack > 0.0 && self.rng.chance(self.profile.dup_ack)
+    }
+
+    /// whether a packet of code may be held in reorder window
+    pub fn reorderable(&self, code: u16) -> bool {
+        if self.profile.reorder_window < 2 {
+            return false
+        }
+        !matches!(
+            MCodeType::try_from(code),
+            Ok(MCodeType::CNISUP | MCodeType::CNISUP_ACK | MCodeType::REGISTER | MCodeType::REGISTER_ACK | MCodeType::HEARTBEAT)
+        )
+    }
+
+    pub fn shuffle<T>(&self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.rng.range(0, i as u64 + 1) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{utils::rng::SimRng, vn_proto::MCodeType};
+
+    use super::{Chaos, ChaosProfile, Verdict, PRESETS};
+
+    #[test]
+    fn test_chaos_presets() {
+        for name in PRESETS {
+            assert!(!ChaosProfile::load(name).unwrap().is_none(), "{name}");
+        }
+        assert!(ChaosProfile::load("no-such-preset").is_err());
+
+        let restart = Chaos::new(ChaosProfile::preset("ms-restart-every-5m").unwrap(), &SimRng::new(1));
+        assert!(!restart.in_outage(Duration::from_secs(289)));
+        assert!(restart.in_outage(Duration::from_secs(290)));
+        assert!(!restart.in_outage(Duration::from_secs(300)));
+        assert_eq!(restart.decide(20, 12, Duration::from_secs(295)), Verdict::Drop);
+
+        let profile = ChaosProfile { drop: 0.5, corrupt: 1.0, ..Default::default() };
+        let verdicts = |seed| {
+            let chaos = Chaos::new(profile.clone(), &SimRng::new(seed));
+            (0..100).map(|_| chaos.decide(20, 12, Duration::ZERO)).collect::<Vec<_>>()
+        };
+        assert_eq!(verdicts(3), verdicts(3));
+        let dropped = verdicts(3).iter().filter(|x| **x == Verdict::Drop).count();
+        assert!((30..70).contains(&dropped), "{dropped}");
+        assert!(verdicts(3).iter().all(|x| match x {
+            Verdict::Drop => true,
+            Verdict::Deliver { corrupt: Some((pos, _)), .. } => (12..20).contains(pos),
+            _ => false,
+        }));
+
+        let chaos = Chaos::new(ChaosProfile { dup_ack: 1.0, reorder_window: 4, ..Default::default() }, &SimRng::new(5));
+        assert!(chaos.duplicate(MCodeType::PLAY_ACK.code()));
+        assert!(!chaos.duplicate(MCodeType::PLAY.code()));
+        assert!(chaos.reorderable(MCodeType::PLAY.code()));
+        assert!(!chaos.reorderable(MCodeType::REGISTER.code()));
+        let mut items: Vec<_> = (0..16).collect();
+        chaos.shuffle(&mut items);
+        assert_ne!(items, (0..16).collect::<Vec<_>>());
+        items.sort();
+        assert_eq!(items, (0..16).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,106 @@
+//! charset of strings embedded in packets, older MS builds send GBK.
+//!
+//! utf-8 is always tried first, the configured charset is the fallback.
+//! GBK needs the gbk feature, without it GBK strings don't decode.
+
+use core::{fmt, str::FromStr, sync::atomic::{AtomicU8, Ordering}};
+
+use alloc::{borrow::Cow, string::String};
+
+use anyhow::bail;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Charset {
+    /// invalid utf-8 is shown as error
+    #[default]
+    Utf8 = 0,
+    Gbk = 1,
+    Latin1 = 2,
+}
+
+impl FromStr for Charset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "utf8" | "utf-8" => Ok(Self::Utf8),
+            "gbk" | "gb2312" | "gb18030" => Ok(Self::Gbk),
+            "latin1" | "latin-1" | "iso-8859-1" => Ok(Self::Latin1),
+            _ => bail!("unknown charset [{}]", s),
+        }
+    }
+}
+
+static CHARSET: AtomicU8 = AtomicU8::new(Charset::Utf8 as u8);
+
+/// charset used by Debug output of packets
+pub fn set_charset(charset: Charset) {
+    CHARSET.store(charset as u8, Ordering::Relaxed);
+}
+
+pub fn charset() -> Charset {
+    match CHARSET.load(Ordering::Relaxed) {
+        1 => Charset::Gbk,
+        2 => Charset::Latin1,
+        _ => Charset::Utf8,
+    }
+}
+
+impl Charset {
+    /// None if not decodable in utf-8 nor this charset
+    pub fn decode<'a>(&self, data: &'a [u8]) -> Option<Cow<'a, str>> {
+        if let Ok(s) = core::str::from_utf8(data) {
+            return Some(Cow::Borrowed(s))
+        }
+
+        match self {
+            Charset::Utf8 => None,
+            #[cfg(feature = "gbk")]
+            Charset::Gbk => encoding_rs::GBK
+                .decode_without_bom_handling_and_without_replacement(data),
+            #[cfg(not(feature = "gbk"))]
+            Charset::Gbk => None,
+            Charset::Latin1 => Some(Cow::Owned(data.iter().map(|x| *x as char).collect::<String>())),
+        }
+    }
+}
+
+/// decode with global charset
+pub fn decode_str(data: &[u8]) -> Option<Cow<'_, str>> {
+    charset().decode(data)
+}
+
+/// Debug of string field, falls back to utf-8 error and raw bytes
+pub struct StrDebug<'a>(pub &'a [u8]);
+
+impl<'a> fmt::Debug for StrDebug<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(s) = decode_str(self.0) {
+            return fmt::Debug::fmt(&s, f)
+        }
+
+        match core::str::from_utf8(self.0) {
+            Ok(v) => fmt::Debug::fmt(&v, f),
+            Err(e) => fmt::Debug::fmt(&Result::<(), core::str::Utf8Error>::Err(e), f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Charset;
+
+    #[test]
+    fn test_decode() {
+        // "你好.wav" in GBK
+        let gbk = b"\xc4\xe3\xba\xc3.wav";
+        assert!(Charset::Utf8.decode(gbk).is_none());
+        #[cfg(feature = "gbk")]
+        assert_eq!(Charset::Gbk.decode(gbk).unwrap(), "你好.wav");
+        assert_eq!(Charset::Latin1.decode(b"caf\xe9").unwrap(), "café");
+        assert_eq!(Charset::Gbk.decode(b"PCMA/8000").unwrap(), "PCMA/8000");
+        assert_eq!("GBK".parse::<Charset>().unwrap(), Charset::Gbk);
+    }
+}
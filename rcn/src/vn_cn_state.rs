@@ -0,0 +1,123 @@
+//! sn counters and fsm_id allocator of a CN kept in a small state file
+//! across restarts.
+//!
+//! A CN restarted within seconds otherwise numbers channels and sns from the
+//! start again while the MS still holds channels of the old process under
+//! those fsm_ids, and answers cross over between calls. The file is written
+//! on every fsm_id allocated and at most every [`SAVE_INTERVAL`] as sns go
+//! up; sns of a restored state are advanced by [`SN_SKIP`] to cover packets
+//! sent after the last save.
+
+use std::{collections::BTreeMap, path::{Path, PathBuf}, time::{Duration, Instant}};
+
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+
+/// sn changes are saved at most this often
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// sns restored are advanced this much, more than sent within SAVE_INTERVAL
+pub const SN_SKIP: u16 = 256;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CnState {
+    pub cn_id: u32,
+    /// fsm_id space and strategy the cursor is of, e.g. "5000000-5999999 increment"
+    pub fsm_ids: String,
+    /// see FsmIdAllocator::cursor
+    pub cursor: u32,
+    /// last sn sent of each fsm_id
+    pub sns: BTreeMap<u32, u16>,
+}
+
+impl CnState {
+    /// sns to continue with after a restart
+    pub fn resumed_sns(&self) -> impl Iterator<Item = (u32, u16)> + '_ {
+        self.sns.iter().map(|(fsm_id, sn)| (*fsm_id, sn.wrapping_add(SN_SKIP)))
+    }
+}
+
+#[derive(Debug)]
+pub struct StateFile {
+    path: PathBuf,
+    saved: Option<Instant>,
+    dirty: bool,
+}
+
+impl StateFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), saved: None, dirty: false }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// None if not saved yet
+    pub fn load(&self) -> Result<Option<CnState>> {
+        let text = match std::fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(||format!("read state file failed [{:?}]", self.path)),
+        };
+        let state = serde_json::from_str(&text).with_context(||format!("invalid state file [{:?}]", self.path))?;
+        Ok(Some(state))
+    }
+
+    /// written aside and renamed, a crash mid-write keeps the last state
+    pub fn save(&mut self, state: &CnState, now: Instant) -> Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let json = serde_json::to_string(state)?;
+        std::fs::write(&tmp, json).with_context(||format!("write state file failed [{tmp:?}]"))?;
+        std::fs::rename(&tmp, &self.path).with_context(||format!("rename state file failed [{:?}]", self.path))?;
+        self.saved = Some(now);
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// state changed since last save
+    pub fn touch(&mut self) {
+        self.dirty = true;
+    }
+
+    /// changed and SAVE_INTERVAL since last save
+    pub fn due(&self, now: Instant) -> bool {
+        self.dirty && self.saved.is_none_or(|x| now.duration_since(x) >= SAVE_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{CnState, StateFile, SAVE_INTERVAL, SN_SKIP};
+
+    #[test]
+    fn test_state_file() {
+        let path = std::env::temp_dir().join(format!("rcn-cn-state-{}.json", std::process::id()));
+        let mut file = StateFile::new(&path);
+        let _r = std::fs::remove_file(&path);
+        assert_eq!(file.load().unwrap(), None);
+
+        let state = CnState {
+            cn_id: 5,
+            fsm_ids: "5000000-5999999 increment".into(),
+            cursor: 42,
+            sns: [(5000000, 7), (5000041, u16::MAX)].into(),
+        };
+        let now = Instant::now();
+        file.save(&state, now).unwrap();
+        assert_eq!(file.load().unwrap().as_ref(), Some(&state));
+        assert_eq!(state.resumed_sns().collect::<Vec<_>>(), [(5000000, 7 + SN_SKIP), (5000041, SN_SKIP - 1)]);
+
+        assert!(!file.due(now));
+        file.touch();
+        assert!(!file.due(now + Duration::from_millis(10)));
+        assert!(file.due(now + SAVE_INTERVAL));
+
+        std::fs::write(&path, "{").unwrap();
+        assert!(file.load().is_err());
+        let _r = std::fs::remove_file(&path);
+    }
+}
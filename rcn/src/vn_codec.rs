@@ -0,0 +1,193 @@
+use thiserror::Error;
+
+use crate::vn_proto::{CodecDescRef, MediaInfoRef, MediaType, RegisterRef, RequestChannel};
+
+/// what the caller wants, codecs in preference order,
+/// each as "NAME" or "NAME/clock", e.g. "PCMA", "AMR/8000"
+#[derive(Debug, Clone)]
+pub struct CallProfile {
+    pub media_type: MediaType,
+    pub audio: Vec<String>,
+    pub video: Vec<String>,
+    pub ptime: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NegotiatedCodec {
+    pub index: u8,
+    pub payload_type: u8,
+    pub rtpmap: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Negotiated {
+    pub media_type: MediaType,
+    pub audio: Option<NegotiatedCodec>,
+    pub video: Option<NegotiatedCodec>,
+    pub ptime: u8,
+}
+
+impl Negotiated {
+    /// fill codec related fields of RequestChannel, its one codec byte
+    /// is the audio codec or the video one of a video only channel
+    pub fn apply_to(&self, req: &mut RequestChannel) {
+        req.media_type = self.media_type as u8;
+        req.ptime = self.ptime;
+        if let Some(codec) = self.audio.as_ref().or(self.video.as_ref()) {
+            req.codec = codec.index;
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum NegotiateError {
+    #[error("no common audio codec, wanted {wanted:?}, offered {offered:?}")]
+    NoAudio { wanted: Vec<String>, offered: Vec<String> },
+
+    #[error("no common video codec, wanted {wanted:?}, offered {offered:?}")]
+    NoVideo { wanted: Vec<String>, offered: Vec<String> },
+
+    #[error("media type {0:?} not supported by negotiation")]
+    UnsupportedMediaType(MediaType),
+}
+
+/// pick first codec of profile (in profile order) which MS registered
+pub fn negotiate(media_info: &MediaInfoRef<'_>, profile: &CallProfile) -> Result<Negotiated, NegotiateError> {
+    let (need_audio, need_video) = match profile.media_type {
+        MediaType::AudioOnly => (true, false),
+        MediaType::AudioVideo => (true, true),
+        MediaType::VideoOnly => (false, true),
+        other => return Err(NegotiateError::UnsupportedMediaType(other)),
+    };
+
+    let audio = if need_audio {
+        let r = select_codec(&media_info.audio_codecs, &profile.audio);
+        Some(r.ok_or_else(||NegotiateError::NoAudio {
+            wanted: profile.audio.clone(),
+            offered: offered_rtpmaps(&media_info.audio_codecs),
+        })?)
+    } else {
+        None
+    };
+
+    let video = if need_video {
+        let r = select_codec(&media_info.video_codecs, &profile.video);
+        Some(r.ok_or_else(||NegotiateError::NoVideo {
+            wanted: profile.video.clone(),
+            offered: offered_rtpmaps(&media_info.video_codecs),
+        })?)
+    } else {
+        None
+    };
+
+    Ok(Negotiated {
+        media_type: profile.media_type,
+        audio,
+        video,
+        ptime: profile.ptime,
+    })
+}
+
+/// negotiate against the payload of the REGISTER an MS was accepted with
+pub fn negotiate_register(register: &[u8], profile: &CallProfile) -> anyhow::Result<Negotiated> {
+    let reg = RegisterRef::parse_from(register)?;
+    Ok(negotiate(&reg.media_info, profile)?)
+}
+
+fn select_codec(offered: &[CodecDescRef<'_>], wanted: &[String]) -> Option<NegotiatedCodec> {
+    for want in wanted.iter() {
+        for codec in offered.iter() {
+            if let Some(rtpmap) = codec.map_str_utf8() {
+                if rtpmap_matches(rtpmap, want) {
+                    return Some(NegotiatedCodec {
+                        index: codec.index(),
+                        payload_type: codec.payload_type(),
+                        rtpmap: rtpmap.to_owned(),
+                    })
+                }
+            }
+        }
+    }
+    None
+}
+
+fn offered_rtpmaps(offered: &[CodecDescRef<'_>]) -> Vec<String> {
+    offered.iter()
+    .map(|x| match x.map_str_utf8() {
+        Some(v) => v.to_owned(),
+        None => format!("pt{}", x.payload_type()),
+    })
+    .collect()
+}
+
+/// "AMR/8000/1" matches "amr" and "AMR/8000", but not "AMR/16000"
+fn rtpmap_matches(rtpmap: &str, want: &str) -> bool {
+    let mut offered = rtpmap.split('/');
+    let mut wanted = want.split('/');
+    loop {
+        match (offered.next(), wanted.next()) {
+            (_, None) => return true,
+            (Some(o), Some(w)) => {
+                if !o.trim().eq_ignore_ascii_case(w.trim()) {
+                    return false
+                }
+            },
+            (None, Some(_)) => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::vn_proto::{MediaInfoRef, MediaType, RequestChannel};
+
+    use super::{negotiate, CallProfile, NegotiateError};
+
+    const MEDIA_INFO: &[u8] = b"\x01\
+        \x02\x00\x00PCMU/8000\x00\x08\x08PCMA/8000\x00\
+        \x01\x03\x62H264/90000\x00\
+        \x00";
+
+    #[test]
+    fn test_negotiate() {
+        let (_n, media_info) = MediaInfoRef::parse_from(MEDIA_INFO).unwrap();
+
+        let profile = CallProfile {
+            media_type: MediaType::AudioVideo,
+            audio: vec!["G729".into(), "pcma/8000".into()],
+            video: vec!["H264".into()],
+            ptime: 20,
+        };
+        let negotiated = negotiate(&media_info, &profile).unwrap();
+        let audio = negotiated.audio.as_ref().unwrap();
+        assert_eq!(audio.index, 8);
+        assert_eq!(audio.rtpmap, "PCMA/8000");
+        assert_eq!(negotiated.video.as_ref().unwrap().payload_type, 0x62);
+
+        let mut req = RequestChannel::default();
+        negotiated.apply_to(&mut req);
+        assert_eq!(req.codec, 8);
+        assert_eq!(req.ptime, 20);
+        assert_eq!(req.media_type, MediaType::AudioVideo as u8);
+
+        let profile = CallProfile {
+            media_type: MediaType::AudioOnly,
+            audio: vec!["PCMU/16000".into()],
+            video: vec![],
+            ptime: 20,
+        };
+        let err = negotiate(&media_info, &profile).unwrap_err();
+        assert!(matches!(err, NegotiateError::NoAudio{..}), "{err}");
+
+        let profile = CallProfile {
+            media_type: MediaType::VideoOnly,
+            audio: vec![],
+            video: vec!["h264".into()],
+            ptime: 20,
+        };
+        let mut req = RequestChannel::default();
+        negotiate(&media_info, &profile).unwrap().apply_to(&mut req);
+        assert_eq!(req.codec, 3);
+        assert_eq!(req.media_type, MediaType::VideoOnly as u8);
+    }
+}
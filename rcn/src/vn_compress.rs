@@ -0,0 +1,97 @@
+//! optional zlib compression of payload section.
+//!
+//! only used after both sides announced `Capability::ZLIB` at REGISTER,
+//! then every payload is prefixed with one encoding byte.
+
+use alloc::vec::Vec;
+
+use anyhow::{Result, bail};
+
+pub const ENCODING_RAW: u8 = 0;
+pub const ENCODING_ZLIB: u8 = 1;
+
+/// header length field is u16, inflated payload never exceeds it
+pub const MAX_INFLATED_LEN: usize = u16::MAX as usize;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Compression {
+    /// payloads shorter than this are sent raw
+    pub threshold: usize,
+    /// zlib level 0..=10
+    pub level: u8,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            threshold: 256,
+            level: 6,
+        }
+    }
+}
+
+impl Compression {
+    /// encoding byte + payload, compressed if it's worth it
+    pub fn encode(&self, payload: &[u8], out: &mut Vec<u8>) {
+        if payload.len() >= self.threshold {
+            let compressed = miniz_oxide::deflate::compress_to_vec_zlib(payload, self.level);
+            if compressed.len() < payload.len() {
+                out.push(ENCODING_ZLIB);
+                out.extend_from_slice(&compressed[..]);
+                return
+            }
+        }
+        out.push(ENCODING_RAW);
+        out.extend_from_slice(payload);
+    }
+}
+
+/// strip encoding byte and inflate into out if needed, return payload
+pub fn decode_payload<'a>(data: &'a [u8], out: &'a mut Vec<u8>) -> Result<&'a [u8]> {
+    if data.is_empty() {
+        bail!("compressed payload without encoding byte")
+    }
+
+    match data[0] {
+        ENCODING_RAW => Ok(&data[1..]),
+        ENCODING_ZLIB => {
+            let inflated = miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(&data[1..], MAX_INFLATED_LEN);
+            match inflated {
+                Ok(v) => {
+                    *out = v;
+                    Ok(&out[..])
+                },
+                Err(e) => bail!("inflate payload failed [{:?}]", e.status),
+            }
+        },
+        other => bail!("unknown payload encoding [{}]", other),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_payload, Compression, ENCODING_RAW, ENCODING_ZLIB};
+
+    #[test]
+    fn test_encode_decode() {
+        let compression = Compression::default();
+
+        let small = b"dtls_roll:client\x00";
+        let mut out = Vec::new();
+        compression.encode(small, &mut out);
+        assert_eq!(out[0], ENCODING_RAW);
+        let mut scratch = Vec::new();
+        assert_eq!(decode_payload(&out[..], &mut scratch).unwrap(), small);
+
+        let large = "a=candidate:1 1 udp 2130706431 192.168.9.246 5000 typ host\r\n".repeat(40);
+        let mut out = Vec::new();
+        compression.encode(large.as_bytes(), &mut out);
+        assert_eq!(out[0], ENCODING_ZLIB);
+        assert!(out.len() < 1700, "{}", out.len());
+        let mut scratch = Vec::new();
+        assert_eq!(decode_payload(&out[..], &mut scratch).unwrap(), large.as_bytes());
+
+        assert!(decode_payload(&[ENCODING_ZLIB, 1, 2, 3], &mut Vec::new()).is_err());
+        assert!(decode_payload(&[9], &mut Vec::new()).is_err());
+    }
+}
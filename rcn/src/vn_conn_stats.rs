@@ -0,0 +1,140 @@
+//! link health per MS for `rcn ctl stats`.
+//!
+//! Subcommands publish a snapshot of their sessions as they go and the ctl
+//! socket thread answers with the last one, so asking never touches a
+//! session nor waits for its loop.
+
+use std::{sync::Mutex, time::Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{utils::datagram::Datagram, vn_session::CnSession};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LinkStats {
+    /// msvn path of the peer
+    pub peer: String,
+    /// of the session, see CnSession::set_tenant
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub alive: bool,
+    pub sent: u64,
+    pub sent_bytes: u64,
+    pub received: u64,
+    pub received_bytes: u64,
+    /// sn received again, i.e. MS sent a packet twice
+    pub retransmits: u64,
+    /// heartbeats unanswered when the next one went out
+    pub timeouts: u64,
+    /// since the last heartbeat answer when published, None if none yet
+    pub last_heartbeat_ms: Option<u64>,
+    pub rtt_ms: Option<f64>,
+    /// recv buffer bytes now and at most
+    pub recv_buf: usize,
+    pub recv_buf_max: usize,
+    /// datagrams too large for the recv buffer
+    pub truncated: u64,
+}
+
+impl LinkStats {
+    pub fn of_session<S: Datagram>(session: &CnSession<S>, alive: bool, now: Instant) -> Self {
+        let traffic = session.traffic();
+        let rtt = session.rtt();
+        Self {
+            peer: session.ms_path().display().to_string(),
+            tenant: session.tenant().map(String::from),
+            alive,
+            sent: traffic.sent,
+            sent_bytes: traffic.sent_bytes,
+            received: traffic.received,
+            received_bytes: traffic.received_bytes,
+            retransmits: session.loss().duplicates,
+            timeouts: rtt.timeouts,
+            last_heartbeat_ms: rtt.last_answer.map(|x| now.saturating_duration_since(x).as_millis() as u64),
+            rtt_ms: rtt.ewma.map(|x| x.as_secs_f64() * 1000.0),
+            recv_buf: session.recv_buf().capacity(),
+            recv_buf_max: session.recv_buf().max(),
+            truncated: session.recv_buf().truncated(),
+        }
+    }
+}
+
+static PUBLISHED: Mutex<Vec<LinkStats>> = Mutex::new(Vec::new());
+
+/// replace the snapshot served to `rcn ctl stats`
+pub fn publish(links: Vec<LinkStats>) {
+    *PUBLISHED.lock().unwrap_or_else(|e| e.into_inner()) = links;
+}
+
+pub fn published() -> Vec<LinkStats> {
+    PUBLISHED.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// one row per peer with a header row, tenant column only if any
+/// link has one
+pub fn render_table(links: &[LinkStats]) -> String {
+    let header = ["tenant", "peer", "alive", "sent", "recv", "bytes out", "bytes in", "retrans", "timeouts", "last hb", "rtt", "recv buf"];
+    let mut rows = vec![header.map(String::from).to_vec()];
+    for x in links.iter() {
+        rows.push(vec![
+            x.tenant.clone().unwrap_or_else(|| "-".into()),
+            x.peer.clone(),
+            if x.alive { "yes" } else { "no" }.into(),
+            x.sent.to_string(),
+            x.received.to_string(),
+            x.sent_bytes.to_string(),
+            x.received_bytes.to_string(),
+            x.retransmits.to_string(),
+            x.timeouts.to_string(),
+            x.last_heartbeat_ms.map(|x| format!("{x}ms ago")).unwrap_or_else(|| "-".into()),
+            x.rtt_ms.map(|x| format!("{x:.3}ms")).unwrap_or_else(|| "-".into()),
+            format!("{}/{}", x.recv_buf, x.recv_buf_max),
+        ]);
+    }
+
+    if links.iter().all(|x| x.tenant.is_none()) {
+        rows.iter_mut().for_each(|x| { x.remove(0); });
+    }
+
+    let widths: Vec<usize> = (0..rows[0].len()).map(|i| rows.iter().map(|x| x[i].len()).max().unwrap_or(0)).collect();
+    let mut out = String::new();
+    for row in rows.iter() {
+        let cells: Vec<String> = row.iter().zip(widths.iter()).map(|(cell, w)| format!("{cell:<w$}")).collect();
+        out.push_str(cells.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{publish, published, render_table, LinkStats};
+
+    #[test]
+    fn test_render_table() {
+        let link = LinkStats {
+            peer: "/tmp/cin/msvn".into(),
+            alive: true,
+            sent: 12,
+            received: 10,
+            last_heartbeat_ms: Some(250),
+            rtt_ms: Some(0.5),
+            recv_buf: 1700,
+            recv_buf_max: 65536,
+            ..Default::default()
+        };
+        publish(vec![link.clone()]);
+        assert_eq!(published(), vec![link.clone()]);
+
+        let table = render_table(&[LinkStats { tenant: Some("team-a".into()), ..link.clone() }]);
+        assert!(table.starts_with("tenant  peer"), "{table}");
+        assert!(table.lines().nth(1).unwrap().starts_with("team-a  /tmp/cin/msvn  yes"), "{table}");
+
+        let table = render_table(&[link]);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("peer           alive  sent  recv"));
+        assert!(lines[1].starts_with("/tmp/cin/msvn  yes    12    10"));
+        assert!(lines[1].ends_with("250ms ago  0.500ms  1700/65536"));
+    }
+}
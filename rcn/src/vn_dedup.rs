@@ -0,0 +1,121 @@
+//! de-duplication of retransmitted requests.
+//!
+//! A retransmission carries the code, fsm_id and sn of the original. The last
+//! few of each peer are remembered and a packet seen again among them is a
+//! duplicate, dropped before it has side effects (a second PLAY, a second port
+//! reservation). A peer's window is cleared when it starts over at CNISUP, as
+//! its sns start over too.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::{utils::log_once::warn_first, vn_proto::CodeName};
+
+/// packets remembered per peer by default
+pub const DEFAULT_WINDOW: usize = 64;
+
+type PacketKey = (u16, u32, u16);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    pub checked: u64,
+    pub duplicates: u64,
+    /// duplicates per code
+    pub codes: BTreeMap<u16, u64>,
+}
+
+impl fmt::Display for DedupStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dedup checked [{}] duplicates [{}]", self.checked, self.duplicates)?;
+        for (code, num) in self.codes.iter() {
+            write!(f, ", [{}]: [{num}]", CodeName(*code))?;
+        }
+        Ok(())
+    }
+}
+
+/// last packets of one peer, oldest first
+#[derive(Debug, Default)]
+struct Window {
+    order: VecDeque<PacketKey>,
+    seen: HashSet<PacketKey>,
+}
+
+#[derive(Debug)]
+pub struct DedupWindow {
+    size: usize,
+    /// by sender path, None for unbound senders
+    peers: HashMap<Option<PathBuf>, Window>,
+    stats: DedupStats,
+}
+
+impl DedupWindow {
+    pub fn new(size: usize) -> Self {
+        Self { size: size.max(1), peers: HashMap::new(), stats: DedupStats::default() }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn stats(&self) -> &DedupStats {
+        &self.stats
+    }
+
+    /// true if the packet is new and is to be processed
+    pub fn check(&mut self, from: Option<&Path>, code: u16, fsm_id: u32, sn: u16) -> bool {
+        self.stats.checked += 1;
+        let window = self.peers.entry(from.map(Path::to_path_buf)).or_default();
+        let key = (code, fsm_id, sn);
+        if !window.seen.insert(key) {
+            self.stats.duplicates += 1;
+            *self.stats.codes.entry(code).or_default() += 1;
+            warn_first!(self.stats.duplicates, "drop duplicate {} of fsm_id [{fsm_id}] sn [{sn}] from [{from:?}]", CodeName(code));
+            return false
+        }
+        window.order.push_back(key);
+        if window.order.len() > self.size {
+            if let Some(oldest) = window.order.pop_front() {
+                window.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// forget a peer starting over
+    pub fn reset(&mut self, from: Option<&Path>) {
+        self.peers.remove(&from.map(Path::to_path_buf));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::vn_proto::MCodeType;
+
+    use super::DedupWindow;
+
+    #[test]
+    fn test_dedup_window() {
+        let play = MCodeType::PLAY.code();
+        let (a, b) = (Some(Path::new("/tmp/cn5")), Some(Path::new("/tmp/cn6")));
+        let mut dedup = DedupWindow::new(2);
+        assert!(dedup.check(a, play, 7, 1));
+        assert!(!dedup.check(a, play, 7, 1));
+        // other peer, code or sn
+        assert!(dedup.check(b, play, 7, 1));
+        assert!(dedup.check(a, MCodeType::DTMFRCV.code(), 7, 1));
+        // sn 1 of a pushed out of the window by the last two
+        assert!(dedup.check(a, play, 7, 2));
+        assert!(dedup.check(a, play, 7, 1));
+
+        dedup.reset(a);
+        assert!(dedup.check(a, play, 7, 2));
+        assert_eq!((dedup.stats().checked, dedup.stats().duplicates), (7, 1));
+        assert_eq!(dedup.stats().codes.get(&play), Some(&1));
+    }
+}
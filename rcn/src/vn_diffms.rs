@@ -0,0 +1,189 @@
+//! codec catalogs and capabilities two MS builds advertise in REGISTER,
+//! side by side for upgrade sign-off, see `rcn diffms`.
+//!
+//! Codecs are matched by kind and index, one with a different payload type
+//! or map string is changed, one on a side only is added or removed.
+
+use std::{collections::BTreeMap, fmt, net::Ipv4Addr};
+
+use crate::vn_proto::{Capability, RegisterRef};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CodecKind {
+    Audio,
+    Video,
+    Fax,
+}
+
+impl fmt::Display for CodecKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Audio => "audio",
+            Self::Video => "video",
+            Self::Fax => "fax",
+        })
+    }
+}
+
+/// what a REGISTER advertises
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MsCatalog {
+    pub ip: Ipv4Addr,
+    pub support_t38: bool,
+    pub capabilities: u32,
+    /// (kind, index) -> (payload_type, mapstr)
+    pub codecs: BTreeMap<(CodecKind, u8), (u8, String)>,
+}
+
+impl MsCatalog {
+    pub fn from_register(reg: &RegisterRef<'_>) -> Self {
+        let info = &reg.media_info;
+        let codecs = [(CodecKind::Audio, &info.audio_codecs), (CodecKind::Video, &info.video_codecs), (CodecKind::Fax, &info.fax_codecs)]
+        .into_iter()
+        .flat_map(|(kind, codecs)| codecs.iter().map(move |x| {
+            ((kind, x.index()), (x.payload_type(), String::from_utf8_lossy(x.map_str_data()).into_owned()))
+        }))
+        .collect();
+        Self { ip: reg.ip, support_t38: info.support_t38, capabilities: reg.capabilities(), codecs }
+    }
+}
+
+/// e.g. `zlib,fragment`, unknown bits in hex, `-` for none
+pub fn capability_names(flags: u32) -> String {
+    let mut names = Vec::new();
+    let mut rest = flags;
+    for (flag, name) in [(Capability::ZLIB, "zlib"), (Capability::FRAGMENT, "fragment"), (Capability::EPOCH, "epoch")] {
+        if flags & flag != 0 {
+            names.push(name.to_string());
+            rest &= !flag;
+        }
+    }
+    if rest != 0 {
+        names.push(format!("0x{rest:x}"));
+    }
+    if names.is_empty() {
+        return "-".into()
+    }
+    names.join(",")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffStatus {
+    Same,
+    Changed,
+    /// only in b
+    Added,
+    /// only in a
+    Removed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffRow {
+    /// e.g. "ip", "audio 8"
+    pub item: String,
+    /// "-" if not on that side
+    pub a: String,
+    pub b: String,
+    pub status: DiffStatus,
+}
+
+/// ip, t38 and capabilities, then every codec of either side
+pub fn diff(a: &MsCatalog, b: &MsCatalog) -> Vec<DiffRow> {
+    let row = |item: String, a: Option<String>, b: Option<String>| {
+        let status = match (&a, &b) {
+            (Some(a), Some(b)) if a == b => DiffStatus::Same,
+            (Some(_a), Some(_b)) => DiffStatus::Changed,
+            (None, _) => DiffStatus::Added,
+            (_, None) => DiffStatus::Removed,
+        };
+        DiffRow { item, a: a.unwrap_or_else(|| "-".into()), b: b.unwrap_or_else(|| "-".into()), status }
+    };
+    let yes_no = |x: bool| if x { "yes" } else { "no" }.to_string();
+
+    let mut rows = vec![
+        row("ip".into(), Some(a.ip.to_string()), Some(b.ip.to_string())),
+        row("t38".into(), Some(yes_no(a.support_t38)), Some(yes_no(b.support_t38))),
+        row("capabilities".into(), Some(capability_names(a.capabilities)), Some(capability_names(b.capabilities))),
+    ];
+    let mut keys: Vec<_> = a.codecs.keys().chain(b.codecs.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    let codec = |x: &(u8, String)| format!("{} {}", x.0, x.1);
+    for key in keys {
+        rows.push(row(format!("{} {}", key.0, key.1), a.codecs.get(key).map(codec), b.codecs.get(key).map(codec)));
+    }
+    rows
+}
+
+/// aligned columns, names of both sides as header, ending with a count
+pub fn render_diff(a_name: &str, b_name: &str, rows: &[DiffRow]) -> String {
+    let mut table = vec![["item", a_name, b_name, ""].map(String::from).to_vec()];
+    for row in rows.iter() {
+        let status = match row.status {
+            DiffStatus::Same => "",
+            DiffStatus::Changed => "changed",
+            DiffStatus::Added => "added",
+            DiffStatus::Removed => "removed",
+        };
+        table.push(vec![row.item.clone(), row.a.clone(), row.b.clone(), status.into()]);
+    }
+
+    let widths: Vec<usize> = (0..4).map(|i| table.iter().map(|x| x[i].len()).max().unwrap_or(0)).collect();
+    let mut out = String::new();
+    for row in table.iter() {
+        let cells: Vec<String> = row.iter().zip(widths.iter()).map(|(cell, w)| format!("{cell:<w$}")).collect();
+        out.push_str(cells.join("  ").trim_end());
+        out.push('\n');
+    }
+    let differing = rows.iter().filter(|x| x.status != DiffStatus::Same).count();
+    out.push_str(&format!("[{differing}] of [{}] items differ\n", rows.len()));
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use crate::vn_proto::{Capability, CodecDesc, Register, RegisterRef};
+
+    use super::{diff, render_diff, DiffStatus, MsCatalog};
+
+    fn catalog(reg: &Register) -> MsCatalog {
+        let mut payload = Vec::new();
+        reg.write_to(&mut payload);
+        MsCatalog::from_register(&RegisterRef::parse_from(&payload).unwrap())
+    }
+
+    #[test]
+    fn test_diffms() {
+        let codec = |index, payload_type, mapstr: &str| CodecDesc { index, payload_type, mapstr: mapstr.into() };
+        let old = Register {
+            audio_codecs: vec![codec(0, 0, "PCMU/8000"), codec(8, 8, "PCMA/8000")],
+            video_codecs: vec![codec(100, 96, "H264/90000")],
+            capability: Some(Capability { flags: Capability::ZLIB }),
+            ..Default::default()
+        };
+        let new = Register {
+            ip: Ipv4Addr::new(10, 0, 0, 2),
+            audio_codecs: vec![codec(0, 0, "PCMU/8000"), codec(18, 18, "G729/8000")],
+            video_codecs: vec![codec(100, 97, "H264/90000")],
+            capability: Some(Capability { flags: Capability::ZLIB | Capability::EPOCH | 0x100 }),
+            ..Default::default()
+        };
+
+        assert!(diff(&catalog(&old), &catalog(&old)).iter().all(|x| x.status == DiffStatus::Same));
+        let rows = diff(&catalog(&old), &catalog(&new));
+        let status = |item: &str| rows.iter().find(|x| x.item == item).unwrap().status;
+        assert_eq!(
+            ["ip", "t38", "capabilities", "audio 0", "audio 8", "audio 18", "video 100"].map(status),
+            [DiffStatus::Changed, DiffStatus::Same, DiffStatus::Changed, DiffStatus::Same, DiffStatus::Removed, DiffStatus::Added, DiffStatus::Changed],
+        );
+
+        let table = render_diff("ms-1.2", "ms-1.3", &rows);
+        let lines: Vec<_> = table.lines().collect();
+        assert_eq!(lines[0], "item          ms-1.2         ms-1.3");
+        assert_eq!(lines[3], "capabilities  zlib           zlib,epoch,0x100  changed");
+        assert_eq!(lines[5], "audio 8       8 PCMA/8000    -                 removed");
+        assert_eq!(lines.last(), Some(&"[5] of [7] items differ"));
+    }
+}
@@ -0,0 +1,345 @@
+//! digit maps telling when collected dtmf digits are complete, H.248 style.
+//!
+//! A map is alternatives separated by `|`, optionally in parentheses, e.g.
+//! `[2-9]xxxxxx|0T|#`. Each alternative is a sequence of
+//! - a key `0`-`9`, `*`, `#`, `A`-`D`
+//! - `x` for any of `0`-`9`
+//! - `[..]` for a set of keys and ranges, e.g. `[1-357#]`
+//! - `T` for the inter-digit timer running out
+//!
+//! each optionally followed by `.` for zero or more of it.
+//!
+//! Collection is complete as soon as the digits match an alternative and
+//! no longer one could, see [`DigitMatch`]. An ambiguous match, e.g. `0` of
+//! `0|01`, completes when the timer runs out.
+//! [`collect_digits`] collects DTMFRCV of a channel by a map, ms-sim
+//! collects DTMFRCV of CN the same way when it has MsSimConfig.digit_map.
+
+use std::{fmt, str::FromStr, time::Duration};
+
+use anyhow::{Result, bail};
+use tracing::debug;
+
+use crate::{
+    utils::{clock::timeout_at, datagram::Datagram},
+    vn_proto::{Header, MCodeType},
+    vn_session::CnSession,
+};
+
+/// index of T after the 16 keys
+const TIMER: u32 = 16;
+
+/// index of a key, 0-9, *, #, A-D
+fn key_index(c: char) -> Option<u32> {
+    match c.to_ascii_uppercase() {
+        '0'..='9' => c.to_digit(10),
+        '*' => Some(10),
+        '#' => Some(11),
+        c @ 'A'..='D' => Some(12 + (c as u32 - 'A' as u32)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Element {
+    /// bit per key index, T included
+    set: u32,
+    /// `.` after it
+    repeat: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigitMatch {
+    /// prefix of an alternative, keep collecting
+    Partial,
+    /// matches an alternative but a longer one could too
+    Ambiguous,
+    /// done
+    Complete,
+    /// no alternative can match any more
+    NoMatch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DigitMap {
+    text: String,
+    alternatives: Vec<Vec<Element>>,
+}
+
+impl DigitMap {
+    pub fn parse(s: &str) -> Result<Self> {
+        let text: String = s.chars().filter(|x| !x.is_whitespace()).collect();
+        let inner = match text.strip_prefix('(') {
+            Some(x) => match x.strip_suffix(')') {
+                Some(x) => x,
+                None => bail!("digit map [{s}] missing )"),
+            },
+            None => &text[..],
+        };
+        let alternatives = inner.split('|')
+        .map(|x| parse_alternative(x).map_err(|e| e.context(format!("digit map [{s}]"))))
+        .collect::<Result<Vec<_>>>()?;
+        Ok(Self { text, alternatives })
+    }
+
+    /// digits collected so far, with the timer run out after them or not
+    pub fn check(&self, digits: &str, timed_out: bool) -> DigitMatch {
+        let Some(mut input) = digits.chars().map(key_index).collect::<Option<Vec<u32>>>() else {
+            return DigitMatch::NoMatch
+        };
+        if !timed_out {
+            return self.classify(&input)
+        }
+        let waiting = self.classify(&input);
+        input.push(TIMER);
+        match (self.classify(&input), waiting) {
+            (DigitMatch::Ambiguous | DigitMatch::Complete, _) => DigitMatch::Complete,
+            (_, DigitMatch::Ambiguous | DigitMatch::Complete) => DigitMatch::Complete,
+            _ => DigitMatch::NoMatch,
+        }
+    }
+
+    fn classify(&self, input: &[u32]) -> DigitMatch {
+        let (mut full, mut more) = (false, false);
+        for elements in self.alternatives.iter() {
+            let states = run(elements, input);
+            full |= states[elements.len()];
+            more |= states[..elements.len()].iter().any(|x| *x);
+        }
+        match (full, more) {
+            (true, true) => DigitMatch::Ambiguous,
+            (true, false) => DigitMatch::Complete,
+            (false, true) => DigitMatch::Partial,
+            (false, false) => DigitMatch::NoMatch,
+        }
+    }
+}
+
+fn parse_alternative(s: &str) -> Result<Vec<Element>> {
+    let mut elements: Vec<Element> = Vec::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        let set = match c {
+            '.' => {
+                match elements.last_mut() {
+                    Some(last) if !last.repeat => last.repeat = true,
+                    _ => bail!("[.] without an element before it in [{s}]"),
+                }
+                continue
+            },
+            'x' | 'X' => 0x3ff,
+            'T' | 't' => 1 << TIMER,
+            '[' => {
+                let mut set = 0u32;
+                let mut prev: Option<u32> = None;
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some('-') => {
+                            let (Some(from), Some(to)) = (prev, chars.next().and_then(|x| x.to_digit(10))) else {
+                                bail!("bad range in [{s}]")
+                            };
+                            if from > 9 || to < from {
+                                bail!("bad range in [{s}]")
+                            }
+                            set |= ((1 << (to + 1)) - 1) & !((1 << from) - 1);
+                            prev = None;
+                        },
+                        Some(c) => {
+                            let Some(index) = key_index(c) else { bail!("[{c}] in set of [{s}]") };
+                            set |= 1 << index;
+                            prev = Some(index);
+                        },
+                        None => bail!("missing ] in [{s}]"),
+                    }
+                }
+                if set == 0 {
+                    bail!("empty set in [{s}]")
+                }
+                set
+            },
+            c => match key_index(c) {
+                Some(index) => 1 << index,
+                None => bail!("[{c}] in [{s}]"),
+            },
+        };
+        elements.push(Element { set, repeat: false });
+    }
+    if elements.is_empty() {
+        bail!("empty alternative")
+    }
+    Ok(elements)
+}
+
+/// positions of elements reachable after input, one past the last
+/// meaning matched
+fn run(elements: &[Element], input: &[u32]) -> Vec<bool> {
+    let mut states = vec![false; elements.len() + 1];
+    states[0] = true;
+    skip_repeats(elements, &mut states);
+    for symbol in input.iter() {
+        let mut next = vec![false; elements.len() + 1];
+        for (i, element) in elements.iter().enumerate() {
+            if states[i] && element.set & (1 << symbol) != 0 {
+                next[if element.repeat { i } else { i + 1 }] = true;
+            }
+        }
+        skip_repeats(elements, &mut next);
+        states = next;
+    }
+    states
+}
+
+/// a repeated element may match none
+fn skip_repeats(elements: &[Element], states: &mut [bool]) {
+    for (i, element) in elements.iter().enumerate() {
+        if states[i] && element.repeat {
+            states[i + 1] = true;
+        }
+    }
+}
+
+impl FromStr for DigitMap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for DigitMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DigitTimers {
+    /// for the first digit
+    pub first: Duration,
+    /// between digits, T of the map
+    pub inter: Duration,
+}
+
+impl Default for DigitTimers {
+    fn default() -> Self {
+        Self { first: Duration::from_secs(10), inter: Duration::from_secs(4) }
+    }
+}
+
+/// digits of DTMFRCV on fsm_id until they complete the map, each DTMFRCV
+/// acked with result 0, error if they can't match or the first digit
+/// never comes
+pub async fn collect_digits<S: Datagram>(session: &mut CnSession<S>, fsm_id: u32, map: &DigitMap, timers: DigitTimers) -> Result<String> {
+    let clock = session.clock().clone();
+    let mut digits = String::new();
+    let mut deadline = clock.now() + timers.first;
+    loop {
+        let packet = match timeout_at(clock.as_ref(), deadline, session.recv_packet()).await {
+            Some(r) => r?,
+            None => {
+                if map.check(&digits, true) == DigitMatch::Complete {
+                    return Ok(digits)
+                }
+                bail!("timeout collecting digits of [{fsm_id}], [{digits}] by [{map}]")
+            },
+        };
+        if packet.code() != MCodeType::DTMFRCV.code() || packet.fsm_id() != fsm_id {
+            debug!("skip packet {packet:?} while collecting digits");
+            continue
+        }
+        let keys: Vec<char> = packet.payload().iter().map(|x| *x as char).filter(|x| key_index(*x).is_some()).collect();
+        let header = Header { code: MCodeType::DTMFRCV_ACK.code(), fsm_id, sn: session.next_sn(fsm_id), ..Default::default() };
+        session.send_packet(&header, &[0]).await?;
+
+        for key in keys {
+            digits.push(key);
+            match map.check(&digits, false) {
+                DigitMatch::Complete => return Ok(digits),
+                DigitMatch::NoMatch => bail!("digits [{digits}] of [{fsm_id}] don't match [{map}]"),
+                DigitMatch::Partial | DigitMatch::Ambiguous => {},
+            }
+        }
+        deadline = clock.now() + timers.inter;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        vn_ms_sim::MsSimConfig,
+        vn_proto::{MCodeType, RequestChannel},
+        vn_testkit::MsStub,
+    };
+
+    use super::{collect_digits, DigitMap, DigitMatch, DigitTimers};
+
+    #[test]
+    fn test_digit_map() {
+        let map = DigitMap::parse("[2-9]xxxxxx|0T|#").unwrap();
+        assert_eq!(map.check("", false), DigitMatch::Partial);
+        assert_eq!(map.check("555", false), DigitMatch::Partial);
+        assert_eq!(map.check("5551234", false), DigitMatch::Complete);
+        assert_eq!(map.check("1", false), DigitMatch::NoMatch);
+        assert_eq!(map.check("#", false), DigitMatch::Complete);
+        assert_eq!(map.check("0", false), DigitMatch::Partial);
+        assert_eq!(map.check("0", true), DigitMatch::Complete);
+        assert_eq!(map.check("555", true), DigitMatch::NoMatch);
+        assert_eq!(map.check("5a", false), DigitMatch::NoMatch);
+
+        let map = DigitMap::parse("(0|01x.#|[*Ab]3)").unwrap();
+        assert_eq!(map.to_string(), "(0|01x.#|[*Ab]3)");
+        assert_eq!(map.check("0", false), DigitMatch::Ambiguous);
+        assert_eq!(map.check("0", true), DigitMatch::Complete);
+        assert_eq!(map.check("01", false), DigitMatch::Partial);
+        assert_eq!(map.check("01#", false), DigitMatch::Complete);
+        assert_eq!(map.check("01987#", false), DigitMatch::Complete);
+        assert_eq!(map.check("*3", false), DigitMatch::Complete);
+        assert_eq!(map.check("b3", false), DigitMatch::Complete);
+        assert_eq!(map.check("C3", false), DigitMatch::NoMatch);
+        assert_eq!(map.check("0123", true), DigitMatch::NoMatch);
+
+        let map = DigitMap::parse("[13-5]").unwrap();
+        let matched: String = "0123456".chars().filter(|x| map.check(&x.to_string(), false) == DigitMatch::Complete).collect();
+        assert_eq!(matched, "1345");
+
+        for bad in ["", "1|", ".1", "1..", "[", "[]", "[5-2]", "[-3]", "(12", "1e"] {
+            assert!(DigitMap::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collect_digits() {
+        let config = MsSimConfig {
+            digit_map: Some("[2-9]xxxxxx|0T|#".parse().unwrap()),
+            digit_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let (_ms, mut session) = MsStub::start(config, 5);
+        session.handshake().await.unwrap();
+        session.accept_register().await.unwrap();
+        let fsm_id = session.base_fsm_id() + 1;
+        session.request_channel(fsm_id, &RequestChannel { media_type: 1, webrtc: vec!["".into()], ..Default::default() }).await.unwrap();
+        session.expect_packet(MCodeType::REQUESTCHANNEL_ACK).await.unwrap();
+
+        // keys pressed one by one, ms-sim reports them as the map completes
+        for key in ["55", "5", "1234", "0"] {
+            session.send_request(MCodeType::DTMFRCV, fsm_id, key.as_bytes()).await.unwrap();
+        }
+        let map = DigitMap::parse("xxxxxxx").unwrap();
+        let timers = DigitTimers { first: Duration::from_millis(500), inter: Duration::from_millis(100) };
+        assert_eq!(collect_digits(&mut session, fsm_id, &map, timers).await.unwrap(), "5551234");
+
+        // 0 is due after the timer, checked as the next key comes
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        session.send_request(MCodeType::DTMFRCV, fsm_id, b"#").await.unwrap();
+        let map = DigitMap::parse("[0#]").unwrap();
+        assert_eq!(collect_digits(&mut session, fsm_id, &map, timers).await.unwrap(), "0");
+        assert_eq!(collect_digits(&mut session, fsm_id, &map, timers).await.unwrap(), "#");
+
+        let e = collect_digits(&mut session, fsm_id, &map, timers).await.unwrap_err();
+        assert!(format!("{e:#}").contains("timeout collecting digits"), "{e:#}");
+    }
+}
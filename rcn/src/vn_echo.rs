@@ -0,0 +1,162 @@
+//! echo of a channel's rtp, each datagram sent back to where it came from
+//! after a delay.
+//!
+//! The simplest end-to-end media check, a tester calling through MS hears
+//! themself. Payloads aren't looked at, so any codec works. Echo of a
+//! channel is impaired by its profile, see vn_impair.
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{atomic::{AtomicU64, Ordering}, Arc},
+    time::Duration,
+};
+
+use anyhow::{Result, Context};
+use tokio::{net::UdpSocket, task::JoinHandle, time::Instant};
+use tracing::debug;
+
+use crate::vn_impair::{self, Impairer};
+
+/// datagrams waiting for their delay, more are dropped
+const MAX_QUEUED: usize = 1000;
+
+#[derive(Debug, Default)]
+struct Counts {
+    received: AtomicU64,
+    echoed: AtomicU64,
+    dropped: AtomicU64,
+    impaired: AtomicU64,
+}
+
+/// echo task of one port, stopped when dropped
+#[derive(Debug)]
+pub struct RtpEcho {
+    local: SocketAddr,
+    counts: Arc<Counts>,
+    task: JoinHandle<()>,
+}
+
+impl RtpEcho {
+    pub async fn bind(addr: SocketAddr, delay: Duration) -> Result<Self> {
+        Self::bind_with(addr, delay, None).await
+    }
+
+    /// echo impaired by the profile of fsm_id
+    pub async fn bind_impaired(addr: SocketAddr, delay: Duration, fsm_id: u32, impairer: Impairer) -> Result<Self> {
+        Self::bind_with(addr, delay, Some((fsm_id, impairer))).await
+    }
+
+    async fn bind_with(addr: SocketAddr, delay: Duration, impair: Option<(u32, Impairer)>) -> Result<Self> {
+        let socket = UdpSocket::bind(addr).await.with_context(||format!("bind echo failed [{addr}]"))?;
+        let local = socket.local_addr()?;
+        let counts = Arc::new(Counts::default());
+        let task = tokio::spawn(echo_loop(socket, delay, impair, counts.clone()));
+        Ok(Self { local, counts, task })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+
+    pub fn received(&self) -> u64 {
+        self.counts.received.load(Ordering::Relaxed)
+    }
+
+    pub fn echoed(&self) -> u64 {
+        self.counts.echoed.load(Ordering::Relaxed)
+    }
+
+    /// queue full
+    pub fn dropped(&self) -> u64 {
+        self.counts.dropped.load(Ordering::Relaxed)
+    }
+
+    /// lost by impairment
+    pub fn impaired(&self) -> u64 {
+        self.counts.impaired.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for RtpEcho {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn echo_loop(socket: UdpSocket, delay: Duration, mut impair: Option<(u32, Impairer)>, counts: Arc<Counts>) {
+    let started = Instant::now();
+    let mut buf = vec![0_u8; 2048];
+    let mut queue: VecDeque<(Instant, SocketAddr, Vec<u8>)> = VecDeque::new();
+    loop {
+        let due = queue.front().map(|x| x.0);
+        tokio::select! {
+            r = socket.recv_from(&mut buf) => {
+                // e.g. ECONNREFUSED of an earlier send, keep on
+                let Ok((len, from)) = r.map_err(|e| debug!("echo recv failed [{e}]")) else { continue };
+                counts.received.fetch_add(1, Ordering::Relaxed);
+                if queue.len() >= MAX_QUEUED {
+                    counts.dropped.fetch_add(1, Ordering::Relaxed);
+                    continue
+                }
+                let now = Instant::now();
+                let extra = match &mut impair {
+                    Some((fsm_id, impairer)) => impairer.decide(&vn_impair::profile_of(*fsm_id), now - started, len),
+                    None => Some(Duration::ZERO),
+                };
+                let Some(extra) = extra else {
+                    counts.impaired.fetch_add(1, Ordering::Relaxed);
+                    continue
+                };
+                // impaired ones may overtake, kept in order of due
+                let due = now + delay + extra;
+                let pos = queue.partition_point(|x| x.0 <= due);
+                queue.insert(pos, (due, from, buf[..len].to_vec()));
+            },
+            _r = tokio::time::sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {
+                while queue.front().is_some_and(|x| x.0 <= Instant::now()) {
+                    let Some((_, to, data)) = queue.pop_front() else { break };
+                    match socket.send_to(&data, to).await {
+                        Ok(_) => { counts.echoed.fetch_add(1, Ordering::Relaxed); },
+                        Err(e) => debug!("echo to [{to}] failed [{e}]"),
+                    }
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::{Ipv4Addr, SocketAddr}, time::Duration};
+
+    use tokio::{net::UdpSocket, time::Instant};
+
+    use crate::{utils::rng::SimRng, vn_impair::{set_profile, ImpairProfile, Impairer}};
+
+    use super::RtpEcho;
+
+    #[tokio::test]
+    async fn test_rtp_echo() {
+        let echo = RtpEcho::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), Duration::from_millis(50)).await.unwrap();
+        let peer = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        let start = Instant::now();
+        for n in 0..3_u8 {
+            peer.send_to(&[0x80, 0, 0, n], echo.local_addr()).await.unwrap();
+        }
+        let mut buf = [0_u8; 64];
+        for n in 0..3_u8 {
+            let (len, from) = peer.recv_from(&mut buf).await.unwrap();
+            assert_eq!((&buf[..len], from), (&[0x80, 0, 0, n][..], echo.local_addr()));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!((echo.received(), echo.echoed(), echo.dropped()), (3, 3, 0));
+
+        set_profile(Some(9000102), ImpairProfile { loss_pct: 100.0, ..Default::default() }).unwrap();
+        let echo = RtpEcho::bind_impaired(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), Duration::ZERO, 9000102, Impairer::new(SimRng::new(1))).await.unwrap();
+        peer.send_to(&[0x80, 0, 0, 0], echo.local_addr()).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(100), peer.recv_from(&mut buf)).await.is_err());
+        assert_eq!((echo.received(), echo.echoed(), echo.impaired()), (1, 0, 1));
+    }
+}
@@ -0,0 +1,192 @@
+//! epoch of an MS incarnation, to tell its packets from a previous one.
+//!
+//! CN offers `Capability::EPOCH` in a CAPABILITY tag of CNISUP. An MS having it
+//! answers CNISUP_ACK with its epoch in the [`EPOCH_MASK`] bits of Header.key and
+//! stamps every later packet the same way. A restarted MS comes back with the
+//! next epoch at REGISTER, packets still carrying an older one are stale.
+//! MS builds without it leave those bits 0 and nothing is checked, the key map
+//! of such MS (see vn_key) should mask the epoch bits out.
+
+use std::{collections::VecDeque, fmt};
+
+use tracing::{debug, warn};
+
+use crate::{utils::log_once::warn_first, vn_proto::MCodeType};
+
+/// bits of key carrying the epoch, the fragment flag (see vn_fragment) is left out
+pub const EPOCH_MASK: u16 = 0x7000;
+const EPOCH_SHIFT: u32 = 12;
+/// epochs run 1..=MAX_EPOCH and wrap, 0 means no epoch
+pub const MAX_EPOCH: u16 = EPOCH_MASK >> EPOCH_SHIFT;
+/// older epochs remembered as stale, less than half of them so that wrapped
+/// epochs count as new again
+const RETIRED_LEN: usize = (MAX_EPOCH / 2) as usize;
+
+pub fn epoch_of(key: i16) -> u16 {
+    (key as u16 & EPOCH_MASK) >> EPOCH_SHIFT
+}
+
+/// key with its epoch bits set to epoch
+pub fn with_epoch(key: i16, epoch: u16) -> i16 {
+    ((key as u16 & !EPOCH_MASK) | ((epoch << EPOCH_SHIFT) & EPOCH_MASK)) as i16
+}
+
+/// epoch of the next incarnation
+pub fn next_epoch(epoch: u16) -> u16 {
+    epoch % MAX_EPOCH + 1
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum EpochMode {
+    /// no offer at CNISUP, key taken as is
+    #[default]
+    Off,
+    /// drop packets of stale epochs
+    Discard,
+    /// accept all but log and count packets of stale epochs
+    Warn,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EpochStats {
+    pub accepted: u64,
+    /// dropped, or let through in warn mode
+    pub stale: u64,
+    /// new epochs taken at REGISTER
+    pub changes: u64,
+}
+
+impl fmt::Display for EpochStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "epoch accepted [{}] stale [{}] changes [{}]", self.accepted, self.stale, self.changes)
+    }
+}
+
+/// epoch check of packets from one MS
+#[derive(Debug, Clone, Default)]
+pub struct EpochGuard {
+    mode: EpochMode,
+    /// None until MS answered CNISUP_ACK with an epoch
+    current: Option<u16>,
+    retired: VecDeque<u16>,
+    stats: EpochStats,
+}
+
+impl EpochGuard {
+    pub fn new(mode: EpochMode) -> Self {
+        Self { mode, ..Default::default() }
+    }
+
+    pub fn mode(&self) -> EpochMode {
+        self.mode
+    }
+
+    pub fn current(&self) -> Option<u16> {
+        self.current
+    }
+
+    /// key of CNISUP_ACK, epoch 0 means MS doesn't support it
+    pub fn on_handshake(&mut self, key: i16) {
+        if self.mode == EpochMode::Off {
+            return
+        }
+        match epoch_of(key) {
+            0 => {
+                debug!("MS has no epoch, not checked");
+                self.current = None;
+            },
+            epoch => {
+                debug!("MS epoch [{epoch}]");
+                self.retire();
+                self.current = Some(epoch);
+            },
+        }
+    }
+
+    fn retire(&mut self) {
+        if let Some(current) = self.current.take() {
+            self.retired.retain(|x| *x != current);
+            self.retired.push_back(current);
+            while self.retired.len() > RETIRED_LEN {
+                self.retired.pop_front();
+            }
+        }
+    }
+
+    /// true if a packet with code and key is to be processed,
+    /// REGISTER of a not retired epoch makes it current
+    pub fn check(&mut self, code: u16, key: i16) -> bool {
+        let Some(current) = self.current else {
+            return true
+        };
+        let epoch = epoch_of(key);
+        if epoch == current {
+            self.stats.accepted += 1;
+            return true
+        }
+
+        if code == MCodeType::REGISTER.code() && epoch != 0 && !self.retired.contains(&epoch) {
+            warn!("MS epoch [{current}] -> [{epoch}], restarted");
+            self.retire();
+            self.current = Some(epoch);
+            self.stats.changes += 1;
+            self.stats.accepted += 1;
+            return true
+        }
+
+        self.stats.stale += 1;
+        let drop = self.mode == EpochMode::Discard;
+        warn_first!(self.stats.stale, "packet 0x{code:04x} of stale epoch [{epoch}], current [{current}], {}", if drop { "dropped" } else { "accepted (warn only)" });
+        !drop
+    }
+
+    pub fn stats(&self) -> &EpochStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::vn_proto::MCodeType;
+
+    use super::{epoch_of, next_epoch, with_epoch, EpochGuard, EpochMode, MAX_EPOCH};
+
+    #[test]
+    fn test_epoch_guard() {
+        let key = with_epoch(0x0102, 3);
+        assert_eq!(key, 0x3102);
+        assert_eq!(epoch_of(key), 3);
+        assert_eq!(with_epoch(key, 5), 0x5102);
+        assert_eq!(next_epoch(MAX_EPOCH), 1);
+
+        let play_ack = MCodeType::PLAY_ACK.code();
+        let register = MCodeType::REGISTER.code();
+        let mut guard = EpochGuard::new(EpochMode::Discard);
+        guard.on_handshake(with_epoch(0, 3));
+        assert_eq!(guard.current(), Some(3));
+        assert!(guard.check(play_ack, with_epoch(7, 3)));
+        assert!(!guard.check(play_ack, with_epoch(7, 2)));
+
+        // restarted, old incarnation still talking
+        assert!(guard.check(register, with_epoch(0, 4)));
+        assert_eq!(guard.current(), Some(4));
+        assert!(!guard.check(play_ack, with_epoch(0, 3)));
+        assert!(!guard.check(register, with_epoch(0, 3)));
+        assert!(guard.check(play_ack, with_epoch(0, 4)));
+        let s = guard.stats();
+        assert_eq!((s.accepted, s.stale, s.changes), (3, 3, 1));
+        assert_eq!(s.to_string(), "epoch accepted [3] stale [3] changes [1]");
+
+        // MS without epochs
+        let mut guard = EpochGuard::new(EpochMode::Discard);
+        guard.on_handshake(0);
+        assert!(guard.check(play_ack, with_epoch(0, 2)));
+        assert_eq!(guard.stats().stale, 0);
+
+        let mut guard = EpochGuard::new(EpochMode::Warn);
+        guard.on_handshake(with_epoch(0, 1));
+        assert!(guard.check(play_ack, 0));
+        assert_eq!(guard.stats().stale, 1);
+    }
+}
@@ -0,0 +1,656 @@
+//! channel lifecycle events and CDRs published to NATS or Kafka, so the
+//! canaries of every lab feed one dashboard without scraping logs.
+//!
+//! An [`EventPublisher`] set on a ChannelRegistry (see vn_channels) gets a
+//! [`ChannelEvent`] as each channel is requested, answered and ended, the
+//! last one carrying its CDR (see vn_cdr). Events are json, queued and sent
+//! by a task of their own which reconnects and resends on failure. A broker
+//! falling [`EVENT_BACKLOG`] events behind gets later ones dropped and
+//! counted rather than slowing channels down.
+//!
+//! Both protocols are spoken directly, publish only: NATS core `PUB`, and
+//! Kafka Produce v3 to partition 0 of the topic. The broker given is asked
+//! with Metadata v0 which broker leads that partition and events go there,
+//! a leader moving away fails a batch and the next connect asks again.
+
+use std::{
+    collections::HashMap,
+    fmt, str::FromStr,
+    sync::{atomic::{AtomicU64, Ordering}, Arc},
+    time::Duration,
+};
+
+use anyhow::{Result, Context, bail};
+use bytes::{Buf, BufMut};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpStream},
+    sync::mpsc,
+};
+use tracing::{info, warn};
+
+use crate::{utils::{async_rt::spawn_with_name, log_once::warn_first}, vn_cdr::Cdr, vn_channels::unix_ms};
+
+/// events queued for the broker at most
+pub const EVENT_BACKLOG: usize = 4096;
+
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// events of one Produce request at most
+const MAX_BATCH: usize = 256;
+
+/// `nats://HOST:PORT/SUBJECT` or `kafka://HOST:PORT/TOPIC`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventTarget {
+    Nats { addr: String, subject: String },
+    Kafka { addr: String, topic: String },
+}
+
+impl FromStr for EventTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (scheme, rest) = s.split_once("://").with_context(||format!("invalid event target [{s}], expect nats://HOST:PORT/SUBJECT or kafka://HOST:PORT/TOPIC"))?;
+        let (addr, name) = rest.split_once('/').with_context(||format!("no subject or topic in [{s}]"))?;
+        if addr.is_empty() || name.is_empty() || name.contains(|x: char| x.is_whitespace() || x == '/') {
+            bail!("invalid event target [{s}]")
+        }
+        let (addr, name) = (addr.to_string(), name.to_string());
+        match scheme {
+            "nats" => Ok(Self::Nats { addr, subject: name }),
+            "kafka" => Ok(Self::Kafka { addr, topic: name }),
+            _ => bail!("unknown scheme [{scheme}] of [{s}], expect nats or kafka"),
+        }
+    }
+}
+
+impl fmt::Display for EventTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Nats { addr, subject } => write!(f, "nats://{addr}/{subject}"),
+            Self::Kafka { addr, topic } => write!(f, "kafka://{addr}/{topic}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum ChannelEvent {
+    /// REQUESTCHANNEL sent
+    Requested {
+        fsm_id: u32,
+        at_ms: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tenant: Option<String>,
+    },
+    /// REQUESTCHANNEL_ACK received
+    Answered {
+        fsm_id: u32,
+        at_ms: u64,
+        setup_ms: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        result: Option<u8>,
+    },
+    Ended(Cdr),
+}
+
+/// what goes on the wire, one json object per message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventRecord {
+    /// lab or host the events come from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(flatten)]
+    pub event: ChannelEvent,
+}
+
+#[derive(Clone)]
+pub struct EventPublisher {
+    tx: mpsc::Sender<Vec<u8>>,
+    source: Option<String>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl fmt::Debug for EventPublisher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventPublisher")
+        .field("source", &self.source)
+        .field("dropped", &self.dropped())
+        .finish()
+    }
+}
+
+impl EventPublisher {
+    /// sender task runs until every clone is dropped
+    pub fn spawn(target: EventTarget, source: Option<String>) -> Self {
+        let (tx, rx) = mpsc::channel(EVENT_BACKLOG);
+        spawn_with_name("events", run_sender(target, rx));
+        Self { tx, source, dropped: Arc::default() }
+    }
+
+    /// queued, never waits for the broker
+    pub fn publish(&self, event: ChannelEvent) {
+        let record = EventRecord { source: self.source.clone(), event };
+        let json = match serde_json::to_vec(&record) {
+            Ok(json) => json,
+            Err(e) => return warn!("encode event failed [{e}]"),
+        };
+        if self.tx.try_send(json).is_err() {
+            let n = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            warn_first!(n, "event backlog full, dropped [{n}]");
+        }
+    }
+
+    /// events not queued as the backlog was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// events taken from rx but not sent yet are kept and sent again after a reconnect
+async fn run_sender(target: EventTarget, mut rx: mpsc::Receiver<Vec<u8>>) {
+    let mut pending = Vec::new();
+    loop {
+        let r = match &target {
+            EventTarget::Nats { addr, subject } => match Nats::connect(addr).await {
+                Ok(mut conn) => {
+                    info!("publishing events to [{target}]");
+                    conn.run(subject, &mut rx, &mut pending).await
+                },
+                Err(e) => Err(e),
+            },
+            EventTarget::Kafka { addr, topic } => match Kafka::connect(addr, topic).await {
+                Ok(mut conn) => {
+                    info!("publishing events to [{target}]");
+                    conn.run(topic, &mut rx, &mut pending).await
+                },
+                Err(e) => Err(e),
+            },
+        };
+        match r {
+            Ok(()) => return,
+            Err(e) => warn!("events to [{target}] failed [{e:#}], [{}] pending, retry in [{RECONNECT_DELAY:?}]", pending.len()),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// wait for one event, then take what else is queued up to MAX_BATCH,
+/// false if every publisher is gone
+async fn recv_batch(rx: &mut mpsc::Receiver<Vec<u8>>, pending: &mut Vec<Vec<u8>>) -> bool {
+    let Some(first) = rx.recv().await else { return false };
+    pending.push(first);
+    while pending.len() < MAX_BATCH {
+        match rx.try_recv() {
+            Ok(x) => pending.push(x),
+            Err(_e) => break,
+        }
+    }
+    true
+}
+
+async fn connect_tcp(addr: &str) -> Result<TcpStream> {
+    tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await
+    .with_context(||format!("connect [{addr}] timeout"))?
+    .with_context(||format!("connect [{addr}] failed"))
+}
+
+struct Nats {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl Nats {
+    /// INFO, CONNECT, and a PING answered to know CONNECT was taken
+    async fn connect(addr: &str) -> Result<Self> {
+        let (reader, writer) = connect_tcp(addr).await?.into_split();
+        let mut me = Self { lines: BufReader::new(reader).lines(), writer };
+        let info = me.next_line().await?;
+        if !info.starts_with("INFO") {
+            bail!("expect INFO from nats but [{info}]")
+        }
+        let connect = format!(
+            "CONNECT {{\"verbose\":false,\"pedantic\":false,\"name\":\"rcn\",\"lang\":\"rust\",\"version\":\"{}\"}}\r\nPING\r\n",
+            env!("CARGO_PKG_VERSION"),
+        );
+        me.writer.write_all(connect.as_bytes()).await?;
+        loop {
+            match me.next_line().await?.as_str() {
+                "PONG" => return Ok(me),
+                line if line.starts_with("-ERR") => bail!("nats refused CONNECT [{line}]"),
+                _ => {},
+            }
+        }
+    }
+
+    async fn next_line(&mut self) -> Result<String> {
+        self.lines.next_line().await?.with_context(||"closed by nats")
+    }
+
+    async fn run(&mut self, subject: &str, rx: &mut mpsc::Receiver<Vec<u8>>, pending: &mut Vec<Vec<u8>>) -> Result<()> {
+        loop {
+            if !pending.is_empty() {
+                let mut out = Vec::new();
+                for payload in pending.iter() {
+                    nats_pub(&mut out, subject, payload);
+                }
+                self.writer.write_all(&out).await?;
+                pending.clear();
+            }
+            tokio::select! {
+                // next_line is cancel safe
+                line = self.lines.next_line() => match line?.as_deref() {
+                    None => bail!("closed by nats"),
+                    Some("PING") => self.writer.write_all(b"PONG\r\n").await?,
+                    Some(line) if line.starts_with("-ERR") => bail!("nats error [{line}]"),
+                    Some(_line) => {},
+                },
+                more = recv_batch(rx, pending) => if !more {
+                    self.writer.flush().await?;
+                    return Ok(())
+                },
+            }
+        }
+    }
+}
+
+fn nats_pub(out: &mut Vec<u8>, subject: &str, payload: &[u8]) {
+    out.extend_from_slice(format!("PUB {subject} {}\r\n", payload.len()).as_bytes());
+    out.extend_from_slice(payload);
+    out.extend_from_slice(b"\r\n");
+}
+
+struct Kafka {
+    stream: TcpStream,
+    correlation_id: i32,
+}
+
+impl Kafka {
+    /// to the leader of partition 0 of topic, as the broker at addr tells
+    async fn connect(addr: &str, topic: &str) -> Result<Self> {
+        let mut me = Self { stream: connect_tcp(addr).await?, correlation_id: 0 };
+        me.correlation_id += 1;
+        me.stream.write_all(&metadata_request(topic, me.correlation_id)).await?;
+        let body = me.read_response("Metadata").await?;
+        let leader = metadata_leader(&body, me.correlation_id, topic)?;
+        if leader != addr {
+            info!("partition 0 of [{topic}] led by [{leader}]");
+            me.stream = connect_tcp(&leader).await?;
+        }
+        Ok(me)
+    }
+
+    async fn read_response(&mut self, api: &str) -> Result<Vec<u8>> {
+        let size = self.stream.read_i32().await?;
+        if !(4..=1 << 20).contains(&size) {
+            bail!("invalid {api} response size [{size}]")
+        }
+        let mut body = vec![0_u8; size as usize];
+        self.stream.read_exact(&mut body).await?;
+        Ok(body)
+    }
+
+    /// a batch is done once the broker answered it, acks=1
+    async fn run(&mut self, topic: &str, rx: &mut mpsc::Receiver<Vec<u8>>, pending: &mut Vec<Vec<u8>>) -> Result<()> {
+        loop {
+            if !pending.is_empty() {
+                self.correlation_id = self.correlation_id.wrapping_add(1);
+                let request = produce_request(topic, self.correlation_id, pending, unix_ms() as i64);
+                self.stream.write_all(&request).await?;
+                let body = self.read_response("Produce").await?;
+                match produce_error(&body, self.correlation_id)? {
+                    0 => pending.clear(),
+                    code => bail!("Produce to [{topic}] failed, error [{}] code [{code}]", kafka_error(code)),
+                }
+            }
+            if !recv_batch(rx, pending).await {
+                return Ok(())
+            }
+        }
+    }
+}
+
+/// Metadata v0 of topic, with its size in front
+fn metadata_request(topic: &str, correlation_id: i32) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.put_i16(3);
+    body.put_i16(0);
+    body.put_i32(correlation_id);
+    put_str(&mut body, "rcn");
+    body.put_i32(1);
+    put_str(&mut body, topic);
+
+    let mut out = Vec::with_capacity(4 + body.len());
+    out.put_i32(body.len() as i32);
+    out.put_slice(&body);
+    out
+}
+
+/// host:port of the leader of partition 0 of topic in a Metadata v0 response
+fn metadata_leader(mut body: &[u8], correlation_id: i32, topic: &str) -> Result<String> {
+    let body = &mut body;
+    let id = get_i32(body)?;
+    if id != correlation_id {
+        bail!("Metadata response of [{id}] but expect [{correlation_id}]")
+    }
+    let mut brokers = HashMap::new();
+    for _ in 0..get_i32(body)? {
+        let node_id = get_i32(body)?;
+        let host = get_str(body)?;
+        let port = get_i32(body)?;
+        brokers.insert(node_id, format!("{host}:{port}"));
+    }
+    let mut leader = None;
+    for _ in 0..get_i32(body)? {
+        let error = get_i16(body)?;
+        let name = get_str(body)?;
+        if name == topic && error != 0 {
+            bail!("Metadata of [{topic}] failed, error [{}] code [{error}]", kafka_error(error))
+        }
+        for _ in 0..get_i32(body)? {
+            let error = get_i16(body)?;
+            let partition = get_i32(body)?;
+            let node_id = get_i32(body)?;
+            // replicas and isr
+            for _ in 0..2 {
+                for _ in 0..get_i32(body)? {
+                    get_i32(body)?;
+                }
+            }
+            if name == topic && partition == 0 {
+                if error != 0 {
+                    bail!("partition 0 of [{topic}] failed, error [{}] code [{error}]", kafka_error(error))
+                }
+                leader = Some(node_id);
+            }
+        }
+    }
+    let node_id = leader.with_context(||format!("no partition 0 of [{topic}] in Metadata"))?;
+    brokers.remove(&node_id).with_context(||format!("leader [{node_id}] of [{topic}] not among brokers"))
+}
+
+fn get_i16(body: &mut &[u8]) -> Result<i16> {
+    if body.remaining() < 2 {
+        bail!("response too short")
+    }
+    Ok(body.get_i16())
+}
+
+fn get_i32(body: &mut &[u8]) -> Result<i32> {
+    if body.remaining() < 4 {
+        bail!("response too short")
+    }
+    Ok(body.get_i32())
+}
+
+fn get_str(body: &mut &[u8]) -> Result<String> {
+    let len = get_i16(body)?.max(0) as usize;
+    if body.remaining() < len {
+        bail!("response too short")
+    }
+    let s = String::from_utf8_lossy(&body[..len]).into_owned();
+    body.advance(len);
+    Ok(s)
+}
+
+/// name of a kafka error code, as the protocol guide has it
+fn kafka_error(code: i16) -> &'static str {
+    match code {
+        0 => "NONE",
+        1 => "OFFSET_OUT_OF_RANGE",
+        2 => "CORRUPT_MESSAGE",
+        3 => "UNKNOWN_TOPIC_OR_PARTITION",
+        5 => "LEADER_NOT_AVAILABLE",
+        6 => "NOT_LEADER_OR_FOLLOWER",
+        7 => "REQUEST_TIMED_OUT",
+        8 => "BROKER_NOT_AVAILABLE",
+        9 => "REPLICA_NOT_AVAILABLE",
+        10 => "MESSAGE_TOO_LARGE",
+        19 => "NOT_ENOUGH_REPLICAS",
+        20 => "NOT_ENOUGH_REPLICAS_AFTER_APPEND",
+        29 => "TOPIC_AUTHORIZATION_FAILED",
+        35 => "UNSUPPORTED_VERSION",
+        87 => "INVALID_RECORD",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Produce v3 of values to partition 0 of topic, with its size in front
+fn produce_request(topic: &str, correlation_id: i32, values: &[Vec<u8>], timestamp_ms: i64) -> Vec<u8> {
+    let batch = record_batch(values, timestamp_ms);
+    let mut body = Vec::new();
+    // header: api_key Produce, api_version, correlation_id, client_id
+    body.put_i16(0);
+    body.put_i16(3);
+    body.put_i32(correlation_id);
+    put_str(&mut body, "rcn");
+    // transactional_id null, acks, timeout_ms
+    body.put_i16(-1);
+    body.put_i16(1);
+    body.put_i32(5000);
+    body.put_i32(1);
+    put_str(&mut body, topic);
+    body.put_i32(1);
+    body.put_i32(0);
+    body.put_i32(batch.len() as i32);
+    body.put_slice(&batch);
+
+    let mut out = Vec::with_capacity(4 + body.len());
+    out.put_i32(body.len() as i32);
+    out.put_slice(&body);
+    out
+}
+
+/// RecordBatch v2, no key and headers, uncompressed
+fn record_batch(values: &[Vec<u8>], timestamp_ms: i64) -> Vec<u8> {
+    let mut records = Vec::new();
+    for (i, value) in values.iter().enumerate() {
+        let mut record = Vec::new();
+        record.put_i8(0);
+        put_varint(&mut record, 0);
+        put_varint(&mut record, i as i64);
+        put_varint(&mut record, -1);
+        put_varint(&mut record, value.len() as i64);
+        record.put_slice(value);
+        put_varint(&mut record, 0);
+        put_varint(&mut records, record.len() as i64);
+        records.extend_from_slice(&record);
+    }
+
+    // from attributes on, what the crc covers
+    let mut tail = Vec::new();
+    tail.put_i16(0);
+    tail.put_i32(values.len() as i32 - 1);
+    tail.put_i64(timestamp_ms);
+    tail.put_i64(timestamp_ms);
+    // producer_id, producer_epoch, base_sequence: not idempotent
+    tail.put_i64(-1);
+    tail.put_i16(-1);
+    tail.put_i32(-1);
+    tail.put_i32(values.len() as i32);
+    tail.extend_from_slice(&records);
+
+    let mut batch = Vec::with_capacity(21 + tail.len());
+    batch.put_i64(0);
+    // partition_leader_epoch, magic and crc before tail
+    batch.put_i32((4 + 1 + 4 + tail.len()) as i32);
+    batch.put_i32(-1);
+    batch.put_i8(2);
+    batch.put_u32(crc32c(&tail));
+    batch.put_slice(&tail);
+    batch
+}
+
+/// error code of the first partition of a Produce v3 response
+fn produce_error(mut body: &[u8], correlation_id: i32) -> Result<i16> {
+    let need = |body: &[u8], n: usize| if body.remaining() < n { bail!("Produce response too short") } else { Ok(()) };
+    need(body, 8)?;
+    let id = body.get_i32();
+    if id != correlation_id {
+        bail!("Produce response of [{id}] but expect [{correlation_id}]")
+    }
+    if body.get_i32() < 1 {
+        bail!("Produce response without topic")
+    }
+    need(body, 2)?;
+    let len = body.get_i16().max(0) as usize;
+    need(body, len + 4)?;
+    body.advance(len);
+    if body.get_i32() < 1 {
+        bail!("Produce response without partition")
+    }
+    need(body, 6)?;
+    let _partition = body.get_i32();
+    Ok(body.get_i16())
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.put_i16(s.len() as i16);
+    buf.put_slice(s.as_bytes());
+}
+
+/// zigzag varint of kafka records
+fn put_varint(buf: &mut Vec<u8>, v: i64) {
+    let mut z = ((v << 1) ^ (v >> 63)) as u64;
+    while z >= 0x80 {
+        buf.put_u8(z as u8 | 0x80);
+        z >>= 7;
+    }
+    buf.put_u8(z as u8);
+}
+
+/// CRC-32C (Castagnoli) of RecordBatch
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use bytes::{Buf, BufMut};
+    use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::TcpListener};
+
+    use super::{crc32c, kafka_error, metadata_leader, metadata_request, produce_error, produce_request, put_str, ChannelEvent, EventPublisher, EventRecord, EventTarget};
+
+    #[tokio::test]
+    async fn test_events() {
+        let target: EventTarget = "kafka://broker:9092/rcn-events".parse().unwrap();
+        assert_eq!(target, EventTarget::Kafka { addr: "broker:9092".into(), topic: "rcn-events".into() });
+        assert_eq!(target.to_string(), "kafka://broker:9092/rcn-events");
+        for s in ["nats://host:4222", "nats://host:4222/", "mqtt://host/x", "nats:///x", "nats://h/a b"] {
+            assert!(s.parse::<EventTarget>().is_err(), "{s}");
+        }
+
+        // Produce v3 as a broker reads it
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        let request = produce_request("rcn-events", 7, &[b"{}".to_vec(), vec![b'x'; 200]], 1_700_000_000_000);
+        let mut buf = &request[..];
+        assert_eq!(buf.get_i32() as usize, buf.remaining());
+        assert_eq!((buf.get_i16(), buf.get_i16(), buf.get_i32()), (0, 3, 7));
+        let client_id = buf.get_i16() as usize;
+        buf.advance(client_id);
+        assert_eq!((buf.get_i16(), buf.get_i16(), buf.get_i32(), buf.get_i32()), (-1, 1, 5000, 1));
+        let topic = buf.get_i16() as usize;
+        assert_eq!(&buf[..topic], b"rcn-events");
+        buf.advance(topic);
+        assert_eq!((buf.get_i32(), buf.get_i32()), (1, 0));
+        assert_eq!(buf.get_i32() as usize, buf.remaining());
+        let mut b = buf;
+        assert_eq!(b.get_i64(), 0);
+        assert_eq!(b.get_i32() as usize, b.remaining());
+        assert_eq!((b.get_i32(), b.get_i8()), (-1, 2));
+        let crc = b.get_u32();
+        assert_eq!(crc, crc32c(b));
+        assert_eq!(b[b.len() - 200 - 1], b'x');
+
+        let mut response = Vec::new();
+        response.put_i32(7);
+        response.put_i32(1);
+        response.put_i16(3);
+        response.put_slice(b"rcn");
+        response.put_i32(1);
+        response.put_i32(0);
+        response.put_i16(6);
+        assert_eq!(produce_error(&response, 7).unwrap(), 6);
+        assert!(produce_error(&response, 8).is_err());
+        assert!(produce_error(&response[..12], 7).is_err());
+        assert_eq!(kafka_error(6), "NOT_LEADER_OR_FOLLOWER");
+
+        // partition 0 led by broker 2 of two
+        let request = metadata_request("rcn-events", 9);
+        assert_eq!(&request[4..8], &[0, 3, 0, 0]);
+        let metadata = |topic_error: i16| {
+            let mut response = Vec::new();
+            response.put_i32(9);
+            response.put_i32(2);
+            for (node_id, host) in [(1, "kafka-1"), (2, "kafka-2")] {
+                response.put_i32(node_id);
+                put_str(&mut response, host);
+                response.put_i32(9092);
+            }
+            response.put_i32(1);
+            response.put_i16(topic_error);
+            put_str(&mut response, "rcn-events");
+            response.put_i32(2);
+            for (partition, leader) in [(1, 1), (0, 2)] {
+                response.put_i16(0);
+                response.put_i32(partition);
+                response.put_i32(leader);
+                response.put_i32(1);
+                response.put_i32(leader);
+                response.put_i32(0);
+            }
+            response
+        };
+        assert_eq!(metadata_leader(&metadata(0), 9, "rcn-events").unwrap(), "kafka-2:9092");
+        assert!(metadata_leader(&metadata(0), 9, "other").is_err());
+        let e = metadata_leader(&metadata(3), 9, "rcn-events").unwrap_err();
+        assert!(format!("{e:#}").contains("UNKNOWN_TOPIC_OR_PARTITION"), "{e:#}");
+        assert!(metadata_leader(&metadata(0)[..30], 9, "rcn-events").is_err());
+
+        // a NATS server gets CONNECT, then each event as PUB
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (stream, _addr) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            writer.write_all(b"INFO {\"server_id\":\"test\"}\r\n").await.unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            assert!(line.starts_with("CONNECT {"), "{line}");
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "PING\r\n");
+            writer.write_all(b"PONG\r\n").await.unwrap();
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            let len: usize = line.trim_end().strip_prefix("PUB rcn.events ").unwrap().parse().unwrap();
+            let mut payload = vec![0_u8; len + 2];
+            reader.read_exact(&mut payload).await.unwrap();
+            payload.truncate(len);
+            payload
+        });
+
+        let publisher = EventPublisher::spawn(EventTarget::Nats { addr, subject: "rcn.events".into() }, Some("lab-3".into()));
+        let event = ChannelEvent::Requested { fsm_id: 5000001, at_ms: 1, tenant: None };
+        publisher.publish(event.clone());
+        let payload = tokio::time::timeout(Duration::from_secs(3), server).await.unwrap().unwrap();
+        let text = String::from_utf8(payload.clone()).unwrap();
+        assert!(text.contains("\"event\":\"requested\""), "{text}");
+        let record: EventRecord = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(record, EventRecord { source: Some("lab-3".into()), event });
+        assert_eq!(publisher.dropped(), 0);
+    }
+}
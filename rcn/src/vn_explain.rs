@@ -0,0 +1,381 @@
+//! field by field walk through a packet with offsets, raw bytes and what
+//! each field means, for learning the VN protocol from real packets.
+
+use std::{fmt, net::Ipv4Addr};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    vn_charset::decode_str,
+    vn_proto::{MCodeType, TagType, HEADER_LENGTH},
+    vn_redact::mask_packet,
+};
+
+/// description of every field name the explainer emits
+pub static FIELD_TABLE: &[(&str, &str)] = &[
+    ("length", "bytes following this field, cn path excluded"),
+    ("code", "unknown message code"),
+    ("fsm_id", "channel id, base of the CN namespace (cn_id * 1000000 by default) + n"),
+    ("key", "routing key set by CN, e.g. board"),
+    ("sn", "sequence number of request, echoed in ACK"),
+    ("ice_type", "0 none, 1 ice lite, 2 full ice"),
+    ("life_seconds", "channel released by MS after this, 0 forever"),
+    ("media_type", "1 audio, 2 video, 3 fax, 4/7 agora"),
+    ("as_call_id", "call id of application server"),
+    ("agora_info", "agora channel and token, agora media types only"),
+    ("is_nbup", "1 if Nb user plane framing"),
+    ("ptime", "rtp packetization time in ms"),
+    ("is_caller", "1 if calling side"),
+    ("codec", "codec index, 0xff lets MS choose"),
+    ("amr_mode", "amr codec mode set"),
+    ("webrtc", "webrtc line, empty or null_<name> if absent"),
+    ("result", "0 ok, others are errors"),
+    ("audio_port", "local rtp port of audio"),
+    ("video_port", "local rtp port of video"),
+    ("fax_port", "local port of fax"),
+    ("interval", "ms between repeated plays"),
+    ("play_times", "times to play the file list"),
+    ("max_duration", "stop after this many ms, 0 no limit"),
+    ("key_mask", "dtmf keys stopping play"),
+    ("record", "1 to record while playing"),
+    ("speech_barge", "1 if speech stops play"),
+    ("erase_dtmf", "1 to clear collected dtmf first"),
+    ("num_tlv", "number of tags following"),
+    ("tag_type", "type of tag"),
+    ("tag_length", "bytes of tag value"),
+    ("format", "file format, 100 wav"),
+    ("filename", "file to play, file://xxx is relative to prompt root"),
+    ("play_duration", "ms actually played"),
+    ("num_tags", "number of tags following"),
+    ("ip", "ipv4 address"),
+    ("port", "rtp port"),
+    ("internal_pltyp", "payload type used inside MS"),
+    ("nego_pltyp", "payload type negotiated with peer"),
+    ("attribute", "extra sdp attribute"),
+    ("tele_event", "payload type of telephone-event"),
+    ("direction", "0 sendrecv, 1 sendonly, 2 recvonly, 3 inactive"),
+    ("support_t38", "0 if MS supports t38 fax"),
+    ("num_codecs", "number of codecs following"),
+    ("codec_index", "index of codec"),
+    ("payload_type", "rtp payload type"),
+    ("mapstr", "rtpmap string, e.g. PCMA/8000"),
+    ("capabilities", "CAPABILITY flags, 0x1 zlib, 0x2 fragment"),
+    ("value", "result text, e.g. recognized speech"),
+    ("op_code", "code of the operation to cancel"),
+    ("cn_path", "socket of CN, appended after the packet"),
+    ("unparsed", "bytes not matching the expected layout"),
+];
+
+pub fn field_description(name: &str) -> &'static str {
+    FIELD_TABLE.iter().find(|x| x.0 == name).map(|x| x.1).unwrap_or("")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExplainedField {
+    pub offset: usize,
+    pub raw: Vec<u8>,
+    pub name: &'static str,
+    pub value: String,
+    /// from FIELD_TABLE, or of the message code for codes
+    pub description: &'static str,
+}
+
+/// raw bytes shown per line, longer fields end with ".."
+const RAW_SHOWN: usize = 8;
+
+impl fmt::Display for ExplainedField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut raw: Vec<String> = self.raw.iter().take(RAW_SHOWN).map(|x| format!("{x:02x}")).collect();
+        if self.raw.len() > RAW_SHOWN {
+            raw.push("..".into());
+        }
+        write!(f, "{:>5}  {:<27} {:<15} {:<24} {}", self.offset, raw.join(" "), self.name, self.value, self.description)
+    }
+}
+
+struct Explainer<'a> {
+    data: &'a [u8],
+    pos: usize,
+    fields: Vec<ExplainedField>,
+}
+
+impl<'a> Explainer<'a> {
+    fn take(&mut self, name: &'static str, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < self.pos + len {
+            bail!("[{name}] needs [{len}] bytes at [{}] but [{}] left", self.pos, self.data.len() - self.pos)
+        }
+        let raw = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(raw)
+    }
+
+    fn push(&mut self, offset: usize, name: &'static str, value: String) {
+        let raw = self.data[offset..self.pos].to_vec();
+        self.fields.push(ExplainedField { offset, raw, name, value, description: field_description(name) });
+    }
+
+    fn uint(&mut self, name: &'static str, len: usize) -> Result<u32> {
+        let offset = self.pos;
+        let v = self.take(name, len)?.iter().fold(0_u32, |acc, x| acc << 8 | *x as u32);
+        self.push(offset, name, v.to_string());
+        Ok(v)
+    }
+
+    fn u8(&mut self, name: &'static str) -> Result<u8> {
+        Ok(self.uint(name, 1)? as u8)
+    }
+
+    fn u16(&mut self, name: &'static str) -> Result<u16> {
+        Ok(self.uint(name, 2)? as u16)
+    }
+
+    fn code(&mut self, name: &'static str) -> Result<u16> {
+        let offset = self.pos;
+        let raw = self.take(name, 2)?;
+        let code = u16::from_be_bytes([raw[0], raw[1]]);
+        match MCodeType::try_from(code) {
+            Ok(c) => {
+                self.push(offset, name, format!("{c:?}"));
+                if let (true, Some(last)) = (name == "code", self.fields.last_mut()) {
+                    last.description = c.description();
+                }
+            },
+            Err(_e) => self.push(offset, name, format!("0x{code:04x} unknown")),
+        }
+        Ok(code)
+    }
+
+    fn ip(&mut self, name: &'static str) -> Result<()> {
+        let offset = self.pos;
+        let raw = self.take(name, 4)?;
+        self.push(offset, name, Ipv4Addr::new(raw[0], raw[1], raw[2], raw[3]).to_string());
+        Ok(())
+    }
+
+    /// null terminated string, null included in raw
+    fn str(&mut self, name: &'static str) -> Result<()> {
+        let offset = self.pos;
+        let rest = &self.data[self.pos..];
+        let Some(len) = rest.iter().position(|x| *x == 0) else {
+            bail!("no null of [{name}] at [{offset}]")
+        };
+        let s = self.take(name, len + 1)?;
+        let value = decode_str(&s[..len]).unwrap_or_else(|| String::from_utf8_lossy(&s[..len]));
+        self.push(offset, name, format!("{value:?}"));
+        Ok(())
+    }
+
+    fn strs_to_end(&mut self, name: &'static str, end: usize) -> Result<()> {
+        while self.pos < end {
+            self.str(name)?;
+        }
+        Ok(())
+    }
+
+    /// tag type and length, returns type and end of value
+    fn tag_head(&mut self) -> Result<(u8, usize)> {
+        let offset = self.pos;
+        let tag = self.take("tag_type", 1)?[0];
+        let value = match tag {
+            x if x == TagType::MEDIAINFO.code() => "MEDIAINFO".to_string(),
+            x if x == TagType::FILENAME.code() => "FILENAME".to_string(),
+            x if x == TagType::RTPINFO.code() => "RTPINFO".to_string(),
+            x if x == TagType::CAPABILITY.code() => "CAPABILITY".to_string(),
+            x if x == TagType::VERSION.code() => "VERSION".to_string(),
+            x if x == TagType::CAPACITY.code() => "CAPACITY".to_string(),
+            x if x == TagType::PORTRANGE.code() => "PORTRANGE".to_string(),
+            x => format!("0x{x:02x} unknown"),
+        };
+        self.push(offset, "tag_type", value);
+        let len = self.u16("tag_length")? as usize;
+        Ok((tag, self.pos + len))
+    }
+
+    /// tags until end, known ones field by field
+    fn tags(&mut self, end: usize) -> Result<()> {
+        while self.pos < end {
+            let (tag, tag_end) = self.tag_head()?;
+            if tag == TagType::FILENAME.code() {
+                self.u8("format")?;
+                self.str("filename")?;
+            } else if tag == TagType::RTPINFO.code() {
+                self.ip("ip")?;
+                self.u16("port")?;
+                self.u8("media_type")?;
+                self.u8("internal_pltyp")?;
+                self.u8("nego_pltyp")?;
+                self.str("attribute")?;
+                self.u8("tele_event")?;
+                self.u8("direction")?;
+                self.strs_to_end("webrtc", tag_end)?;
+            } else if tag == TagType::CAPABILITY.code() {
+                let offset = self.pos;
+                let v = u32::from_be_bytes(self.take("capabilities", 4)?.try_into()?);
+                self.push(offset, "capabilities", format!("0x{v:x}"));
+            } else if tag == TagType::VERSION.code() {
+                self.str("version")?;
+            } else if tag == TagType::CAPACITY.code() {
+                self.uint("capacity", 4)?;
+            } else if tag == TagType::PORTRANGE.code() {
+                self.u16("port_min")?;
+                self.u16("port_max")?;
+            } else if tag == TagType::MEDIAINFO.code() {
+                self.u8("support_t38")?;
+                for _kind in ["audio", "video", "fax"] {
+                    let num = self.u8("num_codecs")?;
+                    for _ in 0..num {
+                        self.u8("codec_index")?;
+                        self.u8("payload_type")?;
+                        self.str("mapstr")?;
+                    }
+                }
+            }
+            if self.pos < tag_end {
+                self.rest("unparsed", tag_end)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn rest(&mut self, name: &'static str, end: usize) -> Result<()> {
+        let offset = self.pos;
+        let len = end.saturating_sub(offset);
+        self.take(name, len)?;
+        self.push(offset, name, format!("{len} bytes"));
+        Ok(())
+    }
+
+    fn payload(&mut self, code: u16, end: usize) -> Result<()> {
+        let Ok(code) = MCodeType::try_from(code) else { return Ok(()) };
+        match code {
+            MCodeType::REGISTER => {
+                self.ip("ip")?;
+                self.tags(end)?;
+            },
+            MCodeType::REQUESTCHANNEL => {
+                self.u8("ice_type")?;
+                self.u16("life_seconds")?;
+                let media_type = self.u8("media_type")?;
+                self.str("as_call_id")?;
+                if matches!(media_type, 4 | 7) {
+                    self.str("agora_info")?;
+                }
+                self.u8("is_nbup")?;
+                self.u8("ptime")?;
+                self.u8("is_caller")?;
+                self.u8("codec")?;
+                self.u16("amr_mode")?;
+                self.strs_to_end("webrtc", end)?;
+            },
+            MCodeType::REQUESTCHANNEL_ACK => {
+                self.u8("result")?;
+                self.u16("audio_port")?;
+                self.u16("video_port")?;
+                self.u16("fax_port")?;
+                self.u8("media_type")?;
+                self.strs_to_end("webrtc", end)?;
+            },
+            MCodeType::OPENRTPCONNECT => {
+                self.u8("num_tags")?;
+                self.tags(end)?;
+            },
+            MCodeType::PLAY => {
+                self.uint("interval", 4)?;
+                self.u16("play_times")?;
+                self.uint("max_duration", 4)?;
+                self.u16("key_mask")?;
+                self.u8("record")?;
+                self.u8("speech_barge")?;
+                self.u8("erase_dtmf")?;
+                self.u8("num_tlv")?;
+                self.tags(end)?;
+            },
+            MCodeType::PLAY_ACK => {
+                self.u8("result")?;
+                self.uint("play_duration", 4)?;
+                self.tags(end)?;
+            },
+            MCodeType::RESFROMTAG => self.str("value")?,
+            MCodeType::CANCEL => {
+                self.code("op_code")?;
+            },
+            MCodeType::OPENRTPCONNECT_ACK | MCodeType::CLOSERTPCONNECT | MCodeType::CLOSERTPCONNECT_ACK => {
+                self.u8("result")?;
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+}
+
+/// every field of a datagram in order, bytes not understood end up as "unparsed".
+/// redacted fields are masked, see vn_redact
+pub fn explain(data: &[u8]) -> Vec<ExplainedField> {
+    let mut masked = data.to_vec();
+    mask_packet(&mut masked);
+
+    let mut ex = Explainer { data: &masked, pos: 0, fields: Vec::new() };
+    let r = (|| {
+        let length = ex.u16("length")? as usize;
+        let code = ex.code("code")?;
+        ex.uint("fsm_id", 4)?;
+        ex.u16("key")?;
+        ex.u16("sn")?;
+        let end = (length + 2).clamp(HEADER_LENGTH, masked.len());
+        ex.payload(code, end)?;
+        if ex.pos < end {
+            ex.rest("unparsed", end)?;
+        }
+        if ex.pos < masked.len() {
+            ex.str("cn_path")?;
+        }
+        anyhow::Ok(())
+    })();
+
+    if r.is_err() && ex.pos < masked.len() {
+        let offset = ex.pos;
+        ex.pos = masked.len();
+        ex.push(offset, "unparsed", format!("{} bytes", masked.len() - offset));
+    }
+    ex.fields
+}
+
+#[cfg(test)]
+mod test {
+    use crate::vn_proto::{Filename, Header, MCodeType, Play};
+
+    use super::{explain, field_description, FIELD_TABLE};
+
+    #[test]
+    fn test_explain_play() {
+        let mut payload = Vec::new();
+        Play { play_times: 1, files: vec![Filename { format: 100, filename: "welcome.wav".into() }], ..Default::default() }.write_to(&mut payload);
+        let mut data = Vec::new();
+        Header { code: MCodeType::PLAY.code(), fsm_id: 123, sn: 4, ..Default::default() }.write_to2(&mut data, &payload[..]);
+        data.extend_from_slice(b"/cin/mscn3\0");
+
+        let fields = explain(&data);
+        let names: Vec<_> = fields.iter().map(|x| x.name).collect();
+        assert_eq!(names, [
+            "length", "code", "fsm_id", "key", "sn",
+            "interval", "play_times", "max_duration", "key_mask", "record", "speech_barge", "erase_dtmf", "num_tlv",
+            "tag_type", "tag_length", "format", "filename", "cn_path",
+        ]);
+        assert_eq!(fields.iter().map(|x| x.raw.len()).sum::<usize>(), data.len());
+        assert_eq!((fields[6].offset, fields[6].value.as_str()), (16, "1"));
+        assert_eq!(fields[1].value, "PLAY");
+        assert!(fields[1].description.starts_with("play files"));
+        assert_eq!(fields[16].value, "\"welcome.wav\"");
+        assert!(fields[16].to_string().contains("file to play"));
+
+        // cut inside the filename
+        let fields = explain(&data[..40]);
+        assert_eq!(fields.last().unwrap().name, "unparsed");
+        assert_eq!(fields.iter().map(|x| x.raw.len()).sum::<usize>(), 40);
+
+        for name in fields.iter().map(|x| x.name) {
+            assert!(!field_description(name).is_empty(), "{name}");
+        }
+        assert!(FIELD_TABLE.iter().all(|x| !x.1.is_empty()));
+    }
+}
@@ -0,0 +1,102 @@
+//! flat named fields of decoded packets, for assertions and reports
+
+use std::{collections::BTreeMap, fmt};
+
+use anyhow::Result;
+
+use crate::{vn_redact::redact_url, vn_proto::{
+    CancelRef, MCodeType, OpenRtpConnectAck, PacketRef, PlayAckRef, PlayRef,
+    RequestChannelAckRef, RequestChannelRef, CloseRtpConnectAck,
+}};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldValue {
+    Int(i64),
+    Str(String),
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldValue::Int(v) => write!(f, "{v}"),
+            FieldValue::Str(v) => write!(f, "{v:?}"),
+        }
+    }
+}
+
+impl From<i64> for FieldValue {
+    fn from(v: i64) -> Self {
+        Self::Int(v)
+    }
+}
+
+impl From<String> for FieldValue {
+    fn from(v: String) -> Self {
+        Self::Str(v)
+    }
+}
+
+pub type Fields = BTreeMap<&'static str, FieldValue>;
+
+/// header fields plus payload fields of codes we can decode
+pub fn packet_fields(packet: &PacketRef<'_>) -> Result<Fields> {
+    let mut fields = Fields::new();
+    fields.insert("code", (packet.code() as i64).into());
+    fields.insert("fsm_id", (packet.fsm_id() as i64).into());
+    fields.insert("key", (packet.key() as i64).into());
+    fields.insert("sn", (packet.sn() as i64).into());
+    if let Some(m) = packet.length_mismatch() {
+        fields.insert("length_declared", (m.declared as i64).into());
+        fields.insert("length_actual", (m.actual as i64).into());
+    }
+
+    let Ok(code) = MCodeType::try_from(packet.code()) else { return Ok(fields) };
+    let payload = packet.payload();
+
+    match code {
+        MCodeType::REQUESTCHANNEL => {
+            let r = RequestChannelRef::parse_from(payload)?;
+            fields.insert("ice_type", (r.part1().ice_type_code() as i64).into());
+            fields.insert("life_seconds", (r.part1().life_seconds() as i64).into());
+            fields.insert("media_type", (r.part1().media_type_code() as i64).into());
+            fields.insert("ptime", (r.part2().ptime() as i64).into());
+            fields.insert("codec", (r.part2().codec_code() as i64).into());
+            fields.insert("amr_mode", (r.part2().amr_mode() as i64).into());
+            fields.insert("is_caller", (r.part2().is_caller() as i64).into());
+        },
+        MCodeType::REQUESTCHANNEL_ACK => {
+            let r = RequestChannelAckRef::parse_from(payload)?;
+            fields.insert("result", (r.part1().result() as i64).into());
+            fields.insert("audio_port", (r.part1().audio_port() as i64).into());
+            fields.insert("video_port", (r.part1().video_port() as i64).into());
+            fields.insert("fax_port", (r.part1().fax_port() as i64).into());
+            fields.insert("media_type", (r.part1().media_type() as i64).into());
+        },
+        MCodeType::PLAY => {
+            let r = PlayRef::parse_from(payload)?;
+            fields.insert("files", (r.files().count() as i64).into());
+            if let Some(Ok(file)) = r.files().next() {
+                if let Some(name) = file.filename().decode() {
+                    fields.insert("filename", redact_url(&name).into_owned().into());
+                }
+            }
+        },
+        MCodeType::PLAY_ACK => {
+            let r = PlayAckRef::parse_from(payload)?;
+            fields.insert("result", (r.part1().result() as i64).into());
+            fields.insert("play_duration", (r.part1().play_duration() as i64).into());
+        },
+        MCodeType::OPENRTPCONNECT_ACK => {
+            fields.insert("result", (OpenRtpConnectAck::parse_from(payload)?.value() as i64).into());
+        },
+        MCodeType::CLOSERTPCONNECT_ACK => {
+            fields.insert("result", (CloseRtpConnectAck::parse_from(payload)?.value() as i64).into());
+        },
+        MCodeType::CANCEL => {
+            fields.insert("op_code", (CancelRef::parse_from(payload)?.op_code() as i64).into());
+        },
+        _ => {},
+    }
+
+    Ok(fields)
+}
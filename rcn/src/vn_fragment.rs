@@ -0,0 +1,219 @@
+//! split large payloads into several datagrams and join them back.
+//!
+//! only used after both sides announced `Capability::FRAGMENT` at REGISTER.
+//! fragments keep code, fsm_id and sn of the original packet,
+//! key field is reused as `FRAG_FLAG | MORE_FLAG? | index`,
+//! original key is carried in first 2 bytes of fragment 0.
+
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+use anyhow::{Result, bail};
+use bytes::Buf;
+
+use crate::vn_proto::{Header, HEADER_LENGTH};
+
+pub const FRAG_FLAG: u16 = 0x8000;
+pub const MORE_FLAG: u16 = 0x4000;
+pub const INDEX_MASK: u16 = 0x3fff;
+
+/// reassembled payload must fit in header length field
+pub const MAX_REASSEMBLED_LEN: usize = u16::MAX as usize + 2 - HEADER_LENGTH;
+
+pub fn is_fragment(key: i16) -> bool {
+    key as u16 & FRAG_FLAG != 0
+}
+
+/// split payload so that each payload is at most max_payload bytes,
+/// return as is if it already fits
+pub fn split(header: &Header, payload: &[u8], max_payload: usize) -> Result<Vec<(Header, Vec<u8>)>> {
+    if payload.len() <= max_payload {
+        return Ok(vec![(header.clone(), payload.to_vec())])
+    }
+
+    if max_payload <= 2 {
+        bail!("too small max fragment payload [{}]", max_payload)
+    }
+
+    if payload.len() > MAX_REASSEMBLED_LEN {
+        bail!("too large payload to fragment [{}]", payload.len())
+    }
+
+    let mut data = Vec::with_capacity(2 + payload.len());
+    data.extend_from_slice(&header.key.to_be_bytes());
+    data.extend_from_slice(payload);
+
+    let chunks: Vec<_> = data.chunks(max_payload).collect();
+    if chunks.len() > INDEX_MASK as usize + 1 {
+        bail!("too many fragments [{}]", chunks.len())
+    }
+
+    let last = chunks.len() - 1;
+    let fragments = chunks.into_iter()
+    .enumerate()
+    .map(|(index, chunk)| {
+        let mut key = FRAG_FLAG | index as u16;
+        if index < last {
+            key |= MORE_FLAG;
+        }
+        let header = Header {
+            key: key as i16,
+            ..header.clone()
+        };
+        (header, chunk.to_vec())
+    })
+    .collect();
+
+    Ok(fragments)
+}
+
+struct Pending {
+    code: u16,
+    parts: HashMap<u16, Vec<u8>>,
+    last: Option<u16>,
+    len: usize,
+    last_seen: Instant,
+}
+
+/// collect fragments per (fsm_id, sn)
+pub struct Reassembler {
+    pending: HashMap<(u32, u16), Pending>,
+    timeout: Duration,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(5))
+    }
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            pending: Default::default(),
+            timeout,
+        }
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// feed one fragment, return joined packet when all fragments arrived
+    pub fn push(&mut self, header: &Header, payload: &[u8], now: Instant) -> Result<Option<(Header, Vec<u8>)>> {
+        let key = header.key as u16;
+        if key & FRAG_FLAG == 0 {
+            bail!("not a fragment, key [{:#06x}]", key)
+        }
+        let index = key & INDEX_MASK;
+
+        let id = (header.fsm_id, header.sn);
+        let pending = self.pending.entry(id).or_insert_with(|| Pending {
+            code: header.code,
+            parts: Default::default(),
+            last: None,
+            len: 0,
+            last_seen: now,
+        });
+
+        if pending.code != header.code {
+            self.pending.remove(&id);
+            bail!("fragment code changed, fsm_id [{}], sn [{}]", id.0, id.1)
+        }
+
+        pending.last_seen = now;
+        if key & MORE_FLAG == 0 {
+            pending.last = Some(index);
+        }
+
+        if pending.parts.contains_key(&index) {
+            return Ok(None)
+        }
+
+        pending.len += payload.len();
+        if pending.len > MAX_REASSEMBLED_LEN + 2 {
+            self.pending.remove(&id);
+            bail!("too large reassembled payload, fsm_id [{}], sn [{}]", id.0, id.1)
+        }
+        pending.parts.insert(index, payload.to_vec());
+
+        let complete = match pending.last {
+            Some(last) => pending.parts.len() == last as usize + 1,
+            None => false,
+        };
+        if !complete {
+            return Ok(None)
+        }
+
+        let Some(mut pending) = self.pending.remove(&id) else { return Ok(None) };
+        let count = pending.parts.len() as u16;
+        let mut data = Vec::with_capacity(pending.len);
+        for index in 0..count {
+            match pending.parts.remove(&index) {
+                Some(part) => data.extend_from_slice(&part[..]),
+                None => bail!("missing fragment [{}], fsm_id [{}], sn [{}]", index, id.0, id.1),
+            }
+        }
+
+        if data.len() < 2 {
+            bail!("too short reassembled payload [{}]", data.len())
+        }
+
+        let header = Header {
+            key: (&data[..2]).get_i16(),
+            ..header.clone()
+        };
+        data.drain(..2);
+
+        Ok(Some((header, data)))
+    }
+
+    /// drop incomplete sets not touched within timeout, return how many dropped
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let timeout = self.timeout;
+        let before = self.pending.len();
+        self.pending.retain(|_k, v| now.saturating_duration_since(v.last_seen) < timeout);
+        before - self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::vn_proto::Header;
+
+    use super::{is_fragment, split, Reassembler};
+
+    #[test]
+    fn test_split_reassemble() {
+        let header = Header {
+            code: 0x1,
+            fsm_id: 5000001,
+            key: 7,
+            sn: 3,
+        };
+        let payload: Vec<u8> = (0..4000_u32).map(|x| x as u8).collect();
+
+        let fragments = split(&header, &payload[..], 1600).unwrap();
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments.iter().all(|(h, p)| is_fragment(h.key) && p.len() <= 1600));
+
+        let now = Instant::now();
+        let mut reassembler = Reassembler::default();
+        assert!(reassembler.push(&fragments[2].0, &fragments[2].1, now).unwrap().is_none());
+        assert!(reassembler.push(&fragments[0].0, &fragments[0].1, now).unwrap().is_none());
+        let (h, p) = reassembler.push(&fragments[1].0, &fragments[1].1, now).unwrap().unwrap();
+        assert_eq!(h.key, 7);
+        assert_eq!(h.sn, 3);
+        assert_eq!(p, payload);
+        assert_eq!(reassembler.pending_len(), 0);
+
+        assert!(reassembler.push(&fragments[0].0, &fragments[0].1, now).unwrap().is_none());
+        assert_eq!(reassembler.expire(now + Duration::from_secs(1)), 0);
+        assert_eq!(reassembler.expire(now + Duration::from_secs(10)), 1);
+
+        let small = split(&header, &payload[..100], 1600).unwrap();
+        assert_eq!(small.len(), 1);
+        assert!(!is_fragment(small[0].0.key));
+    }
+}
@@ -0,0 +1,338 @@
+//! fsm_id namespace of a CN and how channel fsm_ids are picked from it.
+//!
+//! By default a CN owns `cn_id * 1000000 ..` for a million fsm_ids, link level
+//! packets use the base and channels base + 1, base + 2 ... MS deployments
+//! expecting other numbering get a [`FsmIdSpace`] of their own and a
+//! [`FsmIdStrategy`]. Inbound packets of fsm_ids outside the namespace are
+//! checked by [`FsmIdGuard`].
+
+use std::{collections::HashSet, fmt, str::FromStr};
+
+use anyhow::{Result, Context, bail};
+
+use crate::utils::{log_once::warn_first, rng::SimRng};
+
+pub const DEFAULT_SPAN: u32 = 1000000;
+
+/// fsm_ids base ..= base + span - 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsmIdSpace {
+    pub base: u32,
+    pub span: u32,
+}
+
+impl FsmIdSpace {
+    /// cn_id * 1000000 scheme
+    pub fn of_cn(cn_id: u32) -> Self {
+        Self { base: cn_id.wrapping_mul(DEFAULT_SPAN), span: DEFAULT_SPAN }
+    }
+
+    pub fn contains(&self, fsm_id: u32) -> bool {
+        fsm_id.wrapping_sub(self.base) < self.span
+    }
+
+    /// n of base + n, None if outside
+    pub fn offset(&self, fsm_id: u32) -> Option<u32> {
+        self.contains(fsm_id).then(|| fsm_id - self.base)
+    }
+
+    /// fsm_id of channel n
+    pub fn channel(&self, n: u32) -> u32 {
+        self.base.wrapping_add(n)
+    }
+}
+
+/// "first-last", e.g. "7000000-7499999"
+impl FromStr for FsmIdSpace {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (first, last) = s.split_once('-').with_context(||format!("invalid fsm_id space [{s}], expect first-last"))?;
+        let first: u32 = first.trim().parse().with_context(||format!("invalid fsm_id [{first}] of [{s}]"))?;
+        let last: u32 = last.trim().parse().with_context(||format!("invalid fsm_id [{last}] of [{s}]"))?;
+        if last <= first {
+            bail!("fsm_id space [{s}] has no room for channels")
+        }
+        if last - first == u32::MAX {
+            bail!("fsm_id space [{s}] too large")
+        }
+        Ok(Self { base: first, span: last - first + 1 })
+    }
+}
+
+impl fmt::Display for FsmIdSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.base, self.base.wrapping_add(self.span - 1))
+    }
+}
+
+/// how channel fsm_ids are picked, never the base
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsmIdStrategy {
+    /// base + 1, then every step, wrapping within the space
+    Increment(u32),
+    /// uniform within the space
+    Random,
+    /// these in turn
+    List(Vec<u32>),
+}
+
+impl Default for FsmIdStrategy {
+    fn default() -> Self {
+        Self::Increment(1)
+    }
+}
+
+/// "increment", "increment:<step>", "random" or "list:<fsm_id>,<fsm_id>.."
+impl FromStr for FsmIdStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, arg) = s.split_once(':').unwrap_or((s, ""));
+        match (name.trim(), arg.trim()) {
+            ("increment", "") => Ok(Self::Increment(1)),
+            ("increment", step) => match step.parse() {
+                Ok(0) | Err(_) => bail!("invalid step [{step}] of [{s}]"),
+                Ok(step) => Ok(Self::Increment(step)),
+            },
+            ("random", "") => Ok(Self::Random),
+            ("list", list) if !list.is_empty() => {
+                let ids = list.split(',')
+                .map(|x| x.trim().parse().with_context(||format!("invalid fsm_id [{x}] of [{s}]")))
+                .collect::<Result<Vec<u32>>>()?;
+                Ok(Self::List(ids))
+            },
+            _ => bail!("unknown fsm_id strategy [{s}], expect increment[:step], random or list:<ids>"),
+        }
+    }
+}
+
+impl fmt::Display for FsmIdStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Increment(1) => write!(f, "increment"),
+            Self::Increment(step) => write!(f, "increment:{step}"),
+            Self::Random => write!(f, "random"),
+            Self::List(ids) => {
+                let ids: Vec<_> = ids.iter().map(|x| x.to_string()).collect();
+                write!(f, "list:{}", ids.join(","))
+            },
+        }
+    }
+}
+
+/// picks fsm_ids of new channels, skipping those still in use
+#[derive(Debug)]
+pub struct FsmIdAllocator {
+    space: FsmIdSpace,
+    strategy: FsmIdStrategy,
+    /// offset of next increment, or index of next list entry
+    cursor: u32,
+    in_use: HashSet<u32>,
+    rng: SimRng,
+}
+
+impl FsmIdAllocator {
+    /// increment by 1
+    pub fn new(space: FsmIdSpace) -> Self {
+        Self { space, strategy: FsmIdStrategy::default(), cursor: 1, in_use: HashSet::new(), rng: SimRng::new(0) }
+    }
+
+    pub fn with_strategy(space: FsmIdSpace, strategy: FsmIdStrategy) -> Result<Self> {
+        if let FsmIdStrategy::List(ids) = &strategy {
+            if let Some(id) = ids.iter().find(|x| space.offset(**x).unwrap_or(0) == 0) {
+                bail!("fsm_id [{id}] of list not a channel of space [{space}]")
+            }
+        }
+        let cursor = match strategy {
+            FsmIdStrategy::List(_) => 0,
+            _ => 1,
+        };
+        Ok(Self { strategy, cursor, ..Self::new(space) })
+    }
+
+    pub fn set_rng(&mut self, rng: SimRng) {
+        self.rng = rng;
+    }
+
+    pub fn space(&self) -> FsmIdSpace {
+        self.space
+    }
+
+    pub fn strategy(&self) -> &FsmIdStrategy {
+        &self.strategy
+    }
+
+    pub fn in_use(&self) -> usize {
+        self.in_use.len()
+    }
+
+    /// where allocate goes on from, see vn_cn_state
+    pub fn cursor(&self) -> u32 {
+        self.cursor
+    }
+
+    /// go on from a cursor saved by an earlier process
+    pub fn resume(&mut self, cursor: u32) -> Result<()> {
+        let valid = match &self.strategy {
+            FsmIdStrategy::List(ids) => (cursor as usize) < ids.len(),
+            _ => cursor >= 1 && cursor < self.space.span,
+        };
+        if !valid {
+            bail!("cursor [{cursor}] out of [{}] by [{}]", self.space, self.strategy)
+        }
+        self.cursor = cursor;
+        Ok(())
+    }
+
+    pub fn allocate(&mut self) -> Result<u32> {
+        let channels = self.space.span - 1;
+        match &self.strategy {
+            FsmIdStrategy::Increment(step) => {
+                for _ in 0..channels {
+                    let fsm_id = self.space.channel(self.cursor);
+                    self.cursor = ((self.cursor as u64 - 1 + *step as u64) % channels as u64) as u32 + 1;
+                    if self.in_use.insert(fsm_id) {
+                        return Ok(fsm_id)
+                    }
+                }
+            },
+            FsmIdStrategy::Random => {
+                // probe on from a random channel, so that a nearly full space still ends
+                let start = self.rng.range(0, channels as u64) as u32;
+                for n in 0..channels {
+                    let fsm_id = self.space.channel((start + n) % channels + 1);
+                    if self.in_use.insert(fsm_id) {
+                        return Ok(fsm_id)
+                    }
+                }
+            },
+            FsmIdStrategy::List(ids) => {
+                for _ in 0..ids.len() {
+                    let fsm_id = ids[self.cursor as usize];
+                    self.cursor = (self.cursor + 1) % ids.len() as u32;
+                    if self.in_use.insert(fsm_id) {
+                        return Ok(fsm_id)
+                    }
+                }
+            },
+        }
+        bail!("no free fsm_id in [{}] by [{}], in use [{}]", self.space, self.strategy, self.in_use.len())
+    }
+
+    /// fsm_id picked elsewhere, e.g. by a scenario, is taken as in use
+    pub fn mark(&mut self, fsm_id: u32) {
+        self.in_use.insert(fsm_id);
+    }
+
+    pub fn release(&mut self, fsm_id: u32) -> bool {
+        self.in_use.remove(&fsm_id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum FsmIdCheck {
+    /// take packets of any fsm_id
+    #[default]
+    Off,
+    /// drop packets of fsm_ids outside the space
+    Discard,
+    /// accept all but log and count fsm_ids outside the space
+    Warn,
+}
+
+/// inbound fsm_ids against the space
+#[derive(Debug, Clone)]
+pub struct FsmIdGuard {
+    space: FsmIdSpace,
+    mode: FsmIdCheck,
+    /// packets of fsm_ids outside space
+    foreign: u64,
+}
+
+impl FsmIdGuard {
+    pub fn new(space: FsmIdSpace, mode: FsmIdCheck) -> Self {
+        Self { space, mode, foreign: 0 }
+    }
+
+    pub fn space(&self) -> FsmIdSpace {
+        self.space
+    }
+
+    pub fn mode(&self) -> FsmIdCheck {
+        self.mode
+    }
+
+    pub fn foreign(&self) -> u64 {
+        self.foreign
+    }
+
+    /// true if a packet of fsm_id is to be processed
+    pub fn check(&mut self, code: u16, fsm_id: u32) -> bool {
+        if self.mode == FsmIdCheck::Off || self.space.contains(fsm_id) {
+            return true
+        }
+        self.foreign += 1;
+        let drop = self.mode == FsmIdCheck::Discard;
+        warn_first!(self.foreign, "packet 0x{code:04x} of fsm_id [{fsm_id}] outside [{}], {}", self.space, if drop { "dropped" } else { "accepted (warn only)" });
+        !drop
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::utils::rng::SimRng;
+
+    use super::{FsmIdAllocator, FsmIdCheck, FsmIdGuard, FsmIdSpace, FsmIdStrategy};
+
+    #[test]
+    fn test_fsm_id_allocator() {
+        let space = FsmIdSpace::of_cn(5);
+        assert_eq!(space.to_string(), "5000000-5999999");
+        assert_eq!(space.offset(5000007), Some(7));
+        assert_eq!(space.offset(6000000), None);
+        let space: FsmIdSpace = "700-704".parse().unwrap();
+        assert_eq!((space.base, space.span), (700, 5));
+        assert!("700-700".parse::<FsmIdSpace>().is_err());
+
+        // channels 701..=704, wrapping, skipping ones in use
+        let mut ids = FsmIdAllocator::with_strategy(space, "increment:3".parse().unwrap()).unwrap();
+        let picked: Vec<_> = (0..4).map(|_| ids.allocate().unwrap()).collect();
+        assert_eq!(picked, [701, 704, 703, 702]);
+        assert!(ids.allocate().is_err());
+        assert!(ids.release(703));
+        assert_eq!(ids.allocate().unwrap(), 703);
+
+        // a restarted process goes on where the last one stopped
+        let mut ids = FsmIdAllocator::new(space);
+        ids.allocate().unwrap();
+        let mut again = FsmIdAllocator::new(space);
+        again.resume(ids.cursor()).unwrap();
+        assert_eq!(again.allocate().unwrap(), 702);
+        assert!(again.resume(0).is_err() && again.resume(5).is_err());
+
+        let mut ids = FsmIdAllocator::with_strategy(space, FsmIdStrategy::Random).unwrap();
+        ids.set_rng(SimRng::new(7));
+        let mut picked: Vec<_> = (0..4).map(|_| ids.allocate().unwrap()).collect();
+        picked.sort();
+        assert_eq!(picked, [701, 702, 703, 704]);
+
+        let strategy: FsmIdStrategy = "list:703,701".parse().unwrap();
+        assert_eq!(strategy.to_string(), "list:703,701");
+        let mut ids = FsmIdAllocator::with_strategy(space, strategy).unwrap();
+        ids.mark(703);
+        assert_eq!(ids.allocate().unwrap(), 701);
+        assert!(ids.allocate().is_err());
+        assert!(FsmIdAllocator::with_strategy(space, "list:700".parse().unwrap()).is_err());
+        assert!("increment:0".parse::<FsmIdStrategy>().is_err());
+        assert!("sequential".parse::<FsmIdStrategy>().is_err());
+
+        let mut guard = FsmIdGuard::new(space, FsmIdCheck::Discard);
+        assert!(guard.check(0x19, 702));
+        assert!(!guard.check(0x19, 5000001));
+        let mut guard = FsmIdGuard::new(space, FsmIdCheck::Warn);
+        assert!(guard.check(0x19, 5000001));
+        assert_eq!(guard.foreign(), 1);
+    }
+}
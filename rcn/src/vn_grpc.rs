@@ -0,0 +1,356 @@
+//! decoded packets streamed over gRPC to subscribers, e.g. an analytics service.
+//!
+//! Service `rcn.vn.PacketStream`, schema in `proto/vn_stream.proto`:
+//!
+//! ```text
+//! rpc Subscribe(SubscribeRequest) returns (stream PacketEvent);
+//! ```
+//!
+//! Records of a capture or the proxy go into a [`PacketHub`] through its
+//! [`PacketHub::tap`], each subscriber gets those matching its request from
+//! then on. Subscribers too slow to keep up lose packets rather than holding
+//! up the link, see [`DEFAULT_BACKLOG`].
+
+use std::{collections::HashMap, net::SocketAddr, pin::Pin, sync::Arc, task::{Context, Poll}};
+
+use anyhow::{Result, Context as _};
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::{errors::BroadcastStreamRecvError, BroadcastStream}, Stream, StreamExt};
+use tonic::{
+    codec::ProstCodec,
+    codegen::{empty_body, http, BoxFuture, Service},
+    server::{Grpc, NamedService, ServerStreamingService},
+    transport::{Body, Endpoint, Server},
+    Request, Response, Status, Streaming,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    vn_capture::{CaptureDir, CaptureRecord, RecordTap},
+    vn_fields::{self, packet_fields},
+    vn_pb::VnMessage,
+    vn_proto::{CodeName, PacketRef},
+};
+
+/// records kept per subscriber before it starts losing them
+pub const DEFAULT_BACKLOG: usize = 4096;
+
+const SERVICE: &str = "rcn.vn.PacketStream";
+const SUBSCRIBE_PATH: &str = "/rcn.vn.PacketStream/Subscribe";
+
+/// messages of `proto/vn_stream.proto`
+pub mod pb {
+    use std::collections::HashMap;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SubscribeRequest {
+        /// only these codes, all if empty
+        #[prost(uint32, repeated, tag = "1")]
+        pub codes: Vec<u32>,
+        /// only these fsm_ids, all if empty
+        #[prost(uint32, repeated, tag = "2")]
+        pub fsm_ids: Vec<u32>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Direction {
+        CnToMs = 0,
+        MsToCn = 1,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct FieldValue {
+        #[prost(oneof = "field_value::Value", tags = "1, 2")]
+        pub value: Option<field_value::Value>,
+    }
+
+    pub mod field_value {
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Value {
+            #[prost(sint64, tag = "1")]
+            Int(i64),
+            #[prost(string, tag = "2")]
+            Str(String),
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PacketEvent {
+        /// since start of capture
+        #[prost(uint64, tag = "1")]
+        pub ts_us: u64,
+        #[prost(enumeration = "Direction", tag = "2")]
+        pub dir: i32,
+        /// sending socket path, empty if unknown
+        #[prost(string, tag = "3")]
+        pub socket: String,
+        /// header and payload parsed, the rest is only raw if not
+        #[prost(bool, tag = "4")]
+        pub valid: bool,
+        #[prost(uint32, tag = "5")]
+        pub code: u32,
+        #[prost(string, tag = "6")]
+        pub code_name: String,
+        #[prost(uint32, tag = "7")]
+        pub fsm_id: u32,
+        #[prost(uint32, tag = "8")]
+        pub sn: u32,
+        #[prost(sint32, tag = "9")]
+        pub key: i32,
+        /// flat fields as in scenario expectations, see vn_fields
+        #[prost(map = "string, message", tag = "10")]
+        pub fields: HashMap<String, FieldValue>,
+        /// whole packet, redacted fields masked
+        #[prost(bytes = "vec", tag = "11")]
+        pub raw: Vec<u8>,
+        /// decoded packet, None if not valid
+        #[prost(message, optional, tag = "12")]
+        pub message: Option<crate::vn_pb::VnMessage>,
+    }
+}
+
+impl From<&vn_fields::FieldValue> for pb::FieldValue {
+    fn from(v: &vn_fields::FieldValue) -> Self {
+        let value = match v {
+            vn_fields::FieldValue::Int(v) => pb::field_value::Value::Int(*v),
+            vn_fields::FieldValue::Str(v) => pb::field_value::Value::Str(v.clone()),
+        };
+        Self { value: Some(value) }
+    }
+}
+
+impl pb::PacketEvent {
+    pub fn from_record(record: &CaptureRecord) -> Self {
+        let raw = record.data().unwrap_or_default();
+        let dir = match record.dir {
+            CaptureDir::CnToMs => pb::Direction::CnToMs,
+            CaptureDir::MsToCn => pb::Direction::MsToCn,
+        };
+        let mut event = Self {
+            ts_us: record.ts_us,
+            dir: dir as i32,
+            socket: record.socket.clone().unwrap_or_default(),
+            ..Default::default()
+        };
+        if let Ok(packet) = PacketRef::parse_from(&raw[..]) {
+            event.code = packet.code() as u32;
+            event.code_name = CodeName(packet.code()).to_string();
+            event.fsm_id = packet.fsm_id();
+            event.sn = packet.sn() as u32;
+            event.key = packet.key() as i32;
+            if let (Ok(fields), Ok(message)) = (packet_fields(&packet), VnMessage::try_from(&packet)) {
+                event.valid = true;
+                event.fields = fields.iter().map(|(name, value)| (name.to_string(), value.into())).collect();
+                event.message = Some(message);
+            }
+        }
+        event.raw = raw;
+        event
+    }
+}
+
+impl pb::SubscribeRequest {
+    pub fn matches(&self, event: &pb::PacketEvent) -> bool {
+        (self.codes.is_empty() || self.codes.contains(&event.code))
+        && (self.fsm_ids.is_empty() || self.fsm_ids.contains(&event.fsm_id))
+    }
+}
+
+/// fan-out of records to subscribers
+#[derive(Debug, Clone)]
+pub struct PacketHub {
+    tx: broadcast::Sender<CaptureRecord>,
+}
+
+impl PacketHub {
+    pub fn new(backlog: usize) -> Self {
+        let (tx, _rx) = broadcast::channel(backlog.max(1));
+        Self { tx }
+    }
+
+    /// for CaptureWriter::set_tap or Proxy::set_tap
+    pub fn tap(&self) -> RecordTap {
+        let tx = self.tx.clone();
+        Arc::new(move |record: &CaptureRecord| {
+            // no subscriber is fine
+            let _r = tx.send(record.clone());
+        })
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.tx.receiver_count()
+    }
+
+    /// events from now on matching req
+    pub fn subscribe(&self, req: pb::SubscribeRequest) -> EventStream {
+        let stream = BroadcastStream::new(self.tx.subscribe()).filter_map(move |r| match r {
+            Ok(record) => {
+                let event = pb::PacketEvent::from_record(&record);
+                req.matches(&event).then_some(Ok(event))
+            },
+            Err(BroadcastStreamRecvError::Lagged(n)) => {
+                warn!("grpc subscriber lagged, lost [{n}] packets");
+                None
+            },
+        });
+        Box::pin(stream)
+    }
+}
+
+pub type EventStream = Pin<Box<dyn Stream<Item = Result<pb::PacketEvent, Status>> + Send>>;
+
+/// `rcn.vn.PacketStream` of a hub
+#[derive(Debug, Clone)]
+pub struct PacketStreamService {
+    hub: PacketHub,
+}
+
+impl PacketStreamService {
+    pub fn new(hub: PacketHub) -> Self {
+        Self { hub }
+    }
+}
+
+struct SubscribeSvc(PacketHub);
+
+impl ServerStreamingService<pb::SubscribeRequest> for SubscribeSvc {
+    type Response = pb::PacketEvent;
+    type ResponseStream = EventStream;
+    type Future = BoxFuture<Response<Self::ResponseStream>, Status>;
+
+    fn call(&mut self, request: Request<pb::SubscribeRequest>) -> Self::Future {
+        let hub = self.0.clone();
+        Box::pin(async move {
+            let req = request.into_inner();
+            debug!("grpc subscribe codes {:?} fsm_ids {:?}", req.codes, req.fsm_ids);
+            Ok(Response::new(hub.subscribe(req)))
+        })
+    }
+}
+
+impl Service<http::Request<Body>> for PacketStreamService {
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+        match req.uri().path() {
+            SUBSCRIBE_PATH => {
+                let svc = SubscribeSvc(self.hub.clone());
+                Box::pin(async move {
+                    let mut grpc = Grpc::new(ProstCodec::default());
+                    Ok(grpc.server_streaming(svc, req).await)
+                })
+            },
+            _ => Box::pin(async move {
+                let mut res = http::Response::new(empty_body());
+                res.headers_mut().insert("grpc-status", http::HeaderValue::from(tonic::Code::Unimplemented as i32));
+                res.headers_mut().insert(http::header::CONTENT_TYPE, http::HeaderValue::from_static("application/grpc"));
+                Ok(res)
+            }),
+        }
+    }
+}
+
+impl NamedService for PacketStreamService {
+    const NAME: &'static str = SERVICE;
+}
+
+/// serve hub at addr until the future is dropped
+pub async fn serve(addr: SocketAddr, hub: PacketHub) -> Result<()> {
+    info!("grpc packet stream at [{addr}]");
+    Server::builder()
+    .add_service(PacketStreamService::new(hub))
+    .serve(addr)
+    .await
+    .with_context(||format!("grpc serve failed [{addr}]"))
+}
+
+/// subscribe to a server at url, e.g. http://127.0.0.1:50051
+pub async fn subscribe(url: &str, req: pb::SubscribeRequest) -> Result<Streaming<pb::PacketEvent>> {
+    let channel = Endpoint::from_shared(url.to_string())?.connect().await.with_context(||format!("grpc connect failed [{url}]"))?;
+    let mut grpc = tonic::client::Grpc::new(channel);
+    grpc.ready().await.with_context(||"grpc not ready")?;
+    let path = http::uri::PathAndQuery::from_static(SUBSCRIBE_PATH);
+    let res = grpc.server_streaming(Request::new(req), path, ProstCodec::default()).await?;
+    Ok(res.into_inner())
+}
+
+/// fields of an event back as in vn_fields
+pub fn event_fields(event: &pb::PacketEvent) -> HashMap<&str, vn_fields::FieldValue> {
+    event.fields.iter()
+    .filter_map(|(name, value)| {
+        let value = match value.value.as_ref()? {
+            pb::field_value::Value::Int(v) => vn_fields::FieldValue::Int(*v),
+            pb::field_value::Value::Str(v) => vn_fields::FieldValue::Str(v.clone()),
+        };
+        Some((name.as_str(), value))
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::{Ipv4Addr, SocketAddr}, time::Duration};
+
+    use tokio_stream::StreamExt;
+
+    use crate::{
+        vn_capture::{CaptureDir, CaptureRecord},
+        vn_fields::FieldValue,
+        vn_pb::vn_message::Body,
+        vn_proto::{Header, MCodeType, PlayAck},
+    };
+
+    use super::{event_fields, pb, serve, subscribe, PacketHub};
+
+    fn record(code: MCodeType, fsm_id: u32, payload: &[u8]) -> CaptureRecord {
+        let mut data = Vec::new();
+        Header { code: code.code(), fsm_id, sn: 3, key: 0 }.write_to2(&mut data, payload);
+        CaptureRecord::new(Duration::from_millis(5), CaptureDir::MsToCn, None, &data)
+    }
+
+    #[tokio::test]
+    async fn test_grpc_stream() {
+        let hub = PacketHub::new(16);
+        let tap = hub.tap();
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        drop(listener);
+        let server = tokio::spawn(serve(addr, hub.clone()));
+
+        let url = format!("http://{addr}");
+        let mut stream = None;
+        for _ in 0..50 {
+            let req = pb::SubscribeRequest { codes: vec![MCodeType::PLAY_ACK.code() as u32], fsm_ids: vec![] };
+            if let Ok(s) = subscribe(&url, req).await {
+                stream = Some(s);
+                break
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let mut stream = stream.unwrap();
+        while hub.subscribers() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let mut payload = Vec::new();
+        PlayAck { result: 0, play_duration: 1200 }.write_to(&mut payload);
+        tap(&record(MCodeType::HEARTBEAT, 5000000, &[]));
+        tap(&record(MCodeType::PLAY_ACK, 5000001, &payload));
+
+        let event = tokio::time::timeout(Duration::from_secs(2), stream.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!((event.code_name.as_str(), event.fsm_id, event.sn, event.ts_us), ("PLAY_ACK", 5000001, 3, 5000));
+        assert_eq!(event.dir, pb::Direction::MsToCn as i32);
+        assert!(event.valid);
+        assert_eq!(event_fields(&event).get("play_duration"), Some(&FieldValue::Int(1200)));
+        let Some(Body::PlayAck(ack)) = event.message.and_then(|x| x.body) else { panic!() };
+        assert_eq!(ack.play_duration, 1200);
+        server.abort();
+    }
+}
@@ -0,0 +1,194 @@
+//! what to do with packets of other codes while waiting for CNISUP_ACK or
+//! REGISTER.
+//!
+//! MS builds interleave HEARTBEATs and the odd late answer with the handshake.
+//! A [`StrayPolicy`] maps the state waited in and the code of such a packet to
+//! ignore (logged and dropped), queue (returned by recv_packet after the
+//! handshake, in order) or error (the handshake fails). Rules are
+//! `[state:]code=action`, e.g. `heartbeat=ignore` or `register:*=queue`, the
+//! first one matching wins. HEARTBEAT is ignored unless a rule says otherwise,
+//! anything else is an error.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{Result, Context, bail};
+
+use crate::vn_proto::MCodeType;
+
+/// packets queued beyond this fail the handshake
+pub const MAX_QUEUED: usize = 64;
+
+/// packet waited for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeState {
+    CnisupAck,
+    Register,
+}
+
+impl HandshakeState {
+    pub fn code(&self) -> MCodeType {
+        match self {
+            Self::CnisupAck => MCodeType::CNISUP_ACK,
+            Self::Register => MCodeType::REGISTER,
+        }
+    }
+}
+
+impl fmt::Display for HandshakeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CnisupAck => f.write_str("cnisup-ack"),
+            Self::Register => f.write_str("register"),
+        }
+    }
+}
+
+impl FromStr for HandshakeState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "cnisup-ack" => Ok(Self::CnisupAck),
+            "register" => Ok(Self::Register),
+            _ => bail!("unknown handshake state [{s}], expect cnisup-ack or register"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StrayAction {
+    Ignore,
+    Queue,
+    Error,
+}
+
+impl FromStr for StrayAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "ignore" => Ok(Self::Ignore),
+            "queue" => Ok(Self::Queue),
+            "error" => Ok(Self::Error),
+            _ => bail!("unknown action [{s}], expect ignore, queue or error"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StrayRule {
+    /// any state if None
+    pub state: Option<HandshakeState>,
+    /// any code if None
+    pub code: Option<MCodeType>,
+    pub action: StrayAction,
+}
+
+impl StrayRule {
+    fn matches(&self, state: HandshakeState, code: u16) -> bool {
+        self.state.is_none_or(|x| x == state) && self.code.is_none_or(|x| x.code() == code)
+    }
+}
+
+impl FromStr for StrayRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (target, action) = s.split_once('=').with_context(||format!("invalid rule [{s}], expect [state:]code=action"))?;
+        let (state, code) = match target.split_once(':') {
+            Some((state, code)) => (Some(state.parse()?), code),
+            None => (None, target),
+        };
+        let code = match code.trim() {
+            "*" => None,
+            name => Some(MCodeType::from_name(name).with_context(||format!("unknown code [{name}]"))?),
+        };
+        Ok(Self { state, code, action: action.parse()? })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StrayPolicy {
+    rules: Vec<StrayRule>,
+}
+
+impl Default for StrayPolicy {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl StrayPolicy {
+    /// rules in front of the default HEARTBEAT one
+    pub fn new(mut rules: Vec<StrayRule>) -> Self {
+        rules.push(StrayRule { state: None, code: Some(MCodeType::HEARTBEAT), action: StrayAction::Ignore });
+        Self { rules }
+    }
+
+    pub fn action(&self, state: HandshakeState, code: u16) -> StrayAction {
+        self.rules.iter()
+        .find(|x| x.matches(state, code))
+        .map(|x| x.action)
+        .unwrap_or(StrayAction::Error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::vn_proto::MCodeType;
+
+    use super::{HandshakeState, StrayAction, StrayPolicy, StrayRule};
+
+    #[test]
+    fn test_stray_policy() {
+        let heartbeat = MCodeType::HEARTBEAT.code();
+        let play_ack = MCodeType::PLAY_ACK.code();
+        let policy = StrayPolicy::default();
+        assert_eq!(policy.action(HandshakeState::CnisupAck, heartbeat), StrayAction::Ignore);
+        assert_eq!(policy.action(HandshakeState::Register, play_ack), StrayAction::Error);
+
+        let rules = ["register:*=queue", "cnisup-ack:heartbeat=error"].iter().map(|x| x.parse().unwrap()).collect();
+        let policy = StrayPolicy::new(rules);
+        assert_eq!(policy.action(HandshakeState::Register, play_ack), StrayAction::Queue);
+        assert_eq!(policy.action(HandshakeState::Register, heartbeat), StrayAction::Queue);
+        assert_eq!(policy.action(HandshakeState::CnisupAck, heartbeat), StrayAction::Error);
+        assert_eq!(policy.action(HandshakeState::CnisupAck, play_ack), StrayAction::Error);
+
+        for s in ["heartbeat", "heartbeat=drop", "cnisup:heartbeat=ignore", "nosuch=ignore"] {
+            assert!(s.parse::<StrayRule>().is_err(), "{s}");
+        }
+    }
+
+    #[cfg(feature = "runtime")]
+    #[tokio::test]
+    async fn test_handshake_stray() {
+        use crate::{vn_proto::Header, vn_session::{cn_socket_path, CnSession}};
+
+        let dir = std::env::temp_dir().join(format!("rcn_handshake_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ms_path = dir.join("msvn");
+        let _r = std::fs::remove_file(&ms_path);
+        let ms = tokio::net::UnixDatagram::bind(&ms_path).unwrap();
+        let mut session = CnSession::bind(&dir, 5).await.unwrap();
+        let cn_path = cn_socket_path(&dir, 5).unwrap();
+        let send = |code: MCodeType| {
+            let mut data = Vec::new();
+            Header { code: code.code(), fsm_id: 5000000, ..Default::default() }.write_to(&mut data);
+            data
+        };
+
+        // heartbeat ignored, PLAY_ACK kept for later
+        session.set_stray_policy(StrayPolicy::new(vec!["play_ack=queue".parse().unwrap()]));
+        for code in [MCodeType::HEARTBEAT, MCodeType::PLAY_ACK, MCodeType::CNISUP_ACK, MCodeType::HEARTBEAT] {
+            ms.send_to(&send(code), &cn_path).await.unwrap();
+        }
+        session.handshake().await.unwrap();
+        assert_eq!(session.recv_packet().await.unwrap().code(), MCodeType::PLAY_ACK.code());
+        assert_eq!(session.recv_packet().await.unwrap().code(), MCodeType::HEARTBEAT.code());
+
+        session.set_stray_policy(StrayPolicy::default());
+        ms.send_to(&send(MCodeType::PLAY_ACK), &cn_path).await.unwrap();
+        assert!(session.accept_register().await.is_err());
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,175 @@
+//! artificial impairment of a channel's rtp, to characterize PLC and
+//! jitter buffers of a MS.
+//!
+//! An [`ImpairProfile`] loses, jitters, reorders and caps the bitrate of
+//! rtp ms-sim sends for a channel, into its pcap and on the wire by its
+//! echo. Profiles are set by fsm_id or for every channel from a scenario
+//! `impair` step or `rcn ctl impair`, and apply from the next packet on.
+//! Decisions come from a [`SimRng`] fork per channel, so a seeded run
+//! impairs the same packets again.
+
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::rng::SimRng;
+
+/// a packet held back by reorder is sent this much later, after the
+/// next one at 20ms ptime
+pub const REORDER_HOLD: Duration = Duration::from_millis(30);
+
+/// packets queued by bitrate_kbps for longer are lost, as by a full
+/// router queue
+pub const MAX_SHAPE_DELAY: Duration = Duration::from_millis(200);
+
+static PROFILES: Mutex<Profiles> = Mutex::new(Profiles { all: None, channels: BTreeMap::new() });
+
+struct Profiles {
+    all: Option<ImpairProfile>,
+    channels: BTreeMap<u32, ImpairProfile>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImpairProfile {
+    /// percent of packets lost
+    pub loss_pct: f64,
+    /// extra random delay of a packet in [0, jitter_ms)
+    pub jitter_ms: u64,
+    /// percent of packets held back by [`REORDER_HOLD`]
+    pub reorder_pct: f64,
+    /// packets queued to stay under this, 0 for no cap
+    pub bitrate_kbps: u32,
+}
+
+impl ImpairProfile {
+    pub fn is_none(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for (name, pct) in [("loss_pct", self.loss_pct), ("reorder_pct", self.reorder_pct)] {
+            if !(0.0..=100.0).contains(&pct) {
+                bail!("{name} [{pct}] not in 0..=100")
+            }
+        }
+        Ok(())
+    }
+}
+
+/// profile of fsm_id from now on, or if None of every channel without one
+/// of its own, a none profile clears it
+pub fn set_profile(fsm_id: Option<u32>, profile: ImpairProfile) -> Result<()> {
+    profile.validate()?;
+    let mut profiles = PROFILES.lock().unwrap_or_else(|e| e.into_inner());
+    let profile = (!profile.is_none()).then_some(profile);
+    match (fsm_id, profile) {
+        (Some(fsm_id), Some(profile)) => { profiles.channels.insert(fsm_id, profile); },
+        (Some(fsm_id), None) => { profiles.channels.remove(&fsm_id); },
+        (None, profile) => profiles.all = profile,
+    }
+    Ok(())
+}
+
+/// profile in force for fsm_id
+pub fn profile_of(fsm_id: u32) -> ImpairProfile {
+    let profiles = PROFILES.lock().unwrap_or_else(|e| e.into_inner());
+    profiles.channels.get(&fsm_id).or(profiles.all.as_ref()).copied().unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ImpairStats {
+    pub packets: u64,
+    /// by loss_pct
+    pub lost: u64,
+    /// by bitrate_kbps, queued too long
+    pub shaped: u64,
+    pub reordered: u64,
+}
+
+/// impairment of one channel's packets in send order
+#[derive(Debug, Clone)]
+pub struct Impairer {
+    rng: SimRng,
+    /// when the bitrate cap lets the next packet go, on the timeline of decide
+    busy_until: Duration,
+    stats: ImpairStats,
+}
+
+impl Impairer {
+    pub fn new(rng: SimRng) -> Self {
+        Self { rng, busy_until: Duration::ZERO, stats: ImpairStats::default() }
+    }
+
+    /// delay of a packet of len bytes due at `at`, from any fixed origin,
+    /// None if lost
+    pub fn decide(&mut self, profile: &ImpairProfile, at: Duration, len: usize) -> Option<Duration> {
+        self.stats.packets += 1;
+        if profile.is_none() {
+            return Some(Duration::ZERO)
+        }
+        if profile.loss_pct > 0.0 && self.rng.chance(profile.loss_pct / 100.0) {
+            self.stats.lost += 1;
+            return None
+        }
+
+        let mut delay = Duration::ZERO;
+        if profile.bitrate_kbps > 0 {
+            let start = self.busy_until.max(at);
+            if start - at > MAX_SHAPE_DELAY {
+                self.stats.shaped += 1;
+                return None
+            }
+            self.busy_until = start + Duration::from_micros(len as u64 * 8 * 1000 / profile.bitrate_kbps as u64);
+            delay = start - at;
+        }
+        delay += Duration::from_millis(self.rng.range(0, profile.jitter_ms));
+        if profile.reorder_pct > 0.0 && self.rng.chance(profile.reorder_pct / 100.0) {
+            self.stats.reordered += 1;
+            delay += REORDER_HOLD;
+        }
+        Some(delay)
+    }
+
+    pub fn stats(&self) -> &ImpairStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{utils::rng::SimRng, vn_rtp::PTIME};
+
+    use super::{profile_of, set_profile, ImpairProfile, Impairer, REORDER_HOLD};
+
+    #[test]
+    fn test_impair() {
+        let loss = ImpairProfile { loss_pct: 10.0, jitter_ms: 30, ..Default::default() };
+        let run = |seed, profile: ImpairProfile| {
+            let mut impairer = Impairer::new(SimRng::new(seed));
+            let delays: Vec<_> = (0..1000).map(|n| impairer.decide(&profile, PTIME * n, 172)).collect();
+            (delays, *impairer.stats())
+        };
+        let (delays, stats) = run(7, loss);
+        assert_eq!(run(7, loss).0, delays);
+        assert!((50..150).contains(&stats.lost), "{stats:?}");
+        assert!(delays.iter().flatten().all(|x| *x < Duration::from_millis(30)));
+
+        // 172 bytes every 20ms is 68.8 kbps, at 64 the queue grows until
+        // packets wait too long
+        let (delays, stats) = run(7, ImpairProfile { bitrate_kbps: 64, ..Default::default() });
+        assert_eq!((delays[0], delays[1]), (Some(Duration::ZERO), Some(Duration::from_micros(1500))));
+        assert!(stats.shaped > 0 && stats.lost == 0, "{stats:?}");
+        let (delays, stats) = run(7, ImpairProfile { reorder_pct: 100.0, ..Default::default() });
+        assert!(delays.iter().all(|x| *x == Some(REORDER_HOLD)) && stats.reordered == 1000);
+
+        assert!(set_profile(Some(9000101), ImpairProfile { loss_pct: 101.0, ..Default::default() }).is_err());
+        set_profile(Some(9000101), loss).unwrap();
+        assert_eq!(profile_of(9000101), loss);
+        set_profile(Some(9000101), ImpairProfile::default()).unwrap();
+        assert!(profile_of(9000101) != loss);
+    }
+}
@@ -0,0 +1,242 @@
+//! packets of a file sent into a live session, `rcn ctl inject`.
+//!
+//! The file is a capture (see vn_capture), its cn_to_ms records are sent
+//! keeping their spacing by ts_us. Injections are queued by the ctl thread
+//! and taken by the session loop, which sends each packet between two it
+//! handles so none lands in the middle of a send, numbered with the next
+//! sn of its fsm_id. fsm_ids are remapped: link level ones (base of a CN)
+//! to the base of the session, channel ones to the given fsm_id or kept if
+//! inside the session's space. An injection that can't be remapped is
+//! dropped whole rather than sent in part.
+
+use std::{
+    collections::{BTreeSet, VecDeque},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::{
+    utils::datagram::Datagram,
+    vn_capture::{read_capture, CaptureDir, CaptureRecord},
+    vn_fsm_id::{FsmIdSpace, DEFAULT_SPAN},
+    vn_proto::{CodeName, Header, PacketRef},
+    vn_session::CnSession,
+};
+
+static PENDING: Mutex<VecDeque<Injection>> = Mutex::new(VecDeque::new());
+
+static SUBMITTED: Notify = Notify::const_new();
+
+#[derive(Debug, Clone)]
+pub struct Injection {
+    source: String,
+    /// offset from the first one, whole packet
+    packets: Vec<(Duration, Vec<u8>)>,
+    /// fsm_id of channel packets
+    target: Option<u32>,
+}
+
+impl Injection {
+    pub fn load(path: &Path, target: Option<u32>) -> Result<Self> {
+        Self::from_records(&path.display().to_string(), &read_capture(path)?, target)
+    }
+
+    /// cn_to_ms records, invalid ones are an error
+    pub fn from_records(source: &str, records: &[CaptureRecord], target: Option<u32>) -> Result<Self> {
+        let mut packets = Vec::new();
+        let mut channels = BTreeSet::new();
+        let first = records.iter().find(|x| x.dir == CaptureDir::CnToMs).map(|x| x.ts_us).unwrap_or(0);
+        for (n, record) in records.iter().enumerate().filter(|x| x.1.dir == CaptureDir::CnToMs) {
+            let data = record.data()?;
+            let Ok(packet) = PacketRef::parse_from(&data[..]) else {
+                bail!("invalid packet of record [{}] [{}]", n + 1, record.hex)
+            };
+            if !is_link_level(packet.fsm_id()) {
+                channels.insert(packet.fsm_id());
+            }
+            packets.push((Duration::from_micros(record.ts_us.saturating_sub(first)), data));
+        }
+        if packets.is_empty() {
+            bail!("no cn_to_ms packets in [{source}]")
+        }
+        if target.is_some() && channels.len() > 1 {
+            bail!("[{}] channels in [{source}], remap takes one", channels.len())
+        }
+        Ok(Self { source: source.to_string(), packets, target })
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// headers remapped into space, sn left for the session
+    fn remap(&self, space: FsmIdSpace) -> Result<VecDeque<(Duration, Header, Vec<u8>)>> {
+        let mut out = VecDeque::with_capacity(self.packets.len());
+        for (offset, data) in self.packets.iter() {
+            let packet = PacketRef::parse_from(&data[..])?;
+            let fsm_id = match (is_link_level(packet.fsm_id()), self.target) {
+                (true, _) => space.base,
+                (false, Some(target)) => target,
+                (false, None) if space.contains(packet.fsm_id()) => packet.fsm_id(),
+                (false, None) => bail!(
+                    "[{}] of fsm_id [{}] outside [{space}], give one to remap to",
+                    CodeName(packet.code()), packet.fsm_id(),
+                ),
+            };
+            let header = Header { code: packet.code(), fsm_id, key: packet.key(), sn: 0 };
+            out.push_back((*offset, header, packet.payload().to_vec()));
+        }
+        Ok(out)
+    }
+}
+
+/// fsm_id of link level packets, base of a CN
+fn is_link_level(fsm_id: u32) -> bool {
+    fsm_id.is_multiple_of(DEFAULT_SPAN)
+}
+
+/// queue for the session loop, packets queued
+pub fn submit(injection: Injection) -> usize {
+    let len = injection.len();
+    info!("inject [{}] packets of [{}] queued", len, injection.source);
+    PENDING.lock().unwrap_or_else(|e| e.into_inner()).push_back(injection);
+    SUBMITTED.notify_one();
+    len
+}
+
+fn take_pending() -> Option<Injection> {
+    PENDING.lock().unwrap_or_else(|e| e.into_inner()).pop_front()
+}
+
+/// injection being sent by a session loop
+#[derive(Debug, Default)]
+pub struct Injector {
+    started: Option<Instant>,
+    packets: VecDeque<(Duration, Header, Vec<u8>)>,
+    sent: u64,
+}
+
+impl Injector {
+    /// packets due by now, the next queued injection is taken once the
+    /// current one is done
+    pub fn due(&mut self, now: Instant, space: FsmIdSpace) -> Vec<(Header, Vec<u8>)> {
+        if self.packets.is_empty() {
+            let Some(injection) = take_pending() else { return Vec::new() };
+            match injection.remap(space) {
+                Ok(packets) => {
+                    info!("inject [{}] packets of [{}]", packets.len(), injection.source);
+                    self.packets = packets;
+                    self.started = Some(now);
+                },
+                Err(e) => {
+                    warn!("inject [{}] dropped [{e:#}]", injection.source);
+                    return Vec::new()
+                },
+            }
+        }
+
+        let started = self.started.unwrap_or(now);
+        let mut due = Vec::new();
+        while self.packets.front().is_some_and(|x| started + x.0 <= now) {
+            let Some((_offset, header, payload)) = self.packets.pop_front() else { break };
+            due.push((header, payload));
+        }
+        self.sent += due.len() as u64;
+        due
+    }
+
+    /// resolves when packets may be due, at the next one of the current
+    /// injection or once one is queued
+    pub async fn ready(&self) {
+        if let (Some(started), Some((offset, ..))) = (self.started, self.packets.front()) {
+            return tokio::time::sleep_until((started + *offset).into()).await
+        }
+        // a permit left by a submit taken earlier wakes for nothing
+        while PENDING.lock().unwrap_or_else(|e| e.into_inner()).is_empty() {
+            SUBMITTED.notified().await
+        }
+    }
+
+    /// packets injected so far
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+}
+
+/// due packets sent on session with its next sn
+pub async fn send_due<S: Datagram>(injector: &mut Injector, session: &mut CnSession<S>, now: Instant) -> Result<usize> {
+    let due = injector.due(now, session.fsm_ids().space());
+    for (mut header, payload) in due.iter().cloned() {
+        header.sn = session.next_sn(header.fsm_id);
+        info!("inject {header:?}, payload [{}]", payload.len());
+        session.send_packet(&header, &payload).await?;
+    }
+    Ok(due.len())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::{
+        vn_capture::{CaptureDir, CaptureRecord},
+        vn_fsm_id::FsmIdSpace,
+        vn_proto::{Header, MCodeType},
+    };
+
+    use super::{submit, Injection, Injector};
+
+    fn record(ts_ms: u64, dir: CaptureDir, code: u16, fsm_id: u32) -> CaptureRecord {
+        let mut data = Vec::new();
+        Header { code, fsm_id, sn: 40, key: 3 }.write_to2(&mut data, &[1, 2, 3][..]);
+        CaptureRecord::new(Duration::from_millis(ts_ms), dir, None, &data)
+    }
+
+    #[tokio::test]
+    async fn test_inject() {
+        let records = [
+            record(1000, CaptureDir::CnToMs, MCodeType::HEARTBEAT.code(), 9000000),
+            record(1000, CaptureDir::MsToCn, MCodeType::HEARTBEAT.code(), 9000000),
+            record(1000, CaptureDir::CnToMs, 0x7777, 9000003),
+            record(1050, CaptureDir::CnToMs, MCodeType::PLAY.code(), 9000003),
+        ];
+        let space = FsmIdSpace::of_cn(5);
+        assert!(Injection::from_records("odd", &records[1..2], None).is_err());
+        let two = [records[2].clone(), record(0, CaptureDir::CnToMs, 0x7777, 9000004)];
+        assert!(Injection::from_records("two", &two, Some(5000001)).is_err());
+
+        // outside the space without a target, dropped whole
+        let t0 = Instant::now();
+        let mut injector = Injector::default();
+        submit(Injection::from_records("odd", &records, None).unwrap());
+        assert!(injector.due(t0, space).is_empty());
+        assert!(injector.due(t0, space).is_empty());
+
+        assert_eq!(submit(Injection::from_records("odd", &records, Some(5000001)).unwrap()), 3);
+        let due = injector.due(t0, space);
+        let headers: Vec<(u16, u32, i16)> = due.iter().map(|x| (x.0.code, x.0.fsm_id, x.0.key)).collect();
+        assert_eq!(headers, [(MCodeType::HEARTBEAT.code(), 5000000, 3), (0x7777, 5000001, 3)]);
+        assert_eq!(due[1].1, [1, 2, 3]);
+        assert!(injector.due(t0 + Duration::from_millis(49), space).is_empty());
+        tokio::time::timeout(Duration::from_secs(1), injector.ready()).await.unwrap();
+        assert!(Instant::now() >= t0 + Duration::from_millis(50));
+        let due = injector.due(t0 + Duration::from_millis(50), space);
+        assert_eq!((due[0].0.code, due[0].0.fsm_id), (MCodeType::PLAY.code(), 5000001));
+        assert_eq!(injector.sent(), 3);
+
+        // idle until the next one is queued
+        assert!(tokio::time::timeout(Duration::from_millis(20), injector.ready()).await.is_err());
+        submit(Injection::from_records("odd", &records, Some(5000001)).unwrap());
+        tokio::time::timeout(Duration::from_secs(1), injector.ready()).await.unwrap();
+        assert_eq!(injector.due(Instant::now(), space).len(), 2);
+    }
+}
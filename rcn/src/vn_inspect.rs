@@ -0,0 +1,237 @@
+//! step through a capture as a journal of channel transitions.
+//!
+//! Records are fed to a [`ChannelRegistry`] the way vn_pool feeds it, at
+//! the times of the capture, and the registry report is kept after each
+//! record so going back is only picking an earlier [`Frame`].
+//! `rcn inspect` walks the frames interactively.
+
+use std::{collections::HashMap, fmt::{self, Write as _}, time::{Duration, Instant}};
+
+use crate::{
+    vn_capture::{CaptureDir, CaptureRecord},
+    vn_channels::{ChannelRegistry, ChannelReport, EndReason},
+    vn_proto::{CodeName, MCodeType, PacketRef, RequestChannelAckRef, RequestChannelRef},
+};
+
+/// what happened to a channel at a record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Requested,
+    /// REQUESTCHANNEL_ACK with result 0
+    Accepted,
+    Ended(EndReason),
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Requested => f.write_str("requested"),
+            Self::Accepted => f.write_str("accepted"),
+            Self::Ended(reason) => write!(f, "ended [{reason}]"),
+        }
+    }
+}
+
+/// registry after one record
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub ts_us: u64,
+    pub dir: CaptureDir,
+    /// None if the record isn't a packet
+    pub code: Option<u16>,
+    pub fsm_id: Option<u32>,
+    /// by fsm_id, expired ones first
+    pub transitions: Vec<(u32, Transition)>,
+    /// lifetimes of active channels as of this record
+    pub report: ChannelReport,
+}
+
+#[derive(Debug, Default)]
+pub struct Journal {
+    frames: Vec<Frame>,
+}
+
+impl Journal {
+    pub fn replay(records: &[CaptureRecord]) -> Self {
+        let base = Instant::now();
+        let start = records.first().map(|x| x.ts_us).unwrap_or(0);
+        let mut registry = ChannelRegistry::default();
+        let mut requested: HashMap<u32, u64> = HashMap::new();
+        let mut registered = false;
+        let mut frames = Vec::with_capacity(records.len());
+
+        for record in records {
+            let now = base + Duration::from_micros(record.ts_us.saturating_sub(start));
+            let mut transitions: Vec<(u32, Transition)> = registry.expire(now).into_iter()
+            .map(|x| (x, Transition::Ended(EndReason::Expired)))
+            .collect();
+
+            let data = record.data().ok();
+            let packet = data.as_deref().and_then(|x| PacketRef::parse_from(x).ok());
+            if let Some(packet) = packet.as_ref() {
+                let (fsm_id, code, payload) = (packet.fsm_id(), packet.code(), packet.payload());
+                match (record.dir, MCodeType::try_from(code)) {
+                    (CaptureDir::CnToMs, Ok(MCodeType::REQUESTCHANNEL)) => {
+                        let life_seconds = RequestChannelRef::parse_from(payload).map(|x| x.part1().life_seconds()).unwrap_or(0);
+                        registry.on_requested(fsm_id, life_seconds, now);
+                        registry.on_packet_at(fsm_id, code, payload, now);
+                        requested.insert(fsm_id, record.ts_us);
+                        transitions.push((fsm_id, Transition::Requested));
+                    },
+                    (CaptureDir::CnToMs, Ok(MCodeType::RELEASECHANNEL)) => {
+                        registry.on_packet_at(fsm_id, code, payload, now);
+                        if registry.end(fsm_id, EndReason::Released, now) {
+                            transitions.push((fsm_id, Transition::Ended(EndReason::Released)));
+                        }
+                    },
+                    (CaptureDir::MsToCn, Ok(MCodeType::REGISTER)) => {
+                        // MS registering again forgot its channels
+                        if registered {
+                            let mut lost: Vec<u32> = requested.keys().copied().filter(|x| registry.is_open(*x)).collect();
+                            lost.sort_unstable();
+                            for fsm_id in lost {
+                                registry.end(fsm_id, EndReason::MsRestarted, now);
+                                transitions.push((fsm_id, Transition::Ended(EndReason::MsRestarted)));
+                            }
+                        }
+                        registered = true;
+                    },
+                    (CaptureDir::MsToCn, Ok(MCodeType::REQUESTCHANNEL_ACK)) if registry.is_open(fsm_id) => {
+                        registry.on_packet_at(fsm_id, code, payload, now);
+                        let result = RequestChannelAckRef::parse_from(payload).map(|x| x.part1().result()).unwrap_or(u8::MAX);
+                        if result == 0 {
+                            transitions.push((fsm_id, Transition::Accepted));
+                        } else {
+                            registry.end(fsm_id, EndReason::MsError(result), now);
+                            transitions.push((fsm_id, Transition::Ended(EndReason::MsError(result))));
+                        }
+                    },
+                    _ => registry.on_packet_at(fsm_id, code, payload, now),
+                }
+            }
+
+            let mut report = registry.report();
+            for active in report.active.iter_mut() {
+                let since = requested.get(&active.fsm_id).copied().unwrap_or(record.ts_us);
+                active.lifetime_ms = record.ts_us.saturating_sub(since) / 1000;
+            }
+            frames.push(Frame {
+                ts_us: record.ts_us,
+                dir: record.dir,
+                code: packet.as_ref().map(|x| x.code()),
+                fsm_id: packet.as_ref().map(|x| x.fsm_id()),
+                transitions,
+                report,
+            });
+        }
+        Self { frames }
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// first frame after n with a transition
+    pub fn next_transition(&self, n: usize) -> Option<usize> {
+        (n + 1..self.frames.len()).find(|x| !self.frames[*x].transitions.is_empty())
+    }
+
+    /// last frame before n with a transition
+    pub fn prev_transition(&self, n: usize) -> Option<usize> {
+        (0..n.min(self.frames.len())).rev().find(|x| !self.frames[*x].transitions.is_empty())
+    }
+
+    /// record, its transitions then the registry, one item per line
+    pub fn render(&self, n: usize) -> String {
+        let Some(frame) = self.frames.get(n) else { return format!("no step #{n}\n") };
+        let start = self.frames[0].ts_us;
+        let mut out = String::new();
+        let dir = match frame.dir {
+            CaptureDir::CnToMs => "cn -> ms",
+            CaptureDir::MsToCn => "ms -> cn",
+        };
+        let _r = write!(out, "#{n}/{} +{:.3} ms {dir}", self.frames.len() - 1, frame.ts_us.saturating_sub(start) as f64 / 1000.0);
+        match (frame.code, frame.fsm_id) {
+            (Some(code), Some(fsm_id)) => { let _r = writeln!(out, " {} fsm_id [{fsm_id}]", CodeName(code)); },
+            _ => out.push_str(" not a packet\n"),
+        }
+        for (fsm_id, transition) in frame.transitions.iter() {
+            let _r = writeln!(out, "  * [{fsm_id}] {transition}");
+        }
+
+        let _r = writeln!(out, "open [{}]", frame.report.open);
+        for x in frame.report.active.iter() {
+            let codes: Vec<String> = x.detail.codes.iter().map(|x| CodeName(*x).to_string()).collect();
+            let ack = x.detail.ack_result.map(|x| x.to_string()).unwrap_or_else(|| "-".into());
+            let _r = writeln!(out, "  [{}] {} ms, ack [{ack}], codes [{}]", x.fsm_id, x.lifetime_ms, codes.join(" "));
+        }
+        for (reason, num) in frame.report.ended.iter() {
+            let _r = writeln!(out, "ended [{reason}]: [{num}]");
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        vn_capture::{CaptureDir, CaptureRecord},
+        vn_channels::EndReason,
+        vn_proto::{Header, MCodeType, RequestChannel, RequestChannelAck},
+    };
+
+    use super::{Journal, Transition};
+
+    fn record(ms: u64, dir: CaptureDir, code: MCodeType, fsm_id: u32, payload: &[u8]) -> CaptureRecord {
+        let mut data = Vec::new();
+        Header { code: code.code(), fsm_id, sn: 0, key: 0 }.write_to2(&mut data, payload);
+        CaptureRecord::new(Duration::from_millis(ms), dir, None, &data)
+    }
+
+    #[test]
+    fn test_journal() {
+        let mut req = Vec::new();
+        RequestChannel { life_seconds: 5, webrtc: vec!["".into()], ..Default::default() }.write_to(&mut req);
+        let (mut ok, mut rejected) = (Vec::new(), Vec::new());
+        RequestChannelAck { result: 0, ..Default::default() }.write_to(&mut ok);
+        RequestChannelAck { result: 3, ..Default::default() }.write_to(&mut rejected);
+
+        let records = vec![
+            record(0, CaptureDir::CnToMs, MCodeType::REQUESTCHANNEL, 1, &req),
+            record(10, CaptureDir::MsToCn, MCodeType::REQUESTCHANNEL_ACK, 1, &ok),
+            record(20, CaptureDir::CnToMs, MCodeType::REQUESTCHANNEL, 2, &req),
+            record(30, CaptureDir::MsToCn, MCodeType::REQUESTCHANNEL_ACK, 2, &rejected),
+            record(40, CaptureDir::CnToMs, MCodeType::HEARTBEAT, 0, &[]),
+            record(6000, CaptureDir::CnToMs, MCodeType::HEARTBEAT, 0, &[]),
+        ];
+        let journal = Journal::replay(&records);
+        assert_eq!(journal.len(), 6);
+        let frames = journal.frames();
+        assert_eq!(frames[1].transitions, vec![(1, Transition::Accepted)]);
+        assert_eq!(frames[3].transitions, vec![(2, Transition::Ended(EndReason::MsError(3)))]);
+        assert_eq!(frames[4].report.open, 1);
+        assert_eq!(frames[4].report.active[0].lifetime_ms, 40);
+        assert_eq!(frames[5].transitions, vec![(1, Transition::Ended(EndReason::Expired))]);
+        assert_eq!(frames[5].report.open, 0);
+
+        // earlier frames keep their state
+        assert_eq!(frames[2].report.open, 2);
+        assert_eq!(journal.next_transition(3), Some(5));
+        assert_eq!(journal.prev_transition(3), Some(2));
+        assert_eq!(journal.prev_transition(0), None);
+
+        let text = journal.render(3);
+        assert!(text.starts_with("#3/5 +30.000 ms ms -> cn REQUESTCHANNEL_ACK fsm_id [2]\n  * [2] ended [ms-error(3)]\nopen [1]\n"), "{text}");
+        assert!(text.ends_with("ended [ms-error(3)]: [1]\n"), "{text}");
+    }
+}
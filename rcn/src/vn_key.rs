@@ -0,0 +1,110 @@
+//! interpretation of Header.key.
+//!
+//! Our MS puts a channel-group or board id into key. [`KeyMap`] takes it out
+//! with a mask and shift and names the groups, spec like
+//! `mask=0xff00,shift=8,1=board-a,2=board-b`.
+//! The default map uses the whole key as group.
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use anyhow::{Result, Context, bail};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyMap {
+    mask: u16,
+    shift: u8,
+    names: BTreeMap<u16, String>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        Self {
+            mask: 0xffff,
+            shift: 0,
+            names: BTreeMap::new(),
+        }
+    }
+}
+
+impl KeyMap {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut me = Self::default();
+        for item in spec.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            let (name, value) = item.split_once('=').with_context(||format!("invalid key map item [{item}], expect name=value"))?;
+            match name.trim() {
+                "mask" => me.mask = parse_u16(value).with_context(||format!("invalid key mask [{value}]"))?,
+                "shift" => me.shift = value.trim().parse().with_context(||format!("invalid key shift [{value}]"))?,
+                group => {
+                    let group = parse_u16(group).with_context(||format!("invalid key group [{group}]"))?;
+                    me.names.insert(group, value.trim().to_string());
+                },
+            }
+        }
+        if me.shift >= 16 {
+            bail!("key shift [{}] out of range", me.shift)
+        }
+        Ok(me)
+    }
+
+    /// group id carried in key
+    pub fn group(&self, key: i16) -> u16 {
+        (key as u16 & self.mask) >> self.shift
+    }
+
+    /// configured name of group, or the number
+    pub fn label(&self, key: i16) -> String {
+        let group = self.group(key);
+        self.names.get(&group).cloned().unwrap_or_else(|| group.to_string())
+    }
+
+    /// number of keys per label
+    pub fn count_by_label<I: IntoIterator<Item = i16>>(&self, keys: I) -> BTreeMap<String, u64> {
+        let mut counts = BTreeMap::new();
+        for key in keys {
+            *counts.entry(self.label(key)).or_default() += 1;
+        }
+        counts
+    }
+}
+
+impl FromStr for KeyMap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+/// decimal or 0x hex
+fn parse_u16(s: &str) -> Result<u16> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => Ok(u16::from_str_radix(hex, 16)?),
+        None => Ok(s.parse()?),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::KeyMap;
+
+    #[test]
+    fn test_key_map() {
+        let raw = KeyMap::default();
+        assert_eq!((raw.group(-1), raw.label(3)), (0xffff, "3".to_string()));
+
+        let map = KeyMap::parse("mask=0xff00, shift=8, 1=board-a, 0x2=board-b").unwrap();
+        assert_eq!(map.group(0x0105), 1);
+        assert_eq!(map.label(0x0105), "board-a");
+        assert_eq!(map.label(0x02ff), "board-b");
+        assert_eq!(map.label(0x0300), "3");
+
+        let counts = map.count_by_label([0x0101, 0x0102, 0x0201]);
+        assert_eq!(counts.get("board-a"), Some(&2));
+        assert_eq!(counts.get("board-b"), Some(&1));
+
+        assert!(KeyMap::parse("shift=16").is_err());
+        assert!(KeyMap::parse("mask").is_err());
+        assert!(KeyMap::parse("x=board").is_err());
+    }
+}
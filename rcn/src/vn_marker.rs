@@ -0,0 +1,164 @@
+//! correlation markers, harmless packets carrying an id rcn also logs, to
+//! line MS logs up with rcn captures when debugging together.
+//!
+//! A marker is a RESETLIFETIMER with payload `rcn-marker <id>`, on the link
+//! level fsm_id where no channel lives so no timer is touched, or on a
+//! channel given. MS logs it as any packet, rcn logs `marker [<id>]` with
+//! its fsm_id and sn, and captures have it as any other packet. Markers are
+//! sent by the scenario step `- marker: {id: bug-1234}`, or queued by
+//! `rcn ctl marker` for the session loop to send between two packets, as
+//! vn_inject does.
+
+use std::{
+    collections::VecDeque,
+    sync::{atomic::{AtomicU32, Ordering}, Mutex},
+};
+
+use anyhow::{Result, bail};
+use tokio::sync::Notify;
+use tracing::info;
+
+use crate::{
+    utils::datagram::Datagram,
+    vn_channels::unix_ms,
+    vn_proto::{Header, MCodeType, PacketRef},
+    vn_session::CnSession,
+};
+
+pub const MARKER_CODE: MCodeType = MCodeType::RESETLIFETIMER;
+
+/// payload of a marker is this and its id
+pub const MARKER_PREFIX: &str = "rcn-marker ";
+
+pub const MAX_ID_LEN: usize = 64;
+
+static PENDING: Mutex<VecDeque<Marker>> = Mutex::new(VecDeque::new());
+
+static SUBMITTED: Notify = Notify::const_new();
+
+static SEQ: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Marker {
+    pub id: String,
+    /// channel fsm_id, the link level one of the session if None
+    pub fsm_id: Option<u32>,
+}
+
+impl Marker {
+    /// one of unix ms and a count if no id
+    pub fn new(id: Option<&str>, fsm_id: Option<u32>) -> Result<Self> {
+        let id = match id {
+            Some(id) => {
+                check_id(id)?;
+                id.to_string()
+            },
+            None => new_id(),
+        };
+        Ok(Self { id, fsm_id })
+    }
+
+    pub fn payload(&self) -> Vec<u8> {
+        format!("{MARKER_PREFIX}{}", self.id).into_bytes()
+    }
+}
+
+/// printable ascii without spaces, so it is one word in any log
+pub fn check_id(id: &str) -> Result<()> {
+    if id.is_empty() || id.len() > MAX_ID_LEN {
+        bail!("marker id [{id}] must be 1 to [{MAX_ID_LEN}] bytes")
+    }
+    if !id.bytes().all(|x| x.is_ascii_graphic()) {
+        bail!("marker id [{id}] must be printable ascii without spaces")
+    }
+    Ok(())
+}
+
+/// e.g. `1760000000000-3`, unique within a process
+pub fn new_id() -> String {
+    format!("{}-{}", unix_ms(), SEQ.fetch_add(1, Ordering::Relaxed))
+}
+
+/// id of a marker packet, None if packet isn't one
+pub fn marker_id<'a>(packet: &PacketRef<'a>) -> Option<&'a str> {
+    if packet.code() != MARKER_CODE.code() {
+        return None
+    }
+    let id = packet.payload().strip_prefix(MARKER_PREFIX.as_bytes())?;
+    let id = std::str::from_utf8(id).ok()?;
+    check_id(id).ok().map(|_x| id)
+}
+
+/// sent with the next sn of its fsm_id and logged
+pub async fn send_marker<S: Datagram>(session: &mut CnSession<S>, marker: &Marker) -> Result<()> {
+    let fsm_id = marker.fsm_id.unwrap_or_else(|| session.base_fsm_id());
+    let header = Header { code: MARKER_CODE.code(), fsm_id, sn: session.next_sn(fsm_id), key: 0 };
+    session.send_packet(&header, &marker.payload()).await?;
+    info!("marker [{}] sent, fsm_id [{fsm_id}] sn [{}]", marker.id, header.sn);
+    Ok(())
+}
+
+/// queue for the session loop
+pub fn submit(marker: Marker) {
+    info!("marker [{}] queued", marker.id);
+    PENDING.lock().unwrap_or_else(|e| e.into_inner()).push_back(marker);
+    SUBMITTED.notify_one();
+}
+
+/// resolves once a marker was queued, for the session loop to wake up
+pub async fn submitted() {
+    if !PENDING.lock().unwrap_or_else(|e| e.into_inner()).is_empty() {
+        return
+    }
+    SUBMITTED.notified().await
+}
+
+/// markers queued so far, taken
+pub fn take_pending() -> Vec<Marker> {
+    PENDING.lock().unwrap_or_else(|e| e.into_inner()).drain(..).collect()
+}
+
+/// queued markers sent on session, returns how many
+pub async fn send_pending<S: Datagram>(session: &mut CnSession<S>) -> Result<usize> {
+    let markers = take_pending();
+    for marker in markers.iter() {
+        send_marker(session, marker).await?;
+    }
+    Ok(markers.len())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{vn_proto::PacketRef, vn_session::CnSession};
+
+    use super::{check_id, marker_id, send_pending, submit, submitted, Marker};
+
+    #[tokio::test]
+    async fn test_marker() {
+        assert!(check_id("bug-1234").is_ok());
+        for id in ["", "two words", "é", &"x".repeat(65)] {
+            assert!(check_id(id).is_err(), "{id}");
+        }
+        let generated = Marker::new(None, None).unwrap();
+        assert_ne!(generated.id, Marker::new(None, None).unwrap().id);
+
+        let dir = std::env::temp_dir().join(format!("rcn_marker_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ms_path = dir.join("msvn");
+        let _r = std::fs::remove_file(&ms_path);
+        let ms = tokio::net::UnixDatagram::bind(&ms_path).unwrap();
+        let mut session = CnSession::bind(&dir, 5).await.unwrap();
+
+        submit(Marker::new(Some("bug-1234"), Some(5000003)).unwrap());
+        tokio::time::timeout(std::time::Duration::from_secs(1), submitted()).await.unwrap();
+        assert_eq!(send_pending(&mut session).await.unwrap(), 1);
+        assert_eq!(send_pending(&mut session).await.unwrap(), 0);
+
+        let mut buf = vec![0_u8; 1500];
+        let len = ms.recv(&mut buf).await.unwrap();
+        let packet = PacketRef::parse_from(&buf[..len]).unwrap();
+        assert_eq!((packet.fsm_id(), packet.sn()), (5000003, 1));
+        assert_eq!(marker_id(&packet), Some("bug-1234"));
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+}
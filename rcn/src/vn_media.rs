@@ -0,0 +1,399 @@
+//! prompt files referenced by FILENAME tags, resolved under a media root.
+//!
+//! A FILENAME of `tone:<hz>[+<hz>]` or `dtmf:<digits>` is an in-band tone
+//! synthesized by MS instead, see [`ToneSpec`]. Options follow as
+//! `;ms=<tone ms>;gap=<ms between digits>;level=<dBFS per frequency>`.
+
+use std::{collections::HashMap, path::{Path, PathBuf}, time::Duration};
+
+use anyhow::{Result, Context, bail};
+
+use crate::vn_proto::{FileFormat, FilenameRef};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssetProblem {
+    /// not file://, e.g. http, left to MS
+    NotLocal,
+    Undecodable,
+    Missing(PathBuf),
+    UnknownFormat(u8),
+    FormatMismatch(FileFormat),
+}
+
+/// resolves file://xxx to root/xxx and caches probed durations
+#[derive(Debug)]
+pub struct MediaCatalog {
+    root: PathBuf,
+    durations: HashMap<PathBuf, Option<Duration>>,
+}
+
+impl MediaCatalog {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            durations: Default::default(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// None for non local urls
+    pub fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let rel = name.strip_prefix("file://")?;
+        Some(self.root.join(rel.trim_start_matches('/')))
+    }
+
+    /// problems of one FILENAME tag, empty if fine
+    pub fn check(&self, file: &FilenameRef<'_>) -> Vec<AssetProblem> {
+        let mut problems = Vec::new();
+
+        let Some(name) = file.filename().decode() else {
+            problems.push(AssetProblem::Undecodable);
+            return problems
+        };
+
+        let Some(path) = self.resolve(&name) else {
+            problems.push(AssetProblem::NotLocal);
+            return problems
+        };
+
+        if !path.is_file() {
+            problems.push(AssetProblem::Missing(path));
+        }
+
+        match file.file_format() {
+            Some(format) if !format.matches_path(&name) => problems.push(AssetProblem::FormatMismatch(format)),
+            Some(_) => {},
+            None => problems.push(AssetProblem::UnknownFormat(file.format())),
+        }
+
+        problems
+    }
+
+    /// duration of a wav prompt, probed once and cached,
+    /// None if not local or not a wav
+    pub fn duration(&mut self, name: &str) -> Result<Option<Duration>> {
+        let Some(path) = self.resolve(name) else { return Ok(None) };
+
+        if let Some(v) = self.durations.get(&path) {
+            return Ok(*v)
+        }
+
+        let is_wav = FileFormat::Wav.matches_path(name);
+        let duration = if is_wav { Some(probe_wav_duration(&path)?) } else { None };
+        self.durations.insert(path, duration);
+        Ok(duration)
+    }
+}
+
+/// level of each frequency of a synthesized tone
+pub const DEFAULT_TONE_DBFS: f64 = -10.0;
+const DEFAULT_TONE_MS: u64 = 1000;
+const DEFAULT_DTMF_MS: u64 = 100;
+const DEFAULT_DTMF_GAP_MS: u64 = 50;
+
+const DTMF_ROWS: [f64; 4] = [697.0, 770.0, 852.0, 941.0];
+const DTMF_COLS: [f64; 4] = [1209.0, 1336.0, 1477.0, 1633.0];
+const DTMF_KEYS: [[char; 4]; 4] = [['1', '2', '3', 'A'], ['4', '5', '6', 'B'], ['7', '8', '9', 'C'], ['*', '0', '#', 'D']];
+
+/// (row, column) frequencies of a dtmf digit
+pub fn dtmf_freqs(digit: char) -> Option<(f64, f64)> {
+    let digit = digit.to_ascii_uppercase();
+    DTMF_KEYS.iter().enumerate().find_map(|(row, keys)| {
+        keys.iter().position(|x| *x == digit).map(|col| (DTMF_ROWS[row], DTMF_COLS[col]))
+    })
+}
+
+/// digit of (row, column) indexes into dtmf_grid
+pub fn dtmf_digit(row: usize, col: usize) -> char {
+    DTMF_KEYS[row][col]
+}
+
+/// (rows, columns) of the dtmf keypad
+pub fn dtmf_grid() -> (&'static [f64; 4], &'static [f64; 4]) {
+    (&DTMF_ROWS, &DTMF_COLS)
+}
+
+/// sum of sines, each at level_dbfs
+pub fn tone_samples(freqs: &[f64], level_dbfs: f64, duration: Duration, sample_rate: u32) -> Vec<i16> {
+    let amplitude = 32767.0 * 10_f64.powf(level_dbfs / 20.0);
+    let num = (sample_rate as f64 * duration.as_secs_f64()) as usize;
+    let steps: Vec<f64> = freqs.iter().map(|x| std::f64::consts::TAU * x / sample_rate as f64).collect();
+    (0..num).map(|n| {
+        let value: f64 = steps.iter().map(|step| (n as f64 * step).sin() * amplitude).sum();
+        value.clamp(i16::MIN as f64, i16::MAX as f64) as i16
+    })
+    .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ToneKind {
+    Freqs(Vec<f64>),
+    Dtmf(String),
+}
+
+/// in-band tone of a `tone:` or `dtmf:` FILENAME
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToneSpec {
+    pub kind: ToneKind,
+    /// per frequency
+    pub level_dbfs: f64,
+    /// of the tone, or of each digit
+    pub tone: Duration,
+    /// silence between digits
+    pub gap: Duration,
+}
+
+impl ToneSpec {
+    pub fn dtmf(digits: &str) -> Self {
+        Self {
+            kind: ToneKind::Dtmf(digits.to_string()),
+            level_dbfs: DEFAULT_TONE_DBFS,
+            tone: Duration::from_millis(DEFAULT_DTMF_MS),
+            gap: Duration::from_millis(DEFAULT_DTMF_GAP_MS),
+        }
+    }
+
+    /// Ok(None) for ordinary files
+    pub fn parse(filename: &str) -> Result<Option<Self>> {
+        let (mut me, rest) = if let Some(rest) = filename.strip_prefix("dtmf:") {
+            let (digits, opts) = rest.split_once(';').unwrap_or((rest, ""));
+            if digits.is_empty() {
+                bail!("no dtmf digits [{filename}]")
+            }
+            if let Some(c) = digits.chars().find(|x| dtmf_freqs(*x).is_none()) {
+                bail!("invalid dtmf digit [{c}] of [{filename}]")
+            }
+            (Self::dtmf(digits), opts)
+        } else if let Some(rest) = filename.strip_prefix("tone:") {
+            let (freqs, opts) = rest.split_once(';').unwrap_or((rest, ""));
+            let freqs = freqs.split('+')
+            .map(|x| x.trim().parse::<f64>().ok().filter(|x| *x > 0.0).with_context(||format!("invalid tone frequency [{x}] of [{filename}]")))
+            .collect::<Result<Vec<_>>>()?;
+            let me = Self {
+                kind: ToneKind::Freqs(freqs),
+                tone: Duration::from_millis(DEFAULT_TONE_MS),
+                ..Self::dtmf("")
+            };
+            (me, opts)
+        } else {
+            return Ok(None)
+        };
+
+        for opt in rest.split(';').filter(|x| !x.is_empty()) {
+            let (name, value) = opt.split_once('=').with_context(||format!("invalid tone option [{opt}] of [{filename}]"))?;
+            let ms = || value.parse::<u64>().map(Duration::from_millis).with_context(||format!("invalid [{name}] of [{filename}]"));
+            match name {
+                "ms" => me.tone = ms()?,
+                "gap" => me.gap = ms()?,
+                "level" => me.level_dbfs = value.parse().with_context(||format!("invalid level of [{filename}]"))?,
+                _ => bail!("unknown tone option [{name}] of [{filename}]"),
+            }
+        }
+        Ok(Some(me))
+    }
+
+    pub fn duration(&self) -> Duration {
+        match &self.kind {
+            ToneKind::Freqs(_) => self.tone,
+            ToneKind::Dtmf(digits) => {
+                let num = digits.chars().count() as u32;
+                self.tone * num + self.gap * num.saturating_sub(1)
+            },
+        }
+    }
+
+    pub fn synthesize(&self, sample_rate: u32) -> Vec<i16> {
+        match &self.kind {
+            ToneKind::Freqs(freqs) => tone_samples(freqs, self.level_dbfs, self.tone, sample_rate),
+            ToneKind::Dtmf(digits) => {
+                let gap = vec![0; (sample_rate as f64 * self.gap.as_secs_f64()) as usize];
+                let mut samples = Vec::new();
+                for (n, digit) in digits.chars().enumerate() {
+                    let Some((row, col)) = dtmf_freqs(digit) else { continue };
+                    if n > 0 {
+                        samples.extend_from_slice(&gap);
+                    }
+                    samples.extend(tone_samples(&[row, col], self.level_dbfs, self.tone, sample_rate));
+                }
+                samples
+            },
+        }
+    }
+}
+
+/// read RIFF header, duration = data bytes / byte rate
+pub fn probe_wav_duration(path: &Path) -> Result<Duration> {
+    let data = std::fs::read(path).with_context(||format!("read wav failed [{path:?}]"))?;
+    wav_duration(&data[..]).with_context(||format!("invalid wav [{path:?}]"))
+}
+
+/// mono 16bit pcm RIFF/WAVE
+pub fn wav_bytes(sample_rate: u32, samples: &[i16]) -> Vec<u8> {
+    let size = (samples.len() * 2) as u32;
+    let mut data = Vec::with_capacity(44 + size as usize);
+    data.extend_from_slice(b"RIFF");
+    data.extend_from_slice(&(36 + size).to_le_bytes());
+    data.extend_from_slice(b"WAVEfmt ");
+    data.extend_from_slice(&16_u32.to_le_bytes());
+    data.extend_from_slice(&1_u16.to_le_bytes());
+    data.extend_from_slice(&1_u16.to_le_bytes());
+    data.extend_from_slice(&sample_rate.to_le_bytes());
+    data.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    data.extend_from_slice(&2_u16.to_le_bytes());
+    data.extend_from_slice(&16_u16.to_le_bytes());
+    data.extend_from_slice(b"data");
+    data.extend_from_slice(&size.to_le_bytes());
+    for sample in samples {
+        data.extend_from_slice(&sample.to_le_bytes());
+    }
+    data
+}
+
+pub fn write_wav(path: &Path, sample_rate: u32, samples: &[i16]) -> Result<()> {
+    std::fs::write(path, wav_bytes(sample_rate, samples)).with_context(||format!("write wav failed [{path:?}]"))
+}
+
+/// (sample_rate, samples) of a 16bit pcm wav, first channel only
+pub fn read_wav(path: &Path) -> Result<(u32, Vec<i16>)> {
+    let data = std::fs::read(path).with_context(||format!("read wav failed [{path:?}]"))?;
+    wav_samples(&data[..]).with_context(||format!("invalid wav [{path:?}]"))
+}
+
+fn wav_samples(data: &[u8]) -> Result<(u32, Vec<i16>)> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        bail!("not a RIFF/WAVE file")
+    }
+
+    // (channels, sample_rate)
+    let mut format = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos+4];
+        let size = u32::from_le_bytes([data[pos+4], data[pos+5], data[pos+6], data[pos+7]]) as usize;
+        let body = pos + 8;
+        match id {
+            b"fmt " => {
+                if size < 16 || body + 16 > data.len() {
+                    bail!("too short fmt chunk [{}]", size)
+                }
+                let tag = u16::from_le_bytes([data[body], data[body+1]]);
+                let channels = u16::from_le_bytes([data[body+2], data[body+3]]);
+                let rate = u32::from_le_bytes([data[body+4], data[body+5], data[body+6], data[body+7]]);
+                let bits = u16::from_le_bytes([data[body+14], data[body+15]]);
+                if tag != 1 || bits != 16 || channels == 0 {
+                    bail!("not 16bit pcm, format [{tag}] bits [{bits}] channels [{channels}]")
+                }
+                format = Some((channels as usize, rate));
+            },
+            b"data" => {
+                let (channels, rate) = format.with_context(||"data chunk before fmt chunk")?;
+                let size = size.min(data.len() - body);
+                let samples = data[body..body+size]
+                .chunks_exact(2 * channels)
+                .map(|x| i16::from_le_bytes([x[0], x[1]]))
+                .collect();
+                return Ok((rate, samples))
+            },
+            _ => {},
+        }
+        pos = body + size + (size & 1);
+    }
+
+    bail!("no data chunk")
+}
+
+fn wav_duration(data: &[u8]) -> Result<Duration> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        bail!("not a RIFF/WAVE file")
+    }
+
+    let mut byte_rate = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos+4];
+        let size = u32::from_le_bytes([data[pos+4], data[pos+5], data[pos+6], data[pos+7]]) as usize;
+        let body = pos + 8;
+        match id {
+            b"fmt " => {
+                if size < 16 || body + 16 > data.len() {
+                    bail!("too short fmt chunk [{}]", size)
+                }
+                let rate = u32::from_le_bytes([data[body+8], data[body+9], data[body+10], data[body+11]]);
+                byte_rate = Some(rate);
+            },
+            b"data" => {
+                let rate = byte_rate.with_context(||"data chunk before fmt chunk")?;
+                if rate == 0 {
+                    bail!("zero byte rate")
+                }
+                let size = size.min(data.len() - body);
+                return Ok(Duration::from_secs_f64(size as f64 / rate as f64))
+            },
+            _ => {},
+        }
+        pos = body + size + (size & 1);
+    }
+
+    bail!("no data chunk")
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{dtmf_digit, dtmf_freqs, wav_bytes, wav_duration, wav_samples, ToneKind, ToneSpec};
+
+    #[test]
+    fn test_wav_duration() {
+        // 8k mono 16bit, 16000 bytes of samples
+        let mut data = Vec::new();
+        data.extend_from_slice(b"RIFF");
+        data.extend_from_slice(&(36_u32 + 16000).to_le_bytes());
+        data.extend_from_slice(b"WAVEfmt ");
+        data.extend_from_slice(&16_u32.to_le_bytes());
+        data.extend_from_slice(&1_u16.to_le_bytes());
+        data.extend_from_slice(&1_u16.to_le_bytes());
+        data.extend_from_slice(&8000_u32.to_le_bytes());
+        data.extend_from_slice(&16000_u32.to_le_bytes());
+        data.extend_from_slice(&2_u16.to_le_bytes());
+        data.extend_from_slice(&16_u16.to_le_bytes());
+        data.extend_from_slice(b"data");
+        data.extend_from_slice(&16000_u32.to_le_bytes());
+        data.resize(data.len() + 16000, 0);
+
+        assert_eq!(wav_duration(&data[..]).unwrap(), Duration::from_secs(1));
+        assert!(wav_duration(b"RIFF0000AVI ").is_err());
+
+        assert_eq!(wav_bytes(8000, &[0; 8000]).len(), data.len());
+        assert_eq!(wav_duration(&wav_bytes(8000, &[0; 4000])).unwrap(), Duration::from_millis(500));
+        assert_eq!(wav_samples(&wav_bytes(16000, &[1, -2, 3])).unwrap(), (16000, vec![1, -2, 3]));
+        assert!(wav_samples(&data[..40]).is_err());
+    }
+
+    #[test]
+    fn test_tone_spec() {
+        assert_eq!(ToneSpec::parse("file://cc/1.wav").unwrap(), None);
+        let spec = ToneSpec::parse("dtmf:12#").unwrap().unwrap();
+        assert_eq!(spec, ToneSpec::dtmf("12#"));
+        assert_eq!(spec.duration(), Duration::from_millis(400));
+        assert_eq!(spec.synthesize(8000).len(), 3200);
+
+        let spec = ToneSpec::parse("tone:350+440;ms=200;level=-20").unwrap().unwrap();
+        assert_eq!(spec.kind, ToneKind::Freqs(vec![350.0, 440.0]));
+        assert_eq!((spec.duration(), spec.level_dbfs), (Duration::from_millis(200), -20.0));
+        let samples = spec.synthesize(8000);
+        assert_eq!(samples.len(), 1600);
+        // two sines at -20 dBFS peak below twice 3277
+        assert!(samples.iter().all(|x| x.abs() <= 6554));
+
+        assert_eq!(dtmf_freqs('d'), Some((941.0, 1633.0)));
+        assert_eq!(dtmf_digit(3, 2), '#');
+        assert!(ToneSpec::parse("dtmf:12x").is_err());
+        assert!(ToneSpec::parse("dtmf:").is_err());
+        assert!(ToneSpec::parse("tone:abc").is_err());
+        assert!(ToneSpec::parse("dtmf:1;loud=1").is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! shrink a packet failing to decode to the smallest one failing the same
+//! way, for the regression corpus.
+//!
+//! Failures are compared by [`error_class`], the error chain with values
+//! in brackets blanked, so `Play at least [12] bytes but [3]` and
+//! `Play at least [12] bytes but [5]` are one class. [`minimize`] bisects:
+//! it cuts the tail, then deletes ever smaller chunks, then zeroes bytes,
+//! each kept only if the class stays. After a cut Header.length is also
+//! tried fixed up, so a packet stays as consistent as its error allows.
+
+use anyhow::Error;
+
+use crate::vn_proto::HEADER_LENGTH;
+
+/// candidates tried at most, a packet is at most 64k so this is plenty
+pub const MAX_TRIES: usize = 100_000;
+
+/// error chain of e with bracketed values blanked, e.g.
+/// `invalid Play packet: Play at least [] bytes but []`
+pub fn error_class(e: &Error) -> String {
+    let text = e.chain().map(|x| x.to_string()).collect::<Vec<_>>().join(": ");
+    let mut class = String::with_capacity(text.len());
+    let mut depth = 0_usize;
+    for c in text.chars() {
+        match c {
+            '[' => {
+                if depth == 0 {
+                    class.push(c);
+                }
+                depth += 1;
+            },
+            ']' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    class.push(c);
+                }
+            },
+            _ if depth > 0 => {},
+            _ => class.push(c),
+        }
+    }
+    class
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MinimizeStats {
+    pub tries: usize,
+    pub original_len: usize,
+    pub minimized_len: usize,
+}
+
+/// smallest data found for which same_failure still holds, same_failure(data)
+/// must hold to begin with
+pub fn minimize<F>(data: &[u8], mut same_failure: F) -> (Vec<u8>, MinimizeStats)
+where
+    F: FnMut(&[u8]) -> bool,
+{
+    let mut stats = MinimizeStats { original_len: data.len(), ..Default::default() };
+    let mut best = data.to_vec();
+    // fix is of Header.length, only after cuts so zeroing always makes progress
+    let mut attempt = |candidate: Vec<u8>, fix: bool, best: &mut Vec<u8>, stats: &mut MinimizeStats| -> bool {
+        let fixed = if fix { fix_length(&candidate) } else { None };
+        for candidate in [fixed, Some(candidate)].into_iter().flatten() {
+            if stats.tries >= MAX_TRIES {
+                return false
+            }
+            stats.tries += 1;
+            if same_failure(&candidate) {
+                *best = candidate;
+                return true
+            }
+        }
+        false
+    };
+
+    loop {
+        let before = best.clone();
+
+        // shortest prefix, halving the cut
+        let mut cut = best.len() / 2;
+        while cut > 0 {
+            cut = cut.min(best.len());
+            if !attempt(best[..best.len() - cut].to_vec(), true, &mut best, &mut stats) {
+                cut /= 2;
+            }
+        }
+
+        // chunks anywhere, halving their size
+        let mut size = best.len() / 2;
+        while size > 0 {
+            let mut pos = 0;
+            while pos + size <= best.len() {
+                let mut candidate = best[..pos].to_vec();
+                candidate.extend_from_slice(&best[pos + size..]);
+                if !attempt(candidate, true, &mut best, &mut stats) {
+                    pos += size;
+                }
+            }
+            size /= 2;
+        }
+
+        // plainest bytes
+        for pos in 0..best.len() {
+            if best[pos] != 0 {
+                let mut candidate = best.clone();
+                candidate[pos] = 0;
+                attempt(candidate, false, &mut best, &mut stats);
+            }
+        }
+
+        if best == before || stats.tries >= MAX_TRIES {
+            break
+        }
+    }
+    stats.minimized_len = best.len();
+    (best, stats)
+}
+
+/// data with Header.length matching its size, None if already or too short
+fn fix_length(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < HEADER_LENGTH {
+        return None
+    }
+    let length = ((data.len() - 2) as u16).to_be_bytes();
+    if data[..2] == length {
+        return None
+    }
+    let mut fixed = data.to_vec();
+    fixed[..2].copy_from_slice(&length);
+    Some(fixed)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::vn_proto::{parse_message, CodecDesc, Header, MCodeType, Register};
+
+    use super::{error_class, minimize};
+
+    #[test]
+    fn test_minimize() {
+        let mut payload = Vec::new();
+        Register {
+            audio_codecs: vec![CodecDesc { index: 8, payload_type: 8, mapstr: "PCMA/8000".into() }],
+            ..Default::default()
+        }.write_to(&mut payload);
+        // MEDIAINFO tag longer than the payload
+        payload[5] = 0x7f;
+        let mut data = Vec::new();
+        Header { code: MCodeType::REGISTER.code(), fsm_id: 5000000, key: 0, sn: 0 }.write_to2(&mut data, &payload[..]);
+
+        let class = error_class(&parse_message(&data).map(|_x| ()).unwrap_err());
+        assert!(class.contains("too large tag.length, expect [] but []"), "{class}");
+        let same = |x: &[u8]| parse_message(x).map(|_x| ()).err().is_some_and(|e| error_class(&e) == class);
+        let (min, stats) = minimize(&data, same);
+        assert!(same(&min));
+        // header and the shortest Register with the tag length kept
+        assert_eq!(min.len(), 12 + 8, "{min:02x?}");
+        assert_eq!((stats.original_len, stats.minimized_len), (data.len(), min.len()));
+        // deterministic
+        assert_eq!(minimize(&data, same).0, min);
+    }
+}
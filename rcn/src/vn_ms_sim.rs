@@ -0,0 +1,1157 @@
+//! MS emulator, the other end of a CnSession.
+//!
+//! Answers CNISUP, sends REGISTER, reserves rtp ports on REQUESTCHANNEL (see vn_ports)
+//! and acks every request having an ACK code with result 0.
+//! [`SimHooks`] may override the result of channel, play and dtmf requests.
+//! PLAY of `tts:`/`asr:` files goes to speech backends, see vn_speech,
+//! `tone:`/`dtmf:` files are synthesized in-band, see vn_media.
+//! With MsSimConfig.pcap_dir the synthesized audio of each channel is written as
+//! RTP/RTCP into `<fsm_id>.pcap`, see vn_rtp and vn_pcap.
+//! With MsSimConfig.echo_delay rtp coming to a channel's audio port is sent back, see vn_echo.
+//! With MsSimConfig.dedup_window retransmitted requests are dropped, see vn_dedup.
+//! With MsSimConfig.digit_map DTMFRCV of CN are taken as keys pressed on the channel
+//! and reported back as one DTMFRCV once they complete the map, see vn_digit_map.
+//! There is no timer of our own, T of the map is checked as the next key comes.
+//! Once drained (see [`Drain`], `rcn ctl drain`) REQUESTCHANNEL is answered busy,
+//! channels open go on and run returns when the last is released.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::BufWriter,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, Context};
+use tracing::{debug, info, warn};
+
+use crate::{
+    utils::{datagram::Datagram, rate_limit::RateLimiter, recv_buf::RecvBuf, rng::{seed_from_time, SimRng}, rtt::heartbeat_time_payload},
+    vn_dedup::{DedupStats, DedupWindow},
+    vn_digit_map::{DigitMap, DigitMatch, DigitTimers},
+    vn_fields::{packet_fields, Fields},
+    vn_fsm_id::FsmIdSpace,
+    vn_marker::marker_id,
+    vn_acl::PeerAcl,
+    vn_capture::{CaptureDir, CaptureRecord, RecordTap},
+    vn_echo::RtpEcho,
+    vn_epoch::with_epoch,
+    vn_impair::{self, Impairer},
+    vn_key::KeyMap,
+    vn_pcap::PcapWriter,
+    vn_ports::{PortPool, PortPoolConfig, PortStats},
+    vn_proto::{Capability, CodecDesc, CodeName, Header, MCodeType, PacketRef, PlayAck, PlayRef, Register, RequestChannelAck, RequestChannelRef, ResFromTag, TagIter},
+    vn_media::ToneSpec,
+    vn_rtp::{resample, RtpSender, CLOCK_RATE, PT_PCMA, PT_PCMU, PTIME},
+    vn_speech::{AsrBackend, SpeechOp, TtsBackend},
+    vn_session::{bind_socket, ms_socket_path},
+};
+
+/// REQUESTCHANNEL_ACK result when no rtp ports are left
+pub const NO_PORTS_RESULT: u8 = 1;
+
+/// how often a running sim looks whether it is drained and idle
+const DRAIN_POLL: Duration = Duration::from_millis(200);
+
+/// drains of sims running in this process, for `rcn ctl drain`
+static RUNNING: Mutex<Vec<Drain>> = Mutex::new(Vec::new());
+
+/// busy result of REQUESTCHANNEL once a sim is drained, shared with
+/// whoever drains it
+#[derive(Debug, Clone, Default)]
+pub struct Drain(Arc<Mutex<Option<u8>>>);
+
+impl Drain {
+    pub fn start(&self, busy_result: u8) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = Some(busy_result);
+    }
+
+    pub fn busy_result(&self) -> Option<u8> {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// every sim running in this process drained, how many
+pub fn drain_running(busy_result: u8) -> usize {
+    let running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+    for drain in running.iter() {
+        drain.start(busy_result);
+    }
+    running.len()
+}
+
+/// drain of a sim listed while it runs
+struct Running(Drain);
+
+impl Running {
+    fn new(drain: &Drain) -> Self {
+        RUNNING.lock().unwrap_or_else(|e| e.into_inner()).push(drain.clone());
+        Self(drain.clone())
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap_or_else(|e| e.into_inner()).retain(|x| !Arc::ptr_eq(&x.0, &(self.0).0));
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MsSimConfig {
+    /// sent to CN after CNISUP_ACK
+    pub register: Register,
+    /// rtp ports of channels, each channel takes 4 ports (audio, video)
+    pub ports: PortPoolConfig,
+    /// how channels are grouped by Header.key
+    pub key_map: KeyMap,
+    /// answer HEARTBEAT with our unix time shifted by this many ms,
+    /// empty answer if None
+    pub heartbeat_clock_ms: Option<i64>,
+    /// our incarnation stamped in key when CN offers it, see vn_epoch,
+    /// None as MS builds without it
+    pub epoch: Option<u16>,
+    /// write rtp/rtcp of each channel into `<fsm_id>.pcap` here
+    pub pcap_dir: Option<PathBuf>,
+    /// echo rtp to the audio port of each channel after this delay
+    pub echo_delay: Option<Duration>,
+    /// drop requests seen again among the last this many of a CN
+    pub dedup_window: Option<usize>,
+    /// collect keys of DTMFRCV by this map
+    pub digit_map: Option<DigitMap>,
+    /// T of digit_map
+    pub digit_timeout: Duration,
+}
+
+impl Default for MsSimConfig {
+    fn default() -> Self {
+        Self {
+            register: Register {
+                audio_codecs: vec![
+                    CodecDesc { index: 0, payload_type: 0, mapstr: "PCMU/8000".into() },
+                    CodecDesc { index: 8, payload_type: 8, mapstr: "PCMA/8000".into() },
+                ],
+                ..Default::default()
+            },
+            ports: PortPoolConfig::default(),
+            key_map: KeyMap::default(),
+            heartbeat_clock_ms: None,
+            epoch: None,
+            pcap_dir: None,
+            echo_delay: None,
+            dedup_window: None,
+            digit_map: None,
+            digit_timeout: DigitTimers::default().inter,
+        }
+    }
+}
+
+/// request seen by the emulator, passed to hooks
+#[derive(Debug, Clone)]
+pub struct SimEvent {
+    pub fsm_id: u32,
+    /// label of Header.key by MsSimConfig.key_map
+    pub key_group: String,
+    /// requests of this code so far, this one included
+    pub count: u64,
+    /// decoded packet, see vn_fields
+    pub fields: Fields,
+}
+
+/// dynamic answers, Ok(None) keeps the default result
+pub trait SimHooks: Send {
+    fn on_request_channel(&mut self, _ev: &SimEvent) -> Result<Option<u8>> {
+        Ok(None)
+    }
+
+    fn on_play(&mut self, _ev: &SimEvent) -> Result<Option<u8>> {
+        Ok(None)
+    }
+
+    fn on_dtmf(&mut self, _ev: &SimEvent) -> Result<Option<u8>> {
+        Ok(None)
+    }
+}
+
+/// outcome of a speech PLAY
+enum SpeechAnswer {
+    NoBackend,
+    /// unparsable `tone:`/`dtmf:` file
+    Invalid,
+    /// samples at the rtp clock rate
+    Synthesized { play_duration: u32, samples: Vec<i16> },
+    /// text for RESFROMTAG
+    Recognized(String),
+}
+
+#[derive(Debug)]
+struct SimChannel {
+    audio_port: u16,
+    key: i16,
+    media: Option<ChannelMedia>,
+    echo: Option<RtpEcho>,
+    /// keys collected by digit_map
+    digits: String,
+    last_digit: Option<Instant>,
+}
+
+/// rtp engine of a channel, sending into a pcap only.
+/// The remote end isn't known to the sim, taken as loopback at the same ports
+struct ChannelMedia {
+    fsm_id: u32,
+    pcap: PcapWriter<BufWriter<File>>,
+    rtp: RtpSender,
+    impairer: Impairer,
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    /// send time of the next packet
+    clock: SystemTime,
+}
+
+impl std::fmt::Debug for ChannelMedia {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChannelMedia").field("local", &self.local).field("packets", &self.rtp.packets()).finish()
+    }
+}
+
+impl ChannelMedia {
+    fn create(dir: &Path, fsm_id: u32, codec: u8, ip: Ipv4Addr, audio_port: u16, impairer: Impairer) -> Result<Self> {
+        let payload_type = if codec == PT_PCMA { PT_PCMA } else { PT_PCMU };
+        Ok(Self {
+            fsm_id,
+            pcap: PcapWriter::create(&dir.join(format!("{fsm_id}.pcap")))?,
+            rtp: RtpSender::new(payload_type, fsm_id),
+            impairer,
+            local: SocketAddrV4::new(ip, audio_port),
+            remote: SocketAddrV4::new(Ipv4Addr::LOCALHOST, audio_port),
+            clock: SystemTime::now(),
+        })
+    }
+
+    /// a talkspurt paced at PTIME from now on, or after the previous one,
+    /// impaired as vn_impair says and followed by a sender report
+    fn play(&mut self, samples: &[i16]) -> Result<()> {
+        self.clock = self.clock.max(SystemTime::now());
+        let profile = vn_impair::profile_of(self.fsm_id);
+        let mut sent = Vec::new();
+        for packet in self.rtp.packetize(samples) {
+            let at = self.clock.duration_since(UNIX_EPOCH).unwrap_or_default();
+            if let Some(delay) = self.impairer.decide(&profile, at, packet.len()) {
+                sent.push((self.clock + delay, packet));
+            }
+            self.clock += PTIME;
+        }
+        // stable, in send order where delays tie
+        sent.sort_by_key(|x| x.0);
+        for (time, packet) in sent {
+            self.pcap.write_udp(time, self.local, self.remote, &packet)?;
+        }
+        self.sender_report()?;
+        self.pcap.flush()
+    }
+
+    fn sender_report(&mut self) -> Result<()> {
+        let rtcp = |x: SocketAddrV4| SocketAddrV4::new(*x.ip(), x.port() + 1);
+        let report = self.rtp.sender_report(self.clock);
+        self.pcap.write_udp(self.clock, rtcp(self.local), rtcp(self.remote), &report)
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.clock = self.clock.max(SystemTime::now());
+        self.sender_report()?;
+        self.pcap.flush()
+    }
+}
+
+pub struct MsSim<S> {
+    socket: S,
+    config: MsSimConfig,
+    /// where CNISUP came from
+    cn_path: Option<PathBuf>,
+    /// last sn sent per fsm_id
+    sns: HashMap<u32, u16>,
+    channels: HashMap<u32, SimChannel>,
+    ports: PortPool,
+    counts: HashMap<u16, u64>,
+    hooks: Option<Box<dyn SimHooks>>,
+    tts: Option<Box<dyn TtsBackend>>,
+    asr: Option<Box<dyn AsrBackend>>,
+    recv_buf: RecvBuf,
+    /// CNs datagrams are taken from, anyone if None
+    acl: Option<PeerAcl>,
+    /// CN offered epochs at CNISUP, or we announced
+    epoch_active: bool,
+    dedup: Option<DedupWindow>,
+    /// packets received and sent
+    tap: Option<RecordTap>,
+    /// forked per channel for its impairment
+    rng: SimRng,
+    drain: Drain,
+    started: Instant,
+    /// paces what we send, per fsm_id and in total
+    limiter: RateLimiter,
+}
+
+impl MsSim<tokio::net::UnixDatagram> {
+    /// bind $CINDIR/msvn
+    pub async fn bind(cindir: &Path, config: MsSimConfig) -> Result<Self> {
+        let socket = bind_socket(&ms_socket_path(cindir)).await?;
+        Ok(Self::with_socket(socket, config))
+    }
+}
+
+impl<S: Datagram> MsSim<S> {
+    pub fn with_socket(socket: S, config: MsSimConfig) -> Self {
+        Self {
+            socket,
+            ports: PortPool::new(config.ports.clone()),
+            dedup: config.dedup_window.map(DedupWindow::new),
+            config,
+            cn_path: None,
+            sns: HashMap::new(),
+            channels: HashMap::new(),
+            counts: HashMap::new(),
+            hooks: None,
+            tts: None,
+            asr: None,
+            recv_buf: RecvBuf::default(),
+            acl: None,
+            epoch_active: false,
+            tap: None,
+            started: Instant::now(),
+            rng: SimRng::new(seed_from_time()),
+            drain: Drain::default(),
+            limiter: RateLimiter::default(),
+        }
+    }
+
+    /// source of random port allocation
+    pub fn set_rng(&mut self, rng: &SimRng) {
+        self.ports.set_rng(rng.fork("ms_sim.ports"));
+        self.rng = rng.fork("ms_sim.impair");
+    }
+
+    pub fn set_hooks(&mut self, hooks: Option<Box<dyn SimHooks>>) {
+        self.hooks = hooks;
+    }
+
+    /// backend of PLAY with tts: files
+    pub fn set_tts(&mut self, tts: Option<Box<dyn TtsBackend>>) {
+        self.tts = tts;
+    }
+
+    /// backend of PLAY with asr: files
+    pub fn set_asr(&mut self, asr: Option<Box<dyn AsrBackend>>) {
+        self.asr = asr;
+    }
+
+    /// only CNs bound at allowed paths may talk to us, see vn_acl
+    pub fn set_peer_acl(&mut self, acl: Option<PeerAcl>) {
+        self.acl = acl;
+    }
+
+    /// answers wait for a token of both the global and the fsm_id bucket
+    pub fn set_rate_limiter(&mut self, limiter: RateLimiter) {
+        self.limiter = limiter;
+    }
+
+    /// gets every valid packet received and every one sent, e.g. to stream them live
+    pub fn set_tap(&mut self, tap: Option<RecordTap>) {
+        self.tap = tap;
+    }
+
+    pub fn peer_acl(&self) -> Option<&PeerAcl> {
+        self.acl.as_ref()
+    }
+
+    /// occupancy of rtp ports
+    pub fn port_stats(&self) -> &PortStats {
+        self.ports.stats()
+    }
+
+    pub fn num_channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// duplicates dropped, None without MsSimConfig.dedup_window
+    pub fn dedup_stats(&self) -> Option<&DedupStats> {
+        self.dedup.as_ref().map(|x| x.stats())
+    }
+
+    /// active channels per key group
+    pub fn channels_by_key(&self) -> BTreeMap<String, u64> {
+        self.config.key_map.count_by_label(self.channels.values().map(|x| x.key))
+    }
+
+    /// send REGISTER to a CN unasked, as a restarted MS does,
+    /// stamped with our epoch if we have one
+    pub async fn announce(&mut self, cn_path: PathBuf, cn_id: u32) -> Result<()> {
+        self.cn_path = Some(cn_path);
+        self.epoch_active = self.config.epoch.is_some();
+        let mut payload = Vec::new();
+        self.config.register.write_to(&mut payload);
+        self.send(MCodeType::REGISTER, FsmIdSpace::of_cn(cn_id).base, &payload).await
+    }
+
+    /// drains this sim when started, also done by `rcn ctl drain` while it runs
+    pub fn drain(&self) -> Drain {
+        self.drain.clone()
+    }
+
+    /// until drained and idle
+    pub async fn run(&mut self) -> Result<()> {
+        let _running = Running::new(&self.drain);
+        let mut poll = tokio::time::interval(DRAIN_POLL);
+        poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            let r = tokio::select! {
+                r = self.socket.recv_from(self.recv_buf.as_mut_slice()) => r,
+                _r = poll.tick() => {
+                    if self.drain.busy_result().is_some() && self.channels.is_empty() {
+                        info!("drained, no channels left");
+                        return Ok(())
+                    }
+                    continue
+                },
+            };
+            let (len, from) = r.with_context(||"recvfrom failed")?;
+            self.handle_datagram(len, from).await?;
+        }
+    }
+
+    /// recv one datagram and answer it
+    pub async fn handle_next(&mut self) -> Result<()> {
+        let (len, from) = self.socket.recv_from(self.recv_buf.as_mut_slice()).await.with_context(||"recvfrom failed")?;
+        self.handle_datagram(len, from).await
+    }
+
+    async fn handle_datagram(&mut self, len: usize, from: Option<PathBuf>) -> Result<()> {
+        if self.recv_buf.check_truncated(len) {
+            return Ok(())
+        }
+        if let Some(acl) = &mut self.acl {
+            // a rogue sender would otherwise become our cn_path
+            if !acl.check(from.as_deref()) {
+                return Ok(())
+            }
+        }
+        let data = self.recv_buf.as_slice()[..len].to_vec();
+
+        let packet = match PacketRef::parse_from(&data[..]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("drop invalid packet from [{from:?}], [{e:?}]");
+                return Ok(())
+            },
+        };
+        debug!(code = %CodeName(packet.code()), fsm_id = packet.fsm_id(), sn = packet.sn(), bytes = data.len(), "sim recv");
+        if let Some(tap) = &self.tap {
+            let socket = from.as_ref().map(|x| x.to_string_lossy().into_owned());
+            tap(&CaptureRecord::new(self.started.elapsed(), CaptureDir::CnToMs, socket, &data));
+        }
+
+        if let Some(dedup) = &mut self.dedup {
+            // sns of a CN starting over start over too
+            if packet.code() == MCodeType::CNISUP.code() {
+                dedup.reset(from.as_deref());
+            }
+            if !dedup.check(from.as_deref(), packet.code(), packet.fsm_id(), packet.sn()) {
+                return Ok(())
+            }
+        }
+
+        if let Some(from) = from {
+            self.cn_path = Some(from);
+        }
+
+        let Ok(code) = MCodeType::try_from(packet.code()) else {
+            warn!("ignore unknown code [0x{:04x}]", packet.code());
+            return Ok(())
+        };
+        let count = {
+            let count = self.counts.entry(code.code()).or_default();
+            *count += 1;
+            *count
+        };
+
+        let fsm_id = packet.fsm_id();
+        let key = packet.key();
+        let event = |packet: &PacketRef<'_>| -> Result<SimEvent> {
+            Ok(SimEvent { fsm_id, key_group: self.config.key_map.label(key), count, fields: packet_fields(packet)? })
+        };
+        if let Some(id) = marker_id(&packet) {
+            info!("marker [{id}] from cn, fsm_id [{fsm_id}]");
+        }
+        match code {
+            MCodeType::CNISUP => {
+                let offered = TagIter::new(packet.payload()).capability().is_some_and(|x| x.has(Capability::EPOCH));
+                self.epoch_active = offered && self.config.epoch.is_some();
+                self.send(MCodeType::CNISUP_ACK, fsm_id, &[]).await?;
+                let mut payload = Vec::new();
+                self.config.register.write_to(&mut payload);
+                self.send(MCodeType::REGISTER, fsm_id, &payload).await?;
+            },
+            MCodeType::REGISTER_ACK => {
+                info!("registered by cn [{:?}]", self.cn_path);
+            },
+            MCodeType::HEARTBEAT => {
+                match self.config.heartbeat_clock_ms {
+                    Some(shift) => {
+                        let now = SystemTime::now();
+                        let shift_abs = Duration::from_millis(shift.unsigned_abs());
+                        let time = if shift >= 0 { now + shift_abs } else { now - shift_abs };
+                        self.send(MCodeType::HEARTBEAT, fsm_id, &heartbeat_time_payload(time)).await?;
+                    },
+                    None => self.send(MCodeType::HEARTBEAT, fsm_id, &[]).await?,
+                }
+            },
+            MCodeType::REQUESTCHANNEL => {
+                let ev = event(&packet)?;
+                let result = match (self.drain.busy_result(), &mut self.hooks) {
+                    (Some(busy), _) => {
+                        info!("draining, channel [{fsm_id}] refused with [{busy}], [{}] channels left", self.channels.len());
+                        busy
+                    },
+                    (None, Some(hooks)) => hooks.on_request_channel(&ev)?.unwrap_or(0),
+                    (None, None) => 0,
+                };
+
+                let req = RequestChannelRef::parse_from(packet.payload())?;
+                let (media_type, codec) = (req.part1().media_type_code(), req.part2().codec_code());
+                let mut ack = RequestChannelAck { result, media_type, ..Default::default() };
+                if result == 0 {
+                    match self.ports.reserve(fsm_id) {
+                        Ok(audio_port) => {
+                            // same packets impaired whichever order channels come in
+                            let impairer = || Impairer::new(self.rng.fork(&fsm_id.to_string()));
+                            let media = self.config.pcap_dir.as_ref().and_then(|dir| {
+                                ChannelMedia::create(dir, fsm_id, codec, self.config.register.ip, audio_port, impairer())
+                                .map_err(|e| warn!("no pcap of channel [{fsm_id}], [{e:#}]"))
+                                .ok()
+                            });
+                            let echo = match self.config.echo_delay {
+                                Some(delay) => RtpEcho::bind_impaired(SocketAddr::from((Ipv4Addr::UNSPECIFIED, audio_port)), delay, fsm_id, impairer()).await
+                                    .map_err(|e| warn!("no echo of channel [{fsm_id}], [{e:#}]"))
+                                    .ok(),
+                                None => None,
+                            };
+                            self.channels.insert(fsm_id, SimChannel { audio_port, key, media, echo, digits: String::new(), last_digit: None });
+                            ack.audio_port = audio_port;
+                            ack.video_port = audio_port + 2;
+                        },
+                        Err(e) => {
+                            warn!("reject channel [{fsm_id}], [{e}]");
+                            ack.result = NO_PORTS_RESULT;
+                        },
+                    }
+                }
+                let mut payload = Vec::new();
+                ack.write_to(&mut payload);
+                self.send(MCodeType::REQUESTCHANNEL_ACK, fsm_id, &payload).await?;
+            },
+            MCodeType::PLAY => {
+                let ev = event(&packet)?;
+                let speech = self.speech(fsm_id, &packet)?;
+                let default = match (&speech, self.channels.contains_key(&fsm_id)) {
+                    (_, false) | (Some(SpeechAnswer::NoBackend | SpeechAnswer::Invalid), true) => 1,
+                    _ => 0,
+                };
+                let result = match &mut self.hooks {
+                    Some(hooks) => hooks.on_play(&ev)?.unwrap_or(default),
+                    None => default,
+                };
+                let play_duration = match &speech {
+                    Some(SpeechAnswer::Synthesized { play_duration, .. }) => *play_duration,
+                    _ => 0,
+                };
+                if let (0, Some(SpeechAnswer::Synthesized { samples, .. })) = (result, &speech) {
+                    if let Some(media) = self.channels.get_mut(&fsm_id).and_then(|x| x.media.as_mut()) {
+                        media.play(samples).with_context(||format!("write pcap of channel [{fsm_id}] failed"))?;
+                    }
+                }
+                let mut payload = Vec::new();
+                PlayAck { result, play_duration }.write_to(&mut payload);
+                self.send(MCodeType::PLAY_ACK, fsm_id, &payload).await?;
+
+                if let (0, Some(SpeechAnswer::Recognized(value))) = (result, speech) {
+                    let mut payload = Vec::new();
+                    ResFromTag { value }.write_to(&mut payload);
+                    self.send(MCodeType::RESFROMTAG, fsm_id, &payload).await?;
+                }
+            },
+            MCodeType::DTMFRCV => {
+                let ev = event(&packet)?;
+                let result = match &mut self.hooks {
+                    Some(hooks) => hooks.on_dtmf(&ev)?.unwrap_or(0),
+                    None => 0,
+                };
+                self.send(MCodeType::DTMFRCV_ACK, fsm_id, &[result]).await?;
+                if result == 0 {
+                    for digits in self.collect_digits(fsm_id, packet.payload()) {
+                        self.send(MCodeType::DTMFRCV, fsm_id, digits.as_bytes()).await?;
+                    }
+                }
+            },
+            MCodeType::RELEASECHANNEL => {
+                self.sns.remove(&fsm_id);
+                self.ports.release(fsm_id);
+                if let Some(channel) = self.channels.remove(&fsm_id) {
+                    if let Some(echo) = &channel.echo {
+                        debug!(
+                            "echo of channel [{fsm_id}], received [{}] echoed [{}] dropped [{}] impaired [{}]",
+                            echo.received(), echo.echoed(), echo.dropped(), echo.impaired(),
+                        );
+                    }
+                    if let Some(media) = channel.media {
+                        let packets = media.rtp.packets();
+                        let stats = media.impairer.stats();
+                        if stats.lost + stats.shaped + stats.reordered > 0 {
+                            info!("impairment of channel [{fsm_id}] {stats:?}");
+                        }
+                        match media.finish() {
+                            Ok(()) => debug!("pcap of channel [{fsm_id}], rtp packets [{packets}]"),
+                            Err(e) => warn!("finish pcap of channel [{fsm_id}] failed, [{e:#}]"),
+                        }
+                    }
+                    debug!(
+                        "released channel [{fsm_id}], audio port [{}], key group [{}]",
+                        channel.audio_port, self.config.key_map.label(channel.key),
+                    );
+                    if self.drain.busy_result().is_some() {
+                        info!("draining, [{}] channels left", self.channels.len());
+                    }
+                }
+            },
+            _ => {
+                if let Some(ack) = code.ack() {
+                    self.send(ack, fsm_id, &[0]).await?;
+                } else {
+                    debug!("no answer for {code:?}");
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// keys of a DTMFRCV added to those of the channel, collections
+    /// completed by them to be reported
+    fn collect_digits(&mut self, fsm_id: u32, keys: &[u8]) -> Vec<String> {
+        let (Some(map), Some(channel)) = (&self.config.digit_map, self.channels.get_mut(&fsm_id)) else { return Vec::new() };
+        let mut completed = Vec::new();
+        let now = Instant::now();
+        if channel.last_digit.is_some_and(|x| now - x >= self.config.digit_timeout) && !channel.digits.is_empty() {
+            let digits = std::mem::take(&mut channel.digits);
+            match map.check(&digits, true) {
+                DigitMatch::Complete => completed.push(digits),
+                _ => debug!("drop digits [{digits}] of channel [{fsm_id}], timed out"),
+            }
+        }
+        for key in keys.iter().map(|x| *x as char) {
+            channel.digits.push(key);
+            match map.check(&channel.digits, false) {
+                DigitMatch::Complete => completed.push(std::mem::take(&mut channel.digits)),
+                DigitMatch::NoMatch => debug!("drop digits [{}] of channel [{fsm_id}], no match", std::mem::take(&mut channel.digits)),
+                DigitMatch::Partial | DigitMatch::Ambiguous => {},
+            }
+        }
+        channel.last_digit = Some(now);
+        completed
+    }
+
+    /// run speech backend if first file of PLAY is tts: or asr:,
+    /// synthesize it if tone: or dtmf:
+    fn speech(&mut self, fsm_id: u32, packet: &PacketRef<'_>) -> Result<Option<SpeechAnswer>> {
+        let play = PlayRef::parse_from(packet.payload())?;
+        let Some(Ok(file)) = play.files().next() else { return Ok(None) };
+        let Some(name) = file.filename().decode() else { return Ok(None) };
+        match ToneSpec::parse(&name) {
+            Ok(Some(spec)) => {
+                debug!("in-band {:?} of fsm_id [{fsm_id}], [{:?}]", spec.kind, spec.duration());
+                return Ok(Some(SpeechAnswer::Synthesized {
+                    play_duration: spec.duration().as_millis() as u32,
+                    samples: spec.synthesize(CLOCK_RATE),
+                }))
+            },
+            Ok(None) => {},
+            Err(e) => {
+                warn!("reject play of fsm_id [{fsm_id}], [{e:#}]");
+                return Ok(Some(SpeechAnswer::Invalid))
+            },
+        }
+        let Some(op) = SpeechOp::parse(&name) else { return Ok(None) };
+
+        let answer = match (op, &mut self.tts, &mut self.asr) {
+            (SpeechOp::Tts(text), Some(tts), _) => {
+                let synthesized = tts.synthesize(fsm_id, text)?;
+                debug!("tts [{text}] of fsm_id [{fsm_id}], [{:?}]", synthesized.duration());
+                SpeechAnswer::Synthesized {
+                    play_duration: synthesized.duration().as_millis() as u32,
+                    samples: resample(&synthesized.samples, synthesized.sample_rate),
+                }
+            },
+            (SpeechOp::Asr(grammar), _, Some(asr)) => {
+                let text = asr.recognize(fsm_id, grammar)?;
+                debug!("asr [{grammar}] of fsm_id [{fsm_id}], [{text}]");
+                SpeechAnswer::Recognized(text)
+            },
+            (op, _, _) => {
+                warn!("no speech backend for {op:?}");
+                SpeechAnswer::NoBackend
+            },
+        };
+        Ok(Some(answer))
+    }
+
+    async fn send(&mut self, code: MCodeType, fsm_id: u32, payload: &[u8]) -> Result<()> {
+        let Some(cn_path) = &self.cn_path else {
+            warn!("drop {code:?}, unknown cn path");
+            return Ok(())
+        };
+        if self.limiter.is_enabled() {
+            self.limiter.acquire(fsm_id).await;
+        }
+
+        let sn = self.sns.entry(fsm_id).or_default();
+        *sn = sn.wrapping_add(1);
+        let key = match (self.epoch_active, self.config.epoch) {
+            (true, Some(epoch)) => with_epoch(0, epoch),
+            _ => 0,
+        };
+        let header = Header {
+            code: code.code(),
+            fsm_id,
+            sn: *sn,
+            key,
+        };
+        let mut data = Vec::new();
+        header.write_to2(&mut data, payload);
+        self.socket.send_to(&data[..], cn_path).await.with_context(||"sendto failed")?;
+        debug!(code = %CodeName(header.code), fsm_id = header.fsm_id, sn = header.sn, bytes = data.len(), "sim sent");
+        if let Some(tap) = &self.tap {
+            tap(&CaptureRecord::new(self.started.elapsed(), CaptureDir::MsToCn, None, &data));
+        }
+        Ok(())
+    }
+}
+
+/// rhai script defining any of `on_request_channel(ev)`, `on_play(ev)`, `on_dtmf(ev)`,
+/// `ev` is a map of fsm_id, key_group, count and decoded fields, an integer return
+/// is the result code, `()` keeps the default
+///
+/// ```text
+/// // reject every 10th channel
+/// fn on_request_channel(ev) {
+///     if ev.count % 10 == 0 { 1 }
+/// }
+/// ```
+#[cfg(feature = "script")]
+pub struct ScriptHooks {
+    engine: rhai::Engine,
+    ast: rhai::AST,
+    scope: rhai::Scope<'static>,
+}
+
+#[cfg(feature = "script")]
+impl ScriptHooks {
+    pub fn from_script(script: &str) -> Result<Self> {
+        let engine = rhai::Engine::new();
+        let ast = engine.compile(script).map_err(|e| anyhow::anyhow!("compile hooks failed [{e}]"))?;
+        let mut scope = rhai::Scope::new();
+        engine.run_ast_with_scope(&mut scope, &ast).map_err(|e| anyhow::anyhow!("run hooks failed [{e}]"))?;
+        Ok(Self { engine, ast, scope })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let script = std::fs::read_to_string(path).with_context(||format!("read hooks failed [{path:?}]"))?;
+        Self::from_script(&script).with_context(||format!("load hooks failed [{path:?}]"))
+    }
+
+    fn call(&mut self, name: &str, ev: &SimEvent) -> Result<Option<u8>> {
+        if !self.ast.iter_functions().any(|x| x.name == name && x.params.len() == 1) {
+            return Ok(None)
+        }
+
+        let mut map = rhai::Map::new();
+        map.insert("fsm_id".into(), (ev.fsm_id as rhai::INT).into());
+        map.insert("count".into(), (ev.count as rhai::INT).into());
+        map.insert("key_group".into(), ev.key_group.clone().into());
+        for (key, value) in ev.fields.iter() {
+            let value: rhai::Dynamic = match value {
+                crate::vn_fields::FieldValue::Int(v) => (*v as rhai::INT).into(),
+                crate::vn_fields::FieldValue::Str(v) => v.clone().into(),
+            };
+            map.insert((*key).into(), value);
+        }
+
+        let r: rhai::Dynamic = self.engine.call_fn(&mut self.scope, &self.ast, name, (map,))
+        .map_err(|e| anyhow::anyhow!("hook [{name}] failed [{e}]"))?;
+        if r.is_unit() {
+            return Ok(None)
+        }
+        let result = r.as_int().map_err(|t| anyhow::anyhow!("hook [{name}] returned [{t}], expect integer"))?;
+        let result = u8::try_from(result).with_context(||format!("hook [{name}] result out of range [{result}]"))?;
+        Ok(Some(result))
+    }
+}
+
+#[cfg(feature = "script")]
+impl SimHooks for ScriptHooks {
+    fn on_request_channel(&mut self, ev: &SimEvent) -> Result<Option<u8>> {
+        self.call("on_request_channel", ev)
+    }
+
+    fn on_play(&mut self, ev: &SimEvent) -> Result<Option<u8>> {
+        self.call("on_play", ev)
+    }
+
+    fn on_dtmf(&mut self, ev: &SimEvent) -> Result<Option<u8>> {
+        self.call("on_dtmf", ev)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use tokio::net::UnixDatagram;
+
+    use crate::{
+        vn_acl::{AclMode, PeerAcl},
+        vn_epoch::{epoch_of, with_epoch, EpochMode},
+        vn_audio::dtmf_digits,
+        vn_pcap::read_udp,
+        vn_ports::PortPoolConfig,
+        vn_proto::{Filename, Header, MCodeType, Play, PlayAckRef, RequestChannel, RequestChannelAckRef, ResFromTagRef},
+        vn_rtp::{depacketize, CLOCK_RATE},
+        vn_session::{cn_socket_path, ms_socket_path, CnSession},
+        vn_speech::{StubAsr, StubTts},
+    };
+
+    use super::{MsSim, MsSimConfig, SimEvent, SimHooks, NO_PORTS_RESULT};
+
+    struct RejectEvery(u64);
+
+    impl SimHooks for RejectEvery {
+        fn on_request_channel(&mut self, ev: &SimEvent) -> anyhow::Result<Option<u8>> {
+            Ok(ev.count.is_multiple_of(self.0).then_some(1))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sim_answers_session() {
+        let dir = std::env::temp_dir().join(format!("rcn_ms_sim_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut sim = MsSim::bind(&dir, MsSimConfig::default()).await.unwrap();
+        sim.set_hooks(Some(Box::new(RejectEvery(2))));
+        let task = tokio::spawn(async move { sim.run().await });
+
+        let mut session = CnSession::bind(&dir, 5).await.unwrap();
+        session.handshake().await.unwrap();
+        session.accept_register().await.unwrap();
+
+        let req = RequestChannel { media_type: 1, webrtc: vec!["".into()], ..Default::default() };
+        let mut results = Vec::new();
+        for n in 1..=2 {
+            session.request_channel(session.base_fsm_id() + n, &req).await.unwrap();
+            let packet = session.expect_packet(MCodeType::REQUESTCHANNEL_ACK).await.unwrap();
+            let ack = RequestChannelAckRef::parse_from(packet.payload()).unwrap();
+            results.push((ack.part1().result(), ack.part1().audio_port()));
+        }
+        assert_eq!(results, vec![(0, 20000), (1, 0)]);
+        // CNISUP_ACK, REGISTER and two ACKs, numbered per fsm_id
+        assert_eq!(session.loss().received, 4);
+        assert!(!session.loss().has_loss(), "{}", session.loss());
+
+        task.abort();
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_sim_drain() {
+        let dir = std::env::temp_dir().join(format!("rcn_ms_sim_drain_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut sim = MsSim::bind(&dir, MsSimConfig::default()).await.unwrap();
+        let drain = sim.drain();
+        let task = tokio::spawn(async move { sim.run().await });
+
+        let mut session = CnSession::bind(&dir, 5).await.unwrap();
+        session.handshake().await.unwrap();
+        session.accept_register().await.unwrap();
+
+        let req = RequestChannel { media_type: 1, webrtc: vec!["".into()], ..Default::default() };
+        let mut results = Vec::new();
+        for n in 1..=2 {
+            if n == 2 {
+                drain.start(7);
+            }
+            session.request_channel(session.base_fsm_id() + n, &req).await.unwrap();
+            let packet = session.expect_packet(MCodeType::REQUESTCHANNEL_ACK).await.unwrap();
+            results.push(RequestChannelAckRef::parse_from(packet.payload()).unwrap().part1().result());
+        }
+        assert_eq!(results, [0, 7]);
+
+        // the open channel goes on, the sim exits once it is released
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!task.is_finished());
+        session.send_request(MCodeType::RELEASECHANNEL, session.base_fsm_id() + 1, &[]).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), task).await.unwrap().unwrap().unwrap();
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_sim_ports_released() {
+        let dir = std::env::temp_dir().join(format!("rcn_ms_sim_ports_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let config = MsSimConfig { ports: PortPoolConfig { ranges: "30000-30003".parse().unwrap(), ..Default::default() }, ..Default::default() };
+        let mut sim = MsSim::bind(&dir, config).await.unwrap();
+        let task = tokio::spawn(async move { sim.run().await });
+
+        let mut session = CnSession::bind(&dir, 5).await.unwrap();
+        session.handshake().await.unwrap();
+        session.accept_register().await.unwrap();
+
+        let req = RequestChannel { media_type: 1, webrtc: vec!["".into()], ..Default::default() };
+        let mut results = Vec::new();
+        for n in 1..=3 {
+            let fsm_id = session.base_fsm_id() + n;
+            session.request_channel(fsm_id, &req).await.unwrap();
+            let packet = session.expect_packet(MCodeType::REQUESTCHANNEL_ACK).await.unwrap();
+            let ack = RequestChannelAckRef::parse_from(packet.payload()).unwrap();
+            results.push((ack.part1().result(), ack.part1().audio_port()));
+            if n == 2 {
+                session.send_request(MCodeType::RELEASECHANNEL, session.base_fsm_id() + 1, &[]).await.unwrap();
+            }
+        }
+        assert_eq!(results, vec![(0, 30000), (NO_PORTS_RESULT, 0), (0, 30000)]);
+
+        task.abort();
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_sim_echo() {
+        let dir = std::env::temp_dir().join(format!("rcn_ms_sim_echo_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let ports = PortPoolConfig { ranges: "41000-41399".parse().unwrap(), check_bind: true, ..Default::default() };
+        let config = MsSimConfig { ports, echo_delay: Some(Duration::from_millis(20)), ..Default::default() };
+        let mut sim = MsSim::bind(&dir, config).await.unwrap();
+        let task = tokio::spawn(async move { sim.run().await });
+
+        let mut session = CnSession::bind(&dir, 5).await.unwrap();
+        session.handshake().await.unwrap();
+        session.accept_register().await.unwrap();
+        let req = RequestChannel { media_type: 1, webrtc: vec!["".into()], ..Default::default() };
+        session.request_channel(session.base_fsm_id() + 1, &req).await.unwrap();
+        let packet = session.expect_packet(MCodeType::REQUESTCHANNEL_ACK).await.unwrap();
+        let audio_port = RequestChannelAckRef::parse_from(packet.payload()).unwrap().part1().audio_port();
+
+        let peer = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        peer.send_to(&[0x80, 0, 0, 1], (Ipv4Addr::LOCALHOST, audio_port)).await.unwrap();
+        let mut buf = [0_u8; 64];
+        let (len, from) = tokio::time::timeout(Duration::from_secs(2), peer.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!((&buf[..len], from.port()), (&[0x80, 0, 0, 1][..], audio_port));
+
+        task.abort();
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_sim_dedup() {
+        let dir = std::env::temp_dir().join(format!("rcn_ms_sim_dedup_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let config = MsSimConfig { dedup_window: Some(8), ..Default::default() };
+        let mut sim = MsSim::bind(&dir, config).await.unwrap();
+        let mut session = CnSession::bind(&dir, 5).await.unwrap();
+
+        let mut payload = Vec::new();
+        RequestChannel { media_type: 1, webrtc: vec!["".into()], ..Default::default() }.write_to(&mut payload);
+        let mut data = Vec::new();
+        Header { code: MCodeType::REQUESTCHANNEL.code(), fsm_id: session.base_fsm_id() + 1, sn: 1, key: 0 }.write_to2(&mut data, &payload[..]);
+        // retransmitted with the same sn
+        for _ in 0..2 {
+            session.send_verbatim(&data).await.unwrap();
+            sim.handle_next().await.unwrap();
+        }
+        assert_eq!(sim.num_channels(), 1);
+        assert_eq!(sim.port_stats().in_use, 1);
+        let stats = sim.dedup_stats().unwrap();
+        assert_eq!((stats.checked, stats.duplicates), (2, 1));
+
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_sim_peer_acl() {
+        let dir = std::env::temp_dir().join(format!("rcn_ms_sim_acl_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut sim = MsSim::bind(&dir, MsSimConfig::default()).await.unwrap();
+        sim.set_peer_acl(Some(PeerAcl::new(AclMode::Enforce, vec![cn_socket_path(&dir, 5).unwrap()])));
+
+        let mut session = CnSession::bind(&dir, 5).await.unwrap();
+        let rogue = UnixDatagram::unbound().unwrap();
+        let mut cnisup = Vec::new();
+        Header { code: MCodeType::CNISUP.code(), ..Default::default() }.write_to(&mut cnisup);
+        rogue.send_to(&cnisup, ms_socket_path(&dir)).await.unwrap();
+        sim.handle_next().await.unwrap();
+        assert_eq!(sim.peer_acl().unwrap().stats().rejected, 1);
+
+        let task = tokio::spawn(async move { sim.run().await });
+        session.handshake().await.unwrap();
+        session.accept_register().await.unwrap();
+
+        task.abort();
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_sim_heartbeat_clock() {
+        let dir = std::env::temp_dir().join(format!("rcn_ms_sim_clock_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut sim = MsSim::bind(&dir, MsSimConfig { heartbeat_clock_ms: Some(-500), ..Default::default() }).await.unwrap();
+        let task = tokio::spawn(async move { sim.run().await });
+
+        let mut session = CnSession::bind(&dir, 5).await.unwrap();
+        session.handshake().await.unwrap();
+        session.accept_register().await.unwrap();
+        for _ in 0..3 {
+            session.send_heartbeat().await.unwrap();
+            session.expect_packet(MCodeType::HEARTBEAT).await.unwrap();
+        }
+
+        let rtt = session.rtt();
+        assert_eq!(rtt.count, 3);
+        assert!(rtt.min <= rtt.ewma && rtt.ewma <= rtt.max);
+        let offset = rtt.clock_offset_us.unwrap();
+        assert!((-550_000..-450_000).contains(&offset), "{offset}");
+
+        task.abort();
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_sim_epoch() {
+        let dir = std::env::temp_dir().join(format!("rcn_ms_sim_epoch_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut sim = MsSim::bind(&dir, MsSimConfig { epoch: Some(2), ..Default::default() }).await.unwrap();
+        let task = tokio::spawn(async move { sim.run().await });
+        let mut session = CnSession::bind(&dir, 5).await.unwrap();
+        session.set_epoch_mode(EpochMode::Discard);
+        session.handshake().await.unwrap();
+        session.accept_register().await.unwrap();
+        assert_eq!(session.epoch().current(), Some(2));
+        task.abort();
+
+        // restarted with the next epoch
+        let mut sim = MsSim::bind(&dir, MsSimConfig { epoch: Some(3), ..Default::default() }).await.unwrap();
+        sim.announce(cn_socket_path(&dir, 5).unwrap(), 5).await.unwrap();
+        let payload = session.expect_packet(MCodeType::REGISTER).await.unwrap().payload().to_vec();
+        session.reregister(&payload).await.unwrap();
+        assert_eq!(session.epoch().current(), Some(3));
+        let task = tokio::spawn(async move { sim.run().await });
+
+        // late answer of the old incarnation
+        let old = Header { code: MCodeType::HEARTBEAT.code(), fsm_id: session.base_fsm_id(), sn: 9, key: with_epoch(0, 2) };
+        let mut data = Vec::new();
+        old.write_to2(&mut data, &[][..]);
+        let rogue = UnixDatagram::unbound().unwrap();
+        rogue.send_to(&data, cn_socket_path(&dir, 5).unwrap()).await.unwrap();
+
+        session.send_heartbeat().await.unwrap();
+        let packet = session.expect_packet(MCodeType::HEARTBEAT).await.unwrap();
+        assert_eq!(epoch_of(packet.key()), 3);
+        let s = session.epoch().stats();
+        assert_eq!((s.stale, s.changes), (1, 1));
+
+        task.abort();
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_sim_speech() {
+        let dir = std::env::temp_dir().join(format!("rcn_ms_sim_speech_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let config = MsSimConfig { pcap_dir: Some(dir.clone()), ..Default::default() };
+        let mut sim = MsSim::bind(&dir, config).await.unwrap();
+        sim.set_tts(Some(Box::new(StubTts::default())));
+        sim.set_asr(Some(Box::new(StubAsr::new(vec!["yes".into()]))));
+        let task = tokio::spawn(async move { sim.run().await });
+
+        let mut session = CnSession::bind(&dir, 5).await.unwrap();
+        session.handshake().await.unwrap();
+        session.accept_register().await.unwrap();
+
+        let fsm_id = session.base_fsm_id() + 1;
+        let req = RequestChannel { media_type: 1, codec: 8, webrtc: vec!["".into()], ..Default::default() };
+        session.request_channel(fsm_id, &req).await.unwrap();
+        let packet = session.expect_packet(MCodeType::REQUESTCHANNEL_ACK).await.unwrap();
+        let audio_port = RequestChannelAckRef::parse_from(packet.payload()).unwrap().part1().audio_port();
+
+        let play = |filename: &str| Play { play_times: 1, files: vec![Filename { format: 0, filename: filename.into() }], ..Default::default() };
+        session.play(fsm_id, &play("tts:hello")).await.unwrap();
+        let packet = session.expect_packet(MCodeType::PLAY_ACK).await.unwrap();
+        let ack = PlayAckRef::parse_from(packet.payload()).unwrap();
+        assert_eq!((ack.part1().result(), ack.part1().play_duration()), (0, 300));
+
+        session.play(fsm_id, &play("dtmf:12;gap=100")).await.unwrap();
+        let packet = session.expect_packet(MCodeType::PLAY_ACK).await.unwrap();
+        let ack = PlayAckRef::parse_from(packet.payload()).unwrap();
+        assert_eq!((ack.part1().result(), ack.part1().play_duration()), (0, 300));
+        session.play(fsm_id, &play("dtmf:1x")).await.unwrap();
+        let packet = session.expect_packet(MCodeType::PLAY_ACK).await.unwrap();
+        assert_eq!(PlayAckRef::parse_from(packet.payload()).unwrap().part1().result(), 1);
+
+        session.play(fsm_id, &play("asr:yesno")).await.unwrap();
+        let packet = session.expect_packet(MCodeType::PLAY_ACK).await.unwrap();
+        assert_eq!(PlayAckRef::parse_from(packet.payload()).unwrap().part1().result(), 0);
+        let packet = session.expect_packet(MCodeType::RESFROMTAG).await.unwrap();
+        assert_eq!(ResFromTagRef::parse_from(packet.payload()).unwrap().value(), b"yes");
+
+        session.send_request(MCodeType::RELEASECHANNEL, fsm_id, &[]).await.unwrap();
+        session.send_request(MCodeType::HEARTBEAT, session.base_fsm_id(), &[]).await.unwrap();
+        session.expect_packet(MCodeType::HEARTBEAT).await.unwrap();
+
+        // 300ms of PCMA tts and 300ms of dtmf, a sender report after each play and at release
+        let records = read_udp(&std::fs::read(dir.join(format!("{fsm_id}.pcap"))).unwrap()).unwrap();
+        assert_eq!(records.len(), 15 + 1 + 15 + 1 + 1);
+        assert_eq!(records[0].src.port(), audio_port);
+        assert_eq!((records[0].payload.len(), records[0].payload[1]), (172, 0x80 | 8));
+        assert_eq!(records[14].time.duration_since(records[0].time).unwrap(), Duration::from_millis(280));
+        assert_eq!((records[15].src.port(), records[15].payload[1]), (audio_port + 1, 200));
+        let dtmf = depacketize(records[16..31].iter().map(|x| &x.payload[..]));
+        assert_eq!(dtmf_digits(&dtmf, CLOCK_RATE), "12");
+
+        task.abort();
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "script")]
+    #[test]
+    fn test_script_hooks() {
+        use super::ScriptHooks;
+
+        let mut hooks = ScriptHooks::from_script(r#"
+            fn on_request_channel(ev) {
+                if ev.count % 10 == 0 { 1 }
+            }
+            fn on_play(ev) { ev.fsm_id % 256 }
+        "#).unwrap();
+
+        let mut ev = SimEvent { fsm_id: 5000003, key_group: "0".into(), count: 9, fields: Default::default() };
+        assert_eq!(hooks.on_request_channel(&ev).unwrap(), None);
+        ev.count = 10;
+        assert_eq!(hooks.on_request_channel(&ev).unwrap(), Some(1));
+        assert_eq!(hooks.on_play(&ev).unwrap(), Some((5000003 % 256) as u8));
+        assert_eq!(hooks.on_dtmf(&ev).unwrap(), None);
+    }
+}
@@ -0,0 +1,767 @@
+//! protobuf messages of VN packets, schema in `proto/vn_message.proto`.
+//!
+//! Owned payloads of vn_proto convert to messages with `From` and back
+//! with `TryFrom`, which fails on numbers too wide for the wire. A whole
+//! packet goes to a [`VnMessage`] with `TryFrom<&PacketRef>` and back with
+//! [`VnMessage::to_packet`]. Payloads of codes without a message here are
+//! kept as raw bytes so nothing is lost on the way.
+
+use std::{borrow::Cow, fmt, net::Ipv4Addr};
+
+use anyhow::{Result, Context};
+
+use crate::vn_proto::{
+    self, CancelRef, CloseRtpConnect, CloseRtpConnectAck, MCodeType, OpenRtpConnectAck,
+    OpenRtpConnectRef, PacketRef, PlayAckRef, PlayRef, RegisterRef, RequestChannelAckRef,
+    RequestChannelRef, ResFromTagRef, StrRef,
+};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Header {
+    #[prost(uint32, tag = "1")]
+    pub code: u32,
+    #[prost(uint32, tag = "2")]
+    pub fsm_id: u32,
+    #[prost(sint32, tag = "3")]
+    pub key: i32,
+    #[prost(uint32, tag = "4")]
+    pub sn: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CodecDesc {
+    #[prost(uint32, tag = "1")]
+    pub index: u32,
+    #[prost(uint32, tag = "2")]
+    pub payload_type: u32,
+    #[prost(string, tag = "3")]
+    pub mapstr: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Capability {
+    #[prost(uint32, tag = "1")]
+    pub flags: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PortRange {
+    #[prost(uint32, tag = "1")]
+    pub min: u32,
+    #[prost(uint32, tag = "2")]
+    pub max: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Register {
+    /// ipv4 in network order as a number
+    #[prost(fixed32, tag = "1")]
+    pub ip: u32,
+    #[prost(bool, tag = "2")]
+    pub support_t38: bool,
+    #[prost(message, repeated, tag = "3")]
+    pub audio_codecs: Vec<CodecDesc>,
+    #[prost(message, repeated, tag = "4")]
+    pub video_codecs: Vec<CodecDesc>,
+    #[prost(message, repeated, tag = "5")]
+    pub fax_codecs: Vec<CodecDesc>,
+    #[prost(message, optional, tag = "6")]
+    pub capability: Option<Capability>,
+    #[prost(string, optional, tag = "7")]
+    pub version: Option<String>,
+    #[prost(uint32, optional, tag = "8")]
+    pub capacity: Option<u32>,
+    #[prost(message, optional, tag = "9")]
+    pub port_range: Option<PortRange>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RequestChannel {
+    #[prost(uint32, tag = "1")]
+    pub ice_type: u32,
+    #[prost(uint32, tag = "2")]
+    pub life_seconds: u32,
+    #[prost(uint32, tag = "3")]
+    pub media_type: u32,
+    #[prost(string, tag = "4")]
+    pub as_call_id: String,
+    #[prost(string, optional, tag = "5")]
+    pub agora_info: Option<String>,
+    #[prost(bool, tag = "6")]
+    pub is_nbup: bool,
+    #[prost(uint32, tag = "7")]
+    pub ptime: u32,
+    #[prost(bool, tag = "8")]
+    pub is_caller: bool,
+    #[prost(uint32, tag = "9")]
+    pub codec: u32,
+    #[prost(uint32, tag = "10")]
+    pub amr_mode: u32,
+    #[prost(string, repeated, tag = "11")]
+    pub webrtc: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RequestChannelAck {
+    #[prost(uint32, tag = "1")]
+    pub result: u32,
+    #[prost(uint32, tag = "2")]
+    pub audio_port: u32,
+    #[prost(uint32, tag = "3")]
+    pub video_port: u32,
+    #[prost(uint32, tag = "4")]
+    pub fax_port: u32,
+    #[prost(uint32, tag = "5")]
+    pub media_type: u32,
+    #[prost(string, repeated, tag = "6")]
+    pub webrtc: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RtpInfo {
+    #[prost(fixed32, tag = "1")]
+    pub ip: u32,
+    #[prost(uint32, tag = "2")]
+    pub port: u32,
+    #[prost(uint32, tag = "3")]
+    pub media_type: u32,
+    #[prost(uint32, tag = "4")]
+    pub internal_pltyp: u32,
+    #[prost(uint32, tag = "5")]
+    pub nego_pltyp: u32,
+    #[prost(string, tag = "6")]
+    pub attribute: String,
+    #[prost(uint32, tag = "7")]
+    pub tele_event: u32,
+    #[prost(uint32, tag = "8")]
+    pub direction: u32,
+    #[prost(string, repeated, tag = "9")]
+    pub webrtc: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OpenRtpConnect {
+    #[prost(message, repeated, tag = "1")]
+    pub rtpinfos: Vec<RtpInfo>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Filename {
+    #[prost(uint32, tag = "1")]
+    pub format: u32,
+    #[prost(string, tag = "2")]
+    pub filename: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Play {
+    #[prost(uint32, tag = "1")]
+    pub interval: u32,
+    #[prost(uint32, tag = "2")]
+    pub play_times: u32,
+    #[prost(uint32, tag = "3")]
+    pub max_duration: u32,
+    #[prost(uint32, tag = "4")]
+    pub key_mask: u32,
+    #[prost(bool, tag = "5")]
+    pub record: bool,
+    #[prost(bool, tag = "6")]
+    pub speech_barge: bool,
+    #[prost(bool, tag = "7")]
+    pub erase_dtmf: bool,
+    #[prost(message, repeated, tag = "8")]
+    pub files: Vec<Filename>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PlayAck {
+    #[prost(uint32, tag = "1")]
+    pub result: u32,
+    #[prost(uint32, tag = "2")]
+    pub play_duration: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ResFromTag {
+    #[prost(string, tag = "1")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Cancel {
+    #[prost(uint32, tag = "1")]
+    pub op_code: u32,
+}
+
+/// one byte payloads, OPENRTPCONNECT_ACK, CLOSERTPCONNECT and CLOSERTPCONNECT_ACK
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ResultCode {
+    #[prost(uint32, tag = "1")]
+    pub result: u32,
+}
+
+/// header and payload of one packet
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct VnMessage {
+    #[prost(message, optional, tag = "1")]
+    pub header: Option<Header>,
+    #[prost(oneof = "vn_message::Body", tags = "2, 3, 4, 5, 6, 7, 8, 9, 10, 11")]
+    pub body: Option<vn_message::Body>,
+}
+
+pub mod vn_message {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Body {
+        #[prost(message, tag = "2")]
+        Register(super::Register),
+        #[prost(message, tag = "3")]
+        RequestChannel(super::RequestChannel),
+        #[prost(message, tag = "4")]
+        RequestChannelAck(super::RequestChannelAck),
+        #[prost(message, tag = "5")]
+        OpenRtpConnect(super::OpenRtpConnect),
+        #[prost(message, tag = "6")]
+        Play(super::Play),
+        #[prost(message, tag = "7")]
+        PlayAck(super::PlayAck),
+        #[prost(message, tag = "8")]
+        ResFromTag(super::ResFromTag),
+        #[prost(message, tag = "9")]
+        Cancel(super::Cancel),
+        #[prost(message, tag = "10")]
+        ResultCode(super::ResultCode),
+        /// payload of codes without a message of their own
+        #[prost(bytes, tag = "11")]
+        Raw(Vec<u8>),
+    }
+}
+
+impl From<&vn_proto::Header> for Header {
+    fn from(v: &vn_proto::Header) -> Self {
+        Self { code: v.code as u32, fsm_id: v.fsm_id, key: v.key as i32, sn: v.sn as u32 }
+    }
+}
+
+impl TryFrom<&Header> for vn_proto::Header {
+    type Error = anyhow::Error;
+
+    fn try_from(v: &Header) -> Result<Self> {
+        Ok(Self { code: narrow(v.code, "code")?, fsm_id: v.fsm_id, key: narrow(v.key, "key")?, sn: narrow(v.sn, "sn")? })
+    }
+}
+
+impl From<&vn_proto::CodecDesc> for CodecDesc {
+    fn from(v: &vn_proto::CodecDesc) -> Self {
+        Self { index: v.index as u32, payload_type: v.payload_type as u32, mapstr: v.mapstr.clone() }
+    }
+}
+
+impl TryFrom<&CodecDesc> for vn_proto::CodecDesc {
+    type Error = anyhow::Error;
+
+    fn try_from(v: &CodecDesc) -> Result<Self> {
+        Ok(Self { index: narrow(v.index, "index")?, payload_type: narrow(v.payload_type, "payload_type")?, mapstr: v.mapstr.clone() })
+    }
+}
+
+impl From<&vn_proto::Capability> for Capability {
+    fn from(v: &vn_proto::Capability) -> Self {
+        Self { flags: v.flags }
+    }
+}
+
+impl From<&Capability> for vn_proto::Capability {
+    fn from(v: &Capability) -> Self {
+        Self { flags: v.flags }
+    }
+}
+
+impl From<&vn_proto::PortRange> for PortRange {
+    fn from(v: &vn_proto::PortRange) -> Self {
+        Self { min: v.min as u32, max: v.max as u32 }
+    }
+}
+
+impl TryFrom<&PortRange> for vn_proto::PortRange {
+    type Error = anyhow::Error;
+
+    fn try_from(v: &PortRange) -> Result<Self> {
+        Ok(Self { min: narrow(v.min, "min")?, max: narrow(v.max, "max")? })
+    }
+}
+
+impl From<&vn_proto::Register> for Register {
+    fn from(v: &vn_proto::Register) -> Self {
+        Self {
+            ip: v.ip.into(),
+            support_t38: v.support_t38,
+            audio_codecs: v.audio_codecs.iter().map(Into::into).collect(),
+            video_codecs: v.video_codecs.iter().map(Into::into).collect(),
+            fax_codecs: v.fax_codecs.iter().map(Into::into).collect(),
+            capability: v.capability.as_ref().map(Into::into),
+            version: v.version.clone(),
+            capacity: v.capacity,
+            port_range: v.port_range.as_ref().map(Into::into),
+        }
+    }
+}
+
+impl TryFrom<&Register> for vn_proto::Register {
+    type Error = anyhow::Error;
+
+    fn try_from(v: &Register) -> Result<Self> {
+        Ok(Self {
+            ip: Ipv4Addr::from(v.ip),
+            support_t38: v.support_t38,
+            audio_codecs: v.audio_codecs.iter().map(TryInto::try_into).collect::<Result<_>>()?,
+            video_codecs: v.video_codecs.iter().map(TryInto::try_into).collect::<Result<_>>()?,
+            fax_codecs: v.fax_codecs.iter().map(TryInto::try_into).collect::<Result<_>>()?,
+            capability: v.capability.as_ref().map(Into::into),
+            version: v.version.clone(),
+            capacity: v.capacity,
+            port_range: v.port_range.as_ref().map(TryInto::try_into).transpose()?,
+        })
+    }
+}
+
+impl From<&vn_proto::RequestChannel> for RequestChannel {
+    fn from(v: &vn_proto::RequestChannel) -> Self {
+        Self {
+            ice_type: v.ice_type as u32,
+            life_seconds: v.life_seconds as u32,
+            media_type: v.media_type as u32,
+            as_call_id: v.as_call_id.clone(),
+            agora_info: v.agora_info.clone(),
+            is_nbup: v.is_nbup,
+            ptime: v.ptime as u32,
+            is_caller: v.is_caller,
+            codec: v.codec as u32,
+            amr_mode: v.amr_mode as u32,
+            webrtc: v.webrtc.clone(),
+        }
+    }
+}
+
+impl TryFrom<&RequestChannel> for vn_proto::RequestChannel {
+    type Error = anyhow::Error;
+
+    fn try_from(v: &RequestChannel) -> Result<Self> {
+        Ok(Self {
+            ice_type: narrow(v.ice_type, "ice_type")?,
+            life_seconds: narrow(v.life_seconds, "life_seconds")?,
+            media_type: narrow(v.media_type, "media_type")?,
+            as_call_id: v.as_call_id.clone(),
+            agora_info: v.agora_info.clone(),
+            is_nbup: v.is_nbup,
+            ptime: narrow(v.ptime, "ptime")?,
+            is_caller: v.is_caller,
+            codec: narrow(v.codec, "codec")?,
+            amr_mode: narrow(v.amr_mode, "amr_mode")?,
+            webrtc: v.webrtc.clone(),
+        })
+    }
+}
+
+impl From<&vn_proto::RequestChannelAck> for RequestChannelAck {
+    fn from(v: &vn_proto::RequestChannelAck) -> Self {
+        Self {
+            result: v.result as u32,
+            audio_port: v.audio_port as u32,
+            video_port: v.video_port as u32,
+            fax_port: v.fax_port as u32,
+            media_type: v.media_type as u32,
+            webrtc: v.webrtc.clone(),
+        }
+    }
+}
+
+impl TryFrom<&RequestChannelAck> for vn_proto::RequestChannelAck {
+    type Error = anyhow::Error;
+
+    fn try_from(v: &RequestChannelAck) -> Result<Self> {
+        Ok(Self {
+            result: narrow(v.result, "result")?,
+            audio_port: narrow(v.audio_port, "audio_port")?,
+            video_port: narrow(v.video_port, "video_port")?,
+            fax_port: narrow(v.fax_port, "fax_port")?,
+            media_type: narrow(v.media_type, "media_type")?,
+            webrtc: v.webrtc.clone(),
+        })
+    }
+}
+
+impl From<&vn_proto::RtpInfo> for RtpInfo {
+    fn from(v: &vn_proto::RtpInfo) -> Self {
+        Self {
+            ip: v.ip.into(),
+            port: v.port as u32,
+            media_type: v.media_type as u32,
+            internal_pltyp: v.internal_pltyp as u32,
+            nego_pltyp: v.nego_pltyp as u32,
+            attribute: v.attribute.clone(),
+            tele_event: v.tele_event as u32,
+            direction: v.direction as u32,
+            webrtc: v.webrtc.clone(),
+        }
+    }
+}
+
+impl TryFrom<&RtpInfo> for vn_proto::RtpInfo {
+    type Error = anyhow::Error;
+
+    fn try_from(v: &RtpInfo) -> Result<Self> {
+        Ok(Self {
+            ip: Ipv4Addr::from(v.ip),
+            port: narrow(v.port, "port")?,
+            media_type: narrow(v.media_type, "media_type")?,
+            internal_pltyp: narrow(v.internal_pltyp, "internal_pltyp")?,
+            nego_pltyp: narrow(v.nego_pltyp, "nego_pltyp")?,
+            attribute: v.attribute.clone(),
+            tele_event: narrow(v.tele_event, "tele_event")?,
+            direction: narrow(v.direction, "direction")?,
+            webrtc: v.webrtc.clone(),
+        })
+    }
+}
+
+impl From<&vn_proto::OpenRtpConnect> for OpenRtpConnect {
+    fn from(v: &vn_proto::OpenRtpConnect) -> Self {
+        Self { rtpinfos: v.rtpinfos.iter().map(Into::into).collect() }
+    }
+}
+
+impl TryFrom<&OpenRtpConnect> for vn_proto::OpenRtpConnect {
+    type Error = anyhow::Error;
+
+    fn try_from(v: &OpenRtpConnect) -> Result<Self> {
+        Ok(Self { rtpinfos: v.rtpinfos.iter().map(TryInto::try_into).collect::<Result<_>>()? })
+    }
+}
+
+impl From<&vn_proto::Filename> for Filename {
+    fn from(v: &vn_proto::Filename) -> Self {
+        Self { format: v.format as u32, filename: v.filename.clone() }
+    }
+}
+
+impl TryFrom<&Filename> for vn_proto::Filename {
+    type Error = anyhow::Error;
+
+    fn try_from(v: &Filename) -> Result<Self> {
+        Ok(Self { format: narrow(v.format, "format")?, filename: v.filename.clone() })
+    }
+}
+
+impl From<&vn_proto::Play> for Play {
+    fn from(v: &vn_proto::Play) -> Self {
+        Self {
+            interval: v.interval,
+            play_times: v.play_times as u32,
+            max_duration: v.max_duration,
+            key_mask: v.key_mask as u32,
+            record: v.record,
+            speech_barge: v.speech_barge,
+            erase_dtmf: v.erase_dtmf,
+            files: v.files.iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl TryFrom<&Play> for vn_proto::Play {
+    type Error = anyhow::Error;
+
+    fn try_from(v: &Play) -> Result<Self> {
+        Ok(Self {
+            interval: v.interval,
+            play_times: narrow(v.play_times, "play_times")?,
+            max_duration: v.max_duration,
+            key_mask: narrow(v.key_mask, "key_mask")?,
+            record: v.record,
+            speech_barge: v.speech_barge,
+            erase_dtmf: v.erase_dtmf,
+            files: v.files.iter().map(TryInto::try_into).collect::<Result<_>>()?,
+        })
+    }
+}
+
+impl From<&vn_proto::PlayAck> for PlayAck {
+    fn from(v: &vn_proto::PlayAck) -> Self {
+        Self { result: v.result as u32, play_duration: v.play_duration }
+    }
+}
+
+impl TryFrom<&PlayAck> for vn_proto::PlayAck {
+    type Error = anyhow::Error;
+
+    fn try_from(v: &PlayAck) -> Result<Self> {
+        Ok(Self { result: narrow(v.result, "result")?, play_duration: v.play_duration })
+    }
+}
+
+impl From<&vn_proto::ResFromTag> for ResFromTag {
+    fn from(v: &vn_proto::ResFromTag) -> Self {
+        Self { value: v.value.clone() }
+    }
+}
+
+impl From<&ResFromTag> for vn_proto::ResFromTag {
+    fn from(v: &ResFromTag) -> Self {
+        Self { value: v.value.clone() }
+    }
+}
+
+/// v as the narrower type of the wire, an error naming field if it doesn't fit
+fn narrow<S: Copy + fmt::Display, T: TryFrom<S>>(v: S, field: &str) -> Result<T> {
+    T::try_from(v).ok().with_context(||format!("{field} [{v}] out of range"))
+}
+
+/// decoded with the configured charset, lossy utf8 if that fails
+fn owned_str(s: StrRef<'_>) -> String {
+    s.decode().map(Cow::into_owned).unwrap_or_else(|| String::from_utf8_lossy(s.data()).into_owned())
+}
+
+fn codecs(v: &[vn_proto::CodecDescRef<'_>]) -> Vec<CodecDesc> {
+    v.iter().map(|x| CodecDesc {
+        index: x.index() as u32,
+        payload_type: x.payload_type() as u32,
+        mapstr: x.map_str().map(Cow::into_owned).unwrap_or_default(),
+    }).collect()
+}
+
+fn body_of(code: MCodeType, payload: &[u8]) -> Result<Option<vn_message::Body>> {
+    use vn_message::Body;
+
+    let body = match code {
+        MCodeType::REGISTER => {
+            let r = RegisterRef::parse_from(payload)?;
+            Body::Register(Register {
+                ip: r.ip.into(),
+                support_t38: r.media_info.support_t38,
+                audio_codecs: codecs(&r.media_info.audio_codecs),
+                video_codecs: codecs(&r.media_info.video_codecs),
+                fax_codecs: codecs(&r.media_info.fax_codecs),
+                capability: r.tags().capability().as_ref().map(Into::into),
+                version: r.version().map(Cow::into_owned),
+                capacity: r.capacity(),
+                port_range: r.port_range().as_ref().map(Into::into),
+            })
+        },
+        MCodeType::REQUESTCHANNEL => {
+            let r = RequestChannelRef::parse_from(payload)?;
+            Body::RequestChannel(RequestChannel {
+                ice_type: r.part1().ice_type_code() as u32,
+                life_seconds: r.part1().life_seconds() as u32,
+                media_type: r.part1().media_type_code() as u32,
+                as_call_id: owned_str(r.as_call_id()),
+                agora_info: r.agora_info().map(owned_str),
+                is_nbup: r.part2().is_nbup(),
+                ptime: r.part2().ptime() as u32,
+                is_caller: r.part2().is_caller(),
+                codec: r.part2().codec_code() as u32,
+                amr_mode: r.part2().amr_mode() as u32,
+                webrtc: r.webrtc_strs().map(owned_str).collect(),
+            })
+        },
+        MCodeType::REQUESTCHANNEL_ACK => {
+            let r = RequestChannelAckRef::parse_from(payload)?;
+            let p = r.part1();
+            Body::RequestChannelAck(RequestChannelAck {
+                result: p.result() as u32,
+                audio_port: p.audio_port() as u32,
+                video_port: p.video_port() as u32,
+                fax_port: p.fax_port() as u32,
+                media_type: p.media_type() as u32,
+                // an empty block is sent as a single null
+                webrtc: r.webrtc_strs().map(owned_str).filter(|x| !x.is_empty()).collect(),
+            })
+        },
+        MCodeType::OPENRTPCONNECT => {
+            let r = OpenRtpConnectRef::parse_from(payload)?;
+            let mut rtpinfos = Vec::new();
+            for info in r.rtpinfo_iter() {
+                let info = info?;
+                let ip = match info.part1().ip() {
+                    std::net::IpAddr::V4(ip) => ip,
+                    std::net::IpAddr::V6(_ip) => Ipv4Addr::UNSPECIFIED,
+                };
+                rtpinfos.push(RtpInfo {
+                    ip: ip.into(),
+                    port: info.part1().port() as u32,
+                    media_type: info.part1().media_type() as u32,
+                    internal_pltyp: info.part1().internal_pltyp() as u32,
+                    nego_pltyp: info.part1().nego_pltyp() as u32,
+                    attribute: owned_str(info.attribute()),
+                    tele_event: info.part2().tele_event() as u32,
+                    direction: info.part2().direction() as u32,
+                    webrtc: info.webrtc_strs().map(owned_str).collect(),
+                });
+            }
+            Body::OpenRtpConnect(OpenRtpConnect { rtpinfos })
+        },
+        MCodeType::PLAY => {
+            let r = PlayRef::parse_from(payload)?;
+            let p = r.part1();
+            let mut files = Vec::new();
+            for file in r.files() {
+                let file = file?;
+                files.push(Filename { format: file.format() as u32, filename: owned_str(file.filename().clone()) });
+            }
+            Body::Play(Play {
+                interval: p.interval(),
+                play_times: p.play_times() as u32,
+                max_duration: p.max_duration(),
+                key_mask: p.key_mask() as u32,
+                record: p.record(),
+                speech_barge: p.speech_barge(),
+                erase_dtmf: p.erase_dtmf(),
+                files,
+            })
+        },
+        MCodeType::PLAY_ACK => {
+            let r = PlayAckRef::parse_from(payload)?;
+            Body::PlayAck(PlayAck { result: r.part1().result() as u32, play_duration: r.part1().play_duration() })
+        },
+        MCodeType::RESFROMTAG => {
+            let r = ResFromTagRef::parse_from(payload)?;
+            Body::ResFromTag(ResFromTag { value: String::from_utf8_lossy(r.value()).into_owned() })
+        },
+        MCodeType::CANCEL => Body::Cancel(Cancel { op_code: CancelRef::parse_from(payload)?.op_code() as u32 }),
+        MCodeType::OPENRTPCONNECT_ACK => Body::ResultCode(ResultCode { result: OpenRtpConnectAck::parse_from(payload)?.value() as u32 }),
+        MCodeType::CLOSERTPCONNECT => Body::ResultCode(ResultCode { result: CloseRtpConnect::parse_from(payload)?.value() as u32 }),
+        MCodeType::CLOSERTPCONNECT_ACK => Body::ResultCode(ResultCode { result: CloseRtpConnectAck::parse_from(payload)?.value() as u32 }),
+        _ if payload.is_empty() => return Ok(None),
+        _ => Body::Raw(payload.to_vec()),
+    };
+    Ok(Some(body))
+}
+
+impl<'a> TryFrom<&PacketRef<'a>> for VnMessage {
+    type Error = anyhow::Error;
+
+    fn try_from(packet: &PacketRef<'a>) -> Result<Self> {
+        let header = Header {
+            code: packet.code() as u32,
+            fsm_id: packet.fsm_id(),
+            key: packet.key() as i32,
+            sn: packet.sn() as u32,
+        };
+        let payload = packet.payload();
+        let body = match MCodeType::try_from(packet.code()) {
+            Ok(code) => body_of(code, payload).with_context(||format!("decode {code:?} failed"))?,
+            Err(_e) if payload.is_empty() => None,
+            Err(_e) => Some(vn_message::Body::Raw(payload.to_vec())),
+        };
+        Ok(Self { header: Some(header), body })
+    }
+}
+
+impl VnMessage {
+    /// decode a whole datagram
+    pub fn from_packet(data: &[u8]) -> Result<Self> {
+        Self::try_from(&PacketRef::parse_from(data)?)
+    }
+
+    /// datagram of this message, header and payload as vn_proto writes them,
+    /// an error if a field is out of range of the wire
+    pub fn to_packet(&self) -> Result<Vec<u8>> {
+        use vn_message::Body;
+
+        let mut payload = Vec::new();
+        match &self.body {
+            Some(Body::Register(v)) => { vn_proto::Register::try_from(v)?.write_to(&mut payload); },
+            Some(Body::RequestChannel(v)) => { vn_proto::RequestChannel::try_from(v)?.write_to(&mut payload); },
+            Some(Body::RequestChannelAck(v)) => { vn_proto::RequestChannelAck::try_from(v)?.write_to(&mut payload); },
+            Some(Body::OpenRtpConnect(v)) => { vn_proto::OpenRtpConnect::try_from(v)?.write_to(&mut payload); },
+            Some(Body::Play(v)) => { vn_proto::Play::try_from(v)?.write_to(&mut payload); },
+            Some(Body::PlayAck(v)) => { vn_proto::PlayAck::try_from(v)?.write_to(&mut payload); },
+            Some(Body::ResFromTag(v)) => { vn_proto::ResFromTag::from(v).write_to(&mut payload); },
+            Some(Body::Cancel(v)) => payload.extend_from_slice(&narrow::<_, u16>(v.op_code, "op_code")?.to_be_bytes()),
+            Some(Body::ResultCode(v)) => payload.push(narrow(v.result, "result")?),
+            Some(Body::Raw(v)) => payload.extend_from_slice(v),
+            None => {},
+        }
+
+        let header = self.header.as_ref().map(vn_proto::Header::try_from).transpose()?.unwrap_or_default();
+        let mut data = Vec::with_capacity(vn_proto::HEADER_LENGTH + payload.len());
+        header.write_to2(&mut data, &payload[..]);
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use prost::Message;
+
+    use crate::vn_proto::{self, Filename, Header, MCodeType, Play, RtpInfo};
+
+    use super::{vn_message::Body, VnMessage};
+
+    fn packet(code: MCodeType, payload: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        Header { code: code.code(), fsm_id: 5000001, key: -2, sn: 9 }.write_to2(&mut data, payload);
+        data
+    }
+
+    #[test]
+    fn test_vn_message() {
+        let mut payload = Vec::new();
+        Play {
+            play_times: 2,
+            key_mask: 0xfff,
+            files: vec![Filename { format: 100, filename: "file://cc/11000.wav".into() }],
+            ..Default::default()
+        }.write_to(&mut payload);
+        let data = packet(MCodeType::PLAY, &payload);
+        let msg = VnMessage::from_packet(&data).unwrap();
+        let Some(Body::Play(play)) = &msg.body else { panic!("{msg:?}") };
+        assert_eq!((play.play_times, play.key_mask, play.files[0].filename.as_str()), (2, 0xfff, "file://cc/11000.wav"));
+        assert_eq!(msg.header.as_ref().map(|x| (x.fsm_id, x.key, x.sn)), Some((5000001, -2, 9)));
+
+        // through protobuf bytes and back to the same datagram
+        let decoded = VnMessage::decode(&msg.encode_to_vec()[..]).unwrap();
+        assert_eq!(decoded, msg);
+        assert_eq!(decoded.to_packet().unwrap(), data);
+
+        let mut payload = Vec::new();
+        vn_proto::OpenRtpConnect { rtpinfos: vec![RtpInfo::plain(Ipv4Addr::new(10, 0, 0, 1), 4000, 8)] }.write_to(&mut payload);
+        let data = packet(MCodeType::OPENRTPCONNECT, &payload);
+        let msg = VnMessage::from_packet(&data).unwrap();
+        assert_eq!(msg.to_packet().unwrap(), data);
+        let Some(Body::OpenRtpConnect(open)) = &msg.body else { panic!("{msg:?}") };
+        let info = vn_proto::RtpInfo::try_from(&open.rtpinfos[0]).unwrap();
+        assert_eq!((info.ip, info.port, info.webrtc.len()), (Ipv4Addr::new(10, 0, 0, 1), 4000, 6));
+
+        let mut payload = Vec::new();
+        vn_proto::Register {
+            version: Some("V2.1.0".into()),
+            capacity: Some(2000),
+            port_range: Some(vn_proto::PortRange { min: 20000, max: 29999 }),
+            ..Default::default()
+        }.write_to(&mut payload);
+        let data = packet(MCodeType::REGISTER, &payload);
+        let msg = VnMessage::from_packet(&data).unwrap();
+        assert_eq!(msg.to_packet().unwrap(), data);
+        let Some(Body::Register(register)) = &msg.body else { panic!("{msg:?}") };
+        assert_eq!((register.version.as_deref(), register.capacity), (Some("V2.1.0"), Some(2000)));
+
+        // codes without a message keep their payload
+        let data = packet(MCodeType::DTMFRCV, b"12#");
+        let msg = VnMessage::from_packet(&data).unwrap();
+        assert_eq!(msg.body, Some(Body::Raw(b"12#".to_vec())));
+        assert_eq!(msg.to_packet().unwrap(), data);
+
+        // numbers too wide for the wire are refused, not truncated
+        let mut wide = msg.clone();
+        wide.header.as_mut().unwrap().sn = 70000;
+        let e = wide.to_packet().unwrap_err();
+        assert!(format!("{e:#}").contains("sn [70000] out of range"), "{e:#}");
+        let mut wide = decoded.clone();
+        let Some(Body::Play(play)) = &mut wide.body else { panic!("{wide:?}") };
+        play.files[0].format = 256;
+        assert!(wide.to_packet().is_err());
+        wide.body = Some(Body::ResultCode(super::ResultCode { result: 300 }));
+        assert!(wide.to_packet().is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! classic pcap files of udp datagrams, as Wireshark opens them.
+//!
+//! Records are raw IPv4 (LINKTYPE_RAW), no ethernet header. UDP checksum is
+//! left 0, which IPv4 allows.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    net::{Ipv4Addr, SocketAddrV4},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Result, Context, bail};
+
+const MAGIC: u32 = 0xa1b2c3d4;
+pub const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+const IP_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const IPPROTO_UDP: u8 = 17;
+
+pub struct PcapWriter<W: Write> {
+    w: W,
+    records: u64,
+}
+
+impl PcapWriter<BufWriter<File>> {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(||format!("create pcap failed [{path:?}]"))?;
+        Self::new(BufWriter::new(file))
+    }
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// writes the global header
+    pub fn new(mut w: W) -> Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&MAGIC.to_le_bytes());
+        header.extend_from_slice(&2_u16.to_le_bytes());
+        header.extend_from_slice(&4_u16.to_le_bytes());
+        header.extend_from_slice(&0_i32.to_le_bytes());
+        header.extend_from_slice(&0_u32.to_le_bytes());
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        w.write_all(&header)?;
+        Ok(Self { w, records: 0 })
+    }
+
+    pub fn write_udp(&mut self, time: SystemTime, src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Result<()> {
+        let total = IP_HEADER_LEN + UDP_HEADER_LEN + payload.len();
+        if total > SNAPLEN as usize {
+            bail!("udp payload too large [{}]", payload.len())
+        }
+        let since = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + total);
+        record.extend_from_slice(&(since.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&since.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(total as u32).to_le_bytes());
+        record.extend_from_slice(&(total as u32).to_le_bytes());
+
+        let mut ip = [0_u8; IP_HEADER_LEN];
+        ip[0] = 0x45;
+        ip[2..4].copy_from_slice(&(total as u16).to_be_bytes());
+        ip[4..6].copy_from_slice(&(self.records as u16).to_be_bytes());
+        ip[8] = 64;
+        ip[9] = IPPROTO_UDP;
+        ip[12..16].copy_from_slice(&src.ip().octets());
+        ip[16..20].copy_from_slice(&dst.ip().octets());
+        let checksum = ip_checksum(&ip);
+        ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+        record.extend_from_slice(&ip);
+
+        record.extend_from_slice(&src.port().to_be_bytes());
+        record.extend_from_slice(&dst.port().to_be_bytes());
+        record.extend_from_slice(&((UDP_HEADER_LEN + payload.len()) as u16).to_be_bytes());
+        record.extend_from_slice(&0_u16.to_be_bytes());
+        record.extend_from_slice(payload);
+
+        self.w.write_all(&record)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.w.flush()?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+fn ip_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header.chunks(2).map(|x| u16::from_be_bytes([x[0], x[1]]) as u32).sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// udp datagram of a pcap record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpRecord {
+    pub time: SystemTime,
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    pub payload: Vec<u8>,
+}
+
+/// udp records of a pcap written by PcapWriter
+pub fn read_udp(data: &[u8]) -> Result<Vec<UdpRecord>> {
+    let u32_at = |pos: usize| u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]);
+    if data.len() < 24 || u32_at(0) != MAGIC {
+        bail!("not a pcap")
+    }
+    if u32_at(20) != LINKTYPE_RAW {
+        bail!("unsupported linktype [{}]", u32_at(20))
+    }
+
+    let mut records = Vec::new();
+    let mut pos = 24;
+    while pos < data.len() {
+        if pos + 16 > data.len() {
+            bail!("truncated record header at [{pos}]")
+        }
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(u32_at(pos) as u64) + Duration::from_micros(u32_at(pos + 4) as u64);
+        let len = u32_at(pos + 8) as usize;
+        let body = data.get(pos + 16..pos + 16 + len).with_context(||format!("truncated record at [{pos}]"))?;
+        pos += 16 + len;
+
+        if body.len() < IP_HEADER_LEN + UDP_HEADER_LEN || body[9] != IPPROTO_UDP {
+            continue
+        }
+        let ip = |x: &[u8]| Ipv4Addr::new(x[0], x[1], x[2], x[3]);
+        let port = |x: &[u8]| u16::from_be_bytes([x[0], x[1]]);
+        let udp = &body[IP_HEADER_LEN..];
+        records.push(UdpRecord {
+            time,
+            src: SocketAddrV4::new(ip(&body[12..16]), port(&udp[0..2])),
+            dst: SocketAddrV4::new(ip(&body[16..20]), port(&udp[2..4])),
+            payload: udp[UDP_HEADER_LEN..].to_vec(),
+        });
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::{Ipv4Addr, SocketAddrV4}, time::{Duration, SystemTime}};
+
+    use super::{ip_checksum, read_udp, PcapWriter};
+
+    #[test]
+    fn test_pcap_udp() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let src = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 20000);
+        let dst = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 30000);
+        let mut pcap = PcapWriter::new(Vec::new()).unwrap();
+        pcap.write_udp(time, src, dst, b"hello").unwrap();
+        pcap.write_udp(time + Duration::from_millis(20), dst, src, b"").unwrap();
+        assert_eq!(pcap.records(), 2);
+        let data = pcap.into_inner();
+        assert_eq!(data.len(), 24 + 2 * (16 + 28) + 5);
+        // checksum over a header including it folds to 0
+        assert_eq!(ip_checksum(&data[24 + 16..24 + 16 + 20]), 0);
+
+        let records = read_udp(&data).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].time, records[0].src, records[0].dst), (time, src, dst));
+        assert_eq!(records[0].payload, b"hello");
+        assert_eq!(records[1].src, dst);
+        assert!(records[1].payload.is_empty());
+
+        assert!(read_udp(&data[..30]).is_err());
+        assert!(read_udp(b"not a pcap file at all!!").is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! PLAYs queued per channel for chained prompts.
+//!
+//! One PLAY is out on a channel at a time, its PLAY_ACK sends the next one.
+//! What happens to a PLAY queued while one is out is up to [`QueuePolicy`].
+//! CANCEL of the current PLAY is sent at most once and is done when its
+//! PLAY_ACK comes back, whether MS cut it short or it had already ended.
+//!
+//! [`PlayQueue`] only says what to send, see [`send_actions`].
+
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    utils::datagram::Datagram,
+    vn_proto::{MCodeType, Play},
+    vn_session::CnSession,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueuePolicy {
+    /// play after the ones before it
+    #[default]
+    Serialize,
+    /// drop queued ones and cancel the current one
+    Replace,
+    /// refuse while one is out
+    RejectWhileBusy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlayAction {
+    Play(Play),
+    /// CANCEL of the current PLAY
+    Cancel,
+}
+
+#[derive(Debug, Default)]
+struct ChannelPlays {
+    playing: bool,
+    cancel_sent: bool,
+    pending: VecDeque<Play>,
+    /// results of PLAY_ACKs in order
+    results: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub played: u64,
+    pub rejected: u64,
+    /// queued ones dropped by replace or cancel
+    pub dropped: u64,
+    pub cancelled: u64,
+}
+
+#[derive(Debug)]
+pub struct PlayQueue {
+    policy: QueuePolicy,
+    /// queued besides the current one, per channel
+    max_pending: usize,
+    channels: HashMap<u32, ChannelPlays>,
+    stats: QueueStats,
+}
+
+impl PlayQueue {
+    pub fn new(policy: QueuePolicy, max_pending: usize) -> Self {
+        Self { policy, max_pending, channels: HashMap::new(), stats: QueueStats::default() }
+    }
+
+    /// what to send for a new PLAY, error if refused or the queue is full
+    pub fn enqueue(&mut self, fsm_id: u32, play: Play) -> Result<Vec<PlayAction>> {
+        let channel = self.channels.entry(fsm_id).or_default();
+        if !channel.playing {
+            channel.playing = true;
+            self.stats.played += 1;
+            return Ok(vec![PlayAction::Play(play)])
+        }
+
+        match self.policy {
+            QueuePolicy::Serialize => {
+                if channel.pending.len() >= self.max_pending {
+                    self.stats.rejected += 1;
+                    bail!("play queue of [{fsm_id}] full, [{}] pending", channel.pending.len())
+                }
+                channel.pending.push_back(play);
+                Ok(Vec::new())
+            },
+            QueuePolicy::Replace => {
+                self.stats.dropped += channel.pending.len() as u64;
+                channel.pending.clear();
+                channel.pending.push_back(play);
+                Ok(Self::cancel_current(channel, &mut self.stats))
+            },
+            QueuePolicy::RejectWhileBusy => {
+                self.stats.rejected += 1;
+                bail!("channel [{fsm_id}] busy playing")
+            },
+        }
+    }
+
+    fn cancel_current(channel: &mut ChannelPlays, stats: &mut QueueStats) -> Vec<PlayAction> {
+        if !channel.playing || channel.cancel_sent {
+            return Vec::new()
+        }
+        channel.cancel_sent = true;
+        stats.cancelled += 1;
+        vec![PlayAction::Cancel]
+    }
+
+    /// PLAY_ACK ends the current PLAY and sends the next queued one,
+    /// ignored if none is out
+    pub fn on_play_ack(&mut self, fsm_id: u32, result: u8) -> Vec<PlayAction> {
+        let Some(channel) = self.channels.get_mut(&fsm_id).filter(|x| x.playing) else {
+            debug!("PLAY_ACK of [{fsm_id}] while not playing");
+            return Vec::new()
+        };
+        channel.playing = false;
+        channel.cancel_sent = false;
+        channel.results.push(result);
+        let Some(next) = channel.pending.pop_front() else { return Vec::new() };
+        channel.playing = true;
+        self.stats.played += 1;
+        vec![PlayAction::Play(next)]
+    }
+
+    /// drop queued PLAYs and cancel the current one
+    pub fn cancel(&mut self, fsm_id: u32) -> Vec<PlayAction> {
+        let Some(channel) = self.channels.get_mut(&fsm_id) else { return Vec::new() };
+        self.stats.dropped += channel.pending.len() as u64;
+        channel.pending.clear();
+        Self::cancel_current(channel, &mut self.stats)
+    }
+
+    /// channel released, nothing more of it is sent
+    pub fn release(&mut self, fsm_id: u32) {
+        if let Some(channel) = self.channels.remove(&fsm_id) {
+            self.stats.dropped += channel.pending.len() as u64;
+        }
+    }
+
+    /// a PLAY is out or queued
+    pub fn is_busy(&self, fsm_id: u32) -> bool {
+        self.channels.get(&fsm_id).is_some_and(|x| x.playing || !x.pending.is_empty())
+    }
+
+    pub fn pending(&self, fsm_id: u32) -> usize {
+        self.channels.get(&fsm_id).map(|x| x.pending.len()).unwrap_or(0)
+    }
+
+    pub fn results(&self, fsm_id: u32) -> &[u8] {
+        self.channels.get(&fsm_id).map(|x| &x.results[..]).unwrap_or(&[])
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.stats
+    }
+}
+
+pub async fn send_actions<S: Datagram>(session: &mut CnSession<S>, fsm_id: u32, actions: Vec<PlayAction>) -> Result<()> {
+    for action in actions {
+        match action {
+            PlayAction::Play(play) => { session.play(fsm_id, &play).await?; },
+            PlayAction::Cancel => {
+                session.send_request(MCodeType::CANCEL, fsm_id, &MCodeType::PLAY.code().to_be_bytes()).await?;
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        vn_ms_sim::MsSimConfig,
+        vn_proto::{Filename, Play},
+        vn_scenario::{run_scenario, Scenario},
+        vn_testkit::MsStub,
+    };
+
+    use super::{PlayAction, PlayQueue, QueuePolicy};
+
+    fn play(name: &str) -> Play {
+        Play { files: vec![Filename { format: 0, filename: name.into() }], ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn test_play_queue() {
+        let mut queue = PlayQueue::new(QueuePolicy::Serialize, 1);
+        assert_eq!(queue.enqueue(1, play("a")).unwrap(), vec![PlayAction::Play(play("a"))]);
+        assert!(queue.enqueue(1, play("b")).unwrap().is_empty());
+        assert!(queue.enqueue(1, play("c")).is_err());
+        assert_eq!(queue.on_play_ack(1, 0), vec![PlayAction::Play(play("b"))]);
+        assert!(queue.on_play_ack(1, 0).is_empty());
+        assert!(!queue.is_busy(1));
+        assert!(queue.on_play_ack(1, 0).is_empty());
+        assert_eq!(queue.results(1), [0, 0]);
+
+        // cancel is sent once, its PLAY_ACK starts the replacement
+        let mut queue = PlayQueue::new(QueuePolicy::Replace, 8);
+        queue.enqueue(1, play("a")).unwrap();
+        assert_eq!(queue.enqueue(1, play("b")).unwrap(), vec![PlayAction::Cancel]);
+        assert!(queue.enqueue(1, play("c")).unwrap().is_empty());
+        assert_eq!(queue.on_play_ack(1, 5), vec![PlayAction::Play(play("c"))]);
+        assert_eq!(queue.cancel(1), vec![PlayAction::Cancel]);
+        assert!(queue.cancel(1).is_empty());
+        let stats = queue.stats();
+        assert_eq!((stats.played, stats.dropped, stats.cancelled), (2, 1, 2));
+
+        let mut queue = PlayQueue::new(QueuePolicy::RejectWhileBusy, 8);
+        queue.enqueue(1, play("a")).unwrap();
+        assert!(queue.enqueue(1, play("b")).is_err());
+        assert!(queue.enqueue(2, play("b")).is_ok());
+
+        let (_ms, mut session) = MsStub::start(MsSimConfig::default(), 5);
+        let flow = r#"
+steps:
+  - send: {code: REQUESTCHANNEL, fsm: 1, request_channel: {media_type: 1, webrtc: [""]}}
+  - expect: {code: REQUESTCHANNEL_ACK}
+  - play_queue: {fsm: 1, plays: [{files: ["file://cc/1.wav"]}, {files: ["file://cc/2.wav"]}, {files: ["file://cc/3.wav"]}]}
+  - expect_quiet: {window: 50ms, code: PLAY_ACK}
+"#;
+        run_scenario(&mut session, &Scenario::from_yaml(flow).unwrap()).await.unwrap();
+        let flow = flow.replace("steps:", "register: false\nsteps:").replace("plays:", "policy: reject-while-busy, played: 3, plays:");
+        let e = run_scenario(&mut session, &Scenario::from_yaml(&flow).unwrap()).await.unwrap_err();
+        assert!(format!("{e:#}").contains("played [1] of [3]"), "{e:#}");
+    }
+}
@@ -0,0 +1,806 @@
+//! CN registered with several MS at once.
+//!
+//! Each MS is a msvn path, possibly in its own CINDIR, the CN binds
+//! `mscn{cn_id}` next to each of them. A channel belongs to the MS its
+//! REQUESTCHANNEL went to and later requests of that fsm_id go there too.
+//! When a MS dies (send fails, or requests time out while it's silent)
+//! its pending REQUESTCHANNELs are sent again to another MS and
+//! other pending requests are reported as failed.
+//!
+//! Where a new channel goes is decided by [`SelectPolicy`], per MS
+//! counters are in [`PeerStats`], why each channel ended is kept in a
+//! [`ChannelRegistry`].
+
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, Context, bail};
+use futures::future::select_all;
+use tokio::net::UnixDatagram;
+use tracing::{info, warn};
+
+use crate::{
+    utils::{clock::{default_clock, timeout_at, SharedClock}, rate_limit::RateLimiter, rtt::RttStats},
+    vn_acl::AclMode,
+    vn_epoch::EpochMode,
+    vn_fsm_id::{FsmIdAllocator, FsmIdCheck, FsmIdSpace},
+    vn_cdr::CdrWriter,
+    vn_codec::{negotiate_register, CallProfile},
+    vn_channels::{ChannelRegistry, EndReason},
+    vn_conn_stats::LinkStats,
+    vn_handshake::StrayPolicy,
+    vn_marker::{send_marker, Marker},
+    vn_proto::{Header, MCodeType, RequestChannel, RequestChannelAckRef},
+    vn_session::{bind_socket, cn_socket_path, CnSession, RegisterDiff},
+    vn_socket_name::socket_naming,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PoolEvent {
+    Packet {
+        peer: usize,
+        header: Header,
+        payload: Vec<u8>,
+    },
+    PeerDown {
+        peer: usize,
+        /// REQUESTCHANNELs sent again to another MS, (fsm_id, new peer)
+        moved: Vec<(u32, usize)>,
+        /// pending requests which can't be moved
+        failed: Vec<(u32, MCodeType)>,
+        /// channels owned by the dead MS
+        lost: Vec<u32>,
+    },
+    /// MS sent REGISTER again and was acked
+    Reregistered {
+        peer: usize,
+        diff: RegisterDiff,
+        /// channels owned by the MS before, they are gone
+        lost: Vec<u32>,
+    },
+}
+
+/// which alive MS gets a new channel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum SelectPolicy {
+    #[default]
+    RoundRobin,
+    /// fewest active channels, ties go round robin
+    LeastActive,
+    /// fewest active channels relative to weight, MS of weight 0 gets none
+    Weighted,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    /// REQUESTCHANNELs sent, including moved ones
+    pub requested: u64,
+    /// REQUESTCHANNELs moved here from a dead MS
+    pub moved_in: u64,
+    /// REQUESTCHANNEL_ACKs with result 0
+    pub accepted: u64,
+    /// REQUESTCHANNEL_ACKs with other results
+    pub rejected: u64,
+    /// channels owned now
+    pub active: usize,
+}
+
+struct Pending {
+    payload: Vec<u8>,
+    sent_at: Instant,
+}
+
+struct MsPeer {
+    session: CnSession<UnixDatagram>,
+    alive: bool,
+    last_seen: Instant,
+    weight: u32,
+    stats: PeerStats,
+    /// (fsm_id, request code) waiting for ACK
+    pending: HashMap<(u32, u16), Pending>,
+}
+
+pub struct MsPool {
+    peers: Vec<MsPeer>,
+    /// fsm_id -> index of peer owning the channel
+    owners: HashMap<u32, usize>,
+    next: usize,
+    policy: SelectPolicy,
+    /// pending request older than this from a silent MS means it's dead
+    dead_after: Duration,
+    events: VecDeque<PoolEvent>,
+    channels: ChannelRegistry,
+    /// paces packets to every MS together, per fsm_id and in total
+    limiter: RateLimiter,
+    /// of dead_after, channel lifetimes and the timers of each session
+    clock: SharedClock,
+    /// codecs of each REQUESTCHANNEL negotiated against the REGISTER of its MS
+    profile: Option<CallProfile>,
+}
+
+impl MsPool {
+    /// bind the cn socket in the CINDIR of each ms socket path
+    pub async fn bind(ms_paths: &[PathBuf], cn_id: u32) -> Result<Self> {
+        if ms_paths.is_empty() {
+            bail!("no MS in pool")
+        }
+
+        let clock = default_clock();
+        let mut peers = Vec::with_capacity(ms_paths.len());
+        for ms_path in ms_paths {
+            // a path not named by the ms template is taken as in CINDIR
+            let cindir = match socket_naming().cindir_of(ms_path) {
+                Some(cindir) => cindir,
+                None => ms_path.parent().with_context(||format!("no dir of ms path [{ms_path:?}]"))?.to_path_buf(),
+            };
+            let socket = bind_socket(&cn_socket_path(&cindir, cn_id)?).await?;
+            peers.push(MsPeer {
+                session: CnSession::with_socket(socket, ms_path.clone(), cn_id),
+                alive: true,
+                last_seen: clock.now(),
+                weight: 1,
+                stats: PeerStats::default(),
+                pending: HashMap::new(),
+            });
+        }
+
+        Ok(Self {
+            peers,
+            owners: HashMap::new(),
+            next: 0,
+            policy: SelectPolicy::default(),
+            dead_after: Duration::from_secs(5),
+            events: VecDeque::new(),
+            channels: ChannelRegistry::default(),
+            limiter: RateLimiter::default(),
+            clock,
+            profile: None,
+        })
+    }
+
+    pub fn set_dead_after(&mut self, dead_after: Duration) {
+        self.dead_after = dead_after;
+    }
+
+    /// each MS only takes datagrams from its own msvn path, see vn_acl
+    pub fn set_peer_acl(&mut self, mode: AclMode) {
+        for peer in self.peers.iter_mut() {
+            peer.session.set_peer_acl(mode, &[]);
+        }
+    }
+
+    /// epochs offered to every MS at handshake, see vn_epoch
+    pub fn set_epoch_mode(&mut self, mode: EpochMode) {
+        for peer in self.peers.iter_mut() {
+            peer.session.set_epoch_mode(mode);
+        }
+    }
+
+    /// packets out of context at handshake of every MS, see vn_handshake
+    pub fn set_stray_policy(&mut self, policy: StrayPolicy) {
+        for peer in self.peers.iter_mut() {
+            peer.session.set_stray_policy(policy.clone());
+        }
+    }
+
+    /// fsm_id namespace every MS is checked against, see vn_fsm_id
+    pub fn set_fsm_ids(&mut self, space: FsmIdSpace, check: FsmIdCheck) {
+        for peer in self.peers.iter_mut() {
+            peer.session.set_fsm_ids(FsmIdAllocator::new(space), check);
+        }
+    }
+
+    /// one limiter shared by all MS, so moving a channel doesn't reset its bucket
+    pub fn set_rate_limiter(&mut self, limiter: RateLimiter) {
+        self.limiter = limiter;
+    }
+
+    /// time source of our timers and those of every MS session
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        for peer in self.peers.iter_mut() {
+            peer.session.set_clock(clock.clone());
+        }
+        self.clock = clock;
+    }
+
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+
+    /// negotiate media type, ptime and codec of every REQUESTCHANNEL with
+    /// the MS it goes to, None sends them as given
+    pub fn set_profile(&mut self, profile: Option<CallProfile>) {
+        self.profile = profile;
+    }
+
+    pub fn set_policy(&mut self, policy: SelectPolicy) {
+        self.policy = policy;
+    }
+
+    /// one weight per MS in bind order, for SelectPolicy::Weighted
+    pub fn set_weights(&mut self, weights: &[u32]) -> Result<()> {
+        if weights.len() != self.peers.len() {
+            bail!("expect [{}] weights but [{}]", self.peers.len(), weights.len())
+        }
+        if weights.iter().all(|x| *x == 0) {
+            bail!("all weights are 0")
+        }
+        for (peer, weight) in self.peers.iter_mut().zip(weights) {
+            peer.weight = *weight;
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self, peer: usize) -> &PeerStats {
+        &self.peers[peer].stats
+    }
+
+    /// one line per MS
+    pub fn summary(&self) -> Vec<String> {
+        self.peers.iter().enumerate().map(|(index, peer)| {
+            let s = &peer.stats;
+            let mut line = format!(
+                "ms [{index}] [{:?}] {}weight [{}] requested [{}] moved_in [{}] accepted [{}] rejected [{}] active [{}]",
+                peer.session.ms_path(), if peer.alive { "" } else { "down " }, peer.weight,
+                s.requested, s.moved_in, s.accepted, s.rejected, s.active,
+            );
+            if peer.session.rtt().count > 0 {
+                line = format!("{line} {}", peer.session.rtt());
+            }
+            if peer.session.loss().has_loss() {
+                line = format!("{line} sn {}", peer.session.loss());
+            }
+            if let Some(acl) = peer.session.peer_acl().filter(|x| x.stats().rejected > 0) {
+                line = format!("{line} {}", acl.stats());
+            }
+            let epoch = peer.session.epoch().stats();
+            if epoch.stale + epoch.changes > 0 {
+                line = format!("{line} {epoch}");
+            }
+            line
+        }).collect()
+    }
+
+    pub fn channels(&self) -> &ChannelRegistry {
+        &self.channels
+    }
+
+    /// label of every MS link and of channels requested from now on
+    pub fn set_tenant(&mut self, tenant: Option<String>) {
+        for peer in self.peers.iter_mut() {
+            peer.session.set_tenant(tenant.clone());
+        }
+        self.channels.set_tenant(tenant);
+    }
+
+    /// CDR of each channel as it ends, see vn_cdr
+    pub fn set_cdr(&mut self, cdr: Option<CdrWriter>) {
+        self.channels.set_cdr(cdr);
+    }
+
+    /// channel events to NATS or Kafka, see vn_events
+    #[cfg(feature = "events")]
+    pub fn set_events(&mut self, events: Option<crate::vn_events::EventPublisher>) {
+        self.channels.set_events(events);
+    }
+
+    pub fn num_peers(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn ms_path(&self, peer: usize) -> &Path {
+        self.peers[peer].session.ms_path()
+    }
+
+    pub fn is_alive(&self, peer: usize) -> bool {
+        self.peers[peer].alive
+    }
+
+    /// peer owning the channel of fsm_id
+    pub fn owner(&self, fsm_id: u32) -> Option<usize> {
+        self.owners.get(&fsm_id).copied()
+    }
+
+    /// handshake and register with every MS, the ones failing are marked dead
+    pub async fn register_all(&mut self, timeout: Duration) -> Result<()> {
+        for (index, peer) in self.peers.iter_mut().enumerate() {
+            let r = timeout_at(self.clock.as_ref(), self.clock.now() + timeout, async {
+                peer.session.handshake().await?;
+                peer.session.accept_register().await
+            }).await;
+            match r {
+                Some(Ok(_register)) => {
+                    peer.last_seen = self.clock.now();
+                    info!("registered with ms [{index}] [{:?}]", peer.session.ms_path());
+                    if let Some(info) = peer.session.register_info() {
+                        self.channels.on_register(&peer.session.ms_path().display().to_string(), info);
+                    }
+                },
+                Some(Err(e)) => {
+                    warn!("register with ms [{index}] failed [{e:#}]");
+                    peer.alive = false;
+                },
+                None => {
+                    warn!("register with ms [{index}] timeout");
+                    peer.alive = false;
+                },
+            }
+        }
+
+        if !self.peers.iter().any(|x| x.alive) {
+            bail!("no MS registered")
+        }
+        Ok(())
+    }
+
+    /// pick alive MS for a new channel by policy
+    fn select(&mut self) -> Result<usize> {
+        let num = self.peers.len();
+        let start = self.next % num;
+        self.next = self.next.wrapping_add(1);
+
+        let mut best: Option<usize> = None;
+        for index in (start..num).chain(0..start) {
+            let peer = &self.peers[index];
+            if !peer.alive {
+                continue
+            }
+            let weight = match self.policy {
+                SelectPolicy::RoundRobin => return Ok(index),
+                SelectPolicy::LeastActive => 1,
+                SelectPolicy::Weighted => peer.weight as usize,
+            };
+            if weight == 0 {
+                continue
+            }
+            // active / weight < best.active / best.weight
+            let better = best.is_none_or(|x| {
+                let other = &self.peers[x];
+                let other_weight = match self.policy {
+                    SelectPolicy::Weighted => other.weight as usize,
+                    _ => 1,
+                };
+                peer.stats.active * other_weight < other.stats.active * weight
+            });
+            if better {
+                best = Some(index);
+            }
+        }
+        best.context("no MS alive")
+    }
+
+    fn set_owner(&mut self, fsm_id: u32, peer: usize) {
+        if let Some(old) = self.owners.insert(fsm_id, peer) {
+            self.peers[old].stats.active -= 1;
+        }
+        self.peers[peer].stats.active += 1;
+    }
+
+    fn clear_owner(&mut self, fsm_id: u32) {
+        if let Some(old) = self.owners.remove(&fsm_id) {
+            self.peers[old].stats.active -= 1;
+        }
+    }
+
+    /// new channel on next alive MS, returns the MS it ends up on
+    pub async fn request_channel(&mut self, fsm_id: u32, req: &RequestChannel) -> Result<usize> {
+        let peer = self.select()?;
+        let mut req = req.clone();
+        if let Some(profile) = &self.profile {
+            let register = self.peers[peer].session.register().with_context(||format!("ms [{peer}] not registered"))?;
+            negotiate_register(register, profile).with_context(||format!("negotiate with ms [{peer}] failed"))?.apply_to(&mut req);
+        }
+        let mut payload = Vec::new();
+        req.write_to(&mut payload);
+        self.channels.on_requested(fsm_id, req.life_seconds, self.clock.now());
+        self.channels.on_packet(fsm_id, MCodeType::REQUESTCHANNEL.code(), &payload);
+        self.set_owner(fsm_id, peer);
+        self.peers[peer].stats.requested += 1;
+        self.send_to_peer(peer, MCodeType::REQUESTCHANNEL, fsm_id, payload).await?;
+        self.owner(fsm_id).with_context(||format!("no MS left for fsm_id [{fsm_id}]"))
+    }
+
+    /// request on an existing channel, sent to its owner
+    pub async fn send_request(&mut self, code: MCodeType, fsm_id: u32, payload: &[u8]) -> Result<usize> {
+        let peer = self.owner(fsm_id).with_context(||format!("no MS owns fsm_id [{fsm_id}]"))?;
+        self.channels.on_packet(fsm_id, code.code(), payload);
+        self.send_to_peer(peer, code, fsm_id, payload.to_vec()).await?;
+        Ok(peer)
+    }
+
+    /// packet of an existing channel sent as is to its owner with the next
+    /// sn, e.g. by vn_inject
+    pub async fn send_raw(&mut self, mut header: Header, payload: &[u8]) -> Result<usize> {
+        let peer = self.owner(header.fsm_id).with_context(||format!("no MS owns fsm_id [{}]", header.fsm_id))?;
+        if self.limiter.is_enabled() {
+            self.limiter.acquire(header.fsm_id).await;
+        }
+        let session = &mut self.peers[peer].session;
+        header.sn = session.next_sn(header.fsm_id);
+        self.channels.on_packet(header.fsm_id, header.code, payload);
+        session.send_packet(&header, payload).await?;
+        Ok(peer)
+    }
+
+    /// marker of a channel to its owner, a link level one to every alive MS,
+    /// see vn_marker
+    pub async fn send_marker(&mut self, marker: &Marker) -> Result<()> {
+        let peers: Vec<usize> = match marker.fsm_id {
+            Some(fsm_id) => vec![self.owner(fsm_id).with_context(||format!("no MS owns fsm_id [{fsm_id}]"))?],
+            None => (0..self.peers.len()).filter(|x| self.peers[*x].alive).collect(),
+        };
+        for peer in peers {
+            send_marker(&mut self.peers[peer].session, marker).await?;
+        }
+        Ok(())
+    }
+
+    /// HEARTBEAT to every alive MS, their answers feed rtt
+    pub async fn send_heartbeats(&mut self) -> Result<()> {
+        for peer in 0..self.peers.len() {
+            if self.peers[peer].alive {
+                let fsm_id = self.peers[peer].session.base_fsm_id();
+                self.send_to_peer(peer, MCodeType::HEARTBEAT, fsm_id, Vec::new()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn rtt(&self, peer: usize) -> &RttStats {
+        self.peers[peer].session.rtt()
+    }
+
+    /// link of each MS in bind order, see vn_conn_stats
+    pub fn link_stats(&self) -> Vec<LinkStats> {
+        let now = self.clock.now();
+        self.peers.iter().map(|x| LinkStats::of_session(&x.session, x.alive, now)).collect()
+    }
+
+    /// RELEASECHANNEL sent, stop routing its fsm_id
+    pub fn release(&mut self, fsm_id: u32) {
+        self.end(fsm_id, EndReason::Released);
+    }
+
+    /// channel is gone for reason, stop routing its fsm_id
+    pub fn end(&mut self, fsm_id: u32, reason: EndReason) {
+        self.clear_owner(fsm_id);
+        self.channels.end(fsm_id, reason, self.clock.now());
+    }
+
+    async fn send_to_peer(&mut self, peer: usize, code: MCodeType, fsm_id: u32, payload: Vec<u8>) -> Result<()> {
+        if self.limiter.is_enabled() {
+            self.limiter.acquire(fsm_id).await;
+        }
+        let r = self.peers[peer].session.send_request(code, fsm_id, &payload).await;
+        if code.ack().is_some() {
+            self.peers[peer].pending.insert((fsm_id, code.code()), Pending { payload, sent_at: self.clock.now() });
+        }
+        if let Err(e) = r {
+            warn!("send to ms [{peer}] failed [{e:#}]");
+            self.peer_down(peer).await?;
+        }
+        Ok(())
+    }
+
+    /// mark dead and move its pending work, queues a PeerDown event
+    async fn peer_down(&mut self, peer: usize) -> Result<()> {
+        if !self.peers[peer].alive {
+            return Ok(())
+        }
+        self.peers[peer].alive = false;
+        warn!("ms [{peer}] [{:?}] is down", self.peers[peer].session.ms_path());
+
+        let mut pending: Vec<_> = self.peers[peer].pending.drain().collect();
+        pending.sort_by_key(|x| x.1.sent_at);
+
+        let mut lost: Vec<u32> = self.owners.iter().filter(|x| *x.1 == peer).map(|x| *x.0).collect();
+        lost.sort_unstable();
+        for fsm_id in lost.iter() {
+            self.clear_owner(*fsm_id);
+        }
+
+        let mut moved = Vec::new();
+        let mut failed = Vec::new();
+        for ((fsm_id, code), item) in pending {
+            let code = MCodeType::try_from(code)?;
+            let to = match code {
+                MCodeType::REQUESTCHANNEL => self.select().ok(),
+                _ => None,
+            };
+            let Some(to) = to else {
+                failed.push((fsm_id, code));
+                continue
+            };
+            lost.retain(|x| *x != fsm_id);
+            self.set_owner(fsm_id, to);
+            self.peers[to].stats.requested += 1;
+            self.peers[to].stats.moved_in += 1;
+            moved.push((fsm_id, to));
+            // a failure here queues another PeerDown
+            Box::pin(self.send_to_peer(to, code, fsm_id, item.payload)).await?;
+        }
+
+        let now = self.clock.now();
+        for fsm_id in lost.iter() {
+            self.channels.end(*fsm_id, EndReason::TransportLost, now);
+        }
+        self.events.push_back(PoolEvent::PeerDown { peer, moved, failed, lost });
+        Ok(())
+    }
+
+    /// next packet from any MS or pool event, Ok(None) if nothing within timeout
+    pub async fn recv(&mut self, timeout: Duration) -> Result<Option<PoolEvent>> {
+        if let Some(ev) = self.events.pop_front() {
+            return Ok(Some(ev))
+        }
+
+        self.check_timeouts().await?;
+        if let Some(ev) = self.events.pop_front() {
+            return Ok(Some(ev))
+        }
+
+        let r = {
+            let recvs: Vec<_> = self.peers.iter_mut().enumerate()
+            .filter(|(_index, peer)| peer.alive)
+            .map(|(index, peer)| Box::pin(async move {
+                let r = peer.session.recv_packet().await.map(|x| (x.to_header(), x.payload().to_vec()));
+                (index, r)
+            }))
+            .collect();
+            if recvs.is_empty() {
+                bail!("no MS alive")
+            }
+
+            match timeout_at(self.clock.as_ref(), self.clock.now() + timeout, select_all(recvs)).await {
+                Some((r, _n, _rest)) => r,
+                None => return Ok(None),
+            }
+        };
+
+        match r {
+            (peer, Ok((header, payload))) if header.code == MCodeType::REGISTER.code() => {
+                self.peers[peer].last_seen = self.clock.now();
+                self.on_reregister(peer, &payload).await.map(Some)
+            },
+            (peer, Ok((header, payload))) => {
+                self.channels.on_packet(header.fsm_id, header.code, &payload);
+                let me = &mut self.peers[peer];
+                me.last_seen = self.clock.now();
+                if let Some(request) = MCodeType::try_from(header.code).ok().and_then(|x| x.request()) {
+                    me.pending.remove(&(header.fsm_id, request.code()));
+                }
+                if header.code == MCodeType::REQUESTCHANNEL_ACK.code() {
+                    let result = RequestChannelAckRef::parse_from(&payload).map(|x| x.part1().result()).unwrap_or(u8::MAX);
+                    if result == 0 {
+                        me.stats.accepted += 1;
+                    } else {
+                        me.stats.rejected += 1;
+                        if self.owner(header.fsm_id) == Some(peer) {
+                            self.end(header.fsm_id, EndReason::MsError(result));
+                        }
+                    }
+                }
+                Ok(Some(PoolEvent::Packet { peer, header, payload }))
+            },
+            (peer, Err(e)) => {
+                warn!("recv from ms [{peer}] failed [{e:#}]");
+                self.peer_down(peer).await?;
+                Ok(self.events.pop_front())
+            },
+        }
+    }
+
+    /// a restarted MS forgot its channels and pending requests
+    async fn on_reregister(&mut self, peer: usize, payload: &[u8]) -> Result<PoolEvent> {
+        let diff = self.peers[peer].session.reregister(payload).await?;
+        if let Some(info) = self.peers[peer].session.register_info() {
+            self.channels.on_register(&self.peers[peer].session.ms_path().display().to_string(), info);
+        }
+        self.peers[peer].pending.clear();
+        self.peers[peer].alive = true;
+
+        let mut lost: Vec<u32> = self.owners.iter().filter(|x| *x.1 == peer).map(|x| *x.0).collect();
+        lost.sort_unstable();
+        for fsm_id in lost.iter() {
+            self.end(*fsm_id, EndReason::MsRestarted);
+        }
+        warn!("ms [{peer}] registered again, [{diff}], lost channels {lost:?}");
+        Ok(PoolEvent::Reregistered { peer, diff, lost })
+    }
+
+    /// MS silent since a request older than dead_after is dead,
+    /// channels past life_seconds expire
+    async fn check_timeouts(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        for fsm_id in self.channels.expire(now) {
+            self.clear_owner(fsm_id);
+        }
+
+        let dead: Vec<usize> = self.peers.iter().enumerate()
+        .filter(|(_index, peer)| peer.alive)
+        .filter(|(_index, peer)| peer.pending.values().any(|x| {
+            now.duration_since(x.sent_at) >= self.dead_after && peer.last_seen <= x.sent_at
+        }))
+        .map(|(index, _peer)| index)
+        .collect();
+
+        for peer in dead {
+            self.peer_down(peer).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::net::UnixDatagram;
+
+    use crate::{
+        vn_channels::EndReason,
+        vn_ms_sim::{MsSim, MsSimConfig},
+        vn_proto::{MCodeType, PortRange, RequestChannel},
+        vn_session::{cn_socket_path, ms_socket_path},
+    };
+
+    use super::{MsPool, PoolEvent, SelectPolicy};
+
+    #[tokio::test]
+    async fn test_pool_routing_and_failover() {
+        let root = std::env::temp_dir().join(format!("rcn_pool_{}", std::process::id()));
+        let dirs = [root.join("ms0"), root.join("ms1")];
+        let mut tasks = Vec::new();
+        for dir in dirs.iter() {
+            std::fs::create_dir_all(dir).unwrap();
+            let mut sim = MsSim::bind(dir, MsSimConfig::default()).await.unwrap();
+            tasks.push(tokio::spawn(async move { sim.run().await }));
+        }
+
+        let paths: Vec<_> = dirs.iter().map(|x| ms_socket_path(x)).collect();
+        let mut pool = MsPool::bind(&paths, 5).await.unwrap();
+        pool.register_all(Duration::from_secs(1)).await.unwrap();
+
+        let req = RequestChannel { media_type: 1, webrtc: vec!["".into()], ..Default::default() };
+        let base = 5000000;
+        assert_eq!(pool.request_channel(base + 1, &req).await.unwrap(), 0);
+        assert_eq!(pool.request_channel(base + 2, &req).await.unwrap(), 1);
+        for _ in 0..2 {
+            let ev = pool.recv(Duration::from_secs(1)).await.unwrap().unwrap();
+            assert!(matches!(ev, PoolEvent::Packet { header, .. } if header.code == MCodeType::REQUESTCHANNEL_ACK.code()));
+        }
+        assert_eq!(pool.owner(base + 1), Some(0));
+
+        // ms0 dies, its socket file stays so sending gets refused
+        tasks[0].abort();
+        let _r = (&mut tasks[0]).await;
+
+        // round robin picks ms0 again, the pending REQUESTCHANNEL moves to ms1
+        assert_eq!(pool.request_channel(base + 3, &req).await.unwrap(), 1);
+        let ev = pool.recv(Duration::from_secs(1)).await.unwrap().unwrap();
+        assert_eq!(ev, PoolEvent::PeerDown {
+            peer: 0,
+            moved: vec![(base + 3, 1)],
+            failed: vec![],
+            lost: vec![base + 1],
+        });
+        assert!(!pool.is_alive(0));
+        assert_eq!(pool.owner(base + 1), None);
+        assert_eq!(pool.channels().count(EndReason::TransportLost), 1);
+        assert!(pool.send_request(MCodeType::PLAY, base + 1, &[0; 16]).await.is_err());
+
+        let ev = pool.recv(Duration::from_secs(1)).await.unwrap().unwrap();
+        assert!(matches!(ev, PoolEvent::Packet { peer: 1, header, .. } if header.fsm_id == base + 3));
+
+        // ms1 dies too, nowhere to move its pending request
+        tasks[1].abort();
+        let _r = (&mut tasks[1]).await;
+        pool.send_request(MCodeType::PLAY, base + 2, &[0; 16]).await.unwrap();
+        let ev = pool.recv(Duration::from_secs(1)).await.unwrap().unwrap();
+        assert!(matches!(ev, PoolEvent::PeerDown { peer: 1, failed, .. } if failed == vec![(base + 2, MCodeType::PLAY)]));
+
+        let _r = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn test_pool_select_policy() {
+        let root = std::env::temp_dir().join(format!("rcn_pool_policy_{}", std::process::id()));
+        let dirs = [root.join("ms0"), root.join("ms1")];
+        let mut tasks = Vec::new();
+        for dir in dirs.iter() {
+            std::fs::create_dir_all(dir).unwrap();
+            let mut sim = MsSim::bind(dir, MsSimConfig::default()).await.unwrap();
+            tasks.push(tokio::spawn(async move { sim.run().await }));
+        }
+
+        let paths: Vec<_> = dirs.iter().map(|x| ms_socket_path(x)).collect();
+        let mut pool = MsPool::bind(&paths, 5).await.unwrap();
+        pool.register_all(Duration::from_secs(1)).await.unwrap();
+        assert!(pool.set_weights(&[1]).is_err());
+        assert!(pool.set_weights(&[0, 0]).is_err());
+
+        let req = RequestChannel { media_type: 1, webrtc: vec!["".into()], ..Default::default() };
+        let base = 5000000;
+
+        // weighted 3:1
+        pool.set_policy(SelectPolicy::Weighted);
+        pool.set_weights(&[3, 1]).unwrap();
+        let mut peers = Vec::new();
+        for n in 1..=8 {
+            peers.push(pool.request_channel(base + n, &req).await.unwrap());
+        }
+        assert_eq!(peers.iter().filter(|x| **x == 0).count(), 6, "{peers:?}");
+        for _ in 0..8 {
+            let ev = pool.recv(Duration::from_secs(1)).await.unwrap().unwrap();
+            assert!(matches!(ev, PoolEvent::Packet { .. }));
+        }
+        assert_eq!(pool.stats(0).accepted, 6);
+        assert_eq!(pool.stats(1).accepted, 2);
+
+        // least active fills up ms1 first
+        pool.set_policy(SelectPolicy::LeastActive);
+        for n in 9..=12 {
+            assert_eq!(pool.request_channel(base + n, &req).await.unwrap(), 1);
+        }
+        assert_eq!(pool.stats(1).active, 6);
+        let released: Vec<_> = (1..=8).filter(|x| pool.owner(base + x) == Some(0)).take(2).collect();
+        for n in released {
+            pool.release(base + n);
+        }
+        assert_eq!(pool.stats(0).active, 4);
+        assert_eq!(pool.channels().count(EndReason::Released), 2);
+        assert_eq!(pool.request_channel(base + 13, &req).await.unwrap(), 0);
+        assert_eq!(pool.stats(0).requested, 7);
+        assert_eq!(pool.summary().len(), 2);
+
+        for task in tasks {
+            task.abort();
+        }
+        let _r = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn test_pool_reregister() {
+        let dir = std::env::temp_dir().join(format!("rcn_pool_rereg_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut sim = MsSim::bind(&dir, MsSimConfig::default()).await.unwrap();
+        let task = tokio::spawn(async move { sim.run().await });
+
+        let mut pool = MsPool::bind(&[ms_socket_path(&dir)], 5).await.unwrap();
+        pool.register_all(Duration::from_secs(1)).await.unwrap();
+        let req = RequestChannel { media_type: 1, webrtc: vec!["".into()], ..Default::default() };
+        pool.request_channel(5000001, &req).await.unwrap();
+        let ev = pool.recv(Duration::from_secs(1)).await.unwrap().unwrap();
+        assert!(matches!(ev, PoolEvent::Packet { .. }));
+
+        let ms = ms_socket_path(&dir).display().to_string();
+        assert_eq!(pool.channels().report().ms[&ms].version, None);
+
+        // restarted MS announces itself with one codec less, version and ports
+        let mut config = MsSimConfig::default();
+        config.register.audio_codecs.truncate(1);
+        config.register.version = Some("V2.1.0".into());
+        config.register.port_range = Some(PortRange { min: 20000, max: 29999 });
+        let mut restarted = MsSim::with_socket(UnixDatagram::unbound().unwrap(), config);
+        restarted.announce(cn_socket_path(&dir, 5).unwrap(), 5).await.unwrap();
+
+        let ev = pool.recv(Duration::from_secs(1)).await.unwrap().unwrap();
+        let PoolEvent::Reregistered { peer, diff, lost } = ev else { panic!("{ev:?}") };
+        assert_eq!((peer, lost), (0, vec![5000001]));
+        assert_eq!(diff.removed_codecs, vec!["audio 8:8:PCMA/8000".to_string()]);
+        assert!(diff.added_codecs.is_empty());
+        assert_eq!(diff.to_string(), "version none -> V2.1.0, ports none -> 20000-29999, -audio 8:8:PCMA/8000");
+        let report = pool.channels().report();
+        assert_eq!(report.ms[&ms].port_range, Some(PortRange { min: 20000, max: 29999 }));
+        assert!(pool.channels().summary().contains(&format!("ms [{ms}] version [V2.1.0] ports [20000-29999]")));
+        assert_eq!(pool.owner(5000001), None);
+        assert_eq!(pool.channels().count(EndReason::MsRestarted), 1);
+
+        task.abort();
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,240 @@
+//! rtp ports handed out by the MS emulator.
+//!
+//! Ports come from configured ranges in slots of [`SLOT_PORTS`] (audio rtp,
+//! rtcp, video rtp, rtcp), a slot is reserved by a channel until released.
+//! With bind checks on, a slot whose ports some other process holds is
+//! skipped and counted as a conflict, as a real rtp engine would fail there.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    net::{Ipv4Addr, UdpSocket},
+    ops::RangeInclusive,
+    str::FromStr,
+};
+
+use anyhow::{Result, Context, bail};
+
+use crate::utils::rng::SimRng;
+
+/// ports per channel
+pub const SLOT_PORTS: u16 = 4;
+
+/// port ranges, e.g. `20000-20999,30000-30099`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortRanges(pub Vec<RangeInclusive<u16>>);
+
+impl Default for PortRanges {
+    fn default() -> Self {
+        Self(vec![20000..=29999])
+    }
+}
+
+impl FromStr for PortRanges {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut ranges = Vec::new();
+        for part in s.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            let (low, high) = part.split_once('-').unwrap_or((part, part));
+            let low: u16 = low.trim().parse().with_context(||format!("invalid port [{low}] of [{s}]"))?;
+            let high: u16 = high.trim().parse().with_context(||format!("invalid port [{high}] of [{s}]"))?;
+            if low > high {
+                bail!("empty port range [{part}]")
+            }
+            if let Some(r) = ranges.iter().find(|r: &&RangeInclusive<u16>| r.start() <= &high && &low <= r.end()) {
+                bail!("port range [{part}] overlaps [{}-{}]", r.start(), r.end())
+            }
+            ranges.push(low..=high);
+        }
+        if ranges.is_empty() {
+            bail!("no port range in [{s}]")
+        }
+        Ok(Self(ranges))
+    }
+}
+
+impl fmt::Display for PortRanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<_> = self.0.iter().map(|r| format!("{}-{}", r.start(), r.end())).collect();
+        f.write_str(&parts.join(","))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortPoolConfig {
+    pub ranges: PortRanges,
+    /// pick free slots at random instead of lowest first
+    pub random: bool,
+    /// bind each port of a slot before handing it out
+    pub check_bind: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PortStats {
+    /// slots of all ranges
+    pub capacity: usize,
+    pub in_use: usize,
+    pub peak: usize,
+    /// slots skipped since their ports were bound by others
+    pub conflicts: u64,
+    /// reservations failed for no free slot
+    pub exhausted: u64,
+}
+
+impl PortStats {
+    /// in_use of capacity, 0.0 ..= 1.0
+    pub fn occupancy(&self) -> f64 {
+        match self.capacity {
+            0 => 0.0,
+            n => self.in_use as f64 / n as f64,
+        }
+    }
+}
+
+impl fmt::Display for PortStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "ports in use [{}/{}] ({:.1}%) peak [{}] conflicts [{}] exhausted [{}]",
+            self.in_use, self.capacity, self.occupancy() * 100.0, self.peak, self.conflicts, self.exhausted,
+        )
+    }
+}
+
+pub struct PortPool {
+    config: PortPoolConfig,
+    /// first port of each free slot
+    free: BTreeSet<u16>,
+    /// fsm_id -> first port of its slot
+    reserved: HashMap<u32, u16>,
+    stats: PortStats,
+    rng: SimRng,
+}
+
+impl PortPool {
+    pub fn new(config: PortPoolConfig) -> Self {
+        let free: BTreeSet<u16> = config.ranges.0.iter()
+        .flat_map(|r| (*r.start() as u32..=*r.end() as u32)
+            .step_by(SLOT_PORTS as usize)
+            .filter(|port| port + SLOT_PORTS as u32 - 1 <= *r.end() as u32)
+            .map(|port| port as u16))
+        .collect();
+        let stats = PortStats { capacity: free.len(), ..Default::default() };
+        Self { config, free, reserved: HashMap::new(), stats, rng: SimRng::new(0) }
+    }
+
+    pub fn set_rng(&mut self, rng: SimRng) {
+        self.rng = rng;
+    }
+
+    /// first port of a slot for fsm_id, the one it has if already reserved
+    pub fn reserve(&mut self, fsm_id: u32) -> Result<u16> {
+        if let Some(port) = self.reserved.get(&fsm_id) {
+            return Ok(*port)
+        }
+
+        while !self.free.is_empty() {
+            let port = match self.config.random {
+                true => {
+                    let nth = self.rng.range(0, self.free.len() as u64) as usize;
+                    self.free.iter().nth(nth).copied()
+                },
+                false => self.free.first().copied(),
+            }.unwrap_or_default();
+            self.free.remove(&port);
+
+            if self.config.check_bind && !can_bind(port) {
+                // held by someone else, left out from now on
+                self.stats.conflicts += 1;
+                self.stats.capacity -= 1;
+                continue
+            }
+
+            self.reserved.insert(fsm_id, port);
+            self.stats.in_use += 1;
+            self.stats.peak = self.stats.peak.max(self.stats.in_use);
+            return Ok(port)
+        }
+
+        self.stats.exhausted += 1;
+        bail!("no free rtp ports in [{}], [{}] in use", self.config.ranges, self.stats.in_use)
+    }
+
+    /// slot of fsm_id back to free, its first port if it had one
+    pub fn release(&mut self, fsm_id: u32) -> Option<u16> {
+        let port = self.reserved.remove(&fsm_id)?;
+        self.free.insert(port);
+        self.stats.in_use -= 1;
+        Some(port)
+    }
+
+    pub fn port_of(&self, fsm_id: u32) -> Option<u16> {
+        self.reserved.get(&fsm_id).copied()
+    }
+
+    pub fn stats(&self) -> &PortStats {
+        &self.stats
+    }
+}
+
+/// every port of slot bindable as udp now
+fn can_bind(first: u16) -> bool {
+    (first..=first + (SLOT_PORTS - 1)).all(|port| UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).is_ok())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    use crate::utils::rng::SimRng;
+
+    use super::{PortPool, PortPoolConfig, PortRanges};
+
+    #[test]
+    fn test_port_pool() {
+        let ranges: PortRanges = "20000-20009, 30000-30003".parse().unwrap();
+        assert_eq!(ranges.to_string(), "20000-20009,30000-30003");
+        assert!("20000-20009,20008-20100".parse::<PortRanges>().is_err());
+        assert!("2-1".parse::<PortRanges>().is_err());
+
+        // 20008-20009 is too short for a slot
+        let mut pool = PortPool::new(PortPoolConfig { ranges, ..Default::default() });
+        assert_eq!(pool.stats().capacity, 3);
+        assert_eq!(pool.reserve(1).unwrap(), 20000);
+        assert_eq!(pool.reserve(1).unwrap(), 20000);
+        assert_eq!(pool.reserve(2).unwrap(), 20004);
+        assert_eq!(pool.reserve(3).unwrap(), 30000);
+        assert!(pool.reserve(4).is_err());
+
+        assert_eq!(pool.release(2), Some(20004));
+        assert_eq!(pool.release(2), None);
+        assert_eq!(pool.reserve(4).unwrap(), 20004);
+        let s = *pool.stats();
+        assert_eq!((s.in_use, s.peak, s.exhausted), (3, 3, 1));
+        assert_eq!(s.occupancy(), 1.0);
+
+        let mut pool = PortPool::new(PortPoolConfig { ranges: "40000-40399".parse().unwrap(), random: true, ..Default::default() });
+        pool.set_rng(SimRng::new(7));
+        let ports: Vec<_> = (0..100).map(|fsm_id| pool.reserve(fsm_id).unwrap()).collect();
+        let mut unique = ports.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), 100);
+        assert_ne!(ports, unique);
+    }
+
+    #[test]
+    fn test_port_pool_bind_conflict() {
+        // some port is held by us, its slot is skipped
+        let held = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let port = held.local_addr().unwrap().port();
+        let first = port - port % 4;
+        let ranges = PortRanges(vec![first..=first + 7]);
+        let mut pool = PortPool::new(PortPoolConfig { ranges, check_bind: true, ..Default::default() });
+        let got = pool.reserve(1);
+        assert!(pool.stats().conflicts >= 1);
+        if let Ok(got) = got {
+            assert_eq!(got, first + 4);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! which requests an unknown MS build answers, and how.
+//!
+//! After REGISTER one channel is requested, every probed code is sent on
+//! it with a minimal valid payload, one at a time, and the first packet
+//! back on that fsm_id (base fsm_id for HEARTBEAT) within the timeout is
+//! its answer. The channel is released at the end.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{
+    utils::datagram::Datagram,
+    vn_fields::packet_fields,
+    vn_proto::{CodeName, Direction, MCodeType, OpenRtpConnect, ResFromTag, MCODE_TABLE},
+    vn_scenario::{PlaySpec, RequestChannelSpec},
+    vn_session::CnSession,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "answer")]
+pub enum Answer {
+    /// the ACK of the request, result field if it has one
+    Ack { result: Option<String>, latency_ms: f64 },
+    /// some other code came back on the fsm_id
+    Other { code: String, latency_ms: f64 },
+    Silent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProbeRow {
+    pub code: String,
+    /// ACK expected if any
+    pub expects: Option<String>,
+    #[serde(flatten)]
+    pub answer: Answer,
+}
+
+impl ProbeRow {
+    /// ACK came back, codes without ACK are never
+    pub fn is_supported(&self) -> bool {
+        matches!(self.answer, Answer::Ack { .. })
+    }
+}
+
+/// requests CN may send, REGISTER_ACK and CNISUP left to the handshake
+/// and RELEASECHANNEL to the end
+pub fn default_codes() -> Vec<MCodeType> {
+    MCODE_TABLE.iter()
+    .filter(|x| x.direction.allows(Direction::CnToMs) && x.code.request().is_none())
+    .map(|x| x.code)
+    .filter(|x| !matches!(x, MCodeType::CNISUP | MCodeType::RELEASECHANNEL))
+    .collect()
+}
+
+/// smallest payload MS should parse, empty for most codes
+pub fn minimal_payload(code: MCodeType) -> Vec<u8> {
+    let mut payload = Vec::new();
+    match code {
+        MCodeType::REQUESTCHANNEL => { RequestChannelSpec { webrtc: vec!["".into()], ..Default::default() }.to_request().write_to(&mut payload); },
+        MCodeType::PLAY => { PlaySpec { files: vec!["file://cc/11000.wav".into()], ..Default::default() }.to_play().write_to(&mut payload); },
+        MCodeType::OPENRTPCONNECT => { OpenRtpConnect::default().write_to(&mut payload); },
+        MCodeType::RESFROMTAG => { ResFromTag { value: String::new() }.write_to(&mut payload); },
+        _ => {},
+    }
+    payload
+}
+
+/// probe codes in order on a registered session, REQUESTCHANNEL is sent
+/// first whether listed or not so the rest have a channel
+pub async fn probe<S: Datagram>(session: &mut CnSession<S>, codes: &[MCodeType], timeout: Duration) -> Result<Vec<ProbeRow>> {
+    let fsm_id = session.base_fsm_id() + 1;
+    let mut rows = Vec::with_capacity(codes.len() + 1);
+    let channel = probe_one(session, MCodeType::REQUESTCHANNEL, fsm_id, timeout).await?;
+    if codes.contains(&MCodeType::REQUESTCHANNEL) {
+        rows.push(channel);
+    }
+    for code in codes.iter().filter(|x| **x != MCodeType::REQUESTCHANNEL) {
+        let fsm_id = if *code == MCodeType::HEARTBEAT { session.base_fsm_id() } else { fsm_id };
+        rows.push(probe_one(session, *code, fsm_id, timeout).await?);
+    }
+    session.send_request(MCodeType::RELEASECHANNEL, fsm_id, &[]).await?;
+    Ok(rows)
+}
+
+async fn probe_one<S: Datagram>(session: &mut CnSession<S>, code: MCodeType, fsm_id: u32, timeout: Duration) -> Result<ProbeRow> {
+    let expects = if code == MCodeType::HEARTBEAT { Some(MCodeType::HEARTBEAT) } else { code.ack() };
+    let started = Instant::now();
+    session.send_request(code, fsm_id, &minimal_payload(code)).await?;
+
+    let answer = loop {
+        let Some(left) = timeout.checked_sub(started.elapsed()) else { break Answer::Silent };
+        let Ok(r) = tokio::time::timeout(left, session.recv_packet()).await else { break Answer::Silent };
+        let packet = r?;
+        // link heartbeats of MS and packets of other channels are not answers
+        if packet.fsm_id() != fsm_id || (packet.code() == MCodeType::HEARTBEAT.code() && code != MCodeType::HEARTBEAT) {
+            continue
+        }
+        let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+        if Some(packet.code()) == expects.map(|x| x.code()) {
+            let result = packet_fields(&packet).ok().and_then(|x| x.get("result").map(|x| x.to_string()));
+            break Answer::Ack { result, latency_ms }
+        }
+        break Answer::Other { code: CodeName(packet.code()).to_string(), latency_ms }
+    };
+    Ok(ProbeRow { code: format!("{code:?}"), expects: expects.map(|x| format!("{x:?}")), answer })
+}
+
+/// one row per code with a header row, then how many of the codes
+/// with an ACK got it
+pub fn render_matrix(rows: &[ProbeRow]) -> String {
+    let mut table = vec![["code", "answer", "result", "latency"].map(String::from).to_vec()];
+    for row in rows.iter() {
+        let (answer, result, latency) = match &row.answer {
+            Answer::Ack { result, latency_ms } => (row.expects.clone().unwrap_or_default(), result.clone().unwrap_or_else(|| "-".into()), format!("{latency_ms:.3}ms")),
+            Answer::Other { code, latency_ms } => (format!("other {code}"), "-".into(), format!("{latency_ms:.3}ms")),
+            Answer::Silent => ("silent".into(), "-".into(), "-".into()),
+        };
+        table.push(vec![row.code.clone(), answer, result, latency]);
+    }
+
+    let widths: Vec<usize> = (0..4).map(|i| table.iter().map(|x| x[i].len()).max().unwrap_or(0)).collect();
+    let mut out = String::new();
+    for row in table.iter() {
+        let cells: Vec<String> = row.iter().zip(widths.iter()).map(|(cell, w)| format!("{cell:<w$}")).collect();
+        out.push_str(cells.join("  ").trim_end());
+        out.push('\n');
+    }
+    let supported = rows.iter().filter(|x| x.is_supported()).count();
+    let expecting = rows.iter().filter(|x| x.expects.is_some()).count();
+    out.push_str(&format!("acked [{supported}] of [{expecting}] expecting an ACK\n"));
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{vn_ms_sim::MsSimConfig, vn_proto::MCodeType, vn_testkit::MsStub};
+
+    use super::{default_codes, probe, render_matrix, Answer};
+
+    #[tokio::test]
+    async fn test_probe() {
+        let (_ms, mut session) = MsStub::start(MsSimConfig::default(), 5);
+        session.handshake().await.unwrap();
+        session.accept_register().await.unwrap();
+
+        let codes = [MCodeType::REQUESTCHANNEL, MCodeType::HEARTBEAT, MCodeType::PLAY, MCodeType::BRIDGE, MCodeType::CANCEL];
+        let rows = probe(&mut session, &codes, Duration::from_millis(200)).await.unwrap();
+        let names: Vec<&str> = rows.iter().map(|x| x.code.as_str()).collect();
+        assert_eq!(names, ["REQUESTCHANNEL", "HEARTBEAT", "PLAY", "BRIDGE", "CANCEL"]);
+        assert!(matches!(&rows[0].answer, Answer::Ack { result: Some(x), .. } if x == "0"), "{:?}", rows[0]);
+        assert!(matches!(&rows[1].answer, Answer::Ack { result: None, .. }), "{:?}", rows[1]);
+        assert!(matches!(&rows[2].answer, Answer::Ack { .. }), "{:?}", rows[2]);
+        assert!(matches!(&rows[3].answer, Answer::Ack { .. }), "{:?}", rows[3]);
+        assert_eq!(rows[4].answer, Answer::Silent);
+
+        let matrix = render_matrix(&rows);
+        assert!(matrix.lines().nth(5).unwrap().starts_with("CANCEL          silent"), "{matrix}");
+        assert!(matrix.ends_with("acked [4] of [4] expecting an ACK\n"), "{matrix}");
+
+        let defaults = default_codes();
+        assert!(defaults.contains(&MCodeType::DTMFRCV) && defaults.contains(&MCodeType::HEARTBEAT));
+        assert!(!defaults.contains(&MCodeType::REGISTER_ACK) && !defaults.contains(&MCodeType::RELEASECHANNEL));
+    }
+}
@@ -1,10 +1,16 @@
-use std::{fmt, net::{Ipv4Addr, IpAddr}, marker::PhantomData};
+use core::{fmt, net::{Ipv4Addr, IpAddr}, marker::PhantomData};
+
+use alloc::{borrow::Cow, string::String, vec::Vec};
 
 use anyhow::{Result, bail, Context};
 use bytes::{Buf, BufMut};
 use num_enum::TryFromPrimitive;
 
-use crate::utils::common::{EnumHexU16, EnumNum};
+use crate::{
+    utils::common::{EnumHexU16, EnumNum, IsEnum},
+    vn_charset::{decode_str, StrDebug},
+    vn_redact::{RedactField, RedactStr, RedactUrl},
+};
 
 pub const HEADER_LENGTH: usize = 12;
 
@@ -12,6 +18,7 @@ pub const HEADER_LENGTH: usize = 12;
 #[repr(u16)]
 #[derive(Debug, Clone, Copy, Eq, PartialEq,)]
 #[derive(TryFromPrimitive)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
 pub enum MCodeType {
     HEARTBEAT                = 0xffff,
     REGISTER                 = 0xff01,
@@ -74,11 +81,196 @@ impl MCodeType {
     pub fn code(&self) -> u16 {
         *self as u16
     }
+
+    pub fn info(&self) -> &'static MCodeInfo {
+        // every variant has an entry, checked by test
+        MCODE_TABLE.iter()
+        .find(|x| x.code == *self)
+        .unwrap_or(&MCODE_TABLE[0])
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.info().direction
+    }
+
+    pub fn description(&self) -> &'static str {
+        self.info().description
+    }
+
+    /// by variant name (case insensitive) or number like "0x3"
+    pub fn from_name(name: &str) -> Option<MCodeType> {
+        let name = name.trim();
+        if let Some(hex) = name.strip_prefix("0x").or_else(|| name.strip_prefix("0X")) {
+            let code = u16::from_str_radix(hex, 16).ok()?;
+            return MCodeType::try_from(code).ok()
+        }
+        MCODE_TABLE.iter()
+        .find(|x| alloc::format!("{:?}", x.code).eq_ignore_ascii_case(name))
+        .map(|x| x.code)
+    }
+
+    /// ACK answering this request
+    pub fn ack(&self) -> Option<MCodeType> {
+        ACK_PAIRS.iter().find(|x| x.0 == *self).map(|x| x.1)
+    }
+
+    /// request this ACK answers
+    pub fn request(&self) -> Option<MCodeType> {
+        ACK_PAIRS.iter().find(|x| x.1 == *self).map(|x| x.0)
+    }
+
+    /// how [`Message::from_packet`] takes the payload, no wildcard so a
+    /// new code doesn't build until it is decided
+    pub fn payload_support(&self) -> PayloadSupport {
+        use PayloadSupport::*;
+        match self {
+            Self::REGISTER | Self::REQUESTCHANNEL | Self::REQUESTCHANNEL_ACK | Self::OPENRTPCONNECT
+            | Self::OPENRTPCONNECT_ACK | Self::RESFROMTAG | Self::PLAY | Self::PLAY_ACK | Self::CANCEL
+            | Self::CLOSERTPCONNECT | Self::CLOSERTPCONNECT_ACK => Parsed,
+            Self::HEARTBEAT | Self::RELEASECHANNEL => HeaderOnly,
+            Self::REGISTER_ACK | Self::CNISUP | Self::CNISUP_ACK | Self::COLLECTDIGIT | Self::COLLECTDIGIT_ACK
+            | Self::RECORD | Self::RECORD_ACK | Self::SENDFAX | Self::SENDFAX_ACK | Self::RECEIVEFAX
+            | Self::RECEIVEFAX_ACK | Self::SETRTPCONNECT | Self::SETRTPCONNECT_ACK | Self::FAXEVENT
+            | Self::AUDIODETECT | Self::AUDIODETECT_ACK | Self::DTMFRCV | Self::DTMFRCV_ACK
+            | Self::GET3PARTYPORT | Self::GET3PARTYPORT_ACK | Self::BRIDGE | Self::BRIDGE_ACK
+            | Self::HTTPDOWNLOAD | Self::THEARTBEAT | Self::UNBRIDGE | Self::RESETLIFETIMER | Self::INFODTMF
+            | Self::NBUPINFO | Self::MODIFYCHANNEL | Self::MODIFYCHANNEL_ACK | Self::ADDVIDEO_ACK
+            | Self::ERASEVIDEO_ACK | Self::OPENRTMPCONNECT | Self::OPENRTMPCONNECT_ACK | Self::CLOSERTMPCONNECT
+            | Self::CLOSERTMPCONNECT_ACK | Self::FACERECOG | Self::FACERECOG_ACK | Self::AGORASUBSCRIBE
+            | Self::AGORAUNSUBSCRIBE | Self::IVRMSGNAMELISTLENGTH => Unsupported,
+        }
+    }
+}
+
+/// decoding of a code's payload
+#[derive(Debug, Clone, Copy, Eq, PartialEq,)]
+pub enum PayloadSupport {
+    /// typed variant of [`Message`] with its fields
+    Parsed,
+    /// typed variant of [`Message`], payload not looked at
+    HeaderOnly,
+    /// layout not known yet, [`Message::Unknown`] keeps the bytes
+    Unsupported,
+}
+
+/// (request, ack)
+static ACK_PAIRS: &[(MCodeType, MCodeType)] = &[
+    (MCodeType::REGISTER, MCodeType::REGISTER_ACK),
+    (MCodeType::CNISUP, MCodeType::CNISUP_ACK),
+    (MCodeType::REQUESTCHANNEL, MCodeType::REQUESTCHANNEL_ACK),
+    (MCodeType::PLAY, MCodeType::PLAY_ACK),
+    (MCodeType::COLLECTDIGIT, MCodeType::COLLECTDIGIT_ACK),
+    (MCodeType::RECORD, MCodeType::RECORD_ACK),
+    (MCodeType::SENDFAX, MCodeType::SENDFAX_ACK),
+    (MCodeType::RECEIVEFAX, MCodeType::RECEIVEFAX_ACK),
+    (MCodeType::OPENRTPCONNECT, MCodeType::OPENRTPCONNECT_ACK),
+    (MCodeType::SETRTPCONNECT, MCodeType::SETRTPCONNECT_ACK),
+    (MCodeType::CLOSERTPCONNECT, MCodeType::CLOSERTPCONNECT_ACK),
+    (MCodeType::AUDIODETECT, MCodeType::AUDIODETECT_ACK),
+    (MCodeType::DTMFRCV, MCodeType::DTMFRCV_ACK),
+    (MCodeType::GET3PARTYPORT, MCodeType::GET3PARTYPORT_ACK),
+    (MCodeType::BRIDGE, MCodeType::BRIDGE_ACK),
+    (MCodeType::MODIFYCHANNEL, MCodeType::MODIFYCHANNEL_ACK),
+    (MCodeType::OPENRTMPCONNECT, MCodeType::OPENRTMPCONNECT_ACK),
+    (MCodeType::CLOSERTMPCONNECT, MCodeType::CLOSERTMPCONNECT_ACK),
+    (MCodeType::FACERECOG, MCodeType::FACERECOG_ACK),
+];
+
+/// which side sends the packet
+#[derive(Debug, Clone, Copy, Eq, PartialEq,)]
+pub enum Direction {
+    CnToMs,
+    MsToCn,
+    Both,
+}
+
+impl Direction {
+    /// whether a packet sent by `from` is expected
+    pub fn allows(&self, from: Direction) -> bool {
+        *self == Direction::Both || *self == from || from == Direction::Both
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::CnToMs => f.pad("CN->MS"),
+            Direction::MsToCn => f.pad("MS->CN"),
+            Direction::Both => f.pad("both"),
+        }
+    }
 }
 
+#[derive(Debug)]
+pub struct MCodeInfo {
+    pub code: MCodeType,
+    pub direction: Direction,
+    pub description: &'static str,
+}
+
+/// all known codes in declaration order
+pub static MCODE_TABLE: &[MCodeInfo] = &[
+    MCodeInfo { code: MCodeType::HEARTBEAT, direction: Direction::Both, description: "link heartbeat" },
+    MCodeInfo { code: MCodeType::REGISTER, direction: Direction::MsToCn, description: "MS registers its ip and media capabilities" },
+    MCodeInfo { code: MCodeType::REGISTER_ACK, direction: Direction::CnToMs, description: "answer to REGISTER" },
+    MCodeInfo { code: MCodeType::CNISUP, direction: Direction::CnToMs, description: "CN announces it is up" },
+    MCodeInfo { code: MCodeType::CNISUP_ACK, direction: Direction::MsToCn, description: "answer to CNISUP" },
+    MCodeInfo { code: MCodeType::REQUESTCHANNEL, direction: Direction::CnToMs, description: "allocate a media channel" },
+    MCodeInfo { code: MCodeType::REQUESTCHANNEL_ACK, direction: Direction::MsToCn, description: "channel result with local rtp ports" },
+    MCodeInfo { code: MCodeType::PLAY, direction: Direction::CnToMs, description: "play files on channel" },
+    MCodeInfo { code: MCodeType::PLAY_ACK, direction: Direction::MsToCn, description: "play finished" },
+    MCodeInfo { code: MCodeType::COLLECTDIGIT, direction: Direction::CnToMs, description: "collect dtmf digits" },
+    MCodeInfo { code: MCodeType::COLLECTDIGIT_ACK, direction: Direction::MsToCn, description: "collected digits" },
+    MCodeInfo { code: MCodeType::RECORD, direction: Direction::CnToMs, description: "record channel audio" },
+    MCodeInfo { code: MCodeType::RECORD_ACK, direction: Direction::MsToCn, description: "record finished" },
+    MCodeInfo { code: MCodeType::SENDFAX, direction: Direction::CnToMs, description: "send fax" },
+    MCodeInfo { code: MCodeType::SENDFAX_ACK, direction: Direction::MsToCn, description: "send fax finished" },
+    MCodeInfo { code: MCodeType::RECEIVEFAX, direction: Direction::CnToMs, description: "receive fax" },
+    MCodeInfo { code: MCodeType::RECEIVEFAX_ACK, direction: Direction::MsToCn, description: "receive fax finished" },
+    MCodeInfo { code: MCodeType::OPENRTPCONNECT, direction: Direction::CnToMs, description: "open rtp to remote peer" },
+    MCodeInfo { code: MCodeType::OPENRTPCONNECT_ACK, direction: Direction::MsToCn, description: "answer to OPENRTPCONNECT" },
+    MCodeInfo { code: MCodeType::SETRTPCONNECT, direction: Direction::CnToMs, description: "update rtp of remote peer" },
+    MCodeInfo { code: MCodeType::SETRTPCONNECT_ACK, direction: Direction::MsToCn, description: "answer to SETRTPCONNECT" },
+    MCodeInfo { code: MCodeType::CLOSERTPCONNECT, direction: Direction::CnToMs, description: "close rtp to remote peer" },
+    MCodeInfo { code: MCodeType::CLOSERTPCONNECT_ACK, direction: Direction::MsToCn, description: "answer to CLOSERTPCONNECT" },
+    MCodeInfo { code: MCodeType::CANCEL, direction: Direction::CnToMs, description: "cancel running operation" },
+    MCodeInfo { code: MCodeType::RELEASECHANNEL, direction: Direction::CnToMs, description: "release channel" },
+    MCodeInfo { code: MCodeType::FAXEVENT, direction: Direction::MsToCn, description: "fax progress event" },
+    MCodeInfo { code: MCodeType::AUDIODETECT, direction: Direction::CnToMs, description: "start audio detection" },
+    MCodeInfo { code: MCodeType::AUDIODETECT_ACK, direction: Direction::MsToCn, description: "audio detection result" },
+    MCodeInfo { code: MCodeType::DTMFRCV, direction: Direction::Both, description: "dtmf received" },
+    MCodeInfo { code: MCodeType::DTMFRCV_ACK, direction: Direction::Both, description: "answer to DTMFRCV" },
+    MCodeInfo { code: MCodeType::GET3PARTYPORT, direction: Direction::CnToMs, description: "get port for third party" },
+    MCodeInfo { code: MCodeType::GET3PARTYPORT_ACK, direction: Direction::MsToCn, description: "answer to GET3PARTYPORT" },
+    MCodeInfo { code: MCodeType::BRIDGE, direction: Direction::CnToMs, description: "bridge two channels" },
+    MCodeInfo { code: MCodeType::BRIDGE_ACK, direction: Direction::MsToCn, description: "answer to BRIDGE" },
+    MCodeInfo { code: MCodeType::HTTPDOWNLOAD, direction: Direction::CnToMs, description: "download media file by http" },
+    MCodeInfo { code: MCodeType::THEARTBEAT, direction: Direction::Both, description: "channel heartbeat" },
+    MCodeInfo { code: MCodeType::UNBRIDGE, direction: Direction::CnToMs, description: "unbridge channels" },
+    MCodeInfo { code: MCodeType::RESETLIFETIMER, direction: Direction::CnToMs, description: "reset channel life timer" },
+    MCodeInfo { code: MCodeType::INFODTMF, direction: Direction::Both, description: "dtmf by sip info" },
+    MCodeInfo { code: MCodeType::NBUPINFO, direction: Direction::Both, description: "nbup information" },
+    MCodeInfo { code: MCodeType::MODIFYCHANNEL, direction: Direction::CnToMs, description: "modify channel media" },
+    MCodeInfo { code: MCodeType::MODIFYCHANNEL_ACK, direction: Direction::MsToCn, description: "answer to MODIFYCHANNEL" },
+    MCodeInfo { code: MCodeType::ADDVIDEO_ACK, direction: Direction::MsToCn, description: "video added to channel" },
+    MCodeInfo { code: MCodeType::ERASEVIDEO_ACK, direction: Direction::MsToCn, description: "video removed from channel" },
+    MCodeInfo { code: MCodeType::OPENRTMPCONNECT, direction: Direction::CnToMs, description: "open rtmp stream" },
+    MCodeInfo { code: MCodeType::OPENRTMPCONNECT_ACK, direction: Direction::MsToCn, description: "answer to OPENRTMPCONNECT" },
+    MCodeInfo { code: MCodeType::CLOSERTMPCONNECT, direction: Direction::CnToMs, description: "close rtmp stream" },
+    MCodeInfo { code: MCodeType::CLOSERTMPCONNECT_ACK, direction: Direction::MsToCn, description: "answer to CLOSERTMPCONNECT" },
+    MCodeInfo { code: MCodeType::FACERECOG, direction: Direction::CnToMs, description: "start face recognition" },
+    MCodeInfo { code: MCodeType::FACERECOG_ACK, direction: Direction::MsToCn, description: "face recognition result" },
+    MCodeInfo { code: MCodeType::RESFROMTAG, direction: Direction::Both, description: "result carried in tags" },
+    MCodeInfo { code: MCodeType::AGORASUBSCRIBE, direction: Direction::CnToMs, description: "subscribe agora stream" },
+    MCodeInfo { code: MCodeType::AGORAUNSUBSCRIBE, direction: Direction::CnToMs, description: "unsubscribe agora stream" },
+    MCodeInfo { code: MCodeType::IVRMSGNAMELISTLENGTH, direction: Direction::Both, description: "ivr message name list length" },
+];
+
 
 pub type MCode = EnumHexU16<MCodeType>;
 
+impl IsEnum for MCodeType {}
+
 
 #[allow(non_camel_case_types)]
 #[repr(u8)]
@@ -88,8 +280,17 @@ pub enum TagType {
     MEDIAINFO               = 0x01,
     FILENAME                = 0x02,
     RTPINFO                 = 0x06,
+    CAPABILITY              = 0x41,
+    /// MS version string, after MEDIAINFO in REGISTER
+    VERSION                 = 0x42,
+    /// max channels of MS, u32
+    CAPACITY                = 0x43,
+    /// RTP ports of MS, see PortRange
+    PORTRANGE               = 0x44,
 }
 
+impl IsEnum for TagType {}
+
 impl TagType {
     pub fn code(&self) -> u8 {
         *self as u8
@@ -97,25 +298,76 @@ impl TagType {
 }
 
 
+/// what to do when Header.length disagrees with datagram size
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum LengthPolicy {
+    /// any mismatch is an error
+    Strict,
+    /// payload ends at declared length or datagram end whichever comes first,
+    /// trailing bytes are left as they are
+    #[default]
+    Truncate,
+    /// payload runs to datagram end
+    Extend,
+}
+
+/// declared packet size (length + 2) and datagram size when they differ
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct LengthMismatch {
+    pub declared: usize,
+    pub actual: usize,
+}
+
 pub struct PacketRef<'a> {
     data: &'a [u8],
+    payload_end: usize,
+    mismatch: Option<LengthMismatch>,
 }
 
 impl<'a> PacketRef<'a> {
+    /// declared length beyond datagram is an error,
+    /// trailing bytes (e.g. cn path) are kept as with [`LengthPolicy::Truncate`]
     pub fn parse_from(data: &'a [u8]) -> Result<Self> {
+        let me = Self::parse_with(data, LengthPolicy::Truncate)?;
+        if let Some(m) = me.mismatch {
+            if m.declared > m.actual {
+                bail!("too large field.length, expect [{}] but [{}]", m.actual - 2, m.declared - 2)
+            }
+        }
+        Ok(me)
+    }
+
+    /// every packet of a datagram, see [`split_packets`]
+    pub fn parse_all(data: &'a [u8]) -> Result<Vec<Self>> {
+        split_packets(data).map(Self::parse_from).collect()
+    }
+
+    pub fn parse_with(data: &'a [u8], policy: LengthPolicy) -> Result<Self> {
         if data.len() < HEADER_LENGTH {
             bail!("data too short, [{}]", data.len())
         }
 
-        let mut buf = data;
-
-        let length = buf.get_u16() as usize;
- 
-        if length > buf.len() {
-            bail!("too large field.length, expect [{}] but [{}]", buf.len(), length)
+        let declared = (&data[0..]).get_u16() as usize + 2;
+        if declared < HEADER_LENGTH {
+            bail!("too small field.length [{}]", declared - 2)
         }
 
-        Ok(Self{data})
+        let actual = data.len();
+        let mismatch = (declared != actual).then_some(LengthMismatch { declared, actual });
+        let payload_end = match (policy, mismatch) {
+            (_, None) => declared,
+            (LengthPolicy::Strict, Some(_)) => bail!("field.length [{}] mismatch datagram [{actual}]", declared - 2),
+            (LengthPolicy::Truncate, Some(_)) => declared.min(actual),
+            (LengthPolicy::Extend, Some(_)) => actual,
+        };
+
+        Ok(Self{data, payload_end, mismatch})
+    }
+
+    /// set when Header.length disagrees with datagram size
+    pub fn length_mismatch(&self) -> Option<LengthMismatch> {
+        self.mismatch
     }
 
     pub fn length(&self) -> usize {
@@ -139,17 +391,22 @@ impl<'a> PacketRef<'a> {
     }
 
     pub fn payload(&self) -> &'a [u8] {
-        let len = self.length();
-        &self.data[HEADER_LENGTH..len+2]
+        &self.data[HEADER_LENGTH..self.payload_end]
+    }
+
+    /// whole datagram parsed, trailing bytes included
+    pub fn raw(&self) -> &'a [u8] {
+        self.data
     }
 
+    /// bytes after the declared packet end, empty if none
     pub fn cn_path_data(&self) -> &'a [u8] {
-        let len = self.length();
-        &self.data[len..]
+        let end = (self.length() + 2).min(self.data.len());
+        &self.data[end..]
     }
 
     pub fn cn_path_utf8(&self) -> Result<&'a str> {
-        let s = std::str::from_utf8(self.cn_path_data())?;
+        let s = core::str::from_utf8(self.cn_path_data()).map_err(anyhow::Error::msg)?;
         Ok(s)
     }
 
@@ -164,6 +421,53 @@ impl<'a> PacketRef<'a> {
     }
 }
 
+/// packets some MS builds send back to back in one datagram, split by their
+/// length fields. Bytes after a packet that aren't a whole packet of a known
+/// code (e.g. cn path, or a bad length) stay with it, so a datagram of one
+/// packet is yielded as it is and left to PacketRef to judge.
+pub fn split_packets(data: &[u8]) -> SplitPackets<'_> {
+    SplitPackets { data }
+}
+
+pub struct SplitPackets<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SplitPackets<'a> {
+    /// bytes not yielded yet
+    pub fn rest(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+impl<'a> Iterator for SplitPackets<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None
+        }
+        let end = match whole_packet_len(self.data) {
+            Some(len) if whole_packet_len(&self.data[len..]).is_some() => len,
+            _ => self.data.len(),
+        };
+        let (packet, rest) = self.data.split_at(end);
+        self.data = rest;
+        Some(packet)
+    }
+}
+
+/// declared size of the packet data starts with, if all of it is there
+/// and its code is known
+fn whole_packet_len(data: &[u8]) -> Option<usize> {
+    if data.len() < HEADER_LENGTH {
+        return None
+    }
+    let declared = (&data[0..]).get_u16() as usize + 2;
+    let known = MCodeType::try_from((&data[2..]).get_u16()).is_ok();
+    (known && (HEADER_LENGTH..=data.len()).contains(&declared)).then_some(declared)
+}
+
 impl<'a> fmt::Debug for PacketRef<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut builder = f.debug_struct("Packet");
@@ -181,12 +485,16 @@ impl<'a> fmt::Debug for PacketRef<'a> {
         .field("fsm_id", &self.fsm_id())
         .field("key", &self.key())
         .field("sn", &self.sn())
-        .field("payload", &self.payload().len())
-        .finish()
+        .field("payload", &self.payload().len());
+
+        if let Some(m) = self.mismatch {
+            builder.field("length_mismatch", &m);
+        }
+        builder.finish()
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Clone, PartialEq, Eq)]
 pub struct Header {
     // pub length: usize,  // 2 bytes
     pub code: u16,      // 2 bytes
@@ -226,10 +534,13 @@ impl fmt::Debug for Header {
 }
 
 
-#[derive(Debug)]
 pub struct RegisterRef<'a> {
     pub ip: Ipv4Addr,      // 2 bytes
     pub media_info: MediaInfoRef<'a>,
+    /// bytes inside MEDIAINFO tag not consumed by MediaInfoRef
+    pub media_info_remains: &'a [u8],
+    /// tags following MEDIAINFO tag
+    pub tags: TagIter<'a>,
 }
 
 impl<'a> RegisterRef<'a> {
@@ -245,13 +556,152 @@ impl<'a> RegisterRef<'a> {
             bail!("Register expect MEDIAINFO tag but [{:?}]", tag.tag_type() )
         }
 
-        let (_n, media_info) = MediaInfoRef::parse_from(tag.payload())?;
+        let (n, media_info) = MediaInfoRef::parse_from(tag.payload())?;
+
+        let tags_offset = 4 + TagRef::MIN_LEN + tag.payload().len();
 
         Ok(Self {
             ip: Ipv4Addr::new(data[0], data[1], data[2], data[3]),
             media_info,
+            media_info_remains: &tag.payload()[n..],
+            tags: TagIter(&data[tags_offset..]),
         })
     }
+
+    pub fn tags(&self) -> TagIter<'a> {
+        self.tags.clone()
+    }
+
+    /// flags of CAPABILITY tag, 0 if MS not send it
+    pub fn capabilities(&self) -> u32 {
+        self.tags().capability().map(|x| x.flags).unwrap_or(0)
+    }
+
+    /// VERSION tag, e.g. "V2.1.0"
+    pub fn version(&self) -> Option<Cow<'a, str>> {
+        self.tags().find_type(TagType::VERSION).and_then(|x| tag_str(x.payload()).decode())
+    }
+
+    /// CAPACITY tag, max channels of MS
+    pub fn capacity(&self) -> Option<u32> {
+        let tag = self.tags().find_type(TagType::CAPACITY)?;
+        (tag.payload().len() >= 4).then(|| (&tag.payload()[..4]).get_u32())
+    }
+
+    /// PORTRANGE tag
+    pub fn port_range(&self) -> Option<PortRange> {
+        self.tags().find_type(TagType::PORTRANGE).and_then(|x| PortRange::parse_from(x.payload()).ok())
+    }
+}
+
+/// string of a tag, null terminated or up to the end
+fn tag_str(payload: &[u8]) -> StrRef<'_> {
+    StrRef::from_str_null(payload).map(|x| x.1).unwrap_or(StrRef(payload))
+}
+
+impl<'a> fmt::Debug for RegisterRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut builder = f.debug_struct("Register");
+
+        builder
+        .field("ip", &self.ip)
+        .field("media_info", &self.media_info);
+
+        if !self.media_info_remains.is_empty() {
+            builder.field("media_info_remains", &format_args!("{:02x?}", self.media_info_remains));
+        }
+
+        builder
+        .field("tags", &TagIterDebug(self.tags()))
+        .finish()
+    }
+}
+
+/// owned Register payload for building packets, as sent by MS
+#[derive(Debug, Clone)]
+pub struct Register {
+    pub ip: Ipv4Addr,
+    pub support_t38: bool,
+    pub audio_codecs: Vec<CodecDesc>,
+    pub video_codecs: Vec<CodecDesc>,
+    pub fax_codecs: Vec<CodecDesc>,
+    /// CAPABILITY tag after MEDIAINFO if Some
+    pub capability: Option<Capability>,
+    /// VERSION, CAPACITY and PORTRANGE tags after it if Some
+    pub version: Option<String>,
+    pub capacity: Option<u32>,
+    pub port_range: Option<PortRange>,
+}
+
+impl Default for Register {
+    fn default() -> Self {
+        Self {
+            ip: Ipv4Addr::LOCALHOST,
+            support_t38: false,
+            audio_codecs: Vec::new(),
+            video_codecs: Vec::new(),
+            fax_codecs: Vec::new(),
+            capability: None,
+            version: None,
+            capacity: None,
+            port_range: None,
+        }
+    }
+}
+
+impl Register {
+    pub fn write_to<B: BufMut>(&self, mut buf: B) -> usize {
+        let mut media_info = Vec::new();
+        media_info.put_u8(if self.support_t38 { 0 } else { 1 });
+        for codecs in [&self.audio_codecs, &self.video_codecs, &self.fax_codecs] {
+            media_info.put_u8(codecs.len() as u8);
+            for codec in codecs.iter() {
+                codec.write_to(&mut media_info);
+            }
+        }
+
+        buf.put_slice(&self.ip.octets());
+        buf.put_u8(TagType::MEDIAINFO.code());
+        buf.put_u16(media_info.len() as u16);
+        buf.put_slice(&media_info);
+        let mut len = 4 + TagRef::MIN_LEN + media_info.len();
+
+        if let Some(capability) = &self.capability {
+            len += capability.write_tag_to(&mut buf);
+        }
+        if let Some(version) = &self.version {
+            buf.put_u8(TagType::VERSION.code());
+            buf.put_u16(version.len() as u16 + 1);
+            len += TagRef::MIN_LEN + put_str_null(&mut buf, version);
+        }
+        if let Some(capacity) = self.capacity {
+            buf.put_u8(TagType::CAPACITY.code());
+            buf.put_u16(4);
+            buf.put_u32(capacity);
+            len += TagRef::MIN_LEN + 4;
+        }
+        if let Some(port_range) = &self.port_range {
+            len += port_range.write_tag_to(&mut buf);
+        }
+        len
+    }
+}
+
+/// owned codec of MediaInfo
+#[derive(Debug, Clone, Default)]
+pub struct CodecDesc {
+    pub index: u8,
+    pub payload_type: u8,
+    /// e.g. "PCMA/8000"
+    pub mapstr: String,
+}
+
+impl CodecDesc {
+    pub fn write_to<B: BufMut>(&self, mut buf: B) -> usize {
+        buf.put_u8(self.index);
+        buf.put_u8(self.payload_type);
+        2 + put_str_null(&mut buf, &self.mapstr)
+    }
 }
 
 #[derive(Debug)]
@@ -300,6 +750,9 @@ pub struct CodecDescRef<'a> {
 
 impl<'a> CodecDescRef<'a> {
     pub fn parse_vec_from(data: &'a[u8]) -> Result<(usize, Vec<Self>)> {
+        if data.is_empty() {
+            bail!("codec list at least 1 byte")
+        }
         let mut buf = data;
         let count = buf.get_u8() as usize;
         let mut v = Vec::with_capacity(count);
@@ -334,6 +787,10 @@ impl<'a> CodecDescRef<'a> {
         self.mapdata.len() + 3
     }
 
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
     pub fn index(&self) -> u8 {
         self.index
     }
@@ -347,7 +804,12 @@ impl<'a> CodecDescRef<'a> {
     }
 
     pub fn map_str_utf8(&self) -> Option<&'a str> {
-        std::str::from_utf8(self.mapdata).ok()
+        core::str::from_utf8(self.mapdata).ok()
+    }
+
+    /// like map_str_utf8 but falls back to configured charset
+    pub fn map_str(&self) -> Option<Cow<'a, str>> {
+        decode_str(self.mapdata)
     }
 }
 
@@ -359,7 +821,7 @@ impl<'a> fmt::Debug for CodecDescRef<'a> {
         .field("index", &self.index)
         .field("payload_type", &self.payload_type);
 
-        match self.map_str_utf8() {
+        match self.map_str() {
             Some(v) => builder.field("mapstr", &v),
             None => builder.field("mapdata", &self.mapdata.len()),
         };
@@ -428,6 +890,7 @@ impl<'a> fmt::Debug for TagRef<'a> {
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, Eq, PartialEq,)]
 #[derive(TryFromPrimitive)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
 pub enum IceType {
     Simple = 0, // no stun，dtls，srtp
     Webrtc = 1, // has stun，dtls，srtp
@@ -436,9 +899,12 @@ pub enum IceType {
 
 pub type IceCode = EnumNum<u8, IceType>;
 
+impl IsEnum for IceType {}
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, Eq, PartialEq,)]
 #[derive(TryFromPrimitive)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
 pub enum MediaType {
     AudioOnly = 1, 
     AudioVideo = 2,
@@ -454,6 +920,13 @@ pub enum MediaType {
 
 pub type MediaCode = EnumNum<u8, MediaType>;
 
+impl IsEnum for MediaType {}
+
+
+/// agora media types 4 and 7 carry agora_info after as_call_id
+fn has_agora_info(media_type: u8) -> bool {
+    matches!(media_type, 4 | 7)
+}
 
 // #[derive(Debug)]
 pub struct RequestChannelRef<'a> {
@@ -484,14 +957,14 @@ impl<'a> RequestChannelRef<'a> {
         buf.advance(pos+1);
 
         
-        let agora_info = match fixed_part1.media_type_code() {
-            4 | 7 => {
+        let agora_info = match has_agora_info(fixed_part1.media_type_code()) {
+            true => {
                 let pos = find_str_null(buf).with_context(||"Not found null for agora_info")?;
                 let info = &buf[..pos];
                 buf.advance(pos+1);
                 Some(info)
             },
-            _ => None,
+            false => None,
         };
 
         if buf.len() < Self::PART2_LEN {
@@ -501,7 +974,7 @@ impl<'a> RequestChannelRef<'a> {
         let fixed_part2 = RequestChannelPart2(&buf[..Self::PART2_LEN]);
         buf.advance(Self::PART2_LEN);
 
-        let webrtc = StrIter(&buf[..]);
+        let webrtc = StrIter(buf);
         buf.advance(buf.len());
         
         Ok(Self {
@@ -520,6 +993,24 @@ impl<'a> RequestChannelRef<'a> {
     pub fn part2<'b>(&'b self) -> &'b RequestChannelPart2<'a> {
         &self.fixed_part2
     }
+
+    pub fn as_call_id(&self) -> StrRef<'a> {
+        StrRef(self.as_call_id)
+    }
+
+    /// only with agora media types
+    pub fn agora_info(&self) -> Option<StrRef<'a>> {
+        self.agora_info.map(StrRef)
+    }
+
+    pub fn webrtc(&self) -> WebrtcInfo<'a> {
+        WebrtcInfo::parse(self.webrtc.clone())
+    }
+
+    /// strings of webrtc block as sent, empty ones included
+    pub fn webrtc_strs(&self) -> impl Iterator<Item = StrRef<'a>> + Clone {
+        self.webrtc.clone().map(StrRef)
+    }
 }
 
 impl<'a> fmt::Debug for RequestChannelRef<'a> {
@@ -531,11 +1022,11 @@ impl<'a> fmt::Debug for RequestChannelRef<'a> {
         .field("life", &self.part1().life_seconds())
         .field("ice", &MediaCode::new(self.part1().media_type_code()));
 
-        fmt_struct_field_str(&mut builder, "as_call_id", self.as_call_id);
+        builder.field("as_call_id", &RedactStr(RedactField::AsCallId, self.as_call_id));
 
         match &self.agora_info {
             Some(info) => {
-                fmt_struct_field_str(&mut builder, "agora_info", *info)
+                builder.field("agora_info", &RedactStr(RedactField::AgoraInfo, info))
             },
             None => builder.field("agora_info", &Option::<&str>::None),
         };
@@ -556,17 +1047,64 @@ impl<'a> fmt::Debug for RequestChannelRef<'a> {
     }
 }
 
+/// owned RequestChannel payload for building packets
+#[derive(Debug, Clone, Default)]
+pub struct RequestChannel {
+    pub ice_type: u8,
+    pub life_seconds: u16,
+    pub media_type: u8,
+    pub as_call_id: String,
+    /// written only for agora media types, empty there if None
+    pub agora_info: Option<String>,
+    pub is_nbup: bool,
+    pub ptime: u8,
+    pub is_caller: bool,
+    pub codec: u8,
+    pub amr_mode: u16,
+    pub webrtc: Vec<String>,
+}
+
+impl RequestChannel {
+    pub fn write_to<B: BufMut>(&self, mut buf: B) -> usize {
+        let mut len = 0;
+
+        buf.put_u8(self.ice_type);
+        buf.put_u16(self.life_seconds);
+        buf.put_u8(self.media_type);
+        len += RequestChannelRef::PART1_LEN;
+
+        len += put_str_null(&mut buf, &self.as_call_id);
+
+        if has_agora_info(self.media_type) {
+            len += put_str_null(&mut buf, self.agora_info.as_deref().unwrap_or_default());
+        }
+
+        buf.put_u8(self.is_nbup as u8);
+        buf.put_u8(self.ptime);
+        buf.put_u8(self.is_caller as u8);
+        buf.put_u8(self.codec);
+        buf.put_u16(self.amr_mode);
+        len += RequestChannelRef::PART2_LEN;
+
+        for s in self.webrtc.iter() {
+            len += put_str_null(&mut buf, s);
+        }
+
+        len
+    }
+}
+
 pub struct RequestChannelPart1<'a>(&'a [u8]);
 impl<'a> RequestChannelPart1<'a> {
-    fn ice_type_code(&self) -> u8 {
+    pub fn ice_type_code(&self) -> u8 {
         self.0[0]
     }
 
-    fn life_seconds(&self) -> u16 {
+    pub fn life_seconds(&self) -> u16 {
         (&self.0[1..3]).get_u16()
     }
 
-    fn media_type_code(&self) -> u8 {
+    pub fn media_type_code(&self) -> u8 {
         self.0[3]
     }
 }
@@ -616,7 +1154,7 @@ impl<'a> RequestChannelAckRef<'a> {
         buf.advance(Self::PART1_LEN);
 
 
-        let webrtc = StrIter(&buf[..]);
+        let webrtc = StrIter(buf);
         buf.advance(buf.len());
         
         Ok(Self {
@@ -629,6 +1167,10 @@ impl<'a> RequestChannelAckRef<'a> {
         &self.fixed_part1
     }
 
+    /// strings of webrtc block as sent, empty ones included
+    pub fn webrtc_strs(&self) -> impl Iterator<Item = StrRef<'a>> + Clone {
+        self.webrtc.clone().map(StrRef)
+    }
 }
 
 impl<'a> fmt::Debug for RequestChannelAckRef<'a> {
@@ -673,6 +1215,38 @@ impl<'a> RequestChannelAckPart1<'a> {
     }
 }
 
+/// owned RequestChannelAck payload for building packets, as sent by MS
+#[derive(Debug, Clone, Default)]
+pub struct RequestChannelAck {
+    pub result: u8,
+    pub audio_port: u16,
+    pub video_port: u16,
+    pub fax_port: u16,
+    pub media_type: u8,
+    pub webrtc: Vec<String>,
+}
+
+impl RequestChannelAck {
+    pub fn write_to<B: BufMut>(&self, mut buf: B) -> usize {
+        buf.put_u8(self.result);
+        buf.put_u16(self.audio_port);
+        buf.put_u16(self.video_port);
+        buf.put_u16(self.fax_port);
+        buf.put_u8(self.media_type);
+        let mut len = RequestChannelAckRef::PART1_LEN;
+
+        if self.webrtc.is_empty() {
+            // parser wants at least one byte after fixed part
+            buf.put_u8(0);
+            len += 1;
+        }
+        for s in self.webrtc.iter() {
+            len += put_str_null(&mut buf, s);
+        }
+        len
+    }
+}
+
 
 pub struct OpenRtpConnectRef<'a> {
     num_tags: u8,
@@ -735,7 +1309,7 @@ macro_rules! define_u8_packet {
 
             pub fn parse_from(data: & [u8]) -> Result<Self> {
                 if data.len() < Self::MIN_LEN {
-                    bail!("{} at least [{}] bytes but [{}]", stringify!($func_name), Self::MIN_LEN, data.len())
+                    bail!("{} at least [{}] bytes but [{}]", stringify!($type_name), Self::MIN_LEN, data.len())
                 }
                 Ok(Self(data[0]))
             }
@@ -744,6 +1318,12 @@ macro_rules! define_u8_packet {
                 self.0
             }
         }
+
+        impl<'a> WireParse<'a> for $type_name {
+            fn parse(data: &'a [u8]) -> Result<(usize, Self)> {
+                Ok((Self::MIN_LEN, Self::parse_from(data)?))
+            }
+        }
     };
 }
 
@@ -772,6 +1352,22 @@ impl<'a> ResFromTagRef<'a> {
 
         Ok(Self(slice))
     }
+
+    pub fn value(&self) -> &'a [u8] {
+        self.0
+    }
+}
+
+/// owned RESFROMTAG, e.g. text recognized by ASR
+#[derive(Debug, Clone, Default)]
+pub struct ResFromTag {
+    pub value: String,
+}
+
+impl ResFromTag {
+    pub fn write_to<B: BufMut>(&self, mut buf: B) -> usize {
+        put_str_null(&mut buf, &self.value)
+    }
 }
 
 
@@ -779,11 +1375,8 @@ impl<'a> fmt::Debug for ResFromTagRef<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut builder = f.debug_tuple("ResFromTag");
         
-        match std::str::from_utf8(self.0) {
-            Ok(v) => builder.field(&v),
-            Err(e) => builder.field(&Result::<(), std::str::Utf8Error>::Err(e)),
-        };
-        
+        builder.field(&StrDebug(self.0));
+
         builder.finish()
     }
 }
@@ -815,6 +1408,17 @@ impl<'a> PlayRef<'a> {
             tags,
         })
     }
+
+    pub fn part1<'b>(&'b self) -> &'b PlayPart1<'a> {
+        &self.part1
+    }
+
+    /// FILENAME tags, other tags skipped
+    pub fn files(&self) -> impl Iterator<Item = Result<FilenameRef<'a>>> + Clone {
+        self.tags.clone()
+        .filter(|x| !matches!(x, Ok(tag) if tag.tag_type() != Some(TagType::FILENAME)))
+        .map(|x| x.and_then(|tag| FilenameRef::parse_from(tag.payload())))
+    }
 }
 
 impl<'a> fmt::Debug for PlayRef<'a> {
@@ -837,6 +1441,39 @@ impl<'a> fmt::Debug for PlayRef<'a> {
 }
 
 
+/// owned Play payload for building packets
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Play {
+    pub interval: u32,
+    pub play_times: u16,
+    pub max_duration: u32,
+    pub key_mask: u16,
+    pub record: bool,
+    pub speech_barge: bool,
+    pub erase_dtmf: bool,
+    pub files: Vec<Filename>,
+}
+
+impl Play {
+    pub fn write_to<B: BufMut>(&self, mut buf: B) -> usize {
+        buf.put_u32(self.interval);
+        buf.put_u16(self.play_times);
+        buf.put_u32(self.max_duration);
+        buf.put_u16(self.key_mask);
+        buf.put_u8(self.record as u8);
+        buf.put_u8(self.speech_barge as u8);
+        buf.put_u8(self.erase_dtmf as u8);
+        buf.put_u8(self.files.len() as u8);
+        let mut len = PlayRef::PART1_LEN;
+
+        for file in self.files.iter() {
+            len += file.write_tag_to(&mut buf);
+        }
+
+        len
+    }
+}
+
 pub struct PlayPart1<'a>(&'a [u8]);
 
 impl<'a> PlayPart1<'a> {
@@ -905,6 +1542,10 @@ impl<'a> PlayAckRef<'a> {
             tags,
         })
     }
+
+    pub fn part1<'b>(&'b self) -> &'b PlayAckPart1<'a> {
+        &self.part1
+    }
 }
 
 impl<'a> fmt::Debug for PlayAckRef<'a> {
@@ -933,13 +1574,64 @@ impl<'a> PlayAckPart1<'a> {
     }
 }
 
+/// owned PlayAck payload for building packets, as sent by MS
+#[derive(Debug, Clone, Default)]
+pub struct PlayAck {
+    pub result: u8,
+    pub play_duration: u32,
+}
+
+impl PlayAck {
+    pub fn write_to<B: BufMut>(&self, mut buf: B) -> usize {
+        buf.put_u8(self.result);
+        buf.put_u32(self.play_duration);
+        PlayAckRef::PART1_LEN
+    }
+}
+
 
 
 
 
 
 
-#[derive(Debug)]
+/// format byte of FILENAME tag, 100 is what MS uses for wav prompts
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq,)]
+#[derive(TryFromPrimitive)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum FileFormat {
+    Wav = 100,
+    Pcm = 101,
+    Amr = 102,
+    Mp4 = 103,
+    Jpg = 104,
+}
+
+impl FileFormat {
+    /// file extensions expected for this format
+    pub fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            FileFormat::Wav => &["wav"],
+            FileFormat::Pcm => &["pcm", "raw"],
+            FileFormat::Amr => &["amr"],
+            FileFormat::Mp4 => &["mp4"],
+            FileFormat::Jpg => &["jpg", "jpeg"],
+        }
+    }
+
+    pub fn matches_path(&self, path: &str) -> bool {
+        match path.rsplit_once('.') {
+            Some((_, ext)) => self.extensions().iter().any(|x| x.eq_ignore_ascii_case(ext)),
+            None => false,
+        }
+    }
+}
+
+pub type FileFormatCode = EnumNum<u8, FileFormat>;
+
+impl IsEnum for FileFormat {}
+
 pub struct FilenameRef<'a> {
     format: u8,
     filename: StrRef<'a>,
@@ -957,7 +1649,7 @@ impl<'a> FilenameRef<'a> {
 
         let format = buf.get_u8();
 
-        let (_n, filename) = StrRef::from_str_null(&buf[..])
+        let (_n, filename) = StrRef::from_str_null(buf)
         .with_context(||"Not found null for filename")?;
         buf.advance(buf.len());
 
@@ -971,11 +1663,115 @@ impl<'a> FilenameRef<'a> {
         self.format
     }
 
+    pub fn file_format(&self) -> Option<FileFormat> {
+        FileFormat::try_from(self.format).ok()
+    }
+
     pub fn filename(&self) -> &StrRef<'a> {
         &self.filename
     }
 }
 
+impl<'a> fmt::Debug for FilenameRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FilenameRef")
+        .field("format", &FileFormatCode::new(self.format))
+        .field("filename", &RedactUrl(self.filename.data()))
+        .finish()
+    }
+}
+
+
+/// owned FILENAME tag
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filename {
+    pub format: u8,
+    pub filename: String,
+}
+
+impl Filename {
+    pub fn write_tag_to<B: BufMut>(&self, mut buf: B) -> usize {
+        let length = 1 + self.filename.len() + 1;
+        buf.put_u8(TagType::FILENAME.code());
+        buf.put_u16(length as u16);
+        buf.put_u8(self.format);
+        put_str_null(&mut buf, &self.filename);
+        TagRef::MIN_LEN + length
+    }
+}
+
+
+/// CAPABILITY tag, in REGISTER from MS and echoed in REGISTER_ACK, EPOCH in CNISUP
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capability {
+    pub flags: u32,
+}
+
+impl Capability {
+    /// payload section is prefixed with encoding byte, see vn_compress
+    pub const ZLIB: u32 = 0x01;
+
+    /// large payloads are split into fragments, see vn_fragment
+    pub const FRAGMENT: u32 = 0x02;
+
+    /// key carries the epoch of MS, offered by CN at CNISUP, see vn_epoch
+    pub const EPOCH: u32 = 0x04;
+
+    const LEN: usize = 4;
+
+    pub fn parse_from(data: &[u8]) -> Result<Self> {
+        if data.len() < Self::LEN {
+            bail!("Capability at least [{}] bytes but [{}]", Self::LEN, data.len())
+        }
+        Ok(Self { flags: (&data[..Self::LEN]).get_u32() })
+    }
+
+    pub fn has(&self, flag: u32) -> bool {
+        self.flags & flag == flag
+    }
+
+    pub fn write_tag_to<B: BufMut>(&self, mut buf: B) -> usize {
+        buf.put_u8(TagType::CAPABILITY.code());
+        buf.put_u16(Self::LEN as u16);
+        buf.put_u32(self.flags);
+        TagRef::MIN_LEN + Self::LEN
+    }
+}
+
+
+/// PORTRANGE tag, RTP ports MS allocates from, both included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(any(feature = "runtime", feature = "smol"), derive(serde::Serialize, serde::Deserialize))]
+pub struct PortRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl PortRange {
+    const LEN: usize = 4;
+
+    pub fn parse_from(mut data: &[u8]) -> Result<Self> {
+        if data.len() < Self::LEN {
+            bail!("PortRange at least [{}] bytes but [{}]", Self::LEN, data.len())
+        }
+        Ok(Self { min: data.get_u16(), max: data.get_u16() })
+    }
+
+    pub fn write_tag_to<B: BufMut>(&self, mut buf: B) -> usize {
+        buf.put_u8(TagType::PORTRANGE.code());
+        buf.put_u16(Self::LEN as u16);
+        buf.put_u16(self.min);
+        buf.put_u16(self.max);
+        TagRef::MIN_LEN + Self::LEN
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.min, self.max)
+    }
+}
+
 
 pub struct CancelRef<'a>(&'a [u8]);
 
@@ -1033,6 +1829,25 @@ where
 #[derive(Clone)]
 pub struct TagIter<'a>(&'a [u8]);
 
+impl<'a> TagIter<'a> {
+    /// tags making up data, e.g. payload of CNISUP
+    pub fn new(data: &'a [u8]) -> Self {
+        Self(data)
+    }
+
+    /// first CAPABILITY tag
+    pub fn capability(self) -> Option<Capability> {
+        self.find_type(TagType::CAPABILITY)
+        .and_then(|x| Capability::parse_from(x.payload()).ok())
+    }
+
+    /// first tag of ttype
+    pub fn find_type(self, ttype: TagType) -> Option<TagRef<'a>> {
+        self.filter_map(|x| x.ok())
+        .find(|x| x.tag_type() == Some(ttype))
+    }
+}
+
 impl<'a> Iterator for TagIter<'a> {
     type Item = Result<TagRef<'a>>;
 
@@ -1048,7 +1863,7 @@ impl<'a> Iterator for TagIter<'a> {
             },
             Err(e) => {
                 self.0.advance(self.0.len());
-                Some(Err(e.into()))
+                Some(Err(e))
             },
         }
     }
@@ -1092,15 +1907,29 @@ impl<'a> fmt::Debug for TagDebug<'a> {
         
         match self.0.tag_type() {
             None => {
-                fmt::Debug::fmt(&self.0, f)
-            },
-            Some(ttype) => {
                 let mut builder = f.debug_struct("Tag");
-                builder.field("type", &ttype);
+                builder
+                .field("type", &format_args!("0x{:02X}", self.0.tag_code()))
+                .field("payload", &self.0.payload().len());
+
+                match StrRef::from_str_null(self.0.payload()) {
+                    Some((n, v)) if n == self.0.payload().len() => builder.field("str", &v),
+                    _ => builder.field("data", &format_args!("{:02x?}", self.0.payload())),
+                };
+
+                builder.finish()
+            },
+            Some(ttype) => {
+                let mut builder = f.debug_struct("Tag");
+                builder.field("type", &ttype);
                 match ttype {
                     TagType::MEDIAINFO => builder.field("value", &MediaInfoRef::parse_from(self.0.payload())),
                     TagType::FILENAME => builder.field("value", &FilenameRef::parse_from(self.0.payload())),
                     TagType::RTPINFO => builder.field("value", &RtpInfoRef::parse_from(self.0.payload())),
+                    TagType::CAPABILITY => builder.field("value", &Capability::parse_from(self.0.payload())),
+                    TagType::VERSION => builder.field("value", &tag_str(self.0.payload())),
+                    TagType::CAPACITY => builder.field("value", &(self.0.payload().len() >= 4).then(|| (&self.0.payload()[..4]).get_u32())),
+                    TagType::PORTRANGE => builder.field("value", &PortRange::parse_from(self.0.payload())),
                 };
 
                 builder.finish()
@@ -1121,6 +1950,8 @@ pub enum RtpMediaType {
 
 pub type RtpMediaTypeCode = EnumNum<u8, RtpMediaType>;
 
+impl IsEnum for RtpMediaType {}
+
 pub struct RtpInfoRef<'a> {
     fixed_part1: RtpInfoPart1<'a>,
     attribute: &'a [u8],
@@ -1156,7 +1987,7 @@ impl<'a> RtpInfoRef<'a> {
         buf.advance(Self::PART2_LEN);
 
 
-        let part3 = StrIter(&buf[..]);
+        let part3 = StrIter(buf);
         buf.advance(buf.len());
         
         Ok(Self {
@@ -1174,6 +2005,19 @@ impl<'a> RtpInfoRef<'a> {
     pub fn part2<'b>(&'b self) -> &'b RtpInfoPart2<'a> {
         &self.fixed_part2
     }
+
+    pub fn attribute(&self) -> StrRef<'a> {
+        StrRef(self.attribute)
+    }
+
+    pub fn webrtc(&self) -> WebrtcInfo<'a> {
+        WebrtcInfo::parse(self.part3.clone())
+    }
+
+    /// webrtc lines as sent
+    pub fn webrtc_strs(&self) -> impl Iterator<Item = StrRef<'a>> + Clone {
+        self.part3.clone().map(StrRef)
+    }
 }
 
 impl<'a> fmt::Debug for RtpInfoRef<'a> {
@@ -1203,6 +2047,72 @@ impl<'a> fmt::Debug for RtpInfoRef<'a> {
 }
 
 
+/// owned RTPINFO tag, remote rtp end of a channel in OPENRTPCONNECT
+#[derive(Debug, Clone)]
+pub struct RtpInfo {
+    pub ip: Ipv4Addr,
+    pub port: u16,
+    pub media_type: u8,
+    pub internal_pltyp: u8,
+    pub nego_pltyp: u8,
+    pub attribute: String,
+    pub tele_event: u8,
+    pub direction: u8,
+    /// webrtc lines, "null_<name>" for absent values
+    pub webrtc: Vec<String>,
+}
+
+impl RtpInfo {
+    /// audio without webrtc, sendrecv
+    pub fn plain(ip: Ipv4Addr, port: u16, pltyp: u8) -> Self {
+        Self {
+            ip,
+            port,
+            media_type: RtpMediaType::Audio as u8,
+            internal_pltyp: pltyp,
+            nego_pltyp: pltyp,
+            attribute: String::new(),
+            tele_event: 101,
+            direction: 0,
+            webrtc: ["null_crypto", "null_ufrag", "null_pwd", "null_fingerprint", "null_setup", "null_ice"]
+                .iter().map(|x| String::from(*x)).collect(),
+        }
+    }
+
+    pub fn write_tag_to<B: BufMut>(&self, mut buf: B) -> usize {
+        let mut value = Vec::new();
+        value.put_slice(&self.ip.octets());
+        value.put_u16(self.port);
+        value.put_u8(self.media_type);
+        value.put_u8(self.internal_pltyp);
+        value.put_u8(self.nego_pltyp);
+        put_str_null(&mut value, &self.attribute);
+        value.put_u8(self.tele_event);
+        value.put_u8(self.direction);
+        for line in self.webrtc.iter() {
+            put_str_null(&mut value, line);
+        }
+
+        buf.put_u8(TagType::RTPINFO.code());
+        buf.put_u16(value.len() as u16);
+        buf.put_slice(&value);
+        TagRef::MIN_LEN + value.len()
+    }
+}
+
+/// owned OPENRTPCONNECT
+#[derive(Debug, Clone, Default)]
+pub struct OpenRtpConnect {
+    pub rtpinfos: Vec<RtpInfo>,
+}
+
+impl OpenRtpConnect {
+    pub fn write_to<B: BufMut>(&self, mut buf: B) -> usize {
+        buf.put_u8(self.rtpinfos.len() as u8);
+        1 + self.rtpinfos.iter().map(|x| x.write_tag_to(&mut buf)).sum::<usize>()
+    }
+}
+
 pub struct RtpInfoPart1<'a>(&'a [u8]);
 impl<'a> RtpInfoPart1<'a> {
     pub fn ip(&self) -> IpAddr {
@@ -1237,7 +2147,7 @@ impl<'a> RtpInfoPart2<'a> {
     }
 }
 
-// #[derive(Clone)]
+#[derive(Clone)]
 struct StrIter<'a>(&'a [u8]);
 
 impl<'a> Iterator for StrIter<'a> {
@@ -1266,15 +2176,100 @@ impl<'a> fmt::Debug for StrIter<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut builder = f.debug_list();
         for data in Self(self.0) {
-            match std::str::from_utf8(data) {
-                Ok(v) => builder.entry(&v),
-                Err(e) => builder.entry(&Result::<(), std::str::Utf8Error>::Err(e)),
-            };
+            builder.entry(&StrDebug(data));
         }
         builder.finish()
     }
 }
 
+/// dtls setup role of webrtc block
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SetupRole {
+    /// "client" or "active"
+    Active,
+    /// "server" or "passive"
+    Passive,
+    Actpass,
+}
+
+impl SetupRole {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim() {
+            "client" | "active" => Some(Self::Active),
+            "server" | "passive" => Some(Self::Passive),
+            "actpass" => Some(Self::Actpass),
+            _ => None,
+        }
+    }
+}
+
+/// named fields of webrtc string block in RequestChannel/RtpInfo.
+///
+/// each line is "null_<name>" for absent value or "<name>:<value>",
+/// lines not recognized are kept in `unknown` in original order.
+#[derive(Debug, Clone, Default)]
+pub struct WebrtcInfo<'a> {
+    pub crypto: Option<&'a str>,
+    pub ufrag: Option<&'a str>,
+    pub pwd: Option<&'a str>,
+    pub fingerprint: Option<&'a str>,
+    pub setup: Option<&'a str>,
+    pub ice: Option<&'a str>,
+    pub candidates: Vec<&'a str>,
+    pub unknown: Vec<&'a [u8]>,
+}
+
+impl<'a> WebrtcInfo<'a> {
+    fn parse(iter: StrIter<'a>) -> Self {
+        let mut me = Self::default();
+        for line in iter {
+            if !me.parse_line(line) {
+                me.unknown.push(line);
+            }
+        }
+        me
+    }
+
+    fn parse_line(&mut self, line: &'a [u8]) -> bool {
+        let Ok(line) = core::str::from_utf8(line) else { return false };
+
+        if let Some(name) = line.strip_prefix("null_") {
+            return self.field_mut(name).is_some()
+        }
+
+        let Some((name, value)) = line.split_once(':') else { return false };
+
+        if name == "candidate" {
+            self.candidates.push(value);
+            return true
+        }
+
+        match self.field_mut(name) {
+            Some(field) => {
+                *field = Some(value);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn field_mut(&mut self, name: &str) -> Option<&mut Option<&'a str>> {
+        match name {
+            "crypto" => Some(&mut self.crypto),
+            "ice_frag" | "ice_ufrag" | "ufrag" => Some(&mut self.ufrag),
+            "ice_pwd" | "pwd" => Some(&mut self.pwd),
+            "fingerprint" => Some(&mut self.fingerprint),
+            "dtls_roll" | "setup" => Some(&mut self.setup),
+            "ice" => Some(&mut self.ice),
+            _ => None,
+        }
+    }
+
+    pub fn setup_role(&self) -> Option<SetupRole> {
+        self.setup.and_then(SetupRole::parse)
+    }
+}
+
 #[derive(Clone)]
 pub struct StrRef<'a>(&'a [u8]);
 
@@ -1291,11 +2286,25 @@ impl<'a> StrRef<'a> {
     }
 }
 
+impl<'a> StrRef<'a> {
+    pub fn data(&self) -> &'a [u8] {
+        self.0
+    }
+
+    /// decode with configured charset, see vn_charset
+    pub fn decode(&self) -> Option<Cow<'a, str>> {
+        decode_str(self.0)
+    }
+}
+
 impl<'a> fmt::Debug for StrRef<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match std::str::from_utf8(self.0) {
-            Ok(v) => fmt::Debug::fmt(&v, f),
-            Err(e) => fmt::Debug::fmt(&e, f),
+        match self.decode() {
+            Some(v) => fmt::Debug::fmt(&v, f),
+            None => match core::str::from_utf8(self.0) {
+                Ok(v) => fmt::Debug::fmt(&v, f),
+                Err(e) => fmt::Debug::fmt(&e, f),
+            },
         }
     }
 }
@@ -1303,12 +2312,13 @@ impl<'a> fmt::Debug for StrRef<'a> {
 
 fn fmt_struct_field_str<'a, 'b, 'c>(builder: &'a mut fmt::DebugStruct<'b, 'c>, name: &str, data: &[u8]) -> &'a mut fmt::DebugStruct<'b, 'c> {
 
-    match std::str::from_utf8(data) {
-        Ok(v) => builder.field(name, &v),
-        Err(e) => builder.field(name, &Result::<(), std::str::Utf8Error>::Err(e)),
-    };
+    builder.field(name, &StrDebug(data))
+}
 
-    builder
+fn put_str_null<B: BufMut>(buf: &mut B, s: &str) -> usize {
+    buf.put_slice(s.as_bytes());
+    buf.put_u8(0);
+    s.len() + 1
 }
 
 fn find_str_null(buf: &[u8]) -> Option<usize> {
@@ -1316,3 +2326,1000 @@ fn find_str_null(buf: &[u8]) -> Option<usize> {
 }
 
 
+/// parse a wire structure and tell how many bytes it took,
+/// so concatenated structures can be walked generically.
+///
+/// Structures ending with a variable tail (tags, webrtc strings) take the whole input.
+pub trait WireParse<'a>: Sized {
+    fn parse(data: &'a [u8]) -> Result<(usize, Self)>;
+}
+
+/// parse structures back to back until data is used up
+pub fn parse_seq<'a, T: WireParse<'a>>(mut data: &'a [u8]) -> Result<Vec<T>> {
+    let mut items = Vec::new();
+    while !data.is_empty() {
+        let (len, item) = T::parse(data)?;
+        if len == 0 {
+            bail!("parse consumed nothing at item [{}]", items.len())
+        }
+        items.push(item);
+        data = &data[len..];
+    }
+    Ok(items)
+}
+
+/// for parse_from returning (usize, Self)
+macro_rules! impl_wire_parse_counted {
+    ($($type_name:ident),*) => {
+        $(
+            impl<'a> WireParse<'a> for $type_name<'a> {
+                fn parse(data: &'a [u8]) -> Result<(usize, Self)> {
+                    Self::parse_from(data)
+                }
+            }
+        )*
+    };
+}
+
+/// for parse_from taking all the remaining bytes
+macro_rules! impl_wire_parse_rest {
+    ($($type_name:ident),*) => {
+        $(
+            impl<'a> WireParse<'a> for $type_name<'a> {
+                fn parse(data: &'a [u8]) -> Result<(usize, Self)> {
+                    Ok((data.len(), Self::parse_from(data)?))
+                }
+            }
+        )*
+    };
+}
+
+impl_wire_parse_counted!(MediaInfoRef, CodecDescRef);
+
+impl_wire_parse_rest!(RegisterRef, RequestChannelRef, RequestChannelAckRef, OpenRtpConnectRef, PlayRef, PlayAckRef, RtpInfoRef);
+
+impl<'a> WireParse<'a> for PacketRef<'a> {
+    fn parse(data: &'a [u8]) -> Result<(usize, Self)> {
+        let len = Self::parse_from(data)?.length() + 2;
+        Ok((len, Self::parse_with(&data[..len], LengthPolicy::Strict)?))
+    }
+}
+
+impl<'a> WireParse<'a> for TagRef<'a> {
+    fn parse(data: &'a [u8]) -> Result<(usize, Self)> {
+        let me = Self::parse_from(data)?;
+        Ok((Self::MIN_LEN + me.payload.len(), me))
+    }
+}
+
+impl<'a> WireParse<'a> for ResFromTagRef<'a> {
+    fn parse(data: &'a [u8]) -> Result<(usize, Self)> {
+        let me = Self::parse_from(data)?;
+        Ok((me.0.len() + 1, me))
+    }
+}
+
+impl<'a> WireParse<'a> for FilenameRef<'a> {
+    fn parse(data: &'a [u8]) -> Result<(usize, Self)> {
+        let me = Self::parse_from(data)?;
+        Ok((1 + me.filename.0.len() + 1, me))
+    }
+}
+
+impl<'a> WireParse<'a> for CancelRef<'a> {
+    fn parse(data: &'a [u8]) -> Result<(usize, Self)> {
+        Ok((Self::MIN_LEN, Self::parse_from(data)?))
+    }
+}
+
+impl<'a> WireParse<'a> for Capability {
+    fn parse(data: &'a [u8]) -> Result<(usize, Self)> {
+        Ok((Self::LEN, Self::parse_from(data)?))
+    }
+}
+
+
+/// typed payload of a packet, see [`parse_message`]
+pub enum Message<'a> {
+    Heartbeat,
+    Register(RegisterRef<'a>),
+    RequestChannel(RequestChannelRef<'a>),
+    RequestChannelAck(RequestChannelAckRef<'a>),
+    OpenRtpConnect(OpenRtpConnectRef<'a>),
+    OpenRtpConnectAck(OpenRtpConnectAck),
+    ResFromTag(ResFromTagRef<'a>),
+    Play(PlayRef<'a>),
+    PlayAck(PlayAckRef<'a>),
+    Cancel(CancelRef<'a>),
+    CloseRtpConnect(CloseRtpConnect),
+    CloseRtpConnectAck(CloseRtpConnectAck),
+    ReleaseChannel,
+    /// unknown code or no payload layout yet
+    Unknown { code: u16, payload: &'a [u8] },
+}
+
+impl<'a> Message<'a> {
+    /// payload of packet by its code
+    pub fn from_packet(packet: &PacketRef<'a>) -> Result<Self> {
+        let payload = packet.payload();
+        let Ok(code) = MCodeType::try_from(packet.code()) else {
+            return Ok(Self::Unknown { code: packet.code(), payload })
+        };
+
+        let me = match code {
+            MCodeType::HEARTBEAT => Self::Heartbeat,
+            MCodeType::REGISTER => Self::Register(RegisterRef::parse_from(payload).with_context(||"invalid Register packet")?),
+            MCodeType::REQUESTCHANNEL => Self::RequestChannel(RequestChannelRef::parse_from(payload).with_context(||"invalid RequestChannel packet")?),
+            MCodeType::REQUESTCHANNEL_ACK => Self::RequestChannelAck(RequestChannelAckRef::parse_from(payload).with_context(||"invalid RequestChannelAck packet")?),
+            MCodeType::OPENRTPCONNECT => Self::OpenRtpConnect(OpenRtpConnectRef::parse_from(payload).with_context(||"invalid OpenRtpConnect packet")?),
+            MCodeType::OPENRTPCONNECT_ACK => Self::OpenRtpConnectAck(OpenRtpConnectAck::parse_from(payload).with_context(||"invalid OpenRtpConnectAck packet")?),
+            MCodeType::RESFROMTAG => Self::ResFromTag(ResFromTagRef::parse_from(payload).with_context(||"invalid ResFromTag packet")?),
+            MCodeType::PLAY => Self::Play(PlayRef::parse_from(payload).with_context(||"invalid Play packet")?),
+            MCodeType::PLAY_ACK => Self::PlayAck(PlayAckRef::parse_from(payload).with_context(||"invalid PlayAck packet")?),
+            MCodeType::CANCEL => Self::Cancel(CancelRef::parse_from(payload).with_context(||"invalid Cancel packet")?),
+            MCodeType::CLOSERTPCONNECT => Self::CloseRtpConnect(CloseRtpConnect::parse_from(payload).with_context(||"invalid CloseRtpConnect packet")?),
+            MCodeType::CLOSERTPCONNECT_ACK => Self::CloseRtpConnectAck(CloseRtpConnectAck::parse_from(payload).with_context(||"invalid CloseRtpConnectAck packet")?),
+            MCodeType::RELEASECHANNEL => Self::ReleaseChannel,
+            _ => Self::Unknown { code: code.code(), payload },
+        };
+        Ok(me)
+    }
+
+    pub fn code(&self) -> u16 {
+        let code = match self {
+            Self::Heartbeat => MCodeType::HEARTBEAT,
+            Self::Register(_) => MCodeType::REGISTER,
+            Self::RequestChannel(_) => MCodeType::REQUESTCHANNEL,
+            Self::RequestChannelAck(_) => MCodeType::REQUESTCHANNEL_ACK,
+            Self::OpenRtpConnect(_) => MCodeType::OPENRTPCONNECT,
+            Self::OpenRtpConnectAck(_) => MCodeType::OPENRTPCONNECT_ACK,
+            Self::ResFromTag(_) => MCodeType::RESFROMTAG,
+            Self::Play(_) => MCodeType::PLAY,
+            Self::PlayAck(_) => MCodeType::PLAY_ACK,
+            Self::Cancel(_) => MCodeType::CANCEL,
+            Self::CloseRtpConnect(_) => MCodeType::CLOSERTPCONNECT,
+            Self::CloseRtpConnectAck(_) => MCodeType::CLOSERTPCONNECT_ACK,
+            Self::ReleaseChannel => MCodeType::RELEASECHANNEL,
+            Self::Unknown { code, .. } => return *code,
+        };
+        code.code()
+    }
+}
+
+impl<'a> fmt::Debug for Message<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Heartbeat => f.write_str("Heartbeat"),
+            Self::Register(r) => fmt::Debug::fmt(r, f),
+            Self::RequestChannel(r) => fmt::Debug::fmt(r, f),
+            Self::RequestChannelAck(r) => fmt::Debug::fmt(r, f),
+            Self::OpenRtpConnect(r) => fmt::Debug::fmt(r, f),
+            Self::OpenRtpConnectAck(r) => fmt::Debug::fmt(r, f),
+            Self::ResFromTag(r) => fmt::Debug::fmt(r, f),
+            Self::Play(r) => fmt::Debug::fmt(r, f),
+            Self::PlayAck(r) => fmt::Debug::fmt(r, f),
+            Self::Cancel(r) => fmt::Debug::fmt(r, f),
+            Self::CloseRtpConnect(r) => fmt::Debug::fmt(r, f),
+            Self::CloseRtpConnectAck(r) => fmt::Debug::fmt(r, f),
+            Self::ReleaseChannel => f.write_str("ReleaseChannel"),
+            Self::Unknown { code, payload } => f.debug_struct("Unknown")
+                .field("code", &format_args!("{:02X?}", MCode::new(*code)))
+                .field("payload", &format_args!("{payload:02x?}"))
+                .finish(),
+        }
+    }
+}
+
+/// parse a whole packet and its payload by code, trailing bytes (e.g. cn path) are ignored
+pub fn parse_message(data: &[u8]) -> Result<Message<'_>> {
+    Message::from_packet(&PacketRef::parse_from(data)?)
+}
+
+
+// compact one line formatting, e.g. `PLAY fsm=123 sn=4 file=welcome.wav times=1`,
+// payload types only write their fields
+
+/// variant name of known codes, hex otherwise,
+/// cheap enough for a log field of every packet
+pub struct CodeName(pub u16);
+
+impl fmt::Display for CodeName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match MCodeType::try_from(self.0) {
+            Ok(code) => write!(f, "{code:?}"),
+            Err(_e) => write!(f, "0x{:04x}", self.0),
+        }
+    }
+}
+
+/// items separated by ','
+fn write_list<T, I>(f: &mut fmt::Formatter<'_>, items: I) -> fmt::Result
+where
+    T: fmt::Display,
+    I: Iterator<Item = T>,
+{
+    for (n, item) in items.enumerate() {
+        if n > 0 {
+            f.write_str(",")?;
+        }
+        write!(f, "{item}")?;
+    }
+    Ok(())
+}
+
+impl<'a> fmt::Display for StrRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.decode() {
+            Some(v) => f.write_str(&v),
+            None => f.write_str(&alloc::string::String::from_utf8_lossy(self.0)),
+        }
+    }
+}
+
+impl<'a> fmt::Display for CodecDescRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.map_str() {
+            Some(v) => f.write_str(&v),
+            None => write!(f, "{}", self.payload_type),
+        }
+    }
+}
+
+impl<'a> fmt::Display for RegisterRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ip={}", self.ip)?;
+        let info = &self.media_info;
+        for (name, codecs) in [("audio", &info.audio_codecs), ("video", &info.video_codecs), ("fax", &info.fax_codecs)] {
+            if !codecs.is_empty() {
+                write!(f, " {name}=")?;
+                write_list(f, codecs.iter())?;
+            }
+        }
+        if info.support_t38 {
+            f.write_str(" t38")?;
+        }
+        let caps = self.capabilities();
+        if caps != 0 {
+            write!(f, " caps=0x{caps:x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Display for RequestChannelRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.as_call_id.is_empty() {
+            write!(f, "call={} ", RedactStr(RedactField::AsCallId, self.as_call_id))?;
+        }
+        write!(f, "media={} life={} codec={}", self.part1().media_type_code(), self.part1().life_seconds(), self.part2().codec_code())
+    }
+}
+
+impl<'a> fmt::Display for RequestChannelAckRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let part1 = self.part1();
+        write!(f, "result={} audio={}", part1.result(), part1.audio_port())?;
+        for (name, port) in [("video", part1.video_port()), ("fax", part1.fax_port())] {
+            if port != 0 {
+                write!(f, " {name}={port}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Display for RtpInfoRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}/{}", self.part1().ip(), self.part1().port(), self.part1().nego_pltyp())
+    }
+}
+
+impl<'a> fmt::Display for OpenRtpConnectRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("rtp=")?;
+        write_list(f, self.rtpinfo_iter().map(|x| match x {
+            Ok(rtp) => alloc::format!("{rtp}"),
+            Err(_e) => "invalid".into(),
+        }))
+    }
+}
+
+impl<'a> fmt::Display for ResFromTagRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "value={}", StrRef(self.0))
+    }
+}
+
+impl<'a> fmt::Display for PlayRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("file=")?;
+        write_list(f, self.files().map(|x| match x {
+            Ok(file) => alloc::format!("{}", RedactUrl(file.filename().data())),
+            Err(_e) => "invalid".into(),
+        }))?;
+        write!(f, " times={}", self.part1().play_times())?;
+        if self.part1().record() {
+            f.write_str(" record")?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Display for PlayAckRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "result={} duration={}", self.part1().result(), self.part1().play_duration())
+    }
+}
+
+impl<'a> fmt::Display for CancelRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "op={}", CodeName(self.op_code()))
+    }
+}
+
+impl<'a> Message<'a> {
+    /// fields of payload, nothing for messages without payload
+    fn fmt_fields(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Heartbeat | Self::ReleaseChannel => Ok(()),
+            Self::Register(r) => fmt::Display::fmt(r, f),
+            Self::RequestChannel(r) => fmt::Display::fmt(r, f),
+            Self::RequestChannelAck(r) => fmt::Display::fmt(r, f),
+            Self::OpenRtpConnect(r) => fmt::Display::fmt(r, f),
+            Self::OpenRtpConnectAck(r) => write!(f, "result={}", r.value()),
+            Self::ResFromTag(r) => fmt::Display::fmt(r, f),
+            Self::Play(r) => fmt::Display::fmt(r, f),
+            Self::PlayAck(r) => fmt::Display::fmt(r, f),
+            Self::Cancel(r) => fmt::Display::fmt(r, f),
+            Self::CloseRtpConnect(r) => write!(f, "value={}", r.value()),
+            Self::CloseRtpConnectAck(r) => write!(f, "result={}", r.value()),
+            Self::Unknown { payload, .. } => write!(f, "len={}", payload.len()),
+        }
+    }
+
+    fn has_fields(&self) -> bool {
+        !matches!(self, Self::Heartbeat | Self::ReleaseChannel)
+    }
+}
+
+impl<'a> fmt::Display for Message<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", CodeName(self.code()))?;
+        if self.has_fields() {
+            f.write_str(" ")?;
+            self.fmt_fields(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Display for PacketRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} fsm={} sn={}", CodeName(self.code()), self.fsm_id(), self.sn())?;
+        if self.key() != 0 {
+            write!(f, " key={}", self.key())?;
+        }
+        match Message::from_packet(self) {
+            Ok(msg) if msg.has_fields() => {
+                f.write_str(" ")?;
+                msg.fmt_fields(f)
+            },
+            Ok(_msg) => Ok(()),
+            Err(_e) => f.write_str(" invalid-payload"),
+        }
+    }
+}
+
+impl<'a> PacketRef<'a> {
+    /// one line of header and payload fields, see Display
+    pub fn format_compact(&self) -> String {
+        alloc::format!("{self}")
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use core::net::IpAddr;
+
+    use super::{
+        parse_message, parse_seq, CancelRef, Capability, CloseRtpConnect, CodecDesc, CodecDescRef, Filename, FilenameRef, Header, LengthMismatch,
+        LengthPolicy, MCodeType, MediaInfoRef, Message, OpenRtpConnect, OpenRtpConnectRef, PacketRef, Play, PlayAck,
+        PlayAckRef, PlayRef, PortRange, Register, RegisterRef, RequestChannel, RequestChannelAck, RequestChannelAckRef, RequestChannelRef,
+        ResFromTagRef, RtpInfo, RtpInfoRef, SetupRole, split_packets, TagIter, TagRef, TagType, WireParse, HEADER_LENGTH, MCODE_TABLE,
+        FileFormat, FileFormatCode, IceCode, IceType, MCode, MediaCode, MediaType, PayloadSupport, RtpMediaType, RtpMediaTypeCode,
+    };
+    use proptest::{collection::vec, prelude::*, sample::select};
+
+    #[test]
+    fn test_webrtc_info() {
+        let req = RequestChannel {
+            as_call_id: "call-1".into(),
+            webrtc: vec![
+                "null_crypto".into(),
+                "ice_frag:F7gI".into(),
+                "null_ice_pwd".into(),
+                "fingerprint:sha-256 AB:CD".into(),
+                "dtls_roll:client".into(),
+                "ice:0".into(),
+                "candidate:1 1 udp 2130706431 192.168.9.246 5000 typ host".into(),
+                "videoext:0|0|0|0".into(),
+            ],
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        req.write_to(&mut buf);
+
+        let r = RequestChannelRef::parse_from(&buf[..]).unwrap();
+        let webrtc = r.webrtc();
+        assert_eq!(webrtc.crypto, None);
+        assert_eq!(webrtc.ufrag, Some("F7gI"));
+        assert_eq!(webrtc.pwd, None);
+        assert_eq!(webrtc.fingerprint, Some("sha-256 AB:CD"));
+        assert_eq!(webrtc.setup_role(), Some(SetupRole::Active));
+        assert_eq!(webrtc.ice, Some("0"));
+        assert_eq!(webrtc.candidates.len(), 1);
+        assert_eq!(webrtc.unknown, vec![&b"videoext:0|0|0|0"[..]]);
+    }
+
+    #[test]
+    fn test_mcode_table_complete() {
+        for code in 0..=u16::MAX {
+            if let Ok(t) = MCodeType::try_from(code) {
+                assert_eq!(t.info().code, t, "missing table entry for {t:?}");
+            }
+        }
+        let mut codes: Vec<_> = MCODE_TABLE.iter().map(|x| x.code.code()).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), MCODE_TABLE.len());
+    }
+
+    #[test]
+    fn test_payload_support() {
+        // each code decodes as payload_support says, whatever the payload
+        for info in MCODE_TABLE.iter() {
+            let code = info.code;
+            for payload in [&[][..], &[0_u8; 64][..]] {
+                let mut data = Vec::new();
+                Header { code: code.code(), ..Default::default() }.write_to2(&mut data, payload);
+                let r = parse_message(&data[..]);
+                match code.payload_support() {
+                    PayloadSupport::Parsed => assert!(
+                        !matches!(r, Ok(Message::Unknown { .. })),
+                        "{code:?} claims a parser but decodes as Unknown",
+                    ),
+                    PayloadSupport::HeaderOnly => assert!(
+                        matches!(&r, Ok(msg) if msg.code() == code.code() && !matches!(msg, Message::Unknown { .. })),
+                        "{code:?} claims header only but [{:?}]", r.map(|x| format!("{x:?}")),
+                    ),
+                    PayloadSupport::Unsupported => assert!(
+                        matches!(r, Ok(Message::Unknown { code: x, .. }) if x == code.code()),
+                        "{code:?} has a parser, mark it Parsed",
+                    ),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_enum_round_trip() {
+        // every number maps to at most one variant and back, others are Unknown
+        let mut known = 0;
+        for n in 0..=u16::MAX {
+            let code = MCode::new(n);
+            match code.as_type() {
+                Some(t) => {
+                    known += 1;
+                    assert_eq!(t.code(), n);
+                    assert_eq!(format!("{code:?}"), format!("{t:?}(0x{n:04x})"));
+                    assert_eq!(MCodeType::from_name(&format!("{t:?}")), Some(t));
+                    assert_eq!(MCodeType::from_name(&format!("0x{n:x}")), Some(t));
+                },
+                None => assert_eq!(format!("{code:?}"), format!("Unknown(0x{n:04x})")),
+            }
+        }
+        assert_eq!(known, MCODE_TABLE.len());
+
+        fn check_u8<T: TryFrom<u8> + Copy + core::fmt::Debug>(num: fn(T) -> u8, variants: &[T]) {
+            let mut known = 0;
+            for n in 0..=u8::MAX {
+                let code = crate::utils::common::EnumNum::<u8, T>::new(n);
+                match code.as_type() {
+                    Some(t) => {
+                        known += 1;
+                        assert_eq!(num(t), n);
+                        assert_eq!(format!("{code:?}"), format!("{t:?}({n})"));
+                    },
+                    None => assert_eq!(format!("{code:?}"), format!("Unknown({n})")),
+                }
+            }
+            assert_eq!(known, variants.len(), "{variants:?}");
+        }
+        check_u8(|x: IceType| x as u8, &[IceType::Simple, IceType::Webrtc, IceType::StunOnly]);
+        check_u8(|x: MediaType| x as u8, &[
+            MediaType::AudioOnly, MediaType::AudioVideo, MediaType::Image, MediaType::Agora, MediaType::Rtmp,
+            MediaType::TRtc, MediaType::TRtcVideo, MediaType::BRtc, MediaType::VideoOnly, MediaType::PRtc,
+        ]);
+        check_u8(|x: FileFormat| x as u8, &[FileFormat::Wav, FileFormat::Pcm, FileFormat::Amr, FileFormat::Mp4, FileFormat::Jpg]);
+        check_u8(|x: RtpMediaType| x as u8, &[RtpMediaType::Audio, RtpMediaType::Video, RtpMediaType::T38]);
+        check_u8(|x: TagType| x.code(), &[
+            TagType::MEDIAINFO, TagType::FILENAME, TagType::RTPINFO, TagType::CAPABILITY,
+            TagType::VERSION, TagType::CAPACITY, TagType::PORTRANGE,
+        ]);
+        // aliases agree with the generic ones
+        assert_eq!(IceCode::new(1).as_type(), Some(IceType::Webrtc));
+        assert_eq!(MediaCode::new(8).as_type(), Some(MediaType::Rtmp));
+        assert_eq!(FileFormatCode::new(100).as_type(), Some(FileFormat::Wav));
+        assert_eq!(RtpMediaTypeCode::new(2).as_type(), Some(RtpMediaType::T38));
+    }
+
+    #[test]
+    fn test_request_channel_round_trip() {
+        let req = RequestChannel {
+            ice_type: 1,
+            life_seconds: 300,
+            media_type: 2,
+            as_call_id: "call-1".into(),
+            agora_info: None,
+            is_nbup: false,
+            ptime: 20,
+            is_caller: true,
+            codec: 8,
+            amr_mode: 7,
+            webrtc: vec!["null_crypto".into(), "encode:0".into()],
+        };
+
+        let mut buf = Vec::new();
+        let len = req.write_to(&mut buf);
+        assert_eq!(len, buf.len());
+
+        let r = RequestChannelRef::parse_from(&buf[..]).unwrap();
+        assert_eq!(r.part2().ptime(), 20);
+        assert_eq!(r.part2().codec_code(), 8);
+        assert_eq!(r.part2().amr_mode(), 7);
+        assert!(r.part2().is_caller());
+
+        // agora_info goes with the media type, as the parser expects it
+        for (media_type, info, parsed) in [(4, None, Some("")), (7, Some("token"), Some("token")), (1, Some("token"), None)] {
+            let req = RequestChannel { media_type, agora_info: info.map(Into::into), ..req.clone() };
+            let mut buf = Vec::new();
+            req.write_to(&mut buf);
+            let r = RequestChannelRef::parse_from(&buf[..]).unwrap();
+            assert_eq!(r.agora_info().map(|x| x.to_string()).as_deref(), parsed, "{media_type}");
+            assert_eq!(r.part2().codec_code(), 8);
+        }
+    }
+
+    #[test]
+    fn test_play_round_trip() {
+        let play = Play {
+            play_times: 2,
+            max_duration: 60,
+            files: vec![
+                Filename { format: 100, filename: "file://cc/11000.wav".into() },
+                Filename { format: 100, filename: "file://cc/11001.wav".into() },
+            ],
+            ..Default::default()
+        };
+
+        let mut buf = Vec::new();
+        let len = play.write_to(&mut buf);
+        assert_eq!(len, buf.len());
+
+        let r = PlayRef::parse_from(&buf[..]).unwrap();
+        assert_eq!(r.part1.play_times(), 2);
+        assert_eq!(r.part1.num_tlv(), 2);
+        let files: Vec<_> = r.tags.clone()
+        .map(|x| FilenameRef::parse_from(x.unwrap().payload()).unwrap().format())
+        .collect();
+        assert_eq!(files, vec![100, 100]);
+    }
+
+    #[test]
+    fn test_register_trailing_tags() {
+        let mut data = vec![192, 168, 9, 246];
+
+        let media_info = b"\x01\x01\x00\x00PCMU/8000\x00\x00\x00\xaa";
+        data.push(TagType::MEDIAINFO.code());
+        data.extend_from_slice(&(media_info.len() as u16).to_be_bytes());
+        data.extend_from_slice(media_info);
+
+        let version = b"V2.1.0\x00";
+        data.push(0x40);
+        data.extend_from_slice(&(version.len() as u16).to_be_bytes());
+        data.extend_from_slice(version);
+
+        let reg = RegisterRef::parse_from(&data[..]).unwrap();
+        assert_eq!(reg.media_info.audio_codecs.len(), 1);
+        assert_eq!(reg.media_info_remains, &[0xaa]);
+
+        let tags: Vec<_> = reg.tags().collect::<Result<_, _>>().unwrap();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].tag_code(), 0x40);
+        assert_eq!(tags[0].payload(), version);
+
+        let text = format!("{reg:?}");
+        assert!(text.contains("V2.1.0"), "{text}");
+        assert_eq!((reg.version(), reg.capacity(), reg.port_range()), (None, None, None));
+
+        let register = Register {
+            version: Some("V2.1.0".into()),
+            capacity: Some(2000),
+            port_range: Some(PortRange { min: 20000, max: 29999 }),
+            ..Default::default()
+        };
+        let mut data = Vec::new();
+        assert_eq!(register.write_to(&mut data), data.len());
+        let reg = RegisterRef::parse_from(&data[..]).unwrap();
+        assert_eq!(reg.version().as_deref(), Some("V2.1.0"));
+        assert_eq!(reg.capacity(), Some(2000));
+        assert_eq!(reg.port_range(), Some(PortRange { min: 20000, max: 29999 }));
+        let text = format!("{reg:?}");
+        assert!(text.contains("VERSION") && text.contains("max: 29999"), "{text}");
+    }
+
+    #[test]
+    fn test_ms_side_round_trip() {
+        let register = Register {
+            ip: [10, 0, 0, 1].into(),
+            audio_codecs: vec![CodecDesc { index: 8, payload_type: 8, mapstr: "PCMA/8000".into() }],
+            capability: Some(Capability { flags: Capability::ZLIB }),
+            ..Default::default()
+        };
+        let mut data = Vec::new();
+        assert_eq!(register.write_to(&mut data), data.len());
+        let reg = RegisterRef::parse_from(&data[..]).unwrap();
+        assert_eq!(reg.ip, register.ip);
+        assert!(!reg.media_info.support_t38);
+        assert_eq!(reg.media_info.audio_codecs[0].map_str_utf8(), Some("PCMA/8000"));
+        assert!(reg.media_info_remains.is_empty());
+        assert_eq!(reg.capabilities(), Capability::ZLIB);
+
+        let mut data = Vec::new();
+        let ack = RequestChannelAck { audio_port: 20000, media_type: 1, ..Default::default() };
+        assert_eq!(ack.write_to(&mut data), data.len());
+        let r = RequestChannelAckRef::parse_from(&data[..]).unwrap();
+        assert_eq!((r.part1().result(), r.part1().audio_port(), r.part1().media_type()), (0, 20000, 1));
+
+        let mut data = Vec::new();
+        PlayAck { result: 0, play_duration: 1500 }.write_to(&mut data);
+        let r = PlayAckRef::parse_from(&data[..]).unwrap();
+        assert_eq!(r.part1().play_duration(), 1500);
+
+        let mut data = Vec::new();
+        let open = OpenRtpConnect { rtpinfos: vec![RtpInfo::plain([10, 0, 0, 2].into(), 4000, 8)] };
+        assert_eq!(open.write_to(&mut data), data.len());
+        let r = OpenRtpConnectRef::parse_from(&data[..]).unwrap();
+        let infos: Vec<_> = r.rtpinfo_iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(infos.len(), 1);
+        assert_eq!((infos[0].part1().ip(), infos[0].part1().port()), (IpAddr::from([10, 0, 0, 2]), 4000));
+        assert_eq!(infos[0].part1().nego_pltyp(), 8);
+        assert!(infos[0].webrtc().unknown.is_empty());
+    }
+
+    #[test]
+    fn test_wire_parse_consumed() {
+        let mut data = Vec::new();
+        let first = Filename { format: 1, filename: "a.wav".into() }.write_tag_to(&mut data);
+        Capability { flags: Capability::FRAGMENT }.write_tag_to(&mut data);
+        let tags: Vec<TagRef> = parse_seq(&data[..]).unwrap();
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[1].tag_type(), Some(TagType::CAPABILITY));
+
+        let (n, file) = FilenameRef::parse(tags[0].payload()).unwrap();
+        assert_eq!((n, file.filename().decode().as_deref()), (first - 3, Some("a.wav")));
+
+        let mut data = Vec::new();
+        Header { code: MCodeType::HEARTBEAT.code(), ..Default::default() }.write_to(&mut data);
+        Header { code: MCodeType::CLOSERTPCONNECT.code(), ..Default::default() }.write_to2(&mut data, &[7_u8][..]);
+        let packets: Vec<PacketRef> = parse_seq(&data[..]).unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[1].payload(), &[7]);
+        let (n, close) = CloseRtpConnect::parse(packets[1].payload()).unwrap();
+        assert_eq!((n, close.value()), (1, 7));
+
+        assert!(parse_seq::<TagRef>(&[0x41, 0, 4, 0]).is_err());
+    }
+
+    #[test]
+    fn test_split_packets() {
+        let mut data = Vec::new();
+        Header { code: MCodeType::PLAY_ACK.code(), fsm_id: 1, ..Default::default() }.write_to2(&mut data, &[0_u8; 6][..]);
+        Header { code: MCodeType::HEARTBEAT.code(), fsm_id: 2, ..Default::default() }.write_to(&mut data);
+        Header { code: MCodeType::CANCEL.code(), fsm_id: 3, ..Default::default() }.write_to2(&mut data, &[0_u8, 3][..]);
+        data.extend_from_slice(b"/cin/mscn3\0");
+
+        let packets = PacketRef::parse_all(&data[..]).unwrap();
+        assert_eq!(packets.iter().map(|x| x.fsm_id()).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(packets[0].payload().len(), 6);
+        // cn path stays with the last one
+        assert_eq!(packets[2].cn_path_utf8().unwrap(), "/cin/mscn3\0");
+
+        // a single packet or garbage is yielded whole
+        assert_eq!(split_packets(&data[..18]).collect::<Vec<_>>(), [&data[..18]]);
+        assert_eq!(split_packets(&data[..20]).collect::<Vec<_>>(), [&data[..20]]);
+        assert_eq!(split_packets(&[0, 1, 2]).collect::<Vec<_>>(), [&[0_u8, 1, 2][..]]);
+        assert_eq!(split_packets(&[]).count(), 0);
+
+        let mut split = split_packets(&data[..]);
+        assert_eq!(split.next(), Some(&data[..18]));
+        assert_eq!(split.rest(), &data[18..]);
+    }
+
+    #[test]
+    fn test_length_policy() {
+        let mut data = Vec::new();
+        Header { code: MCodeType::CANCEL.code(), ..Default::default() }.write_to2(&mut data, &[0_u8, 3][..]);
+        let exact = data.clone();
+        data.extend_from_slice(b"/cin/mscn3\0");
+
+        let p = PacketRef::parse_with(&exact[..], LengthPolicy::Strict).unwrap();
+        assert_eq!((p.payload(), p.length_mismatch()), (&[0_u8, 3][..], None));
+
+        // trailing bytes
+        assert!(PacketRef::parse_with(&data[..], LengthPolicy::Strict).is_err());
+        let p = PacketRef::parse_with(&data[..], LengthPolicy::Truncate).unwrap();
+        assert_eq!(p.payload(), &[0, 3]);
+        assert_eq!(p.length_mismatch(), Some(LengthMismatch { declared: 14, actual: 25 }));
+        assert!(format!("{p:?}").contains("length_mismatch"));
+        assert_eq!(PacketRef::parse_with(&data[..], LengthPolicy::Extend).unwrap().payload().len(), 13);
+        assert!(PacketRef::parse_from(&data[..]).is_ok());
+
+        // datagram shorter than declared
+        let short = &exact[..13];
+        assert!(PacketRef::parse_from(short).is_err());
+        assert!(PacketRef::parse_with(short, LengthPolicy::Strict).is_err());
+        assert_eq!(PacketRef::parse_with(short, LengthPolicy::Truncate).unwrap().payload(), &[0]);
+        assert_eq!(PacketRef::parse_with(short, LengthPolicy::Extend).unwrap().payload(), &[0]);
+
+        // declared shorter than header
+        let mut bad = exact.clone();
+        bad[1] = 4;
+        assert!(PacketRef::parse_with(&bad[..], LengthPolicy::Extend).is_err());
+    }
+
+    #[test]
+    fn test_parse_message() {
+        let mut payload = Vec::new();
+        Play { play_times: 1, files: vec![Filename { format: 100, filename: "welcome.wav".into() }], ..Default::default() }.write_to(&mut payload);
+        let mut data = Vec::new();
+        Header { code: MCodeType::PLAY.code(), fsm_id: 123, ..Default::default() }.write_to2(&mut data, &payload[..]);
+        data.extend_from_slice(b"/cin/mscn3\0");
+
+        let msg = parse_message(&data[..]).unwrap();
+        assert_eq!(msg.code(), MCodeType::PLAY.code());
+        let Message::Play(play) = msg else { panic!("{msg:?}") };
+        assert_eq!(play.part1().play_times(), 1);
+
+        let mut data = Vec::new();
+        Header { code: MCodeType::RELEASECHANNEL.code(), ..Default::default() }.write_to(&mut data);
+        assert!(matches!(parse_message(&data[..]).unwrap(), Message::ReleaseChannel));
+
+        let mut data = Vec::new();
+        Header { code: 0x7777, ..Default::default() }.write_to2(&mut data, &[1_u8, 2][..]);
+        let msg = parse_message(&data[..]).unwrap();
+        assert!(matches!(msg, Message::Unknown { code: 0x7777, payload: [1, 2] }));
+        assert!(format!("{msg:?}").starts_with("Unknown"), "{msg:?}");
+
+        // known code, short payload
+        let mut data = Vec::new();
+        Header { code: MCodeType::PLAY_ACK.code(), ..Default::default() }.write_to2(&mut data, &[0_u8][..]);
+        let err = parse_message(&data[..]).unwrap_err();
+        assert!(format!("{err:#}").contains("invalid PlayAck packet"));
+    }
+
+    #[test]
+    fn test_format_compact() {
+        let mut payload = Vec::new();
+        Play { play_times: 1, files: vec![Filename { format: 100, filename: "welcome.wav".into() }], ..Default::default() }.write_to(&mut payload);
+        let mut data = Vec::new();
+        Header { code: MCodeType::PLAY.code(), fsm_id: 123, sn: 4, ..Default::default() }.write_to2(&mut data, &payload[..]);
+        let packet = PacketRef::parse_from(&data[..]).unwrap();
+        assert_eq!(packet.format_compact(), "PLAY fsm=123 sn=4 file=welcome.wav times=1");
+        assert_eq!(parse_message(&data[..]).unwrap().to_string(), "PLAY file=welcome.wav times=1");
+
+        let mut payload = Vec::new();
+        Register {
+            ip: [10, 0, 0, 1].into(),
+            audio_codecs: vec![
+                CodecDesc { index: 0, payload_type: 0, mapstr: "PCMU/8000".into() },
+                CodecDesc { index: 8, payload_type: 8, mapstr: "PCMA/8000".into() },
+            ],
+            capability: Some(Capability { flags: Capability::ZLIB }),
+            ..Default::default()
+        }.write_to(&mut payload);
+        let mut data = Vec::new();
+        Header { code: MCodeType::REGISTER.code(), fsm_id: 5000000, key: 2, ..Default::default() }.write_to2(&mut data, &payload[..]);
+        assert_eq!(PacketRef::parse_from(&data[..]).unwrap().to_string(), "REGISTER fsm=5000000 sn=0 key=2 ip=10.0.0.1 audio=PCMU/8000,PCMA/8000 caps=0x1");
+
+        let mut data = Vec::new();
+        Header { code: MCodeType::CANCEL.code(), fsm_id: 1, ..Default::default() }.write_to2(&mut data, &[0_u8, 3][..]);
+        assert_eq!(PacketRef::parse_from(&data[..]).unwrap().to_string(), "CANCEL fsm=1 sn=0 op=PLAY");
+
+        let mut data = Vec::new();
+        Header { code: MCodeType::PLAY_ACK.code(), fsm_id: 1, ..Default::default() }.write_to2(&mut data, &[0_u8][..]);
+        assert_eq!(PacketRef::parse_from(&data[..]).unwrap().to_string(), "PLAY_ACK fsm=1 sn=0 invalid-payload");
+
+        let mut data = Vec::new();
+        Header { code: 0x7777, fsm_id: 1, ..Default::default() }.write_to(&mut data);
+        assert_eq!(PacketRef::parse_from(&data[..]).unwrap().to_string(), "0x7777 fsm=1 sn=0 len=0");
+    }
+
+    /// every parser on data, and Debug/Display of whatever parsed
+    fn parse_everything(data: &[u8]) {
+        for policy in [LengthPolicy::Strict, LengthPolicy::Truncate, LengthPolicy::Extend] {
+            if let Ok(packet) = PacketRef::parse_with(data, policy) {
+                let _r = format!("{packet:?} {packet} {:?}", packet.cn_path_data());
+                if let Ok(msg) = Message::from_packet(&packet) {
+                    let _r = format!("{msg:?} {msg}");
+                }
+            }
+        }
+
+        macro_rules! fmt_both {
+            ($($type_name:ident),*) => { $(
+                if let Ok(x) = $type_name::parse_from(data) {
+                    let _r = format!("{x:?} {x}");
+                }
+            )* };
+        }
+        fmt_both!(RegisterRef, RequestChannelRef, RequestChannelAckRef, OpenRtpConnectRef, PlayRef, PlayAckRef, CancelRef, ResFromTagRef);
+        if let Ok(x) = RtpInfoRef::parse_from(data) {
+            let _r = format!("{x:?} {x} {:?}", x.webrtc());
+        }
+        if let Ok(x) = FilenameRef::parse_from(data) {
+            let _r = format!("{x:?}");
+        }
+        if let Ok((_n, x)) = MediaInfoRef::parse_from(data) {
+            let _r = format!("{x:?}");
+        }
+        let _r = format!("{:?}", TagIter::new(data));
+        let _r = parse_seq::<PacketRef>(data).map(|x| format!("{x:?}"));
+        let _r = parse_seq::<TagRef>(data).map(|x| format!("{x:?}"));
+        let _r = parse_seq::<CodecDescRef>(data).map(|x| format!("{x:?}"));
+        let _r = parse_seq::<FilenameRef>(data).map(|x| format!("{x:?}"));
+        let _r = parse_seq::<ResFromTagRef>(data).map(|x| format!("{x:?}"));
+    }
+
+    /// well formed payloads to mutate, by code
+    fn seed_payloads() -> Vec<(MCodeType, Vec<u8>)> {
+        let mut seeds = Vec::new();
+        let mut push = |code, write: &dyn Fn(&mut Vec<u8>)| {
+            let mut payload = Vec::new();
+            write(&mut payload);
+            seeds.push((code, payload));
+        };
+        push(MCodeType::REGISTER, &|b| { Register {
+            audio_codecs: vec![CodecDesc { index: 8, payload_type: 8, mapstr: "PCMA/8000".into() }],
+            capability: Some(Capability { flags: Capability::ZLIB }),
+            ..Default::default()
+        }.write_to(b); });
+        push(MCodeType::REQUESTCHANNEL, &|b| { RequestChannel {
+            media_type: 4,
+            as_call_id: "call-1".into(),
+            agora_info: Some("token".into()),
+            webrtc: vec!["null_crypto".into(), "ice_frag:F7gI".into(), "candidate:1 udp".into()],
+            ..Default::default()
+        }.write_to(b); });
+        push(MCodeType::REQUESTCHANNEL_ACK, &|b| { RequestChannelAck { webrtc: vec!["dtls_roll:client".into()], ..Default::default() }.write_to(b); });
+        push(MCodeType::OPENRTPCONNECT, &|b| { OpenRtpConnect { rtpinfos: vec![RtpInfo::plain([10, 0, 0, 1].into(), 20000, 8)] }.write_to(b); });
+        push(MCodeType::PLAY, &|b| { Play {
+            files: vec![Filename { format: 100, filename: "rtmp://host/app/key".into() }, Filename { format: 100, filename: "welcome.wav".into() }],
+            ..Default::default()
+        }.write_to(b); });
+        push(MCodeType::PLAY_ACK, &|b| { PlayAck { result: 0, play_duration: 300 }.write_to(b); });
+        push(MCodeType::RESFROMTAG, &|b| b.extend_from_slice(b"yes\0"));
+        push(MCodeType::CANCEL, &|b| b.extend_from_slice(&[0, 3]));
+        seeds
+    }
+
+    #[test]
+    fn test_parse_edge_cases() {
+        // declared length beyond datagram is kept by Truncate
+        let mut data = Vec::new();
+        Header { code: MCodeType::PLAY.code(), ..Default::default() }.write_to2(&mut data, &[0_u8; 8][..]);
+        let packet = PacketRef::parse_with(&data[..14], LengthPolicy::Truncate).unwrap();
+        assert!(packet.cn_path_data().is_empty());
+        data.extend_from_slice(b"/tmp/mscn1");
+        assert_eq!(PacketRef::parse_from(&data[..]).unwrap().cn_path_utf8().unwrap(), "/tmp/mscn1");
+
+        assert!(CodecDescRef::parse_vec_from(&[]).is_err());
+        assert!(MediaInfoRef::parse_from(&[0, 0, 0, 1]).is_err());
+    }
+
+    proptest! {
+        // inputs up to 64KiB, fewer cases keep debug test runs short
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn prop_parse_no_panic(data in prop_oneof![vec(any::<u8>(), 0..256), vec(any::<u8>(), 0..=65536)]) {
+            parse_everything(&data);
+        }
+
+        #[test]
+        fn prop_parse_packet_no_panic(code in select(MCODE_TABLE.iter().map(|x| x.code.code()).collect::<Vec<_>>()), payload in vec(any::<u8>(), 0..=65535 - HEADER_LENGTH + 2)) {
+            let mut data = Vec::new();
+            Header { code, fsm_id: 1, sn: 1, key: 0 }.write_to2(&mut data, &payload[..]);
+            parse_everything(&data);
+            parse_everything(&payload);
+        }
+
+        #[test]
+        fn prop_parse_mutated_no_panic(
+            seed in any::<prop::sample::Index>(),
+            edits in vec((any::<prop::sample::Index>(), any::<u8>()), 1..8),
+            cut in any::<prop::sample::Index>(),
+        ) {
+            let seeds = seed_payloads();
+            let (code, mut payload) = seed.get(&seeds).clone();
+            for (at, byte) in edits.iter() {
+                let n = at.index(payload.len());
+                // nulls and tag/length-ish bytes shift where strings and tags end
+                payload[n] = *byte;
+            }
+            payload.truncate(cut.index(payload.len() + 1));
+            let mut data = Vec::new();
+            Header { code: code.code(), ..Default::default() }.write_to2(&mut data, &payload[..]);
+            parse_everything(&data);
+            parse_everything(&payload);
+        }
+    }
+}
+
+
+/// bounded model checking with `cargo kani`, inputs of every length up to N
+#[cfg(kani)]
+mod proofs {
+    use super::{CodecDescRef, LengthPolicy, MediaInfoRef, PacketRef, PlayRef, RequestChannelRef, RtpInfoRef, TagIter};
+
+    fn any_slice<const N: usize>(data: &[u8; N]) -> &[u8] {
+        let len: usize = kani::any();
+        kani::assume(len <= N);
+        &data[..len]
+    }
+
+    #[kani::proof]
+    fn proof_packet() {
+        let data: [u8; 16] = kani::any();
+        let data = any_slice(&data);
+        for policy in [LengthPolicy::Strict, LengthPolicy::Truncate, LengthPolicy::Extend] {
+            if let Ok(packet) = PacketRef::parse_with(data, policy) {
+                let _r = (packet.to_header(), packet.payload(), packet.cn_path_data());
+            }
+        }
+    }
+
+    #[kani::proof]
+    #[kani::unwind(10)]
+    fn proof_media_info() {
+        let data: [u8; 8] = kani::any();
+        let _r = CodecDescRef::parse_vec_from(any_slice(&data));
+        let _r = MediaInfoRef::parse_from(any_slice(&data));
+    }
+
+    #[kani::proof]
+    #[kani::unwind(10)]
+    fn proof_tags() {
+        let data: [u8; 8] = kani::any();
+        for tag in TagIter::new(any_slice(&data)) {
+            let _r = tag.map(|x| x.payload().len());
+        }
+    }
+
+    #[kani::proof]
+    #[kani::unwind(22)]
+    fn proof_fixed_parts() {
+        let data: [u8; 20] = kani::any();
+        let data = any_slice(&data);
+        if let Ok(r) = RequestChannelRef::parse_from(data) {
+            let _r = (r.part1().life_seconds(), r.part2().amr_mode());
+        }
+        if let Ok(r) = RtpInfoRef::parse_from(data) {
+            let _r = (r.part1().port(), r.part2().direction());
+        }
+        if let Ok(r) = PlayRef::parse_from(data) {
+            let _r = (r.part1().num_tlv(), r.files().count());
+        }
+    }
+}
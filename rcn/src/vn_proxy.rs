@@ -0,0 +1,671 @@
+//! unix datagram proxy between CNs and a MS living in different CINDIRs.
+//!
+//! CNs bind `cn_dir/mscn{id}` and send to `cn_dir/msvn` which is the proxy.
+//! For each CN the proxy binds `ms_dir/mscn{id}` and forwards to `ms_dir/msvn`,
+//! so the MS answers the proxy which forwards back to the CN.
+//! Every packet passes through [`Chaos`] on the way, and [`Reaction`]
+//! tracks what each end was actually given so stuck requests show up.
+//!
+//! With a shadow MS, see vn_shadow, CN packets also go to it as received
+//! and its ACKs are compared to those of the primary MS, not forwarded.
+//!
+//! Datagrams are parsed for stats only and forwarded as received, never
+//! re-encoded, so unknown codes, unknown tags and even garbage reach the
+//! other end byte for byte unless chaos corrupts them.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, Context};
+use tokio::net::UnixDatagram;
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    utils::recv_buf::RecvBuf,
+    vn_capture::{join_taps, CaptureDir, CaptureRecord, RecordTap},
+    vn_chaos::{Chaos, Verdict},
+    vn_proto::{MCodeType, PacketRef, HEADER_LENGTH},
+    vn_session::{bind_socket, ms_socket_path},
+    vn_shadow::{ShadowConfig, ShadowDiff, ShadowStats, Side},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProxyDir {
+    CnToMs,
+    MsToCn,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DirStats {
+    pub received: u64,
+    pub forwarded: u64,
+    pub dropped: u64,
+    pub delayed: u64,
+    pub corrupted: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    /// same code, fsm_id and sn seen again, i.e. sender retransmitted
+    pub retransmits: u64,
+    /// not a VN packet, forwarded all the same
+    pub unparsed: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProxyStats {
+    pub cn_to_ms: DirStats,
+    pub ms_to_cn: DirStats,
+    pub reaction: Reaction,
+    /// with a shadow MS
+    pub shadow: Option<ShadowStats>,
+}
+
+impl ProxyStats {
+    fn dir_mut(&mut self, dir: ProxyDir) -> &mut DirStats {
+        match dir {
+            ProxyDir::CnToMs => &mut self.cn_to_ms,
+            ProxyDir::MsToCn => &mut self.ms_to_cn,
+        }
+    }
+
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines: Vec<String> = [("cn->ms", &self.cn_to_ms), ("ms->cn", &self.ms_to_cn)].iter()
+        .map(|(name, x)| format!(
+            "{name}: received {}, forwarded {}, dropped {}, delayed {}, corrupted {}, duplicated {}, reordered {}, retransmits {}, unparsed {}",
+            x.received, x.forwarded, x.dropped, x.delayed, x.corrupted, x.duplicated, x.reordered, x.retransmits, x.unparsed,
+        ))
+        .collect();
+        lines.extend(self.reaction.summary());
+        if let Some(shadow) = &self.shadow {
+            lines.extend(shadow.summary());
+        }
+        lines
+    }
+}
+
+/// how both ends look from what was actually delivered to them
+#[derive(Debug, Clone, Default)]
+pub struct Reaction {
+    /// (fsm_id, request code) delivered to MS whose ACK never reached CN
+    pending: BTreeMap<(u32, u16), u64>,
+    /// ACKs delivered to CN without a pending request
+    pub unsolicited_acks: u64,
+    /// packets delivered for a fsm_id after its RELEASECHANNEL reached MS
+    pub after_release: u64,
+    released: BTreeSet<u32>,
+}
+
+impl Reaction {
+    fn on_delivered(&mut self, dir: ProxyDir, code: u16, fsm_id: u32) {
+        let Ok(code) = MCodeType::try_from(code) else { return };
+        if self.released.contains(&fsm_id) && code != MCodeType::RELEASECHANNEL {
+            self.after_release += 1;
+        }
+
+        match dir {
+            ProxyDir::CnToMs => {
+                if code == MCodeType::RELEASECHANNEL {
+                    self.released.insert(fsm_id);
+                }
+                if code.ack().is_some() {
+                    *self.pending.entry((fsm_id, code.code())).or_default() += 1;
+                }
+            },
+            ProxyDir::MsToCn => {
+                if code == MCodeType::REQUESTCHANNEL_ACK {
+                    // fsm_id may be reused by a new call
+                    self.released.remove(&fsm_id);
+                }
+                let Some(request) = code.request() else { return };
+                match self.pending.get_mut(&(fsm_id, request.code())) {
+                    Some(n) => {
+                        *n -= 1;
+                        if *n == 0 {
+                            self.pending.remove(&(fsm_id, request.code()));
+                        }
+                    },
+                    None => self.unsolicited_acks += 1,
+                }
+            },
+        }
+    }
+
+    /// requests still waiting for ACK, the state CN and MS may disagree on
+    pub fn unanswered(&self) -> Vec<(u32, MCodeType)> {
+        self.pending.keys()
+        .filter_map(|(fsm_id, code)| MCodeType::try_from(*code).ok().map(|x| (*fsm_id, x)))
+        .collect()
+    }
+
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "reaction: unanswered requests {}, unsolicited acks {}, after release {}",
+            self.pending.len(), self.unsolicited_acks, self.after_release,
+        )];
+        lines.extend(self.unanswered().iter().map(|(fsm_id, code)| format!("  unanswered {code:?} fsm_id [{fsm_id}]")));
+        lines
+    }
+}
+
+pub struct ProxyConfig {
+    /// where CNs are, proxy binds msvn here
+    pub cn_dir: PathBuf,
+    /// where MS is, proxy binds mscn{id} here
+    pub ms_dir: PathBuf,
+    pub chaos: Chaos,
+    /// second MS getting the same requests, answers compared not forwarded
+    pub shadow: Option<ShadowConfig>,
+}
+
+struct Outgoing {
+    socket: Arc<UnixDatagram>,
+    target: PathBuf,
+    data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Window {
+    held: Vec<Outgoing>,
+    /// bumped on each release so stale flush timers do nothing
+    generation: u64,
+}
+
+#[derive(Default)]
+struct State {
+    stats: ProxyStats,
+    /// last sn per (code, fsm_id) of each direction
+    last_sn: HashMap<(ProxyDir, u16, u32), u16>,
+    windows: HashMap<ProxyDir, Window>,
+    shadow: Option<ShadowDiff>,
+}
+
+#[derive(Clone)]
+struct Forwarder {
+    chaos: Chaos,
+    started: Instant,
+    state: Arc<Mutex<State>>,
+    /// packets as forwarded
+    tap: Option<RecordTap>,
+}
+
+impl Forwarder {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// packet of a MS as it sent it, before chaos
+    fn shadow_observe(&self, side: Side, data: &[u8]) {
+        if let Some(shadow) = &mut self.lock().shadow {
+            shadow.on_packet(side, data);
+        }
+    }
+
+    /// apply chaos then send data from socket to target,
+    /// data is only looked at, what goes out is data as is
+    async fn forward(&self, dir: ProxyDir, socket: &Arc<UnixDatagram>, mut data: Vec<u8>, target: &Path) {
+        let (code, fsm_id, sn) = match PacketRef::parse_from(&data) {
+            Ok(packet) => (packet.code(), packet.fsm_id(), Some(packet.sn())),
+            Err(_e) => (0, 0, None),
+        };
+
+        let verdict = self.chaos.decide(data.len(), HEADER_LENGTH, self.started.elapsed());
+        let (delay, corrupt) = {
+            let mut state = self.lock();
+            let retransmit = sn.is_some_and(|sn| state.last_sn.insert((dir, code, fsm_id), sn) == Some(sn));
+            let stats = state.stats.dir_mut(dir);
+            stats.received += 1;
+            if sn.is_none() {
+                stats.unparsed += 1;
+            }
+            if retransmit {
+                stats.retransmits += 1;
+            }
+            match verdict {
+                Verdict::Drop => {
+                    stats.dropped += 1;
+                    debug!("chaos drop {dir:?}, bytes [{}]", data.len());
+                    return
+                },
+                Verdict::Deliver { delay, corrupt } => {
+                    if !delay.is_zero() {
+                        stats.delayed += 1;
+                    }
+                    if corrupt.is_some() {
+                        stats.corrupted += 1;
+                    }
+                    (delay, corrupt)
+                },
+            }
+        };
+
+        if let Some((pos, mask)) = corrupt {
+            data[pos] ^= mask;
+            debug!("chaos corrupt {dir:?} at [{pos}]");
+        }
+
+        let copies = if self.chaos.duplicate(code) {
+            self.lock().stats.dir_mut(dir).duplicated += 1;
+            debug!("chaos duplicate {dir:?} code [{code:#x}] fsm_id [{fsm_id}]");
+            2
+        } else {
+            1
+        };
+
+        for _ in 0..copies {
+            let out = Outgoing { socket: socket.clone(), target: target.to_path_buf(), data: data.clone() };
+            if self.chaos.reorderable(code) {
+                self.hold(dir, out, delay);
+            } else {
+                self.release(dir).await;
+                self.dispatch(dir, out, delay).await;
+            }
+        }
+    }
+
+    /// put into reorder window, release shuffled when full or on timer
+    fn hold(&self, dir: ProxyDir, out: Outgoing, delay: Duration) {
+        let profile = self.chaos.profile();
+        let (full, generation) = {
+            let mut state = self.lock();
+            let window = state.windows.entry(dir).or_default();
+            window.held.push(out);
+            (window.held.len() >= profile.reorder_window, window.generation)
+        };
+
+        let me = self.clone();
+        let wait = if full { Duration::ZERO } else { Duration::from_millis(profile.reorder_window_ms.max(1)) };
+        tokio::spawn(async move {
+            tokio::time::sleep(wait + delay).await;
+            me.release_generation(dir, Some(generation)).await;
+        });
+    }
+
+    async fn release(&self, dir: ProxyDir) {
+        self.release_generation(dir, None).await
+    }
+
+    async fn release_generation(&self, dir: ProxyDir, generation: Option<u64>) {
+        let (held, order) = {
+            let mut state = self.lock();
+            let Some(window) = state.windows.get_mut(&dir) else { return };
+            if generation.is_some_and(|x| x != window.generation) || window.held.is_empty() {
+                return
+            }
+            window.generation += 1;
+            let held = std::mem::take(&mut window.held);
+            let mut order: Vec<usize> = (0..held.len()).collect();
+            self.chaos.shuffle(&mut order);
+            let moved = order.iter().enumerate().filter(|(i, x)| i != *x).count();
+            state.stats.dir_mut(dir).reordered += moved as u64;
+            (held, order)
+        };
+
+        for i in order {
+            self.send(dir, &held[i]).await;
+        }
+    }
+
+    async fn dispatch(&self, dir: ProxyDir, out: Outgoing, delay: Duration) {
+        if delay.is_zero() {
+            self.send(dir, &out).await;
+        } else {
+            let me = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                me.send(dir, &out).await;
+            });
+        }
+    }
+
+    async fn send(&self, dir: ProxyDir, out: &Outgoing) {
+        match out.socket.send_to(&out.data, &out.target).await {
+            Ok(_n) => {
+                let packet = PacketRef::parse_from(&out.data).ok();
+                {
+                    let mut state = self.lock();
+                    state.stats.dir_mut(dir).forwarded += 1;
+                    if let Some(packet) = &packet {
+                        state.stats.reaction.on_delivered(dir, packet.code(), packet.fsm_id());
+                    }
+                }
+                // Display decodes the payload, only worth it at trace and never under the lock
+                if let Some(packet) = &packet {
+                    trace!("{dir:?} {packet}");
+                }
+                if let Some(tap) = &self.tap {
+                    let dir = match dir {
+                        ProxyDir::CnToMs => CaptureDir::CnToMs,
+                        ProxyDir::MsToCn => CaptureDir::MsToCn,
+                    };
+                    // sent from our socket as far as the receiver knows
+                    let socket = out.socket.local_addr().ok().and_then(|x| x.as_pathname().map(|x| x.to_string_lossy().into_owned()));
+                    tap(&CaptureRecord::new(self.started.elapsed(), dir, socket, &out.data));
+                }
+            },
+            Err(e) => warn!("forward {dir:?} to [{:?}] failed [{e}]", out.target),
+        }
+    }
+}
+
+pub struct Proxy {
+    config: ProxyConfig,
+    forwarder: Forwarder,
+}
+
+impl Proxy {
+    pub fn new(config: ProxyConfig) -> Self {
+        let state = State {
+            shadow: config.shadow.as_ref().map(|x| ShadowDiff::new(&x.ignore)),
+            ..Default::default()
+        };
+        let forwarder = Forwarder {
+            chaos: config.chaos.clone(),
+            started: Instant::now(),
+            state: Arc::new(Mutex::new(state)),
+            tap: None,
+        };
+        Self { config, forwarder }
+    }
+
+    /// gets every packet forwarded, e.g. to stream them live
+    pub fn set_tap(&mut self, tap: Option<RecordTap>) {
+        self.forwarder.tap = tap;
+    }
+
+    /// tap called after those set before
+    pub fn add_tap(&mut self, tap: RecordTap) {
+        self.forwarder.tap = Some(join_taps(self.forwarder.tap.take(), tap));
+    }
+
+    pub fn stats(&self) -> ProxyStats {
+        let state = self.forwarder.lock();
+        let mut stats = state.stats.clone();
+        stats.shadow = state.shadow.as_ref().map(ShadowDiff::stats);
+        stats
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let cn_side = Arc::new(bind_socket(&ms_socket_path(&self.config.cn_dir)).await?);
+        let ms_path = ms_socket_path(&self.config.ms_dir);
+        info!("proxy [{:?}] -> [{ms_path:?}]", ms_socket_path(&self.config.cn_dir));
+
+        // cn path -> socket bound in ms_dir for that cn
+        let mut ms_sides: HashMap<PathBuf, Arc<UnixDatagram>> = HashMap::new();
+        // cn path -> socket bound in shadow ms_dir for that cn
+        let mut shadow_sides: HashMap<PathBuf, Arc<UnixDatagram>> = HashMap::new();
+        let mut recv_buf = RecvBuf::default();
+
+        loop {
+            let (len, from) = cn_side.recv_from(recv_buf.as_mut_slice()).await.with_context(||"recvfrom cn failed")?;
+            if recv_buf.check_truncated(len) {
+                continue
+            }
+            let Some(from) = from.as_pathname().map(|x| x.to_path_buf()) else {
+                warn!("drop packet from unnamed socket");
+                continue
+            };
+
+            let ms_side = match ms_sides.get(&from) {
+                Some(socket) => socket.clone(),
+                None => {
+                    let socket = Arc::new(self.bind_ms_side(&from).await?);
+                    ms_sides.insert(from.clone(), socket.clone());
+                    self.spawn_ms_to_cn(socket.clone(), cn_side.clone(), from.clone());
+                    socket
+                },
+            };
+
+            let data = recv_buf.as_slice()[..len].to_vec();
+            if let Some(shadow) = &self.config.shadow {
+                let shadow_side = match shadow_sides.get(&from) {
+                    Some(socket) => socket.clone(),
+                    None => {
+                        let socket = Arc::new(self.bind_side(&shadow.ms_dir, &from).await?);
+                        shadow_sides.insert(from.clone(), socket.clone());
+                        self.spawn_shadow(socket.clone(), from.clone());
+                        socket
+                    },
+                };
+                let target = ms_socket_path(&shadow.ms_dir);
+                if let Err(e) = shadow_side.send_to(&data, &target).await {
+                    debug!("forward to shadow [{target:?}] failed [{e}]");
+                }
+            }
+            self.forwarder.forward(ProxyDir::CnToMs, &ms_side, data, &ms_path).await;
+        }
+    }
+
+    async fn bind_ms_side(&self, cn_path: &Path) -> Result<UnixDatagram> {
+        self.bind_side(&self.config.ms_dir, cn_path).await
+    }
+
+    /// same file name as the cn socket, under ms_dir
+    async fn bind_side(&self, ms_dir: &Path, cn_path: &Path) -> Result<UnixDatagram> {
+        let name = cn_path.file_name().with_context(||format!("no file name of cn path [{cn_path:?}]"))?;
+        let path = ms_dir.join(name);
+        info!("new cn [{cn_path:?}], ms side [{path:?}]");
+        bind_socket(&path).await
+    }
+
+    /// answers of the shadow MS, compared and dropped
+    fn spawn_shadow(&self, shadow_side: Arc<UnixDatagram>, cn_path: PathBuf) {
+        let forwarder = self.forwarder.clone();
+        tokio::spawn(async move {
+            let mut recv_buf = RecvBuf::default();
+            loop {
+                let len = match shadow_side.recv_from(recv_buf.as_mut_slice()).await {
+                    Ok((len, _from)) => len,
+                    Err(e) => {
+                        warn!("recvfrom shadow ms failed [{e}], stop comparing for [{cn_path:?}]");
+                        break;
+                    },
+                };
+                if recv_buf.check_truncated(len) {
+                    continue
+                }
+                forwarder.shadow_observe(Side::Shadow, &recv_buf.as_slice()[..len]);
+            }
+        });
+    }
+
+    fn spawn_ms_to_cn(&self, ms_side: Arc<UnixDatagram>, cn_side: Arc<UnixDatagram>, cn_path: PathBuf) {
+        let forwarder = self.forwarder.clone();
+        tokio::spawn(async move {
+            let mut recv_buf = RecvBuf::default();
+            loop {
+                let len = match ms_side.recv_from(recv_buf.as_mut_slice()).await {
+                    Ok((len, _from)) => len,
+                    Err(e) => {
+                        warn!("recvfrom ms failed [{e}], stop forwarding to [{cn_path:?}]");
+                        break;
+                    },
+                };
+                if recv_buf.check_truncated(len) {
+                    continue
+                }
+                let data = recv_buf.as_slice()[..len].to_vec();
+                forwarder.shadow_observe(Side::Primary, &data);
+                forwarder.forward(ProxyDir::MsToCn, &cn_side, data, &cn_path).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use tokio::net::UnixDatagram;
+
+    use crate::{
+        utils::rng::SimRng,
+        vn_chaos::{Chaos, ChaosProfile},
+        vn_fields::FieldValue,
+        vn_ms_sim::{MsSim, MsSimConfig},
+        vn_ports::{PortPoolConfig, PortRanges},
+        vn_proto::{Header, MCodeType, RequestChannel},
+        vn_session::{cn_socket_path, ms_socket_path, CnSession},
+        vn_shadow::ShadowConfig,
+    };
+
+    use super::{Proxy, ProxyConfig, ProxyDir, Reaction};
+
+    #[test]
+    fn test_reaction() {
+        let mut reaction = Reaction::default();
+        reaction.on_delivered(ProxyDir::CnToMs, MCodeType::REQUESTCHANNEL.code(), 7);
+        reaction.on_delivered(ProxyDir::CnToMs, MCodeType::PLAY.code(), 7);
+        reaction.on_delivered(ProxyDir::MsToCn, MCodeType::REQUESTCHANNEL_ACK.code(), 7);
+        reaction.on_delivered(ProxyDir::MsToCn, MCodeType::REQUESTCHANNEL_ACK.code(), 7);
+        assert_eq!(reaction.unanswered(), vec![(7, MCodeType::PLAY)]);
+        assert_eq!(reaction.unsolicited_acks, 1);
+
+        reaction.on_delivered(ProxyDir::CnToMs, MCodeType::RELEASECHANNEL.code(), 7);
+        reaction.on_delivered(ProxyDir::MsToCn, MCodeType::PLAY_ACK.code(), 7);
+        assert_eq!(reaction.after_release, 1);
+        assert!(reaction.unanswered().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_forwards_both_ways() {
+        let root = std::env::temp_dir().join(format!("rcn_proxy_{}", std::process::id()));
+        let (cn_dir, ms_dir) = (root.join("cn"), root.join("ms"));
+        std::fs::create_dir_all(&cn_dir).unwrap();
+        std::fs::create_dir_all(&ms_dir).unwrap();
+
+        let mut sim = MsSim::bind(&ms_dir, MsSimConfig::default()).await.unwrap();
+        let sim_task = tokio::spawn(async move { sim.run().await });
+
+        let proxy = Arc::new(Proxy::new(ProxyConfig {
+            cn_dir: cn_dir.clone(),
+            ms_dir: ms_dir.clone(),
+            chaos: Chaos::new(ChaosProfile { delay_ms: 1, ..Default::default() }, &SimRng::new(0)),
+            shadow: None,
+        }));
+        let proxy_task = {
+            let proxy = proxy.clone();
+            tokio::spawn(async move { proxy.run().await })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        let mut session = CnSession::bind(&cn_dir, 5).await.unwrap();
+        session.handshake().await.unwrap();
+        session.accept_register().await.unwrap();
+        let req = RequestChannel { media_type: 1, webrtc: vec!["".into()], ..Default::default() };
+        session.request_channel(session.base_fsm_id() + 1, &req).await.unwrap();
+        session.expect_packet(MCodeType::REQUESTCHANNEL_ACK).await.unwrap();
+        // forwarded is counted right after send_to returns
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let stats = proxy.stats();
+        assert_eq!(stats.cn_to_ms.forwarded, 3);
+        assert_eq!(stats.ms_to_cn.forwarded, 3);
+        assert_eq!(stats.cn_to_ms.dropped, 0);
+
+        proxy_task.abort();
+        sim_task.abort();
+        let _r = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_shadow() {
+        let root = std::env::temp_dir().join(format!("rcn_proxy_shadow_{}", std::process::id()));
+        let (cn_dir, ms_dir, shadow_dir) = (root.join("cn"), root.join("ms"), root.join("shadow"));
+        for dir in [&cn_dir, &ms_dir, &shadow_dir] {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+
+        let mut sim = MsSim::bind(&ms_dir, MsSimConfig::default()).await.unwrap();
+        let sim_task = tokio::spawn(async move { sim.run().await });
+        let ports = PortPoolConfig { ranges: PortRanges(vec![30000..=30099]), ..Default::default() };
+        let mut shadow = MsSim::bind(&shadow_dir, MsSimConfig { ports, ..Default::default() }).await.unwrap();
+        let shadow_task = tokio::spawn(async move { shadow.run().await });
+
+        let proxy = Arc::new(Proxy::new(ProxyConfig {
+            cn_dir: cn_dir.clone(),
+            ms_dir: ms_dir.clone(),
+            chaos: Chaos::new(ChaosProfile::default(), &SimRng::new(0)),
+            shadow: Some(ShadowConfig { ms_dir: shadow_dir.clone(), ignore: vec!["video_port".into(), "fax_port".into()] }),
+        }));
+        let proxy_task = {
+            let proxy = proxy.clone();
+            tokio::spawn(async move { proxy.run().await })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        let mut session = CnSession::bind(&cn_dir, 5).await.unwrap();
+        session.handshake().await.unwrap();
+        session.accept_register().await.unwrap();
+        let req = RequestChannel { media_type: 1, webrtc: vec!["".into()], ..Default::default() };
+        session.request_channel(session.base_fsm_id() + 1, &req).await.unwrap();
+        session.expect_packet(MCodeType::REQUESTCHANNEL_ACK).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        // only the primary answers reach CN
+        assert_eq!(proxy.stats().ms_to_cn.forwarded, 3);
+        let shadow = proxy.stats().shadow.unwrap();
+        assert_eq!((shadow.compared, shadow.diverged), (2, 1));
+        assert_eq!(shadow.divergences[0].field, "audio_port");
+        assert_eq!(shadow.divergences[0].shadow, Some(FieldValue::Int(30000)));
+
+        proxy_task.abort();
+        sim_task.abort();
+        shadow_task.abort();
+        let _r = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_passthrough() {
+        let root = std::env::temp_dir().join(format!("rcn_proxy_raw_{}", std::process::id()));
+        let (cn_dir, ms_dir) = (root.join("cn"), root.join("ms"));
+        std::fs::create_dir_all(&cn_dir).unwrap();
+        std::fs::create_dir_all(&ms_dir).unwrap();
+
+        let proxy = Arc::new(Proxy::new(ProxyConfig {
+            cn_dir: cn_dir.clone(),
+            ms_dir: ms_dir.clone(),
+            chaos: Chaos::new(ChaosProfile::default(), &SimRng::new(0)),
+            shadow: None,
+        }));
+        let proxy_task = {
+            let proxy = proxy.clone();
+            tokio::spawn(async move { proxy.run().await })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        let cn = UnixDatagram::bind(cn_socket_path(&cn_dir, 5).unwrap()).unwrap();
+        let ms = UnixDatagram::bind(ms_socket_path(&ms_dir)).unwrap();
+
+        let mut unknown_code = Vec::new();
+        Header { code: 0x7e01, fsm_id: 5000001, key: 0x1234, sn: 9 }.write_to2(&mut unknown_code, &[0xfe, 0x00, 0x03, 1, 2, 3][..]);
+        let mut unknown_tag = Vec::new();
+        // REGISTER with a tag nobody knows after a short MEDIAINFO
+        Header { code: MCodeType::REGISTER.code(), fsm_id: 5000000, key: 0, sn: 1 }.write_to2(&mut unknown_tag, &[0, 0, 0, 0, 0x01, 0x00, 0x00, 0xee, 0x00, 0x02, 0xaa, 0xbb][..]);
+        let mut trailing = unknown_code.clone();
+        trailing.extend_from_slice(b"/tmp/cin/mscn5");
+        let packets = [unknown_code, unknown_tag, trailing, vec![0xff; 5], vec![0x00, 0x02, 0x00]];
+
+        let mut buf = vec![0_u8; 2048];
+        for data in packets.iter() {
+            cn.send_to(data, ms_socket_path(&cn_dir)).await.unwrap();
+            let (len, from) = ms.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], &data[..]);
+
+            ms.send_to(data, from.as_pathname().unwrap()).await.unwrap();
+            let len = cn.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], &data[..]);
+        }
+        // forwarded is counted right after send_to returns
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let stats = proxy.stats();
+        assert_eq!((stats.cn_to_ms.forwarded, stats.cn_to_ms.unparsed), (5, 2));
+        assert_eq!((stats.ms_to_cn.forwarded, stats.ms_to_cn.unparsed), (5, 2));
+
+        proxy_task.abort();
+        let _r = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,249 @@
+//! redaction of sensitive packet fields, off unless configured.
+//!
+//! Fields are picked by name, e.g. `as_call_id,agora_info,rtmp_key`, and
+//! apply to Debug, Display, field reports and captures alike. Captures are
+//! masked in place so redacted packets still parse.
+
+use core::{fmt, ops::Range, str::FromStr, sync::atomic::{AtomicU32, Ordering}};
+
+use alloc::{borrow::Cow, format, string::String, vec::Vec};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    vn_charset::{decode_str, StrDebug},
+    vn_proto::{MCodeType, PacketRef, PlayRef, RequestChannelRef},
+};
+
+/// shown instead of a redacted value
+pub const REDACTED: &str = "<redacted>";
+
+/// byte written over redacted values in captures
+const MASK: u8 = b'*';
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RedactField {
+    /// as_call_id of REQUESTCHANNEL
+    AsCallId = 0x01,
+    /// agora_info of REQUESTCHANNEL, carries the agora token
+    AgoraInfo = 0x02,
+    /// stream key of rtmp:// filenames, the last path segment
+    RtmpKey = 0x04,
+}
+
+impl RedactField {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::AsCallId => "as_call_id",
+            Self::AgoraInfo => "agora_info",
+            Self::RtmpKey => "rtmp_key",
+        }
+    }
+}
+
+impl FromStr for RedactField {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "as_call_id" => Ok(Self::AsCallId),
+            "agora_info" => Ok(Self::AgoraInfo),
+            "rtmp_key" => Ok(Self::RtmpKey),
+            _ => bail!("unknown redact field [{s}], expect as_call_id, agora_info or rtmp_key"),
+        }
+    }
+}
+
+static REDACT: AtomicU32 = AtomicU32::new(0);
+
+/// fields redacted from now on, replaces earlier setting
+pub fn set_redact(fields: &[RedactField]) {
+    let flags = fields.iter().fold(0, |acc, x| acc | *x as u32);
+    REDACT.store(flags, Ordering::Relaxed);
+}
+
+pub fn is_redacted(field: RedactField) -> bool {
+    REDACT.load(Ordering::Relaxed) & field as u32 != 0
+}
+
+fn any_redacted() -> bool {
+    REDACT.load(Ordering::Relaxed) != 0
+}
+
+/// Debug and Display of a string field, REDACTED if its field is
+pub struct RedactStr<'a>(pub RedactField, pub &'a [u8]);
+
+impl<'a> fmt::Debug for RedactStr<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if is_redacted(self.0) {
+            return f.write_str(REDACTED)
+        }
+        fmt::Debug::fmt(&StrDebug(self.1), f)
+    }
+}
+
+impl<'a> fmt::Display for RedactStr<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if is_redacted(self.0) {
+            return f.write_str(REDACTED)
+        }
+        match decode_str(self.1) {
+            Some(s) => f.write_str(&s),
+            None => f.write_str(&String::from_utf8_lossy(self.1)),
+        }
+    }
+}
+
+/// value of a field or REDACTED
+pub fn redact_str<'a>(field: RedactField, s: &'a str) -> Cow<'a, str> {
+    if is_redacted(field) {
+        return Cow::Borrowed(REDACTED)
+    }
+    Cow::Borrowed(s)
+}
+
+/// byte range of the stream key of an rtmp url, after the last '/' past the host
+fn rtmp_key_range(url: &[u8]) -> Option<Range<usize>> {
+    let scheme = b"rtmp";
+    if url.len() < scheme.len() || !url[..scheme.len()].eq_ignore_ascii_case(scheme) {
+        return None
+    }
+    let host_start = url.windows(3).position(|x| x == b"://")? + 3;
+    let path_start = host_start + url[host_start..].iter().position(|x| *x == b'/')?;
+    let key_start = path_start + 1 + url[path_start + 1..].iter().rposition(|x| *x == b'/').map(|x| x + 1).unwrap_or(0);
+    (key_start < url.len()).then_some(key_start..url.len())
+}
+
+/// filename with its rtmp stream key replaced, as is if not redacted
+pub fn redact_url(url: &str) -> Cow<'_, str> {
+    if !is_redacted(RedactField::RtmpKey) {
+        return Cow::Borrowed(url)
+    }
+    match rtmp_key_range(url.as_bytes()) {
+        Some(range) => Cow::Owned(format!("{}{REDACTED}", &url[..range.start])),
+        None => Cow::Borrowed(url),
+    }
+}
+
+/// Debug and Display of a filename, see redact_url
+pub struct RedactUrl<'a>(pub &'a [u8]);
+
+impl<'a> fmt::Debug for RedactUrl<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match rtmp_key_range(self.0).filter(|_x| is_redacted(RedactField::RtmpKey)) {
+            Some(range) => write!(f, "\"{}{REDACTED}\"", String::from_utf8_lossy(&self.0[..range.start])),
+            None => fmt::Debug::fmt(&StrDebug(self.0), f),
+        }
+    }
+}
+
+impl<'a> fmt::Display for RedactUrl<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = decode_str(self.0).unwrap_or_else(|| String::from_utf8_lossy(self.0));
+        f.write_str(&redact_url(&s))
+    }
+}
+
+/// offset of sub inside data, sub must be a slice of data
+fn range_of(data: &[u8], sub: &[u8]) -> Range<usize> {
+    let start = sub.as_ptr() as usize - data.as_ptr() as usize;
+    start..start + sub.len()
+}
+
+/// byte ranges of redacted fields in packet
+fn sensitive_ranges(data: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let Ok(packet) = PacketRef::parse_from(data) else { return ranges };
+    let payload = packet.payload();
+
+    if packet.code() == MCodeType::REQUESTCHANNEL.code() {
+        if let Ok(r) = RequestChannelRef::parse_from(payload) {
+            if is_redacted(RedactField::AsCallId) {
+                ranges.push(range_of(data, r.as_call_id().data()));
+            }
+            if let (true, Some(info)) = (is_redacted(RedactField::AgoraInfo), r.agora_info()) {
+                ranges.push(range_of(data, info.data()));
+            }
+        }
+    } else if packet.code() == MCodeType::PLAY.code() && is_redacted(RedactField::RtmpKey) {
+        if let Ok(r) = PlayRef::parse_from(payload) {
+            for file in r.files().flatten() {
+                let name = file.filename().data();
+                if let Some(key) = rtmp_key_range(name) {
+                    let start = range_of(data, name).start;
+                    ranges.push(start + key.start..start + key.end);
+                }
+            }
+        }
+    }
+    ranges
+}
+
+/// overwrite redacted fields of a packet with '*', keeps lengths so it still parses,
+/// returns number of fields masked
+pub fn mask_packet(data: &mut [u8]) -> usize {
+    if !any_redacted() {
+        return 0
+    }
+    let ranges = sensitive_ranges(data);
+    for range in ranges.iter() {
+        data[range.clone()].fill(MASK);
+    }
+    ranges.len()
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{format, vec::Vec};
+
+    use crate::vn_proto::{Filename, Header, MCodeType, PacketRef, Play, RequestChannel, RequestChannelRef};
+
+    use super::{mask_packet, redact_url, set_redact, RedactField, RedactStr, REDACTED};
+
+    #[test]
+    fn test_redact() {
+        let req = RequestChannel {
+            media_type: 4,
+            as_call_id: "call-secret".into(),
+            agora_info: Some("token-secret".into()),
+            webrtc: vec!["".into()],
+            ..Default::default()
+        };
+        let mut payload = Vec::new();
+        req.write_to(&mut payload);
+        let mut data = Vec::new();
+        Header { code: MCodeType::REQUESTCHANNEL.code(), fsm_id: 1, ..Default::default() }.write_to2(&mut data, &payload[..]);
+
+        let mut play_payload = Vec::new();
+        Play { files: vec![Filename { format: 100, filename: "rtmp://live.example.com/app/key-secret".into() }], ..Default::default() }.write_to(&mut play_payload);
+        let mut play = Vec::new();
+        Header { code: MCodeType::PLAY.code(), fsm_id: 1, ..Default::default() }.write_to2(&mut play, &play_payload[..]);
+
+        // off by default
+        assert_eq!(mask_packet(&mut data.clone()), 0);
+        assert_eq!(format!("{}", RedactStr(RedactField::AsCallId, b"call-secret")), "call-secret");
+
+        set_redact(&[RedactField::AsCallId, RedactField::AgoraInfo, RedactField::RtmpKey]);
+        let r = RequestChannelRef::parse_from(&payload[..]).unwrap();
+        let text = format!("{r:?}");
+        assert!(!text.contains("secret") && text.contains(REDACTED), "{text}");
+        let text = PacketRef::parse_from(&play[..]).unwrap().to_string();
+        assert_eq!(text, format!("PLAY fsm=1 sn=0 file=rtmp://live.example.com/app/{REDACTED} times=0"));
+        assert_eq!(redact_url("rtmp://host/k"), format!("rtmp://host/{REDACTED}"));
+        assert_eq!(redact_url("rtmp://host"), "rtmp://host");
+        assert_eq!(redact_url("file://cc/1.wav"), "file://cc/1.wav");
+
+        let mut masked = data.clone();
+        assert_eq!(mask_packet(&mut masked), 2);
+        assert_eq!(masked.len(), data.len());
+        let r = RequestChannelRef::parse_from(PacketRef::parse_from(&masked[..]).unwrap().payload()).unwrap();
+        assert_eq!(r.as_call_id().data(), b"***********");
+        assert_eq!(mask_packet(&mut play), 1);
+        assert!(play.ends_with(b"/app/**********\0"));
+
+        set_redact(&[]);
+        assert!("call_id".parse::<RedactField>().is_err());
+        assert_eq!("rtmp_key".parse::<RedactField>().unwrap().name(), "rtmp_key");
+    }
+}
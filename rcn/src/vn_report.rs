@@ -0,0 +1,307 @@
+//! self-contained html report of a capture, opened in any browser.
+//!
+//! One page without scripts or external resources: stats charts as inline
+//! svg, a timeline lane per channel (fsm_id) and every packet decoded in a
+//! collapsed `<details>`. Records are redacted as in captures, see vn_redact.
+//! Channels whose RTPINFO disagrees with codec or ptime requested are listed,
+//! see vn_channels::MediaMismatch.
+
+use std::{collections::BTreeMap, fmt::Write as _, time::Duration};
+
+use anyhow::Result;
+
+#[cfg(feature = "runtime")]
+use crate::vn_channels::ChannelRegistry;
+use crate::{
+    vn_capture::{to_hex, CaptureDir, CaptureRecord},
+    vn_proto::{CodeName, MCodeType, Message, PacketRef},
+};
+
+const LANE_WIDTH: f64 = 800.0;
+const BAR_WIDTH: f64 = 400.0;
+
+const STYLE: &str = "\
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin: 1em 0; }
+td, th { border: 1px solid #ccc; padding: 2px 8px; text-align: left; }
+pre { background: #f6f6f6; padding: 8px; overflow-x: auto; }
+summary { cursor: pointer; font-family: monospace; }
+.cn { fill: #1f77b4; color: #1f77b4; }
+.ms { fill: #d62728; color: #d62728; }
+.bad { color: #999; }
+";
+
+/// packet of a record, None if it doesn't parse
+struct Entry<'a> {
+    n: usize,
+    record: &'a CaptureRecord,
+    data: Vec<u8>,
+}
+
+impl Entry<'_> {
+    fn packet(&self) -> Option<PacketRef<'_>> {
+        PacketRef::parse_from(&self.data[..]).ok()
+    }
+
+    fn class(&self) -> &'static str {
+        match self.record.dir {
+            CaptureDir::CnToMs => "cn",
+            CaptureDir::MsToCn => "ms",
+        }
+    }
+}
+
+/// request to ACK times per request code
+#[derive(Debug, Default)]
+struct Latency {
+    samples: Vec<Duration>,
+}
+
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn render_html(title: &str, records: &[CaptureRecord]) -> Result<String> {
+    let mut entries = Vec::with_capacity(records.len());
+    for (n, record) in records.iter().enumerate() {
+        let data = record.redacted().and_then(|x| x.data())?;
+        entries.push(Entry { n, record, data });
+    }
+    let start = entries.iter().map(|x| x.record.ts_us).min().unwrap_or_default();
+    let end = entries.iter().map(|x| x.record.ts_us).max().unwrap_or_default();
+    let span = (end - start).max(1) as f64;
+
+    let mut html = String::new();
+    let _r = write!(html, "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title><style>{STYLE}</style></head><body>\n<h1>{0}</h1>\n", escape(title));
+    let invalid = entries.iter().filter(|x| x.packet().is_none()).count();
+    let _r = writeln!(
+        html, "<p>packets [{}] invalid [{invalid}] duration [{:.3} s], <span class=\"cn\">cn &rarr; ms</span>, <span class=\"ms\">ms &rarr; cn</span></p>",
+        entries.len(), (end - start) as f64 / 1e6,
+    );
+
+    write_stats(&mut html, &entries, start, span);
+    #[cfg(feature = "runtime")]
+    write_mismatches(&mut html, &entries);
+    write_timeline(&mut html, &entries, start, span);
+    write_packets(&mut html, &entries, start);
+    html.push_str("</body></html>\n");
+    Ok(html)
+}
+
+fn write_stats(html: &mut String, entries: &[Entry<'_>], start: u64, span: f64) {
+    html.push_str("<h2>stats</h2>\n");
+
+    let mut codes: BTreeMap<u16, u64> = BTreeMap::new();
+    for packet in entries.iter().filter_map(|x| x.packet()) {
+        *codes.entry(packet.code()).or_default() += 1;
+    }
+    let max = codes.values().copied().max().unwrap_or(1) as f64;
+    let _r = writeln!(html, "<h3>packets per code</h3>\n<svg width=\"{}\" height=\"{}\">", BAR_WIDTH + 250.0, codes.len() * 20);
+    for (row, (code, num)) in codes.iter().enumerate() {
+        let y = row * 20;
+        let _r = writeln!(
+            html, "<text x=\"0\" y=\"{}\" font-size=\"12\">{}</text><rect x=\"180\" y=\"{}\" width=\"{:.1}\" height=\"14\" fill=\"#888\"/><text x=\"{:.1}\" y=\"{}\" font-size=\"12\">{num}</text>",
+            y + 12, CodeName(*code), y + 2, BAR_WIDTH * *num as f64 / max, 185.0 + BAR_WIDTH * *num as f64 / max, y + 12,
+        );
+    }
+    html.push_str("</svg>\n");
+
+    // packets per second, at most 100 columns
+    let secs = (span / 1e6).ceil().max(1.0) as usize;
+    let columns = secs.min(100);
+    let mut rates = vec![0_u64; columns];
+    for entry in entries {
+        let column = ((entry.record.ts_us - start) as f64 / span * columns as f64) as usize;
+        rates[column.min(columns - 1)] += 1;
+    }
+    let max = rates.iter().copied().max().unwrap_or(1).max(1) as f64;
+    let width = LANE_WIDTH / columns as f64;
+    let _r = writeln!(html, "<h3>packets over time, {:.1} s per column</h3>\n<svg width=\"{LANE_WIDTH}\" height=\"110\">", secs as f64 / columns as f64);
+    for (n, num) in rates.iter().enumerate() {
+        let height = 100.0 * *num as f64 / max;
+        let _r = writeln!(
+            html, "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{height:.1}\" fill=\"#888\"><title>{num}</title></rect>",
+            n as f64 * width, 100.0 - height, (width - 1.0).max(1.0),
+        );
+    }
+    html.push_str("</svg>\n");
+
+    // requests of CN to ACKs of MS, by fsm_id
+    let mut pending: BTreeMap<(u32, u16), u64> = BTreeMap::new();
+    let mut latency: BTreeMap<u16, Latency> = BTreeMap::new();
+    for entry in entries {
+        let Some(packet) = entry.packet() else { continue };
+        let Ok(code) = MCodeType::try_from(packet.code()) else { continue };
+        match (entry.record.dir, code.ack(), code.request()) {
+            (CaptureDir::CnToMs, Some(_), _) => { pending.insert((packet.fsm_id(), code.code()), entry.record.ts_us); },
+            (CaptureDir::MsToCn, _, Some(request)) => {
+                if let Some(sent) = pending.remove(&(packet.fsm_id(), request.code())) {
+                    latency.entry(request.code()).or_default().samples.push(Duration::from_micros(entry.record.ts_us.saturating_sub(sent)));
+                }
+            },
+            _ => {},
+        }
+    }
+    html.push_str("<h3>request to ack</h3>\n<table><tr><th>request</th><th>count</th><th>min ms</th><th>avg ms</th><th>max ms</th></tr>\n");
+    for (code, latency) in latency.iter() {
+        let ms = |x: Duration| x.as_secs_f64() * 1000.0;
+        let sum: Duration = latency.samples.iter().sum();
+        let _r = writeln!(
+            html, "<tr><td>{}</td><td>{}</td><td>{:.3}</td><td>{:.3}</td><td>{:.3}</td></tr>",
+            CodeName(*code), latency.samples.len(),
+            ms(latency.samples.iter().copied().min().unwrap_or_default()),
+            ms(sum / latency.samples.len() as u32),
+            ms(latency.samples.iter().copied().max().unwrap_or_default()),
+        );
+    }
+    let _r = writeln!(html, "</table>\n<p>unanswered requests [{}]</p>", pending.len());
+}
+
+#[cfg(feature = "runtime")]
+fn write_mismatches(html: &mut String, entries: &[Entry<'_>]) {
+    let mut registry = ChannelRegistry::default();
+    let now = std::time::Instant::now();
+    for entry in entries {
+        let Some(packet) = entry.packet() else { continue };
+        if entry.record.dir == CaptureDir::CnToMs && packet.code() == MCodeType::REQUESTCHANNEL.code() {
+            registry.on_requested(packet.fsm_id(), 0, now);
+        }
+        registry.on_packet_at(packet.fsm_id(), packet.code(), packet.payload(), now);
+    }
+    let report = registry.report();
+    let mut rows: Vec<_> = report.mismatched().collect();
+    if rows.is_empty() {
+        return
+    }
+    rows.sort_by_key(|x| x.0);
+    html.push_str("<h3>media mismatches</h3>\n<table><tr><th>fsm_id</th><th>field</th><th>requested</th><th>rtpinfo</th></tr>\n");
+    for (fsm_id, detail) in rows {
+        for m in detail.mismatches.iter() {
+            let _r = writeln!(html, "<tr><td>{fsm_id}</td><td>{}</td><td>{}</td><td>{}</td></tr>", escape(&m.field), m.requested, m.rtpinfo);
+        }
+    }
+    html.push_str("</table>\n");
+}
+
+fn write_timeline(html: &mut String, entries: &[Entry<'_>], start: u64, span: f64) {
+    let mut channels: BTreeMap<u32, Vec<&Entry<'_>>> = BTreeMap::new();
+    for entry in entries {
+        if let Some(packet) = entry.packet() {
+            channels.entry(packet.fsm_id()).or_default().push(entry);
+        }
+    }
+    html.push_str("<h2>timeline per channel</h2>\n<table>\n");
+    for (fsm_id, lane) in channels.iter() {
+        let _r = write!(html, "<tr><td>fsm_id {fsm_id}<br>packets {}</td><td><svg width=\"{LANE_WIDTH}\" height=\"24\">", lane.len());
+        for entry in lane {
+            let Some(packet) = entry.packet() else { continue };
+            let x = (entry.record.ts_us - start) as f64 / span * (LANE_WIDTH - 4.0) + 2.0;
+            // requests of CN on top, answers of MS below
+            let y = if entry.record.dir == CaptureDir::CnToMs { 2 } else { 12 };
+            let _r = write!(
+                html, "<rect class=\"{}\" x=\"{x:.1}\" y=\"{y}\" width=\"3\" height=\"10\"><title>#{} +{:.3} ms {}</title></rect>",
+                entry.class(), entry.n, (entry.record.ts_us - start) as f64 / 1000.0, CodeName(packet.code()),
+            );
+        }
+        html.push_str("</svg></td></tr>\n");
+    }
+    html.push_str("</table>\n");
+}
+
+fn write_packets(html: &mut String, entries: &[Entry<'_>], start: u64) {
+    html.push_str("<h2>packets</h2>\n");
+    for entry in entries {
+        let time = (entry.record.ts_us - start) as f64 / 1000.0;
+        let from = entry.record.socket.as_deref().unwrap_or("-");
+        let mut detail = String::new();
+        let (class, summary) = match entry.packet() {
+            Some(packet) => {
+                let _r = writeln!(detail, "{packet:?}");
+                match Message::from_packet(&packet) {
+                    Ok(Message::Unknown { .. } | Message::Heartbeat | Message::ReleaseChannel) => {},
+                    Ok(msg) => { let _r = writeln!(detail, "{msg:#?}"); },
+                    Err(e) => { let _r = writeln!(detail, "decode payload failed [{e:?}]"); },
+                }
+                (entry.class(), packet.to_string())
+            },
+            None => ("bad", "invalid packet".to_string()),
+        };
+        detail.push('\n');
+        for (n, chunk) in entry.data.chunks(16).enumerate() {
+            let _r = writeln!(detail, "{:04x}  {}", n * 16, to_hex(chunk));
+        }
+        let _r = writeln!(
+            html, "<details><summary class=\"{class}\">#{} +{time:.3} ms {:?} [{}] {}</summary><pre>{}</pre></details>",
+            entry.n, entry.record.dir, escape(from), escape(&summary), escape(&detail),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        vn_capture::{to_hex, CaptureDir, CaptureRecord},
+        vn_proto::{Header, MCodeType},
+    };
+
+    use super::{escape, render_html};
+
+    fn record(ts_us: u64, dir: CaptureDir, code: MCodeType, fsm_id: u32) -> CaptureRecord {
+        let mut data = Vec::new();
+        Header { code: code.code(), fsm_id, sn: 1, key: 0 }.write_to(&mut data);
+        CaptureRecord { ts_us, dir, socket: None, hex: to_hex(&data) }
+    }
+
+    #[test]
+    fn test_render_html() {
+        assert_eq!(escape("<a href=\"x\">&</a>"), "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;");
+
+        let records = vec![
+            record(1_000_000, CaptureDir::CnToMs, MCodeType::CLOSERTPCONNECT, 5000001),
+            record(1_002_000, CaptureDir::MsToCn, MCodeType::CLOSERTPCONNECT_ACK, 5000001),
+            record(1_500_000, CaptureDir::CnToMs, MCodeType::HEARTBEAT, 5000000),
+            CaptureRecord { ts_us: 2_000_000, dir: CaptureDir::MsToCn, socket: Some("<ms>".into()), hex: "00".into() },
+        ];
+        let html = render_html("capture <1>", &records).unwrap();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<title>capture &lt;1&gt;</title>"));
+        assert!(html.contains("packets [4] invalid [1] duration [1.000 s]"));
+        assert!(html.contains("fsm_id 5000001<br>packets 2"));
+        assert!(html.contains("<tr><td>CLOSERTPCONNECT</td><td>1</td><td>2.000</td>"), "{html}");
+        assert!(html.contains("[&lt;ms&gt;] invalid packet"));
+        assert_eq!(html.matches("<details>").count(), 4);
+        // self-contained
+        assert!(!html.contains("<script") && !html.contains("http"));
+        assert!(!html.contains("media mismatches"));
+    }
+
+    /// mismatches come from the channel registry, runtime only
+    #[cfg(feature = "runtime")]
+    #[test]
+    fn test_render_mismatches() {
+        use crate::vn_proto::{OpenRtpConnect, RequestChannel, RtpInfo};
+
+        let mut req = Vec::new();
+        RequestChannel { codec: 8, ptime: 20, webrtc: vec!["".into()], ..Default::default() }.write_to(&mut req);
+        let mut open = Vec::new();
+        OpenRtpConnect { rtpinfos: vec![RtpInfo::plain([10, 0, 0, 2].into(), 4000, 0)] }.write_to(&mut open);
+        let mut records = Vec::new();
+        for (ts_us, code, payload) in [(0, MCodeType::REQUESTCHANNEL, req), (1000, MCodeType::OPENRTPCONNECT, open)] {
+            let mut data = Vec::new();
+            Header { code: code.code(), fsm_id: 5000001, sn: 1, key: 0 }.write_to2(&mut data, &payload[..]);
+            records.push(CaptureRecord { ts_us, dir: CaptureDir::CnToMs, socket: None, hex: to_hex(&data) });
+        }
+        let html = render_html("mismatch", &records).unwrap();
+        assert!(html.contains("<tr><td>5000001</td><td>codec</td><td>8</td><td>0</td></tr>"), "{html}");
+    }
+}
@@ -0,0 +1,204 @@
+//! rtp of simulated media: G.711 packets of pcm samples and RTCP sender reports.
+//!
+//! Nothing goes on the wire, packets are for pcap files (see vn_pcap),
+//! and are decoded back from them for analysis (see vn_audio).
+
+use std::time::{Duration, SystemTime};
+
+/// G.711 clock rate
+pub const CLOCK_RATE: u32 = 8000;
+pub const PTIME: Duration = Duration::from_millis(20);
+const SAMPLES_PER_PACKET: usize = (CLOCK_RATE / 50) as usize;
+const RTP_HEADER_LEN: usize = 12;
+const RTCP_SR: u8 = 200;
+/// seconds from 1900 to 1970
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+pub const PT_PCMU: u8 = 0;
+pub const PT_PCMA: u8 = 8;
+
+pub fn ulaw_encode(sample: i16) -> u8 {
+    const BIAS: i32 = 0x84;
+    const CLIP: i32 = 32635;
+    let sign = if sample < 0 { 0x80 } else { 0 };
+    let magnitude = (sample as i32).abs().min(CLIP) + BIAS;
+    let exponent = (7 - (magnitude as u16).leading_zeros().saturating_sub(1).min(7)) as i32;
+    let mantissa = (magnitude >> (exponent + 3)) & 0x0f;
+    !(sign | (exponent << 4) as u8 | mantissa as u8)
+}
+
+pub fn alaw_encode(sample: i16) -> u8 {
+    let sign = if sample < 0 { 0 } else { 0x80 };
+    let magnitude = ((sample as i32).abs().min(32767) >> 3) as u16;
+    let encoded = match magnitude {
+        0..=31 => (magnitude >> 1) as u8,
+        _ => {
+            let exponent = 15 - magnitude.leading_zeros() as u8 - 4;
+            (exponent << 4) | ((magnitude >> exponent) & 0x0f) as u8
+        },
+    };
+    (sign | encoded) ^ 0x55
+}
+
+pub fn ulaw_decode(byte: u8) -> i16 {
+    let byte = !byte;
+    let exponent = (byte >> 4) & 0x07;
+    let magnitude = ((((byte & 0x0f) as i32) << 3) + 0x84) << exponent;
+    let sample = magnitude - 0x84;
+    if byte & 0x80 != 0 { -sample as i16 } else { sample as i16 }
+}
+
+pub fn alaw_decode(byte: u8) -> i16 {
+    let byte = byte ^ 0x55;
+    let exponent = (byte >> 4) & 0x07;
+    let mantissa = (byte & 0x0f) as i32;
+    let magnitude = match exponent {
+        0 => (mantissa << 4) + 8,
+        _ => ((mantissa << 4) + 0x108) << (exponent - 1),
+    };
+    if byte & 0x80 != 0 { magnitude as i16 } else { -magnitude as i16 }
+}
+
+/// pcm of G.711 rtp packets in the given order, other packets skipped
+pub fn depacketize<'a>(packets: impl IntoIterator<Item = &'a [u8]>) -> Vec<i16> {
+    let mut samples = Vec::new();
+    for packet in packets {
+        if packet.len() < RTP_HEADER_LEN || packet[0] >> 6 != 2 {
+            continue
+        }
+        let payload = &packet[RTP_HEADER_LEN..];
+        match packet[1] & 0x7f {
+            PT_PCMU => samples.extend(payload.iter().map(|x| ulaw_decode(*x))),
+            PT_PCMA => samples.extend(payload.iter().map(|x| alaw_decode(*x))),
+            _ => {},
+        }
+    }
+    samples
+}
+
+/// samples at CLOCK_RATE, nearest neighbour
+pub fn resample(samples: &[i16], sample_rate: u32) -> Vec<i16> {
+    if sample_rate == CLOCK_RATE || sample_rate == 0 {
+        return samples.to_vec()
+    }
+    let num = samples.len() as u64 * CLOCK_RATE as u64 / sample_rate as u64;
+    (0..num).map(|n| samples[(n * sample_rate as u64 / CLOCK_RATE as u64) as usize]).collect()
+}
+
+/// one rtp stream, PCMU unless payload_type is PT_PCMA
+#[derive(Debug, Clone)]
+pub struct RtpSender {
+    payload_type: u8,
+    ssrc: u32,
+    seq: u16,
+    timestamp: u32,
+    packets: u32,
+    octets: u32,
+}
+
+impl RtpSender {
+    pub fn new(payload_type: u8, ssrc: u32) -> Self {
+        Self { payload_type, ssrc, seq: 1, timestamp: 0, packets: 0, octets: 0 }
+    }
+
+    pub fn payload_type(&self) -> u8 {
+        self.payload_type
+    }
+
+    pub fn packets(&self) -> u32 {
+        self.packets
+    }
+
+    /// a talkspurt of samples at CLOCK_RATE, one packet per PTIME,
+    /// the first one marked
+    pub fn packetize(&mut self, samples: &[i16]) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        for (n, chunk) in samples.chunks(SAMPLES_PER_PACKET).enumerate() {
+            let mut packet = Vec::with_capacity(RTP_HEADER_LEN + chunk.len());
+            packet.push(0x80);
+            packet.push(self.payload_type | if n == 0 { 0x80 } else { 0 });
+            packet.extend_from_slice(&self.seq.to_be_bytes());
+            packet.extend_from_slice(&self.timestamp.to_be_bytes());
+            packet.extend_from_slice(&self.ssrc.to_be_bytes());
+            packet.extend(chunk.iter().map(|x| match self.payload_type {
+                PT_PCMA => alaw_encode(*x),
+                _ => ulaw_encode(*x),
+            }));
+            self.seq = self.seq.wrapping_add(1);
+            self.timestamp = self.timestamp.wrapping_add(chunk.len() as u32);
+            self.packets += 1;
+            self.octets += chunk.len() as u32;
+            packets.push(packet);
+        }
+        packets
+    }
+
+    /// RTCP SR of what was sent so far, without report blocks
+    pub fn sender_report(&self, time: SystemTime) -> Vec<u8> {
+        let since = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let ntp_frac = ((since.subsec_nanos() as u64) << 32) / 1_000_000_000;
+        let mut packet = Vec::with_capacity(28);
+        packet.push(0x80);
+        packet.push(RTCP_SR);
+        // length in 32 bit words minus one
+        packet.extend_from_slice(&6_u16.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(&((since.as_secs() + NTP_UNIX_OFFSET) as u32).to_be_bytes());
+        packet.extend_from_slice(&(ntp_frac as u32).to_be_bytes());
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.packets.to_be_bytes());
+        packet.extend_from_slice(&self.octets.to_be_bytes());
+        packet
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use super::{alaw_decode, alaw_encode, depacketize, resample, ulaw_decode, ulaw_encode, RtpSender, PT_PCMA, PT_PCMU};
+
+    #[test]
+    fn test_rtp_sender() {
+        // reference values of G.711
+        assert_eq!(ulaw_encode(0), 0xff);
+        assert_eq!(ulaw_encode(-1), 0x7f);
+        assert_eq!(ulaw_encode(32767), 0x80);
+        assert_eq!(ulaw_encode(-32768), 0x00);
+        assert_eq!(ulaw_encode(1000), 0xce);
+        assert_eq!(alaw_encode(0), 0xd5);
+        assert_eq!(alaw_encode(-8), 0x55);
+        assert_eq!(alaw_encode(32767), 0xaa);
+        assert_eq!(alaw_encode(-32768), 0x2a);
+        assert_eq!(resample(&[1, 2, 3, 4], 16000), [1, 3]);
+
+        let mut rtp = RtpSender::new(PT_PCMU, 0x1234);
+        let packets = rtp.packetize(&[0; 400]);
+        assert_eq!(packets.iter().map(|x| x.len()).collect::<Vec<_>>(), [172, 172, 92]);
+        assert_eq!(&packets[0][..12], [0x80, 0x80, 0, 1, 0, 0, 0, 0, 0, 0, 0x12, 0x34]);
+        assert_eq!(&packets[2][..8], [0x80, 0, 0, 3, 0, 0, 1, 64]);
+        // next talkspurt marked again
+        assert_eq!(&rtp.packetize(&[0; 160])[0][1..4], [0x80, 0, 4]);
+
+        let sr = rtp.sender_report(SystemTime::UNIX_EPOCH + Duration::from_millis(1500));
+        assert_eq!(sr.len(), 28);
+        assert_eq!(&sr[..4], [0x80, 200, 0, 6]);
+        assert_eq!(&sr[8..12], 2_208_988_801_u32.to_be_bytes());
+        assert_eq!(&sr[12..16], 0x8000_0000_u32.to_be_bytes());
+        assert_eq!(&sr[20..28], [0, 0, 0, 4, 0, 0, 2, 48]);
+
+        assert_eq!(RtpSender::new(PT_PCMA, 1).packetize(&[0; 1])[0][12], 0xd5);
+
+        for sample in [0_i16, 100, -100, 1000, -5000, 20000, -32768] {
+            let tolerance = (sample as i32).abs() / 16 + 16;
+            assert!((ulaw_decode(ulaw_encode(sample)) as i32 - sample as i32).abs() <= tolerance, "{sample}");
+            assert!((alaw_decode(alaw_encode(sample)) as i32 - sample as i32).abs() <= tolerance, "{sample}");
+        }
+        let mut rtp = RtpSender::new(PT_PCMA, 1);
+        let mut packets = rtp.packetize(&[1000; 200]);
+        packets.push(rtp.sender_report(SystemTime::UNIX_EPOCH));
+        let samples = depacketize(packets.iter().map(|x| &x[..]));
+        assert_eq!(samples.len(), 200);
+        assert!(samples.iter().all(|x| (x - 1000).abs() < 64));
+    }
+}
@@ -0,0 +1,1363 @@
+//! yaml call flows run by a CnSession against a MS.
+//!
+//! ```yaml
+//! name: play one prompt
+//! steps:
+//!   - send: {code: REQUESTCHANNEL, fsm: 1, request_channel: {media_type: 1, ptime: 20, codec: 8}}
+//!   - expect: {code: REQUESTCHANNEL_ACK, fields: {result: 0, audio_port: ">0"}}
+//!   - send: {code: PLAY, fsm: 1, play: {files: ["file://cc/11000.wav"]}}
+//!   - expect: {code: PLAY_ACK, timeout_ms: 60000}
+//!   - audio: {file: "/tmp/pcap/{fsm_id}.pcap", fsm: 1, min_duration_ms: 200, tone_hz: 440}
+//!   - send: {code: RELEASECHANNEL, fsm: 1}
+//! matrix: {codec: [0, 8], media_type: [1]}
+//! ```
+//!
+//! A request_channel without `codec` negotiates one against the accepted
+//! REGISTER, from `codecs` (default `[PCMA, PCMU]`) and `video_codecs`
+//! (default `[H264]`) in preference order, see vn_codec.
+//!
+//! With a `matrix` the scenario is swept over every codec × media_type × ice_type
+//! combination, each run on its own cn_id, see [`run_sweep`]. A `tenant` labels
+//! the logs of every run and the sweep report.
+//!
+//! `audio` steps check recorded audio of a channel, e.g. pcaps of `ms-sim --pcap-dir`,
+//! see vn_audio. Digits go in-band by PLAY of a `dtmf:` file instead of DTMFRCV
+//! where out-of-band digits aren't negotiated, e.g.
+//! `play: {files: ["dtmf:123#;ms=80;level=-12"]}` then `audio: {.., dtmf: "123#"}`.
+//!
+//! `expect_quiet` asserts nothing arrives for a while, e.g. no PLAY_ACK retry
+//! once CANCEL went out: `expect_quiet: 2s` for any packet or
+//! `expect_quiet: {window: 2s, code: PLAY_ACK, fsm: 1}` for some.
+//!
+//! `play_queue` chains prompts on a channel, each PLAY sent when the one
+//! before is acked or as its policy says, see vn_play_queue:
+//! `play_queue: {fsm: 1, policy: serialize, plays: [{files: [..]}, {files: [..]}]}`.
+//!
+//! `at` holds the next step until an offset from the start of the steps, e.g.
+//! `- at: +120ms`. Offsets are all from one start so late wake-ups don't add
+//! up, and each `at` reports when it was due and when it went on, see
+//! [`Timing`].
+//!
+//! `impair` sets the rtp impairment of a channel of ms-sim, e.g.
+//! `- impair: {fsm: 1, loss_pct: 5, jitter_ms: 40}`, every channel without
+//! `fsm`, and clears it with no profile fields. It applies to an ms-sim of
+//! this process, or of the one at `socket`, its --ctl-socket, see vn_impair.
+//!
+//! `marker` sends a packet with an id rcn also logs, to line MS logs up with
+//! the capture of a run, e.g. `- marker: {id: bug-1234}` or `- marker: {}`
+//! for a generated one, on a channel with `fsm`, see vn_marker.
+//!
+//! [`scenario_from_capture`] turns a capture of a live call into a scenario to edit.
+
+use std::{collections::BTreeMap, fmt, path::{Path, PathBuf}, sync::Arc, time::{Duration, Instant}};
+
+use anyhow::{Result, Context, bail};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::{debug, info, warn, Instrument};
+
+use crate::{
+    utils::{clock::{timeout_at, Clock, SharedClock}, ctl::request, datagram::Datagram, log::tenant_span, rng::SimRng},
+    vn_audio::{analyze, load_audio, AudioExpect},
+    vn_canary::parse_duration,
+    vn_capture::{parse_hex, to_hex, CaptureDir, CaptureRecord},
+    vn_codec::{negotiate_register, CallProfile},
+    vn_fields::{packet_fields, FieldValue, Fields},
+    vn_fsm_id::DEFAULT_SPAN,
+    vn_impair::{self, ImpairProfile},
+    vn_marker::{check_id, marker_id, send_marker, Marker},
+    vn_play_queue::{send_actions, PlayQueue, QueuePolicy},
+    vn_proto::{Filename, MCodeType, MediaType, PacketRef, Play, PlayRef, RequestChannel, RequestChannelRef},
+    vn_redact::{redact_str, redact_url, RedactField},
+    vn_session::CnSession,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scenario {
+    #[serde(default)]
+    pub name: String,
+
+    /// handshake and accept REGISTER before steps
+    #[serde(default = "default_true")]
+    pub register: bool,
+
+    /// steps written as `- send: {..}` instead of yaml tags
+    #[serde(with = "serde_yaml::with::singleton_map_recursive")]
+    pub steps: Vec<Step>,
+
+    /// parameter sweep over request_channel steps
+    #[serde(default, skip_serializing_if = "Matrix::is_empty")]
+    pub matrix: Matrix,
+
+    /// team the scenario runs for, see CnSession::set_tenant
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+
+    /// audio codecs of request_channel steps without codec, in preference order
+    #[serde(default = "default_codecs")]
+    pub codecs: Vec<String>,
+
+    /// video codecs of request_channel steps without codec, in preference order
+    #[serde(default = "default_video_codecs")]
+    pub video_codecs: Vec<String>,
+}
+
+/// values swept in request_channel steps, empty axis keeps value of the step
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Matrix {
+    pub codec: Vec<u8>,
+    pub media_type: Vec<u8>,
+    pub ice_type: Vec<u8>,
+}
+
+impl Matrix {
+    pub fn is_empty(&self) -> bool {
+        self.codec.is_empty() && self.media_type.is_empty() && self.ice_type.is_empty()
+    }
+
+    /// cartesian product, at least one (empty) combination
+    pub fn combinations(&self) -> Vec<Params> {
+        fn axis(values: &[u8]) -> Vec<Option<u8>> {
+            if values.is_empty() {
+                vec![None]
+            } else {
+                values.iter().map(|x| Some(*x)).collect()
+            }
+        }
+
+        let mut combos = Vec::new();
+        for codec in axis(&self.codec) {
+            for media_type in axis(&self.media_type) {
+                for ice_type in axis(&self.ice_type) {
+                    combos.push(Params { codec, media_type, ice_type });
+                }
+            }
+        }
+        combos
+    }
+}
+
+/// one combination of [`Matrix`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Params {
+    pub codec: Option<u8>,
+    pub media_type: Option<u8>,
+    pub ice_type: Option<u8>,
+}
+
+impl fmt::Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (name, value) in [("codec", self.codec), ("media_type", self.media_type), ("ice_type", self.ice_type)] {
+            if let Some(value) = value {
+                if !first {
+                    write!(f, ",")?;
+                }
+                write!(f, "{name}={value}")?;
+                first = false;
+            }
+        }
+        if first {
+            write!(f, "-")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    Send(SendStep),
+    Expect(ExpectStep),
+    SleepMs(u64),
+    Audio(AudioStep),
+    ExpectQuiet(QuietStep),
+    /// e.g. "+120ms", from the start of the steps
+    At(String),
+    PlayQueue(PlayQueueStep),
+    Impair(ImpairStep),
+    Marker(MarkerStep),
+}
+
+/// correlation marker, see vn_marker
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MarkerStep {
+    /// generated if omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// channel number, the link level fsm_id if omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fsm: Option<u32>,
+}
+
+/// rtp impairment of channels of ms-sim
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImpairStep {
+    /// channel number, every channel if omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fsm: Option<u32>,
+    #[serde(flatten)]
+    pub profile: ImpairProfile,
+    /// --ctl-socket of an ms-sim in another process
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket: Option<PathBuf>,
+}
+
+/// reply of a ctl socket
+const CTL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// PLAYs queued on one channel at once, done when all are acked
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayQueueStep {
+    #[serde(default)]
+    pub fsm: u32,
+    #[serde(default)]
+    pub policy: QueuePolicy,
+    pub plays: Vec<PlaySpec>,
+    /// error unless this many were acked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub played: Option<usize>,
+    /// for all of them
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+/// offset of an `at` step
+pub fn parse_at(s: &str) -> Result<Duration> {
+    parse_duration(s.trim().trim_start_matches('+'))
+}
+
+/// within this of its time an `at` step stops sleeping and yields until
+/// due, timers of tokio are only as good as a millisecond
+const AT_SPIN: Duration = Duration::from_millis(2);
+
+/// an `at` step, requested and actual offsets from the start of the steps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Timing {
+    pub step: usize,
+    pub requested_us: u64,
+    pub actual_us: u64,
+}
+
+impl Timing {
+    pub fn late_us(&self) -> i64 {
+        self.actual_us as i64 - self.requested_us as i64
+    }
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "step [{}] at +{:.3}ms, went on at +{:.3}ms, late {:.3}ms",
+            self.step, self.requested_us as f64 / 1000.0, self.actual_us as f64 / 1000.0, self.late_us() as f64 / 1000.0,
+        )
+    }
+}
+
+/// absolute deadlines from one start, sleep to near then yield to exact
+async fn wait_until(clock: &dyn Clock, start: Instant, offset: Duration) -> Duration {
+    let deadline = start + offset;
+    if let Some(early) = deadline.checked_sub(AT_SPIN) {
+        clock.sleep_until(early).await;
+    }
+    while clock.now() < deadline {
+        tokio::task::yield_now().await;
+    }
+    clock.now().saturating_duration_since(start)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendStep {
+    pub code: String,
+
+    /// channel number, fsm_id = base_fsm_id + fsm
+    #[serde(default)]
+    pub fsm: u32,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_channel: Option<RequestChannelSpec>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub play: Option<PlaySpec>,
+
+    /// raw payload in hex, e.g. "00 14"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hex: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExpectStep {
+    pub code: String,
+
+    /// only match packets of this channel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fsm: Option<u32>,
+
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+
+    /// field name -> 0, "abc", ">0", "<=20", "!=3"
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, serde_yaml::Value>,
+}
+
+/// no packet, or none of code and fsm, arrives within window
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum QuietStep {
+    /// any packet, e.g. "2s"
+    Any(String),
+    Filtered {
+        /// e.g. "2s", "500ms"
+        window: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fsm: Option<u32>,
+    },
+}
+
+impl QuietStep {
+    pub fn window(&self) -> Result<Duration> {
+        let window = match self {
+            QuietStep::Any(window) | QuietStep::Filtered { window, .. } => window,
+        };
+        parse_duration(window)
+    }
+
+    fn filter(&self) -> Result<(Option<MCodeType>, Option<u32>)> {
+        match self {
+            QuietStep::Any(_) => Ok((None, None)),
+            QuietStep::Filtered { code, fsm, .. } => Ok((code.as_deref().map(parse_code).transpose()?, *fsm)),
+        }
+    }
+}
+
+/// recorded audio of a channel is to meet expect
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioStep {
+    /// .wav or .pcap, `{fsm_id}` is replaced by fsm_id of the channel
+    pub file: String,
+
+    #[serde(default)]
+    pub fsm: u32,
+
+    #[serde(flatten)]
+    pub expect: AudioExpect,
+}
+
+impl AudioStep {
+    pub fn path(&self, base_fsm_id: u32) -> PathBuf {
+        PathBuf::from(self.file.replace("{fsm_id}", &(base_fsm_id + self.fsm).to_string()))
+    }
+
+    pub fn check(&self, base_fsm_id: u32) -> Result<()> {
+        let path = self.path(base_fsm_id);
+        let (sample_rate, samples) = load_audio(&path)?;
+        debug!("audio of [{path:?}], {}", analyze(&samples, sample_rate));
+        let problems = self.expect.check(&samples, sample_rate);
+        if !problems.is_empty() {
+            let lines: Vec<_> = problems.iter().map(|x| format!("  {x}")).collect();
+            bail!("audio of [{path:?}] mismatch:\n{}", lines.join("\n"))
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestChannelSpec {
+    pub ice_type: u8,
+    pub life_seconds: u16,
+    pub media_type: u8,
+    pub as_call_id: String,
+    pub is_caller: bool,
+    pub ptime: u8,
+    /// None negotiates it, see Scenario::codecs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub codec: Option<u8>,
+    pub amr_mode: u16,
+    pub webrtc: Vec<String>,
+}
+
+impl Default for RequestChannelSpec {
+    fn default() -> Self {
+        Self {
+            ice_type: 0,
+            life_seconds: 300,
+            media_type: 1,
+            as_call_id: String::new(),
+            is_caller: true,
+            ptime: 20,
+            codec: None,
+            amr_mode: 0,
+            webrtc: Vec::new(),
+        }
+    }
+}
+
+impl RequestChannelSpec {
+    pub fn from_ref(r: &RequestChannelRef<'_>) -> Self {
+        Self {
+            ice_type: r.part1().ice_type_code(),
+            life_seconds: r.part1().life_seconds(),
+            media_type: r.part1().media_type_code(),
+            as_call_id: redact_str(RedactField::AsCallId, &r.as_call_id().decode().unwrap_or_default()).into_owned(),
+            is_caller: r.part2().is_caller(),
+            ptime: r.part2().ptime(),
+            codec: Some(r.part2().codec_code()),
+            amr_mode: r.part2().amr_mode(),
+            webrtc: r.webrtc_strs().map(|x| x.decode().unwrap_or_default().into_owned()).collect(),
+        }
+    }
+
+    pub fn to_request(&self) -> RequestChannel {
+        RequestChannel {
+            ice_type: self.ice_type,
+            life_seconds: self.life_seconds,
+            media_type: self.media_type,
+            as_call_id: self.as_call_id.clone(),
+            is_caller: self.is_caller,
+            ptime: self.ptime,
+            codec: self.codec.unwrap_or_default(),
+            amr_mode: self.amr_mode,
+            webrtc: self.webrtc.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlaySpec {
+    pub play_times: u16,
+    pub max_duration: u32,
+    pub key_mask: u16,
+    pub format: u8,
+    pub files: Vec<String>,
+}
+
+impl Default for PlaySpec {
+    fn default() -> Self {
+        Self {
+            play_times: 1,
+            max_duration: 0,
+            key_mask: 0,
+            format: 100,
+            files: Vec::new(),
+        }
+    }
+}
+
+impl PlaySpec {
+    /// None if files are not all of the same format
+    pub fn from_ref(r: &PlayRef<'_>) -> Result<Option<Self>> {
+        let mut format = None;
+        let mut files = Vec::new();
+        for file in r.files() {
+            let file = file?;
+            if format.replace(file.format()).map(|x| x != file.format()).unwrap_or(false) {
+                return Ok(None)
+            }
+            let Some(name) = file.filename().decode() else { return Ok(None) };
+            files.push(redact_url(&name).into_owned());
+        }
+
+        Ok(Some(Self {
+            play_times: r.part1().play_times(),
+            max_duration: r.part1().max_duration(),
+            key_mask: r.part1().key_mask(),
+            format: format.unwrap_or(Self::default().format),
+            files,
+        }))
+    }
+
+    pub fn to_play(&self) -> Play {
+        Play {
+            play_times: self.play_times,
+            max_duration: self.max_duration,
+            key_mask: self.key_mask,
+            files: self.files.iter()
+            .map(|x| Filename { format: self.format, filename: x.clone() })
+            .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+pub(crate) fn default_codecs() -> Vec<String> {
+    vec!["PCMA".into(), "PCMU".into()]
+}
+
+pub(crate) fn default_video_codecs() -> Vec<String> {
+    vec!["H264".into()]
+}
+
+pub(crate) fn default_timeout_ms() -> u64 {
+    3000
+}
+
+impl Scenario {
+    pub fn from_yaml(text: &str) -> Result<Self> {
+        let me: Self = serde_yaml::from_str(text).with_context(||"invalid scenario yaml")?;
+        me.validate()?;
+        Ok(me)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(||format!("read scenario failed [{path:?}]"))?;
+        Self::from_yaml(&text).with_context(||format!("load scenario failed [{path:?}]"))
+    }
+
+    /// codec of a request_channel step without one, by the REGISTER the session accepted
+    fn negotiate(&self, register: Option<&[u8]>, req: &mut RequestChannel) -> Result<()> {
+        let register = register.with_context(||"no codec given and no REGISTER to negotiate one")?;
+        let media_type = MediaType::try_from(req.media_type).with_context(||format!("unknown media_type [{}]", req.media_type))?;
+        let profile = CallProfile {
+            media_type,
+            audio: self.codecs.clone(),
+            video: self.video_codecs.clone(),
+            ptime: req.ptime,
+        };
+        negotiate_register(register, &profile)?.apply_to(req);
+        Ok(())
+    }
+
+    /// copy with params applied to every request_channel step
+    pub fn with_params(&self, params: &Params) -> Self {
+        let mut me = self.clone();
+        for step in me.steps.iter_mut() {
+            let Step::Send(SendStep { request_channel: Some(req), .. }) = step else { continue };
+            if let Some(codec) = params.codec {
+                req.codec = Some(codec);
+            }
+            if let Some(media_type) = params.media_type {
+                req.media_type = media_type;
+            }
+            if let Some(ice_type) = params.ice_type {
+                req.ice_type = ice_type;
+            }
+        }
+        me
+    }
+
+    /// codes and matchers are checked up front instead of mid-call
+    pub fn validate(&self) -> Result<()> {
+        for (index, step) in self.steps.iter().enumerate() {
+            match step {
+                Step::Send(send) => {
+                    parse_code(&send.code).with_context(||format!("step [{index}]"))?;
+                    if let Some(hex) = &send.hex {
+                        parse_hex(hex).with_context(||format!("step [{index}]"))?;
+                    }
+                },
+                Step::Expect(expect) => {
+                    parse_code(&expect.code).with_context(||format!("step [{index}]"))?;
+                    for (name, value) in expect.fields.iter() {
+                        FieldMatcher::parse(value).with_context(||format!("step [{index}] field [{name}]"))?;
+                    }
+                },
+                Step::SleepMs(_) => {},
+                Step::ExpectQuiet(quiet) => {
+                    quiet.window().with_context(||format!("step [{index}]"))?;
+                    quiet.filter().with_context(||format!("step [{index}]"))?;
+                },
+                Step::Audio(audio) => {
+                    if audio.file.is_empty() {
+                        bail!("step [{index}] audio without file")
+                    }
+                },
+                Step::At(at) => {
+                    parse_at(at).with_context(||format!("step [{index}]"))?;
+                },
+                Step::PlayQueue(queue) => {
+                    if queue.plays.is_empty() {
+                        bail!("step [{index}] play_queue without plays")
+                    }
+                },
+                Step::Impair(impair) => {
+                    impair.profile.validate().with_context(||format!("step [{index}]"))?;
+                },
+                Step::Marker(marker) => {
+                    if let Some(id) = &marker.id {
+                        check_id(id).with_context(||format!("step [{index}]"))?;
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_code(name: &str) -> Result<MCodeType> {
+    MCodeType::from_name(name).with_context(||format!("unknown code [{name}]"))
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldMatcher {
+    Str(String),
+    Cmp(CmpOp, i64),
+}
+
+impl FieldMatcher {
+    pub fn parse(value: &serde_yaml::Value) -> Result<Self> {
+        match value {
+            serde_yaml::Value::Number(n) => {
+                let n = n.as_i64().with_context(||format!("not an integer [{n}]"))?;
+                Ok(Self::Cmp(CmpOp::Eq, n))
+            },
+            serde_yaml::Value::Bool(b) => Ok(Self::Cmp(CmpOp::Eq, *b as i64)),
+            serde_yaml::Value::String(s) => Ok(Self::parse_str(s)),
+            other => bail!("unsupported matcher [{other:?}]"),
+        }
+    }
+
+    fn parse_str(s: &str) -> Self {
+        let t = s.trim();
+        let ops = [(">=", CmpOp::Ge), ("<=", CmpOp::Le), ("!=", CmpOp::Ne), ("==", CmpOp::Eq), (">", CmpOp::Gt), ("<", CmpOp::Lt)];
+        for (prefix, op) in ops {
+            if let Some(rest) = t.strip_prefix(prefix) {
+                if let Ok(n) = rest.trim().parse() {
+                    return Self::Cmp(op, n)
+                }
+            }
+        }
+        match t.parse() {
+            Ok(n) => Self::Cmp(CmpOp::Eq, n),
+            Err(_) => Self::Str(s.to_string()),
+        }
+    }
+
+    pub fn matches(&self, value: &FieldValue) -> bool {
+        match (self, value) {
+            (Self::Str(s), FieldValue::Str(v)) => s == v,
+            (Self::Cmp(op, n), FieldValue::Int(v)) => match op {
+                CmpOp::Eq => v == n,
+                CmpOp::Ne => v != n,
+                CmpOp::Gt => v > n,
+                CmpOp::Ge => v >= n,
+                CmpOp::Lt => v < n,
+                CmpOp::Le => v <= n,
+            },
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for FieldMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Str(s) => write!(f, "{s:?}"),
+            Self::Cmp(op, n) => {
+                let op = match op {
+                    CmpOp::Eq => "",
+                    CmpOp::Ne => "!=",
+                    CmpOp::Gt => ">",
+                    CmpOp::Ge => ">=",
+                    CmpOp::Lt => "<",
+                    CmpOp::Le => "<=",
+                };
+                write!(f, "{op}{n}")
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FieldDiff {
+    pub name: String,
+    pub expected: FieldMatcher,
+    pub actual: Option<FieldValue>,
+}
+
+impl fmt::Display for FieldDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.actual {
+            Some(v) => write!(f, "{}: expected {}, got {}", self.name, self.expected, v),
+            None => write!(f, "{}: expected {}, not present", self.name, self.expected),
+        }
+    }
+}
+
+/// fields not matching expectation, empty if all matched
+pub fn diff_fields(fields: &Fields, expect: &BTreeMap<String, serde_yaml::Value>) -> Result<Vec<FieldDiff>> {
+    let mut diffs = Vec::new();
+    for (name, value) in expect.iter() {
+        let matcher = FieldMatcher::parse(value)?;
+        let actual = fields.get(name.as_str());
+        if !actual.map(|x| matcher.matches(x)).unwrap_or(false) {
+            diffs.push(FieldDiff {
+                name: name.clone(),
+                expected: matcher,
+                actual: actual.cloned(),
+            });
+        }
+    }
+    Ok(diffs)
+}
+
+/// timings of its `at` steps if passed
+pub async fn run_scenario<S: Datagram>(session: &mut CnSession<S>, scenario: &Scenario) -> Result<Vec<Timing>> {
+    if scenario.register {
+        session.handshake().await?;
+        session.accept_register().await?;
+    }
+
+    let clock = session.clock().clone();
+    let start = clock.now();
+    let mut timings = Vec::new();
+    for (index, step) in scenario.steps.iter().enumerate() {
+        if let Step::At(at) = step {
+            let requested = parse_at(at).with_context(||format!("step [{index}] failed"))?;
+            let actual = wait_until(clock.as_ref(), start, requested).await;
+            let timing = Timing { step: index, requested_us: requested.as_micros() as u64, actual_us: actual.as_micros() as u64 };
+            debug!("{timing}");
+            timings.push(timing);
+            continue
+        }
+        run_step(session, scenario, step).await.with_context(||format!("step [{index}] failed"))?;
+    }
+    Ok(timings)
+}
+
+async fn run_step<S: Datagram>(session: &mut CnSession<S>, scenario: &Scenario, step: &Step) -> Result<()> {
+    match step {
+        Step::Send(send) => {
+            let code = parse_code(&send.code)?;
+            let fsm_id = session.base_fsm_id() + send.fsm;
+            let mut payload = Vec::new();
+            if let Some(spec) = &send.request_channel {
+                let mut req = spec.to_request();
+                if spec.codec.is_none() {
+                    scenario.negotiate(session.register(), &mut req)?;
+                }
+                req.write_to(&mut payload);
+            } else if let Some(play) = &send.play {
+                play.to_play().write_to(&mut payload);
+            } else if let Some(hex) = &send.hex {
+                payload = parse_hex(hex)?;
+            }
+            session.send_request(code, fsm_id, &payload).await?;
+        },
+        Step::Expect(expect) => {
+            expect_fields(session, expect).await?;
+        },
+        Step::SleepMs(ms) => {
+            session.clock().sleep(Duration::from_millis(*ms)).await;
+        },
+        Step::Audio(audio) => {
+            audio.check(session.base_fsm_id())?;
+        },
+        Step::ExpectQuiet(quiet) => {
+            expect_quiet(session, quiet).await?;
+        },
+        Step::At(_) => {},
+        Step::PlayQueue(step) => {
+            play_queue(session, step).await?;
+        },
+        Step::Impair(step) => {
+            let fsm_id = step.fsm.map(|x| session.base_fsm_id() + x);
+            match step.socket.clone() {
+                Some(socket) => {
+                    let target = fsm_id.map(|x| x.to_string()).unwrap_or_else(|| "*".into());
+                    let cmd = format!("impair {target} {}", serde_json::to_string(&step.profile)?);
+                    tokio::task::spawn_blocking(move || request(&socket, &cmd, CTL_TIMEOUT)).await??;
+                },
+                None => vn_impair::set_profile(fsm_id, step.profile)?,
+            }
+        },
+        Step::Marker(step) => {
+            let marker = Marker::new(step.id.as_deref(), step.fsm.map(|x| session.base_fsm_id() + x))?;
+            send_marker(session, &marker).await?;
+        },
+    }
+    Ok(())
+}
+
+/// enqueue every play then feed PLAY_ACKs to the queue until it is idle,
+/// refused ones are only logged
+async fn play_queue<S: Datagram>(session: &mut CnSession<S>, step: &PlayQueueStep) -> Result<()> {
+    let fsm_id = session.base_fsm_id() + step.fsm;
+    let mut queue = PlayQueue::new(step.policy, usize::MAX);
+    for spec in step.plays.iter() {
+        match queue.enqueue(fsm_id, spec.to_play()) {
+            Ok(actions) => send_actions(session, fsm_id, actions).await?,
+            Err(e) => debug!("play not queued, [{e:#}]"),
+        }
+    }
+
+    let clock = session.clock().clone();
+    let deadline = clock.now() + Duration::from_millis(step.timeout_ms);
+    while queue.is_busy(fsm_id) {
+        let packet = match timeout_at(clock.as_ref(), deadline, session.recv_packet()).await {
+            Some(r) => r?,
+            None => bail!("timeout waiting PLAY_ACK, [{}] plays queued", queue.pending(fsm_id)),
+        };
+        if packet.code() != MCodeType::PLAY_ACK.code() || packet.fsm_id() != fsm_id {
+            debug!("skip packet {packet:?} while playing queue");
+            continue
+        }
+        let result = packet.payload().first().copied().unwrap_or(u8::MAX);
+        let actions = queue.on_play_ack(fsm_id, result);
+        send_actions(session, fsm_id, actions).await?;
+    }
+
+    let played = queue.results(fsm_id).len();
+    debug!("play queue of [{fsm_id}] done, results {:?}, {:?}", queue.results(fsm_id), queue.stats());
+    if let Some(expected) = step.played {
+        if played != expected {
+            bail!("played [{played}] of [{}], expect [{expected}]", step.plays.len())
+        }
+    }
+    Ok(())
+}
+
+/// error on the first packet matching quiet before its window is over, others are skipped
+pub async fn expect_quiet<S: Datagram>(session: &mut CnSession<S>, quiet: &QuietStep) -> Result<()> {
+    let (code, fsm) = quiet.filter()?;
+    let fsm_id = fsm.map(|x| session.base_fsm_id() + x);
+    let window = quiet.window()?;
+    let clock = session.clock().clone();
+    let deadline = clock.now() + window;
+    loop {
+        let packet = match timeout_at(clock.as_ref(), deadline, session.recv_packet()).await {
+            Some(r) => r?,
+            None => return Ok(()),
+        };
+        if code.is_some_and(|x| x.code() != packet.code()) || fsm_id.is_some_and(|x| x != packet.fsm_id()) {
+            debug!("skip packet {packet:?} while quiet");
+            continue;
+        }
+        bail!("expect quiet for [{window:?}] but got {packet:?}")
+    }
+}
+
+/// fields of the first packet matching code and fsm of expect, others are skipped,
+/// error if its fields mismatch or none comes in time
+pub async fn expect_fields<S: Datagram>(session: &mut CnSession<S>, expect: &ExpectStep) -> Result<Fields> {
+    let code = parse_code(&expect.code)?;
+    let fsm_id = expect.fsm.map(|x| session.base_fsm_id() + x);
+    let clock = session.clock().clone();
+    let deadline = clock.now() + Duration::from_millis(expect.timeout_ms);
+    loop {
+        let r = timeout_at(clock.as_ref(), deadline, session.recv_packet()).await;
+        let packet = match r {
+            Some(r) => r?,
+            None => bail!("timeout waiting {code:?}"),
+        };
+
+        if packet.code() != code.code() || fsm_id.map(|x| x != packet.fsm_id()).unwrap_or(false) {
+            debug!("skip packet {packet:?} while waiting {code:?}");
+            continue;
+        }
+
+        let fields = packet_fields(&packet)?;
+        let diffs = diff_fields(&fields, &expect.fields)?;
+        if !diffs.is_empty() {
+            let lines: Vec<_> = diffs.iter().map(|x| format!("  {x}")).collect();
+            bail!("{code:?} fields mismatch:\n{}", lines.join("\n"))
+        }
+        return Ok(fields)
+    }
+}
+
+/// gaps shorter than this between packets are not turned into sleep steps
+const MIN_SLEEP_MS: u64 = 20;
+
+/// sends for CN packets, expects for MS packets, handshake folded into `register`,
+/// gaps before sends become sleeps and slow answers get longer timeouts
+pub fn scenario_from_capture(name: &str, records: &[CaptureRecord]) -> Result<Scenario> {
+    let mut scenario = Scenario {
+        name: name.to_string(),
+        register: false,
+        steps: Vec::new(),
+        matrix: Matrix::default(),
+        tenant: None,
+        codecs: default_codecs(),
+        video_codecs: default_video_codecs(),
+    };
+
+    let mut last_us = None;
+    for (index, record) in records.iter().enumerate() {
+        let data = record.data().with_context(||format!("record [{index}]"))?;
+        let packet = PacketRef::parse_from(&data).with_context(||format!("record [{index}] invalid packet"))?;
+        let Ok(code) = MCodeType::try_from(packet.code()) else {
+            warn!("record [{index}] skip unknown code [0x{:04x}]", packet.code());
+            continue;
+        };
+
+        match code {
+            MCodeType::CNISUP | MCodeType::CNISUP_ACK | MCodeType::REGISTER | MCodeType::REGISTER_ACK => {
+                scenario.register = true;
+                last_us = Some(record.ts_us);
+                continue;
+            },
+            MCodeType::HEARTBEAT => continue,
+            _ => {},
+        }
+        if marker_id(&packet).is_some() {
+            // a new id each run, the old one would line up with old MS logs
+            if record.dir == CaptureDir::CnToMs {
+                let fsm = packet.fsm_id() % DEFAULT_SPAN;
+                scenario.steps.push(Step::Marker(MarkerStep { id: None, fsm: (fsm != 0).then_some(fsm) }));
+            }
+            continue;
+        }
+
+        let gap_ms = last_us.map(|x| record.ts_us.saturating_sub(x) / 1000).unwrap_or(0);
+        last_us = Some(record.ts_us);
+        // fsm_id of channels is base_fsm_id + n, in the default namespace
+        let fsm = packet.fsm_id() % DEFAULT_SPAN;
+
+        match record.dir {
+            CaptureDir::CnToMs => {
+                if gap_ms >= MIN_SLEEP_MS {
+                    scenario.steps.push(Step::SleepMs(gap_ms));
+                }
+                scenario.steps.push(Step::Send(send_step_from(code, fsm, packet.payload())?));
+            },
+            CaptureDir::MsToCn => {
+                let mut fields = BTreeMap::new();
+                if let Some(FieldValue::Int(result)) = packet_fields(&packet)?.get("result") {
+                    fields.insert("result".to_string(), serde_yaml::Value::from(*result));
+                }
+                scenario.steps.push(Step::Expect(ExpectStep {
+                    code: format!("{code:?}"),
+                    fsm: Some(fsm),
+                    timeout_ms: default_timeout_ms().max(gap_ms * 2),
+                    fields,
+                }));
+            },
+        }
+    }
+    Ok(scenario)
+}
+
+/// typed spec if it encodes back to the same payload, hex otherwise
+fn send_step_from(code: MCodeType, fsm: u32, payload: &[u8]) -> Result<SendStep> {
+    let mut step = SendStep {
+        code: format!("{code:?}"),
+        fsm,
+        request_channel: None,
+        play: None,
+        hex: None,
+    };
+
+    let mut encoded = Vec::new();
+    match code {
+        MCodeType::REQUESTCHANNEL => {
+            let spec = RequestChannelSpec::from_ref(&RequestChannelRef::parse_from(payload)?);
+            spec.to_request().write_to(&mut encoded);
+            step.request_channel = Some(spec);
+        },
+        MCodeType::PLAY => {
+            if let Some(spec) = PlaySpec::from_ref(&PlayRef::parse_from(payload)?)? {
+                spec.to_play().write_to(&mut encoded);
+                step.play = Some(spec);
+            }
+        },
+        _ => {},
+    }
+
+    if encoded != payload {
+        step.request_channel = None;
+        step.play = None;
+        if !payload.is_empty() {
+            step.hex = Some(to_hex(payload));
+        }
+    }
+    Ok(step)
+}
+
+impl Scenario {
+    pub fn to_yaml(&self) -> Result<String> {
+        Ok(serde_yaml::to_string(self)?)
+    }
+}
+
+pub struct SweepOptions {
+    pub cindir: PathBuf,
+    /// run n uses cn_id base_cn_id + n
+    pub base_cn_id: u32,
+    /// runs of each matrix combination
+    pub repeat: usize,
+    /// max runs at the same time
+    pub parallel: usize,
+    /// each run starts after a random delay up to this
+    pub start_jitter_ms: u64,
+    pub rng: SimRng,
+    /// of jitter, elapsed and the timers of each run
+    pub clock: SharedClock,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RunResult {
+    pub index: usize,
+    pub cn_id: u32,
+    pub params: Params,
+    pub elapsed_ms: u64,
+    /// None if passed
+    pub error: Option<String>,
+    /// `at` steps of a passed run
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub timings: Vec<Timing>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SweepReport {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub runs: Vec<RunResult>,
+}
+
+impl SweepReport {
+    pub fn failed(&self) -> usize {
+        self.runs.iter().filter(|x| x.error.is_some()).count()
+    }
+
+    /// pass/fail count per combination, then each failure
+    pub fn summary(&self) -> Vec<String> {
+        let mut per_params: Vec<(Params, usize, usize)> = Vec::new();
+        for run in self.runs.iter() {
+            let index = match per_params.iter().position(|x| x.0 == run.params) {
+                Some(index) => index,
+                None => {
+                    per_params.push((run.params, 0, 0));
+                    per_params.len() - 1
+                }
+            };
+            if run.error.is_none() {
+                per_params[index].1 += 1;
+            } else {
+                per_params[index].2 += 1;
+            }
+        }
+
+        let mut lines: Vec<_> = per_params.iter()
+        .map(|(params, passed, failed)| format!("[{params}]: passed {passed}, failed {failed}"))
+        .collect();
+
+        let timings: Vec<&Timing> = self.runs.iter().flat_map(|x| x.timings.iter()).collect();
+        if let Some(worst) = timings.iter().max_by_key(|x| x.late_us()) {
+            lines.push(format!("at steps [{}], latest {worst}", timings.len()));
+        }
+
+        for run in self.runs.iter() {
+            if let Some(e) = &run.error {
+                lines.push(format!("run [{}] cn [{}] [{}]: {}", run.index, run.cn_id, run.params, e));
+            }
+        }
+        lines
+    }
+}
+
+/// run every matrix combination `repeat` times, up to `parallel` at once
+pub async fn run_sweep(scenario: &Scenario, opts: &SweepOptions) -> SweepReport {
+    let semaphore = Arc::new(Semaphore::new(opts.parallel.max(1)));
+    let mut tasks = JoinSet::new();
+
+    let rng = opts.rng.fork("scenario.start_jitter");
+    let combos = scenario.matrix.combinations();
+    let runs = (0..opts.repeat).flat_map(|_| combos.iter().copied());
+    for (index, params) in runs.enumerate() {
+        let scenario = scenario.with_params(&params);
+        let cindir = opts.cindir.clone();
+        let cn_id = opts.base_cn_id + index as u32;
+        let semaphore = semaphore.clone();
+        let jitter = Duration::from_millis(rng.range(0, opts.start_jitter_ms));
+        let span = tenant_span(scenario.tenant.as_deref());
+        let clock = opts.clock.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            clock.sleep(jitter).await;
+            let start = clock.now();
+            let r = async {
+                let mut session = CnSession::bind(&cindir, cn_id).await?;
+                session.set_clock(clock.clone());
+                session.set_tenant(scenario.tenant.clone());
+                run_scenario(&mut session, &scenario).await
+            }.await;
+            let (timings, error) = match r {
+                Ok(timings) => (timings, None),
+                Err(e) => (Vec::new(), Some(format!("{e:#}"))),
+            };
+            info!("run [{index}] cn [{cn_id}] [{params}] {}", if error.is_none() { "passed" } else { "failed" });
+            RunResult {
+                index,
+                cn_id,
+                params,
+                elapsed_ms: clock.now().saturating_duration_since(start).as_millis() as u64,
+                error,
+                timings,
+            }
+        }.instrument(span));
+    }
+
+    let mut report = SweepReport {
+        name: scenario.name.clone(),
+        tenant: scenario.tenant.clone(),
+        runs: Vec::new(),
+    };
+    while let Some(r) = tasks.join_next().await {
+        match r {
+            Ok(run) => report.runs.push(run),
+            Err(e) => debug!("run task failed [{e:?}]"),
+        }
+    }
+    report.runs.sort_by_key(|x| x.index);
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        utils::clock::MockClock,
+        vn_capture::{to_hex, CaptureDir, CaptureRecord},
+        vn_fields::{FieldValue, Fields},
+        vn_ms_sim::{MsSim, MsSimConfig},
+        vn_proto::{Header, MCodeType, RequestChannel},
+        vn_session::CnSession,
+        vn_speech::StubTts,
+        vn_testkit::MsStub,
+    };
+
+    use super::{diff_fields, run_scenario, scenario_from_capture, Params, RequestChannelSpec, Scenario, Step};
+
+    const FLOW: &str = r#"
+name: play one prompt
+steps:
+  - send: {code: REQUESTCHANNEL, fsm: 1, request_channel: {media_type: 1, codec: 8}}
+  - expect: {code: REQUESTCHANNEL_ACK, fields: {result: 0, audio_port: ">0"}}
+  - sleep_ms: 10
+  - send: {code: RELEASECHANNEL, fsm: 1}
+"#;
+
+    #[test]
+    fn test_expect_fields() {
+        let scenario = Scenario::from_yaml(FLOW).unwrap();
+        assert_eq!(scenario.steps.len(), 4);
+        let Step::Expect(expect) = &scenario.steps[1] else { panic!("expect step") };
+
+        let mut fields = Fields::new();
+        fields.insert("result", FieldValue::Int(0));
+        fields.insert("audio_port", FieldValue::Int(5000));
+        assert!(diff_fields(&fields, &expect.fields).unwrap().is_empty());
+
+        fields.insert("audio_port", FieldValue::Int(0));
+        fields.remove("result");
+        let diffs = diff_fields(&fields, &expect.fields).unwrap();
+        let text: Vec<_> = diffs.iter().map(|x| x.to_string()).collect();
+        assert_eq!(text, vec!["audio_port: expected >0, got 0", "result: expected 0, not present"]);
+
+        assert!(Scenario::from_yaml("steps: [{send: {code: NOPE}}]").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_audio_step() {
+        let dir = std::env::temp_dir().join(format!("rcn_scenario_audio_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = MsSimConfig { pcap_dir: Some(dir.clone()), ..Default::default() };
+        let mut sim = MsSim::bind(&dir, config).await.unwrap();
+        sim.set_tts(Some(Box::new(StubTts::default())));
+        let task = tokio::spawn(async move { sim.run().await });
+
+        let flow = format!(r#"
+steps:
+  - send: {{code: REQUESTCHANNEL, fsm: 1, request_channel: {{media_type: 1, codec: 0, webrtc: [""]}}}}
+  - expect: {{code: REQUESTCHANNEL_ACK}}
+  - send: {{code: PLAY, fsm: 1, play: {{files: ["tts:hello"]}}}}
+  - expect: {{code: PLAY_ACK, fields: {{result: 0}}}}
+  - audio: {{file: "{}/{{fsm_id}}.pcap", fsm: 1, min_duration_ms: 250, max_silence_ratio: 0.1, tone_hz: 440}}
+"#, dir.display());
+        let scenario = Scenario::from_yaml(&flow).unwrap();
+        let Step::Audio(audio) = &scenario.steps[4] else { panic!("audio step") };
+        assert_eq!(audio.path(5000000), dir.join("5000001.pcap"));
+        let mut session = CnSession::bind(&dir, 5).await.unwrap();
+        run_scenario(&mut session, &scenario).await.unwrap();
+
+        // the prompt was a 440Hz tone, not 1000Hz
+        let wrong = flow.replace("tone_hz: 440", "tone_hz: 1000").replace("fsm: 1", "fsm: 2");
+        let scenario = Scenario::from_yaml(&wrong).unwrap();
+        let mut session = CnSession::bind(&dir, 6).await.unwrap();
+        let e = run_scenario(&mut session, &scenario).await.unwrap_err();
+        assert!(format!("{e:#}").contains("tone [1000Hz]"), "{e:#}");
+
+        // every rtp packet of the channel lost on the way
+        let lossy = flow.replace("  - send: {code: PLAY,", "  - impair: {fsm: 1, loss_pct: 100}\n  - send: {code: PLAY,");
+        let scenario = Scenario::from_yaml(&lossy).unwrap();
+        let Step::Impair(impair) = &scenario.steps[2] else { panic!("impair step") };
+        assert_eq!((impair.fsm, impair.profile.loss_pct), (Some(1), 100.0));
+        let mut session = CnSession::bind(&dir, 7).await.unwrap();
+        let e = run_scenario(&mut session, &scenario).await.unwrap_err();
+        assert!(format!("{e:#}").contains("step [5]") && format!("{e:#}").contains("duration [0ns]"), "{e:#}");
+
+        task.abort();
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_expect_quiet() {
+        let (_ms, mut session) = MsStub::start(MsSimConfig::default(), 5);
+        let flow = r#"
+steps:
+  - expect_quiet: 50ms
+  - send: {code: REQUESTCHANNEL, fsm: 1, request_channel: {media_type: 1, webrtc: [""]}}
+  - expect_quiet: {window: 100ms, code: PLAY_ACK}
+  - send: {code: PLAY, fsm: 1, play: {files: ["file://cc/11000.wav"]}}
+  - expect_quiet: {window: 1s, code: PLAY_ACK, fsm: 1}
+"#;
+        let scenario = Scenario::from_yaml(flow).unwrap();
+        let Step::ExpectQuiet(quiet) = &scenario.steps[0] else { panic!("quiet step") };
+        assert_eq!(quiet.window().unwrap(), std::time::Duration::from_millis(50));
+        // REQUESTCHANNEL_ACK is skipped in the first window, PLAY_ACK breaks the last
+        let e = run_scenario(&mut session, &scenario).await.unwrap_err();
+        assert!(format!("{e:#}").contains("step [4]") && format!("{e:#}").contains("PLAY_ACK"), "{e:#}");
+
+        assert!(Scenario::from_yaml("steps: [{expect_quiet: 2 minutes}]").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_timeout_on_mock_clock() {
+        let (_ms, mut session) = MsStub::start(MsSimConfig::default(), 5);
+        let clock = MockClock::new();
+        session.set_clock(clock.clone());
+        let flow = r#"
+steps:
+  - expect_quiet: {window: 30s, code: PLAY_ACK}
+  - sleep_ms: 20000
+  - expect: {code: PLAY_ACK, timeout_ms: 60000}
+"#;
+        let scenario = Scenario::from_yaml(flow).unwrap();
+        let e = clock.drive(Duration::from_millis(10), run_scenario(&mut session, &scenario)).await.unwrap_err();
+        assert!(format!("{e:#}").contains("step [2]") && format!("{e:#}").contains("timeout waiting"), "{e:#}");
+        assert!(clock.elapsed() >= Duration::from_secs(110), "{:?}", clock.elapsed());
+    }
+
+    #[tokio::test]
+    async fn test_at_step() {
+        let (_ms, mut session) = MsStub::start(MsSimConfig::default(), 5);
+        let clock = MockClock::new();
+        session.set_clock(clock.clone());
+        let flow = r#"
+steps:
+  - at: +30ms
+  - sleep_ms: 5
+  - at: 45ms
+  - at: +10ms
+"#;
+        let scenario = Scenario::from_yaml(flow).unwrap();
+        let timings = clock.drive(Duration::from_millis(1), run_scenario(&mut session, &scenario)).await.unwrap();
+        let requested: Vec<(usize, u64)> = timings.iter().map(|x| (x.step, x.requested_us)).collect();
+        assert_eq!(requested, vec![(0, 30000), (2, 45000), (3, 10000)]);
+        // on time, an offset already passed goes on at once
+        let late: Vec<i64> = timings.iter().map(|x| x.late_us()).collect();
+        assert_eq!(late, vec![0, 0, 35000]);
+
+        assert!(Scenario::from_yaml("steps: [{at: soon}]").is_err());
+    }
+
+    #[test]
+    fn test_matrix_params() {
+        let scenario = Scenario::from_yaml(&format!("{FLOW}matrix: {{codec: [0, 8], media_type: [1, 2]}}\n")).unwrap();
+        let combos = scenario.matrix.combinations();
+        assert_eq!(combos.len(), 4);
+        assert_eq!(combos[1], Params { codec: Some(0), media_type: Some(2), ice_type: None });
+        assert_eq!(combos[1].to_string(), "codec=0,media_type=2");
+
+        let swept = scenario.with_params(&combos[1]);
+        let Step::Send(send) = &swept.steps[0] else { panic!("send step") };
+        let req = send.request_channel.as_ref().unwrap();
+        assert_eq!((req.codec, req.media_type, req.ice_type), (Some(0), 2, 0));
+
+        let plain = Scenario::from_yaml(FLOW).unwrap();
+        assert_eq!(plain.matrix.combinations(), vec![Params::default()]);
+    }
+
+    #[test]
+    fn test_negotiate_codec() {
+        let mut register = Vec::new();
+        MsSimConfig::default().register.write_to(&mut register);
+
+        let scenario = Scenario::from_yaml(FLOW).unwrap();
+        assert_eq!(scenario.codecs, vec!["PCMA", "PCMU"]);
+        let spec = RequestChannelSpec::default();
+        assert_eq!(spec.codec, None);
+        let mut req = spec.to_request();
+        scenario.negotiate(Some(&register), &mut req).unwrap();
+        assert_eq!(req.codec, 8);
+
+        let scenario = Scenario::from_yaml(&format!("{FLOW}codecs: [G729, PCMU]\n")).unwrap();
+        let mut req = spec.to_request();
+        scenario.negotiate(Some(&register), &mut req).unwrap();
+        assert_eq!(req.codec, 0);
+
+        let scenario = Scenario::from_yaml(&format!("{FLOW}codecs: [G729]\n")).unwrap();
+        let e = scenario.negotiate(Some(&register), &mut spec.to_request()).unwrap_err();
+        assert!(format!("{e:#}").contains("no common audio codec"), "{e:#}");
+        let e = scenario.negotiate(None, &mut spec.to_request()).unwrap_err();
+        assert!(format!("{e:#}").contains("no REGISTER"), "{e:#}");
+    }
+
+    #[test]
+    fn test_from_capture() {
+        let record = |ts_ms: u64, dir, code: MCodeType, payload: &[u8]| {
+            let mut data = Vec::new();
+            Header { code: code.code(), fsm_id: 5000001, ..Default::default() }.write_to2(&mut data, payload);
+            CaptureRecord { ts_us: ts_ms * 1000, dir, socket: None, hex: to_hex(&data) }
+        };
+        let mut req = Vec::new();
+        RequestChannel {
+            life_seconds: 300,
+            media_type: 1,
+            ptime: 20,
+            codec: 8,
+            webrtc: vec!["null_crypto".into(), "".into(), "encode:0".into()],
+            ..Default::default()
+        }.write_to(&mut req);
+
+        let records = vec![
+            record(0, CaptureDir::CnToMs, MCodeType::CNISUP, &[]),
+            record(1, CaptureDir::MsToCn, MCodeType::CNISUP_ACK, &[]),
+            record(5, CaptureDir::CnToMs, MCodeType::REQUESTCHANNEL, &req),
+            record(505, CaptureDir::CnToMs, MCodeType::CLOSERTPCONNECT, &[0x01]),
+            record(4505, CaptureDir::MsToCn, MCodeType::CLOSERTPCONNECT_ACK, &[0]),
+        ];
+
+        let scenario = scenario_from_capture("captured", &records).unwrap();
+        let scenario = Scenario::from_yaml(&scenario.to_yaml().unwrap()).unwrap();
+        assert!(scenario.register);
+        assert_eq!(scenario.steps.len(), 4);
+
+        let Step::Send(send) = &scenario.steps[0] else { panic!("send step") };
+        assert_eq!((send.code.as_str(), send.fsm), ("REQUESTCHANNEL", 1));
+        assert_eq!(send.request_channel.as_ref().and_then(|x| x.codec), Some(8));
+
+        assert!(matches!(scenario.steps[1], Step::SleepMs(500)));
+
+        let Step::Send(send) = &scenario.steps[2] else { panic!("send step") };
+        assert_eq!(send.hex.as_deref(), Some("01"));
+
+        let Step::Expect(expect) = &scenario.steps[3] else { panic!("expect step") };
+        assert_eq!((expect.code.as_str(), expect.fsm, expect.timeout_ms), ("CLOSERTPCONNECT_ACK", Some(1), 8000));
+        assert_eq!(expect.fields.get("result"), Some(&serde_yaml::Value::from(0)));
+    }
+}
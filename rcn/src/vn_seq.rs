@@ -0,0 +1,140 @@
+//! loss detection from Header.sn of received packets.
+//!
+//! Each sender numbers its packets per fsm_id, a jump of sn means datagrams
+//! were lost between, a repeated sn a duplicate and a step back a late
+//! (reordered) one. The first packet of a key only sets where counting starts.
+
+use std::{collections::HashMap, fmt, hash::Hash};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LossStats {
+    pub received: u64,
+    /// jumps of sn
+    pub gaps: u64,
+    /// packets missing in gaps
+    pub lost: u64,
+    pub duplicates: u64,
+    /// older than the last sn of its key
+    pub reordered: u64,
+}
+
+impl LossStats {
+    pub fn has_loss(&self) -> bool {
+        self.gaps + self.duplicates + self.reordered > 0
+    }
+
+    pub fn add(&mut self, other: &LossStats) {
+        self.received += other.received;
+        self.gaps += other.gaps;
+        self.lost += other.lost;
+        self.duplicates += other.duplicates;
+        self.reordered += other.reordered;
+    }
+}
+
+impl fmt::Display for LossStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "received [{}] gaps [{}] lost [{}] duplicates [{}] reordered [{}]",
+            self.received, self.gaps, self.lost, self.duplicates, self.reordered,
+        )
+    }
+}
+
+/// what a received sn means
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeqEvent {
+    /// first of key or next one
+    InOrder,
+    /// this many were skipped
+    Gap(u16),
+    Duplicate,
+    Reordered,
+}
+
+/// last sn per key, e.g. fsm_id or (peer, fsm_id)
+#[derive(Debug)]
+pub struct SeqTracker<K> {
+    last: HashMap<K, u16>,
+    stats: LossStats,
+}
+
+impl<K> Default for SeqTracker<K> {
+    fn default() -> Self {
+        Self { last: HashMap::new(), stats: LossStats::default() }
+    }
+}
+
+impl<K: Hash + Eq> SeqTracker<K> {
+    pub fn on_recv(&mut self, key: K, sn: u16) -> SeqEvent {
+        self.stats.received += 1;
+        let Some(last) = self.last.get_mut(&key) else {
+            self.last.insert(key, sn);
+            return SeqEvent::InOrder
+        };
+
+        // wrapping distance, half the space ahead and half behind
+        let ahead = sn.wrapping_sub(*last) as i16;
+        match ahead {
+            0 => {
+                self.stats.duplicates += 1;
+                SeqEvent::Duplicate
+            },
+            1 => {
+                *last = sn;
+                SeqEvent::InOrder
+            },
+            n if n > 1 => {
+                *last = sn;
+                self.stats.gaps += 1;
+                self.stats.lost += (n - 1) as u64;
+                SeqEvent::Gap((n - 1) as u16)
+            },
+            _ => {
+                self.stats.reordered += 1;
+                SeqEvent::Reordered
+            },
+        }
+    }
+
+    /// forget key, e.g. channel released, its next sn starts over
+    pub fn remove(&mut self, key: &K) {
+        self.last.remove(key);
+    }
+
+    pub fn clear(&mut self) {
+        self.last.clear();
+    }
+
+    pub fn stats(&self) -> &LossStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SeqEvent, SeqTracker};
+
+    #[test]
+    fn test_seq_gaps() {
+        let mut seq = SeqTracker::default();
+        let events: Vec<_> = [1, 2, 5, 5, 4, 6].iter().map(|sn| seq.on_recv(7_u32, *sn)).collect();
+        assert_eq!(events, [
+            SeqEvent::InOrder, SeqEvent::InOrder, SeqEvent::Gap(2),
+            SeqEvent::Duplicate, SeqEvent::Reordered, SeqEvent::InOrder,
+        ]);
+
+        // keys are apart, sn wraps
+        assert_eq!(seq.on_recv(8, u16::MAX), SeqEvent::InOrder);
+        assert_eq!(seq.on_recv(8, 0), SeqEvent::InOrder);
+        assert_eq!(seq.on_recv(8, 3), SeqEvent::Gap(2));
+
+        let s = seq.stats();
+        assert_eq!((s.received, s.gaps, s.lost, s.duplicates, s.reordered), (9, 2, 4, 1, 1));
+        assert!(s.has_loss());
+        assert_eq!(s.to_string(), "received [9] gaps [2] lost [4] duplicates [1] reordered [1]");
+
+        seq.remove(&8);
+        assert_eq!(seq.on_recv(8, 100), SeqEvent::InOrder);
+    }
+}
@@ -0,0 +1,1054 @@
+//! CN side of a VN link over unix datagram sockets.
+//!
+//! ```no_run
+//! use rcn::vn_proto::{Filename, Play, RegisterRef, RequestChannel};
+//! use rcn::vn_session::CnSession;
+//!
+//! # #[cfg(feature = "runtime")]
+//! # async fn demo() -> anyhow::Result<()> {
+//! let mut session = CnSession::bind_env(5).await?;
+//! session.handshake().await?;
+//!
+//! let register = session.accept_register().await?;
+//! let reg = RegisterRef::parse_from(&register[..])?;
+//! println!("{reg:?}");
+//!
+//! let fsm_id = session.base_fsm_id() + 1;
+//! session.request_channel(fsm_id, &RequestChannel {
+//!     life_seconds: 300,
+//!     media_type: 1,
+//!     ptime: 20,
+//!     ..Default::default()
+//! }).await?;
+//!
+//! session.play(fsm_id, &Play {
+//!     play_times: 1,
+//!     files: vec![Filename { format: 100, filename: "file://cc/11000.wav".into() }],
+//!     ..Default::default()
+//! }).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::{HashMap, VecDeque}, fmt, net::Ipv4Addr, path::{Path, PathBuf}, time::SystemTime};
+
+use anyhow::{Result, Context, bail};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    utils::{clock::{default_clock, SharedClock}, datagram::Datagram, latency::LatencyRecorder, rate_limit::RateLimiter, recv_buf::RecvBuf, rtt::{RttStats, RttTracker}},
+    vn_acl::{AclMode, PeerAcl},
+    vn_auth::{split_trailer, AuthStatus, PacketAuth},
+    vn_capture::{CaptureDir, CaptureWriter},
+    vn_cn_state::{CnState, StateFile},
+    vn_compress::{decode_payload, Compression},
+    vn_epoch::{EpochGuard, EpochMode},
+    vn_fragment::{self, Reassembler},
+    vn_fsm_id::{FsmIdAllocator, FsmIdCheck, FsmIdGuard, FsmIdSpace},
+    vn_handshake::{HandshakeState, StrayAction, StrayPolicy, MAX_QUEUED},
+    vn_proto::{Capability, CodeName, Direction, Header, LengthPolicy, MCodeType, PacketRef, Play, PortRange, RegisterRef, RequestChannel, split_packets, HEADER_LENGTH},
+    vn_seq::{LossStats, SeqEvent, SeqTracker},
+    vn_socket_name::socket_naming,
+};
+
+pub const CINDIR: &str = "CINDIR";
+
+/// $CINDIR/mscn{cn_id} or as --cn-socket-template, see vn_socket_name
+pub fn cn_socket_path(cindir: &Path, cn_id: u32) -> Result<PathBuf> {
+    Ok(socket_naming().cn_path(cindir, cn_id))
+}
+
+/// $CINDIR/msvn or as --ms-socket-template
+pub fn ms_socket_path(cindir: &Path) -> PathBuf {
+    socket_naming().ms_path(cindir)
+}
+
+pub fn cindir_from_env() -> Result<PathBuf> {
+    let cindir = std::env::var(CINDIR).with_context(||format!("can't get env [{CINDIR}]"))?;
+    Ok(cindir.into())
+}
+
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(||format!("failed to remove unix socket path [{path:?}]")),
+    }
+}
+
+/// remove stale socket file and bind
+#[cfg(feature = "runtime")]
+pub async fn bind_socket(path: &Path) -> Result<tokio::net::UnixDatagram> {
+    remove_stale_socket(path)?;
+    let socket = tokio::net::UnixDatagram::bind(path)
+    .with_context(||format!("can't bind unix socket path [{path:?}]"))?;
+    Ok(socket)
+}
+
+/// remove stale socket file and bind, for async-io based executors
+#[cfg(feature = "smol")]
+pub fn bind_async_io_socket(path: &Path) -> Result<crate::utils::datagram::AsyncIoDatagram> {
+    remove_stale_socket(path)?;
+    let socket = std::os::unix::net::UnixDatagram::bind(path)
+    .with_context(||format!("can't bind unix socket path [{path:?}]"))?;
+    let socket = async_io::Async::new(socket)?;
+    Ok(socket)
+}
+
+/// datagrams exchanged with the MS, fragments counted each
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    pub sent: u64,
+    pub sent_bytes: u64,
+    pub received: u64,
+    pub received_bytes: u64,
+}
+
+/// what an MS announced in its REGISTER, kept per MS in the channel report
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisterInfo {
+    pub ip: Ipv4Addr,
+    pub support_t38: bool,
+    pub capabilities: u32,
+    /// "audio 8:8:PCMA/8000"
+    pub codecs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capacity: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port_range: Option<PortRange>,
+}
+
+impl RegisterInfo {
+    pub fn from_ref(reg: &RegisterRef<'_>) -> Self {
+        Self {
+            ip: reg.ip,
+            support_t38: reg.media_info.support_t38,
+            capabilities: reg.capabilities(),
+            codecs: codec_names(reg),
+            version: reg.version().map(|x| x.into_owned()),
+            capacity: reg.capacity(),
+            port_range: reg.port_range(),
+        }
+    }
+}
+
+/// changes of a REGISTER against the previous one
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RegisterDiff {
+    /// (old, new)
+    pub ip: Option<(Ipv4Addr, Ipv4Addr)>,
+    pub support_t38: Option<(bool, bool)>,
+    pub capabilities: Option<(u32, u32)>,
+    pub version: Option<(Option<String>, Option<String>)>,
+    pub capacity: Option<(Option<u32>, Option<u32>)>,
+    pub port_range: Option<(Option<PortRange>, Option<PortRange>)>,
+    /// "audio 8:8:PCMA/8000"
+    pub added_codecs: Vec<String>,
+    pub removed_codecs: Vec<String>,
+}
+
+impl RegisterDiff {
+    pub fn between(old: &RegisterRef<'_>, new: &RegisterRef<'_>) -> Self {
+        let old_codecs = codec_names(old);
+        let new_codecs = codec_names(new);
+        Self {
+            ip: changed(old.ip, new.ip),
+            support_t38: changed(old.media_info.support_t38, new.media_info.support_t38),
+            capabilities: changed(old.capabilities(), new.capabilities()),
+            version: changed(old.version().map(|x| x.into_owned()), new.version().map(|x| x.into_owned())),
+            capacity: changed(old.capacity(), new.capacity()),
+            port_range: changed(old.port_range(), new.port_range()),
+            added_codecs: new_codecs.iter().filter(|x| !old_codecs.contains(x)).cloned().collect(),
+            removed_codecs: old_codecs.iter().filter(|x| !new_codecs.contains(x)).cloned().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+fn changed<T: PartialEq>(old: T, new: T) -> Option<(T, T)> {
+    (old != new).then_some((old, new))
+}
+
+impl fmt::Display for RegisterDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("no change")
+        }
+        let mut parts = Vec::new();
+        if let Some((a, b)) = self.ip {
+            parts.push(format!("ip {a} -> {b}"));
+        }
+        if let Some((a, b)) = self.support_t38 {
+            parts.push(format!("t38 {a} -> {b}"));
+        }
+        if let Some((a, b)) = self.capabilities {
+            parts.push(format!("capabilities 0x{a:x} -> 0x{b:x}"));
+        }
+        if let Some((a, b)) = &self.version {
+            parts.push(format!("version {} -> {}", or_none(a), or_none(b)));
+        }
+        if let Some((a, b)) = &self.capacity {
+            parts.push(format!("capacity {} -> {}", or_none(a), or_none(b)));
+        }
+        if let Some((a, b)) = &self.port_range {
+            parts.push(format!("ports {} -> {}", or_none(a), or_none(b)));
+        }
+        parts.extend(self.added_codecs.iter().map(|x| format!("+{x}")));
+        parts.extend(self.removed_codecs.iter().map(|x| format!("-{x}")));
+        f.write_str(&parts.join(", "))
+    }
+}
+
+fn or_none<T: fmt::Display>(v: &Option<T>) -> String {
+    v.as_ref().map(|x| x.to_string()).unwrap_or_else(|| "none".into())
+}
+
+fn codec_names(reg: &RegisterRef<'_>) -> Vec<String> {
+    let info = &reg.media_info;
+    [("audio", &info.audio_codecs), ("video", &info.video_codecs), ("fax", &info.fax_codecs)].iter()
+    .flat_map(|(kind, codecs)| codecs.iter().map(move |x| {
+        format!("{kind} {}:{}:{}", x.index(), x.payload_type(), String::from_utf8_lossy(x.map_str_data()))
+    }))
+    .collect()
+}
+
+pub struct CnSession<S> {
+    socket: S,
+    ms_path: PathBuf,
+    cn_id: u32,
+    /// last sn sent per fsm_id
+    sns: HashMap<u32, u16>,
+    /// sn received per fsm_id
+    seq: SeqTracker<u32>,
+    send_buf: Vec<u8>,
+    recv_buf: RecvBuf,
+    /// packets after the first of a datagram carrying several, not returned yet
+    batch: Vec<u8>,
+    batch_from: Option<PathBuf>,
+    traffic: TrafficStats,
+    auth: Option<Box<dyn PacketAuth>>,
+    /// offered at REGISTER_ACK
+    compression: Option<Compression>,
+    /// both sides agreed on compression
+    compress_active: bool,
+    inflate_buf: Vec<u8>,
+    packet_buf: Vec<u8>,
+    /// offered at REGISTER_ACK, max datagram bytes
+    fragment_mtu: Option<usize>,
+    /// both sides agreed on fragmentation
+    fragment_active: bool,
+    reassembler: Reassembler,
+    frag_buf: Vec<u8>,
+    latency: Option<LatencyRecorder>,
+    /// of HEARTBEATs sent by us
+    rtt: RttTracker,
+    capture: Option<CaptureWriter>,
+    /// peers datagrams are taken from, anyone if None
+    acl: Option<PeerAcl>,
+    /// incarnation of MS, see vn_epoch
+    epoch: EpochGuard,
+    length_policy: LengthPolicy,
+    /// payload of last REGISTER accepted
+    register: Option<Vec<u8>>,
+    /// channel fsm_ids of our namespace, see vn_fsm_id
+    fsm_ids: FsmIdAllocator,
+    fsm_guard: FsmIdGuard,
+    /// team sharing the lab this session runs for
+    tenant: Option<String>,
+    /// sns and fsm_id cursor kept across restarts
+    state_file: Option<StateFile>,
+    /// of packets out of context during handshake
+    stray: StrayPolicy,
+    /// queued during handshake, returned by recv_packet first
+    held: VecDeque<Vec<u8>>,
+    /// paces send_packet, per fsm_id and in total
+    limiter: RateLimiter,
+    /// of fragment expiry, latency, rtt and state file saves
+    clock: SharedClock,
+}
+
+#[cfg(feature = "runtime")]
+impl CnSession<tokio::net::UnixDatagram> {
+    pub async fn bind_env(cn_id: u32) -> Result<Self> {
+        let cindir = cindir_from_env()?;
+        Self::bind(&cindir, cn_id).await
+    }
+
+    pub async fn bind(cindir: &Path, cn_id: u32) -> Result<Self> {
+        let socket = bind_socket(&cn_socket_path(cindir, cn_id)?).await?;
+        Ok(Self::with_socket(socket, ms_socket_path(cindir), cn_id))
+    }
+}
+
+impl<S: Datagram> CnSession<S> {
+    pub fn with_socket(socket: S, ms_path: PathBuf, cn_id: u32) -> Self {
+        Self {
+            socket,
+            ms_path,
+            cn_id,
+            sns: HashMap::new(),
+            seq: SeqTracker::default(),
+            send_buf: vec![0_u8; 1700],
+            recv_buf: RecvBuf::default(),
+            batch: Vec::new(),
+            batch_from: None,
+            traffic: TrafficStats::default(),
+            auth: None,
+            compression: None,
+            compress_active: false,
+            inflate_buf: Vec::new(),
+            packet_buf: Vec::new(),
+            fragment_mtu: None,
+            fragment_active: false,
+            reassembler: Reassembler::default(),
+            frag_buf: Vec::new(),
+            latency: None,
+            rtt: RttTracker::default(),
+            capture: None,
+            acl: None,
+            epoch: EpochGuard::default(),
+            length_policy: LengthPolicy::default(),
+            register: None,
+            fsm_ids: FsmIdAllocator::new(FsmIdSpace::of_cn(cn_id)),
+            fsm_guard: FsmIdGuard::new(FsmIdSpace::of_cn(cn_id), FsmIdCheck::Off),
+            tenant: None,
+            state_file: None,
+            stray: StrayPolicy::default(),
+            held: VecDeque::new(),
+            limiter: RateLimiter::default(),
+            clock: default_clock(),
+        }
+    }
+
+    /// sign outgoing and verify incoming packets with auth trailer
+    pub fn set_auth(&mut self, auth: Option<Box<dyn PacketAuth>>) {
+        self.auth = auth;
+    }
+
+    /// offer payload compression, takes effect if MS announces it at REGISTER
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    pub fn is_compress_active(&self) -> bool {
+        self.compress_active
+    }
+
+    /// offer fragmentation of packets larger than mtu,
+    /// takes effect if MS announces it at REGISTER
+    pub fn set_fragment_mtu(&mut self, mtu: Option<usize>) {
+        self.fragment_mtu = mtu;
+    }
+
+    pub fn is_fragment_active(&self) -> bool {
+        self.fragment_active
+    }
+
+    /// send_packet waits for a token of both the global and the fsm_id bucket
+    pub fn set_rate_limiter(&mut self, limiter: RateLimiter) {
+        self.limiter = limiter;
+    }
+
+    /// time source of our timers, e.g. a MockClock in tests
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        self.clock = clock;
+    }
+
+    /// for timers of callers to run on the same time
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+
+    /// initial and max size of recv buffer, it grows when datagram truncated
+    pub fn set_recv_buf(&mut self, size: usize, max: usize) {
+        self.recv_buf = RecvBuf::new(size, max);
+    }
+
+    /// what packets of other codes do while waiting for CNISUP_ACK or REGISTER
+    pub fn set_stray_policy(&mut self, policy: StrayPolicy) {
+        self.stray = policy;
+    }
+
+    /// how received packets with Header.length not matching datagram size are parsed
+    pub fn set_length_policy(&mut self, policy: LengthPolicy) {
+        self.length_policy = policy;
+    }
+
+    pub fn traffic(&self) -> &TrafficStats {
+        &self.traffic
+    }
+
+    pub fn recv_buf(&self) -> &RecvBuf {
+        &self.recv_buf
+    }
+
+    /// record request -> ACK latencies
+    pub fn enable_latency(&mut self) {
+        if self.latency.is_none() {
+            self.latency = Some(LatencyRecorder::default());
+        }
+    }
+
+    pub fn latency(&self) -> Option<&LatencyRecorder> {
+        self.latency.as_ref()
+    }
+
+    /// round trips of HEARTBEATs sent by send_request
+    pub fn rtt(&self) -> &RttStats {
+        self.rtt.stats()
+    }
+
+    /// record every packet sent and received,
+    /// origins are filled from ms_path when it is a unix socket path
+    pub fn set_capture(&mut self, mut capture: Option<CaptureWriter>) {
+        if let (Some(capture), Some(cindir)) = (&mut capture, self.ms_path.parent()) {
+            capture.set_origin(CaptureDir::MsToCn, &self.ms_path);
+            if let Ok(path) = cn_socket_path(cindir, self.cn_id) {
+                capture.set_origin(CaptureDir::CnToMs, &path);
+            }
+        }
+        self.capture = capture;
+    }
+
+    /// drop or warn about datagrams from peers other than ms_path
+    /// and the allowed ones, see vn_acl
+    pub fn set_peer_acl(&mut self, mode: AclMode, allowed: &[PathBuf]) {
+        self.acl = match mode {
+            AclMode::Off => None,
+            _ => {
+                let mut acl = PeerAcl::new(mode, allowed.to_vec());
+                acl.allow(self.ms_path.clone());
+                Some(acl)
+            },
+        };
+    }
+
+    pub fn peer_acl(&self) -> Option<&PeerAcl> {
+        self.acl.as_ref()
+    }
+
+    /// offer epochs at CNISUP and drop or warn about packets of stale ones,
+    /// takes effect at next handshake, see vn_epoch
+    pub fn set_epoch_mode(&mut self, mode: EpochMode) {
+        self.epoch = EpochGuard::new(mode);
+    }
+
+    pub fn epoch(&self) -> &EpochGuard {
+        &self.epoch
+    }
+
+    pub fn cn_id(&self) -> u32 {
+        self.cn_id
+    }
+
+    /// label carried into link stats and channels of this session
+    pub fn set_tenant(&mut self, tenant: Option<String>) {
+        self.tenant = tenant;
+    }
+
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    /// fsm_id used by link level packets, channels use base + n
+    pub fn base_fsm_id(&self) -> u32 {
+        self.fsm_ids.space().base
+    }
+
+    /// namespace and strategy of channel fsm_ids, and how inbound fsm_ids
+    /// outside it are treated
+    pub fn set_fsm_ids(&mut self, ids: FsmIdAllocator, check: FsmIdCheck) {
+        self.fsm_guard = FsmIdGuard::new(ids.space(), check);
+        self.fsm_ids = ids;
+    }
+
+    pub fn fsm_ids(&self) -> &FsmIdAllocator {
+        &self.fsm_ids
+    }
+
+    pub fn fsm_guard(&self) -> &FsmIdGuard {
+        &self.fsm_guard
+    }
+
+    /// fsm_id for a new channel
+    pub fn allocate_fsm_id(&mut self) -> Result<u32> {
+        let fsm_id = self.fsm_ids.allocate()?;
+        // before it's on the wire, a restart must not pick it again
+        self.save_state_if(true);
+        Ok(fsm_id)
+    }
+
+    pub fn release_fsm_id(&mut self, fsm_id: u32) {
+        self.fsm_ids.release(fsm_id);
+    }
+
+    pub fn socket(&self) -> &S {
+        &self.socket
+    }
+
+    pub fn ms_path(&self) -> &Path {
+        &self.ms_path
+    }
+
+    /// packets of each fsm_id are numbered from 1
+    pub fn next_sn(&mut self, fsm_id: u32) -> u16 {
+        let sn = self.sns.entry(fsm_id).or_default();
+        *sn = sn.wrapping_add(1);
+        let sn = *sn;
+        if let Some(file) = &mut self.state_file {
+            file.touch();
+        }
+        self.save_state_if(false);
+        sn
+    }
+
+    /// keep sns and fsm_id cursor in path, going on from what it holds, see vn_cn_state.
+    /// Call after set_fsm_ids, a state of other fsm_ids or cn is ignored.
+    pub fn set_state_file(&mut self, path: &Path) -> Result<()> {
+        let mut file = StateFile::new(path);
+        match file.load()? {
+            Some(state) if state.cn_id == self.cn_id && state.fsm_ids == self.fsm_ids_desc() => {
+                self.fsm_ids.resume(state.cursor).with_context(||format!("invalid state file [{path:?}]"))?;
+                self.sns.extend(state.resumed_sns());
+                info!("resumed cursor [{}], sns of [{}] fsm_ids from [{path:?}]", state.cursor, state.sns.len());
+            },
+            Some(state) => warn!("state file [{path:?}] of cn [{}] fsm_ids [{}] ignored", state.cn_id, state.fsm_ids),
+            None => {},
+        }
+        file.save(&self.state(), self.clock.now())?;
+        self.state_file = Some(file);
+        Ok(())
+    }
+
+    /// what a state file gets
+    pub fn state(&self) -> CnState {
+        CnState {
+            cn_id: self.cn_id,
+            fsm_ids: self.fsm_ids_desc(),
+            cursor: self.fsm_ids.cursor(),
+            sns: self.sns.iter().map(|(k, v)| (*k, *v)).collect(),
+        }
+    }
+
+    /// save to state file now, e.g. at exit
+    pub fn save_state(&mut self) -> Result<()> {
+        let state = self.state();
+        match &mut self.state_file {
+            Some(file) => file.save(&state, self.clock.now()),
+            None => Ok(()),
+        }
+    }
+
+    fn save_state_if(&mut self, force: bool) {
+        let now = self.clock.now();
+        if !self.state_file.as_ref().is_some_and(|x| force || x.due(now)) {
+            return
+        }
+        let state = self.state();
+        if let Some(file) = &mut self.state_file {
+            if let Err(e) = file.save(&state, now) {
+                warn!("{e:#}");
+            }
+        }
+    }
+
+    fn fsm_ids_desc(&self) -> String {
+        format!("{} {}", self.fsm_ids.space(), self.fsm_ids.strategy())
+    }
+
+    /// gaps and duplicates of sn received
+    pub fn loss(&self) -> &LossStats {
+        self.seq.stats()
+    }
+
+    pub async fn send_packet(&mut self, header: &Header, payload: &[u8]) -> Result<usize> {
+        if self.limiter.is_enabled() {
+            self.limiter.acquire(header.fsm_id).await;
+        }
+
+        if let Some(capture) = &mut self.capture {
+            let mut data = Vec::with_capacity(HEADER_LENGTH + payload.len());
+            header.write_to2(&mut data, payload);
+            capture.write(CaptureDir::CnToMs, &data)?;
+        }
+
+        let mut encoded = Vec::new();
+        let payload = match (self.compress_active, &self.compression) {
+            (true, Some(compression)) => {
+                compression.encode(payload, &mut encoded);
+                &encoded[..]
+            },
+            _ => payload,
+        };
+
+        if let (true, Some(mtu)) = (self.fragment_active, self.fragment_mtu) {
+            let trailer_len = self.auth.as_ref().map(|x|x.trailer_len()).unwrap_or(0);
+            let max_payload = mtu.saturating_sub(HEADER_LENGTH + trailer_len);
+            let fragments = vn_fragment::split(header, payload, max_payload)?;
+            let mut total = 0;
+            for (header, payload) in fragments.iter() {
+                total += self.send_datagram(header, payload).await?;
+            }
+            return Ok(total)
+        }
+
+        self.send_datagram(header, payload).await
+    }
+
+    /// data sent and captured as is, no compression, fragmentation or signing
+    pub async fn send_verbatim(&mut self, data: &[u8]) -> Result<usize> {
+        if let Some(capture) = &mut self.capture {
+            capture.write(CaptureDir::CnToMs, data)?;
+        }
+        self.socket.send_to(data, &self.ms_path).await.with_context(||"sendto failed")?;
+        self.traffic.sent += 1;
+        self.traffic.sent_bytes += data.len() as u64;
+        debug!(bytes = data.len(), "sent verbatim");
+        Ok(data.len())
+    }
+
+    async fn send_datagram(&mut self, header: &Header, payload: &[u8]) -> Result<usize> {
+        let trailer_len = self.auth.as_ref().map(|x|x.trailer_len()).unwrap_or(0);
+        let need = HEADER_LENGTH + payload.len() + trailer_len;
+        if HEADER_LENGTH + payload.len() - 2 > u16::MAX as usize {
+            bail!("too large payload [{}]", payload.len())
+        }
+        if need > self.send_buf.len() {
+            self.send_buf.resize(need, 0);
+        }
+
+        let mut len = header.write_to2(&mut self.send_buf[..], payload);
+        if let Some(auth) = &self.auth {
+            let mut trailer = Vec::with_capacity(auth.trailer_len());
+            auth.sign(&self.send_buf[..len], &mut trailer);
+            self.send_buf[len..len+trailer.len()].copy_from_slice(&trailer[..]);
+            len += trailer.len();
+        }
+        self.socket.send_to(&self.send_buf[..len], &self.ms_path).await.with_context(||"sendto failed")?;
+        self.traffic.sent += 1;
+        self.traffic.sent_bytes += len as u64;
+        debug!(code = %CodeName(header.code), fsm_id = header.fsm_id, sn = header.sn, bytes = len, "sent");
+        Ok(len)
+    }
+
+    /// next packet, a datagram of several packets sent back to back is returned
+    /// one packet per call
+    pub async fn recv_packet(&mut self) -> Result<PacketRef<'_>> {
+        if let Some(packet) = self.held.pop_front() {
+            // checked, counted and captured as it came
+            self.packet_buf = packet;
+            return PacketRef::parse_with(&self.packet_buf, self.length_policy)
+        }
+        self.recv_next().await
+    }
+
+    async fn recv_next(&mut self) -> Result<PacketRef<'_>> {
+        // Some(len) of packet in recv_buf, None if reassembled into frag_buf
+        let (recv_len, from) = loop {
+            let (data, from) = if self.batch.is_empty() {
+                let (recv_len, from) = self.socket.recv_from(self.recv_buf.as_mut_slice()).await.with_context(||"recvfrom failed")?;
+                if self.recv_buf.check_truncated(recv_len) {
+                    continue
+                }
+                if let Some(acl) = &mut self.acl {
+                    if !acl.check(from.as_deref()) {
+                        continue
+                    }
+                }
+                self.traffic.received += 1;
+                self.traffic.received_bytes += recv_len as u64;
+                let (data, status) = split_trailer(self.auth.as_deref(), &self.recv_buf.as_slice()[..recv_len]);
+                if !matches!(status, AuthStatus::None | AuthStatus::Verified) {
+                    bail!("packet auth failed [{status:?}]")
+                }
+                (data, from)
+            } else {
+                // rest of a datagram checked as it came, fits where it came in
+                let len = self.batch.len();
+                self.recv_buf.as_mut_slice()[..len].copy_from_slice(&self.batch);
+                self.batch.clear();
+                (&self.recv_buf.as_slice()[..len], self.batch_from.clone())
+            };
+
+            // packets sent back to back come out one per call, Extend
+            // means trailing bytes belong to the payload
+            let data = match self.length_policy {
+                LengthPolicy::Extend => data,
+                _ => {
+                    let mut packets = split_packets(data);
+                    let first = packets.next().unwrap_or(data);
+                    if !packets.rest().is_empty() {
+                        debug!("datagram carries more packets, [{}] bytes left", packets.rest().len());
+                        self.batch.extend_from_slice(packets.rest());
+                        self.batch_from = from.clone();
+                    }
+                    first
+                },
+            };
+
+            if !self.fragment_active {
+                // garbage is left to the parse below
+                if let Ok(packet) = PacketRef::parse_from(data) {
+                    if !self.epoch.check(packet.code(), packet.key()) || !self.fsm_guard.check(packet.code(), packet.fsm_id()) {
+                        continue
+                    }
+                }
+                break (Some(data.len()), from)
+            }
+
+            let packet = PacketRef::parse_from(data).with_context(||"parse packet failed")?;
+            if !vn_fragment::is_fragment(packet.key()) {
+                if !self.epoch.check(packet.code(), packet.key()) || !self.fsm_guard.check(packet.code(), packet.fsm_id()) {
+                    continue
+                }
+                break (Some(data.len()), from)
+            }
+
+            let now = self.clock.now();
+            let dropped = self.reassembler.expire(now);
+            if dropped > 0 {
+                warn!("dropped incomplete fragment sets [{dropped}]");
+            }
+
+            if let Some((header, payload)) = self.reassembler.push(&packet.to_header(), packet.payload(), now)? {
+                // key of fragment 0 is the original one
+                if !self.epoch.check(header.code, header.key) || !self.fsm_guard.check(header.code, header.fsm_id) {
+                    continue
+                }
+                self.frag_buf.clear();
+                header.write_to2(&mut self.frag_buf, &payload[..]);
+                self.frag_buf.extend_from_slice(&data[packet.length()+2..]);
+                break (None, from)
+            }
+        };
+
+        let mut data = match recv_len {
+            Some(len) => &self.recv_buf.as_slice()[..len],
+            None => &self.frag_buf[..],
+        };
+
+        if self.compress_active || self.fragment_active {
+            // MS (re)started sends REGISTER raw, whatever was negotiated is
+            // gone until ack_register runs again
+            if PacketRef::parse_from(data).is_ok_and(|x| x.code() == MCodeType::REGISTER.code()) {
+                debug!("register received, compression and fragmentation reset");
+                self.compress_active = false;
+                self.fragment_active = false;
+            }
+        }
+
+        if self.compress_active {
+            let packet = PacketRef::parse_from(data).with_context(||"parse packet failed")?;
+            let payload = decode_payload(packet.payload(), &mut self.inflate_buf)?;
+            self.packet_buf.clear();
+            packet.to_header().write_to2(&mut self.packet_buf, payload);
+            self.packet_buf.extend_from_slice(&data[packet.length()+2..]);
+            data = &self.packet_buf[..];
+        }
+
+        let packet = PacketRef::parse_with(data, self.length_policy).with_context(||"parse packet failed")?;
+        // fields are formatted only if the event is enabled, payload is never decoded for it
+        debug!(code = %CodeName(packet.code()), fsm_id = packet.fsm_id(), sn = packet.sn(), bytes = data.len(), from = ?from, "recv");
+        if let Some(m) = packet.length_mismatch() {
+            warn!("packet length mismatch, declared [{}] but datagram [{}], policy {:?}", m.declared, m.actual, self.length_policy);
+        }
+        if let Some(capture) = &mut self.capture {
+            capture.write(CaptureDir::MsToCn, &data[..(packet.length()+2).min(data.len())])?;
+        }
+        if let Some(latency) = &mut self.latency {
+            latency.on_response(packet.fsm_id(), packet.code(), self.clock.now());
+        }
+        if packet.code() == MCodeType::REGISTER.code() {
+            // MS (re)started, numbering of all fsm_ids starts over
+            self.seq.clear();
+        }
+        match self.seq.on_recv(packet.fsm_id(), packet.sn()) {
+            SeqEvent::InOrder => {},
+            ev => debug!("sn [{}] of fsm_id [{}] is {ev:?}", packet.sn(), packet.fsm_id()),
+        }
+        if packet.code() == MCodeType::HEARTBEAT.code() {
+            if let Some(rtt) = self.rtt.on_answer(self.clock.now(), packet.payload()) {
+                debug!("heartbeat rtt [{rtt:?}]");
+            }
+        }
+        if let Ok(code) = MCodeType::try_from(packet.code()) {
+            if !code.direction().allows(Direction::MsToCn) {
+                warn!("unexpected direction, {code:?} is {} only", code.direction());
+            }
+        }
+        Ok(packet)
+    }
+
+    /// recv next packet and check its code
+    pub async fn expect_packet(&mut self, code: MCodeType) -> Result<PacketRef<'_>> {
+        let packet = self.recv_packet().await?;
+        if packet.code() != code.code() {
+            bail!("expect {code:?} but [{:?}]", packet.code())
+        }
+        Ok(packet)
+    }
+
+    /// send CNISUP and wait for CNISUP_ACK,
+    /// epoch of MS is taken from the ack if offered
+    pub async fn handshake(&mut self) -> Result<()> {
+        let fsm_id = self.base_fsm_id();
+        let header = Header {
+            code: MCodeType::CNISUP.code(),
+            fsm_id,
+            sn: self.next_sn(fsm_id),
+            ..Default::default()
+        };
+        let mut payload = Vec::new();
+        if self.epoch.mode() != EpochMode::Off {
+            Capability { flags: Capability::EPOCH }.write_tag_to(&mut payload);
+        }
+        self.send_packet(&header, &payload).await?;
+        let ack = self.expect_handshake(HandshakeState::CnisupAck).await?;
+        let key = PacketRef::parse_with(&ack, self.length_policy)?.key();
+        self.epoch.on_handshake(key);
+        Ok(())
+    }
+
+    /// wait for REGISTER, answer REGISTER_ACK and return the register payload,
+    /// compression is enabled here if both sides have it
+    pub async fn accept_register(&mut self) -> Result<Vec<u8>> {
+        let register = self.expect_handshake(HandshakeState::Register).await?;
+        let payload = PacketRef::parse_with(&register, self.length_policy)?.payload().to_vec();
+        self.ack_register(&payload).await?;
+        self.register = Some(payload.clone());
+        Ok(payload)
+    }
+
+    /// packet of the code state waits for, others handled as StrayPolicy says
+    async fn expect_handshake(&mut self, state: HandshakeState) -> Result<Vec<u8>> {
+        let code = state.code();
+        loop {
+            let (got, raw) = {
+                let packet = self.recv_next().await?;
+                (packet.code(), packet.raw().to_vec())
+            };
+            if got == code.code() {
+                return Ok(raw)
+            }
+            match self.stray.action(state, got) {
+                StrayAction::Ignore => debug!("ignore [{}] waiting for {code:?}", CodeName(got)),
+                StrayAction::Queue => {
+                    if self.held.len() >= MAX_QUEUED {
+                        bail!("more than [{MAX_QUEUED}] packets queued waiting for {code:?}")
+                    }
+                    debug!("queue [{}] waiting for {code:?}", CodeName(got));
+                    self.held.push_back(raw);
+                },
+                StrayAction::Error => bail!("expect {code:?} but [{got:?}]"),
+            }
+        }
+    }
+
+    /// of the REGISTER accepted last
+    pub fn register_info(&self) -> Option<RegisterInfo> {
+        let register = self.register.as_deref()?;
+        RegisterRef::parse_from(register).ok().map(|x| RegisterInfo::from_ref(&x))
+    }
+
+    pub fn is_registered(&self) -> bool {
+        self.register.is_some()
+    }
+
+    /// payload of the REGISTER accepted last
+    pub fn register(&self) -> Option<&[u8]> {
+        self.register.as_deref()
+    }
+
+    /// REGISTER received while registered, e.g. MS restarted: ack it again
+    /// and return what changed against the last one
+    pub async fn reregister(&mut self, payload: &[u8]) -> Result<RegisterDiff> {
+        let new = RegisterRef::parse_from(payload)?;
+        let diff = match &self.register {
+            Some(old) => RegisterDiff::between(&RegisterRef::parse_from(&old[..])?, &new),
+            None => RegisterDiff::default(),
+        };
+        self.ack_register(payload).await?;
+        self.register = Some(payload.to_vec());
+        Ok(diff)
+    }
+
+    async fn ack_register(&mut self, payload: &[u8]) -> Result<()> {
+        let ms_caps = Capability { flags: RegisterRef::parse_from(payload)?.capabilities() };
+        let compress = self.compression.is_some() && ms_caps.has(Capability::ZLIB);
+        let fragment = self.fragment_mtu.is_some() && ms_caps.has(Capability::FRAGMENT);
+
+        let fsm_id = self.base_fsm_id();
+        let header = Header {
+            code: MCodeType::REGISTER_ACK.code(),
+            fsm_id,
+            sn: self.next_sn(fsm_id),
+            ..Default::default()
+        };
+        let mut caps = Capability::default();
+        if compress {
+            caps.flags |= Capability::ZLIB;
+        }
+        if fragment {
+            caps.flags |= Capability::FRAGMENT;
+        }
+        let mut ack = vec![0];
+        if caps.flags != 0 {
+            caps.write_tag_to(&mut ack);
+        }
+        self.send_packet(&header, &ack[..]).await?;
+
+        self.compress_active = compress;
+        self.fragment_active = fragment;
+        debug!("compression [{compress}], fragmentation [{fragment}]");
+        Ok(())
+    }
+
+    pub async fn request_channel(&mut self, fsm_id: u32, req: &RequestChannel) -> Result<usize> {
+        let mut payload = Vec::new();
+        req.write_to(&mut payload);
+        self.send_request(MCodeType::REQUESTCHANNEL, fsm_id, &payload).await
+    }
+
+    pub async fn play(&mut self, fsm_id: u32, req: &Play) -> Result<usize> {
+        let mut payload = Vec::new();
+        req.write_to(&mut payload);
+        self.send_request(MCodeType::PLAY, fsm_id, &payload).await
+    }
+
+    pub async fn send_request(&mut self, code: MCodeType, fsm_id: u32, payload: &[u8]) -> Result<usize> {
+        let header = Header {
+            code: code.code(),
+            fsm_id,
+            sn: self.next_sn(fsm_id),
+            ..Default::default()
+        };
+        if let Some(latency) = &mut self.latency {
+            latency.on_request(fsm_id, header.code, self.clock.now());
+        }
+        if code == MCodeType::HEARTBEAT {
+            self.rtt.on_sent(self.clock.now(), SystemTime::now());
+        }
+        if code == MCodeType::RELEASECHANNEL {
+            // fsm_id may be reused, numbering starts over
+            self.sns.remove(&fsm_id);
+            if let Some(file) = &mut self.state_file {
+                file.touch();
+            }
+            self.seq.remove(&fsm_id);
+        }
+        self.send_packet(&header, payload).await
+    }
+
+    pub async fn send_heartbeat(&mut self) -> Result<usize> {
+        self.send_request(MCodeType::HEARTBEAT, self.base_fsm_id(), &[]).await
+    }
+}
+
+#[cfg(all(test, feature = "runtime"))]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        utils::clock::MockClock,
+        vn_compress::Compression,
+        vn_fragment,
+        vn_proto::{Capability, Header, MCodeType, Register},
+    };
+
+    use super::{cn_socket_path, CnSession};
+
+    #[tokio::test]
+    async fn test_register_resets_compression() {
+        let dir = std::env::temp_dir().join(format!("rcn_session_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ms_path = dir.join("msvn");
+        let _r = std::fs::remove_file(&ms_path);
+        let ms = tokio::net::UnixDatagram::bind(&ms_path).unwrap();
+        let mut session = CnSession::bind(&dir, 6).await.unwrap();
+        session.set_compression(Some(Compression::default()));
+        let cn_path = cn_socket_path(&dir, 6).unwrap();
+        let register = |capability: Option<Capability>| {
+            let mut payload = Vec::new();
+            Register { ip: [10, 0, 0, 1].into(), capability, ..Default::default() }.write_to(&mut payload);
+            let mut data = Vec::new();
+            Header { code: MCodeType::REGISTER.code(), fsm_id: 6000000, ..Default::default() }.write_to2(&mut data, &payload[..]);
+            data
+        };
+
+        ms.send_to(&register(Some(Capability { flags: Capability::ZLIB })), &cn_path).await.unwrap();
+        session.accept_register().await.unwrap();
+        assert!(session.is_compress_active());
+
+        // MS restarted without zlib, its REGISTER is raw
+        ms.send_to(&register(None), &cn_path).await.unwrap();
+        let payload = {
+            let packet = session.recv_packet().await.unwrap();
+            assert_eq!(packet.code(), MCodeType::REGISTER.code());
+            packet.payload().to_vec()
+        };
+        assert!(!session.is_compress_active());
+        session.reregister(&payload).await.unwrap();
+        assert!(!session.is_compress_active());
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_fragment_expiry() {
+        let dir = std::env::temp_dir().join(format!("rcn_session_frag_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ms_path = dir.join("msvn");
+        let _r = std::fs::remove_file(&ms_path);
+        let ms = tokio::net::UnixDatagram::bind(&ms_path).unwrap();
+        let mut session = CnSession::bind(&dir, 7).await.unwrap();
+        session.set_fragment_mtu(Some(200));
+        let clock = MockClock::new();
+        session.set_clock(clock.clone());
+        let cn_path = cn_socket_path(&dir, 7).unwrap();
+        let send = |header: &Header, payload: &[u8]| {
+            let mut data = Vec::new();
+            header.write_to2(&mut data, payload);
+            data
+        };
+
+        let mut payload = Vec::new();
+        Register { ip: [10, 0, 0, 1].into(), capability: Some(Capability { flags: Capability::FRAGMENT }), ..Default::default() }.write_to(&mut payload);
+        let register = Header { code: MCodeType::REGISTER.code(), fsm_id: 7000000, ..Default::default() };
+        ms.send_to(&send(&register, &payload), &cn_path).await.unwrap();
+        session.accept_register().await.unwrap();
+        assert!(session.is_fragment_active());
+
+        let heartbeat = send(&Header { code: MCodeType::HEARTBEAT.code(), fsm_id: 7000000, ..Default::default() }, &[]);
+        let payload = vec![7_u8; 500];
+        let fragments = |sn: u16| {
+            let header = Header { code: MCodeType::PLAY.code(), fsm_id: 7000001, sn, ..Default::default() };
+            vn_fragment::split(&header, &payload, 100).unwrap()
+        };
+
+        // in time
+        for (header, payload) in fragments(1) {
+            ms.send_to(&send(&header, &payload), &cn_path).await.unwrap();
+        }
+        assert_eq!(session.recv_packet().await.unwrap().code(), MCodeType::PLAY.code());
+
+        // rest of the set comes after the first fragment expired
+        let mut rest = fragments(2).into_iter();
+        let (header, first) = rest.next().unwrap();
+        ms.send_to(&send(&header, &first), &cn_path).await.unwrap();
+        ms.send_to(&heartbeat, &cn_path).await.unwrap();
+        assert_eq!(session.recv_packet().await.unwrap().code(), MCodeType::HEARTBEAT.code());
+
+        clock.advance(Duration::from_secs(10));
+        for (header, payload) in rest {
+            ms.send_to(&send(&header, &payload), &cn_path).await.unwrap();
+        }
+        ms.send_to(&heartbeat, &cn_path).await.unwrap();
+        assert_eq!(session.recv_packet().await.unwrap().code(), MCodeType::HEARTBEAT.code());
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+}
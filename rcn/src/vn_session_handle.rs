@@ -0,0 +1,235 @@
+//! cloneable handle of a [`CnSession`] for tasks sharing one link, e.g. the
+//! control plane, a scenario runner and the dashboard.
+//!
+//! The session moves into an actor (see utils::actor) which receives packets
+//! and runs the requests of every handle one at a time, so sns, fsm_ids and
+//! stats stay consistent without a lock held across sends. Packets received
+//! are fanned out to [`SessionHandle::subscribe`]. The actor stops when the
+//! last handle is dropped.
+//!
+//! ```no_run
+//! use rcn::vn_proto::{MCodeType, PacketRef};
+//! use rcn::vn_session::CnSession;
+//! use rcn::vn_session_handle::SessionHandle;
+//!
+//! # async fn demo() -> anyhow::Result<()> {
+//! let mut session = CnSession::bind_env(5).await?;
+//! session.handshake().await?;
+//! session.accept_register().await?;
+//!
+//! let handle = SessionHandle::spawn(session);
+//! let mut packets = handle.subscribe();
+//! let heartbeat = handle.clone();
+//! tokio::spawn(async move { heartbeat.send_heartbeat().await });
+//! let packet = packets.recv().await?;
+//! assert_eq!(PacketRef::parse_from(&packet)?.code(), MCodeType::HEARTBEAT.code());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+use tokio::sync::broadcast;
+use tracing::debug;
+
+use crate::{
+    utils::{actor::{Action, ActionRes, Actor, ActorHandler, AsyncHandler}, datagram::Datagram},
+    vn_proto::{MCodeType, Play, RequestChannel},
+    vn_session::CnSession,
+};
+
+/// packets a slow subscriber may fall behind before it misses some
+pub const PACKET_BACKLOG: usize = 1024;
+
+pub struct SessionHandle<S: Datagram = tokio::net::UnixDatagram> {
+    actor: Arc<Actor<SessionActor<S>>>,
+    packets: broadcast::Sender<Bytes>,
+    cn_id: u32,
+    base_fsm_id: u32,
+}
+
+impl<S: Datagram> Clone for SessionHandle<S> {
+    fn clone(&self) -> Self {
+        Self { actor: self.actor.clone(), packets: self.packets.clone(), cn_id: self.cn_id, base_fsm_id: self.base_fsm_id }
+    }
+}
+
+impl<S: Datagram> SessionHandle<S> {
+    /// take over session, usually registered already
+    pub fn spawn(session: CnSession<S>) -> Self {
+        let (packets, _rx) = broadcast::channel(PACKET_BACKLOG);
+        let cn_id = session.cn_id();
+        let base_fsm_id = session.base_fsm_id();
+        let actor = SessionActor { session, packets: packets.clone() }.start(format!("session-{cn_id}"));
+        Self { actor: Arc::new(actor), packets, cn_id, base_fsm_id }
+    }
+
+    pub fn cn_id(&self) -> u32 {
+        self.cn_id
+    }
+
+    pub fn base_fsm_id(&self) -> u32 {
+        self.base_fsm_id
+    }
+
+    /// packets received from now on, as parsed by CnSession::recv_packet
+    pub fn subscribe(&self) -> broadcast::Receiver<Bytes> {
+        self.packets.subscribe()
+    }
+
+    pub async fn send_request(&self, code: MCodeType, fsm_id: u32, payload: Vec<u8>) -> Result<usize> {
+        self.actor.invoker().invoke(SendOp { code, fsm_id, payload }).await?
+    }
+
+    pub async fn request_channel(&self, fsm_id: u32, req: &RequestChannel) -> Result<usize> {
+        let mut payload = Vec::new();
+        req.write_to(&mut payload);
+        self.send_request(MCodeType::REQUESTCHANNEL, fsm_id, payload).await
+    }
+
+    pub async fn play(&self, fsm_id: u32, req: &Play) -> Result<usize> {
+        let mut payload = Vec::new();
+        req.write_to(&mut payload);
+        self.send_request(MCodeType::PLAY, fsm_id, payload).await
+    }
+
+    pub async fn send_heartbeat(&self) -> Result<usize> {
+        self.send_request(MCodeType::HEARTBEAT, self.base_fsm_id, Vec::new()).await
+    }
+
+    pub async fn allocate_fsm_id(&self) -> Result<u32> {
+        self.with(|x| x.allocate_fsm_id()).await?
+    }
+
+    pub async fn release_fsm_id(&self, fsm_id: u32) -> Result<()> {
+        self.with(move |x| x.release_fsm_id(fsm_id)).await
+    }
+
+    /// run f on the session between other requests, e.g. to read stats
+    pub async fn with<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut CnSession<S>) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.actor.invoker().invoke(WithOp(f)).await
+    }
+}
+
+struct SessionActor<S: Datagram> {
+    session: CnSession<S>,
+    packets: broadcast::Sender<Bytes>,
+}
+
+struct SendOp {
+    code: MCodeType,
+    fsm_id: u32,
+    payload: Vec<u8>,
+}
+
+#[async_trait::async_trait]
+impl<S: Datagram> AsyncHandler<SendOp> for SessionActor<S> {
+    type Response = Result<usize>;
+
+    async fn handle(&mut self, req: SendOp) -> Self::Response {
+        self.session.send_request(req.code, req.fsm_id, &req.payload).await
+    }
+}
+
+struct WithOp<F>(F);
+
+#[async_trait::async_trait]
+impl<S, F, R> AsyncHandler<WithOp<F>> for SessionActor<S>
+where
+    S: Datagram,
+    F: FnOnce(&mut CnSession<S>) -> R + Send + 'static,
+    R: Send + 'static,
+{
+    type Response = R;
+
+    async fn handle(&mut self, req: WithOp<F>) -> Self::Response {
+        (req.0)(&mut self.session)
+    }
+}
+
+impl<S: Datagram> ActorHandler for SessionActor<S> {
+    type Next = Result<Bytes>;
+
+    type Msg = ();
+
+    type Result = ();
+
+    fn into_result(self) -> Self::Result {
+    }
+
+    // recv_packet only awaits the socket, dropping it for a request loses nothing
+    async fn wait_next(&mut self) -> Self::Next {
+        let packet = self.session.recv_packet().await?;
+        Ok(Bytes::copy_from_slice(packet.raw()))
+    }
+
+    async fn handle_next(&mut self, next: Self::Next) -> ActionRes {
+        match next {
+            // no subscriber is fine
+            Ok(packet) => { let _r = self.packets.send(packet); },
+            Err(e) => debug!("recv failed [{e:#}]"),
+        }
+        Ok(Action::None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        vn_ms_sim::{MsSim, MsSimConfig},
+        vn_proto::{MCodeType, PacketRef},
+        vn_session::CnSession,
+    };
+
+    use super::SessionHandle;
+
+    #[tokio::test]
+    async fn test_session_handle() {
+        let dir = std::env::temp_dir().join(format!("rcn_session_handle_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut sim = MsSim::bind(&dir, MsSimConfig::default()).await.unwrap();
+        let task = tokio::spawn(async move { sim.run().await });
+
+        let mut session = CnSession::bind(&dir, 5).await.unwrap();
+        session.handshake().await.unwrap();
+        session.accept_register().await.unwrap();
+        let handle = SessionHandle::spawn(session);
+        let mut packets = handle.subscribe();
+
+        // tasks sharing the session get distinct fsm_ids and in order sns
+        let tasks: Vec<_> = (0..4).map(|_| {
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                let fsm_id = handle.allocate_fsm_id().await.unwrap();
+                handle.send_heartbeat().await.unwrap();
+                fsm_id
+            })
+        }).collect();
+        let mut fsm_ids = Vec::new();
+        for task in tasks {
+            fsm_ids.push(task.await.unwrap());
+        }
+        fsm_ids.sort();
+        assert_eq!(fsm_ids, [5000001, 5000002, 5000003, 5000004]);
+
+        for _ in 0..4 {
+            let packet = tokio::time::timeout(Duration::from_secs(3), packets.recv()).await.unwrap().unwrap();
+            assert_eq!(PacketRef::parse_from(&packet).unwrap().code(), MCodeType::HEARTBEAT.code());
+        }
+        let (sent, next_sn) = handle.with(|x| (x.traffic().sent, x.next_sn(x.base_fsm_id()))).await.unwrap();
+        // CNISUP and the heartbeats
+        assert!(sent >= 5, "{sent}");
+        assert!(next_sn >= 5, "{next_sn}");
+
+        task.abort();
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,214 @@
+//! parity of two MS implementations behind one proxy.
+//!
+//! Every CN packet goes to the primary MS as usual and, as received, to a
+//! shadow MS whose answers never reach the CN. ACKs of both are paired by
+//! fsm_id and code in order of arrival and compared field by field, see
+//! vn_fields. Header sn is never compared as each MS numbers on its own.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    path::PathBuf,
+};
+
+use crate::{
+    utils::log_once::warn_first,
+    vn_fields::{packet_fields, FieldValue, Fields},
+    vn_proto::{CodeName, MCodeType, PacketRef},
+};
+
+/// divergences kept for the report, the rest are only counted
+pub const MAX_KEPT: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct ShadowConfig {
+    /// CINDIR of the shadow MS, proxy binds one mscn{id} per CN here
+    pub ms_dir: PathBuf,
+    /// fields not compared, e.g. audio_port when port ranges differ
+    pub ignore: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Primary,
+    Shadow,
+}
+
+/// a field of one ACK the two MS disagree on, None if absent on that side
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub fsm_id: u32,
+    pub code: u16,
+    pub field: String,
+    pub primary: Option<FieldValue>,
+    pub shadow: Option<FieldValue>,
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let show = |x: &Option<FieldValue>| x.as_ref().map(|x| x.to_string()).unwrap_or_else(|| "-".into());
+        write!(f, "{} fsm_id [{}] {}: primary [{}] shadow [{}]",
+            CodeName(self.code), self.fsm_id, self.field, show(&self.primary), show(&self.shadow))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ShadowStats {
+    /// ACK pairs compared
+    pub compared: u64,
+    /// pairs with at least one divergent field
+    pub diverged: u64,
+    /// divergences per field name
+    pub fields: BTreeMap<String, u64>,
+    /// ACKs still waiting for the other side
+    pub primary_only: u64,
+    pub shadow_only: u64,
+    /// first MAX_KEPT divergences
+    pub divergences: Vec<Divergence>,
+}
+
+impl ShadowStats {
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "shadow: compared {}, diverged {}, primary only {}, shadow only {}",
+            self.compared, self.diverged, self.primary_only, self.shadow_only,
+        )];
+        lines.extend(self.fields.iter().map(|(field, n)| format!("  field {field}: {n}")));
+        lines
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queues {
+    primary: VecDeque<Fields>,
+    shadow: VecDeque<Fields>,
+}
+
+#[derive(Debug, Default)]
+pub struct ShadowDiff {
+    ignore: BTreeSet<String>,
+    /// ACKs of one side not yet paired, by (fsm_id, code)
+    queued: HashMap<(u32, u16), Queues>,
+    stats: ShadowStats,
+}
+
+impl ShadowDiff {
+    pub fn new(ignore: &[String]) -> Self {
+        let mut ignore: BTreeSet<String> = ignore.iter().cloned().collect();
+        ignore.insert("sn".into());
+        Self { ignore, ..Default::default() }
+    }
+
+    /// packet from one MS to CN, divergences of the pair it completes
+    pub fn on_packet(&mut self, side: Side, data: &[u8]) -> Vec<Divergence> {
+        let Ok(packet) = PacketRef::parse_from(data) else { return Vec::new() };
+        let is_ack = MCodeType::try_from(packet.code()).ok().and_then(|x| x.request()).is_some();
+        if !is_ack {
+            return Vec::new()
+        }
+
+        let fields = packet_fields(&packet).unwrap_or_else(|e| {
+            let mut fields = Fields::new();
+            fields.insert("decode_error", e.to_string().into());
+            fields
+        });
+        let key = (packet.fsm_id(), packet.code());
+        let queues = self.queued.entry(key).or_default();
+        match side {
+            Side::Primary => queues.primary.push_back(fields),
+            Side::Shadow => queues.shadow.push_back(fields),
+        }
+        if queues.primary.is_empty() || queues.shadow.is_empty() {
+            return Vec::new()
+        }
+        let (Some(primary), Some(shadow)) = (queues.primary.pop_front(), queues.shadow.pop_front()) else {
+            return Vec::new()
+        };
+        if queues.primary.is_empty() && queues.shadow.is_empty() {
+            self.queued.remove(&key);
+        }
+
+        let found = self.compare(key, &primary, &shadow);
+        self.stats.compared += 1;
+        if !found.is_empty() {
+            self.stats.diverged += 1;
+        }
+        for d in found.iter() {
+            *self.stats.fields.entry(d.field.clone()).or_default() += 1;
+            warn_first!(self.stats.diverged, "shadow diverged, {d}");
+            if self.stats.divergences.len() < MAX_KEPT {
+                self.stats.divergences.push(d.clone());
+            }
+        }
+        found
+    }
+
+    fn compare(&self, (fsm_id, code): (u32, u16), primary: &Fields, shadow: &Fields) -> Vec<Divergence> {
+        let names: BTreeSet<&&str> = primary.keys().chain(shadow.keys()).collect();
+        names.into_iter()
+        .filter(|name| !self.ignore.contains(**name))
+        .filter_map(|name| {
+            let (p, s) = (primary.get(*name), shadow.get(*name));
+            (p != s).then(|| Divergence {
+                fsm_id,
+                code,
+                field: name.to_string(),
+                primary: p.cloned(),
+                shadow: s.cloned(),
+            })
+        })
+        .collect()
+    }
+
+    pub fn stats(&self) -> ShadowStats {
+        let mut stats = self.stats.clone();
+        for queues in self.queued.values() {
+            stats.primary_only += queues.primary.len() as u64;
+            stats.shadow_only += queues.shadow.len() as u64;
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        vn_fields::FieldValue,
+        vn_proto::{Header, MCodeType, PlayAck, RequestChannelAck},
+    };
+
+    use super::{ShadowDiff, Side};
+
+    fn ack(code: MCodeType, sn: u16, payload: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        Header { code: code.code(), fsm_id: 5000001, sn, key: 0 }.write_to2(&mut data, payload);
+        data
+    }
+
+    #[test]
+    fn test_shadow_diff() {
+        let mut diff = ShadowDiff::new(&["video_port".into()]);
+        let payload = |audio_port, video_port| {
+            let mut payload = Vec::new();
+            RequestChannelAck { audio_port, video_port, ..Default::default() }.write_to(&mut payload);
+            payload
+        };
+        // other sn and ignored field only
+        assert!(diff.on_packet(Side::Primary, &ack(MCodeType::REQUESTCHANNEL_ACK, 1, &payload(20000, 20002))).is_empty());
+        assert!(diff.on_packet(Side::Shadow, &ack(MCodeType::REQUESTCHANNEL_ACK, 7, &payload(20000, 30002))).is_empty());
+
+        assert!(diff.on_packet(Side::Shadow, &ack(MCodeType::REQUESTCHANNEL_ACK, 2, &payload(30000, 0))).is_empty());
+        let found = diff.on_packet(Side::Primary, &ack(MCodeType::REQUESTCHANNEL_ACK, 2, &payload(20004, 0)));
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].field.as_str(), &found[0].primary, &found[0].shadow), ("audio_port", &Some(FieldValue::Int(20004)), &Some(FieldValue::Int(30000))));
+
+        // requests and unpaired ACKs are not compared
+        assert!(diff.on_packet(Side::Primary, &ack(MCodeType::PLAY, 3, &[])).is_empty());
+        let mut payload = Vec::new();
+        PlayAck { result: 0, play_duration: 10 }.write_to(&mut payload);
+        assert!(diff.on_packet(Side::Primary, &ack(MCodeType::PLAY_ACK, 3, &payload)).is_empty());
+
+        let stats = diff.stats();
+        assert_eq!((stats.compared, stats.diverged, stats.primary_only, stats.shadow_only), (2, 1, 1, 0));
+        assert_eq!(stats.fields.get("audio_port"), Some(&1));
+    }
+}
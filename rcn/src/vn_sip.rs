@@ -0,0 +1,583 @@
+//! tiny B2BUA, terminates a SIP leg over udp and drives a VN channel for it.
+//!
+//! INVITE with SDP offer sends REQUESTCHANNEL, the ACK of it sends
+//! OPENRTPCONNECT towards the rtp address of the offer, and the ACK of that
+//! answers 200 with the MS port in SDP. BYE and CANCEL send RELEASECHANNEL.
+//! No auth, no retransmission timers, no re-INVITE.
+
+use std::{collections::HashMap, fmt::Write, net::{Ipv4Addr, SocketAddr}, time::Instant};
+
+use anyhow::{Result, Context, bail};
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+use crate::{
+    utils::datagram::Datagram,
+    vn_cdr::CdrWriter,
+    vn_channels::{ChannelRegistry, EndReason},
+    vn_proto::{Header, MCodeType, OpenRtpConnect, OpenRtpConnectAck, RequestChannel, RequestChannelAckRef, RtpInfo},
+    vn_session::CnSession,
+};
+
+/// request or response, headers kept in original order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SipMessage {
+    pub start: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl SipMessage {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let text = std::str::from_utf8(data).with_context(||"SIP message not utf8")?;
+        let (head, body) = text.split_once("\r\n\r\n").with_context(||"no end of SIP headers")?;
+
+        let mut lines = head.split("\r\n");
+        let start = lines.next().unwrap_or_default().to_string();
+        if start.is_empty() {
+            bail!("empty SIP start line")
+        }
+
+        let mut headers = Vec::new();
+        for line in lines {
+            let (name, value) = line.split_once(':').with_context(||format!("invalid SIP header [{line}]"))?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        let mut me = Self { start, headers, body: String::new() };
+        let len = match me.header("Content-Length") {
+            Some(len) => len.parse::<usize>().with_context(||format!("invalid Content-Length [{len}]"))?,
+            None => body.len(),
+        };
+        me.body = body.get(..len).with_context(||format!("SIP body [{}] shorter than Content-Length [{len}]", body.len()))?.to_string();
+        Ok(me)
+    }
+
+    /// method of a request
+    pub fn method(&self) -> Option<&str> {
+        let (method, rest) = self.start.split_once(' ')?;
+        rest.ends_with("SIP/2.0").then_some(method)
+    }
+
+    /// status code of a response
+    pub fn status(&self) -> Option<u16> {
+        let rest = self.start.strip_prefix("SIP/2.0 ")?;
+        rest.split(' ').next()?.parse().ok()
+    }
+
+    /// first header of name, compact forms included
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+        .find(|x| x.0.eq_ignore_ascii_case(name) || compact_name(&x.0).is_some_and(|full| full.eq_ignore_ascii_case(name)))
+        .map(|x| x.1.as_str())
+    }
+
+    pub fn call_id(&self) -> Option<&str> {
+        self.header("Call-ID")
+    }
+
+    /// response to request, dialog headers copied
+    pub fn response(req: &Self, status: u16, reason: &str) -> Self {
+        let headers = req.headers.iter()
+        .filter(|x| {
+            let name = compact_name(&x.0).unwrap_or(&x.0);
+            ["Via", "From", "To", "Call-ID", "CSeq"].iter().any(|keep| keep.eq_ignore_ascii_case(name))
+        })
+        .cloned()
+        .collect();
+        Self { start: format!("SIP/2.0 {status} {reason}"), headers, body: String::new() }
+    }
+
+    /// add tag to To header unless it has one
+    pub fn set_to_tag(&mut self, tag: &str) {
+        for (name, value) in self.headers.iter_mut() {
+            if (name.eq_ignore_ascii_case("To") || name == "t") && !value.contains(";tag=") {
+                value.push_str(";tag=");
+                value.push_str(tag);
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut text = String::new();
+        let _r = write!(text, "{}\r\n", self.start);
+        for (name, value) in self.headers.iter().filter(|x| !x.0.eq_ignore_ascii_case("Content-Length") && x.0 != "l") {
+            let _r = write!(text, "{name}: {value}\r\n");
+        }
+        let _r = write!(text, "Content-Length: {}\r\n\r\n{}", self.body.len(), self.body);
+        text.into_bytes()
+    }
+}
+
+fn compact_name(name: &str) -> Option<&'static str> {
+    let full = match name {
+        "i" => "Call-ID",
+        "v" => "Via",
+        "f" => "From",
+        "t" => "To",
+        "l" => "Content-Length",
+        "c" => "Content-Type",
+        "m" => "Contact",
+        _ => return None,
+    };
+    Some(full)
+}
+
+/// audio part of an SDP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sdp {
+    pub ip: Ipv4Addr,
+    pub port: u16,
+    pub payload_types: Vec<u8>,
+}
+
+impl Sdp {
+    /// first audio m= line, c= of media level over session level
+    pub fn parse(body: &str) -> Result<Self> {
+        let mut session_ip = None;
+        let mut media_ip = None;
+        let mut audio: Option<(u16, Vec<u8>)> = None;
+        for line in body.lines() {
+            if let Some(addr) = line.strip_prefix("c=IN IP4 ") {
+                let ip = addr.trim().parse::<Ipv4Addr>().with_context(||format!("invalid SDP address [{line}]"))?;
+                match audio {
+                    Some(_) => media_ip = media_ip.or(Some(ip)),
+                    None => session_ip = Some(ip),
+                }
+            } else if let Some(media) = line.strip_prefix("m=") {
+                if audio.is_some() {
+                    break
+                }
+                let mut parts = media.split_whitespace();
+                if parts.next() != Some("audio") {
+                    continue
+                }
+                let port = parts.next().and_then(|x| x.parse().ok()).with_context(||format!("invalid SDP media [{line}]"))?;
+                let _proto = parts.next();
+                let payload_types = parts.filter_map(|x| x.parse().ok()).collect();
+                audio = Some((port, payload_types));
+            }
+        }
+
+        let (port, payload_types) = audio.with_context(||"no audio in SDP")?;
+        let ip = media_ip.or(session_ip).with_context(||"no IPv4 connection in SDP")?;
+        Ok(Self { ip, port, payload_types })
+    }
+
+    pub fn write(&self, session_id: u32) -> String {
+        let mut text = String::new();
+        let _r = write!(text, "v=0\r\no=rcn {session_id} {session_id} IN IP4 {}\r\ns=rcn\r\nc=IN IP4 {}\r\nt=0 0\r\n", self.ip, self.ip);
+        let _r = write!(text, "m=audio {} RTP/AVP", self.port);
+        for pt in self.payload_types.iter() {
+            let _r = write!(text, " {pt}");
+        }
+        text.push_str("\r\n");
+        for pt in self.payload_types.iter() {
+            let name = match pt {
+                0 => "PCMU/8000",
+                8 => "PCMA/8000",
+                18 => "G729/8000",
+                _ => continue,
+            };
+            let _r = write!(text, "a=rtpmap:{pt} {name}\r\n");
+        }
+        text
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SipBridgeConfig {
+    /// address put into answer SDP, where the MS receives rtp
+    pub media_ip: Ipv4Addr,
+    /// payload types accepted from offers, in order of preference
+    pub codecs: Vec<u8>,
+}
+
+impl Default for SipBridgeConfig {
+    fn default() -> Self {
+        Self {
+            media_ip: Ipv4Addr::LOCALHOST,
+            codecs: vec![8, 0],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CallState {
+    /// REQUESTCHANNEL sent
+    Allocating,
+    /// OPENRTPCONNECT sent
+    Connecting,
+    /// 200 sent
+    Answered,
+    /// ACK of 200 received
+    Confirmed,
+}
+
+struct Call {
+    fsm_id: u32,
+    state: CallState,
+    invite: SipMessage,
+    peer: SocketAddr,
+    offer: Sdp,
+    codec: u8,
+    /// audio port of MS from REQUESTCHANNEL_ACK
+    answer_port: u16,
+}
+
+pub struct SipBridge<S> {
+    udp: UdpSocket,
+    session: CnSession<S>,
+    config: SipBridgeConfig,
+    /// Call-ID -> call
+    calls: HashMap<String, Call>,
+    /// fsm_id -> Call-ID
+    fsm_ids: HashMap<u32, String>,
+    channels: ChannelRegistry,
+    buf: Vec<u8>,
+}
+
+impl<S: Datagram> SipBridge<S> {
+    /// session must be registered already, channels take its tenant
+    pub fn new(udp: UdpSocket, session: CnSession<S>, config: SipBridgeConfig) -> Self {
+        let mut channels = ChannelRegistry::default();
+        channels.set_tenant(session.tenant().map(String::from));
+        Self {
+            udp,
+            session,
+            config,
+            calls: HashMap::new(),
+            fsm_ids: HashMap::new(),
+            channels,
+            buf: vec![0; 65536],
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.udp.local_addr().with_context(||"no local addr of SIP socket")
+    }
+
+    pub fn num_calls(&self) -> usize {
+        self.calls.len()
+    }
+
+    pub fn channels(&self) -> &ChannelRegistry {
+        &self.channels
+    }
+
+    /// CDR of each call's channel as it ends, see vn_cdr
+    pub fn set_cdr(&mut self, cdr: Option<CdrWriter>) {
+        self.channels.set_cdr(cdr);
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        loop {
+            self.handle_next().await?;
+        }
+    }
+
+    /// handle one SIP message or VN packet
+    pub async fn handle_next(&mut self) -> Result<()> {
+        enum Input {
+            Sip(usize, SocketAddr),
+            Vn(Header, Vec<u8>),
+        }
+
+        let input = {
+            let udp = &self.udp;
+            let buf = &mut self.buf;
+            let session = &mut self.session;
+            tokio::select! {
+                r = udp.recv_from(buf) => {
+                    let (len, from) = r.with_context(||"recv SIP failed")?;
+                    Input::Sip(len, from)
+                },
+                r = session.recv_packet() => {
+                    let packet = r?;
+                    Input::Vn(packet.to_header(), packet.payload().to_vec())
+                },
+            }
+        };
+
+        match input {
+            Input::Sip(len, from) => {
+                match SipMessage::parse(&self.buf[..len]) {
+                    Ok(msg) => self.handle_sip(msg, from).await,
+                    Err(e) => {
+                        warn!("drop invalid SIP from [{from}], [{e:#}]");
+                        Ok(())
+                    },
+                }
+            },
+            Input::Vn(header, payload) => self.handle_vn(header, &payload).await,
+        }
+    }
+
+    async fn handle_sip(&mut self, msg: SipMessage, from: SocketAddr) -> Result<()> {
+        let Some(method) = msg.method() else {
+            debug!("ignore SIP response [{}] from [{from}]", msg.start);
+            return Ok(())
+        };
+        let call_id = msg.call_id().unwrap_or_default().to_string();
+        debug!("SIP [{method}] call [{call_id}] from [{from}]");
+
+        match method {
+            "INVITE" => self.on_invite(msg, call_id, from).await,
+            "ACK" => {
+                if let Some(call) = self.calls.get_mut(&call_id) {
+                    if call.state == CallState::Answered {
+                        call.state = CallState::Confirmed;
+                        info!("call [{call_id}] confirmed on fsm_id [{}]", call.fsm_id);
+                    }
+                }
+                Ok(())
+            },
+            "BYE" => {
+                let status = match self.end_call(&call_id, EndReason::Released).await? {
+                    Some(_call) => 200,
+                    None => 481,
+                };
+                self.reply(&msg, from, status, "").await
+            },
+            "CANCEL" => {
+                match self.end_call(&call_id, EndReason::Cancelled).await? {
+                    Some(call) => {
+                        self.reply(&msg, from, 200, "").await?;
+                        if matches!(call.state, CallState::Allocating | CallState::Connecting) {
+                            self.reply(&call.invite, call.peer, 487, "").await?;
+                        }
+                        Ok(())
+                    },
+                    None => self.reply(&msg, from, 481, "").await,
+                }
+            },
+            "OPTIONS" => self.reply(&msg, from, 200, "").await,
+            _ => self.reply(&msg, from, 501, "").await,
+        }
+    }
+
+    async fn on_invite(&mut self, msg: SipMessage, call_id: String, from: SocketAddr) -> Result<()> {
+        if self.calls.contains_key(&call_id) {
+            debug!("ignore INVITE again of call [{call_id}]");
+            return Ok(())
+        }
+
+        let offer = match Sdp::parse(&msg.body) {
+            Ok(offer) => offer,
+            Err(e) => {
+                warn!("reject call [{call_id}], [{e:#}]");
+                return self.reply(&msg, from, 488, "").await
+            },
+        };
+        let Some(codec) = self.config.codecs.iter().find(|x| offer.payload_types.contains(x)).copied() else {
+            warn!("reject call [{call_id}], no codec of {:?} in offer {:?}", self.config.codecs, offer.payload_types);
+            return self.reply(&msg, from, 488, "").await
+        };
+        let fsm_id = match self.session.allocate_fsm_id() {
+            Ok(fsm_id) => fsm_id,
+            Err(e) => {
+                warn!("reject call [{call_id}], [{e:#}]");
+                return self.reply(&msg, from, 503, "").await
+            },
+        };
+        self.reply(&msg, from, 100, "").await?;
+
+        let req = RequestChannel {
+            media_type: 1,
+            as_call_id: call_id.clone(),
+            ptime: 20,
+            codec,
+            webrtc: vec!["".into()],
+            ..Default::default()
+        };
+        self.session.request_channel(fsm_id, &req).await?;
+
+        info!("call [{call_id}] from [{from}] on fsm_id [{fsm_id}], remote rtp [{}:{}]", offer.ip, offer.port);
+        self.fsm_ids.insert(fsm_id, call_id.clone());
+        self.channels.on_requested(fsm_id, 0, Instant::now());
+        let mut payload = Vec::new();
+        req.write_to(&mut payload);
+        self.channels.on_packet(fsm_id, MCodeType::REQUESTCHANNEL.code(), &payload);
+        self.calls.insert(call_id, Call { fsm_id, state: CallState::Allocating, invite: msg, peer: from, offer, codec, answer_port: 0 });
+        Ok(())
+    }
+
+    async fn handle_vn(&mut self, header: Header, payload: &[u8]) -> Result<()> {
+        self.channels.on_packet(header.fsm_id, header.code, payload);
+        let Some(call_id) = self.fsm_ids.get(&header.fsm_id).cloned() else {
+            debug!("ignore VN packet {header:?}");
+            return Ok(())
+        };
+
+        match MCodeType::try_from(header.code) {
+            Ok(MCodeType::REQUESTCHANNEL_ACK) => {
+                let ack = RequestChannelAckRef::parse_from(payload)?;
+                let Some(call) = self.calls.get_mut(&call_id) else { return Ok(()) };
+                if ack.part1().result() != 0 {
+                    warn!("channel of call [{call_id}] refused, result [{}]", ack.part1().result());
+                    return self.fail_call(&call_id, ack.part1().result()).await
+                }
+                call.state = CallState::Connecting;
+                call.answer_port = ack.part1().audio_port();
+
+                let open = OpenRtpConnect { rtpinfos: vec![RtpInfo::plain(call.offer.ip, call.offer.port, call.codec)] };
+                let fsm_id = call.fsm_id;
+                let mut payload = Vec::new();
+                open.write_to(&mut payload);
+                self.session.send_request(MCodeType::OPENRTPCONNECT, fsm_id, &payload).await?;
+                self.channels.on_packet(fsm_id, MCodeType::OPENRTPCONNECT.code(), &payload);
+            },
+            Ok(MCodeType::OPENRTPCONNECT_ACK) => {
+                let result = OpenRtpConnectAck::parse_from(payload)?.value();
+                if result != 0 {
+                    warn!("rtp connect of call [{call_id}] failed, result [{result}]");
+                    return self.fail_call(&call_id, result).await
+                }
+                let contact = format!("<sip:rcn@{}>", self.local_addr()?);
+                let Some(call) = self.calls.get_mut(&call_id) else { return Ok(()) };
+                call.state = CallState::Answered;
+
+                let answer = Sdp { ip: self.config.media_ip, port: call.answer_port, payload_types: vec![call.codec] };
+                let mut rsp = SipMessage::response(&call.invite, 200, "OK");
+                rsp.set_to_tag(&format!("rcn{}", call.fsm_id));
+                rsp.headers.push(("Contact".into(), contact));
+                rsp.headers.push(("Content-Type".into(), "application/sdp".into()));
+                rsp.body = answer.write(call.fsm_id);
+                let peer = call.peer;
+                self.send_sip(&rsp, peer).await?;
+            },
+            _ => debug!("call [{call_id}] VN packet {header:?}"),
+        }
+        Ok(())
+    }
+
+    /// release channel and answer INVITE with 503
+    async fn fail_call(&mut self, call_id: &str, result: u8) -> Result<()> {
+        if let Some(call) = self.end_call(call_id, EndReason::MsError(result)).await? {
+            self.reply(&call.invite, call.peer, 503, "").await?;
+        }
+        Ok(())
+    }
+
+    async fn end_call(&mut self, call_id: &str, reason: EndReason) -> Result<Option<Call>> {
+        let Some(call) = self.calls.remove(call_id) else { return Ok(None) };
+        self.fsm_ids.remove(&call.fsm_id);
+        self.session.release_fsm_id(call.fsm_id);
+        self.session.send_request(MCodeType::RELEASECHANNEL, call.fsm_id, &[]).await?;
+        self.channels.on_packet(call.fsm_id, MCodeType::RELEASECHANNEL.code(), &[]);
+        self.channels.end(call.fsm_id, reason, Instant::now());
+        info!("call [{call_id}] ended, released fsm_id [{}]", call.fsm_id);
+        Ok(Some(call))
+    }
+
+    async fn reply(&mut self, req: &SipMessage, to: SocketAddr, status: u16, reason: &str) -> Result<()> {
+        let reason = if reason.is_empty() { reason_phrase(status) } else { reason };
+        let mut rsp = SipMessage::response(req, status, reason);
+        if status > 100 {
+            let fsm_id = req.call_id().and_then(|x| self.calls.get(x)).map(|x| x.fsm_id);
+            rsp.set_to_tag(&format!("rcn{}", fsm_id.unwrap_or_default()));
+        }
+        self.send_sip(&rsp, to).await
+    }
+
+    async fn send_sip(&mut self, msg: &SipMessage, to: SocketAddr) -> Result<()> {
+        debug!("SIP [{}] to [{to}]", msg.start);
+        self.udp.send_to(&msg.to_bytes(), to).await.with_context(||format!("send SIP to [{to}] failed"))?;
+        Ok(())
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Trying",
+        200 => "OK",
+        481 => "Call/Transaction Does Not Exist",
+        487 => "Request Terminated",
+        488 => "Not Acceptable Here",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use crate::{
+        vn_ms_sim::{MsSim, MsSimConfig},
+        vn_session::CnSession,
+    };
+
+    use super::{Sdp, SipBridge, SipBridgeConfig, SipMessage};
+
+    async fn recv_sip(phone: &UdpSocket) -> SipMessage {
+        let mut buf = vec![0; 4096];
+        let len = tokio::time::timeout(Duration::from_secs(1), phone.recv(&mut buf)).await.unwrap().unwrap();
+        SipMessage::parse(&buf[..len]).unwrap()
+    }
+
+    fn request(method: &str, cseq: u32, body: &str) -> Vec<u8> {
+        SipMessage {
+            start: format!("{method} sip:rcn@127.0.0.1 SIP/2.0"),
+            headers: vec![
+                ("v".into(), "SIP/2.0/UDP 127.0.0.1;branch=z9hG4bK1".into()),
+                ("From".into(), "<sip:phone@127.0.0.1>;tag=p1".into()),
+                ("To".into(), "<sip:rcn@127.0.0.1>".into()),
+                ("i".into(), "call-1".into()),
+                ("CSeq".into(), format!("{cseq} {method}")),
+            ],
+            body: body.into(),
+        }.to_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_sip_bridge_call() {
+        let dir = std::env::temp_dir().join(format!("rcn_sip_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut sim = MsSim::bind(&dir, MsSimConfig::default()).await.unwrap();
+        let sim_task = tokio::spawn(async move { sim.run().await });
+
+        let mut session = CnSession::bind(&dir, 5).await.unwrap();
+        session.handshake().await.unwrap();
+        session.accept_register().await.unwrap();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut bridge = SipBridge::new(udp, session, SipBridgeConfig::default());
+        let addr = bridge.local_addr().unwrap();
+        let bridge_task = tokio::spawn(async move { bridge.run().await });
+
+        let phone = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        phone.connect(addr).await.unwrap();
+
+        let offer = "v=0\r\no=- 1 1 IN IP4 127.0.0.1\r\ns=-\r\nc=IN IP4 127.0.0.1\r\nt=0 0\r\nm=audio 40000 RTP/AVP 0 101\r\n";
+        phone.send(&request("INVITE", 1, offer)).await.unwrap();
+        assert_eq!(recv_sip(&phone).await.status(), Some(100));
+
+        let ok = recv_sip(&phone).await;
+        assert_eq!(ok.status(), Some(200));
+        assert_eq!(ok.call_id(), Some("call-1"));
+        assert!(ok.header("To").unwrap().contains(";tag="));
+        let answer = Sdp::parse(&ok.body).unwrap();
+        assert_eq!((answer.port, answer.payload_types), (20000, vec![0]));
+
+        phone.send(&request("ACK", 1, "")).await.unwrap();
+        phone.send(&request("BYE", 2, "")).await.unwrap();
+        let ok = recv_sip(&phone).await;
+        assert_eq!((ok.status(), ok.header("CSeq")), (Some(200), Some("2 BYE")));
+
+        phone.send(&request("BYE", 3, "")).await.unwrap();
+        assert_eq!(recv_sip(&phone).await.status(), Some(481));
+
+        let no_codec = offer.replace("RTP/AVP 0 101", "RTP/AVP 18");
+        phone.send(&request("INVITE", 4, &no_codec)).await.unwrap();
+        assert_eq!(recv_sip(&phone).await.status(), Some(488));
+
+        bridge_task.abort();
+        sim_task.abort();
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+}
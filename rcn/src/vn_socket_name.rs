@@ -0,0 +1,156 @@
+//! socket paths of CNs and MS under CINDIR from templates.
+//!
+//! One deployment binds `$CINDIR/mscn{id}` and `$CINDIR/msvn`, another
+//! `$CINDIR/cn/cn_{id}.sock` and `$CINDIR/ms/ms.sock`. The naming in force
+//! is process wide, set once at startup by --cn-socket-template and
+//! --ms-socket-template, and used by vn_session::cn_socket_path and
+//! vn_session::ms_socket_path.
+
+use std::{
+    borrow::Cow,
+    path::{Component, Path, PathBuf},
+    sync::RwLock,
+};
+
+use anyhow::{Result, bail};
+
+/// placeholder of the cn id in the cn template
+pub const ID: &str = "{id}";
+
+static NAMING: RwLock<SocketNaming> = RwLock::new(SocketNaming::DEFAULT);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketNaming {
+    /// relative to CINDIR, with one [`ID`]
+    cn: Cow<'static, str>,
+    /// relative to CINDIR
+    ms: Cow<'static, str>,
+}
+
+impl Default for SocketNaming {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl SocketNaming {
+    pub const DEFAULT: Self = Self { cn: Cow::Borrowed("mscn{id}"), ms: Cow::Borrowed("msvn") };
+
+    pub fn new(cn: &str, ms: &str) -> Result<Self> {
+        check_relative("cn", cn)?;
+        check_relative("ms", ms)?;
+        if cn.matches(ID).count() != 1 {
+            bail!("cn socket template [{cn}] must have [{ID}] once")
+        }
+        if ms.contains(ID) {
+            bail!("ms socket template [{ms}] can't have [{ID}]")
+        }
+        for (name, template) in [("cn", cn.replacen(ID, "", 1)), ("ms", ms.to_string())] {
+            if template.contains(['{', '}']) {
+                bail!("{name} socket template [{template}] has braces other than [{ID}]")
+            }
+        }
+        let me = Self { cn: cn.to_string().into(), ms: ms.to_string().into() };
+        if me.cn_id_of(Path::new(ms)).is_some() {
+            bail!("ms socket [{ms}] is also a cn socket of [{cn}]")
+        }
+        Ok(me)
+    }
+
+    pub fn cn_path(&self, cindir: &Path, cn_id: u32) -> PathBuf {
+        cindir.join(self.cn.replacen(ID, &cn_id.to_string(), 1))
+    }
+
+    pub fn ms_path(&self, cindir: &Path) -> PathBuf {
+        cindir.join(&*self.ms)
+    }
+
+    /// CINDIR of a ms path, None if path isn't one
+    pub fn cindir_of(&self, ms_path: &Path) -> Option<PathBuf> {
+        let mut dir = ms_path;
+        for _ in Path::new(&*self.ms).components() {
+            dir = dir.parent()?;
+        }
+        (self.ms_path(dir) == ms_path).then(|| dir.to_path_buf())
+    }
+
+    /// cn id of a path relative to CINDIR, None if not a cn socket
+    pub fn cn_id_of(&self, relative: &Path) -> Option<u32> {
+        let relative = relative.to_str()?;
+        let (prefix, suffix) = self.cn.split_once(ID)?;
+        let id = relative.strip_prefix(prefix)?.strip_suffix(suffix)?;
+        if id.is_empty() || !id.bytes().all(|x| x.is_ascii_digit()) {
+            return None
+        }
+        id.parse().ok()
+    }
+
+    /// path relative to CINDIR is of a cn or the ms
+    pub fn is_socket(&self, relative: &Path) -> bool {
+        relative == Path::new(&*self.ms) || self.cn_id_of(relative).is_some()
+    }
+
+    /// dirs sockets are bound in, CINDIR itself with the default naming
+    pub fn dirs(&self, cindir: &Path) -> Vec<PathBuf> {
+        let mut dirs = Vec::new();
+        for path in [self.cn_path(cindir, 0), self.ms_path(cindir)] {
+            if let Some(dir) = path.parent().filter(|x| !dirs.iter().any(|d| d == x)) {
+                dirs.push(dir.to_path_buf());
+            }
+        }
+        dirs
+    }
+}
+
+fn check_relative(name: &str, template: &str) -> Result<()> {
+    let path = Path::new(template);
+    if template.is_empty() || template.ends_with('/') {
+        bail!("{name} socket template [{template}] is not a file name")
+    }
+    if !path.components().all(|x| matches!(x, Component::Normal(_))) {
+        bail!("{name} socket template [{template}] must be relative to CINDIR without . or ..")
+    }
+    Ok(())
+}
+
+/// naming of every socket path from now on
+pub fn set_socket_naming(naming: SocketNaming) {
+    *NAMING.write().unwrap_or_else(|e| e.into_inner()) = naming;
+}
+
+pub fn socket_naming() -> SocketNaming {
+    NAMING.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use super::SocketNaming;
+
+    #[test]
+    fn test_socket_naming() {
+        let cindir = Path::new("/tmp/cin");
+        let naming = SocketNaming::default();
+        assert_eq!(naming.cn_path(cindir, 5), Path::new("/tmp/cin/mscn5"));
+        assert_eq!(naming.ms_path(cindir), Path::new("/tmp/cin/msvn"));
+        assert_eq!(naming.dirs(cindir), [PathBuf::from("/tmp/cin")]);
+        assert_eq!(naming.cn_id_of(Path::new("mscn12")), Some(12));
+        assert_eq!(naming.cn_id_of(Path::new("mscn")), None);
+
+        let naming = SocketNaming::new("cn/cn_{id}.sock", "ms/ms.sock").unwrap();
+        assert_eq!(naming.cn_path(cindir, 5), Path::new("/tmp/cin/cn/cn_5.sock"));
+        assert_eq!(naming.cindir_of(&naming.ms_path(cindir)).as_deref(), Some(cindir));
+        assert_eq!(naming.cindir_of(Path::new("/tmp/cin/msvn")), None);
+        assert_eq!(naming.dirs(cindir), [PathBuf::from("/tmp/cin/cn"), PathBuf::from("/tmp/cin/ms")]);
+        assert!(naming.is_socket(Path::new("cn/cn_7.sock")) && naming.is_socket(Path::new("ms/ms.sock")));
+        assert!(!naming.is_socket(Path::new("cn/cn_x.sock")));
+
+        for (cn, ms) in [
+            ("mscn", "msvn"), ("mscn{id}{id}", "msvn"), ("mscn{id}", "msvn{id}"), ("/abs/cn{id}", "msvn"),
+            ("../cn{id}", "msvn"), ("cn{id}", ""), ("cn{id}", "ms/"), ("cn{id}{x}", "msvn"), ("{id}", "5"),
+        ] {
+            assert!(SocketNaming::new(cn, ms).is_err(), "{cn} {ms}");
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! speech ops carried by PLAY, MRCP style.
+//!
+//! A FILENAME of `tts:<text>` goes to a [`TtsBackend`], one of
+//! `asr:<grammar>` goes to an [`AsrBackend`] and the recognized text is
+//! sent back to CN in RESFROMTAG. [`StubTts`] and [`StubAsr`] stand in
+//! for real engines.
+
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use anyhow::Result;
+
+use crate::vn_media::write_wav;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpeechOp<'a> {
+    Tts(&'a str),
+    Asr(&'a str),
+}
+
+impl<'a> SpeechOp<'a> {
+    /// None for ordinary files
+    pub fn parse(filename: &'a str) -> Option<Self> {
+        if let Some(text) = filename.strip_prefix("tts:") {
+            return Some(Self::Tts(text))
+        }
+        filename.strip_prefix("asr:").map(Self::Asr)
+    }
+}
+
+/// mono 16bit pcm
+#[derive(Debug, Clone, Default)]
+pub struct Synthesized {
+    pub sample_rate: u32,
+    pub samples: Vec<i16>,
+}
+
+impl Synthesized {
+    pub fn duration(&self) -> Duration {
+        if self.sample_rate == 0 {
+            return Duration::ZERO
+        }
+        Duration::from_secs_f64(self.samples.len() as f64 / self.sample_rate as f64)
+    }
+}
+
+pub trait TtsBackend: Send {
+    fn synthesize(&mut self, fsm_id: u32, text: &str) -> Result<Synthesized>;
+}
+
+pub trait AsrBackend: Send {
+    /// text sent back in RESFROMTAG
+    fn recognize(&mut self, fsm_id: u32, grammar: &str) -> Result<String>;
+}
+
+/// sine tone as long as the text, optionally saved as `<fsm_id>.wav`
+#[derive(Debug, Clone)]
+pub struct StubTts {
+    pub sample_rate: u32,
+    pub freq: f64,
+    pub ms_per_char: u64,
+    pub out_dir: Option<PathBuf>,
+}
+
+impl Default for StubTts {
+    fn default() -> Self {
+        Self {
+            sample_rate: 8000,
+            freq: 440.0,
+            ms_per_char: 60,
+            out_dir: None,
+        }
+    }
+}
+
+impl TtsBackend for StubTts {
+    fn synthesize(&mut self, fsm_id: u32, text: &str) -> Result<Synthesized> {
+        let ms = text.chars().count() as u64 * self.ms_per_char;
+        let num = (self.sample_rate as u64 * ms / 1000) as usize;
+        let step = std::f64::consts::TAU * self.freq / self.sample_rate as f64;
+        let samples = (0..num).map(|n| ((n as f64 * step).sin() * 8000.0) as i16).collect();
+        let synthesized = Synthesized { sample_rate: self.sample_rate, samples };
+
+        if let Some(dir) = &self.out_dir {
+            write_wav(&dir.join(format!("{fsm_id}.wav")), synthesized.sample_rate, &synthesized.samples)?;
+        }
+        Ok(synthesized)
+    }
+}
+
+/// canned results, cycled per grammar, "nomatch" if none
+#[derive(Debug, Clone, Default)]
+pub struct StubAsr {
+    /// for grammars without own results
+    pub results: Vec<String>,
+    pub by_grammar: HashMap<String, Vec<String>>,
+    counts: HashMap<String, usize>,
+}
+
+impl StubAsr {
+    pub fn new(results: Vec<String>) -> Self {
+        Self { results, ..Default::default() }
+    }
+}
+
+impl AsrBackend for StubAsr {
+    fn recognize(&mut self, _fsm_id: u32, grammar: &str) -> Result<String> {
+        let results = self.by_grammar.get(grammar).unwrap_or(&self.results);
+        if results.is_empty() {
+            return Ok("nomatch".into())
+        }
+        let count = self.counts.entry(grammar.to_string()).or_default();
+        let text = results[*count % results.len()].clone();
+        *count += 1;
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{AsrBackend, SpeechOp, StubAsr, StubTts, TtsBackend};
+
+    #[test]
+    fn test_speech_stubs() {
+        assert_eq!(SpeechOp::parse("tts:hello"), Some(SpeechOp::Tts("hello")));
+        assert_eq!(SpeechOp::parse("asr:digits"), Some(SpeechOp::Asr("digits")));
+        assert_eq!(SpeechOp::parse("file://cc/1.wav"), None);
+
+        let r = StubTts::default().synthesize(5000001, "hello").unwrap();
+        assert_eq!(r.duration(), Duration::from_millis(300));
+        assert!(r.samples.iter().any(|x| *x > 4000));
+
+        let mut asr = StubAsr::new(vec!["yes".into(), "no".into()]);
+        asr.by_grammar.insert("digits".into(), vec!["1234".into()]);
+        let texts: Vec<_> = ["yesno", "yesno", "digits", "yesno"].iter().map(|x| asr.recognize(1, x).unwrap()).collect();
+        assert_eq!(texts, ["yes", "no", "1234", "yes"]);
+        assert_eq!(StubAsr::default().recognize(1, "any").unwrap(), "nomatch");
+    }
+}
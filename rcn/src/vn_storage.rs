@@ -0,0 +1,671 @@
+//! where captures and archives are kept: a directory or an S3 compatible
+//! bucket.
+//!
+//! A [`ChunkWriter`] cuts what is written into objects of about a chunk
+//! size, named `<stem>.000001.<ext>` and so on, and puts them from a thread
+//! of its own so a week long capture streams out as it goes instead of
+//! filling the local disk. Chunks end where the writer flushes, a capture
+//! flushes after every record so each chunk reads as a capture by itself.
+//! What was flushed is put once it is max_age old even if nothing follows,
+//! and a writer outrunning the uploads waits for them instead of losing
+//! chunks.
+//!
+//! Buckets are given as `s3://bucket/prefix/name`, the endpoint and keys
+//! come from the usual variables:
+//!
+//! ```text
+//! AWS_ENDPOINT_URL=http://10.0.0.5:9000 AWS_ACCESS_KEY_ID=.. AWS_SECRET_ACCESS_KEY=.. AWS_REGION=us-east-1
+//! ```
+//!
+//! Requests are path style and signed with AWS signature v4, plain http
+//! only, e.g. MinIO in the lab or a local gateway.
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::{mpsc::{self, RecvTimeoutError, SendError, SyncSender, TrySendError}, Arc, Mutex, MutexGuard},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, Context, bail};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use time::{macros::format_description, OffsetDateTime};
+use tracing::{debug, info};
+
+use crate::{utils::{clock::{default_clock, SharedClock}, log_once::warn_first}, vn_capture::to_hex};
+
+/// chunks waiting for upload before the writer waits
+const MAX_QUEUED_CHUNKS: usize = 16;
+
+/// upload thread looks at the age of pending bytes at most this often
+const MIN_AGE_CHECK: Duration = Duration::from_millis(10);
+
+/// and at least this often, a clock other than the real one may jump ahead
+const MAX_AGE_CHECK: Duration = Duration::from_secs(1);
+
+const PUT_ATTEMPTS: u32 = 3;
+
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub trait Storage: Send {
+    /// writes object name, replacing it
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<()>;
+
+    fn get(&mut self, name: &str) -> Result<Vec<u8>>;
+
+    /// where name is kept, for logs
+    fn location(&self, name: &str) -> String;
+}
+
+#[derive(Debug, Clone)]
+pub struct FsStorage {
+    dir: PathBuf,
+}
+
+impl FsStorage {
+    pub fn new(dir: &Path) -> Self {
+        Self { dir: dir.to_path_buf() }
+    }
+}
+
+impl Storage for FsStorage {
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let path = self.dir.join(name);
+        std::fs::write(&path, data).with_context(||format!("write failed [{path:?}]"))
+    }
+
+    fn get(&mut self, name: &str) -> Result<Vec<u8>> {
+        let path = self.dir.join(name);
+        std::fs::read(&path).with_context(||format!("read failed [{path:?}]"))
+    }
+
+    fn location(&self, name: &str) -> String {
+        self.dir.join(name).display().to_string()
+    }
+}
+
+#[derive(Clone)]
+pub struct S3Config {
+    /// host:port
+    pub endpoint: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+impl std::fmt::Debug for S3Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Config")
+        .field("endpoint", &self.endpoint)
+        .field("region", &self.region)
+        .field("access_key", &self.access_key)
+        .finish()
+    }
+}
+
+impl S3Config {
+    /// AWS_ENDPOINT_URL, AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY are
+    /// required, AWS_REGION defaults to us-east-1
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| std::env::var(name).with_context(||format!("missing env [{name}]"));
+        let url = var("AWS_ENDPOINT_URL")?;
+        let Some(endpoint) = url.strip_prefix("http://") else {
+            bail!("only http:// endpoints supported but [{url}]")
+        };
+        Ok(Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            region: std::env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".into()),
+            access_key: var("AWS_ACCESS_KEY_ID")?,
+            secret_key: var("AWS_SECRET_ACCESS_KEY")?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct S3Storage {
+    config: S3Config,
+    bucket: String,
+    /// prepended to names, no trailing '/'
+    prefix: String,
+}
+
+impl S3Storage {
+    pub fn new(config: S3Config, bucket: &str, prefix: &str) -> Self {
+        Self { config, bucket: bucket.to_string(), prefix: prefix.trim_matches('/').to_string() }
+    }
+
+    fn key(&self, name: &str) -> String {
+        match self.prefix.is_empty() {
+            true => name.to_string(),
+            false => format!("{}/{name}", self.prefix),
+        }
+    }
+
+    /// one request per connection, status and body of the response
+    fn request(&self, method: &str, name: &str, body: &[u8]) -> Result<(u16, Vec<u8>)> {
+        let path = format!("/{}/{}", uri_encode(&self.bucket, false), uri_encode(&self.key(name), true));
+        let headers = sign(&self.config, method, &path, body, OffsetDateTime::now_utc());
+
+        let mut stream = TcpStream::connect(&self.config.endpoint)
+        .with_context(||format!("connect failed [{}]", self.config.endpoint))?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let mut head = format!("{method} {path} HTTP/1.1\r\nContent-Length: {}\r\nConnection: close\r\n", body.len());
+        for (k, v) in headers.iter() {
+            head.push_str(&format!("{k}: {v}\r\n"));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).with_context(||"read response failed")?;
+        let end = response.windows(4).position(|x| x == b"\r\n\r\n").with_context(||"incomplete response")?;
+        let head = String::from_utf8_lossy(&response[..end]);
+        let status = head.split_whitespace().nth(1).and_then(|x| x.parse::<u16>().ok())
+        .with_context(||format!("invalid status line [{}]", head.lines().next().unwrap_or_default()))?;
+        let chunked = head.lines().any(|x| {
+            x.split_once(':').is_some_and(|(k, v)| k.trim().eq_ignore_ascii_case("transfer-encoding") && v.trim().eq_ignore_ascii_case("chunked"))
+        });
+        let body = &response[end + 4..];
+        match chunked {
+            true => Ok((status, decode_chunked(body)?)),
+            false => Ok((status, body.to_vec())),
+        }
+    }
+}
+
+impl Storage for S3Storage {
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let (status, body) = self.request("PUT", name, data)?;
+        if status / 100 != 2 {
+            bail!("put [{}] failed, status [{status}] [{}]", self.location(name), String::from_utf8_lossy(&body))
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, name: &str) -> Result<Vec<u8>> {
+        let (status, body) = self.request("GET", name, &[])?;
+        if status / 100 != 2 {
+            bail!("get [{}] failed, status [{status}] [{}]", self.location(name), String::from_utf8_lossy(&body))
+        }
+        Ok(body)
+    }
+
+    fn location(&self, name: &str) -> String {
+        format!("s3://{}/{}", self.bucket, self.key(name))
+    }
+}
+
+pub fn is_remote(url: &str) -> bool {
+    url.starts_with("s3://")
+}
+
+/// storage of a path or s3:// url and the name of the object in it
+pub fn open_storage(url: &str) -> Result<(Box<dyn Storage>, String)> {
+    if let Some(rest) = url.strip_prefix("s3://") {
+        let (bucket, key) = rest.split_once('/').with_context(||format!("expect s3://bucket/name but [{url}]"))?;
+        let (prefix, name) = key.rsplit_once('/').unwrap_or(("", key));
+        if bucket.is_empty() || name.is_empty() {
+            bail!("expect s3://bucket/name but [{url}]")
+        }
+        let storage = S3Storage::new(S3Config::from_env()?, bucket, prefix);
+        return Ok((Box::new(storage), name.to_string()))
+    }
+
+    let path = Path::new(url);
+    let name = path.file_name().with_context(||format!("no file name in [{url}]"))?;
+    let dir = path.parent().filter(|x| !x.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    Ok((Box::new(FsStorage::new(dir)), name.to_string_lossy().into_owned()))
+}
+
+/// body of a `Transfer-Encoding: chunked` response, trailers ignored
+fn decode_chunked(mut data: &[u8]) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let end = data.windows(2).position(|x| x == b"\r\n").with_context(||"incomplete chunk size")?;
+        let line = String::from_utf8_lossy(&data[..end]);
+        // chunk extensions after ';' are ignored
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).with_context(||format!("invalid chunk size [{line}]"))?;
+        data = &data[end + 2..];
+        if size == 0 {
+            return Ok(body)
+        }
+        if data.len() < size + 2 {
+            bail!("chunk of [{size}] bytes but [{}] left", data.len())
+        }
+        body.extend_from_slice(&data[..size]);
+        data = &data[size + 2..];
+    }
+}
+
+/// headers signing a path style request, AWS signature v4
+fn sign(config: &S3Config, method: &str, path: &str, body: &[u8], now: OffsetDateTime) -> Vec<(&'static str, String)> {
+    let amz_date = now.format(format_description!("[year][month][day]T[hour][minute][second]Z")).unwrap_or_default();
+    let date = &amz_date[..8];
+    let payload_hash = to_hex(&Sha256::digest(body));
+    let canonical = format!(
+        "{method}\n{path}\n\nhost:{}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{amz_date}\n\nhost;x-amz-content-sha256;x-amz-date\n{payload_hash}",
+        config.endpoint,
+    );
+    let scope = format!("{date}/{}/s3/aws4_request", config.region);
+    let to_sign = format!("AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}", to_hex(&Sha256::digest(canonical.as_bytes())));
+
+    let key = [date, config.region.as_str(), "s3", "aws4_request"].iter()
+    .fold(format!("AWS4{}", config.secret_key).into_bytes(), |key, x| hmac_sha256(&key, x.as_bytes()));
+    let signature = to_hex(&hmac_sha256(&key, to_sign.as_bytes()));
+
+    vec![
+        ("Host", config.endpoint.clone()),
+        ("x-amz-content-sha256", payload_hash),
+        ("x-amz-date", amz_date.clone()),
+        ("Authorization", format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={signature}",
+            config.access_key,
+        )),
+    ]
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    // hmac accepts keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap_or_else(|_|unreachable!());
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// percent encoding of sigv4, '/' kept if keep_slash
+fn uri_encode(s: &str, keep_slash: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+            b'/' if keep_slash => out.push('/'),
+            _ => out.push_str(&format!("%{b:02X}")),
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkConfig {
+    /// a chunk is put once it has this many bytes
+    pub bytes: usize,
+    /// or is this old, so a quiet link still streams
+    pub max_age: Duration,
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        Self { bytes: 8 << 20, max_age: Duration::from_secs(300) }
+    }
+}
+
+/// flushed and not yet handed to the upload thread, shared with it so a
+/// quiet link still gets its chunk put once max_age is up
+struct Pending {
+    stem: String,
+    /// with its '.', empty if none
+    ext: String,
+    /// starts every chunk, e.g. a file header
+    header: Vec<u8>,
+    buf: Vec<u8>,
+    started: Instant,
+    chunks: u64,
+    /// chunks whose put failed after retries
+    failed: u64,
+}
+
+impl Pending {
+    /// name and data of the next chunk, None if nothing flushed
+    fn take(&mut self, now: Instant) -> Option<(String, Vec<u8>)> {
+        self.started = now;
+        if self.buf.is_empty() {
+            return None
+        }
+        self.chunks += 1;
+        let name = format!("{}.{:06}{}", self.stem, self.chunks, self.ext);
+        let mut data = self.header.clone();
+        data.append(&mut self.buf);
+        Some((name, data))
+    }
+}
+
+fn lock(pending: &Mutex<Pending>) -> MutexGuard<'_, Pending> {
+    pending.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// writes chunks of what is written to a storage, see module doc
+pub struct ChunkWriter {
+    tx: Option<SyncSender<(String, Vec<u8>)>>,
+    worker: Option<JoinHandle<()>>,
+    pending: Arc<Mutex<Pending>>,
+    /// written since the last flush
+    partial: Vec<u8>,
+    config: ChunkConfig,
+    /// of max_age
+    clock: SharedClock,
+    /// times the writer waited for uploads to catch up
+    stalled: u64,
+    dropped: u64,
+}
+
+impl ChunkWriter {
+    pub fn new(storage: Box<dyn Storage>, name: &str, config: ChunkConfig) -> Self {
+        Self::with_clock(storage, name, config, default_clock())
+    }
+
+    pub fn with_clock(mut storage: Box<dyn Storage>, name: &str, config: ChunkConfig, clock: SharedClock) -> Self {
+        let (stem, ext) = match name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() => (stem.to_string(), format!(".{ext}")),
+            _ => (name.to_string(), String::new()),
+        };
+        let pending = Arc::new(Mutex::new(Pending {
+            stem,
+            ext,
+            header: Vec::new(),
+            buf: Vec::new(),
+            started: clock.now(),
+            chunks: 0,
+            failed: 0,
+        }));
+        let (tx, rx) = mpsc::sync_channel::<(String, Vec<u8>)>(MAX_QUEUED_CHUNKS);
+        let shared = pending.clone();
+        let worker_clock = clock.clone();
+        let worker = std::thread::spawn(move || {
+            let clock = worker_clock;
+            loop {
+                let age = clock.now().saturating_duration_since(lock(&shared).started);
+                let wait = config.max_age.saturating_sub(age);
+                let (name, data) = match rx.recv_timeout(wait.clamp(MIN_AGE_CHECK, MAX_AGE_CHECK)) {
+                    Ok(next) => next,
+                    Err(RecvTimeoutError::Timeout) => {
+                        let now = clock.now();
+                        let mut pending = lock(&shared);
+                        if now.saturating_duration_since(pending.started) < config.max_age {
+                            continue
+                        }
+                        let Some(next) = pending.take(now) else { continue };
+                        next
+                    },
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                match put_with_retry(storage.as_mut(), &name, &data) {
+                    Ok(()) => debug!("put chunk [{}] [{}] bytes", storage.location(&name), data.len()),
+                    Err(e) => {
+                        let failed = {
+                            let mut pending = lock(&shared);
+                            pending.failed += 1;
+                            pending.failed
+                        };
+                        warn_first!(failed, "put chunk failed [{e:#}]");
+                    },
+                }
+            }
+        });
+        Self {
+            tx: Some(tx),
+            worker: Some(worker),
+            pending,
+            partial: Vec::new(),
+            config,
+            clock,
+            stalled: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn set_header(&mut self, header: &[u8]) {
+        lock(&self.pending).header = header.to_vec();
+    }
+
+    /// chunks handed to the upload thread so far
+    pub fn chunks(&self) -> u64 {
+        lock(&self.pending).chunks
+    }
+
+    /// chunks lost, put failed or upload thread gone
+    pub fn dropped(&self) -> u64 {
+        self.dropped + lock(&self.pending).failed
+    }
+
+    /// times the writer waited for uploads falling behind
+    pub fn stalled(&self) -> u64 {
+        self.stalled
+    }
+
+    /// waits for a free slot rather than losing a chunk, uploads falling
+    /// behind slow the writer down
+    fn ship(&mut self, chunk: (String, Vec<u8>)) {
+        let Some(tx) = &self.tx else { return };
+        let chunk = match tx.try_send(chunk) {
+            Ok(()) => return,
+            Err(TrySendError::Full(chunk)) => {
+                self.stalled += 1;
+                warn_first!(self.stalled, "uploads falling behind, writer waits, [{}] times", self.stalled);
+                chunk
+            },
+            Err(TrySendError::Disconnected(chunk)) => chunk,
+        };
+        if let Err(SendError((name, _))) = tx.send(chunk) {
+            self.dropped += 1;
+            warn_first!(self.dropped, "dropped chunk [{name}], upload thread gone");
+        }
+    }
+}
+
+fn put_with_retry(storage: &mut dyn Storage, name: &str, data: &[u8]) -> Result<()> {
+    let mut n = 1;
+    loop {
+        match storage.put(name, data) {
+            Ok(()) => return Ok(()),
+            Err(e) if n >= PUT_ATTEMPTS => return Err(e),
+            Err(e) => debug!("put [{name}] attempt [{n}] failed [{e:#}]"),
+        }
+        std::thread::sleep(Duration::from_secs(1 << n));
+        n += 1;
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    /// ends the chunk if big or old enough
+    fn flush(&mut self) -> std::io::Result<()> {
+        let chunk = {
+            let now = self.clock.now();
+            let mut pending = lock(&self.pending);
+            pending.buf.append(&mut self.partial);
+            if pending.buf.len() >= self.config.bytes || now.saturating_duration_since(pending.started) >= self.config.max_age {
+                pending.take(now)
+            } else {
+                None
+            }
+        };
+        if let Some(chunk) = chunk {
+            self.ship(chunk);
+        }
+        Ok(())
+    }
+}
+
+/// puts the last chunk and waits for uploads
+impl Drop for ChunkWriter {
+    fn drop(&mut self) {
+        let chunk = {
+            let mut pending = lock(&self.pending);
+            pending.buf.append(&mut self.partial);
+            pending.take(self.clock.now())
+        };
+        if let Some(chunk) = chunk {
+            self.ship(chunk);
+        }
+        self.tx = None;
+        if let Some(worker) = self.worker.take() {
+            let _r = worker.join();
+        }
+        let pending = lock(&self.pending);
+        info!(
+            "put [{}] chunks of [{}{}], [{}] failed, [{}] dropped, writer waited [{}] times",
+            pending.chunks, pending.stem, pending.ext, pending.failed, self.dropped, self.stalled,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        io::{Read, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::vn_capture::{parse_capture_bytes, CaptureDir, CaptureWriter};
+
+    use super::{open_storage, ChunkConfig, ChunkWriter, S3Config, S3Storage, Storage};
+
+    type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// answers PUT and GET of signed requests, keeping objects by path
+    fn fake_s3() -> (String, Objects) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        let objects = Arc::new(Mutex::new(HashMap::new()));
+        let store = objects.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut req = Vec::new();
+                let mut buf = [0_u8; 4096];
+                let (head, body) = loop {
+                    let n = stream.read(&mut buf).unwrap();
+                    req.extend_from_slice(&buf[..n]);
+                    let Some(end) = req.windows(4).position(|x| x == b"\r\n\r\n") else { continue };
+                    let head = String::from_utf8_lossy(&req[..end]).into_owned();
+                    let len: usize = head.lines().find_map(|x| x.strip_prefix("Content-Length: ")).unwrap().parse().unwrap();
+                    if req.len() >= end + 4 + len {
+                        break (head, req[end + 4..].to_vec())
+                    }
+                };
+                let mut words = head.split_whitespace();
+                let (method, path) = (words.next().unwrap().to_string(), words.next().unwrap().to_string());
+                let signed = head.contains("Authorization: AWS4-HMAC-SHA256 Credential=ak/")
+                    && head.contains("SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature=");
+                let stored = store.lock().unwrap().get(&path).cloned();
+                let response = match (signed, method.as_str(), stored) {
+                    (false, _, _) => b"HTTP/1.1 403 Forbidden\r\n\r\n".to_vec(),
+                    (true, "PUT", _) => {
+                        store.lock().unwrap().insert(path, body);
+                        b"HTTP/1.1 200 OK\r\n\r\n".to_vec()
+                    },
+                    (true, _, Some(data)) if path.contains("chunked") => {
+                        let (a, b) = data.split_at(data.len() / 2);
+                        let mut response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+                        for part in [a, b] {
+                            response.extend_from_slice(format!("{:x};x=1\r\n", part.len()).as_bytes());
+                            response.extend_from_slice(part);
+                            response.extend_from_slice(b"\r\n");
+                        }
+                        response.extend_from_slice(b"0\r\n\r\n");
+                        response
+                    },
+                    (true, _, Some(data)) => [&b"HTTP/1.1 200 OK\r\n\r\n"[..], &data].concat(),
+                    (true, _, None) => b"HTTP/1.1 404 Not Found\r\n\r\nNoSuchKey".to_vec(),
+                };
+                stream.write_all(&response).unwrap();
+            }
+        });
+        (endpoint, objects)
+    }
+
+    #[test]
+    fn test_storage() {
+        let (endpoint, objects) = fake_s3();
+        let config = S3Config { endpoint, region: "us-east-1".into(), access_key: "ak".into(), secret_key: "sk".into() };
+        let mut s3 = S3Storage::new(config, "caps", "/lab 1/");
+        s3.put("a.jsonl", b"hello").unwrap();
+        assert!(objects.lock().unwrap().contains_key("/caps/lab%201/a.jsonl"));
+        assert_eq!(s3.get("a.jsonl").unwrap(), b"hello");
+        s3.put("chunked.jsonl", b"hello world").unwrap();
+        assert_eq!(s3.get("chunked.jsonl").unwrap(), b"hello world");
+        let e = s3.get("b.jsonl").unwrap_err();
+        assert!(format!("{e:#}").contains("get [s3://caps/lab 1/b.jsonl] failed, status [404]"), "{e:#}");
+
+        // vnrec chunks each with a header, readable by themselves
+        let dir = std::env::temp_dir().join(format!("rcn_storage_{}", std::process::id()));
+        let _r = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (storage, name) = open_storage(dir.join("cap.vnrec").to_str().unwrap()).unwrap();
+        assert_eq!(name, "cap.vnrec");
+        let config = ChunkConfig { bytes: 40, max_age: Duration::from_secs(3600) };
+        let mut capture = CaptureWriter::create_chunked(storage, &name, config).unwrap();
+        for n in 0..5_u8 {
+            capture.write(CaptureDir::CnToMs, &[0, 10, 0, 1, 0, 0, 0, n, 0, 0, 0, 0]).unwrap();
+        }
+        drop(capture);
+        let mut records = Vec::new();
+        for n in 1..=3 {
+            let data = std::fs::read(dir.join(format!("cap.{n:06}.vnrec"))).unwrap();
+            records.extend(parse_capture_bytes(&data).unwrap());
+        }
+        assert!(!dir.join("cap.000004.vnrec").exists());
+        let fsm_ids: Vec<u8> = records.iter().map(|x| x.data().unwrap()[7]).collect();
+        assert_eq!(fsm_ids, [0, 1, 2, 3, 4]);
+
+        // quiet link, chunk put once max_age is up
+        let (storage, name) = open_storage(dir.join("quiet.vnrec").to_str().unwrap()).unwrap();
+        let config = ChunkConfig { bytes: 1 << 20, max_age: Duration::from_millis(50) };
+        let mut capture = CaptureWriter::create_chunked(storage, &name, config).unwrap();
+        capture.write(CaptureDir::CnToMs, &[0, 10, 0, 1, 0, 0, 0, 7, 0, 0, 0, 0]).unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        let data = std::fs::read(dir.join("quiet.000001.vnrec")).unwrap();
+        assert_eq!(parse_capture_bytes(&data).unwrap().len(), 1);
+        drop(capture);
+        assert!(!dir.join("quiet.000002.vnrec").exists());
+        let _r = std::fs::remove_dir_all(&dir);
+    }
+
+    /// puts slower than chunks come
+    struct SlowStorage(Objects);
+
+    impl Storage for SlowStorage {
+        fn put(&mut self, name: &str, data: &[u8]) -> anyhow::Result<()> {
+            std::thread::sleep(Duration::from_millis(5));
+            self.0.lock().unwrap().insert(name.to_string(), data.to_vec());
+            Ok(())
+        }
+
+        fn get(&mut self, name: &str) -> anyhow::Result<Vec<u8>> {
+            self.0.lock().unwrap().get(name).cloned().ok_or_else(|| anyhow::anyhow!("no [{name}]"))
+        }
+
+        fn location(&self, name: &str) -> String {
+            name.to_string()
+        }
+    }
+
+    #[test]
+    fn test_chunk_backpressure() {
+        let objects = Objects::default();
+        let config = ChunkConfig { bytes: 1, max_age: Duration::from_secs(3600) };
+        let mut writer = ChunkWriter::new(Box::new(SlowStorage(objects.clone())), "a.bin", config);
+        for n in 0..40_u8 {
+            writer.write_all(&[n]).unwrap();
+            writer.flush().unwrap();
+        }
+        assert!(writer.stalled() > 0);
+        assert_eq!(writer.dropped(), 0);
+        drop(writer);
+        let objects = objects.lock().unwrap();
+        assert_eq!(objects.len(), 40);
+        assert_eq!(objects["a.000040.bin"], [39]);
+    }
+}
@@ -0,0 +1,257 @@
+//! decoded packets streamed live to `rcn ctl tail` over the ctl socket.
+//!
+//! A tail subscribes by sending `tail <filter json>` from a socket of its
+//! own and gets a [`TailEvent`] as json per matching packet from then on,
+//! one datagram each. It renews by sending the same again within
+//! [`TAIL_TTL`], one not renewed or whose socket is gone is dropped.
+//! Packets come from the [`tap`] cli, proxy and ms-sim install when
+//! --ctl-socket is given. Sends never block, a tail too slow to keep up
+//! loses events rather than holding up the link.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::ErrorKind,
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::{
+    vn_capture::{CaptureDir, CaptureRecord, RecordTap},
+    vn_fields::{packet_fields, FieldValue},
+    vn_proto::{MCode, PacketRef},
+};
+
+/// a tail not renewed for this long is dropped
+pub const TAIL_TTL: Duration = Duration::from_secs(10);
+
+/// tails of a process at once
+pub const MAX_TAILS: usize = 8;
+
+const HEADER_FIELDS: [&str; 4] = ["code", "fsm_id", "sn", "key"];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static TAILS: Mutex<Tails> = Mutex::new(Tails { socket: None, list: Vec::new() });
+
+struct Tails {
+    /// unbound, sends to every tail
+    socket: Option<UnixDatagram>,
+    list: Vec<Tail>,
+}
+
+struct Tail {
+    path: PathBuf,
+    filter: TailFilter,
+    expires: Instant,
+    /// events lost to a full socket
+    lost: u64,
+}
+
+/// packets a tail gets, all if empty
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TailFilter {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub codes: Vec<MCode>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fsm_ids: Vec<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<CaptureDir>,
+}
+
+impl TailFilter {
+    pub fn matches(&self, event: &TailEvent) -> bool {
+        (self.codes.is_empty() || self.codes.contains(&event.code))
+        && (self.fsm_ids.is_empty() || self.fsm_ids.contains(&event.fsm_id))
+        && self.dir.is_none_or(|x| x == event.dir)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TailEvent {
+    /// since start of the tapped link
+    pub ts_us: u64,
+    pub dir: CaptureDir,
+    pub code: MCode,
+    pub fsm_id: u32,
+    pub sn: u16,
+    pub key: i16,
+    /// whole packet
+    pub length: usize,
+    /// flat payload fields as in scenario expectations, see vn_fields,
+    /// empty if the payload isn't decoded
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, serde_json::Value>,
+}
+
+impl TailEvent {
+    /// None if not a packet
+    pub fn from_record(record: &CaptureRecord) -> Option<Self> {
+        let data = record.data().ok()?;
+        let packet = PacketRef::parse_from(&data[..]).ok()?;
+        let fields = packet_fields(&packet).unwrap_or_default().into_iter()
+        // header ones are fields of the event
+        .filter(|x| !HEADER_FIELDS.contains(&x.0))
+        .map(|(name, value)| {
+            let value = match value {
+                FieldValue::Int(v) => v.into(),
+                FieldValue::Str(v) => v.into(),
+            };
+            (name.to_string(), value)
+        }).collect();
+        Some(Self {
+            ts_us: record.ts_us,
+            dir: record.dir,
+            code: MCode::new(packet.code()),
+            fsm_id: packet.fsm_id(),
+            sn: packet.sn(),
+            key: packet.key(),
+            length: data.len(),
+            fields,
+        })
+    }
+}
+
+/// one line, e.g. `12.000345 cn_to_ms PLAY fsm_id [5000001] sn [3] key [0] len [52] filename="..."`
+impl fmt::Display for TailEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let dir = match self.dir {
+            CaptureDir::CnToMs => "cn_to_ms",
+            CaptureDir::MsToCn => "ms_to_cn",
+        };
+        write!(
+            f, "{}.{:06} {dir} {} fsm_id [{}] sn [{}] key [{}] len [{}]",
+            self.ts_us / 1_000_000, self.ts_us % 1_000_000, self.code, self.fsm_id, self.sn, self.key, self.length,
+        )?;
+        for (name, value) in self.fields.iter() {
+            write!(f, " {name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// taps hand packets to tails from now on, done by the ctl socket
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// for CaptureWriter::add_tap and alike, None without a ctl socket
+pub fn tap() -> Option<RecordTap> {
+    ENABLED.load(Ordering::Relaxed).then(|| Arc::new(publish) as RecordTap)
+}
+
+/// new tail at path or the one there renewed with filter, true if new
+pub fn subscribe(path: &Path, filter: TailFilter, now: Instant) -> Result<bool> {
+    let mut tails = TAILS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(tail) = tails.list.iter_mut().find(|x| x.path == path) {
+        tail.filter = filter;
+        tail.expires = now + TAIL_TTL;
+        return Ok(false)
+    }
+    tails.list.retain(|x| x.expires > now);
+    if tails.list.len() >= MAX_TAILS {
+        bail!("already [{}] tails", tails.list.len())
+    }
+    if tails.socket.is_none() {
+        let socket = UnixDatagram::unbound()?;
+        socket.set_nonblocking(true)?;
+        tails.socket = Some(socket);
+    }
+    info!("tail [{path:?}] {filter:?}");
+    tails.list.push(Tail { path: path.to_path_buf(), filter, expires: now + TAIL_TTL, lost: 0 });
+    Ok(true)
+}
+
+fn publish(record: &CaptureRecord) {
+    let mut tails = TAILS.lock().unwrap_or_else(|e| e.into_inner());
+    if tails.list.is_empty() {
+        return
+    }
+    let now = Instant::now();
+    let Tails { socket, list } = &mut *tails;
+    list.retain(|x| {
+        let alive = x.expires > now;
+        if !alive {
+            info!("tail [{:?}] expired, lost [{}]", x.path, x.lost);
+        }
+        alive
+    });
+    let (Some(socket), Some(event)) = (socket.as_ref(), TailEvent::from_record(record)) else { return };
+    let Ok(json) = serde_json::to_vec(&event) else { return };
+    list.retain_mut(|tail| {
+        if !tail.filter.matches(&event) {
+            return true
+        }
+        match socket.send_to(&json, &tail.path) {
+            Ok(_n) => true,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                tail.lost += 1;
+                debug!("tail [{:?}] full, lost [{}]", tail.path, tail.lost);
+                true
+            },
+            Err(e) => {
+                info!("tail [{:?}] gone [{e}], lost [{}]", tail.path, tail.lost);
+                false
+            },
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::{os::unix::net::UnixDatagram, time::{Duration, Instant}};
+
+    use crate::{
+        vn_capture::{CaptureDir, CaptureRecord},
+        vn_proto::{Header, MCode, MCodeType, PlayAck},
+    };
+
+    use super::{publish, subscribe, TailEvent, TailFilter, TAILS, TAIL_TTL};
+
+    fn record(dir: CaptureDir, code: MCodeType, fsm_id: u32) -> CaptureRecord {
+        let mut payload = Vec::new();
+        PlayAck { result: 2, play_duration: 4820 }.write_to(&mut payload);
+        let mut data = Vec::new();
+        Header { code: code.code(), fsm_id, sn: 7, key: 0 }.write_to2(&mut data, &payload[..]);
+        CaptureRecord::new(Duration::from_micros(1_000_345), dir, None, &data)
+    }
+
+    #[test]
+    fn test_tail() {
+        let path = std::env::temp_dir().join(format!("rcn_tail_test_{}", std::process::id()));
+        let _r = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        let filter: TailFilter = serde_json::from_str(r#"{"codes":["play_ack","0x1d"],"dir":"ms_to_cn"}"#).unwrap();
+        assert_eq!(filter.codes, [MCode::new(MCodeType::PLAY_ACK.code()), MCode::new(0x1d)]);
+        let now = Instant::now();
+        assert!(subscribe(&path, filter.clone(), now).unwrap());
+        assert!(!subscribe(&path, filter, now).unwrap());
+
+        publish(&record(CaptureDir::MsToCn, MCodeType::HEARTBEAT, 5000000));
+        publish(&record(CaptureDir::CnToMs, MCodeType::PLAY_ACK, 5000001));
+        publish(&record(CaptureDir::MsToCn, MCodeType::PLAY_ACK, 5000001));
+        let mut buf = vec![0_u8; 4096];
+        let len = socket.recv(&mut buf).unwrap();
+        let event: TailEvent = serde_json::from_slice(&buf[..len]).unwrap();
+        assert_eq!((event.code, event.fsm_id, event.dir), (MCode::new(MCodeType::PLAY_ACK.code()), 5000001, CaptureDir::MsToCn));
+        assert_eq!((event.fields["result"].as_i64(), event.length), (Some(2), 17));
+        assert_eq!(event.to_string(), "1.000345 ms_to_cn PLAY_ACK fsm_id [5000001] sn [7] key [0] len [17] play_duration=4820 result=2");
+
+        // gone tails are dropped, expired ones too
+        drop(socket);
+        let _r = std::fs::remove_file(&path);
+        publish(&record(CaptureDir::MsToCn, MCodeType::PLAY_ACK, 5000001));
+        assert!(TAILS.lock().unwrap().list.iter().all(|x| x.path != path));
+        assert!(subscribe(&path, TailFilter::default(), now - TAIL_TTL).unwrap());
+        publish(&record(CaptureDir::MsToCn, MCodeType::PLAY_ACK, 5000001));
+        assert!(TAILS.lock().unwrap().list.iter().all(|x| x.path != path));
+    }
+}
@@ -0,0 +1,246 @@
+//! helpers for integration tests of CN implementations built on this crate.
+//!
+//! ```no_run
+//! # async fn demo() -> anyhow::Result<()> {
+//! use rcn::{vn_ms_sim::MsSimConfig, vn_proto::MCodeType, vn_testkit::{expect_packet, MsStub, ScenarioBuilder}};
+//!
+//! let (_ms, mut session) = MsStub::start(MsSimConfig::default(), 5);
+//! session.handshake().await?;
+//! session.accept_register().await?;
+//! session.send_request(MCodeType::HEARTBEAT, session.base_fsm_id(), &[]).await?;
+//! expect_packet(MCodeType::HEARTBEAT).recv(&mut session).await?;
+//!
+//! let scenario = ScenarioBuilder::new("one call")
+//! .request_channel(1, Default::default())
+//! .expect(expect_packet(MCodeType::REQUESTCHANNEL_ACK).fsm(1).with_field("result", 0))
+//! .send(MCodeType::RELEASECHANNEL, 1)
+//! .build()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`MsStub`] is ms-sim (see vn_ms_sim) on an in-memory socket, no $CINDIR
+//! needed. Expectations are scenario expect steps, see vn_scenario.
+
+use std::{io, path::{Path, PathBuf}, time::Duration};
+
+use anyhow::Result;
+use tokio::{sync::{mpsc, Mutex}, task::JoinHandle};
+
+use crate::{
+    utils::datagram::Datagram,
+    vn_fields::Fields,
+    vn_impair::ImpairProfile,
+    vn_ms_sim::{MsSim, MsSimConfig},
+    vn_proto::MCodeType,
+    vn_scenario::{default_codecs, default_timeout_ms, default_video_codecs, expect_fields, ExpectStep, ImpairStep, PlaySpec, QuietStep, RequestChannelSpec, Scenario, SendStep, Step},
+    vn_session::CnSession,
+};
+
+/// one end of an in-memory datagram pair, targets are ignored as
+/// everything goes to the other end
+#[derive(Debug)]
+pub struct MemDatagram {
+    path: PathBuf,
+    tx: mpsc::UnboundedSender<(Vec<u8>, PathBuf)>,
+    rx: Mutex<mpsc::UnboundedReceiver<(Vec<u8>, PathBuf)>>,
+}
+
+/// ends seen by each other as sent from path a and b
+pub fn mem_pair(a: &Path, b: &Path) -> (MemDatagram, MemDatagram) {
+    let (a_tx, b_rx) = mpsc::unbounded_channel();
+    let (b_tx, a_rx) = mpsc::unbounded_channel();
+    (
+        MemDatagram { path: a.to_path_buf(), tx: a_tx, rx: Mutex::new(a_rx) },
+        MemDatagram { path: b.to_path_buf(), tx: b_tx, rx: Mutex::new(b_rx) },
+    )
+}
+
+#[async_trait::async_trait]
+impl Datagram for MemDatagram {
+    async fn send_to(&self, buf: &[u8], _target: &Path) -> io::Result<usize> {
+        self.tx.send((buf.to_vec(), self.path.clone())).map_err(|_e| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        Ok(buf.len())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Option<PathBuf>)> {
+        let Some((data, from)) = self.rx.lock().await.recv().await else {
+            return Err(io::ErrorKind::ConnectionReset.into())
+        };
+        // cut like a real socket would
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok((len, Some(from)))
+    }
+}
+
+/// ms-sim running in the background, stopped when dropped
+#[derive(Debug)]
+pub struct MsStub {
+    task: JoinHandle<Result<()>>,
+}
+
+impl MsStub {
+    /// stub and a session of cn_id connected to it, not yet registered
+    pub fn start(config: MsSimConfig, cn_id: u32) -> (Self, CnSession<MemDatagram>) {
+        let (ms_path, cn_path) = (PathBuf::from("mem:msvn"), PathBuf::from(format!("mem:mscn{cn_id}")));
+        let (ms, cn) = mem_pair(&ms_path, &cn_path);
+        let mut sim = MsSim::with_socket(ms, config);
+        let task = tokio::spawn(async move { sim.run().await });
+        (Self { task }, CnSession::with_socket(cn, ms_path, cn_id))
+    }
+}
+
+impl Drop for MsStub {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// expectation of the next packet of a code, fields matched as in scenarios,
+/// e.g. 0, "abc", ">0", "!=3"
+#[derive(Debug, Clone)]
+pub struct PacketExpect {
+    step: ExpectStep,
+}
+
+pub fn expect_packet(code: MCodeType) -> PacketExpect {
+    PacketExpect {
+        step: ExpectStep { code: format!("{code:?}"), fsm: None, timeout_ms: default_timeout_ms(), fields: Default::default() },
+    }
+}
+
+impl PacketExpect {
+    /// only packets of channel fsm, fsm_id = base_fsm_id + fsm
+    pub fn fsm(mut self, fsm: u32) -> Self {
+        self.step.fsm = Some(fsm);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.step.timeout_ms = timeout.as_millis() as u64;
+        self
+    }
+
+    pub fn with_field(mut self, name: &str, value: impl Into<serde_yaml::Value>) -> Self {
+        self.step.fields.insert(name.to_string(), value.into());
+        self
+    }
+
+    /// fields of the matching packet, packets of other codes are skipped
+    pub async fn recv<S: Datagram>(&self, session: &mut CnSession<S>) -> Result<Fields> {
+        expect_fields(session, &self.step).await
+    }
+
+    pub fn into_step(self) -> ExpectStep {
+        self.step
+    }
+}
+
+/// scenarios in code instead of yaml
+#[derive(Debug, Clone)]
+pub struct ScenarioBuilder {
+    scenario: Scenario,
+}
+
+impl ScenarioBuilder {
+    pub fn new(name: &str) -> Self {
+        Self { scenario: Scenario { name: name.to_string(), register: true, steps: Vec::new(), matrix: Default::default(), tenant: None, codecs: default_codecs(), video_codecs: default_video_codecs() } }
+    }
+
+    /// skip handshake and REGISTER before steps
+    pub fn without_register(mut self) -> Self {
+        self.scenario.register = false;
+        self
+    }
+
+    pub fn step(mut self, step: Step) -> Self {
+        self.scenario.steps.push(step);
+        self
+    }
+
+    /// request without payload
+    pub fn send(self, code: MCodeType, fsm: u32) -> Self {
+        self.step(Step::Send(SendStep { code: format!("{code:?}"), fsm, request_channel: None, play: None, hex: None }))
+    }
+
+    pub fn request_channel(self, fsm: u32, spec: RequestChannelSpec) -> Self {
+        self.step(Step::Send(SendStep { code: format!("{:?}", MCodeType::REQUESTCHANNEL), fsm, request_channel: Some(spec), play: None, hex: None }))
+    }
+
+    pub fn play(self, fsm: u32, files: &[&str]) -> Self {
+        let play = PlaySpec { files: files.iter().map(|x| x.to_string()).collect(), ..Default::default() };
+        self.step(Step::Send(SendStep { code: format!("{:?}", MCodeType::PLAY), fsm, request_channel: None, play: Some(play), hex: None }))
+    }
+
+    pub fn expect(self, expect: PacketExpect) -> Self {
+        self.step(Step::Expect(expect.into_step()))
+    }
+
+    pub fn sleep(self, duration: Duration) -> Self {
+        self.step(Step::SleepMs(duration.as_millis() as u64))
+    }
+
+    /// hold next step until offset from the start of the steps
+    pub fn at(self, offset: Duration) -> Self {
+        self.step(Step::At(format!("+{}us", offset.as_micros())))
+    }
+
+    /// rtp impairment of channel fsm of the stub, every channel if None
+    pub fn impair(self, fsm: Option<u32>, profile: ImpairProfile) -> Self {
+        self.step(Step::Impair(ImpairStep { fsm, profile, socket: None }))
+    }
+
+    /// no packet of code, any if None, for window
+    pub fn expect_quiet(self, window: Duration, code: Option<MCodeType>) -> Self {
+        let window = format!("{}ms", window.as_millis());
+        let quiet = match code {
+            Some(code) => QuietStep::Filtered { window, code: Some(format!("{code:?}")), fsm: None },
+            None => QuietStep::Any(window),
+        };
+        self.step(Step::ExpectQuiet(quiet))
+    }
+
+    /// validated as a loaded one
+    pub fn build(self) -> Result<Scenario> {
+        self.scenario.validate()?;
+        Ok(self.scenario)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        vn_ms_sim::MsSimConfig,
+        vn_proto::MCodeType,
+        vn_scenario::{run_scenario, RequestChannelSpec},
+    };
+
+    use super::{expect_packet, MsStub, ScenarioBuilder};
+
+    #[tokio::test]
+    async fn test_testkit() {
+        let (_ms, mut session) = MsStub::start(MsSimConfig::default(), 5);
+        let req = RequestChannelSpec { webrtc: vec!["".into()], ..Default::default() };
+        let scenario = ScenarioBuilder::new("one call")
+        .request_channel(1, req.clone())
+        .expect(expect_packet(MCodeType::REQUESTCHANNEL_ACK).fsm(1).with_field("result", 0).with_field("audio_port", ">0"))
+        .expect_quiet(Duration::from_millis(20), None)
+        .play(1, &["file://cc/11000.wav"])
+        .expect(expect_packet(MCodeType::PLAY_ACK).fsm(1))
+        .send(MCodeType::RELEASECHANNEL, 1)
+        .build()
+        .unwrap();
+        run_scenario(&mut session, &scenario).await.unwrap();
+
+        session.request_channel(session.base_fsm_id() + 2, &req.to_request()).await.unwrap();
+        let fields = expect_packet(MCodeType::REQUESTCHANNEL_ACK).recv(&mut session).await.unwrap();
+        assert_eq!(fields.get("fsm_id").map(|x| x.to_string()), Some((session.base_fsm_id() + 2).to_string()));
+        let r = expect_packet(MCodeType::REQUESTCHANNEL_ACK).timeout(Duration::from_millis(100)).recv(&mut session).await;
+        assert!(r.is_err());
+
+        assert!(ScenarioBuilder::new("bad").expect(expect_packet(MCodeType::PLAY_ACK).with_field("result", vec![1])).build().is_err());
+    }
+}
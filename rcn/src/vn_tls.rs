@@ -0,0 +1,252 @@
+//! VN packets over a TLS stream, each datagram framed by a 2 bytes big endian length.
+
+use std::{io, path::{Path, PathBuf}, sync::Arc, time::SystemTime};
+
+use anyhow::{Result, Context, bail};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, RootCertStore, ServerName,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::TcpStream,
+    sync::Mutex,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use tracing::{info, warn};
+
+use crate::utils::datagram::Datagram;
+
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// host:port
+    pub peer: String,
+
+    /// pem file of trusted CA certificates
+    pub ca: Option<PathBuf>,
+
+    /// name checked against peer certificate, default to host of peer
+    pub server_name: Option<String>,
+
+    /// skip certificate validation
+    pub insecure: bool,
+}
+
+/// frame length prefix
+const PREFIX_LEN: usize = 2;
+
+/// reads frames off a stream, cancel safe: bytes of a frame read before
+/// recv is dropped stay here and the next recv carries on with them
+struct FrameReader<R> {
+    reader: R,
+    /// prefix and frame received so far
+    frame: Vec<u8>,
+    filled: usize,
+    /// length of the last buffer current frame didn't fit
+    oversized: Option<usize>,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            frame: vec![0; PREFIX_LEN + u16::MAX as usize],
+            filled: 0,
+            oversized: None,
+        }
+    }
+
+    /// read until a whole frame is here, return its length
+    async fn fill(&mut self) -> io::Result<usize> {
+        loop {
+            let want = if self.filled < PREFIX_LEN {
+                PREFIX_LEN
+            } else {
+                PREFIX_LEN + u16::from_be_bytes([self.frame[0], self.frame[1]]) as usize
+            };
+            if self.filled >= PREFIX_LEN && self.filled == want {
+                return Ok(want - PREFIX_LEN)
+            }
+            let n = self.reader.read(&mut self.frame[self.filled..want]).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into())
+            }
+            self.filled += n;
+        }
+    }
+
+    /// like a datagram socket, a frame larger than buf comes back cut at
+    /// buf.len(), see RecvBuf. It's kept while the buffer passed in grows,
+    /// dropped once it doesn't.
+    async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.fill().await?;
+        let frame = &self.frame[PREFIX_LEN..PREFIX_LEN + len];
+        if len > buf.len() {
+            buf.copy_from_slice(&frame[..buf.len()]);
+            if self.oversized.is_none_or(|x| x < buf.len()) {
+                self.oversized = Some(buf.len());
+                return Ok(buf.len())
+            }
+        } else {
+            buf[..len].copy_from_slice(frame);
+        }
+        self.filled = 0;
+        self.oversized = None;
+        Ok(len.min(buf.len()))
+    }
+}
+
+pub struct TlsDatagram {
+    reader: Mutex<FrameReader<ReadHalf<TlsStream<TcpStream>>>>,
+    writer: Mutex<WriteHalf<TlsStream<TcpStream>>>,
+}
+
+impl TlsDatagram {
+    pub async fn connect(opts: &TlsOptions) -> Result<Self> {
+        let config = client_config(opts)?;
+
+        let server_name = match &opts.server_name {
+            Some(v) => v.as_str(),
+            None => opts.peer.rsplit_once(':').map(|x|x.0).unwrap_or(&opts.peer),
+        };
+        let server_name = ServerName::try_from(server_name)
+        .with_context(||format!("invalid server name [{server_name}]"))?;
+
+        let tcp = TcpStream::connect(&opts.peer).await
+        .with_context(||format!("failed to connect [{}]", opts.peer))?;
+        tcp.set_nodelay(true)?;
+
+        let stream = TlsConnector::from(Arc::new(config)).connect(server_name, tcp).await
+        .with_context(||format!("tls handshake failed with [{}]", opts.peer))?;
+        info!("connected to [{}]", opts.peer);
+
+        let (reader, writer) = tokio::io::split(stream);
+        Ok(Self {
+            reader: Mutex::new(FrameReader::new(reader)),
+            writer: Mutex::new(writer),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Datagram for TlsDatagram {
+    /// target is ignored, the stream has exactly one peer
+    async fn send_to(&self, buf: &[u8], _target: &Path) -> io::Result<usize> {
+        if buf.len() > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "datagram too large for frame"))
+        }
+
+        let mut writer = self.writer.lock().await;
+        writer.write_u16(buf.len() as u16).await?;
+        writer.write_all(buf).await?;
+        writer.flush().await?;
+        Ok(buf.len())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Option<PathBuf>)> {
+        let len = self.reader.lock().await.recv(buf).await?;
+        Ok((len, None))
+    }
+}
+
+fn client_config(opts: &TlsOptions) -> Result<ClientConfig> {
+    let builder = ClientConfig::builder().with_safe_defaults();
+
+    if opts.insecure {
+        warn!("certificate validation disabled");
+        let config = builder
+        .with_custom_certificate_verifier(Arc::new(NoVerify))
+        .with_no_client_auth();
+        return Ok(config)
+    }
+
+    let ca = match &opts.ca {
+        Some(v) => v,
+        None => bail!("--ca is required unless --insecure"),
+    };
+
+    let pem = std::fs::read(ca).with_context(||format!("failed to read ca [{ca:?}]"))?;
+    let certs = rustls_pemfile::certs(&mut &pem[..]).with_context(||format!("invalid pem [{ca:?}]"))?;
+    if certs.is_empty() {
+        bail!("no certificate in [{ca:?}]")
+    }
+
+    let mut roots = RootCertStore::empty();
+    for cert in certs {
+        roots.add(&Certificate(cert)).with_context(||format!("invalid certificate in [{ca:?}]"))?;
+    }
+
+    let config = builder
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    Ok(config)
+}
+
+struct NoVerify;
+
+impl ServerCertVerifier for NoVerify {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+
+    use crate::utils::recv_buf::RecvBuf;
+
+    use super::FrameReader;
+
+    #[tokio::test]
+    async fn test_frame_reader() {
+        let (mut tx, rx) = tokio::io::duplex(4096);
+        let mut reader = FrameReader::new(rx);
+        let mut buf = [0_u8; 16];
+
+        tx.write_all(&[0, 3, 1, 2, 3, 0, 0]).await.unwrap();
+        assert_eq!(reader.recv(&mut buf).await.unwrap(), 3);
+        assert_eq!(&buf[..3], &[1, 2, 3]);
+        assert_eq!(reader.recv(&mut buf).await.unwrap(), 0);
+
+        // cancelled half way through a frame, the next recv picks it up
+        tx.write_all(&[0, 4, 9]).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(20), reader.recv(&mut buf)).await.is_err());
+        tx.write_all(&[8, 7, 6]).await.unwrap();
+        assert_eq!(reader.recv(&mut buf).await.unwrap(), 4);
+        assert_eq!(&buf[..4], &[9, 8, 7, 6]);
+
+        // oversized frame is taken once the buffer grew
+        let frame: Vec<u8> = (0..40).collect();
+        let mut recv_buf = RecvBuf::new(16, 64);
+        tx.write_all(&[0, 40]).await.unwrap();
+        tx.write_all(&frame).await.unwrap();
+        let len = loop {
+            let len = reader.recv(recv_buf.as_mut_slice()).await.unwrap();
+            if !recv_buf.check_truncated(len) {
+                break len
+            }
+        };
+        assert_eq!(recv_buf.truncated(), 2);
+        assert_eq!(&recv_buf.as_slice()[..len], &frame[..]);
+
+        // and dropped if it still doesn't fit
+        tx.write_all(&[0, 20]).await.unwrap();
+        tx.write_all(&frame[..20]).await.unwrap();
+        tx.write_all(&[0, 1, 5]).await.unwrap();
+        assert_eq!(reader.recv(&mut buf).await.unwrap(), 16);
+        assert_eq!(reader.recv(&mut buf).await.unwrap(), 16);
+        assert_eq!(reader.recv(&mut buf).await.unwrap(), 1);
+        assert_eq!(buf[0], 5);
+    }
+}
@@ -1,28 +1,54 @@
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
-use futures::Future;
 use tokio::net::UnixDatagram;
 
-use crate::utils::actor::{ActorHandler, ActionRes, Action, Actor, AsyncHandler};
+use crate::utils::{actor::{ActorHandler, ActionRes, Action, Actor, AsyncHandler}, rate_limit::RateLimiter, recv_buf::RecvBuf};
 
 pub struct VnUnixSocket {
     actor: Actor<Handler>,
+    /// waited for here, the actor keeps receiving meanwhile
+    limiter: RateLimiter,
 }
 
 impl VnUnixSocket {
     pub fn bind<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::bind_with_limiter(path, RateLimiter::default())
+    }
+
+    pub fn bind_with_limiter<P>(path: P, limiter: RateLimiter) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::bind_with(path, limiter, RecvBuf::default())
+    }
+
+    pub fn bind_with<P>(path: P, limiter: RateLimiter, recv_buf: RecvBuf) -> Result<Self>
     where
         P: AsRef<Path>,
     {
         let socket = UnixDatagram::bind(path)?;
-        let actor = Handler::new(socket).start("vnclient".into());
+        let actor = Handler::new(socket, recv_buf).start("vnclient".into());
         Ok(Self {
             actor,
+            limiter,
         })
     }
 
+    /// send packet of channel fsm_id, waiting for the rate limiter if configured
+    pub async fn send_to(&self, fsm_id: u32, data: Vec<u8>, target: PathBuf) -> Result<usize> {
+        if self.limiter.is_enabled() {
+            self.limiter.acquire(fsm_id).await;
+        }
+        let invoker = self.actor.invoker();
+        let len = invoker.invoke(SendOp { data, target }).await??;
+        Ok(len)
+    }
+
     pub async fn register(&self) -> Result<()> {
         let invoker = self.actor.invoker();
         invoker.invoke(RegisterOp).await??;
@@ -41,26 +67,44 @@ impl AsyncHandler<RegisterOp> for Handler {
     }
 }
 
+struct SendOp {
+    data: Vec<u8>,
+    target: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl AsyncHandler<SendOp> for Handler {
+    type Response = Result<usize>; 
+
+    async fn handle(&mut self, req: SendOp) -> Self::Response {
+        let len = self.socket.send_to(&req.data[..], &req.target).await?;
+        Ok(len)
+    }
+}
+
 
 type UnixSockAddr = tokio::net::unix::SocketAddr;
 
 struct Handler {
     socket: UnixDatagram,
-    recv_buf: Vec<u8>,
+    recv_buf: RecvBuf,
 }
 
 impl Handler {
-    pub fn new(socket: UnixDatagram) -> Self {
+    pub fn new(socket: UnixDatagram, recv_buf: RecvBuf) -> Self {
         Self {
             socket,
-            recv_buf: vec![0; 1700],
+            recv_buf,
         }
     }
 }
 
 impl Handler {
     async fn handle_recv(&mut self, result: Result<(usize, UnixSockAddr)>) -> Result<()> {
-        let (_len, _from) = result?;
+        let (len, _from) = result?;
+        if self.recv_buf.check_truncated(len) {
+            return Ok(())
+        }
 
         Ok(())
     }
@@ -74,21 +118,16 @@ impl ActorHandler for Handler {
     type Result = ();
 
     fn into_result(self) -> Self::Result {
-        ()
     }
 
-    fn wait_next(&mut self) -> impl Future<Output = Self::Next> + Send {
-        async {
-            let r = self.socket.recv_from(&mut self.recv_buf).await?;
-            Ok(r)
-        }
+    async fn wait_next(&mut self) -> Self::Next {
+        let r = self.socket.recv_from(self.recv_buf.as_mut_slice()).await?;
+        Ok(r)
     }
 
-    fn handle_next(&mut self, next: Self::Next) -> impl Future<Output = ActionRes> + Send {
-        async {
-            let _r = self.handle_recv(next).await;
-            Ok(Action::None)
-        }
+    async fn handle_next(&mut self, next: Self::Next) -> ActionRes {
+        let _r = self.handle_recv(next).await;
+        Ok(Action::None)
     }
 
 }